@@ -0,0 +1,143 @@
+//! A synchronous [`ItchClient`] for callers without an async runtime, enabled by the `blocking`
+//! feature.
+//!
+//! Every call runs the async [`crate::ItchClient`] on a runtime the client owns, so retries, rate
+//! limiting and the model types are exactly the same. Like `reqwest::blocking`, it must not be
+//! used from within an async runtime, calls panic if they are.
+
+use crate::client::{
+    self, DownloadProgress, DownloadedFile, ItchClientBuilder, PartialLibrary, PurchaseFilter,
+    SaveOptions,
+};
+use crate::error::{ItchError, Result};
+use crate::models::{
+    Build, CredentialsInfo, Game, GameDetails, OwnedKey, OwnedKeysResponse, Purchase, Upload, User,
+};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Blocking client for the itch.io API, see [`crate::ItchClient`] for what each call does
+#[derive(Clone)]
+pub struct ItchClient {
+    inner: client::ItchClient,
+    runtime: Arc<tokio::runtime::Runtime>,
+}
+
+impl ItchClient {
+    /// A client for `api_key` with the default settings
+    pub fn new(api_key: String) -> Result<Self> {
+        Self::from_async(client::ItchClient::new(api_key))
+    }
+
+    /// Build a client from `builder`, checking its settings as [`ItchClientBuilder::build`] does
+    pub fn build(builder: ItchClientBuilder) -> Result<Self> {
+        Self::from_async(builder.build()?)
+    }
+
+    /// Wrap an async client, sharing its settings, cache and rate limit
+    pub fn from_async(inner: client::ItchClient) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| ItchError::io("Failed to start the blocking client's runtime", e))?;
+        Ok(Self {
+            inner,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// The async client calls are made with
+    pub fn as_async(&self) -> &client::ItchClient {
+        &self.inner
+    }
+
+    /// Everything the game endpoint knows about a game, `None` once it's delisted or made private
+    pub fn get_game(&self, game_id: u64) -> Result<Option<GameDetails>> {
+        self.runtime.block_on(self.inner.get_game(game_id))
+    }
+
+    /// The games the account created, drafts and unlisted ones included
+    pub fn list_my_games(&self) -> Result<Vec<Game>> {
+        self.runtime.block_on(self.inner.list_my_games())
+    }
+
+    /// What the legacy server-side API knows about the key: its type, scopes and expiry
+    pub fn credentials_info(&self) -> Result<CredentialsInfo> {
+        self.runtime.block_on(self.inner.credentials_info())
+    }
+
+    /// Every purchase of one of the account's own games matching `filter`
+    pub fn list_purchases(&self, game_id: u64, filter: &PurchaseFilter) -> Result<Vec<Purchase>> {
+        self.runtime
+            .block_on(self.inner.list_purchases(game_id, filter))
+    }
+
+    /// The account the API key belongs to
+    pub fn get_profile(&self) -> Result<User> {
+        self.runtime.block_on(self.inner.get_profile())
+    }
+
+    /// One page of the library, counting from 1
+    pub fn owned_keys_page(&self, page: u64) -> Result<OwnedKeysResponse> {
+        self.runtime.block_on(self.inner.owned_keys_page(page))
+    }
+
+    /// Every key in the library, `on_page` being called before each page is requested
+    pub fn list_owned_keys(&self, on_page: impl FnMut(u64)) -> Result<Vec<OwnedKey>> {
+        self.runtime.block_on(self.inner.list_owned_keys(on_page))
+    }
+
+    /// Every key in the library that could be fetched, keeping what arrived when a later page
+    /// keeps failing
+    pub fn list_owned_keys_partial(&self, on_page: impl FnMut(u64)) -> Result<PartialLibrary> {
+        self.runtime
+            .block_on(self.inner.list_owned_keys_partial(on_page))
+    }
+
+    /// The files a game has, `download_key_id` granting access to paid ones, `None` for own games
+    ///
+    /// `cancel` can be fired from another thread to stop waiting.
+    pub fn get_game_uploads(
+        &self,
+        game_id: u64,
+        download_key_id: Option<u64>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Upload>> {
+        self.runtime.block_on(
+            self.inner
+                .get_game_uploads(game_id, download_key_id, cancel),
+        )
+    }
+
+    /// Every build pushed to a butler upload, newest first
+    pub fn list_builds(&self, upload_id: u64, download_key_id: Option<u64>) -> Result<Vec<Build>> {
+        self.runtime
+            .block_on(self.inner.list_builds(upload_id, download_key_id))
+    }
+
+    /// Download an upload into `dest_dir`, returning what was saved
+    ///
+    /// `cancel` can be fired from another thread to stop the transfer and remove the partial file.
+    #[allow(clippy::too_many_arguments)]
+    pub fn download_file(
+        &self,
+        upload_id: u64,
+        download_key_id: Option<u64>,
+        filename: &str,
+        dest_dir: &Path,
+        save: &SaveOptions,
+        on_progress: impl FnMut(DownloadProgress) + Send,
+        cancel: &CancellationToken,
+    ) -> Result<DownloadedFile> {
+        self.runtime.block_on(self.inner.download_file(
+            upload_id,
+            download_key_id,
+            filename,
+            dest_dir,
+            save,
+            on_progress,
+            cancel,
+        ))
+    }
+}
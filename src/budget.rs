@@ -0,0 +1,79 @@
+/// Parse a size such as `500M`, `50G` or `1.5T` in binary units, a bare number being bytes
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("'{}' doesn't start with a number", text))?;
+    let multiplier: u64 = match unit
+        .trim()
+        .trim_end_matches(['b', 'B'])
+        .trim_end_matches('i')
+        .to_ascii_uppercase()
+        .as_str()
+    {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("unknown unit '{}' in '{}'", unit, text)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// A cap on how many bytes a run downloads, reserved in the order downloads are about to start
+#[derive(Debug)]
+pub struct Budget {
+    limit: u64,
+    used: u64,
+    /// Set once a download didn't fit, after which nothing else is started
+    exhausted: bool,
+}
+
+/// Whether a download may go ahead under the budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reservation {
+    Granted,
+    /// Would take the run over its budget
+    OverBudget,
+    /// Itch didn't report a size, so it can't be accounted for
+    UnknownSize,
+}
+
+impl Budget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Reserve `size` bytes for a download that's about to start
+    pub fn reserve(&mut self, size: u64) -> Reservation {
+        if self.exhausted {
+            return Reservation::OverBudget;
+        }
+        if size == 0 {
+            return Reservation::UnknownSize;
+        }
+        if self.used + size > self.limit {
+            self.exhausted = true;
+            return Reservation::OverBudget;
+        }
+        self.used += size;
+        Reservation::Granted
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+}
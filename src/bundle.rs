@@ -0,0 +1,276 @@
+use crate::error::{ItchError, Result};
+use reqwest::Client;
+use tracing::{debug, info};
+
+/// The most pages of a bundle we're willing to walk through, well past the largest bundles
+const MAX_PAGES: u64 = 1000;
+
+/// A game in a bundle, as listed on the bundle's download page
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct BundleEntry {
+    pub game_id: u64,
+    pub title: String,
+    /// Whether a download key has been generated, only claimed games are in the library
+    pub claimed: bool,
+    /// The page of the bundle listing the entry, which the claim has to be posted to
+    pub page: u64,
+    /// The form token needed to claim the entry, for unclaimed ones
+    #[serde(skip)]
+    csrf_token: Option<String>,
+}
+
+/// The download page of a bundle from its URL or the secret at the end of it
+///
+/// Accepts `https://itch.io/bundle/download/<secret>` as well as the bare secret.
+pub fn bundle_url(bundle: &str) -> Result<reqwest::Url> {
+    let invalid = |message: &str| ItchError::Config {
+        message: format!("{}: {}", message, bundle),
+    };
+    let secret = match bundle.split_once("/bundle/download/") {
+        Some((_, secret)) => secret.split(['?', '#', '/']).next().unwrap_or_default(),
+        None if bundle.contains('/') => return Err(invalid("not a bundle download URL")),
+        None => bundle,
+    };
+    if secret.is_empty()
+        || !secret
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(invalid("not a bundle download URL"));
+    }
+    format!("https://itch.io/bundle/download/{}", secret)
+        .parse()
+        .map_err(|_| invalid("not a bundle download URL"))
+}
+
+/// The `Cookie` header for a session, given either the `itchio` cookie's value or `itchio=<value>`
+pub(crate) fn cookie(session: &str) -> String {
+    let session = session.trim();
+    let value = session.strip_prefix("itchio=").unwrap_or(session);
+    format!("itchio={}", value)
+}
+
+/// Fetch one page of the bundle as the logged in user
+async fn fetch_page(http: &Client, url: &reqwest::Url, session: &str, page: u64) -> Result<String> {
+    let response = http
+        .get(url.clone())
+        .query(&[("page", page)])
+        .header(reqwest::header::COOKIE, cookie(session))
+        .send()
+        .await
+        .map_err(|source| ItchError::Request {
+            context: "Bundle page request failed",
+            source,
+        })?;
+    // A session that isn't logged in is sent to the login page
+    if response.url().path().starts_with("/login") {
+        return Err(ItchError::SessionRejected);
+    }
+    let status = response.status();
+    let body = response.text().await.map_err(|source| ItchError::Request {
+        context: "Failed to read bundle page",
+        source,
+    })?;
+    if !status.is_success() {
+        return Err(ItchError::Http {
+            context: "Bundle page request",
+            status,
+            body: body.chars().take(200).collect(),
+            saved_to: None,
+        });
+    }
+    Ok(body)
+}
+
+/// Every entry in the bundle, walking its pages until one lists nothing new
+pub async fn list_entries(
+    http: &Client,
+    url: &reqwest::Url,
+    session: &str,
+    mut on_page: impl FnMut(u64),
+) -> Result<Vec<BundleEntry>> {
+    let mut entries: Vec<BundleEntry> = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    for page in 1..=MAX_PAGES {
+        on_page(page);
+        let html = fetch_page(http, url, session, page).await?;
+        let before = entries.len();
+        for entry in parse_page(&html, page) {
+            if seen.insert(entry.game_id) {
+                entries.push(entry);
+            }
+        }
+        debug!(
+            page,
+            entries = entries.len() - before,
+            "Fetched bundle page"
+        );
+        // Past the last page itch repeats it or lists nothing
+        if entries.len() == before {
+            break;
+        }
+    }
+    info!(
+        entries = entries.len(),
+        unclaimed = entries.iter().filter(|entry| !entry.claimed).count(),
+        "Fetched bundle"
+    );
+    Ok(entries)
+}
+
+/// Claim an unclaimed entry, generating the download key that adds it to the library
+pub async fn claim(
+    http: &Client,
+    url: &reqwest::Url,
+    session: &str,
+    entry: &BundleEntry,
+) -> Result<()> {
+    let Some(csrf_token) = &entry.csrf_token else {
+        // Already claimed, nothing to do
+        return Ok(());
+    };
+    let game_id = entry.game_id.to_string();
+    let response = http
+        .post(url.clone())
+        .query(&[("page", entry.page)])
+        .header(reqwest::header::COOKIE, cookie(session))
+        .form(&[
+            ("action", "claim"),
+            ("game_id", game_id.as_str()),
+            ("csrf_token", csrf_token.as_str()),
+        ])
+        .send()
+        .await
+        .map_err(|source| ItchError::Request {
+            context: "Claim request failed",
+            source,
+        })?;
+    if response.url().path().starts_with("/login") {
+        return Err(ItchError::SessionRejected);
+    }
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(ItchError::Http {
+            context: "Claim request",
+            status,
+            body: body.chars().take(200).collect(),
+            saved_to: None,
+        });
+    }
+    info!(game_id = entry.game_id, title = %entry.title, "Claimed bundle entry");
+    Ok(())
+}
+
+/// Pick the entries out of a bundle page
+///
+/// Each game is a `game_row` element; unclaimed ones carry a form posting `action=claim` with the
+/// game's id and a CSRF token, claimed ones link to their download page instead.
+fn parse_page(html: &str, page: u64) -> Vec<BundleEntry> {
+    let mut rows: Vec<&str> = Vec::new();
+    let mut rest = html;
+    while let Some(start) = find_row(rest) {
+        let row = &rest[start..];
+        // The next row is looked for past this one's opening tag, which holds the class itself
+        let tag_end = row.find('>').map_or(row.len(), |end| end + 1);
+        let end = find_row(&row[tag_end..]).map_or(row.len(), |end| end + tag_end);
+        rows.push(&row[..end]);
+        rest = &row[end..];
+    }
+
+    rows.into_iter()
+        .filter_map(|row| {
+            let row_tag = &row[..row.find('>').unwrap_or(row.len())];
+            let game_id = attribute(row_tag, "data-game_id")
+                .or_else(|| input_value(row, "game_id"))?
+                .parse()
+                .ok()?;
+            let title = row
+                .find("game_title")
+                .and_then(|at| element_text(&row[at..], "<a"))
+                .map(decode_entities)
+                .unwrap_or_default();
+            let unclaimed = row.contains("value=\"claim\"");
+            Some(BundleEntry {
+                game_id,
+                title,
+                claimed: !unclaimed,
+                page,
+                csrf_token: unclaimed
+                    .then(|| input_value(row, "csrf_token").map(str::to_string))
+                    .flatten(),
+            })
+        })
+        .collect()
+}
+
+/// Where the next `game_row` element starts, skipping `game_row_data` and the like
+fn find_row(html: &str) -> Option<usize> {
+    let mut offset = 0;
+    while let Some(at) = html[offset..].find("class=\"game_row") {
+        let at = offset + at;
+        let after = &html[at + "class=\"game_row".len()..];
+        if after.starts_with('"') || after.starts_with(' ') {
+            // Back up to the start of the tag so its attributes are part of the row
+            return Some(html[..at].rfind('<').unwrap_or(at));
+        }
+        offset = at + 1;
+    }
+    None
+}
+
+/// The value of `name` on a tag, given the tag's text
+pub(crate) fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let pattern = format!("{}=\"", name);
+    let mut offset = 0;
+    while let Some(at) = tag[offset..].find(&pattern) {
+        let at = offset + at;
+        // Not the tail end of a longer attribute name
+        if at == 0 || tag.as_bytes()[at - 1].is_ascii_whitespace() {
+            let value = &tag[at + pattern.len()..];
+            return value.find('"').map(|end| &value[..end]);
+        }
+        offset = at + 1;
+    }
+    None
+}
+
+/// The value of the `<input>` called `name`
+fn input_value<'a>(html: &'a str, name: &str) -> Option<&'a str> {
+    html.match_indices("<input").find_map(|(at, _)| {
+        let tag = &html[at..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        (attribute(tag, "name") == Some(name))
+            .then(|| attribute(tag, "value"))
+            .flatten()
+    })
+}
+
+/// The text inside the first element opened with `open`, e.g. `<a`
+fn element_text<'a>(html: &'a str, open: &str) -> Option<&'a str> {
+    let start = html.find(open)?;
+    let text = &html[start..];
+    let text = &text[text.find('>')? + 1..];
+    Some(text[..text.find('<').unwrap_or(text.len())].trim())
+}
+
+/// The `content` of the `<meta>` tag whose `key` attribute is `value`
+pub(crate) fn meta<'a>(html: &'a str, key: &str, value: &str) -> Option<&'a str> {
+    html.match_indices("<meta").find_map(|(at, _)| {
+        let tag = &html[at..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        (attribute(tag, key) == Some(value))
+            .then(|| attribute(tag, "content").or_else(|| attribute(tag, "value")))
+            .flatten()
+    })
+}
+
+/// Undo the HTML escaping titles are served with
+pub(crate) fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
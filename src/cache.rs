@@ -0,0 +1,165 @@
+use crate::error::{Context, Result};
+use crate::models::Upload;
+use crate::timestamp::Timestamp;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// File in the cache directory holding a fingerprint of the API key the entries belong to
+pub(crate) const KEY_FILE: &str = "key";
+
+/// Cache entries this process has written, numbering their temporary files
+static WRITES: AtomicU64 = AtomicU64::new(0);
+
+/// Format of stored uploads listings, bumped whenever [`CachedUploads`] or [`Upload`] changes
+/// shape so entries written by another version are refetched rather than misread
+pub const UPLOADS_FORMAT_VERSION: u32 = 2;
+
+/// How long a stored uploads listing is reused before it's fetched again
+///
+/// A developer pushing a new build doesn't touch the owned key, so listings can't be reused
+/// forever even when the key is unchanged.
+pub const UPLOADS_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A cached API response along with the validators needed to revalidate it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub body: String,
+    /// When itch.io last confirmed the body, unknown for entries written by older versions
+    #[serde(default)]
+    pub fetched_at: Option<Timestamp>,
+}
+
+/// A game's resolved uploads listing, reused by later runs without asking itch.io at all
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedUploads {
+    /// [`UPLOADS_FORMAT_VERSION`] when the entry was written
+    pub version: u32,
+    pub fetched_at: Timestamp,
+    /// The owned key's `updated_at` at the time, a change means the listing may be stale
+    pub key_updated_at: Option<Timestamp>,
+    pub etag: Option<String>,
+    pub uploads: Vec<Upload>,
+}
+
+impl CachedUploads {
+    /// Whether the listing can stand in for a fresh one for a key last updated at
+    /// `key_updated_at`
+    pub fn is_fresh(&self, key_updated_at: Option<Timestamp>, now: Timestamp) -> bool {
+        self.version == UPLOADS_FORMAT_VERSION
+            && key_updated_at.is_some()
+            && self.key_updated_at == key_updated_at
+            && (0..UPLOADS_MAX_AGE.as_secs() as i64)
+                .contains(&(now.timestamp() - self.fetched_at.timestamp()))
+    }
+}
+
+/// On-disk cache of metadata responses, revalidated with `If-None-Match`/`If-Modified-Since`
+#[derive(Debug)]
+pub struct HttpCache {
+    dir: PathBuf,
+}
+
+/// Hex encoded SHA-256 of some text
+fn hex_digest(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+impl HttpCache {
+    /// The cache directory for this user and `profile`, if the platform has one
+    pub fn default_dir(profile: Option<&str>) -> Option<PathBuf> {
+        let dir = dirs::cache_dir()?.join("itch-downloader");
+        Some(match profile {
+            Some(name) => dir.join("profiles").join(name).join("http"),
+            None => dir.join("http"),
+        })
+    }
+
+    /// Open the cache in `dir` for the given API key
+    ///
+    /// Entries cached for a different key are thrown away, responses depend on whose library it is.
+    pub fn open(dir: PathBuf, api_key: &str) -> Result<Self> {
+        std::fs::create_dir_all(&dir).context("Failed to create cache directory")?;
+        // Only a digest is stored, the cache shouldn't leak the key
+        let fingerprint = hex_digest(api_key);
+        let key_path = dir.join(KEY_FILE);
+        let cached_fingerprint = std::fs::read_to_string(&key_path).ok();
+
+        if cached_fingerprint.as_deref() != Some(fingerprint.as_str()) {
+            for entry in std::fs::read_dir(&dir).context("Failed to read cache directory")? {
+                let entry = entry.context("Failed to list cache directory")?;
+                if entry.path().extension().is_some_and(|ext| ext == "json") {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+            std::fs::write(&key_path, &fingerprint).context("Failed to write cache key")?;
+        }
+
+        Ok(Self { dir })
+    }
+
+    /// Path of the entry for a request, one per endpoint and set of query parameters
+    fn entry_path(&self, url: &str, query: &[(&str, u64)]) -> PathBuf {
+        let mut request = url.to_string();
+        for (name, value) in query {
+            request.push_str(&format!("&{}={}", name, value));
+        }
+        self.dir.join(format!("{}.json", hex_digest(&request)))
+    }
+
+    /// The cached response for a request, if there is a readable one
+    pub fn get(&self, url: &str, query: &[(&str, u64)]) -> Option<CachedResponse> {
+        let contents = std::fs::read_to_string(self.entry_path(url, query)).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Store a response, replacing any earlier one for the same request
+    pub fn put(&self, url: &str, query: &[(&str, u64)], response: &CachedResponse) -> Result<()> {
+        let contents =
+            serde_json::to_string(response).context("Failed to serialize cache entry")?;
+        self.write_entry(self.entry_path(url, query), contents)
+    }
+
+    /// Path of the stored uploads listing for a game and the key it was listed with
+    fn uploads_path(&self, game_id: u64, download_key_id: Option<u64>) -> PathBuf {
+        self.dir.join(format!(
+            "uploads-{}-{}.json",
+            game_id,
+            download_key_id.unwrap_or(0)
+        ))
+    }
+
+    /// The stored uploads listing for a game, if there is a readable one in any format version
+    pub fn get_uploads(&self, game_id: u64, download_key_id: Option<u64>) -> Option<CachedUploads> {
+        let contents = std::fs::read_to_string(self.uploads_path(game_id, download_key_id)).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Store a game's uploads listing, replacing any earlier one
+    pub fn put_uploads(
+        &self,
+        game_id: u64,
+        download_key_id: Option<u64>,
+        entry: &CachedUploads,
+    ) -> Result<()> {
+        let contents =
+            serde_json::to_string(entry).context("Failed to serialize uploads listing")?;
+        self.write_entry(self.uploads_path(game_id, download_key_id), contents)
+    }
+
+    fn write_entry(&self, path: PathBuf, contents: String) -> Result<()> {
+        // Written under a unique name and renamed so concurrent requests never see half an entry,
+        // nor write into the same temporary file when they store the same one
+        let temp_path = path.with_extension(format!(
+            "{}.{}.tmp",
+            WRITES.fetch_add(1, Ordering::Relaxed),
+            std::process::id()
+        ));
+        std::fs::write(&temp_path, contents).context("Failed to write cache entry")?;
+        std::fs::rename(&temp_path, &path).context("Failed to move cache entry into place")
+    }
+}
@@ -0,0 +1,169 @@
+use crate::error::{Context, ItchError, Result};
+use md5::{Digest, Md5};
+use serde::Serialize;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// How a game's local copy compares to what itch currently serves
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    /// Present and matching the remote size and checksum
+    Ok,
+    /// Present but only as an extracted directory we have no record of, so it can't be compared
+    Unverified,
+    Missing,
+    SizeMismatch,
+    Md5Mismatch,
+    /// The game has no uploads, or they couldn't be listed
+    Error,
+}
+
+impl CheckStatus {
+    /// Whether this entry means the mirror isn't a complete, current copy
+    pub fn is_problem(self) -> bool {
+        !matches!(self, CheckStatus::Ok | CheckStatus::Unverified)
+    }
+
+    /// Short name used in the table
+    pub fn label(self) -> &'static str {
+        match self {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Unverified => "unverified",
+            CheckStatus::Missing => "missing",
+            CheckStatus::SizeMismatch => "size",
+            CheckStatus::Md5Mismatch => "md5",
+            CheckStatus::Error => "error",
+        }
+    }
+}
+
+/// The result of checking a single game
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckEntry {
+    pub game_id: u64,
+    pub title: String,
+    pub upload_id: Option<u64>,
+    pub filename: Option<String>,
+    /// Where the local copy is, or was expected to be
+    pub path: Option<PathBuf>,
+    pub status: CheckStatus,
+    pub detail: Option<String>,
+}
+
+/// Everything `check` found, in the shape written by `--format json`
+#[derive(Debug, Default, Serialize)]
+pub struct CheckReport {
+    pub games: Vec<CheckEntry>,
+    /// Top level entries of the output directory that no selected game accounts for
+    pub extra: Vec<PathBuf>,
+}
+
+impl CheckReport {
+    /// Whether every selected game is present and current, with nothing extra lying around
+    pub fn is_complete(&self) -> bool {
+        self.extra.is_empty() && !self.games.iter().any(|entry| entry.status.is_problem())
+    }
+}
+
+/// Runs file hashing on blocking threads, at most a fixed number of files at a time
+///
+/// Each job reads its file in fixed-size chunks, so memory use stays bounded however large the
+/// files are. Clones share the same limit.
+#[derive(Clone, Debug)]
+pub struct HashPool {
+    permits: std::sync::Arc<tokio::sync::Semaphore>,
+    jobs: usize,
+}
+
+impl HashPool {
+    /// A pool hashing up to `jobs` files at once, at least 1
+    pub fn new(jobs: usize) -> Self {
+        let jobs = jobs.max(1);
+        Self {
+            permits: std::sync::Arc::new(tokio::sync::Semaphore::new(jobs)),
+            jobs,
+        }
+    }
+
+    /// How many files this computer can hash at once, one per CPU
+    pub fn default_jobs() -> usize {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    }
+
+    pub fn jobs(&self) -> usize {
+        self.jobs
+    }
+
+    /// Run `work` on a blocking thread once a slot is free
+    pub async fn run<T: Send + 'static>(
+        &self,
+        work: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|_| ItchError::Cancelled)?;
+        tokio::task::spawn_blocking(work)
+            .await
+            .context("Hash worker panicked")?
+    }
+}
+
+/// Hex encoded MD5 of a file's contents
+pub fn file_md5(path: &Path) -> Result<String> {
+    file_md5_with_progress(path, |_| {})
+}
+
+/// Hex encoded MD5 of a file's contents, `on_read` hearing of every chunk's size
+pub fn file_md5_with_progress(path: &Path, mut on_read: impl FnMut(u64)) -> Result<String> {
+    let _span = tracing::debug_span!("hash", path = %path.display()).entered();
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Md5::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        on_read(read as u64);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Compare a downloaded file against the remote size and, when known, checksum
+///
+/// `on_read` hears of every chunk hashed, nothing is read when the sizes already differ.
+pub fn compare_file(
+    path: &Path,
+    size: u64,
+    md5: Option<&str>,
+    on_read: impl FnMut(u64),
+) -> Result<(CheckStatus, Option<String>)> {
+    let local_size = std::fs::metadata(path)
+        .with_context(|| format!("Failed to read metadata for {}", path.display()))?
+        .len();
+    if local_size != size {
+        return Ok((
+            CheckStatus::SizeMismatch,
+            Some(format!("local {} bytes, remote {} bytes", local_size, size)),
+        ));
+    }
+
+    if let Some(md5) = md5 {
+        let local_md5 = file_md5_with_progress(path, on_read)?;
+        if !local_md5.eq_ignore_ascii_case(md5) {
+            return Ok((
+                CheckStatus::Md5Mismatch,
+                Some(format!("local {}, remote {}", local_md5, md5)),
+            ));
+        }
+    }
+
+    Ok((CheckStatus::Ok, None))
+}
@@ -0,0 +1,139 @@
+use crate::error::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Added to a file's name for its checksum sidecar, `game.zip.sha256`
+pub const SIDECAR_SUFFIX: &str = ".sha256";
+
+/// The sidecar holding the checksum of `file`
+pub fn sidecar_path(file: &Path) -> PathBuf {
+    let mut name = file.file_name().unwrap_or_default().to_os_string();
+    name.push(SIDECAR_SUFFIX);
+    file.with_file_name(name)
+}
+
+/// The name of the file a sidecar is for, if `name` is a sidecar's
+pub fn sidecar_target(name: &str) -> Option<&str> {
+    name.strip_suffix(SIDECAR_SUFFIX)
+        .filter(|target| !target.is_empty())
+}
+
+/// A line `sha256sum -c` reads, `<hash>  <filename>`
+///
+/// Names with a backslash or line break are escaped the way coreutils does it, with the line
+/// starting with a backslash.
+pub fn format_line(sha256: &str, filename: &str) -> String {
+    if filename.contains(['\\', '\n', '\r']) {
+        let escaped = filename
+            .replace('\\', "\\\\")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r");
+        format!("\\{}  {}\n", sha256, escaped)
+    } else {
+        format!("{}  {}\n", sha256, filename)
+    }
+}
+
+/// The hash and filename of a line written by [`format_line`] or `sha256sum`
+pub fn parse_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim_end_matches(['\n', '\r']);
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (hash, filename) = line.split_once(' ')?;
+    // Two spaces for text mode, a space and an asterisk for binary
+    let filename = filename
+        .strip_prefix(' ')
+        .or_else(|| filename.strip_prefix('*'))?;
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) || filename.is_empty() {
+        return None;
+    }
+    let filename = if escaped {
+        let mut unescaped = String::with_capacity(filename.len());
+        let mut chars = filename.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                unescaped.push(c);
+                continue;
+            }
+            match chars.next()? {
+                '\\' => unescaped.push('\\'),
+                'n' => unescaped.push('\n'),
+                'r' => unescaped.push('\r'),
+                _ => return None,
+            }
+        }
+        unescaped
+    } else {
+        filename.to_string()
+    };
+    Some((hash.to_ascii_lowercase(), filename))
+}
+
+/// Write the sidecar of `file`, replacing any earlier one
+pub fn write_sidecar(file: &Path, sha256: &str) -> Result<()> {
+    let filename = file
+        .file_name()
+        .context("A checksum sidecar needs a file name")?
+        .to_string_lossy();
+    let path = sidecar_path(file);
+    std::fs::write(&path, format_line(sha256, &filename))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// The SHA-256 the sidecar of `file` records, `None` without one that names the file
+pub fn read_sidecar(file: &Path) -> Result<Option<String>> {
+    let path = sidecar_path(file);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let filename = file.file_name().unwrap_or_default().to_string_lossy();
+    Ok(contents
+        .lines()
+        .filter_map(parse_line)
+        .find(|(_, named)| *named == filename)
+        .map(|(hash, _)| hash))
+}
+
+/// Move the sidecar of `from` to `to`, e.g. after the file was renamed to keep an old version
+///
+/// The filename inside changes with it, so the sidecar keeps passing `sha256sum -c`.
+pub fn rename_sidecar(from: &Path, to: &Path) -> Result<()> {
+    let Some(sha256) = read_sidecar(from)? else {
+        return Ok(());
+    };
+    write_sidecar(to, &sha256)?;
+    let old = sidecar_path(from);
+    std::fs::remove_file(&old).with_context(|| format!("Failed to remove {}", old.display()))
+}
+
+/// Remove the sidecars in `dir` whose file is gone, returning how many there were
+///
+/// Only sidecars in our own format that name the file they sit next to are touched.
+pub fn remove_stale(dir: &Path) -> Result<usize> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to list {}", dir.display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(target) = sidecar_target(&name) else {
+            continue;
+        };
+        let file = dir.join(target);
+        if file.exists() || !entry.file_type().is_ok_and(|kind| kind.is_file()) {
+            continue;
+        }
+        if read_sidecar(&file).ok().flatten().is_some() {
+            std::fs::remove_file(entry.path())
+                .with_context(|| format!("Failed to remove {}", entry.path().display()))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
@@ -0,0 +1,146 @@
+use crate::bundle::{attribute, cookie, decode_entities, meta};
+use crate::error::{ItchError, Result};
+use reqwest::Client;
+use tracing::{debug, info};
+
+/// A game's page, fetched to find out what the link someone shared points at
+#[derive(Clone, Debug)]
+pub struct GamePage {
+    pub url: reqwest::Url,
+    pub game_id: u64,
+    pub title: String,
+    /// The price the page lists, `None` for free games and pay-what-you-want ones without a minimum
+    pub price: Option<String>,
+    /// The form token the page was served with, tied to the session it was fetched with
+    csrf_token: Option<String>,
+}
+
+impl GamePage {
+    /// Whether the game can only be had by buying it
+    pub fn requires_purchase(&self) -> bool {
+        self.price.as_deref().is_some_and(is_paid)
+    }
+}
+
+/// Whether a price as pages show it, e.g. `$5.00` or `0`, asks for any money
+pub fn is_paid(price: &str) -> bool {
+    let amount = price.trim_start_matches(|c: char| !c.is_ascii_digit());
+    !matches!(amount.parse::<f64>(), Ok(amount) if amount <= 0.0)
+}
+
+/// The page of a game from a link to it, e.g. `https://someone.itch.io/some-game`
+///
+/// Links to a game's other pages, like its devlog or a download page, lead back to the game.
+pub fn game_url(url: &str) -> Result<reqwest::Url> {
+    let invalid = || ItchError::Config {
+        message: format!("not an itch.io game URL: {}", url),
+    };
+    let parsed: reqwest::Url = url.trim().parse().map_err(|_| invalid())?;
+    let host = parsed.host_str().unwrap_or_default().to_ascii_lowercase();
+    let user = host.strip_suffix(".itch.io").ok_or_else(invalid)?;
+    if user.is_empty() || user == "www" || !matches!(parsed.scheme(), "http" | "https") {
+        return Err(invalid());
+    }
+    let slug = parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next())
+        .filter(|slug| !slug.is_empty())
+        .ok_or_else(invalid)?;
+    format!("https://{}/{}", host, slug)
+        .parse()
+        .map_err(|_| invalid())
+}
+
+/// Fetch a game's page, as the logged in user when there's a session
+pub async fn fetch_page(
+    http: &Client,
+    url: &reqwest::Url,
+    session: Option<&str>,
+) -> Result<GamePage> {
+    let mut request = http.get(url.clone());
+    if let Some(session) = session {
+        request = request.header(reqwest::header::COOKIE, cookie(session));
+    }
+    let response = request.send().await.map_err(|source| ItchError::Request {
+        context: "Game page request failed",
+        source,
+    })?;
+    let status = response.status();
+    let body = response.text().await.map_err(|source| ItchError::Request {
+        context: "Failed to read game page",
+        source,
+    })?;
+    if !status.is_success() {
+        return Err(ItchError::Http {
+            context: "Game page request",
+            status,
+            body: body.chars().take(200).collect(),
+            saved_to: None,
+        });
+    }
+
+    // Every game page names the game's id as `games/<id>` for the itch app
+    let game_id = meta(&body, "name", "itch:path")
+        .and_then(|path| path.strip_prefix("games/"))
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| ItchError::Config {
+            message: format!("{} isn't a game page", url),
+        })?;
+    let title = meta(&body, "property", "og:title")
+        .map(decode_entities)
+        .unwrap_or_else(|| url.to_string());
+    let price = body
+        .match_indices("itemprop=\"price\"")
+        .find_map(|(at, _)| {
+            let start = body[..at].rfind('<')?;
+            let tag = &body[start..];
+            attribute(&tag[..tag.find('>')?], "content")
+        })
+        .map(str::to_string);
+    let csrf_token = meta(&body, "name", "csrf_token").map(str::to_string);
+    debug!(game_id, %title, ?price, "Fetched game page");
+    Ok(GamePage {
+        url: url.clone(),
+        game_id,
+        title,
+        price,
+        csrf_token,
+    })
+}
+
+/// Generate a download key for the logged in user, adding a free game to their library
+///
+/// This is what the website does when "No thanks, just take me to the downloads" is clicked.
+/// `page` has to have been fetched with the same session.
+pub async fn claim(http: &Client, page: &GamePage, session: &str) -> Result<()> {
+    let url = format!("{}/download_url", page.url.as_str().trim_end_matches('/'));
+    let response = http
+        .post(url)
+        .header(reqwest::header::COOKIE, cookie(session))
+        .form(&[("csrf_token", page.csrf_token.as_deref().unwrap_or_default())])
+        .send()
+        .await
+        .map_err(|source| ItchError::Request {
+            context: "Claim request failed",
+            source,
+        })?;
+    if response.url().path().starts_with("/login") {
+        return Err(ItchError::SessionRejected);
+    }
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    // Refusals come back as a list of errors, with a success status
+    let errors = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|value| value.get("errors").cloned());
+    if !status.is_success() || errors.is_some() {
+        return Err(ItchError::Http {
+            context: "Claim request",
+            status,
+            body: errors.map_or(body, |errors| errors.to_string()),
+            saved_to: None,
+        });
+    }
+    info!(game_id = page.game_id, title = %page.title, "Claimed game");
+    Ok(())
+}
@@ -0,0 +1,2746 @@
+use crate::dedupe::HashIndex;
+use crate::error::{Context, ItchError, Result, cancellable};
+use crate::models::{
+    Build, BuildsResponse, Collection, CollectionGame, CollectionGamesResponse,
+    CollectionsResponse, CredentialsInfo, DownloadKey, DownloadKeyResponse, Game, GameDetails,
+    GameResponse, LegacyErrors, MyGamesResponse, OwnedKey, OwnedKeysResponse, ProfileResponse,
+    Purchase, PurchasesResponse, Reviews, ReviewsResponse, Upload, UploadsResponse, User,
+};
+use crate::proxy::{NoProxy, Pac, ProxyRoute, ProxyRules};
+use crate::{
+    cache, content_disposition_filename, fixtures, http_dump, json, layout, name_uploads,
+    sanitize_filename, staging,
+    timestamp::{self, Timestamp},
+    upload_filename,
+};
+use futures::stream::{Stream, StreamExt, TryStreamExt};
+use reqwest::Client;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::field::Empty;
+use tracing::{Instrument, Span, debug, info, warn};
+
+/// The itch.io API, unless `--api-base-url` points somewhere else
+pub const DEFAULT_API_BASE_URL: &str = "https://api.itch.io";
+
+/// The legacy server-side API, which takes the key as part of the path
+pub const DEFAULT_LEGACY_API_BASE_URL: &str = "https://itch.io/api/1";
+
+/// Which of itch.io's APIs requests go to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ApiVersion {
+    /// The current API, falling back to the legacy one when it rejects a legacy key
+    #[default]
+    Auto,
+    /// Only the current API at `api.itch.io`
+    Modern,
+    /// Only the legacy server-side API, which can list your own games and nothing else
+    Legacy,
+}
+
+/// What requests identify themselves as unless `--user-agent` says otherwise
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    "itch-downloader/",
+    env!("CARGO_PKG_VERSION"),
+    " (+",
+    env!("CARGO_PKG_REPOSITORY"),
+    ")"
+);
+
+/// The most pages of the library, or any other listing, we'll walk through before assuming the
+/// API is looping
+const MAX_LIBRARY_PAGES: u64 = 10_000;
+
+/// Library keys asked for per page unless [`ItchClientBuilder::page_size`] says otherwise
+pub const DEFAULT_PAGE_SIZE: u64 = 100;
+
+/// The largest page of library keys worth asking for, itch.io caps pages below this anyway
+const MAX_PAGE_SIZE: u64 = 1000;
+
+/// Idle connections kept per host, enough for a burst of upload listings to all reuse one
+const POOL_MAX_IDLE_PER_HOST: usize = 32;
+
+/// How long an idle connection is kept, longer than the pause between metadata requests
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Keepalive probes so idle pooled connections aren't silently dropped by NAT
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// How requests that itch.io rate limits (429) are retried
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt before giving up with [`ItchError::RateLimited`]
+    pub max_retries: u32,
+    /// Wait before any retry
+    pub base_delay: Duration,
+    /// Added to the wait for each attempt made so far
+    pub delay_step: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(1000),
+            delay_step: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `attempt`, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay + self.delay_step * attempt
+    }
+
+    /// The wait before retry number `attempt`, unless the server said how long to wait
+    pub fn delay_after(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        retry_after.unwrap_or_else(|| self.delay(attempt))
+    }
+}
+
+/// A storage host whose downloads are fetched from another host instead, e.g. a LAN cache
+///
+/// Only the host (and port) of the URL itch.io redirects a download to is replaced, the path and
+/// its signature are kept, so the other host has to serve the same paths.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CdnRewrite {
+    /// The host itch.io redirects to, matched case-insensitively
+    pub from: String,
+    /// The host, and optionally port, to fetch from instead
+    pub to: String,
+}
+
+impl std::str::FromStr for CdnRewrite {
+    type Err = String;
+
+    /// Parse `<from-host>=<to-host>[:<port>]`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <from-host>=<to-host>, got `{}`", s))?;
+        let (from, to) = (from.trim(), to.trim());
+        if from.is_empty() || to.is_empty() {
+            return Err(format!("expected <from-host>=<to-host>, got `{}`", s));
+        }
+        let valid = reqwest::Url::parse(&format!("https://{}/", to))
+            .is_ok_and(|url| url.path() == "/" && url.username().is_empty());
+        if !valid {
+            return Err(format!("`{}` isn't a host", to));
+        }
+        Ok(Self {
+            from: from.to_ascii_lowercase(),
+            to: to.to_string(),
+        })
+    }
+}
+
+impl CdnRewrite {
+    /// `url` with this rewrite's host, if it points at the host being replaced
+    fn apply(&self, url: &reqwest::Url) -> Option<reqwest::Url> {
+        if !url
+            .host_str()
+            .is_some_and(|host| host.eq_ignore_ascii_case(&self.from))
+        {
+            return None;
+        }
+        let target = reqwest::Url::parse(&format!("{}://{}/", url.scheme(), self.to)).ok()?;
+        let mut rewritten = url.clone();
+        rewritten.set_host(target.host_str()).ok()?;
+        rewritten.set_port(target.port()).ok()?;
+        Some(rewritten)
+    }
+
+    /// Whether `url` already points at the host this rewrite sends downloads to
+    fn is_target(&self, url: &reqwest::Url) -> bool {
+        reqwest::Url::parse(&format!("{}://{}/", url.scheme(), self.to)).is_ok_and(|target| {
+            target.host_str() == url.host_str()
+                && target.port_or_known_default() == url.port_or_known_default()
+        })
+    }
+}
+
+/// How downloads from a storage host are treated instead of the client's defaults, e.g. a flaky
+/// external host that needs fewer connections and more patience than itch.io's CDN
+///
+/// Anything left unset falls back to the `*` policy, then to the client's settings.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostPolicy {
+    /// Downloads from the host at once, across every clone of the client
+    pub max_concurrent: Option<usize>,
+    /// Retries of a rate limited or unanswered request, and resumes of a transfer that broke
+    /// off, before the download fails
+    pub retries: Option<u32>,
+    /// How long the host may take to answer, or go without sending any of the body, before the
+    /// attempt is given up on and retried
+    pub timeout: Option<Duration>,
+}
+
+impl HostPolicy {
+    /// This policy with what it leaves unset taken from `fallback`
+    fn or(&self, fallback: &HostPolicy) -> HostPolicy {
+        HostPolicy {
+            max_concurrent: self.max_concurrent.or(fallback.max_concurrent),
+            retries: self.retries.or(fallback.retries),
+            timeout: self.timeout.or(fallback.timeout),
+        }
+    }
+}
+
+/// Whether a [`HostPolicy`] pattern covers `host`: `*` covers every host, anything else the host
+/// itself and its subdomains, so `example.com` covers `dl.example.com` but not `badexample.com`
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = normalize_host_pattern(pattern);
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    pattern == "*"
+        || host == pattern
+        || host
+            .strip_suffix(pattern.as_str())
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// A pattern in the form it's matched in, `*.example.com` being the same as `example.com`
+fn normalize_host_pattern(pattern: &str) -> String {
+    let pattern = pattern.trim().to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => domain.trim_end_matches('.').to_string(),
+        None => pattern.trim_end_matches('.').to_string(),
+    }
+}
+
+/// The storage host a download's body comes from, kept across resumes
+#[derive(Debug, Default)]
+struct StorageTarget {
+    /// Where itch.io redirected the download, signature and all
+    url: Option<reqwest::Url>,
+    /// The host's [`HostPolicy`], with the `*` one filled in
+    policy: HostPolicy,
+    /// The host the policy and slot are for
+    host: Option<String>,
+    /// One of the host's `max_concurrent` slots, held until the download is done
+    slot: Option<tokio::sync::OwnedSemaphorePermit>,
+}
+
+/// The error of a storage host going quiet for longer than its timeout
+fn timed_out(timeout: Option<Duration>) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!(
+            "nothing from the storage host for {:?}",
+            timeout.unwrap_or_default()
+        ),
+    )
+}
+
+impl StorageTarget {
+    /// `default` with the host's retries
+    fn retry(&self, default: &RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.policy.retries.unwrap_or(default.max_retries),
+            ..default.clone()
+        }
+    }
+}
+
+/// How far the local clock may be off itch.io's before it's worth a warning
+pub const CLOCK_SKEW_THRESHOLD: Duration = Duration::from_secs(120);
+
+/// The server's time from a response's `Date` header
+fn server_date(headers: &reqwest::header::HeaderMap) -> Option<Timestamp> {
+    timestamp::parse_http_date(headers.get(reqwest::header::DATE)?.to_str().ok()?)
+}
+
+/// The span a download runs in, its bytes, retries and resumes recorded once they're known
+fn download_span(upload_id: u64) -> Span {
+    tracing::info_span!(
+        "download",
+        upload_id,
+        bytes = Empty,
+        retries = Empty,
+        resumes = Empty
+    )
+}
+
+/// How long a response asks for before the next request, from its `Retry-After`
+///
+/// The header is either a number of seconds or a date. A date is measured against the response's
+/// own `Date` so a local clock that's off doesn't turn it into no wait or a very long one.
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let until = timestamp::parse_http_date(value)?;
+    let now = server_date(headers).unwrap_or_else(timestamp::now);
+    Some(Duration::from_secs(
+        until.timestamp().saturating_sub(now.timestamp()).max(0) as u64,
+    ))
+}
+
+/// Whether `url` is one of the API's download endpoints, `uploads/<id>/download` or
+/// `builds/<id>/download/archive/<type>`, which redirect to the file's storage host
+fn is_download_endpoint(url: &reqwest::Url) -> bool {
+    let path = url.path();
+    path.ends_with("/download") || path.contains("/download/archive/")
+}
+
+/// Spaces requests out evenly, shared by every clone of a client
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    /// When the next request may go out
+    next: tokio::sync::Mutex<tokio::time::Instant>,
+}
+
+impl RateLimiter {
+    /// Wait for this request's slot
+    async fn wait(&self) {
+        let mut next = self.next.lock().await;
+        let slot = (*next).max(tokio::time::Instant::now());
+        *next = slot + self.interval;
+        drop(next);
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+/// Window [`ItchClientBuilder::max_requests_per_minute`] and [`ApiUsage::recent_requests`] count
+/// requests over
+pub const REQUEST_WINDOW: Duration = Duration::from_secs(60);
+
+/// Caps how many requests go out per [`REQUEST_WINDOW`], queueing the rest
+#[derive(Debug)]
+struct WindowLimiter {
+    max: usize,
+    /// When the requests still in the window went out, oldest first
+    sent: tokio::sync::Mutex<std::collections::VecDeque<tokio::time::Instant>>,
+}
+
+impl WindowLimiter {
+    /// Wait until the window has room for this request
+    async fn wait(&self) {
+        // Held while waiting so queued requests go out in the order they arrived
+        let mut sent = self.sent.lock().await;
+        let now = tokio::time::Instant::now();
+        while sent.front().is_some_and(|&at| now - at >= REQUEST_WINDOW) {
+            sent.pop_front();
+        }
+        if sent.len() >= self.max
+            && let Some(oldest) = sent.pop_front()
+        {
+            tokio::time::sleep_until(oldest + REQUEST_WINDOW).await;
+        }
+        sent.push_back(tokio::time::Instant::now());
+    }
+}
+
+/// How many requests a client has made and how many itch.io throttled, see
+/// [`ItchClient::api_usage`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ApiUsage {
+    /// Requests sent, API calls and downloads alike, retries included
+    pub requests: u64,
+    /// Responses that were `429 Too Many Requests`
+    pub throttled: u64,
+    /// Requests sent within the last [`REQUEST_WINDOW`]
+    pub recent_requests: u64,
+}
+
+/// Counts requests for [`ApiUsage`], shared by every clone of a client
+#[derive(Debug, Default)]
+struct UsageMeter {
+    requests: std::sync::atomic::AtomicU64,
+    throttled: std::sync::atomic::AtomicU64,
+    /// When the requests within the window went out, oldest first
+    recent: std::sync::Mutex<std::collections::VecDeque<Instant>>,
+}
+
+impl UsageMeter {
+    fn record(&self, throttled: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if throttled {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_back(Instant::now());
+        Self::expire(&mut recent);
+    }
+
+    fn expire(recent: &mut std::collections::VecDeque<Instant>) {
+        while recent
+            .front()
+            .is_some_and(|at| at.elapsed() >= REQUEST_WINDOW)
+        {
+            recent.pop_front();
+        }
+    }
+
+    fn snapshot(&self) -> ApiUsage {
+        let mut recent = self.recent.lock().unwrap();
+        Self::expire(&mut recent);
+        ApiUsage {
+            requests: self.requests.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            recent_requests: recent.len() as u64,
+        }
+    }
+}
+
+/// Sets up an [`ItchClient`], see [`ItchClient::builder`]
+///
+/// Every setting defaults to how the CLI behaves without flags.
+#[derive(Clone, Default)]
+pub struct ItchClientBuilder {
+    api_key: Option<String>,
+    base_url: Option<reqwest::Url>,
+    legacy_base_url: Option<reqwest::Url>,
+    api: ApiVersion,
+    user_agent: Option<String>,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    proxy: Option<reqwest::Url>,
+    no_proxy: Option<NoProxy>,
+    /// Text of the PAC script choosing proxies
+    proxy_pac: Option<String>,
+    /// Proxy choices shared by every client, built by `share_connections`
+    proxy_rules: Option<std::sync::Arc<ProxyRules>>,
+    retry: RetryPolicy,
+    request_delay: Option<Duration>,
+    rate_limit: Option<f64>,
+    max_requests_per_minute: Option<usize>,
+    page_size: Option<u64>,
+    http_client: Option<Client>,
+    no_cache: bool,
+    /// The config profile whose cache is used
+    profile: Option<String>,
+    refresh_uploads: bool,
+    offline: bool,
+    cdn_rewrites: Vec<CdnRewrite>,
+    cdn_insecure: bool,
+    host_policies: Vec<(String, HostPolicy)>,
+    /// Directory responses are recorded to, and whether names are kept in them
+    record_fixtures: Option<(PathBuf, bool)>,
+    replay_fixtures: Option<PathBuf>,
+    /// Client for rewritten hosts without certificate checks, built by `share_connections`
+    insecure_http_client: Option<Client>,
+}
+
+/// Like a derived `Debug`, with the API key left out so builders can be logged
+impl std::fmt::Debug for ItchClientBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ItchClientBuilder")
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("base_url", &self.base_url)
+            .field("legacy_base_url", &self.legacy_base_url)
+            .field("api", &self.api)
+            .field("user_agent", &self.user_agent)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("timeout", &self.timeout)
+            .field("proxy", &self.proxy)
+            .field("no_proxy", &self.no_proxy)
+            .field("proxy_pac", &self.proxy_pac)
+            .field("proxy_rules", &self.proxy_rules)
+            .field("retry", &self.retry)
+            .field("request_delay", &self.request_delay)
+            .field("rate_limit", &self.rate_limit)
+            .field("max_requests_per_minute", &self.max_requests_per_minute)
+            .field("page_size", &self.page_size)
+            .field("http_client", &self.http_client)
+            .field("no_cache", &self.no_cache)
+            .field("profile", &self.profile)
+            .field("refresh_uploads", &self.refresh_uploads)
+            .field("offline", &self.offline)
+            .field("cdn_rewrites", &self.cdn_rewrites)
+            .field("cdn_insecure", &self.cdn_insecure)
+            .field("host_policies", &self.host_policies)
+            .field("record_fixtures", &self.record_fixtures)
+            .field("replay_fixtures", &self.replay_fixtures)
+            .field("insecure_http_client", &self.insecure_http_client)
+            .finish()
+    }
+}
+
+impl ItchClientBuilder {
+    /// The key requests are authenticated with, required
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Root every endpoint is built on, [`DEFAULT_API_BASE_URL`] by default
+    pub fn base_url(mut self, base_url: reqwest::Url) -> Self {
+        self.base_url = Some(base_url);
+        self
+    }
+
+    /// Root of the legacy server-side API, [`DEFAULT_LEGACY_API_BASE_URL`] by default
+    pub fn legacy_base_url(mut self, base_url: reqwest::Url) -> Self {
+        self.legacy_base_url = Some(base_url);
+        self
+    }
+
+    /// Which API requests go to, [`ApiVersion::Auto`] by default
+    pub fn api_version(mut self, api: ApiVersion) -> Self {
+        self.api = api;
+        self
+    }
+
+    /// User-Agent sent with every request, [`DEFAULT_USER_AGENT`] by default
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// How long to wait for a connection, unlimited by default
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// How long an API request may take in total, unlimited by default
+    ///
+    /// Downloads aren't limited, a large file takes as long as it takes.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send every request through this proxy instead of the one from the environment
+    pub fn proxy(mut self, proxy: reqwest::Url) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Hosts requests go to directly whatever the proxy, instead of the ones in `NO_PROXY`
+    ///
+    /// Applies to the API host and the storage hosts downloads are redirected to alike.
+    pub fn no_proxy(mut self, no_proxy: NoProxy) -> Self {
+        self.no_proxy = Some(no_proxy);
+        self
+    }
+
+    /// Let this proxy auto-config script choose the proxy of each host, taking precedence over
+    /// [`proxy`](Self::proxy)
+    ///
+    /// See [`Pac`] for what scripts can use. A script that fails for a host sends its requests
+    /// directly.
+    pub fn proxy_pac(mut self, script: impl Into<String>) -> Self {
+        self.proxy_pac = Some(script.into());
+        self
+    }
+
+    /// Where a request to `url` would be sent, directly or through which proxy
+    pub async fn proxy_route(&self, url: &reqwest::Url) -> Result<ProxyRoute> {
+        Ok(self.proxy_rules()?.prepare(url).await)
+    }
+
+    /// The proxy choices the settings describe, the shared ones once there are some
+    fn proxy_rules(&self) -> Result<std::sync::Arc<ProxyRules>> {
+        if let Some(rules) = &self.proxy_rules {
+            return Ok(rules.clone());
+        }
+        let pac = match &self.proxy_pac {
+            Some(script) => Some(Pac::parse(script).map_err(|e| ItchError::Config {
+                message: format!("the PAC script can't be used: {}", e),
+            })?),
+            None => None,
+        };
+        Ok(std::sync::Arc::new(ProxyRules::new(
+            self.proxy.clone(),
+            pac,
+            self.no_proxy.clone(),
+        )))
+    }
+
+    /// How rate limited requests are retried, see [`RetryPolicy::default`]
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Pause before listing a game's uploads or starting a download, 1 second by default
+    pub fn request_delay(mut self, delay: Duration) -> Self {
+        self.request_delay = Some(delay);
+        self
+    }
+
+    /// Send at most this many requests per second across every clone of the client
+    pub fn rate_limit(mut self, requests_per_second: f64) -> Self {
+        self.rate_limit = Some(requests_per_second);
+        self
+    }
+
+    /// Send at most this many requests in any [`REQUEST_WINDOW`] across every clone of the
+    /// client, later ones waiting for room instead of going out in a burst
+    ///
+    /// Combines with [`rate_limit`](Self::rate_limit), which only spaces requests out.
+    pub fn max_requests_per_minute(mut self, requests: usize) -> Self {
+        self.max_requests_per_minute = Some(requests);
+        self
+    }
+
+    /// Library keys asked for per page, [`DEFAULT_PAGE_SIZE`] by default
+    ///
+    /// Larger pages mean fewer requests for big libraries. If itch.io rejects the size the
+    /// library is fetched at its own page size instead.
+    pub fn page_size(mut self, keys: u64) -> Self {
+        self.page_size = Some(keys);
+        self
+    }
+
+    /// Send requests through a pre-built client, e.g. one set up for a mock server
+    ///
+    /// The client's own settings apply, so this can't be combined with
+    /// [`user_agent`](Self::user_agent), [`connect_timeout`](Self::connect_timeout) or
+    /// the proxy settings. Give it `redirect::Policy::none()` to have downloads follow the
+    /// redirect to the storage host themselves, without the API key, as the default client does.
+    pub fn http_client(mut self, client: Client) -> Self {
+        self.http_client = Some(client);
+        self
+    }
+
+    /// Whether metadata responses are cached in the user's cache directory, on by default
+    pub fn cache(mut self, enabled: bool) -> Self {
+        self.no_cache = !enabled;
+        self
+    }
+
+    /// Keep cached responses apart from other profiles', see [`Layout`](crate::layout::Layout)
+    pub fn profile(mut self, name: impl Into<String>) -> Self {
+        self.profile = Some(name.into());
+        self
+    }
+
+    /// Whether [`ItchClient::get_key_uploads`] always asks itch.io, ignoring stored listings
+    pub fn refresh_uploads(mut self, refresh: bool) -> Self {
+        self.refresh_uploads = refresh;
+        self
+    }
+
+    /// Whether every request is refused, metadata only coming from the cache
+    ///
+    /// Cached responses and stored uploads listings are used however old they are, anything
+    /// else fails with [`ItchError::Offline`]. Needs the cache.
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Fetch downloads that itch.io redirects to `rewrite.from` from `rewrite.to` instead
+    ///
+    /// Each call adds a rewrite. itch.io is still asked for every download, so it authorizes it
+    /// and signs the URL, only the transfer itself goes to the other host, resumes included.
+    pub fn cdn_rewrite(mut self, rewrite: CdnRewrite) -> Self {
+        self.cdn_rewrites.push(rewrite);
+        self
+    }
+
+    /// Whether the hosts downloads are rewritten to are trusted without checking their TLS
+    /// certificate, off by default
+    ///
+    /// Only the rewritten transfers skip the check, itch.io and every other host are verified as
+    /// usual. Needs at least one [`cdn_rewrite`](Self::cdn_rewrite).
+    pub fn cdn_insecure(mut self, insecure: bool) -> Self {
+        self.cdn_insecure = insecure;
+        self
+    }
+
+    /// Treat downloads from the storage hosts `pattern` covers according to `policy`
+    ///
+    /// `pattern` is a host, covering its subdomains too, or `*` for every host, see
+    /// [`host_matches`]. The most specific pattern covering the host a download is redirected to
+    /// applies, filled in from `*`. Each call adds a pattern, replacing an earlier one that's the
+    /// same.
+    pub fn host_policy(mut self, pattern: impl AsRef<str>, policy: HostPolicy) -> Self {
+        let pattern = normalize_host_pattern(pattern.as_ref());
+        self.host_policies
+            .retain(|(existing, _)| *existing != pattern);
+        self.host_policies.push((pattern, policy));
+        self
+    }
+
+    /// Save a sanitized copy of every API response to `dir`, one numbered JSON file each, for
+    /// bug reports and as fixtures for [`ItchClientBuilder::replay_fixtures`]
+    ///
+    /// Keys, tokens and email addresses are always stripped. Unless `keep_names`, the names of
+    /// games, people and files and the URLs of pages are replaced with short hashes, the same
+    /// wherever a value appears.
+    pub fn record_fixtures(mut self, dir: impl Into<PathBuf>, keep_names: bool) -> Self {
+        self.record_fixtures = Some((dir.into(), keep_names));
+        self
+    }
+
+    /// Answer API requests from the fixtures recorded in `dir` instead of the network
+    ///
+    /// Nothing is sent at all: downloads fail and a request with no recorded response fails with
+    /// [`ItchError::MissingFixture`]. The cache isn't used either, so runs are reproducible.
+    pub fn replay_fixtures(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.replay_fixtures = Some(dir.into());
+        self
+    }
+
+    /// The root endpoints will be built on
+    pub fn api_base_url(&self) -> reqwest::Url {
+        self.base_url
+            .clone()
+            .unwrap_or_else(|| DEFAULT_API_BASE_URL.parse().unwrap())
+    }
+
+    /// Build the HTTP client now, so every client built from clones of this builder shares it
+    ///
+    /// Clones of one HTTP client share its connection pool, which saves a TLS handshake per
+    /// request when several [`ItchClient`]s are created for the same run.
+    pub fn share_connections(mut self) -> Result<Self> {
+        if self.http_client.is_none() {
+            self.proxy_rules = Some(self.proxy_rules()?);
+            if self.cdn_insecure {
+                self.insecure_http_client = Some(self.build_http_client(true)?);
+            }
+            self.http_client = Some(self.build_http_client(false)?);
+            self.user_agent = None;
+            self.connect_timeout = None;
+            self.proxy = None;
+            self.no_proxy = None;
+            self.proxy_pac = None;
+        }
+        Ok(self)
+    }
+
+    /// The HTTP client the settings describe, used when none was given
+    ///
+    /// `insecure` accepts any certificate, for the hosts `cdn_insecure` applies to.
+    fn build_http_client(&self, insecure: bool) -> Result<Client> {
+        if self.connect_timeout == Some(Duration::ZERO) {
+            return Err(ItchError::Config {
+                message: "timeouts must be longer than zero".to_string(),
+            });
+        }
+        // Shared by API calls and downloads alike, so both carry the User-Agent. Metadata requests
+        // come in bursts of hundreds, so connections are kept around between them, and HTTP/2 is
+        // negotiated when the server offers it
+        let mut builder = Client::builder()
+            .user_agent(self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
+            .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+            .tcp_keepalive(TCP_KEEPALIVE)
+            .http2_adaptive_window(true)
+            .redirect(reqwest::redirect::Policy::custom(|attempt| {
+                // Downloads redirect to a storage host, which `request_download` follows
+                // itself so the API key stays with itch.io
+                if attempt.previous().first().is_some_and(is_download_endpoint) {
+                    attempt.stop()
+                } else if attempt.previous().len() > 10 {
+                    attempt.error("too many redirects")
+                } else {
+                    attempt.follow()
+                }
+            }));
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if insecure {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(proxy) = self.proxy.clone() {
+            reqwest::Proxy::all(proxy).map_err(|source| ItchError::Request {
+                context: "Invalid proxy",
+                source,
+            })?;
+        }
+        // Without any proxy reqwest's own lookup is kept, which also knows the system settings
+        // of macOS and Windows
+        let rules = self.proxy_rules()?;
+        if rules.uses_proxies() {
+            builder = builder.proxy(reqwest::Proxy::custom(move |url| match rules.route(url) {
+                ProxyRoute::Direct => None,
+                ProxyRoute::Proxy(proxy) => Some(proxy),
+            }));
+        }
+        builder.build().map_err(|source| ItchError::Request {
+            context: "Failed to set up the HTTP client",
+            source,
+        })
+    }
+
+    /// Check the settings fit together and create the client
+    pub fn build(mut self) -> Result<ItchClient> {
+        let invalid = |message: &str| ItchError::Config {
+            message: message.to_string(),
+        };
+        let base_url = self.api_base_url();
+        let legacy_base_url = self
+            .legacy_base_url
+            .clone()
+            .unwrap_or_else(|| DEFAULT_LEGACY_API_BASE_URL.parse().unwrap());
+        let Some(api_key) = self.api_key.clone() else {
+            return Err(invalid("an API key is required"));
+        };
+        if [&base_url, &legacy_base_url]
+            .iter()
+            .any(|url| !matches!(url.scheme(), "http" | "https"))
+        {
+            return Err(invalid("the base URL must be http or https"));
+        }
+        if self.timeout == Some(Duration::ZERO) {
+            return Err(invalid("timeouts must be longer than zero"));
+        }
+        if self
+            .rate_limit
+            .is_some_and(|rate| !(rate.is_finite() && rate > 0.0))
+        {
+            return Err(invalid(
+                "the rate limit must be more than zero requests per second",
+            ));
+        }
+        if self.max_requests_per_minute == Some(0) {
+            return Err(invalid(
+                "the request ceiling must be at least 1 request per minute",
+            ));
+        }
+        if self.page_size == Some(0) {
+            return Err(invalid("the page size must be at least 1"));
+        }
+        for (pattern, policy) in &self.host_policies {
+            if pattern.is_empty() || pattern.contains(['/', ':', ' ']) {
+                return Err(invalid(&format!("`{}` isn't a host or `*`", pattern)));
+            }
+            if policy.max_concurrent == Some(0) {
+                return Err(invalid(&format!(
+                    "downloads at once from {} must be at least 1",
+                    pattern
+                )));
+            }
+            if policy.timeout == Some(Duration::ZERO) {
+                return Err(invalid("timeouts must be longer than zero"));
+            }
+        }
+
+        // The HTTP clients and the API client pick routes from the same rules
+        let proxy_rules = self.proxy_rules()?;
+        self.proxy_rules = Some(proxy_rules.clone());
+        let client = match self.http_client.clone() {
+            Some(_)
+                if self.user_agent.is_some()
+                    || self.connect_timeout.is_some()
+                    || self.proxy.is_some()
+                    || self.no_proxy.is_some()
+                    || self.proxy_pac.is_some() =>
+            {
+                return Err(invalid(
+                    "a pre-built HTTP client can't be combined with a user agent, connect timeout \
+                     or proxy settings, set those on the client instead",
+                ));
+            }
+            Some(client) => client,
+            None => self.build_http_client(false)?,
+        };
+        if self.cdn_insecure && self.cdn_rewrites.is_empty() {
+            return Err(invalid(
+                "skipping certificate checks only applies to rewritten CDN hosts, so it needs a \
+                 CDN rewrite",
+            ));
+        }
+        let insecure_client = match (self.cdn_insecure, self.insecure_http_client.clone()) {
+            (false, _) => None,
+            (true, Some(client)) => Some(client),
+            (true, None) => Some(self.build_http_client(true)?),
+        };
+
+        if self.record_fixtures.is_some() && self.replay_fixtures.is_some() {
+            return Err(invalid(
+                "fixtures can be recorded or replayed, not both at once",
+            ));
+        }
+        let fixture_recorder = match &self.record_fixtures {
+            Some((dir, keep_names)) => Some(std::sync::Arc::new(fixtures::FixtureRecorder::new(
+                dir,
+                *keep_names,
+            )?)),
+            None => None,
+        };
+        let fixture_replay = match &self.replay_fixtures {
+            Some(dir) => Some(std::sync::Arc::new(fixtures::FixtureReplay::load(dir)?)),
+            None => None,
+        };
+
+        if self.offline && (self.no_cache || self.refresh_uploads) {
+            return Err(invalid(
+                "offline mode only has the cache to go on, so it can't be combined with \
+                 disabling the cache or refreshing uploads listings",
+            ));
+        }
+        let cache = if self.no_cache || fixture_replay.is_some() {
+            None
+        } else {
+            cache::HttpCache::default_dir(self.profile.as_deref())
+                .and_then(|dir| cache::HttpCache::open(dir, &api_key).ok())
+                .map(std::sync::Arc::new)
+        };
+        Ok(ItchClient {
+            client,
+            api_key,
+            cache,
+            refresh_uploads: self.refresh_uploads,
+            offline: self.offline,
+            offline_since: Default::default(),
+            http_dump: http_dump::recorder(),
+            fixture_recorder,
+            fixture_replay,
+            base_url,
+            legacy_base_url,
+            api: self.api,
+            legacy_detected: Default::default(),
+            modern_accepted: Default::default(),
+            timeout: self.timeout,
+            retry: self.retry,
+            request_delay: self.request_delay.unwrap_or(Duration::from_millis(1000)),
+            page_size: self
+                .page_size
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .min(MAX_PAGE_SIZE),
+            page_size_rejected: Default::default(),
+            limiter: self.rate_limit.map(|rate| {
+                std::sync::Arc::new(RateLimiter {
+                    interval: Duration::from_secs_f64(1.0 / rate),
+                    next: tokio::sync::Mutex::new(tokio::time::Instant::now()),
+                })
+            }),
+            window_limiter: self.max_requests_per_minute.map(|max| {
+                std::sync::Arc::new(WindowLimiter {
+                    max,
+                    sent: Default::default(),
+                })
+            }),
+            usage: Default::default(),
+            clock_skew: Default::default(),
+            skew_logged: Default::default(),
+            game_details: Default::default(),
+            cdn_rewrites: self.cdn_rewrites.into(),
+            insecure_client,
+            proxy_rules,
+            host_policies: self.host_policies.into(),
+            host_slots: Default::default(),
+        })
+    }
+}
+
+/// What [`ItchClient::download_file`] reports while it works
+#[derive(Clone, Debug)]
+pub enum DownloadProgress {
+    /// The server answered, `total` being 0 when it didn't say how big the file is
+    Started {
+        /// The name the file is saved under
+        filename: String,
+        total: u64,
+    },
+    /// Bytes received so far
+    Bytes(u64),
+    /// Rate limited, trying again after this long
+    RetryScheduled(Duration),
+    /// The transfer broke off, picking up where it stopped after this long
+    Resuming(Duration),
+    /// Every byte is on disk and the file is in place
+    Done,
+}
+
+/// A file that finished downloading and now sits in its destination directory
+pub struct DownloadedFile {
+    pub filename: String,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the contents
+    pub sha256: String,
+    /// Hard linked to an identical file already in the mirror instead of stored again
+    pub linked: bool,
+}
+
+/// The library as far as it could be fetched, see [`ItchClient::list_owned_keys_partial`]
+#[derive(Debug)]
+pub struct PartialLibrary {
+    pub keys: Vec<OwnedKey>,
+    /// Why fetching stopped before the last page, `None` when every page arrived
+    pub error: Option<ItchError>,
+}
+
+impl PartialLibrary {
+    /// Whether every page of the library arrived
+    pub fn is_complete(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// How downloaded files are staged and named
+#[derive(Clone, Default)]
+pub struct SaveOptions {
+    /// Where partial downloads are staged, the output directory when unset
+    pub temp_dir: Option<PathBuf>,
+    /// Save files under the name from the uploads listing, ignoring Content-Disposition
+    pub trust_listed_names: bool,
+    /// Content index used to hard link identical downloads, when deduplicating
+    pub dedupe: Option<std::sync::Arc<std::sync::Mutex<HashIndex>>>,
+    /// The size the uploads listing gave, checked against when the server doesn't send one
+    pub expected_size: Option<u64>,
+}
+
+/// How [`ItchClient::lookup_download_key`] finds a key
+#[derive(Clone, Debug)]
+pub enum KeyLookup {
+    /// The key string itself, as found in the key's download URL
+    Key(String),
+    /// The email address the key was sent to
+    Email(String),
+}
+
+/// Which purchases [`ItchClient::list_purchases`] returns, every one by default
+#[derive(Clone, Debug, Default)]
+pub struct PurchaseFilter {
+    /// Only purchases made with this email address
+    pub email: Option<String>,
+    /// Only purchases made by this itch.io account
+    pub user_id: Option<u64>,
+}
+
+/// Add a page's new items to `into`, returning whether the listing goes on past `page`
+///
+/// A listing stops like the library does: at a short page, at once when the page size is 0, at a
+/// page holding only ids already seen, which means the API has started over, and after
+/// [`MAX_LIBRARY_PAGES`].
+fn next_page<T>(
+    page: u64,
+    items: Vec<T>,
+    per_page: u64,
+    seen: &mut HashSet<u64>,
+    id: impl Fn(&T) -> u64,
+    into: &mut Vec<T>,
+) -> bool {
+    let count = items.len();
+    let before = into.len();
+    into.extend(items.into_iter().filter(|item| seen.insert(id(item))));
+    if count > 0 && into.len() == before {
+        warn!(page, "Page only repeated items already seen, stopping");
+        return false;
+    }
+    if page >= MAX_LIBRARY_PAGES {
+        warn!(
+            "Stopped after {} pages, the listing may be incomplete",
+            MAX_LIBRARY_PAGES
+        );
+        return false;
+    }
+    per_page != 0 && count >= per_page as usize
+}
+
+/// Client for the itch.io API, authenticated with an API key
+#[derive(Clone)]
+pub struct ItchClient {
+    client: Client,
+    api_key: String,
+    /// Cache of metadata responses, unavailable when there's no usable cache directory
+    cache: Option<std::sync::Arc<cache::HttpCache>>,
+    /// Whether stored uploads listings are ignored
+    refresh_uploads: bool,
+    /// Whether requests are refused and only the cache is used
+    offline: bool,
+    /// When the oldest cached data used offline was fetched
+    offline_since: std::sync::Arc<std::sync::Mutex<Option<Timestamp>>>,
+    /// Where raw responses are written with `--debug-http`
+    http_dump: Option<std::sync::Arc<http_dump::HttpDump>>,
+    /// Where sanitized responses are written with `--record-fixtures`
+    fixture_recorder: Option<std::sync::Arc<fixtures::FixtureRecorder>>,
+    /// Recorded responses served instead of the network's with `--replay-fixtures`
+    fixture_replay: Option<std::sync::Arc<fixtures::FixtureReplay>>,
+    /// Root every endpoint is built on
+    base_url: reqwest::Url,
+    /// Root of the legacy server-side API
+    legacy_base_url: reqwest::Url,
+    api: ApiVersion,
+    /// Set once the current API rejected the key and the legacy one took it
+    legacy_detected: std::sync::Arc<AtomicBool>,
+    /// Set once the current API accepted the key, after which a 401/403 is about the resource
+    modern_accepted: std::sync::Arc<AtomicBool>,
+    /// Limit on each API request, downloads aren't limited
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
+    /// Pause before listing uploads or starting a download
+    request_delay: Duration,
+    /// Library keys asked for per page
+    page_size: u64,
+    /// Set once itch.io refused the page size, after which pages are fetched at its own
+    page_size_rejected: std::sync::Arc<AtomicBool>,
+    limiter: Option<std::sync::Arc<RateLimiter>>,
+    window_limiter: Option<std::sync::Arc<WindowLimiter>>,
+    usage: std::sync::Arc<UsageMeter>,
+    /// Seconds the local clock is ahead of itch.io's, from the last response with a `Date`
+    clock_skew: std::sync::Arc<std::sync::Mutex<Option<i64>>>,
+    /// Set once the skew has been logged, so it's only logged once
+    skew_logged: std::sync::Arc<AtomicBool>,
+    /// Answers from the game endpoint, `None` for games that are gone
+    game_details:
+        std::sync::Arc<std::sync::Mutex<std::collections::HashMap<u64, Option<GameDetails>>>>,
+    /// Storage hosts downloads are fetched from another host instead
+    cdn_rewrites: std::sync::Arc<[CdnRewrite]>,
+    /// Used instead of `client` for rewritten hosts when their certificates aren't checked
+    insecure_client: Option<Client>,
+    /// Which proxy each request goes through
+    proxy_rules: std::sync::Arc<ProxyRules>,
+    /// Storage host patterns and how downloads from them are treated
+    host_policies: std::sync::Arc<[(String, HostPolicy)]>,
+    /// The download slots of each host with a `max_concurrent`
+    host_slots: std::sync::Arc<
+        std::sync::Mutex<std::collections::HashMap<String, std::sync::Arc<tokio::sync::Semaphore>>>,
+    >,
+}
+
+impl ItchClient {
+    /// A client for `api_key` with the default settings
+    pub fn new(api_key: String) -> Self {
+        Self::builder()
+            .api_key(api_key)
+            .build()
+            .expect("the default client settings are valid")
+    }
+
+    /// Set up a client with anything other than the default settings
+    pub fn builder() -> ItchClientBuilder {
+        ItchClientBuilder::default()
+    }
+
+    /// The underlying HTTP client, for requests outside the API that should carry the same
+    /// User-Agent
+    pub fn http(&self) -> &Client {
+        &self.client
+    }
+
+    /// Full URL of an API endpoint, `path` being relative to the base URL
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url.as_str().trim_end_matches('/'), path)
+    }
+
+    /// Whether the client refuses requests, see [`ItchClientBuilder::offline`]
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// When the oldest cached data this client used offline was fetched, `None` when it used
+    /// none or its age isn't known
+    pub fn offline_since(&self) -> Option<Timestamp> {
+        *self.offline_since.lock().unwrap()
+    }
+
+    /// Note that cached data fetched at `fetched_at` stood in for a request
+    fn used_cached(&self, fetched_at: Option<Timestamp>) {
+        let Some(fetched_at) = fetched_at.filter(|_| self.offline) else {
+            return;
+        };
+        let mut since = self.offline_since.lock().unwrap();
+        if since.is_none_or(|since| fetched_at.timestamp() < since.timestamp()) {
+            *since = Some(fetched_at);
+        }
+    }
+
+    /// Whether the key only works with the legacy API, as far as we know so far
+    pub fn uses_legacy_api(&self) -> bool {
+        match self.api {
+            ApiVersion::Auto => self.legacy_detected.load(Ordering::Relaxed),
+            ApiVersion::Modern => false,
+            ApiVersion::Legacy => true,
+        }
+    }
+
+    /// A URL with the key blanked out, for logs and `--debug-http`
+    ///
+    /// The legacy API takes the key in the path, so its URLs aren't safe to log as they are.
+    pub(crate) fn redact(&self, url: &str) -> String {
+        url.replace(&self.api_key, "<api-key>")
+    }
+
+    /// How many seconds the local clock is ahead of itch.io's, negative when behind
+    ///
+    /// Measured from the `Date` header of the last response, `None` before any arrived. The
+    /// header only has whole seconds and includes the request's latency, so a second or two is
+    /// noise.
+    pub fn clock_skew(&self) -> Option<i64> {
+        *self.clock_skew.lock().unwrap()
+    }
+
+    /// Note how far off the local clock is going by a response's `Date`
+    fn observe_date(&self, headers: &reqwest::header::HeaderMap) {
+        let Some(server) = server_date(headers) else {
+            return;
+        };
+        let skew = timestamp::now().timestamp() - server.timestamp();
+        *self.clock_skew.lock().unwrap() = Some(skew);
+        if skew.unsigned_abs() > CLOCK_SKEW_THRESHOLD.as_secs()
+            && !self.skew_logged.swap(true, Ordering::Relaxed)
+        {
+            warn!(
+                skew_secs = skew,
+                "The local clock is {}s {} itch.io's, signed download links may be rejected",
+                skew.unsigned_abs(),
+                if skew > 0 { "ahead of" } else { "behind" }
+            );
+        }
+    }
+
+    /// Requests made so far by this client and its clones, and how many were throttled
+    pub fn api_usage(&self) -> ApiUsage {
+        self.usage.snapshot()
+    }
+
+    /// Wait for the rate limits, if there are any
+    async fn throttle(&self) {
+        if self.window_limiter.is_none() && self.limiter.is_none() {
+            return;
+        }
+        async {
+            if let Some(limiter) = &self.window_limiter {
+                limiter.wait().await;
+            }
+            if let Some(limiter) = &self.limiter {
+                limiter.wait().await;
+            }
+        }
+        .instrument(tracing::debug_span!("rate_limit_wait"))
+        .await
+    }
+
+    /// Make an API request with retry logic for 429 errors
+    async fn get_with_retry(
+        &self,
+        url: &str,
+        query_params: &[(&str, u64)],
+        headers: &reqwest::header::HeaderMap,
+    ) -> Result<reqwest::Response> {
+        // Only legacy URLs carry the key, the current API takes it in a header
+        debug!(url = %self.redact(url), ?query_params, "GET");
+        let started = Instant::now();
+        let span = tracing::debug_span!("request", url = %self.redact(url), retries = Empty);
+        let response = self
+            .send_with_retry(
+                || {
+                    let request = self
+                        .client
+                        .get(url)
+                        .bearer_auth(&self.api_key)
+                        .headers(headers.clone())
+                        .query(query_params);
+                    match self.timeout {
+                        Some(timeout) => request.timeout(timeout),
+                        None => request,
+                    }
+                },
+                "Failed to send request to itch.io API",
+                &CancellationToken::new(),
+                |_| {},
+            )
+            .instrument(span)
+            .await?;
+        debug!(
+            url = %self.redact(url),
+            status = %response.status(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Response"
+        );
+        Ok(response)
+    }
+
+    /// Send the request `build` makes, sending a fresh one while itch.io rate limits it (429)
+    ///
+    /// Every request, API call or download, goes through here so they share one retry policy.
+    /// The wait is whatever `Retry-After` asks for when itch sends it, `on_retry` hearing of each.
+    async fn send_with_retry(
+        &self,
+        build: impl Fn() -> reqwest::RequestBuilder,
+        context: &'static str,
+        cancel: &CancellationToken,
+        on_retry: impl FnMut(Duration),
+    ) -> Result<reqwest::Response> {
+        self.send_to_storage(build, context, &StorageTarget::default(), cancel, on_retry)
+            .await
+    }
+
+    /// [`send_with_retry`](Self::send_with_retry) for a request to a download's storage host,
+    /// under the host's retries and timeout
+    ///
+    /// A request the host doesn't answer within the timeout is retried like a rate limited one.
+    async fn send_to_storage(
+        &self,
+        build: impl Fn() -> reqwest::RequestBuilder,
+        context: &'static str,
+        target: &StorageTarget,
+        cancel: &CancellationToken,
+        mut on_retry: impl FnMut(Duration),
+    ) -> Result<reqwest::Response> {
+        if self.offline {
+            return Err(ItchError::Offline { context });
+        }
+        if let Some(replay) = &self.fixture_replay {
+            // Only API responses are recorded, anything else can't be answered
+            return Err(replay.missing(context));
+        }
+        // A PAC script may look the host up, which is done off the async workers before sending
+        if let Ok(request) = build().build() {
+            self.proxy_rules.prepare(request.url()).await;
+        }
+        let retry = target.retry(&self.retry);
+        let mut attempt = 0;
+
+        loop {
+            let sent = cancellable(cancel, async {
+                self.throttle().await;
+                let send = build().send();
+                let sent = match target.policy.timeout {
+                    Some(timeout) => match tokio::time::timeout(timeout, send).await {
+                        Ok(sent) => sent,
+                        Err(_) => return Ok(None),
+                    },
+                    None => send.await,
+                };
+                sent.map(Some).map_err(|source| ItchError::Request {
+                    context,
+                    source: source.without_url(),
+                })
+            })
+            .await?;
+            let retry_after = match &sent {
+                Some(response) => {
+                    self.observe_date(response.headers());
+                    let throttled = response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                    self.usage.record(throttled);
+                    if !throttled {
+                        return Ok(sent.unwrap());
+                    }
+                    retry_after(response.headers())
+                }
+                None => None,
+            };
+
+            attempt += 1;
+            // Counted on whichever span the caller opened, `request` or `download`
+            Span::current().record("retries", attempt);
+            if attempt > retry.max_retries {
+                return Err(match sent {
+                    Some(_) => ItchError::RateLimited {
+                        retries: retry.max_retries,
+                    },
+                    None => ItchError::io(context, timed_out(target.policy.timeout)),
+                });
+            }
+            let retry_delay = retry.delay_after(attempt, retry_after);
+            warn!(
+                "{}, retrying in {:?} (attempt {}/{})",
+                if sent.is_some() {
+                    "Rate limited (429)"
+                } else {
+                    "No answer from the storage host"
+                },
+                retry_delay,
+                attempt,
+                retry.max_retries
+            );
+            on_retry(retry_delay);
+            cancellable(cancel, async {
+                sleep(retry_delay).await;
+                Ok(())
+            })
+            .await?;
+        }
+    }
+
+    /// Write an exchange out for `--debug-http`, returning the file when one was written
+    fn dump(
+        &self,
+        url: &reqwest::Url,
+        status: reqwest::StatusCode,
+        headers: &reqwest::header::HeaderMap,
+        body: &str,
+    ) -> Option<PathBuf> {
+        let result = self
+            .http_dump
+            .as_ref()?
+            .record("GET", url, status, headers, body);
+        match result {
+            Ok(path) => Some(path),
+            Err(e) => {
+                warn!("{:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Save an exchange for `--record-fixtures`, `request` being its [`fixtures::request_key`]
+    fn record_fixture(&self, request: &str, status: reqwest::StatusCode, body: &str) {
+        let Some(recorder) = &self.fixture_recorder else {
+            return;
+        };
+        if let Err(e) = recorder.record(request, status, body) {
+            warn!("{:#}", e);
+        }
+    }
+
+    /// Answer a request from the replayed fixtures the way the API's response would be handled
+    fn replay_json<T: DeserializeOwned>(
+        &self,
+        replay: &fixtures::FixtureReplay,
+        endpoint: &str,
+        request: &str,
+    ) -> Result<T> {
+        let (path, fixture) = replay.respond(request)?;
+        debug!(request, fixture = %path.display(), "Replaying recorded response");
+        let status = reqwest::StatusCode::from_u16(fixture.status)
+            .unwrap_or(reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+        let body = fixture.body_text();
+        if matches!(
+            status,
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+        ) && !crate::error::mentions_key_limit(&body)
+        {
+            return Err(ItchError::Auth { status, body });
+        }
+        if !status.is_success() {
+            return Err(ItchError::Http {
+                context: "API request",
+                status,
+                body: json::shorten(body),
+                saved_to: Some(path),
+            });
+        }
+        json::parse(endpoint, &body).map_err(|mut e| {
+            if let ItchError::Decode { saved_to, .. } = &mut e {
+                *saved_to = Some(path);
+            }
+            e
+        })
+    }
+
+    /// Fetch and parse a metadata endpoint, revalidating any cached copy instead of refetching it
+    async fn get_json<T: DeserializeOwned>(&self, url: &str, query: &[(&str, u64)]) -> Result<T> {
+        if self.uses_legacy_api() {
+            return Err(ItchError::LegacyKey);
+        }
+        let request = fixtures::request_key(&self.base_url, url, query);
+        if let Some(replay) = &self.fixture_replay {
+            return self.replay_json(replay, url, &request);
+        }
+        let cached = self.cache.as_ref().and_then(|cache| cache.get(url, query));
+        if self.offline
+            && let Some(cached) = cached
+        {
+            debug!(url, "Offline, using the cached response");
+            self.used_cached(cached.fetched_at);
+            return json::parse(url, &cached.body);
+        }
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(cached) = &cached {
+            let validators = [
+                (reqwest::header::IF_NONE_MATCH, &cached.etag),
+                (reqwest::header::IF_MODIFIED_SINCE, &cached.last_modified),
+            ];
+            for (name, value) in validators {
+                if let Some(value) = value.as_deref().and_then(|value| value.parse().ok()) {
+                    headers.insert(name, value);
+                }
+            }
+        }
+
+        let response = self.get_with_retry(url, query, &headers).await?;
+        let status = response.status();
+        // Kept for --debug-http, reading the body consumes the response
+        let response_url = response.url().clone();
+        let response_headers = response.headers().clone();
+
+        let body = match cached {
+            Some(mut cached) if status == reqwest::StatusCode::NOT_MODIFIED => {
+                debug!(url, "Cached response is still current");
+                // Stored again so the entry's age says when it was last known to be current
+                if let Some(cache) = &self.cache {
+                    cached.fetched_at = Some(timestamp::now());
+                    let _ = cache.put(url, query, &cached);
+                }
+                cached.body
+            }
+            _ if status.is_success() => {
+                let header = |name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string)
+                };
+                let etag = header(reqwest::header::ETAG);
+                let last_modified = header(reqwest::header::LAST_MODIFIED);
+                let body = response.text().await.map_err(|source| ItchError::Request {
+                    context: "Failed to read API response",
+                    source,
+                })?;
+
+                // Without a validator there'd be no way to reuse the entry
+                let revalidatable = etag.is_some() || last_modified.is_some();
+                if let Some(cache) = self.cache.as_ref().filter(|_| revalidatable) {
+                    let entry = cache::CachedResponse {
+                        etag,
+                        last_modified,
+                        body: body.clone(),
+                        fetched_at: Some(timestamp::now()),
+                    };
+                    // Caching is only an optimisation, a failure to write shouldn't fail the run
+                    let _ = cache.put(url, query, &entry);
+                }
+                body
+            }
+            _ => {
+                let text = response.text().await.unwrap_or_default();
+                let dumped = self.dump(&response_url, status, &response_headers, &text);
+                self.record_fixture(&request, status, &text);
+                // A refused download key is about the game, not the API key
+                if matches!(
+                    status,
+                    reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+                ) && !crate::error::mentions_key_limit(&text)
+                {
+                    if self.api == ApiVersion::Auto
+                        && !self.modern_accepted.load(Ordering::Relaxed)
+                        && self.detect_legacy_key().await
+                    {
+                        return Err(ItchError::LegacyKey);
+                    }
+                    return Err(ItchError::Auth { status, body: text });
+                }
+                return Err(ItchError::Http {
+                    context: "API request",
+                    status,
+                    body: json::shorten(text),
+                    saved_to: dumped,
+                });
+            }
+        };
+
+        self.modern_accepted.store(true, Ordering::Relaxed);
+        // A 304 dumps the cached body it stands for, since that's what gets parsed
+        let dumped = self.dump(&response_url, status, &response_headers, &body);
+        self.record_fixture(&request, status, &body);
+        json::parse(url, &body).map_err(|mut e| {
+            if let ItchError::Decode { saved_to, .. } = &mut e {
+                *saved_to = dumped;
+            }
+            e
+        })
+    }
+
+    /// Fetch and parse an endpoint of the legacy API, `path` being relative to the key
+    async fn get_legacy_json<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        // The key is part of the URL, so fixtures go by the path after it
+        let request = format!("legacy/{}", path);
+        if let Some(replay) = &self.fixture_replay {
+            return self.replay_json(replay, &request, &request);
+        }
+        let url = format!(
+            "{}/{}/{}",
+            self.legacy_base_url.as_str().trim_end_matches('/'),
+            self.api_key,
+            path
+        );
+        let response = self
+            .get_with_retry(&url, &[], &reqwest::header::HeaderMap::new())
+            .await?;
+        let status = response.status();
+        let response_url = self.redact(response.url().as_str()).parse().ok();
+        let response_headers = response.headers().clone();
+        let body = response.text().await.map_err(|source| ItchError::Request {
+            context: "Failed to read API response",
+            source: source.without_url(),
+        })?;
+        let dumped = response_url.and_then(|url| self.dump(&url, status, &response_headers, &body));
+        self.record_fixture(&request, status, &body);
+
+        // A bad key gets a success status and a list of errors
+        let errors = serde_json::from_str::<LegacyErrors>(&body).ok();
+        if matches!(
+            status,
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+        ) || errors.is_some()
+        {
+            let body = errors.map_or(body, |errors| errors.errors.join(", "));
+            return Err(ItchError::Auth { status, body });
+        }
+        if !status.is_success() {
+            return Err(ItchError::Http {
+                context: "Legacy API request",
+                status,
+                body: json::shorten(body),
+                saved_to: dumped,
+            });
+        }
+        let endpoint = self.redact(&url);
+        json::parse(&endpoint, &body).map_err(|mut e| {
+            if let ItchError::Decode { saved_to, .. } = &mut e {
+                *saved_to = dumped;
+            }
+            e
+        })
+    }
+
+    /// Ask the legacy API about a key the current one rejected, remembering if it's a legacy key
+    async fn detect_legacy_key(&self) -> bool {
+        match self.credentials_info().await {
+            Ok(info) => {
+                info!(key_type = %info.key_type, scopes = ?info.scopes, "Detected a legacy API key");
+                self.legacy_detected.store(true, Ordering::Relaxed);
+                true
+            }
+            Err(e) => {
+                debug!("Legacy API rejected the key too: {}", e);
+                false
+            }
+        }
+    }
+
+    /// What the legacy server-side API knows about the key: its type, scopes and expiry
+    pub async fn credentials_info(&self) -> Result<CredentialsInfo> {
+        self.get_legacy_json("credentials/info").await
+    }
+
+    /// The account the API key belongs to
+    pub async fn get_profile(&self) -> Result<User> {
+        let profile: ProfileResponse = self.get_json(&self.endpoint("profile"), &[]).await?;
+        Ok(profile.user)
+    }
+
+    /// One page of the library, counting from 1
+    pub async fn owned_keys_page(&self, page: u64) -> Result<OwnedKeysResponse> {
+        let url = self.endpoint("profile/owned-keys");
+        let Some(page_size) = self.requested_page_size() else {
+            return self.get_json(&url, &[("page", page)]).await;
+        };
+        match self
+            .get_json(&url, &[("page", page), ("per_page", page_size)])
+            .await
+        {
+            Err(ItchError::Http { status, .. })
+                if matches!(
+                    status,
+                    reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::UNPROCESSABLE_ENTITY
+                ) =>
+            {
+                warn!(
+                    page_size,
+                    %status,
+                    "itch.io refused the page size, using its own from now on"
+                );
+                self.page_size_rejected.store(true, Ordering::Relaxed);
+                self.get_json(&url, &[("page", page)]).await
+            }
+            result => result,
+        }
+    }
+
+    /// One page of the library, asked for again while it fails in a way that may pass
+    ///
+    /// Only the failing page is retried, so the pages before it aren't fetched a second time.
+    async fn owned_keys_page_with_retry(&self, page: u64) -> Result<OwnedKeysResponse> {
+        let mut attempt = 0;
+        loop {
+            match self.owned_keys_page(page).await {
+                Err(e) if e.is_transient() && attempt < self.retry.max_retries => {
+                    attempt += 1;
+                    Span::current().record("retries", attempt);
+                    let retry_delay = self.retry.delay(attempt);
+                    warn!(
+                        page,
+                        "Fetching library page failed, retrying in {:?} (attempt {}/{}): {}",
+                        retry_delay,
+                        attempt,
+                        self.retry.max_retries,
+                        e
+                    );
+                    sleep(retry_delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The page size library pages are asked for with, `None` once itch.io refused it
+    fn requested_page_size(&self) -> Option<u64> {
+        (!self.page_size_rejected.load(Ordering::Relaxed)).then_some(self.page_size)
+    }
+
+    /// Every key in the library, yielded as each page arrives rather than after the last one
+    ///
+    /// Pages are fetched one after another until a short one, an empty one or one holding only
+    /// keys already seen, `on_page` being called with each page number before it's requested.
+    /// Keys repeated across pages are yielded once. A page that fails with a transient error is
+    /// retried in place under the client's [`RetryPolicy`]; the stream ends after the first error
+    /// that outlasts the retries, keeping the keys already yielded valid.
+    pub fn owned_keys_stream(
+        &self,
+        on_page: impl FnMut(u64),
+    ) -> impl Stream<Item = Result<OwnedKey>> {
+        let state = (Some(1), 0u64, HashSet::new(), on_page);
+        futures::stream::try_unfold(
+            state,
+            move |(page, pages, mut seen, mut on_page)| async move {
+                let Some(page) = page else {
+                    debug!(pages, keys = seen.len(), "Reached the end of the library");
+                    return Ok(None);
+                };
+                if page > MAX_LIBRARY_PAGES {
+                    warn!(
+                        pages,
+                        keys = seen.len(),
+                        "Stopped fetching the library after {} pages, it may be incomplete",
+                        MAX_LIBRARY_PAGES
+                    );
+                    return Ok(None);
+                }
+                on_page(page);
+
+                let owned_keys_response = self
+                    .owned_keys_page_with_retry(page)
+                    .instrument(tracing::info_span!("library_page", page, retries = Empty))
+                    .await?;
+
+                let keys_count = owned_keys_response.owned_keys.len();
+                // The size we asked for, unless the response says it used another one
+                let per_page = match owned_keys_response.per_page {
+                    0 => self.requested_page_size().unwrap_or(0),
+                    echoed => echoed,
+                };
+                let new_keys: Vec<OwnedKey> = owned_keys_response
+                    .owned_keys
+                    .into_iter()
+                    .filter(|key| seen.insert(key.id))
+                    .collect();
+                info!(
+                    page,
+                    keys = keys_count,
+                    new = new_keys.len(),
+                    per_page,
+                    "Fetched owned keys"
+                );
+                if new_keys.is_empty() {
+                    // Past the last page the API may answer with nothing, or with a page over again
+                    if keys_count > 0 {
+                        warn!(
+                            page,
+                            "Library page only repeated keys already seen, stopping"
+                        );
+                    }
+                    debug!(pages, keys = seen.len(), "Reached the end of the library");
+                    return Ok(None);
+                }
+                // A short page is the last one, unless the page size is unknown and only an empty or
+                // repeated page can tell
+                let next = (per_page == 0 || keys_count >= per_page as usize).then_some(page + 1);
+
+                let keys = futures::stream::iter(new_keys.into_iter().map(Ok));
+                Ok::<_, ItchError>(Some((keys, (next, pages + 1, seen, on_page))))
+            },
+        )
+        .try_flatten()
+    }
+
+    /// Every key in the library, collected from [`ItchClient::owned_keys_stream`]
+    pub async fn list_owned_keys(&self, on_page: impl FnMut(u64)) -> Result<Vec<OwnedKey>> {
+        let all_owned_keys: Vec<OwnedKey> = self.owned_keys_stream(on_page).try_collect().await?;
+        info!(keys = all_owned_keys.len(), "Fetched the whole library");
+        Ok(all_owned_keys)
+    }
+
+    /// Every key in the library that could be fetched, keeping what arrived when a later page
+    /// keeps failing
+    ///
+    /// Fails outright when nothing arrived, or when the error isn't one a missing page explains,
+    /// like a rejected key or cancellation.
+    pub async fn list_owned_keys_partial(
+        &self,
+        on_page: impl FnMut(u64),
+    ) -> Result<PartialLibrary> {
+        let mut keys = Vec::new();
+        let mut stream = std::pin::pin!(self.owned_keys_stream(on_page));
+        loop {
+            match stream.next().await {
+                Some(Ok(key)) => keys.push(key),
+                None => {
+                    info!(keys = keys.len(), "Fetched the whole library");
+                    return Ok(PartialLibrary { keys, error: None });
+                }
+                Some(Err(e)) if keys.is_empty() || e.is_auth() || e.is_cancelled() => {
+                    return Err(e);
+                }
+                Some(Err(e)) => {
+                    warn!(
+                        keys = keys.len(),
+                        "Only part of the library could be fetched: {}", e
+                    );
+                    return Ok(PartialLibrary {
+                        keys,
+                        error: Some(e),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Everything the game endpoint knows about a game, `None` once it's delisted or made private
+    ///
+    /// Answers are remembered for the client's lifetime, so looking a game up again is free.
+    pub async fn get_game(&self, game_id: u64) -> Result<Option<GameDetails>> {
+        if let Some(known) = self.game_details.lock().unwrap().get(&game_id) {
+            return Ok(known.clone());
+        }
+        let url = self.endpoint(&format!("games/{}", game_id));
+        let details = match self.get_json::<GameResponse>(&url, &[]).await {
+            Ok(response) => response.game,
+            Err(ItchError::Http {
+                status: reqwest::StatusCode::NOT_FOUND,
+                ..
+            }) => None,
+            Err(e) => return Err(e),
+        };
+        if details.is_none() {
+            debug!(game_id, "Game endpoint has nothing for this game");
+        }
+        self.game_details
+            .lock()
+            .unwrap()
+            .insert(game_id, details.clone());
+        Ok(details)
+    }
+
+    /// The games the account created, drafts and unlisted ones included
+    ///
+    /// Works with legacy keys too, through the legacy API's `my-games`.
+    pub async fn list_my_games(&self) -> Result<Vec<Game>> {
+        let response: MyGamesResponse =
+            match self.get_json(&self.endpoint("profile/games"), &[]).await {
+                Err(ItchError::LegacyKey) => self.get_legacy_json("my-games").await?,
+                response => response?,
+            };
+        info!(games = response.games.len(), "Fetched own games");
+        Ok(response.games)
+    }
+
+    /// Look up a download key issued for one of the account's own games
+    ///
+    /// itch.io only answers for the game's owner, check [`ItchClient::list_my_games`] first for a
+    /// clearer error than the one it gives.
+    pub async fn lookup_download_key(
+        &self,
+        game_id: u64,
+        lookup: &KeyLookup,
+    ) -> Result<DownloadKey> {
+        let (name, value) = match lookup {
+            KeyLookup::Key(key) => ("download_key", key),
+            KeyLookup::Email(email) => ("email", email),
+        };
+        let url = reqwest::Url::parse_with_params(
+            &self.endpoint(&format!("games/{}/download_keys", game_id)),
+            &[(name, value)],
+        )
+        .map_err(|e| ItchError::Config {
+            message: format!("invalid download key lookup: {}", e),
+        })?;
+        let response: DownloadKeyResponse = self.get_json(url.as_str(), &[]).await?;
+        Ok(response.download_key)
+    }
+
+    /// One page of the purchases of one of the account's own games, counting from 1
+    ///
+    /// Like [`ItchClient::lookup_download_key`], itch.io only answers for the game's owner.
+    pub async fn purchases_page(
+        &self,
+        game_id: u64,
+        filter: &PurchaseFilter,
+        page: u64,
+    ) -> Result<PurchasesResponse> {
+        let mut params = Vec::new();
+        if let Some(email) = &filter.email {
+            params.push(("email", email.clone()));
+        }
+        if let Some(user_id) = filter.user_id {
+            params.push(("user_id", user_id.to_string()));
+        }
+        let url = reqwest::Url::parse_with_params(
+            &self.endpoint(&format!("games/{}/purchases", game_id)),
+            &params,
+        )
+        .map_err(|e| ItchError::Config {
+            message: format!("invalid purchase filter: {}", e),
+        })?;
+        self.get_json(url.as_str(), &[("page", page)]).await
+    }
+
+    /// Every purchase of one of the account's own games matching `filter`, page by page
+    pub async fn list_purchases(
+        &self,
+        game_id: u64,
+        filter: &PurchaseFilter,
+    ) -> Result<Vec<Purchase>> {
+        let mut purchases = Vec::new();
+        let mut seen = HashSet::new();
+        for page in 1.. {
+            let response = self.purchases_page(game_id, filter, page).await?;
+            if !next_page(
+                page,
+                response.purchases,
+                response.per_page,
+                &mut seen,
+                |purchase| purchase.id,
+                &mut purchases,
+            ) {
+                break;
+            }
+        }
+        info!(game_id, purchases = purchases.len(), "Fetched purchases");
+        Ok(purchases)
+    }
+
+    /// The rating summary and reviews of one of the account's own games, page by page
+    ///
+    /// `None` when itch.io doesn't make them available for the game.
+    pub async fn get_reviews(&self, game_id: u64) -> Result<Option<Reviews>> {
+        let url = self.endpoint(&format!("games/{}/reviews", game_id));
+        let mut reviews = Reviews {
+            game_id,
+            ..Reviews::default()
+        };
+        let mut seen = HashSet::new();
+        for page in 1.. {
+            let response: ReviewsResponse = match self.get_json(&url, &[("page", page)]).await {
+                Ok(response) => response,
+                Err(
+                    ItchError::Http {
+                        status: reqwest::StatusCode::NOT_FOUND,
+                        ..
+                    }
+                    | ItchError::Auth {
+                        status: reqwest::StatusCode::FORBIDDEN,
+                        ..
+                    },
+                ) if page == 1 => {
+                    debug!(game_id, "Reviews aren't available for this game");
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(rating) = response.rating {
+                reviews.rating = rating;
+            }
+            if !next_page(
+                page,
+                response.reviews,
+                response.per_page,
+                &mut seen,
+                |review| review.id,
+                &mut reviews.reviews,
+            ) {
+                break;
+            }
+        }
+        info!(game_id, reviews = reviews.reviews.len(), "Fetched reviews");
+        Ok(Some(reviews))
+    }
+
+    /// The collections the account has made
+    pub async fn list_collections(&self) -> Result<Vec<Collection>> {
+        let response: CollectionsResponse = self
+            .get_json(&self.endpoint("profile/collections"), &[])
+            .await?;
+        Ok(response.collections)
+    }
+
+    /// One page of a collection's games, counting from 1
+    pub async fn collection_games_page(
+        &self,
+        collection_id: u64,
+        page: u64,
+    ) -> Result<CollectionGamesResponse> {
+        let url = self.endpoint(&format!("collections/{}/collection-games", collection_id));
+        self.get_json(&url, &[("page", page)]).await
+    }
+
+    /// Every game in a collection, fetched page by page until a short one
+    pub async fn list_collection_games(&self, collection_id: u64) -> Result<Vec<CollectionGame>> {
+        let mut games = Vec::new();
+        let mut seen = HashSet::new();
+        for page in 1.. {
+            let response = self.collection_games_page(collection_id, page).await?;
+            if !next_page(
+                page,
+                response.collection_games,
+                response.per_page,
+                &mut seen,
+                |game| game.game.id,
+                &mut games,
+            ) {
+                break;
+            }
+        }
+        info!(collection_id, games = games.len(), "Fetched collection");
+        Ok(games)
+    }
+
+    /// The files a game has, `download_key_id` granting access to paid ones
+    ///
+    /// Games the account created need no key. Returns [`ItchError::Cancelled`] as soon as `cancel` fires.
+    pub async fn get_game_uploads(
+        &self,
+        game_id: u64,
+        download_key_id: Option<u64>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Upload>> {
+        let url = self.endpoint(&format!("games/{}/uploads", game_id));
+
+        let uploads_response: UploadsResponse = cancellable(cancel, async {
+            // Add delay before making request to avoid rate limiting
+            sleep(self.request_delay).await;
+            let query: Vec<_> = download_key_id
+                .map(|id| ("download_key_id", id))
+                .into_iter()
+                .collect();
+            self.get_json(&url, &query).await
+        })
+        .instrument(tracing::info_span!("list_uploads", game_id))
+        .await?;
+
+        // Filenames end up as paths, so anything that could escape the output directory or clash
+        // with another upload's goes now
+        let mut uploads = uploads_response.uploads;
+        name_uploads(&mut uploads);
+        Ok(uploads)
+    }
+
+    /// The files a game has, as [`ItchClient::get_game_uploads`], reusing a listing stored by an
+    /// earlier run while the key hasn't changed
+    ///
+    /// A listing is reused for up to [`cache::UPLOADS_MAX_AGE`] as long as the key's `updated_at`
+    /// is the one it was stored with and it was written in the current format, otherwise it's
+    /// fetched and stored again. Nothing is stored without a cache directory or with
+    /// [`ItchClientBuilder::refresh_uploads`], which also skips the lookup.
+    pub async fn get_key_uploads(
+        &self,
+        key: &OwnedKey,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Upload>> {
+        let download_key_id = key.download_key_id();
+        let cache = self.cache.as_ref();
+        if !self.refresh_uploads
+            && let Some(stored) =
+                cache.and_then(|cache| cache.get_uploads(key.game_id, download_key_id))
+        {
+            // Offline a listing in the current format is used however old it is
+            let usable = if self.offline {
+                stored.version == cache::UPLOADS_FORMAT_VERSION
+            } else {
+                stored.is_fresh(key.updated_at, timestamp::now())
+            };
+            if usable {
+                self.used_cached(Some(stored.fetched_at));
+                debug!(game_id = key.game_id, "Reusing stored uploads listing");
+                return Ok(stored.uploads);
+            }
+            if stored.version != cache::UPLOADS_FORMAT_VERSION {
+                debug!(
+                    game_id = key.game_id,
+                    version = stored.version,
+                    "Stored uploads listing has another format, fetching it again"
+                );
+            }
+        }
+
+        let uploads = self
+            .get_game_uploads(key.game_id, download_key_id, cancel)
+            .await?;
+        if let Some(cache) = cache {
+            let url = self.endpoint(&format!("games/{}/uploads", key.game_id));
+            let query: Vec<_> = download_key_id
+                .map(|id| ("download_key_id", id))
+                .into_iter()
+                .collect();
+            let entry = cache::CachedUploads {
+                version: cache::UPLOADS_FORMAT_VERSION,
+                fetched_at: timestamp::now(),
+                key_updated_at: key.updated_at,
+                etag: cache.get(&url, &query).and_then(|response| response.etag),
+                uploads: uploads.clone(),
+            };
+            // Like the response cache, a failure to store only costs the next run a request
+            let _ = cache.put_uploads(key.game_id, download_key_id, &entry);
+        }
+        Ok(uploads)
+    }
+
+    /// Fetch a public file such as a cover image, through the same retries as everything else
+    ///
+    /// The key isn't sent, these live on itch.io's image hosts rather than the API.
+    pub(crate) async fn fetch_asset(
+        &self,
+        url: &str,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<u8>> {
+        let response = self
+            .send_with_retry(
+                || {
+                    let request = self.client.get(url);
+                    match self.timeout {
+                        Some(timeout) => request.timeout(timeout),
+                        None => request,
+                    }
+                },
+                "Failed to fetch image",
+                cancel,
+                |_| {},
+            )
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(ItchError::Http {
+                context: "Image request",
+                status,
+                body: String::new(),
+                saved_to: None,
+            });
+        }
+        let bytes = cancellable(cancel, async {
+            response.bytes().await.map_err(|source| ItchError::Request {
+                context: "Failed to read image",
+                source,
+            })
+        })
+        .await?;
+        Ok(bytes.to_vec())
+    }
+
+    /// Every build pushed to a butler upload, newest first, `download_key_id` as for
+    /// [`ItchClient::get_game_uploads`]
+    pub async fn list_builds(
+        &self,
+        upload_id: u64,
+        download_key_id: Option<u64>,
+    ) -> Result<Vec<Build>> {
+        let url = self.endpoint(&format!("uploads/{}/builds", upload_id));
+        let query: Vec<_> = download_key_id
+            .map(|id| ("download_key_id", id))
+            .into_iter()
+            .collect();
+        let response: BuildsResponse = self.get_json(&url, &query).await?;
+        debug!(upload_id, builds = response.builds.len(), "Fetched builds");
+        Ok(response.builds)
+    }
+
+    /// Download an upload into `dest_dir`, returning what was saved
+    ///
+    /// The file is streamed to a staging file first and moved into place once complete, with
+    /// `on_progress` hearing about retries and the bytes received. Once `cancel` fires the
+    /// transfer stops between chunks, the staging file is removed and [`ItchError::Cancelled`] is
+    /// returned.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn download_file(
+        &self,
+        upload_id: u64,
+        download_key_id: Option<u64>,
+        filename: &str,
+        dest_dir: &Path,
+        save: &SaveOptions,
+        on_progress: impl FnMut(DownloadProgress) + Send,
+        cancel: &CancellationToken,
+    ) -> Result<DownloadedFile> {
+        let url = self.endpoint(&format!("uploads/{}/download", upload_id));
+        self.download(
+            &url,
+            upload_id,
+            download_key_id,
+            filename,
+            dest_dir,
+            save,
+            on_progress,
+            cancel,
+        )
+        .instrument(download_span(upload_id))
+        .await
+    }
+
+    /// Download the archive of one build of a butler upload into `dest_dir`, like
+    /// [`ItchClient::download_file`] does for the upload's latest file
+    #[allow(clippy::too_many_arguments)]
+    pub async fn download_build(
+        &self,
+        build_id: u64,
+        download_key_id: Option<u64>,
+        filename: &str,
+        dest_dir: &Path,
+        save: &SaveOptions,
+        on_progress: impl FnMut(DownloadProgress) + Send,
+        cancel: &CancellationToken,
+    ) -> Result<DownloadedFile> {
+        let url = self.endpoint(&format!("builds/{}/download/archive/default", build_id));
+        self.download(
+            &url,
+            build_id,
+            download_key_id,
+            filename,
+            dest_dir,
+            save,
+            on_progress,
+            cancel,
+        )
+        .instrument(tracing::info_span!(
+            "download",
+            build_id,
+            bytes = Empty,
+            retries = Empty,
+            resumes = Empty
+        ))
+        .await
+    }
+
+    /// Stream an upload into `sink` rather than a file, e.g. stdout, returning what was written
+    ///
+    /// Nothing is staged, hashed against the mirror or moved, so the returned file's `filename` is
+    /// only the name the server gave it and `linked` is always false. `expected_size` is the size
+    /// the upload is listed with, which catches a body that ends early without a `Content-Length`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn download_file_to<W: AsyncWrite + Unpin + Send>(
+        &self,
+        upload_id: u64,
+        download_key_id: Option<u64>,
+        filename: &str,
+        expected_size: Option<u64>,
+        sink: &mut W,
+        mut on_progress: impl FnMut(DownloadProgress) + Send,
+        cancel: &CancellationToken,
+    ) -> Result<DownloadedFile> {
+        let url = self.endpoint(&format!("uploads/{}/download", upload_id));
+        async {
+            let (response, filename, target) = self
+                .start_download(
+                    &url,
+                    upload_id,
+                    download_key_id,
+                    filename,
+                    false,
+                    &mut on_progress,
+                    cancel,
+                )
+                .await?;
+            let (size, sha256) = self
+                .stream_download(
+                    &url,
+                    download_key_id,
+                    response,
+                    target,
+                    expected_size,
+                    sink,
+                    &mut on_progress,
+                    cancel,
+                )
+                .await?;
+            on_progress(DownloadProgress::Done);
+            Ok(DownloadedFile {
+                filename,
+                size,
+                sha256,
+                linked: false,
+            })
+        }
+        .instrument(download_span(upload_id))
+        .await
+    }
+
+    /// Stream a download from `url` into `dest_dir`, `id` naming the staging file and log lines
+    #[allow(clippy::too_many_arguments)]
+    async fn download(
+        &self,
+        url: &str,
+        id: u64,
+        download_key_id: Option<u64>,
+        filename: &str,
+        dest_dir: &Path,
+        save: &SaveOptions,
+        mut on_progress: impl FnMut(DownloadProgress) + Send,
+        cancel: &CancellationToken,
+    ) -> Result<DownloadedFile> {
+        let (response, filename, target) = self
+            .start_download(
+                url,
+                id,
+                download_key_id,
+                filename,
+                save.trust_listed_names,
+                &mut on_progress,
+                cancel,
+            )
+            .await?;
+
+        // Stream into a temporary file so partial downloads never sit at the final path
+        let part_path = staging::long_path(
+            &staging::staging_dir(save.temp_dir.as_ref(), dest_dir)
+                .join(staging::part_file_name(id)),
+        );
+        let mut file = File::create(&part_path)
+            .await
+            .context("Failed to create temporary download file")?;
+        let streamed = self
+            .stream_download(
+                url,
+                download_key_id,
+                response,
+                target,
+                save.expected_size,
+                &mut file,
+                &mut on_progress,
+                cancel,
+            )
+            .await;
+        drop(file);
+        let (downloaded, sha256) = match streamed {
+            Ok(streamed) => streamed,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&part_path).await;
+                return Err(e);
+            }
+        };
+
+        let file_path = staging::long_path(&dest_dir.join(&filename));
+        let (from, to) = (part_path.clone(), file_path.clone());
+        let dedupe = save.dedupe.clone();
+        let hash = sha256.clone();
+        let span = tracing::debug_span!("finalize", bytes = downloaded);
+        let linked = tokio::task::spawn_blocking(move || {
+            let _entered = span.entered();
+            match dedupe {
+                Some(index) => index
+                    .lock()
+                    .unwrap()
+                    .finalize(&from, &to, &hash, downloaded),
+                None => staging::move_path(&from, &to).map(|()| false),
+            }
+        })
+        .await
+        .map_err(|e| ItchError::io("Move task failed", std::io::Error::other(e)))?
+        .map_err(|e| {
+            ItchError::io(
+                "Failed to move download into place",
+                std::io::Error::other(e),
+            )
+        })?;
+
+        on_progress(DownloadProgress::Done);
+        Ok(DownloadedFile {
+            filename,
+            size: downloaded,
+            sha256,
+            linked,
+        })
+    }
+
+    /// Send a download's first request and work out what the file is called, reporting `Started`
+    #[allow(clippy::too_many_arguments)]
+    async fn start_download(
+        &self,
+        url: &str,
+        id: u64,
+        download_key_id: Option<u64>,
+        filename: &str,
+        trust_listed_names: bool,
+        on_progress: &mut (impl FnMut(DownloadProgress) + Send),
+        cancel: &CancellationToken,
+    ) -> Result<(reqwest::Response, String, StorageTarget)> {
+        // Add delay before making request to avoid rate limiting
+        cancellable(cancel, async {
+            sleep(self.request_delay).await;
+            Ok(())
+        })
+        .await?;
+
+        let mut target = StorageTarget::default();
+        let response = self
+            .request_download(url, download_key_id, 0, &mut target, on_progress, cancel)
+            .await?;
+
+        // The listed filename can be stale, so prefer the name the server sends now, unless the
+        // listed one is that name with the upload's id added to tell two uploads apart
+        let disposition_name = response
+            .headers()
+            .get(reqwest::header::CONTENT_DISPOSITION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(content_disposition_filename)
+            .and_then(|name| sanitize_filename(&name));
+        let filename = match disposition_name {
+            Some(name)
+                if !trust_listed_names
+                    && layout::versioned_name(&name, &id.to_string()) != filename =>
+            {
+                name
+            }
+            _ => upload_filename(filename, id),
+        };
+        on_progress(DownloadProgress::Started {
+            filename: filename.clone(),
+            total: response.content_length().unwrap_or(0),
+        });
+        Ok((response, filename, target))
+    }
+
+    /// Copy a download's body into `sink`, returning how many bytes were written and their SHA-256
+    ///
+    /// A transfer that breaks off is picked up where it stopped with a `Range` request, which
+    /// counts against the retry policy like a rate limit does, as does the storage host going quiet
+    /// for longer than its [`HostPolicy::timeout`]. When the server ignores the range
+    /// and sends the whole body again, the bytes already written are skipped rather than written
+    /// twice, so the sink never has to go back.
+    #[allow(clippy::too_many_arguments)]
+    async fn stream_download<W: AsyncWrite + Unpin>(
+        &self,
+        url: &str,
+        download_key_id: Option<u64>,
+        mut response: reqwest::Response,
+        mut target: StorageTarget,
+        expected_size: Option<u64>,
+        sink: &mut W,
+        on_progress: &mut (impl FnMut(DownloadProgress) + Send),
+        cancel: &CancellationToken,
+    ) -> Result<(u64, String)> {
+        let started = Instant::now();
+        let content_length = response.content_length();
+        let mut downloaded = 0u64;
+        // Bytes at the start of a restarted body that were already written
+        let mut skip = 0u64;
+        let mut hasher = Sha256::new();
+        let mut resumes = 0;
+
+        loop {
+            let mut stream = response.bytes_stream();
+            let timeout = target.policy.timeout;
+            let ended = loop {
+                // `None` when the host went quiet for longer than its timeout
+                let next = async {
+                    match timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, stream.next()).await.ok(),
+                        None => Some(stream.next().await),
+                    }
+                };
+                let chunk = tokio::select! {
+                    biased;
+                    _ = cancel.cancelled() => return Err(ItchError::Cancelled),
+                    chunk = next => chunk,
+                };
+                let mut chunk = match chunk {
+                    Some(Some(Ok(chunk))) => chunk,
+                    Some(Some(Err(source))) => break Err(Some(source)),
+                    Some(None) => break Ok(()),
+                    None => break Err(None),
+                };
+                if skip > 0 {
+                    let skipped = skip.min(chunk.len() as u64);
+                    skip -= skipped;
+                    chunk = chunk.slice(skipped as usize..);
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                }
+                sink.write_all(&chunk)
+                    .await
+                    .context("Failed to write chunk of the download")?;
+                hasher.update(&chunk);
+                downloaded += chunk.len() as u64;
+                on_progress(DownloadProgress::Bytes(downloaded));
+            };
+
+            // A connection closed early can still end the body cleanly, so count the bytes too
+            let expected = content_length.or(expected_size);
+            let failure = match ended {
+                Ok(()) => match expected.filter(|&expected| downloaded < expected) {
+                    Some(expected) => ItchError::Truncated {
+                        received: downloaded,
+                        expected,
+                    },
+                    None => break,
+                },
+                Err(Some(source)) => ItchError::Request {
+                    context: "Download interrupted",
+                    source: source.without_url(),
+                },
+                Err(None) => ItchError::io("Download stalled", timed_out(timeout)),
+            };
+            let retry = target.retry(&self.retry);
+            resumes += 1;
+            if resumes > retry.max_retries {
+                return Err(failure);
+            }
+            let retry_delay = retry.delay(resumes);
+            warn!(
+                url,
+                received = downloaded,
+                ?expected,
+                attempt = resumes,
+                "Download broke off ({}), resuming in {:?}",
+                failure,
+                retry_delay
+            );
+            on_progress(DownloadProgress::Resuming(retry_delay));
+            cancellable(cancel, async {
+                sleep(retry_delay).await;
+                Ok(())
+            })
+            .await?;
+
+            response = self
+                .request_download(
+                    url,
+                    download_key_id,
+                    downloaded,
+                    &mut target,
+                    on_progress,
+                    cancel,
+                )
+                .await?;
+            skip = 0;
+            if response.status() != reqwest::StatusCode::PARTIAL_CONTENT && downloaded > 0 {
+                // The server sent the whole file again rather than the rest of it
+                debug!(url, "Range ignored, skipping what was already written");
+                skip = downloaded;
+            }
+        }
+        sink.flush().await.context("Failed to flush the download")?;
+
+        debug!(
+            url,
+            bytes = downloaded,
+            resumes,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Download finished"
+        );
+        Span::current()
+            .record("bytes", downloaded)
+            .record("resumes", resumes);
+        Ok((downloaded, format!("{:x}", hasher.finalize())))
+    }
+
+    /// Where a download redirected to `target` is fetched from, after any [`CdnRewrite`]
+    fn rewrite_cdn(&self, target: reqwest::Url) -> reqwest::Url {
+        let Some(rewritten) = self
+            .cdn_rewrites
+            .iter()
+            .find_map(|rewrite| rewrite.apply(&target))
+        else {
+            return target;
+        };
+        info!(
+            from = target.host_str(),
+            to = %rewritten.host_str().unwrap_or_default(),
+            port = rewritten.port(),
+            "Fetching the download from the rewritten CDN host"
+        );
+        rewritten
+    }
+
+    /// The policy for downloads from `host`, with the pattern that picked it
+    fn host_policy(&self, host: &str) -> (Option<&str>, HostPolicy) {
+        let wildcard = self
+            .host_policies
+            .iter()
+            .find(|(pattern, _)| pattern == "*")
+            .map(|(_, policy)| policy.clone())
+            .unwrap_or_default();
+        let specific = self
+            .host_policies
+            .iter()
+            .filter(|(pattern, _)| pattern != "*" && host_matches(pattern, host))
+            .max_by_key(|(pattern, _)| pattern.len());
+        match specific {
+            Some((pattern, policy)) => (Some(pattern), policy.or(&wildcard)),
+            None if wildcard != HostPolicy::default() => (Some("*"), wildcard),
+            None => (None, wildcard),
+        }
+    }
+
+    /// Point `target` at `url`'s host, applying its policy and waiting for one of its slots
+    ///
+    /// The slot is kept while the download stays on the same host.
+    async fn enter_host(
+        &self,
+        target: &mut StorageTarget,
+        url: &reqwest::Url,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        if target.host.as_deref() == Some(host.as_str()) {
+            return Ok(());
+        }
+        let (pattern, policy) = self.host_policy(&host);
+        debug!(
+            host,
+            pattern,
+            max_concurrent = policy.max_concurrent,
+            retries = policy.retries.unwrap_or(self.retry.max_retries),
+            timeout = ?policy.timeout,
+            "Storage host policy"
+        );
+        target.slot = None;
+        if let Some(max_concurrent) = policy.max_concurrent {
+            let slots = self
+                .host_slots
+                .lock()
+                .unwrap()
+                .entry(host.clone())
+                .or_insert_with(|| std::sync::Arc::new(tokio::sync::Semaphore::new(max_concurrent)))
+                .clone();
+            let slot =
+                cancellable(cancel, async { Ok(slots.acquire_owned().await.unwrap()) }).await?;
+            target.slot = Some(slot);
+        }
+        target.policy = policy;
+        target.host = Some(host);
+        Ok(())
+    }
+
+    /// The HTTP client a download's body is fetched from `url` with, skipping certificate checks
+    /// only for hosts downloads are rewritten to with [`ItchClientBuilder::cdn_insecure`]
+    fn transfer_client(&self, url: &reqwest::Url) -> &Client {
+        match &self.insecure_client {
+            Some(insecure)
+                if self
+                    .cdn_rewrites
+                    .iter()
+                    .any(|rewrite| rewrite.is_target(url)) =>
+            {
+                insecure
+            }
+            _ => &self.client,
+        }
+    }
+
+    /// Request a download's body from byte `offset` on, failing unless the server sends it
+    ///
+    /// itch.io answers with a redirect to the file's storage host, which is followed here rather
+    /// than by reqwest so the API key is only ever sent to itch.io. The redirect's target, signature
+    /// and all, is kept in `target` so a resumed download goes straight back to it, asking itch.io
+    /// again once the storage host stops accepting it. Requests to the storage host go out under
+    /// its [`HostPolicy`], once one of its slots is free.
+    async fn request_download(
+        &self,
+        url: &str,
+        download_key_id: Option<u64>,
+        offset: u64,
+        target: &mut StorageTarget,
+        on_progress: &mut (impl FnMut(DownloadProgress) + Send),
+        cancel: &CancellationToken,
+    ) -> Result<reqwest::Response> {
+        let with_range = |request: reqwest::RequestBuilder| {
+            if offset > 0 {
+                request.header(reqwest::header::RANGE, format!("bytes={}-", offset))
+            } else {
+                request
+            }
+        };
+
+        if let Some(url) = target.url.clone() {
+            debug!(
+                host = url.host_str(),
+                offset, "Resuming from the storage host"
+            );
+            let response = self
+                .send_to_storage(
+                    || with_range(self.transfer_client(&url).get(url.clone())),
+                    "Failed to send download request",
+                    target,
+                    cancel,
+                    |retry_delay| on_progress(DownloadProgress::RetryScheduled(retry_delay)),
+                )
+                .await?;
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            // Signed URLs expire, so ask itch.io where the file is now
+            debug!(status = %response.status(), "Storage host refused the resume, asking itch.io again");
+            target.url = None;
+        }
+
+        let query: Vec<_> = download_key_id
+            .map(|id| ("download_key_id", id))
+            .into_iter()
+            .collect();
+        debug!(url, download_key_id, offset, "Requesting download");
+        let response = self
+            .send_with_retry(
+                || {
+                    with_range(
+                        self.client
+                            .get(url)
+                            .bearer_auth(&self.api_key)
+                            .query(&query),
+                    )
+                },
+                "Failed to send download request",
+                cancel,
+                |retry_delay| on_progress(DownloadProgress::RetryScheduled(retry_delay)),
+            )
+            .await?;
+        self.dump(
+            response.url(),
+            response.status(),
+            response.headers(),
+            "(download body not recorded)",
+        );
+
+        let response = if response.status().is_redirection() {
+            let url = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| response.url().join(value).ok())
+                .ok_or_else(|| ItchError::Http {
+                    context: "Download request",
+                    status: response.status(),
+                    body: "redirect without a usable Location".to_string(),
+                    saved_to: None,
+                })?;
+            debug!(
+                host = url.host_str(),
+                "Following the download redirect without the API key"
+            );
+            let url = self.rewrite_cdn(url);
+            self.enter_host(target, &url, cancel).await?;
+            let response = self
+                .send_to_storage(
+                    || with_range(self.transfer_client(&url).get(url.clone())),
+                    "Failed to send download request",
+                    target,
+                    cancel,
+                    |retry_delay| on_progress(DownloadProgress::RetryScheduled(retry_delay)),
+                )
+                .await?;
+            target.url = Some(url);
+            response
+        } else {
+            response
+        };
+
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(ItchError::Http {
+                context: "Download request",
+                status,
+                body: json::shorten(text),
+                saved_to: None,
+            });
+        }
+        Ok(response)
+    }
+}
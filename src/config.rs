@@ -0,0 +1,496 @@
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use itch_downloader::{HostPolicy, UploadSet};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Subcommand whose argument defaults `config show` reports as the built-in values
+const REFERENCE_COMMAND: &str = "dl";
+
+/// Defaults read from `config.toml`, overridden by flags on the command line
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta_concurrent: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_per_author: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unzip: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub per_game_dirs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_latest: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temp_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_total_size: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spread_over: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notify: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notify_webhook: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirm_above: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_base_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_proxy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_pac: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_minute: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub per_page: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress_chars: Option<String>,
+    /// Named `--bundle-set` combinations, the `[bundle_sets.<name>]` sections
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub bundle_sets: BTreeMap<String, UploadSet>,
+    /// How downloads from particular storage hosts are treated, the `[hosts."<host>"]` sections
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hosts: BTreeMap<String, HostSettings>,
+    /// Where the configuration was read from, if a file was found
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// The `[profiles.<name>]` section merged over the top-level values, if any
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+/// A `[hosts."<host>"]` section, see [`itch_downloader::HostPolicy`]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+}
+
+impl HostSettings {
+    /// The policy the section describes, once [`Config::validate`] has checked it
+    pub fn policy(&self) -> Result<HostPolicy> {
+        Ok(HostPolicy {
+            max_concurrent: self.max_concurrent,
+            retries: self.retries,
+            timeout: self
+                .timeout
+                .as_deref()
+                .map(crate::parse_duration)
+                .transpose()
+                .map_err(|e| anyhow::anyhow!("timeout: {}", e))?,
+        })
+    }
+}
+
+/// The configuration file used when `--config` isn't given
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("itch-downloader").join("config.toml"))
+}
+
+/// Set `values` in the configuration file at `path`, creating it if needed
+///
+/// With a `profile` in effect they go into its `[profiles.<name>]` section. Comments and the
+/// rest of the file are kept as they are.
+pub fn save_values(
+    path: &Path,
+    profile: Option<&str>,
+    values: &[(&str, toml_edit::Value)],
+) -> Result<()> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let mut document: toml_edit::DocumentMut = contents
+        .parse()
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let table = match profile {
+        Some(name) => document["profiles"]
+            .or_insert(toml_edit::table())
+            .as_table_mut()
+            .and_then(|profiles| {
+                profiles.set_implicit(true);
+                profiles[name].or_insert(toml_edit::table()).as_table_mut()
+            })
+            .with_context(|| format!("[profiles.{}] in {} isn't a table", name, path.display()))?,
+        None => document.as_table_mut(),
+    };
+    for (key, value) in values {
+        table[*key] = toml_edit::value(value.clone());
+    }
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    std::fs::write(path, document.to_string())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Find `--<name> <value>` among the raw arguments, for options needed before clap can be set up
+fn value_from_args(
+    args: impl IntoIterator<Item = std::ffi::OsString>,
+    name: &str,
+) -> Option<std::ffi::OsString> {
+    let flag = format!("--{}", name);
+    let prefix = format!("{}=", flag);
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == flag.as_str() {
+            return args.next();
+        }
+        if let Some(value) = arg.to_str().and_then(|arg| arg.strip_prefix(&prefix)) {
+            return Some(value.into());
+        }
+    }
+    None
+}
+
+/// Find `--config <path>` among the raw arguments
+pub fn path_from_args(args: impl IntoIterator<Item = std::ffi::OsString>) -> Option<PathBuf> {
+    value_from_args(args, "config").map(PathBuf::from)
+}
+
+/// Find `--profile <name>` among the raw arguments
+pub fn profile_from_args(args: impl IntoIterator<Item = std::ffi::OsString>) -> Option<String> {
+    value_from_args(args, "profile").map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Take the profiles out of the file and merge the chosen one over the top-level values
+///
+/// Returns the name of the profile in effect, `requested` winning over `default_profile`.
+fn select_profile(table: &mut toml::Table, requested: Option<&str>) -> Result<Option<String>> {
+    let profiles = match table.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => bail!("profiles must be a table of [profiles.<name>] sections"),
+        None => toml::Table::new(),
+    };
+    let default = match table.remove("default_profile") {
+        Some(toml::Value::String(name)) => Some(name),
+        Some(_) => bail!("default_profile must be the name of a profile"),
+        None => None,
+    };
+    let Some(name) = requested.map(str::to_string).or(default) else {
+        return Ok(None);
+    };
+
+    // The name becomes a directory for the profile's state
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "profile names may only use letters, digits, '-' and '_', not \"{}\"",
+            name
+        );
+    }
+    let available = || profiles.keys().cloned().collect::<Vec<_>>().join(", ");
+    match profiles.get(&name) {
+        Some(toml::Value::Table(profile)) => {
+            for (key, value) in profile {
+                table.insert(key.clone(), value.clone());
+            }
+        }
+        Some(_) => bail!("[profiles.{}] must be a table", name),
+        None if profiles.is_empty() => bail!("no profile named \"{}\", none are defined", name),
+        None => bail!("no profile named \"{}\" (available: {})", name, available()),
+    }
+    Ok(Some(name))
+}
+
+impl Config {
+    /// Load the configuration from `path`, or from the default location if it exists, with the
+    /// `ITCH_DOWNLOADER_*` environment variables taking precedence over the file
+    ///
+    /// `profile` picks a `[profiles.<name>]` section to merge over the top-level values, falling
+    /// back to the file's `default_profile`.
+    pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Self> {
+        let mut config = Self::load_file(path, profile)?;
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    fn load_file(path: Option<&Path>, profile: Option<&str>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, profile.is_some()),
+                None if profile.is_some() => {
+                    bail!("--profile needs a configuration file, see --config")
+                }
+                None => return Ok(Config::default()),
+            },
+        };
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+                return Ok(Config::default());
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        let mut table: toml::Table = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        let profile = select_profile(&mut table, profile)
+            .with_context(|| format!("Invalid profiles in {}", path.display()))?;
+        let mut config: Config = toml::Value::Table(table)
+            .try_into()
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+        config.path = Some(path);
+        config.profile = profile;
+        Ok(config)
+    }
+
+    /// Override the file with whatever the environment sets, naming the variable when it's invalid
+    fn apply_env(&mut self) -> Result<()> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        if let Some(output) = var("ITCH_DOWNLOADER_OUTPUT") {
+            self.output = Some(PathBuf::from(output));
+        }
+        if let Some(value) = var("ITCH_DOWNLOADER_MAX_CONCURRENT") {
+            match value.trim().parse::<usize>() {
+                Ok(max_concurrent) if max_concurrent > 0 => {
+                    self.max_concurrent = Some(max_concurrent)
+                }
+                _ => bail!(
+                    "ITCH_DOWNLOADER_MAX_CONCURRENT must be a whole number of at least 1, not \"{}\"",
+                    value
+                ),
+            }
+        }
+        if let Some(url) = var("ITCH_API_BASE_URL") {
+            url.parse::<reqwest::Url>()
+                .map_err(|e| anyhow::anyhow!("ITCH_API_BASE_URL is not a valid URL: {}", e))?;
+            self.api_base_url = Some(url);
+        }
+        if let Some(value) = var("ITCH_DOWNLOADER_UNZIP") {
+            self.unzip = Some(match value.trim().to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => true,
+                "0" | "false" | "no" | "off" => false,
+                _ => bail!(
+                    "ITCH_DOWNLOADER_UNZIP must be true or false (or 1/0, yes/no), not \"{}\"",
+                    value
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Check values up front so mistakes point at the file rather than at a flag nobody passed
+    fn validate(&self) -> Result<()> {
+        if self.max_concurrent == Some(0) {
+            bail!("max_concurrent must be at least 1");
+        }
+        if self.meta_concurrent == Some(0) {
+            bail!("meta_concurrent must be at least 1");
+        }
+        if self.max_concurrent_per_author == Some(0) {
+            bail!("max_concurrent_per_author must be at least 1");
+        }
+        for (name, set) in &self.bundle_sets {
+            if name.trim().is_empty() {
+                bail!("bundle_sets can't have an empty name");
+            }
+            set.validate()
+                .map_err(|e| anyhow::anyhow!("bundle_sets.{}: {}", name, e))?;
+        }
+        for (host, settings) in &self.hosts {
+            if host.trim().is_empty() || host.contains(['/', ':', ' ']) {
+                bail!("hosts: \"{}\" isn't a host or \"*\"", host);
+            }
+            if settings.max_concurrent == Some(0) {
+                bail!("hosts.\"{}\".max_concurrent must be at least 1", host);
+            }
+            settings
+                .policy()
+                .map_err(|e| anyhow::anyhow!("hosts.\"{}\".{}", host, e))?;
+        }
+        if let Some(notify) = &self.notify {
+            crate::notify::NotifyMode::from_str(notify, true)
+                .map_err(|_| anyhow::anyhow!("notify must be \"desktop\", not \"{}\"", notify))?;
+        }
+        if let Some(size) = &self.max_total_size {
+            itch_downloader::budget::parse_size(size)
+                .map_err(|e| anyhow::anyhow!("max_total_size: {}", e))?;
+        }
+        if let Some(url) = &self.api_base_url {
+            url.parse::<reqwest::Url>()
+                .map_err(|e| anyhow::anyhow!("api_base_url: {}", e))?;
+        }
+        if let Some(window) = &self.spread_over {
+            crate::parse_duration(window).map_err(|e| anyhow::anyhow!("spread_over: {}", e))?;
+        }
+        for (name, timeout) in [
+            ("timeout", &self.timeout),
+            ("connect_timeout", &self.connect_timeout),
+        ] {
+            if let Some(timeout) = timeout {
+                crate::parse_duration(timeout).map_err(|e| anyhow::anyhow!("{}: {}", name, e))?;
+            }
+        }
+        if let Some(url) = &self.proxy {
+            url.parse::<reqwest::Url>()
+                .map_err(|e| anyhow::anyhow!("proxy: {}", e))?;
+        }
+        if self
+            .rate_limit
+            .is_some_and(|rate| !(rate.is_finite() && rate > 0.0))
+        {
+            bail!("rate_limit must be more than zero requests per second");
+        }
+        if self.max_requests_per_minute == Some(0) {
+            bail!("max_requests_per_minute must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Argument ids and the default each one takes from the file
+    fn defaults(&self) -> Vec<(&'static str, String)> {
+        let path = |path: &Option<PathBuf>| path.as_ref().map(|path| path.display().to_string());
+        let values = [
+            ("output", path(&self.output)),
+            ("max_concurrent", self.max_concurrent.map(|n| n.to_string())),
+            (
+                "meta_concurrent",
+                self.meta_concurrent.map(|n| n.to_string()),
+            ),
+            (
+                "max_concurrent_per_author",
+                self.max_concurrent_per_author.map(|n| n.to_string()),
+            ),
+            ("unzip", self.unzip.map(|flag| flag.to_string())),
+            (
+                "per_game_dirs",
+                self.per_game_dirs.map(|flag| flag.to_string()),
+            ),
+            ("dir_template", self.dir_template.clone()),
+            ("link_latest", self.link_latest.map(|flag| flag.to_string())),
+            ("temp_dir", path(&self.temp_dir)),
+            ("author", self.author.clone()),
+            ("title", self.title.clone()),
+            ("max_total_size", self.max_total_size.clone()),
+            ("spread_over", self.spread_over.clone()),
+            ("notify", self.notify.clone()),
+            ("notify_webhook", self.notify_webhook.clone()),
+            ("confirm_above", self.confirm_above.map(|n| n.to_string())),
+            ("api_base_url", self.api_base_url.clone()),
+            ("user_agent", self.user_agent.clone()),
+            ("timeout", self.timeout.clone()),
+            ("connect_timeout", self.connect_timeout.clone()),
+            ("proxy", self.proxy.clone()),
+            ("no_proxy", self.no_proxy.clone()),
+            ("proxy_pac", self.proxy_pac.clone()),
+            ("max_retries", self.max_retries.map(|n| n.to_string())),
+            ("rate_limit", self.rate_limit.map(|rate| rate.to_string())),
+            (
+                "max_requests_per_minute",
+                self.max_requests_per_minute.map(|n| n.to_string()),
+            ),
+            ("per_page", self.per_page.map(|n| n.to_string())),
+            ("progress_template", self.progress_template.clone()),
+            ("progress_chars", self.progress_chars.clone()),
+        ];
+        values
+            .into_iter()
+            .filter_map(|(id, value)| Some((id, value?)))
+            .collect()
+    }
+
+    /// Make the file's values the defaults of every subcommand that takes them, so flags still win
+    pub fn apply_defaults(&self, command: clap::Command) -> clap::Command {
+        apply_defaults_to(command, &self.defaults())
+    }
+
+    /// The configuration in effect once built-in defaults fill the gaps, with the API key hidden
+    pub fn effective(&self, command: &clap::Command) -> Config {
+        let builtin = |id: &str| {
+            command
+                .find_subcommand(REFERENCE_COMMAND)?
+                .get_arguments()
+                .find(|arg| arg.get_id() == id)?
+                .get_default_values()
+                .first()
+                .map(|value| value.to_string_lossy().into_owned())
+        };
+        Config {
+            api_key: self.api_key.as_ref().map(|_| "<redacted>".to_string()),
+            output: self
+                .output
+                .clone()
+                .or_else(|| builtin("output").map(PathBuf::from)),
+            max_concurrent: self
+                .max_concurrent
+                .or_else(|| builtin("max_concurrent")?.parse().ok()),
+            meta_concurrent: self
+                .meta_concurrent
+                .or_else(|| builtin("meta_concurrent")?.parse().ok()),
+            max_concurrent_per_author: self.max_concurrent_per_author,
+            unzip: self.unzip.or_else(|| builtin("unzip")?.parse().ok()),
+            per_game_dirs: self
+                .per_game_dirs
+                .or_else(|| builtin("per_game_dirs")?.parse().ok()),
+            link_latest: self
+                .link_latest
+                .or_else(|| builtin("link_latest")?.parse().ok()),
+            ..self.clone()
+        }
+    }
+}
+
+/// Set `defaults` on `command` and, since commands like `bundle dl` nest, all of its subcommands
+fn apply_defaults_to(mut command: clap::Command, defaults: &[(&str, String)]) -> clap::Command {
+    // Global options live on the top level command until clap propagates them
+    for (id, value) in defaults {
+        if command.get_arguments().any(|arg| arg.get_id() == id) {
+            command = command.mut_arg(*id, |arg| arg.default_value(value.clone()));
+        }
+    }
+    let subcommands: Vec<String> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect();
+    for name in subcommands {
+        command =
+            command.mut_subcommand(name, |subcommand| apply_defaults_to(subcommand, defaults));
+    }
+    command
+}
@@ -0,0 +1,233 @@
+use crate::config::Config;
+use anyhow::{Context, Result, bail};
+use std::path::{Path, PathBuf};
+
+/// Where to get a new API key when the current one is rejected
+const API_KEYS_URL: &str = "https://itch.io/user/settings/api-keys";
+
+/// Where the API key in use came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeySource {
+    Flag,
+    /// `--api-key-file`, `ITCH_API_KEY_FILE` or `api_key_file` in the config
+    File(PathBuf),
+    Env,
+    Config(Option<PathBuf>),
+    Keyring,
+    /// The credentials file `butler login` saves
+    Butler(PathBuf),
+}
+
+impl KeySource {
+    /// What to tell someone whose key from this source was rejected, never including the key
+    fn explain(&self) -> String {
+        let (from, fix) = match self {
+            KeySource::Flag => (
+                "the --api-key flag".to_string(),
+                "pass it with --api-key".to_string(),
+            ),
+            KeySource::File(path) => (
+                format!("the key file {}", path.display()),
+                format!("write it to {}", path.display()),
+            ),
+            KeySource::Env => (
+                "the ITCH_API_KEY environment variable".to_string(),
+                "update ITCH_API_KEY".to_string(),
+            ),
+            KeySource::Config(path) => {
+                let file = path.as_ref().map_or_else(
+                    || "the config file".to_string(),
+                    |path| path.display().to_string(),
+                );
+                (
+                    format!("`api_key` in {}", file),
+                    format!("update `api_key` in {}", file),
+                )
+            }
+            KeySource::Keyring => (
+                "the system keyring".to_string(),
+                "run `itch-downloader login` again".to_string(),
+            ),
+            KeySource::Butler(path) => (
+                format!("butler's credentials in {}", path.display()),
+                "run `butler login` again or pass a key explicitly".to_string(),
+            ),
+        };
+        format!(
+            "itch.io rejected the API key from {}. It may have expired or been revoked: generate a \
+             new one at {} and {}",
+            from, API_KEYS_URL, fix
+        )
+    }
+}
+
+/// Everywhere an API key can come from besides `--api-key`, in order of precedence
+pub struct KeySources<'a> {
+    /// `--api-key-file` or `ITCH_API_KEY_FILE`
+    pub file: Option<PathBuf>,
+    pub config: &'a Config,
+    /// Where the key handed out by `resolve` came from
+    pub source: std::cell::RefCell<Option<KeySource>>,
+}
+
+impl KeySources<'_> {
+    /// Settle on the API key to use, `api_key` being the one passed on the command line
+    pub fn resolve(&self, api_key: Option<String>) -> Result<String> {
+        let (api_key, source) = self.find(api_key)?;
+        *self.source.borrow_mut() = Some(source);
+        Ok(api_key)
+    }
+
+    fn find(&self, api_key: Option<String>) -> Result<(String, KeySource)> {
+        if let Some(api_key) = api_key {
+            return Ok((api_key, KeySource::Flag));
+        }
+        if let Some(path) = &self.file {
+            return Ok((read_key_file(path)?, KeySource::File(path.clone())));
+        }
+        if let Some(api_key) = std::env::var("ITCH_API_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+        {
+            return Ok((api_key, KeySource::Env));
+        }
+        if let Some(api_key) = &self.config.api_key {
+            return Ok((api_key.clone(), KeySource::Config(self.config.path.clone())));
+        }
+        if let Some(path) = &self.config.api_key_file {
+            return Ok((read_key_file(path)?, KeySource::File(path.clone())));
+        }
+        if let Some(api_key) = load_from_keyring(self.config.profile.as_deref()) {
+            return Ok((api_key, KeySource::Keyring));
+        }
+        if let Some((api_key, path)) = load_butler_creds() {
+            eprintln!("Using the API key butler saved in {}", path.display());
+            return Ok((api_key, KeySource::Butler(path)));
+        }
+        bail!(
+            "API key is required. Provide it via --api-key, --api-key-file, the ITCH_API_KEY \
+             environment variable or the config file, or store one with `itch-downloader login`"
+        )
+    }
+
+    /// Add an explanation of where the key came from and how to replace it to an auth failure
+    pub fn explain(&self, error: anyhow::Error) -> anyhow::Error {
+        if !error
+            .downcast_ref::<itch_downloader::ItchError>()
+            .is_some_and(itch_downloader::ItchError::is_auth)
+        {
+            return error;
+        }
+        error.context(self.rejection_hint())
+    }
+
+    /// Where the rejected key came from and how to replace it
+    pub fn rejection_hint(&self) -> String {
+        match &*self.source.borrow() {
+            Some(source) => source.explain(),
+            None => format!(
+                "itch.io rejected the API key, generate a new one at {}",
+                API_KEYS_URL
+            ),
+        }
+    }
+}
+
+/// Service and account name the key is stored under in the keyring
+const KEYRING_SERVICE: &str = "itch-downloader";
+const KEYRING_USER: &str = "api-key";
+
+/// The keyring entry for `profile`, each profile keeping its own key
+fn keyring_entry(profile: Option<&str>) -> keyring::Result<keyring::Entry> {
+    match profile {
+        Some(name) => keyring::Entry::new(KEYRING_SERVICE, &format!("{}:{}", KEYRING_USER, name)),
+        None => keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER),
+    }
+}
+
+/// The key `login` saved for `profile`, if there is one and the keyring can be reached
+fn load_from_keyring(profile: Option<&str>) -> Option<String> {
+    // No keyring service (e.g. a headless box) just means there's no key there
+    keyring_entry(profile)
+        .and_then(|entry| entry.get_password())
+        .ok()
+}
+
+/// Save `profile`'s key in the platform keyring, replacing any earlier one
+pub fn store_in_keyring(profile: Option<&str>, api_key: &str) -> Result<()> {
+    keyring_entry(profile)
+        .and_then(|entry| entry.set_password(api_key))
+        .context("Failed to store the API key in the system keyring")
+}
+
+/// Remove `profile`'s key from the keyring, returning whether there was one
+pub fn remove_from_keyring(profile: Option<&str>) -> Result<bool> {
+    match keyring_entry(profile).and_then(|entry| entry.delete_credential()) {
+        Ok(()) => Ok(true),
+        Err(keyring::Error::NoEntry) => Ok(false),
+        Err(e) => Err(e).context("Failed to remove the API key from the system keyring"),
+    }
+}
+
+/// Where butler (and the itch app) keep the key from `butler login`
+fn butler_creds_path() -> Option<PathBuf> {
+    itch_downloader::itch_app::default_app_dir().map(|dir| dir.join("butler_creds"))
+}
+
+/// The key saved by `butler login`, if it's there and readable
+///
+/// Missing, unreadable or malformed files just mean this source has nothing to offer.
+fn load_butler_creds() -> Option<(String, PathBuf)> {
+    let path = butler_creds_path()?;
+    let contents = std::fs::read_to_string(&path).ok()?;
+    warn_if_world_readable(&path);
+    let api_key = parse_butler_creds(&contents)?;
+    Some((api_key, path))
+}
+
+/// The file normally holds nothing but the key, some versions wrap it in a JSON object
+fn parse_butler_creds(contents: &str) -> Option<String> {
+    let contents = contents.trim();
+    let api_key = match serde_json::from_str::<serde_json::Value>(contents) {
+        Ok(serde_json::Value::Object(object)) => ["api_key", "apiKey", "key"]
+            .iter()
+            .find_map(|field| object.get(*field)?.as_str())?
+            .trim()
+            .to_string(),
+        _ => contents.to_string(),
+    };
+    let looks_like_key = !api_key.is_empty()
+        && api_key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    looks_like_key.then_some(api_key)
+}
+
+/// Read an API key from a file, ignoring surrounding whitespace and newlines
+pub fn read_key_file(path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read API key from {}", path.display()))?;
+    warn_if_world_readable(path);
+    let api_key = contents.trim();
+    if api_key.is_empty() {
+        bail!("API key file {} is empty", path.display());
+    }
+    Ok(api_key.to_string())
+}
+
+#[cfg(unix)]
+fn warn_if_world_readable(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    let world_readable =
+        std::fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o004 != 0);
+    if world_readable {
+        eprintln!(
+            "Warning: {} is readable by every user on this machine, consider `chmod 600 {}`",
+            path.display(),
+            path.display()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_world_readable(_path: &Path) {}
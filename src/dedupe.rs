@@ -0,0 +1,534 @@
+use crate::error::{Context, Result};
+use crate::export::GameMetadata;
+use crate::itchignore::IgnoreRules;
+use crate::layout::Layout;
+use crate::manifest::Manifest;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Name of the content-hash index inside the state directory
+pub(crate) const INDEX_FILE: &str = "hashes.json";
+
+/// How identical files are deduplicated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DedupeMode {
+    /// Replace duplicates with hard links to the existing copy
+    Hardlink,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    /// Path of a file with this content, relative to the output directory
+    path: PathBuf,
+    size: u64,
+}
+
+/// An index from SHA-256 content hashes to a file in the output directory with that content
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HashIndex {
+    files: HashMap<String, IndexEntry>,
+    #[serde(skip)]
+    root: PathBuf,
+    #[serde(skip)]
+    state_dir: PathBuf,
+    /// Bytes not written to disk this run thanks to hard links
+    #[serde(skip)]
+    pub saved_bytes: u64,
+    /// Files replaced with hard links this run
+    #[serde(skip)]
+    pub linked_files: usize,
+}
+
+impl HashIndex {
+    /// Load the index kept in the output directory's state for `layout`, starting empty if there
+    /// isn't one yet
+    pub fn load(output_path: &Path, layout: &Layout) -> Result<Self> {
+        let state_dir = layout.state_dir(output_path);
+        let path = state_dir.join(INDEX_FILE);
+        let mut index: HashIndex = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashIndex::default(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        index.root = output_path.to_path_buf();
+        index.state_dir = state_dir;
+        Ok(index)
+    }
+
+    /// Write the index back to the output directory
+    pub fn save(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.state_dir).context("Failed to create state directory")?;
+        let contents = serde_json::to_string_pretty(self).context("Failed to serialize index")?;
+        std::fs::write(self.state_dir.join(INDEX_FILE), contents)
+            .context("Failed to write hash index")
+    }
+
+    /// The recorded SHA-256 of each indexed file, keyed by its path relative to the output directory
+    pub fn hashes_by_path(&self) -> HashMap<PathBuf, String> {
+        self.files
+            .iter()
+            .map(|(sha256, entry)| (entry.path.clone(), sha256.clone()))
+            .collect()
+    }
+
+    /// Add the files `other` indexes that this index doesn't
+    pub fn merge(&mut self, other: HashIndex) {
+        for (sha256, entry) in other.files {
+            self.files.entry(sha256).or_insert(entry);
+        }
+    }
+
+    /// Make absolute paths under `old_root` relative, for an index whose mirror has moved,
+    /// returning how many changed
+    pub fn rebase(&mut self, old_root: &Path) -> usize {
+        let mut rebased = 0;
+        for entry in self.files.values_mut() {
+            if let Ok(relative) = entry.path.strip_prefix(old_root) {
+                entry.path = relative.to_path_buf();
+                rebased += 1;
+            }
+        }
+        rebased
+    }
+
+    /// Path relative to the output directory when possible, so the mirror can be moved around
+    fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root)
+            .or_else(|_| path.strip_prefix(crate::staging::long_path(&self.root)))
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|_| path.to_path_buf())
+    }
+
+    /// Move a completed download at `part_path` to `final_path`, hard linking it to an existing
+    /// copy with the same content instead when there is one on the same filesystem
+    ///
+    /// The existing copy is hashed again first, as it may have changed since it was indexed.
+    /// Returns whether the file ended up as a hard link.
+    pub fn finalize(
+        &mut self,
+        part_path: &Path,
+        final_path: &Path,
+        sha256: &str,
+        size: u64,
+    ) -> Result<bool> {
+        let existing = self
+            .files
+            .get(sha256)
+            .map(|entry| (self.root.join(&entry.path), entry.size));
+
+        if let Some((existing, existing_size)) = existing {
+            let still_matches = existing != final_path
+                && existing_size == size
+                && std::fs::metadata(&existing).is_ok_and(|m| m.is_file() && m.len() == size)
+                && has_hash(&existing, sha256);
+
+            if still_matches {
+                let _ = std::fs::remove_file(final_path);
+                // Linking fails across filesystems, in which case we store the file normally
+                if std::fs::hard_link(&existing, final_path).is_ok() {
+                    std::fs::remove_file(part_path)
+                        .context("Failed to remove deduplicated download")?;
+                    self.saved_bytes += size;
+                    self.linked_files += 1;
+                    return Ok(true);
+                }
+            }
+        }
+
+        crate::staging::move_path(part_path, final_path)
+            .context("Failed to move download into place")?;
+        let path = self.relative(final_path);
+        self.files
+            .insert(sha256.to_string(), IndexEntry { path, size });
+        Ok(false)
+    }
+}
+
+/// Hex encoded SHA-256 of a file's contents, `on_read` hearing of every chunk's size
+pub fn file_sha256(path: &Path, mut on_read: impl FnMut(u64)) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        on_read(read as u64);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// One copy of content found more than once in the mirror
+#[derive(Clone, Debug, Serialize)]
+pub struct DuplicateFile {
+    /// Relative to the output directory
+    pub path: PathBuf,
+    /// The game the file belongs to, when the manifest, a sidecar or its directory tells
+    pub game: Option<String>,
+    /// Already a hard link to an earlier file of its group, taking no extra space
+    pub linked: bool,
+    /// The hash was taken from the manifest or index rather than the file, so it's hashed again
+    /// before being linked
+    #[serde(skip)]
+    pub recorded: bool,
+}
+
+/// Files in the mirror with the same contents
+#[derive(Clone, Debug, Serialize)]
+pub struct DuplicateGroup {
+    pub sha256: String,
+    /// Size of each file
+    pub size: u64,
+    /// The first is the one kept when linking
+    pub files: Vec<DuplicateFile>,
+}
+
+impl DuplicateGroup {
+    /// Bytes freed by making every copy but the first a hard link to it
+    pub fn reclaimable(&self) -> u64 {
+        let copies = self
+            .files
+            .iter()
+            .skip(1)
+            .filter(|file| !file.linked)
+            .count();
+        self.size * copies as u64
+    }
+}
+
+/// A file of the mirror, relative to it, and what identifies its data on disk
+type ListedFile = (PathBuf, Option<(u64, u64)>);
+
+/// Every group of identical files in a mirror, see [`find_duplicates`]
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DuplicateReport {
+    pub groups: Vec<DuplicateGroup>,
+    /// Files that had to be hashed, the rest had a hash recorded or a size no other file has
+    pub hashed_files: usize,
+    pub reclaimable_bytes: u64,
+}
+
+/// Group the files of the mirror at `output_path` of at least `min_size` bytes by contents
+///
+/// Only files sharing their size with another are hashed, and hashes the `sync` manifest or
+/// the `--dedupe` index in `layout`'s state recorded are reused while the file still has the
+/// recorded size.
+/// `on_read` hears of every chunk hashed. Groups come largest reclaimable space first. Files
+/// `ignore` matches aren't considered.
+pub fn find_duplicates(
+    output_path: &Path,
+    layout: &Layout,
+    min_size: u64,
+    ignore: &IgnoreRules,
+    mut on_read: impl FnMut(u64),
+) -> Result<DuplicateReport> {
+    let manifest = Manifest::load(output_path, layout)?;
+    let mut recorded: HashMap<PathBuf, (String, u64)> = HashMap::new();
+    for (_, game) in manifest.games() {
+        for upload in game.uploads.values() {
+            if let Some(sha256) = &upload.sha256 {
+                recorded.insert(upload.path.clone(), (sha256.clone(), upload.size));
+            }
+        }
+    }
+    let index = HashIndex::load(output_path, layout)?;
+    for (sha256, entry) in &index.files {
+        recorded
+            .entry(entry.path.clone())
+            .or_insert_with(|| (sha256.clone(), entry.size));
+    }
+
+    let mut by_size: HashMap<u64, Vec<ListedFile>> = HashMap::new();
+    list_files(
+        output_path,
+        Path::new(""),
+        ignore,
+        &mut |relative, metadata| {
+            if metadata.len() >= min_size {
+                by_size
+                    .entry(metadata.len())
+                    .or_default()
+                    .push((relative, file_identity(metadata)));
+            }
+        },
+    )?;
+
+    let mut report = DuplicateReport::default();
+    let mut by_hash: HashMap<(String, u64), Vec<(ListedFile, bool)>> = HashMap::new();
+    for (size, files) in by_size.into_iter().filter(|(_, files)| files.len() > 1) {
+        for (relative, identity) in files {
+            let (sha256, was_recorded) = match recorded.get(&relative) {
+                Some((sha256, recorded_size)) if *recorded_size == size => (sha256.clone(), true),
+                _ => {
+                    report.hashed_files += 1;
+                    let sha256 = file_sha256(&output_path.join(&relative), &mut on_read)?;
+                    (sha256, false)
+                }
+            };
+            by_hash
+                .entry((sha256, size))
+                .or_default()
+                .push(((relative, identity), was_recorded));
+        }
+    }
+
+    for ((sha256, size), mut files) in by_hash.into_iter().filter(|(_, files)| files.len() > 1) {
+        files.sort();
+        let mut seen = Vec::new();
+        let files = files
+            .into_iter()
+            .map(|((path, identity), recorded)| {
+                let linked = identity.is_some_and(|identity| seen.contains(&identity));
+                seen.extend(identity);
+                DuplicateFile {
+                    game: game_of(&manifest, output_path, &path),
+                    path,
+                    linked,
+                    recorded,
+                }
+            })
+            .collect();
+        report.groups.push(DuplicateGroup {
+            sha256,
+            size,
+            files,
+        });
+    }
+    report.groups.sort_by_key(|group| {
+        (
+            std::cmp::Reverse(group.reclaimable()),
+            group.files[0].path.clone(),
+        )
+    });
+    report.reclaimable_bytes = report.groups.iter().map(DuplicateGroup::reclaimable).sum();
+    Ok(report)
+}
+
+/// Call `on_file` for every file under `dir`, leaving out our state, temporary files, links and
+/// what `ignore` matches
+fn list_files(
+    root: &Path,
+    relative: &Path,
+    ignore: &IgnoreRules,
+    on_file: &mut impl FnMut(PathBuf, &std::fs::Metadata),
+) -> Result<()> {
+    let dir = root.join(relative);
+    let entries =
+        std::fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == crate::layout::STATE_DIR || crate::staging::is_temp_name(&name) {
+            continue;
+        }
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = relative.join(&name);
+        if ignore.is_ignored(&root.join(&path), file_type.is_dir()) {
+            continue;
+        }
+        if file_type.is_dir() {
+            list_files(root, &path, ignore, on_file)?;
+        } else if file_type.is_file()
+            && let Ok(metadata) = entry.metadata()
+        {
+            on_file(path, &metadata);
+        }
+    }
+    Ok(())
+}
+
+/// The game a file in the mirror belongs to: the manifest's title for the upload it's part of,
+/// the sidecar next to it or the directory at the top of its path
+fn game_of(manifest: &Manifest, output_path: &Path, relative: &Path) -> Option<String> {
+    let recorded = manifest.games().find_map(|(_, game)| {
+        game.uploads
+            .values()
+            .any(|upload| relative.starts_with(&upload.path))
+            .then(|| game.title.clone())
+    });
+    recorded
+        .or_else(|| {
+            let parent = output_path.join(relative).parent()?.to_path_buf();
+            GameMetadata::find(&parent).map(|metadata| metadata.title)
+        })
+        .or_else(|| {
+            let mut components = relative.components();
+            let top = components.next()?;
+            components
+                .next()
+                .map(|_| top.as_os_str().to_string_lossy().into_owned())
+        })
+}
+
+/// Whether the file at `path` still has the SHA-256 recorded for it
+fn has_hash(path: &Path, sha256: &str) -> bool {
+    file_sha256(path, |_| {}).is_ok_and(|actual| actual == sha256)
+}
+
+/// What identifies a file's data on disk, so hard links to it are recognised
+#[cfg(unix)]
+fn file_identity(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// What identifies a file's data on disk, so hard links to it are recognised
+#[cfg(not(unix))]
+fn file_identity(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// What linking a report's duplicates did
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LinkSummary {
+    pub linked_files: usize,
+    pub saved_bytes: u64,
+    /// Duplicates that couldn't be linked and why, e.g. being on another filesystem
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Replace every copy but the first of each group with a hard link to the first
+///
+/// The link is made under a temporary name next to the copy and renamed over it, so a failure
+/// leaves the copy as it was. Copies on another filesystem than the first can't be linked, and
+/// files whose hash came from a record are hashed again so one changed since isn't linked over.
+pub fn link_duplicates(output_path: &Path, report: &DuplicateReport) -> LinkSummary {
+    let mut summary = LinkSummary::default();
+    for group in &report.groups {
+        let Some((first, copies)) = group.files.split_first() else {
+            continue;
+        };
+        let original = output_path.join(&first.path);
+        if first.recorded && !has_hash(&original, &group.sha256) {
+            for copy in copies.iter().filter(|copy| !copy.linked) {
+                summary.failed.push((
+                    copy.path.clone(),
+                    format!("{} changed since it was hashed", first.path.display()),
+                ));
+            }
+            continue;
+        }
+        let original_device = std::fs::metadata(&original)
+            .ok()
+            .as_ref()
+            .and_then(file_identity)
+            .map(|(device, _)| device);
+        for copy in copies.iter().filter(|copy| !copy.linked) {
+            let path = output_path.join(&copy.path);
+            let device = std::fs::metadata(&path)
+                .ok()
+                .as_ref()
+                .and_then(file_identity)
+                .map(|(device, _)| device);
+            if let (Some(original_device), Some(device)) = (original_device, device)
+                && original_device != device
+            {
+                summary
+                    .failed
+                    .push((copy.path.clone(), "on another filesystem".to_string()));
+                continue;
+            }
+            if copy.recorded && !has_hash(&path, &group.sha256) {
+                summary
+                    .failed
+                    .push((copy.path.clone(), "changed since it was hashed".to_string()));
+                continue;
+            }
+            let temp_path = crate::staging::set_aside_path(&path);
+            let linked = std::fs::hard_link(&original, &temp_path)
+                .and_then(|()| std::fs::rename(&temp_path, &path));
+            match linked {
+                Ok(()) => {
+                    summary.linked_files += 1;
+                    summary.saved_bytes += group.size;
+                }
+                Err(e) => {
+                    let _ = std::fs::remove_file(&temp_path);
+                    summary.failed.push((copy.path.clone(), e.to_string()));
+                }
+            }
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finalize_links_to_an_indexed_copy_with_the_same_content() {
+        let output = tempfile::tempdir().unwrap();
+        let mut index = HashIndex::load(output.path(), &Layout::default()).unwrap();
+        let first_part = output.path().join("first.part");
+        std::fs::write(&first_part, b"same").unwrap();
+        let sha256 = file_sha256(&first_part, |_| {}).unwrap();
+        let first = output.path().join("first.zip");
+        assert!(!index.finalize(&first_part, &first, &sha256, 4).unwrap());
+
+        let second_part = output.path().join("second.part");
+        std::fs::write(&second_part, b"same").unwrap();
+        let second = output.path().join("second.zip");
+        assert!(index.finalize(&second_part, &second, &sha256, 4).unwrap());
+        assert_eq!(index.linked_files, 1);
+        assert!(!second_part.exists());
+    }
+
+    #[test]
+    fn finalize_does_not_link_to_a_copy_changed_since_it_was_indexed() {
+        let output = tempfile::tempdir().unwrap();
+        let mut index = HashIndex::load(output.path(), &Layout::default()).unwrap();
+        let first_part = output.path().join("first.part");
+        std::fs::write(&first_part, b"same").unwrap();
+        let sha256 = file_sha256(&first_part, |_| {}).unwrap();
+        let first = output.path().join("first.zip");
+        index.finalize(&first_part, &first, &sha256, 4).unwrap();
+        // Same size, different content
+        std::fs::write(&first, b"edit").unwrap();
+
+        let second_part = output.path().join("second.part");
+        std::fs::write(&second_part, b"same").unwrap();
+        let second = output.path().join("second.zip");
+        assert!(!index.finalize(&second_part, &second, &sha256, 4).unwrap());
+        assert_eq!(std::fs::read(&second).unwrap(), b"same");
+        assert_eq!(std::fs::read(&first).unwrap(), b"edit");
+    }
+
+    #[test]
+    fn link_duplicates_skips_recorded_files_that_changed() {
+        let output = tempfile::tempdir().unwrap();
+        std::fs::write(output.path().join("a.zip"), b"same").unwrap();
+        std::fs::write(output.path().join("b.zip"), b"edit").unwrap();
+        let sha256 = file_sha256(&output.path().join("a.zip"), |_| {}).unwrap();
+        let file = |path: &str, recorded| DuplicateFile {
+            path: PathBuf::from(path),
+            game: None,
+            linked: false,
+            recorded,
+        };
+        let report = DuplicateReport {
+            groups: vec![DuplicateGroup {
+                sha256,
+                size: 4,
+                files: vec![file("a.zip", false), file("b.zip", true)],
+            }],
+            ..Default::default()
+        };
+
+        let summary = link_duplicates(output.path(), &report);
+        assert_eq!(summary.linked_files, 0);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(std::fs::read(output.path().join("b.zip")).unwrap(), b"edit");
+    }
+}
@@ -0,0 +1,245 @@
+use crate::dedupe::HashIndex;
+use crate::error::{Context, Result};
+use crate::export::GameMetadata;
+use crate::layout::{Layout, STATE_DIR};
+use crate::manifest::{Manifest, top_level_name};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+/// How an entry differs between the two trees
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffStatus {
+    OnlyInA,
+    OnlyInB,
+    /// Present in both but with a different size or checksum
+    Differs,
+}
+
+impl DiffStatus {
+    /// Short name used in the diff table
+    pub fn label(self) -> &'static str {
+        match self {
+            DiffStatus::OnlyInA => "only in a",
+            DiffStatus::OnlyInB => "only in b",
+            DiffStatus::Differs => "differs",
+        }
+    }
+}
+
+/// A file or game that isn't the same on both sides
+#[derive(Clone, Debug, Serialize)]
+pub struct DiffEntry {
+    /// The file's path relative to each tree, or the game's title
+    pub name: String,
+    /// Title of the game a file belongs to, when it could be worked out
+    pub game: Option<String>,
+    pub status: DiffStatus,
+    pub size_a: Option<u64>,
+    pub size_b: Option<u64>,
+}
+
+/// Everything that differs between two trees
+#[derive(Debug, Default, Serialize)]
+pub struct DiffReport {
+    pub entries: Vec<DiffEntry>,
+    /// Bytes to copy from a to b for b to have everything a has
+    pub bytes_a_to_b: u64,
+    /// Bytes to copy from b to a for a to have everything b has
+    pub bytes_b_to_a: u64,
+}
+
+impl DiffReport {
+    pub fn is_identical(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// One file in a tree, with a checksum when the tree recorded one
+#[derive(Debug)]
+struct TreeFile {
+    size: u64,
+    sha256: Option<String>,
+    md5: Option<String>,
+    /// Key of the game the file belongs to, its id when known or its top level name otherwise
+    game: String,
+}
+
+impl TreeFile {
+    fn matches(&self, other: &TreeFile) -> bool {
+        if self.size != other.size {
+            return false;
+        }
+        match (&self.sha256, &other.sha256, &self.md5, &other.md5) {
+            (Some(a), Some(b), _, _) => a == b,
+            (_, _, Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+            // Nothing recorded to go on beyond the size, hashing both trees is what `check` is for
+            _ => true,
+        }
+    }
+}
+
+/// The files of an output directory plus what its state says about them, read without network access
+#[derive(Debug, Default)]
+struct Tree {
+    files: BTreeMap<PathBuf, TreeFile>,
+    /// Title of each game key
+    titles: HashMap<String, String>,
+}
+
+impl Tree {
+    fn load(root: &Path, layout: &Layout) -> Result<Self> {
+        if !root.is_dir() {
+            crate::error::bail!("{} is not a directory", root.display());
+        }
+        let manifest = Manifest::load(root, layout)?;
+        let mut sha256s = HashIndex::load(root, layout)?.hashes_by_path();
+        let mut md5s = HashMap::new();
+
+        // Top level entries we can tie to a game, from the manifest then the metadata sidecars
+        let mut games: HashMap<String, (String, String)> = HashMap::new();
+        for (game_id, game) in manifest.games() {
+            for upload in game.uploads.values() {
+                if let Some(name) = top_level_name(&upload.path) {
+                    games.insert(name, (game_id.to_string(), game.title.clone()));
+                }
+                // Checksums describe the download itself, not the files extracted from it
+                if let Some(sha256) = &upload.sha256 {
+                    sha256s.insert(upload.path.clone(), sha256.clone());
+                }
+                if let Some(md5) = &upload.md5 {
+                    md5s.insert(upload.path.clone(), md5.clone());
+                }
+            }
+        }
+
+        let mut tree = Tree::default();
+        for entry in
+            std::fs::read_dir(root).with_context(|| format!("Failed to read {}", root.display()))?
+        {
+            let entry = entry.context("Failed to list directory")?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name == STATE_DIR || crate::staging::is_temp_name(&name) {
+                continue;
+            }
+            let (game, title) = games.get(&name).cloned().unwrap_or_else(|| {
+                match GameMetadata::find(&entry.path()).filter(|_| entry.path().is_dir()) {
+                    Some(metadata) => (metadata.game_id.to_string(), metadata.title),
+                    None => (name.clone(), name.clone()),
+                }
+            });
+            tree.titles.insert(game.clone(), title);
+
+            let mut pending = vec![PathBuf::from(&name)];
+            while let Some(relative) = pending.pop() {
+                let path = root.join(&relative);
+                // Links such as `current` point at entries that are compared on their own
+                let metadata = std::fs::symlink_metadata(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                if metadata.is_dir() {
+                    for child in std::fs::read_dir(&path)
+                        .with_context(|| format!("Failed to read {}", path.display()))?
+                    {
+                        let child = child.context("Failed to list directory")?;
+                        pending.push(relative.join(child.file_name()));
+                    }
+                } else if metadata.is_file() {
+                    tree.files.insert(
+                        relative.clone(),
+                        TreeFile {
+                            size: metadata.len(),
+                            sha256: sha256s.get(&relative).cloned(),
+                            md5: md5s.get(&relative).cloned(),
+                            game: game.clone(),
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(tree)
+    }
+}
+
+/// Compare two output directories file by file, or game by game with `games_only`, reading the
+/// state `layout` keeps in each
+pub fn compare(a: &Path, b: &Path, layout: &Layout, games_only: bool) -> Result<DiffReport> {
+    let a = Tree::load(a, layout)?;
+    let b = Tree::load(b, layout)?;
+
+    let paths: BTreeSet<&PathBuf> = a.files.keys().chain(b.files.keys()).collect();
+    let mut files = Vec::new();
+    for path in paths {
+        let (file_a, file_b) = (a.files.get(path), b.files.get(path));
+        let status = match (file_a, file_b) {
+            (Some(file_a), Some(file_b)) if file_a.matches(file_b) => continue,
+            (Some(_), Some(_)) => DiffStatus::Differs,
+            (Some(_), None) => DiffStatus::OnlyInA,
+            (None, _) => DiffStatus::OnlyInB,
+        };
+        let game = file_a.or(file_b).map(|file| file.game.clone());
+        files.push((path, game, status, file_a, file_b));
+    }
+
+    let mut report = DiffReport::default();
+    for (_, _, status, file_a, file_b) in &files {
+        if *status != DiffStatus::OnlyInB {
+            report.bytes_a_to_b += file_a.map_or(0, |file| file.size);
+        }
+        if *status != DiffStatus::OnlyInA {
+            report.bytes_b_to_a += file_b.map_or(0, |file| file.size);
+        }
+    }
+
+    let title = |game: &str| {
+        a.titles
+            .get(game)
+            .or_else(|| b.titles.get(game))
+            .cloned()
+            .unwrap_or_else(|| game.to_string())
+    };
+
+    if games_only {
+        let games_a: BTreeSet<&String> = a.files.values().map(|file| &file.game).collect();
+        let games_b: BTreeSet<&String> = b.files.values().map(|file| &file.game).collect();
+        let mut games: BTreeMap<String, DiffEntry> = BTreeMap::new();
+        for (_, game, _, file_a, file_b) in &files {
+            let Some(game) = game else { continue };
+            let entry = games.entry(game.clone()).or_insert_with(|| DiffEntry {
+                name: title(game),
+                game: None,
+                status: match (games_a.contains(game), games_b.contains(game)) {
+                    (true, false) => DiffStatus::OnlyInA,
+                    (false, true) => DiffStatus::OnlyInB,
+                    _ => DiffStatus::Differs,
+                },
+                size_a: None,
+                size_b: None,
+            });
+            if let Some(file) = file_a {
+                *entry.size_a.get_or_insert(0) += file.size;
+            }
+            if let Some(file) = file_b {
+                *entry.size_b.get_or_insert(0) += file.size;
+            }
+        }
+        report.entries = games.into_values().collect();
+        report
+            .entries
+            .sort_by_key(|entry| entry.name.to_lowercase());
+    } else {
+        report.entries = files
+            .into_iter()
+            .map(|(path, game, status, file_a, file_b)| DiffEntry {
+                name: path.to_string_lossy().into_owned(),
+                game: game.map(|game| title(&game)),
+                status,
+                size_a: file_a.map(|file| file.size),
+                size_b: file_b.map(|file| file.size),
+            })
+            .collect();
+    }
+
+    Ok(report)
+}
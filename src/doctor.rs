@@ -0,0 +1,159 @@
+use serde::Serialize;
+use std::path::Path;
+
+/// Free space below which the output directory check fails
+pub const MIN_FREE_SPACE: u64 = 1024 * 1024 * 1024;
+
+/// How a single `doctor` check went
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DoctorStatus {
+    Pass,
+    Fail,
+    /// Not run because a check it depends on failed
+    Skip,
+}
+
+impl DoctorStatus {
+    /// Short name used in the table
+    pub fn label(self) -> &'static str {
+        match self {
+            DoctorStatus::Pass => "pass",
+            DoctorStatus::Fail => "FAIL",
+            DoctorStatus::Skip => "skip",
+        }
+    }
+}
+
+/// The result of one check, with what to do about it when it failed
+#[derive(Clone, Debug, Serialize)]
+pub struct DoctorCheck {
+    pub name: &'static str,
+    pub status: DoctorStatus,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+/// Everything `doctor` looked at, in the shape written by `--format json`
+#[derive(Debug, Default, Serialize)]
+pub struct DoctorReport {
+    pub checks: Vec<DoctorCheck>,
+}
+
+impl DoctorReport {
+    pub fn pass(&mut self, name: &'static str, detail: impl Into<String>) {
+        self.checks.push(DoctorCheck {
+            name,
+            status: DoctorStatus::Pass,
+            detail: detail.into(),
+            hint: None,
+        });
+    }
+
+    pub fn fail(&mut self, name: &'static str, detail: impl Into<String>, hint: impl Into<String>) {
+        self.checks.push(DoctorCheck {
+            name,
+            status: DoctorStatus::Fail,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        });
+    }
+
+    pub fn skip(&mut self, name: &'static str, detail: impl Into<String>) {
+        self.checks.push(DoctorCheck {
+            name,
+            status: DoctorStatus::Skip,
+            detail: detail.into(),
+            hint: None,
+        });
+    }
+
+    /// Whether every check that ran passed
+    pub fn is_healthy(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.status != DoctorStatus::Fail)
+    }
+
+    /// Check that files can be created in `dir`, or in the directory it would be created in
+    pub fn check_writable(&mut self, name: &'static str, dir: &Path, hint: &str) {
+        let existing = nearest_existing(dir);
+        match probe_write(existing) {
+            Ok(()) if existing == dir => self.pass(name, format!("{} is writable", dir.display())),
+            Ok(()) => self.pass(
+                name,
+                format!(
+                    "{} doesn't exist yet, {} is writable",
+                    dir.display(),
+                    existing.display()
+                ),
+            ),
+            Err(e) => self.fail(
+                name,
+                format!("Can't write to {}: {}", existing.display(), e),
+                hint,
+            ),
+        }
+    }
+
+    /// Check there's room for downloads on the filesystem holding `dir`
+    pub fn check_free_space(&mut self, dir: &Path) {
+        match free_space(nearest_existing(dir)) {
+            Some(free) if free >= MIN_FREE_SPACE => self.pass(
+                "free space",
+                format!("{} free", indicatif::HumanBytes(free)),
+            ),
+            Some(free) => self.fail(
+                "free space",
+                format!(
+                    "Only {} free on the filesystem holding {}",
+                    indicatif::HumanBytes(free),
+                    dir.display()
+                ),
+                "Free up some space or download to another disk with --output",
+            ),
+            None => self.skip("free space", "Not supported on this platform"),
+        }
+    }
+}
+
+/// `dir` itself, or the closest parent that exists, so checks don't create anything
+fn nearest_existing(dir: &Path) -> &Path {
+    dir.ancestors()
+        .map(|path| {
+            if path.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                path
+            }
+        })
+        .find(|path| path.is_dir())
+        .unwrap_or(Path::new("."))
+}
+
+/// Write and remove a scratch file in `dir`
+fn probe_write(dir: &Path) -> std::io::Result<()> {
+    let probe = dir.join(format!(".doctor-{}.tmp", std::process::id()));
+    std::fs::write(&probe, b"ok")?;
+    std::fs::remove_file(&probe)
+}
+
+/// Bytes available to this user on the filesystem holding `path`
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// Bytes available to this user on the filesystem holding `path`
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
@@ -0,0 +1,287 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Everything the client, extraction and the files kept alongside a mirror can fail with, so callers
+/// can tell the cases apart
+#[derive(Debug, Error)]
+pub enum ItchError {
+    /// itch.io rejected the API key
+    #[error("itch.io rejected the API key (status {status}): {body}")]
+    Auth {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    /// The server answered with an error status
+    #[error("{context} failed with status {status}: {body}{}", saved_note(.saved_to))]
+    Http {
+        /// What was being requested, e.g. "API request" or "Download request"
+        context: &'static str,
+        status: reqwest::StatusCode,
+        /// The start of the response body
+        body: String,
+        /// Where `--debug-http` saved the response, if it's recording
+        saved_to: Option<PathBuf>,
+    },
+    /// The API key only works with the legacy server-side API, which can't do what was asked
+    #[error(
+        "This is a legacy server-side API key, which itch.io only accepts for listing your own \
+         games (`ls --mine`) and describing the key itself (`whoami`). Create an API key at \
+         https://itch.io/user/settings/api-keys for everything else"
+    )]
+    LegacyKey,
+    /// itch.io sent a request made with a browser session cookie to the login page
+    #[error(
+        "itch.io didn't accept the session cookie, log in to itch.io and copy a fresh `itchio` cookie"
+    )]
+    SessionRejected,
+    /// Still rate limited (429) after every retry
+    #[error("Too many requests (429) - exceeded max retries ({retries})")]
+    RateLimited { retries: u32 },
+    /// The download kept ending before every byte arrived
+    #[error("Download ended after {received} of {expected} bytes, even after retrying")]
+    Truncated { received: u64, expected: u64 },
+    /// The request couldn't be sent or its response couldn't be read
+    #[error("{context}: {source}")]
+    Request {
+        context: &'static str,
+        source: reqwest::Error,
+    },
+    /// A response wasn't JSON, or didn't have the shape we expect
+    #[error(
+        "Unexpected response from {endpoint} at `{path}`: {message}\nNear: {snippet}{}",
+        saved_note(.saved_to)
+    )]
+    Decode {
+        endpoint: String,
+        /// JSON path of the value that didn't fit, `.` for the whole body
+        path: String,
+        message: String,
+        /// The JSON around the failure, cut down to a readable length
+        snippet: String,
+        /// Where `--debug-http` saved the body, if it's recording
+        saved_to: Option<PathBuf>,
+    },
+    /// Reading or writing a local file failed
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        source: std::io::Error,
+    },
+    /// The client is offline and nothing cached could stand in for the request
+    #[error("{context}: --offline forbids network requests and nothing usable is cached")]
+    Offline { context: &'static str },
+    /// The client replays recorded fixtures and none was recorded for a request
+    #[error("No recorded response to {request} in {}", .dir.display())]
+    MissingFixture { request: String, dir: PathBuf },
+    /// The caller's cancellation token fired, partial files have been removed
+    #[error("Cancelled")]
+    Cancelled,
+    /// The client's settings don't fit together
+    #[error("Invalid client configuration: {message}")]
+    Config { message: String },
+    /// An archive couldn't be extracted
+    #[error("{message}")]
+    Archive {
+        message: String,
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+    /// A file kept alongside the mirror, such as the manifest, couldn't be read or written as JSON
+    #[error("{context}: {source}")]
+    Format {
+        context: String,
+        source: serde_json::Error,
+    },
+    /// What was asked doesn't make sense, e.g. a directory template without a placeholder that
+    /// tells games apart or an output directory that's a file
+    #[error("{message}")]
+    Invalid { message: String },
+    /// Another run holds the lock on the output directory
+    #[error("{message}")]
+    Locked { message: String },
+    /// Another error, with what was being done when it happened
+    #[error("{context}: {source}")]
+    Context {
+        context: String,
+        source: Box<ItchError>,
+    },
+}
+
+/// What itch.io's errors say when a download key can't be used any more
+const KEY_LIMIT_PHRASES: &[&str] = &[
+    "download limit",
+    "too many downloads",
+    "no downloads remaining",
+    "key has been revoked",
+    "key was revoked",
+    "key has expired",
+    "key expired",
+];
+
+/// Whether an error body is itch.io refusing a download key that's used up, revoked or expired
+pub(crate) fn mentions_key_limit(body: &str) -> bool {
+    let body = body.to_lowercase();
+    KEY_LIMIT_PHRASES.iter().any(|phrase| body.contains(phrase))
+}
+
+/// Points at the file `--debug-http` wrote a response to
+fn saved_note(saved_to: &Option<PathBuf>) -> String {
+    match saved_to {
+        Some(path) => format!("\n(body saved to {})", path.display()),
+        None => String::new(),
+    }
+}
+
+impl ItchError {
+    /// Whether itch.io refused the API key, which retrying won't fix
+    pub fn is_auth(&self) -> bool {
+        matches!(self, ItchError::Auth { .. } | ItchError::LegacyKey)
+    }
+
+    /// Whether itch.io says the thing asked for has been deleted (404 or 410)
+    pub fn is_gone(&self) -> bool {
+        matches!(
+            self,
+            ItchError::Http {
+                status: reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE,
+                ..
+            }
+        )
+    }
+
+    /// Whether itch.io refused a download key that's used up, revoked or expired, which retrying
+    /// won't fix
+    pub fn is_key_limit(&self) -> bool {
+        match self {
+            ItchError::Http { status, body, .. } => {
+                status.is_client_error()
+                    && !matches!(
+                        *status,
+                        reqwest::StatusCode::NOT_FOUND
+                            | reqwest::StatusCode::GONE
+                            | reqwest::StatusCode::TOO_MANY_REQUESTS
+                    )
+                    && mentions_key_limit(body)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether asking again may well succeed: a dropped connection, a server error (5xx) or a
+    /// garbled response
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ItchError::Request { .. } | ItchError::Decode { .. } => true,
+            ItchError::Http { status, .. } => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    /// Whether the work was cancelled rather than failing
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, ItchError::Cancelled)
+    }
+
+    pub(crate) fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        ItchError::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    #[cfg(feature = "extract")]
+    pub(crate) fn archive(message: impl Into<String>) -> Self {
+        ItchError::Archive {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// An archive error caused by `source`, `message` saying what was being done
+    #[cfg(feature = "extract")]
+    pub(crate) fn archive_caused(
+        message: impl Into<String>,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        let source = source.into();
+        ItchError::Archive {
+            message: format!("{}: {}", message.into(), source),
+            source: Some(source),
+        }
+    }
+}
+
+/// Results from the client, extraction and the files kept alongside a mirror
+pub type Result<T, E = ItchError> = std::result::Result<T, E>;
+
+/// Return early with an [`ItchError::Invalid`], the way `anyhow::bail!` would
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err($crate::error::ItchError::Invalid {
+            message: format!($($arg)*),
+        })
+    };
+}
+pub(crate) use bail;
+
+/// Say what was being done when an error happened, the way `anyhow::Context` does
+pub(crate) trait Context<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T>
+    where
+        Self: Sized,
+    {
+        self.context(context())
+    }
+}
+
+impl<T> Context<T> for std::io::Result<T> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| ItchError::io(context, e))
+    }
+}
+
+impl<T> Context<T> for serde_json::Result<T> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|source| ItchError::Format {
+            context: context.into(),
+            source,
+        })
+    }
+}
+
+impl<T> Context<T> for std::result::Result<T, tokio::task::JoinError> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| ItchError::io(context, e.into()))
+    }
+}
+
+impl<T> Context<T> for Option<T> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.ok_or_else(|| ItchError::Invalid {
+            message: context.into(),
+        })
+    }
+}
+
+impl<T> Context<T> for Result<T> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|source| ItchError::Context {
+            context: context.into(),
+            source: Box::new(source),
+        })
+    }
+}
+
+/// Run `future` unless `cancel` fires first
+pub(crate) async fn cancellable<T>(
+    cancel: &tokio_util::sync::CancellationToken,
+    future: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(ItchError::Cancelled),
+        result = future => result,
+    }
+}
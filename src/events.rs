@@ -0,0 +1,160 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use itch_downloader::DownloadProgress;
+use itch_downloader::history::Outcome;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// A game selected for the run, as listed in `run_started`
+#[derive(Debug, Serialize)]
+pub struct PlannedGame<'a> {
+    pub game_id: u64,
+    pub title: &'a str,
+}
+
+/// One line of `--porcelain` output, tagged with its kind in the `event` field
+///
+/// These are a stable interface for wrappers, so fields are only ever added, never renamed.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// The games the run is about to work through
+    RunStarted {
+        games: Vec<PlannedGame<'a>>,
+    },
+    DownloadStarted {
+        game_id: u64,
+        upload_id: u64,
+        filename: &'a str,
+        /// Size from the uploads listing, 0 when itch doesn't report one
+        total: u64,
+    },
+    /// Sent about once a second while a download is running
+    DownloadProgress {
+        upload_id: u64,
+        bytes: u64,
+        total: u64,
+        /// Average bytes per second since the download started
+        rate: u64,
+    },
+    /// The game's outcome, see the report for the fields
+    DownloadFinished(&'a Outcome),
+    DownloadSkipped(&'a Outcome),
+    DownloadFailed(&'a Outcome),
+    ExtractionStarted {
+        game_id: u64,
+        filename: &'a str,
+    },
+    ExtractionFinished {
+        game_id: u64,
+        filename: &'a str,
+        path: &'a Path,
+    },
+    ExtractionFailed {
+        game_id: u64,
+        filename: &'a str,
+        error: String,
+    },
+    Summary {
+        completed: usize,
+        skipped: usize,
+        failed: usize,
+        gone: usize,
+        /// Games whose download key itch.io refused
+        key_limit: usize,
+        bytes: u64,
+        /// Requests made to itch.io, and how many of them it throttled
+        requests: u64,
+        throttled: u64,
+    },
+}
+
+/// Write an event to stdout as a line of JSON, when running with `--porcelain`
+pub fn emit(event: &Event) {
+    if !crate::ui::is_porcelain() {
+        return;
+    }
+    let Ok(line) = serde_json::to_string(event) else {
+        return;
+    };
+    // Locked so lines from concurrent downloads never interleave
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{}", line);
+    let _ = stdout.flush();
+}
+
+/// Show a download's progress on its bar, also sending `download_progress` about once a second
+/// with `--porcelain`
+///
+/// `filename` names the download in messages until the server says what it'll be saved as.
+pub fn progress_sink(
+    upload_id: u64,
+    filename: &str,
+    bar: ProgressBar,
+) -> impl FnMut(DownloadProgress) + Send + use<> {
+    let mut filename = filename.to_string();
+    let started = Instant::now();
+    let mut last_sent: Option<Instant> = None;
+    let mut total = 0;
+    move |progress| match progress {
+        DownloadProgress::Started {
+            filename: saved_as,
+            total: size,
+        } => {
+            filename = saved_as;
+            total = size;
+            if size == 0 {
+                // Without a size there's nothing to fill a bar towards, so just count the bytes
+                bar.set_style(
+                    ProgressStyle::default_spinner()
+                        .template("{spinner} {msg} {bytes} ({bytes_per_sec})")
+                        .unwrap(),
+                );
+                bar.unset_length();
+            } else {
+                bar.set_length(size);
+            }
+            bar.set_message(format!("Downloading {}", filename));
+        }
+        DownloadProgress::Bytes(bytes) => {
+            bar.set_position(bytes);
+            let due = last_sent.is_none_or(|sent| sent.elapsed() >= Duration::from_secs(1));
+            if crate::ui::is_porcelain() && due {
+                last_sent = Some(Instant::now());
+                let elapsed = started.elapsed().as_secs_f64().max(0.001);
+                emit(&Event::DownloadProgress {
+                    upload_id,
+                    bytes,
+                    total,
+                    rate: (bytes as f64 / elapsed) as u64,
+                });
+            }
+        }
+        DownloadProgress::RetryScheduled(delay) => {
+            bar.set_message(format!(
+                "Rate limited, retrying {} in {:?}...",
+                filename, delay
+            ));
+        }
+        DownloadProgress::Resuming(delay) => {
+            bar.set_message(format!(
+                "Download of {} broke off, resuming in {:?}...",
+                filename, delay
+            ));
+        }
+        DownloadProgress::Done => {}
+    }
+}
+
+/// The event reporting how a game ended
+pub fn outcome(outcome: &Outcome) -> Event<'_> {
+    match outcome.status {
+        itch_downloader::history::Status::Completed => Event::DownloadFinished(outcome),
+        itch_downloader::history::Status::Skipped => Event::DownloadSkipped(outcome),
+        // Told apart from other failures by the outcome's `status`
+        itch_downloader::history::Status::Failed
+        | itch_downloader::history::Status::Gone
+        | itch_downloader::history::Status::KeyLimit => Event::DownloadFailed(outcome),
+    }
+}
@@ -0,0 +1,541 @@
+use crate::error::{Context, Result};
+use crate::layout::{Category, STATE_DIR};
+use crate::manifest::{Manifest, top_level_name};
+use crate::models::Reviews;
+use crate::receipt::Receipt;
+use crate::timestamp::{self, Timestamp};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// How deep inside a game's directory we look for its executable
+const MAX_EXECUTABLE_DEPTH: usize = 3;
+
+/// Names a cover image is saved under inside a game's directory
+const COVER_NAMES: [&str; 3] = ["cover.png", "cover.jpg", "cover.gif"];
+
+/// Name of the sidecar written next to each game's files
+pub const METADATA_FILE: &str = "metadata.json";
+
+/// Names of the files a game's reviews are archived to
+pub const REVIEWS_FILE: &str = "reviews.json";
+pub const REVIEWS_CSV_FILE: &str = "reviews.csv";
+
+/// Name of the Pegasus collection file
+const PEGASUS_FILE: &str = "metadata.pegasus.txt";
+
+/// Name of the file Playnite entries are written to
+const PLAYNITE_FILE: &str = "playnite.json";
+
+/// Which platform's executables to look for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform {
+    Linux,
+    Windows,
+}
+
+impl Platform {
+    /// The platform this binary was built for
+    pub fn host() -> Self {
+        if cfg!(windows) {
+            Platform::Windows
+        } else {
+            Platform::Linux
+        }
+    }
+}
+
+/// What itch told us about a game, saved alongside its files for launchers
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GameMetadata {
+    pub game_id: u64,
+    pub title: String,
+    pub developer: Option<String>,
+    pub short_text: Option<String>,
+    #[serde(default, deserialize_with = "crate::json::timestamp")]
+    pub published_at: Option<Timestamp>,
+    pub url: Option<String>,
+    /// `<author>/<game>` from the URL, which tells the game's directory apart after a rename
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    pub cover_url: Option<String>,
+    /// The page's full description as HTML, when the game endpoint could be reached
+    pub description: Option<String>,
+    /// The systems the game is marked as running on
+    #[serde(default)]
+    pub platforms: Vec<String>,
+    /// Who downloaded the files and with which key, also written out as `RECEIPT.txt`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<Receipt>,
+    /// How many times itch had counted the key being downloaded when the sidecar was written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_downloads: Option<u64>,
+    /// The bundle the key came from, or `direct purchase/claim`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// The folder each downloaded file was sorted into with `--organize`, by filename
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub organized: BTreeMap<String, Category>,
+}
+
+impl GameMetadata {
+    /// Write the sidecar into `dir`, replacing an older one
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let contents =
+            serde_json::to_string_pretty(self).context("Failed to serialize game metadata")?;
+        std::fs::write(dir.join(METADATA_FILE), contents)
+            .with_context(|| format!("Failed to write metadata for {}", self.title))
+    }
+
+    /// The sidecar saved with a game, looked for in its directory and the one above, or two
+    /// above from inside one of the category folders of `--organize`
+    pub fn find(path: &Path) -> Option<Self> {
+        let parent = path.parent();
+        let organized = parent
+            .filter(|dir| {
+                dir.file_name()
+                    .is_some_and(|name| Category::is_dir_name(&name.to_string_lossy()))
+            })
+            .and_then(Path::parent);
+        [Some(path), parent, organized]
+            .into_iter()
+            .flatten()
+            .find_map(|dir| std::fs::read_to_string(dir.join(METADATA_FILE)).ok())
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+    }
+}
+
+/// A game in the mirror that can be exported to a launcher
+#[derive(Debug)]
+pub struct ExportGame {
+    pub title: String,
+    /// The extracted directory or downloaded file
+    pub path: PathBuf,
+    pub metadata: Option<GameMetadata>,
+}
+
+impl ExportGame {
+    fn new(title: String, path: PathBuf) -> Self {
+        let metadata = GameMetadata::find(&path);
+        Self {
+            title,
+            path,
+            metadata,
+        }
+    }
+
+    /// The release date as `YYYY-MM-DD`, if itch has one
+    fn release_date(&self) -> Option<String> {
+        Some(timestamp::date(self.metadata.as_ref()?.published_at?))
+    }
+}
+
+/// Quote a CSV field when it needs it
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Archive a game's ratings and reviews into `dir`, as JSON and as a CSV of the reviews
+pub fn write_reviews(reviews: &Reviews, dir: &Path) -> Result<()> {
+    let contents = serde_json::to_string_pretty(reviews).context("Failed to serialize reviews")?;
+    std::fs::write(dir.join(REVIEWS_FILE), contents).context("Failed to write reviews")?;
+
+    let mut csv = String::from("id,created_at,rating,user,body\n");
+    for review in &reviews.reviews {
+        let fields = [
+            review.id.to_string(),
+            review
+                .created_at
+                .map(timestamp::to_rfc3339)
+                .unwrap_or_default(),
+            review
+                .rating
+                .map(|rating| rating.to_string())
+                .unwrap_or_default(),
+            review
+                .user
+                .as_ref()
+                .map(|user| user.username.clone())
+                .unwrap_or_default(),
+            review.body.clone().unwrap_or_default(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    std::fs::write(dir.join(REVIEWS_CSV_FILE), csv).context("Failed to write reviews CSV")
+}
+
+/// The games in a mirror, from its manifest plus any directories `dl` left without one
+///
+/// Directories that aren't in the manifest are named after the game, so the name stands in for the title.
+pub fn collect_games(manifest: &Manifest) -> Result<Vec<ExportGame>> {
+    let mut games = Vec::new();
+    let mut recorded = HashSet::new();
+
+    for (_, game) in manifest.games() {
+        let paths: Vec<PathBuf> = game
+            .uploads
+            .values()
+            .map(|upload| {
+                if let Some(name) = top_level_name(&upload.path) {
+                    recorded.insert(name);
+                }
+                manifest.resolve(&upload.path)
+            })
+            .collect();
+        // Extracted uploads are the ones that can be played
+        let Some(path) = paths
+            .iter()
+            .find(|path| path.is_dir())
+            .or(paths.first())
+            .cloned()
+        else {
+            continue;
+        };
+        games.push(ExportGame::new(game.title.clone(), path));
+    }
+
+    for entry in std::fs::read_dir(manifest.resolve(Path::new("")))
+        .context("Failed to read output directory")?
+    {
+        let entry = entry.context("Failed to list output directory")?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == STATE_DIR || crate::staging::is_temp_name(&name) || recorded.contains(&name) {
+            continue;
+        }
+        if entry.path().is_dir() {
+            let game = ExportGame::new(name, entry.path());
+            // The sidecar knows the real title, the directory name had to be sanitized
+            let title = game
+                .metadata
+                .as_ref()
+                .map(|metadata| metadata.title.clone());
+            games.push(ExportGame {
+                title: title.unwrap_or(game.title),
+                ..game
+            });
+        }
+    }
+
+    games.sort_by(|a, b| a.title.cmp(&b.title));
+    Ok(games)
+}
+
+/// Turn a title into a launcher slug, e.g. `Minifantasy: True Heroes` -> `minifantasy-true-heroes`
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Quote a string for YAML, escaping what needs escaping
+fn yaml_string(value: &str) -> String {
+    let escaped: String = value
+        .chars()
+        .flat_map(|c| match c {
+            '\\' => vec!['\\', '\\'],
+            '"' => vec!['\\', '"'],
+            '\n' => vec!['\\', 'n'],
+            c => vec![c],
+        })
+        .collect();
+    format!("\"{}\"", escaped)
+}
+
+/// Whether a file looks like something that can be launched on `platform`, and how likely it's the game
+///
+/// Higher is better, `None` means it isn't a candidate at all.
+fn executable_score(path: &Path, platform: Platform) -> Option<u32> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    if platform == Platform::Windows {
+        // Uninstallers and crash reporters ship next to plenty of games
+        let helper = ["unins", "crashhandler", "crashreport", "setup"]
+            .iter()
+            .any(|helper| name.contains(helper));
+        return (name.ends_with(".exe") && !helper).then_some(2);
+    }
+    if name.ends_with(".x86_64") {
+        return Some(4);
+    }
+    if name.ends_with(".appimage") {
+        return Some(2);
+    }
+    if name.ends_with(".so") || name.contains(".so.") {
+        return None;
+    }
+
+    let mut header = [0u8; 4];
+    let read = std::fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read(&mut file, &mut header))
+        .ok()?;
+    let is_elf = read == 4 && header == *b"\x7fELF";
+    let is_script = read >= 2 && header[..2] == *b"#!";
+
+    // Executable bits don't survive every archive, so contents count for more than permissions
+    if is_elf {
+        Some(3)
+    } else if name.ends_with(".sh") || is_script || has_executable_bit(path) {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+#[cfg(unix)]
+fn has_executable_bit(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn has_executable_bit(_path: &Path) -> bool {
+    false
+}
+
+/// Find the most likely executable for a game on `platform`, preferring shallow paths
+pub fn find_executable(path: &Path, platform: Platform) -> Option<PathBuf> {
+    if path.is_file() {
+        return executable_score(path, platform).map(|_| path.to_path_buf());
+    }
+
+    let mut best: Option<(u32, Reverse<usize>, Reverse<PathBuf>)> = None;
+    let mut pending = vec![(path.to_path_buf(), 0)];
+    while let Some((dir, depth)) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let entry_path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                if depth + 1 < MAX_EXECUTABLE_DEPTH {
+                    pending.push((entry_path, depth + 1));
+                }
+                continue;
+            }
+            let Some(score) = executable_score(&entry_path, platform) else {
+                continue;
+            };
+            // Ties go to the shallowest path, then alphabetically so runs agree with each other
+            let candidate = (score, Reverse(depth), Reverse(entry_path));
+            if best.as_ref().is_none_or(|best| candidate > *best) {
+                best = Some(candidate);
+            }
+        }
+    }
+
+    best.map(|(_, _, Reverse(path))| path)
+}
+
+/// A cover image saved alongside a game, if there is one
+pub fn find_cover(path: &Path) -> Option<PathBuf> {
+    let dirs = [Some(path), path.parent()];
+    dirs.into_iter()
+        .flatten()
+        .flat_map(|dir| COVER_NAMES.iter().map(move |name| dir.join(name)))
+        .find(|cover| cover.is_file())
+}
+
+/// Write a Lutris installer for each game with an identifiable executable into `export_dir`
+///
+/// Returns the titles of games that need setting up by hand.
+pub fn write_lutris(games: &[ExportGame], export_dir: &Path) -> Result<Vec<String>> {
+    std::fs::create_dir_all(export_dir).context("Failed to create export directory")?;
+    let mut unidentified = Vec::new();
+
+    for game in games {
+        let Some(executable) = find_executable(&game.path, Platform::Linux) else {
+            unidentified.push(format!("{} ({})", game.title, game.path.display()));
+            continue;
+        };
+        let executable = std::path::absolute(&executable).unwrap_or(executable);
+        let working_dir = executable.parent().unwrap_or(Path::new("/"));
+        let slug = slugify(&game.title);
+
+        let installer = format!(
+            "name: {}\ngame_slug: {}\nversion: itch-downloader\nslug: {}\nrunner: linux\nscript:\n  game:\n    exe: {}\n    working_dir: {}\n",
+            yaml_string(&game.title),
+            yaml_string(&slug),
+            yaml_string(&format!("{}-itch-downloader", slug)),
+            yaml_string(&executable.to_string_lossy()),
+            yaml_string(&working_dir.to_string_lossy()),
+        );
+        std::fs::write(export_dir.join(format!("{}.yml", slug)), installer)
+            .with_context(|| format!("Failed to write Lutris entry for {}", game.title))?;
+
+        // Lutris looks covers up by slug in its coverart directory
+        if let Some(cover) = find_cover(&game.path) {
+            let extension = cover
+                .extension()
+                .map(|extension| extension.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let cover_dir = export_dir.join("coverart");
+            std::fs::create_dir_all(&cover_dir).context("Failed to create coverart directory")?;
+            std::fs::copy(&cover, cover_dir.join(format!("{}.{}", slug, extension)))
+                .with_context(|| format!("Failed to copy cover for {}", game.title))?;
+        }
+    }
+
+    Ok(unidentified)
+}
+
+/// Write `contents` to `path` without leaving a half written file behind if interrupted
+fn replace_file(path: &Path, contents: &str) -> Result<()> {
+    let temp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    std::fs::write(&temp_path, contents)
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    std::fs::rename(&temp_path, path)
+        .with_context(|| format!("Failed to move {} into place", path.display()))
+}
+
+/// Format a Pegasus value, continuing extra lines with an indent and blank ones with `.`
+fn pegasus_value(value: &str) -> String {
+    value
+        .trim()
+        .lines()
+        .map(|line| {
+            if line.trim().is_empty() {
+                "."
+            } else {
+                line.trim()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n  ")
+}
+
+/// Write a Pegasus `metadata.pegasus.txt` collection covering every game into `export_dir`
+///
+/// The file is regenerated from the mirror each time, so re-running picks up new games.
+/// Returns the titles of games without a launch file.
+pub fn write_pegasus(games: &[ExportGame], export_dir: &Path) -> Result<Vec<String>> {
+    std::fs::create_dir_all(export_dir).context("Failed to create export directory")?;
+    let mut unidentified = Vec::new();
+    let mut collection = String::from("collection: itch.io\nlaunch: \"{file.path}\"\n");
+
+    for game in games {
+        collection.push_str(&format!("\ngame: {}\n", pegasus_value(&game.title)));
+        match find_executable(&game.path, Platform::host()) {
+            Some(executable) => {
+                let executable = std::path::absolute(&executable).unwrap_or(executable);
+                collection.push_str(&format!("file: {}\n", executable.display()));
+            }
+            None => unidentified.push(format!("{} ({})", game.title, game.path.display())),
+        }
+        if let Some(metadata) = &game.metadata {
+            if let Some(developer) = &metadata.developer {
+                collection.push_str(&format!("developer: {}\n", pegasus_value(developer)));
+            }
+            if let Some(short_text) = &metadata.short_text {
+                collection.push_str(&format!("description: {}\n", pegasus_value(short_text)));
+            }
+        }
+        if let Some(release) = game.release_date() {
+            collection.push_str(&format!("release: {}\n", release));
+        }
+        if let Some(cover) = find_cover(&game.path) {
+            let cover = std::path::absolute(&cover).unwrap_or(cover);
+            collection.push_str(&format!("assets.boxFront: {}\n", cover.display()));
+        }
+    }
+
+    replace_file(&export_dir.join(PEGASUS_FILE), &collection)?;
+    Ok(unidentified)
+}
+
+/// A game in the shape Playnite's importers expect
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PlayniteGame {
+    game_id: String,
+    name: String,
+    source: &'static str,
+    developers: Vec<String>,
+    description: Option<String>,
+    release_date: Option<String>,
+    cover_image: Option<PathBuf>,
+    install_directory: PathBuf,
+    is_installed: bool,
+    game_actions: Vec<PlayniteAction>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PlayniteAction {
+    name: &'static str,
+    #[serde(rename = "Type")]
+    action_type: &'static str,
+    path: PathBuf,
+    working_dir: PathBuf,
+    is_play_action: bool,
+}
+
+/// Write a Playnite-importable JSON list covering every game into `export_dir`
+///
+/// Like the Pegasus file it's regenerated from the mirror each time.
+/// Returns the titles of games without a Windows executable.
+pub fn write_playnite(games: &[ExportGame], export_dir: &Path) -> Result<Vec<String>> {
+    std::fs::create_dir_all(export_dir).context("Failed to create export directory")?;
+    let mut unidentified = Vec::new();
+    let mut entries = Vec::new();
+
+    for game in games {
+        let path = std::path::absolute(&game.path).unwrap_or(game.path.clone());
+        let install_directory = if path.is_dir() {
+            path.clone()
+        } else {
+            path.parent().unwrap_or(&path).to_path_buf()
+        };
+        let game_actions: Vec<PlayniteAction> = find_executable(&path, Platform::Windows)
+            .map(|executable| PlayniteAction {
+                name: "Play",
+                action_type: "File",
+                working_dir: executable.parent().unwrap_or(&path).to_path_buf(),
+                path: executable,
+                is_play_action: true,
+            })
+            .into_iter()
+            .collect();
+        if game_actions.is_empty() {
+            unidentified.push(format!("{} ({})", game.title, game.path.display()));
+        }
+
+        let metadata = game.metadata.clone().unwrap_or_default();
+        entries.push(PlayniteGame {
+            // Stable across runs so Playnite updates the entry instead of adding another
+            game_id: if metadata.game_id != 0 {
+                metadata.game_id.to_string()
+            } else {
+                slugify(&game.title)
+            },
+            name: game.title.clone(),
+            source: "itch.io",
+            developers: metadata.developer.into_iter().collect(),
+            description: metadata.short_text,
+            release_date: game.release_date(),
+            cover_image: find_cover(&path),
+            install_directory,
+            is_installed: true,
+            game_actions,
+        });
+    }
+
+    let contents =
+        serde_json::to_string_pretty(&entries).context("Failed to serialize Playnite entries")?;
+    replace_file(&export_dir.join(PLAYNITE_FILE), &contents)?;
+    Ok(unidentified)
+}
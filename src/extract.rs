@@ -0,0 +1,440 @@
+//! Recognizing archives, and with the `extract` feature extracting them
+//!
+//! Archive detection is always available since choosing an upload depends on it, the extractors
+//! and the crates behind them are only built with the `extract` feature.
+
+use std::fs::File as StdFile;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "extract")]
+mod audit;
+#[cfg(feature = "extract")]
+mod unpack;
+
+#[cfg(feature = "extract")]
+pub use audit::{EntryFinding, EntryProblem, ExtractionAudit, audit_extraction};
+#[cfg(feature = "extract")]
+pub use unpack::extract_archive;
+
+/// Encoding used for zip entry names that aren't flagged as UTF-8
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ZipEncoding {
+    /// IBM code page 437, the zip specification's default
+    #[default]
+    Cp437,
+    /// Shift-JIS, common for Japanese archives created on Windows
+    Sjis,
+    /// GBK, common for Simplified Chinese archives
+    Gbk,
+    /// Big5, common for Traditional Chinese archives
+    Big5,
+    /// EUC-KR, common for Korean archives
+    EucKr,
+}
+
+/// The archive formats we know how to extract
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    TarGz,
+    TarXz,
+    Tar,
+    SevenZip,
+    /// RAR archives can only be extracted through an external tool
+    Rar,
+}
+
+/// Options controlling how archives are extracted
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    /// Encoding for zip entry names that aren't flagged as UTF-8
+    pub zip_encoding: ZipEncoding,
+    /// An `unrar` or `7z` binary used to extract RAR archives
+    pub unrar_path: Option<PathBuf>,
+    /// How many levels of archives-within-archives to extract, 1 only extracts the download itself
+    pub max_depth: usize,
+    /// Where temporary extraction directories go, next to the target when unset
+    pub temp_dir: Option<PathBuf>,
+    /// How many threads extract a zip with many entries, 0 and 1 both extract on one
+    pub jobs: usize,
+}
+
+impl ExtractOptions {
+    /// Whether archives of this kind can be extracted with these options
+    pub fn can_extract(&self, kind: ArchiveKind) -> bool {
+        kind != ArchiveKind::Rar || self.unrar_path.is_some()
+    }
+}
+
+impl ArchiveKind {
+    /// Work out the archive format from a filename's extension
+    pub fn from_filename(filename: &str) -> Option<Self> {
+        let filename = filename.to_lowercase();
+        if filename.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if filename.ends_with(".7z") {
+            Some(ArchiveKind::SevenZip)
+        } else if filename.ends_with(".rar") {
+            Some(ArchiveKind::Rar)
+        } else if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else if filename.ends_with(".tar.xz") || filename.ends_with(".txz") {
+            Some(ArchiveKind::TarXz)
+        } else if filename.ends_with(".tar") {
+            Some(ArchiveKind::Tar)
+        } else {
+            None
+        }
+    }
+
+    /// Work out the archive format from the file's magic bytes, falling back to its extension
+    ///
+    /// Uploads are regularly misnamed (self-extracting exes called `.zip`, tarballs without an
+    /// extension) so the contents are the authority whenever they're recognisable.
+    pub fn detect(path: &Path, filename: &str) -> Option<Self> {
+        let mut header = [0u8; 512];
+        let read = StdFile::open(path)
+            .and_then(|mut file| read_up_to(&mut file, &mut header))
+            .unwrap_or(0);
+        let header = &header[..read];
+
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Some(ArchiveKind::Zip)
+        } else if header.starts_with(&[0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C]) {
+            Some(ArchiveKind::SevenZip)
+        } else if header.starts_with(b"Rar!\x1A\x07") {
+            Some(ArchiveKind::Rar)
+        } else if header.starts_with(&[0x1F, 0x8B]) {
+            Some(ArchiveKind::TarGz)
+        } else if header.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(ArchiveKind::TarXz)
+        } else if header.len() >= 262 && &header[257..262] == b"ustar" {
+            Some(ArchiveKind::Tar)
+        } else if read > 0 && ArchiveKind::from_filename(filename) != Some(ArchiveKind::Tar) {
+            // The file has contents we don't recognise, so don't trust the extension
+            None
+        } else {
+            // Old-style tarballs have no magic at all
+            ArchiveKind::from_filename(filename)
+        }
+    }
+}
+
+/// Fill as much of `buffer` as the reader allows, returning how many bytes were read
+fn read_up_to(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Strip a known archive extension from a filename, `assets.tar.gz` becomes `assets`
+pub fn strip_archive_extension(filename: &str) -> &str {
+    for extension in [
+        ".tar.gz", ".tar.xz", ".tgz", ".txz", ".tar", ".zip", ".7z", ".rar",
+    ] {
+        let stem_len = filename.len().saturating_sub(extension.len());
+        if stem_len > 0
+            && filename.is_char_boundary(stem_len)
+            && filename[stem_len..].eq_ignore_ascii_case(extension)
+        {
+            return &filename[..stem_len];
+        }
+    }
+    filename
+}
+
+/// Marker file recording which upload an extraction directory was extracted from
+pub const UPLOAD_MARKER: &str = ".itch-upload";
+
+/// The upload that was last extracted into `dir`, if it was marked
+pub fn extracted_upload(dir: &Path) -> Option<u64> {
+    std::fs::read_to_string(dir.join(UPLOAD_MARKER))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Record that `dir` holds the contents of `upload_id`
+pub fn mark_extracted(dir: &Path, upload_id: u64) -> std::io::Result<()> {
+    std::fs::write(dir.join(UPLOAD_MARKER), format!("{}\n", upload_id))
+}
+
+/// What `dl --unzip` does when a different upload already extracted into the target directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ExtractConflict {
+    /// Extract into a directory suffixed with the upload id instead
+    #[default]
+    Suffix,
+    /// Leave the archive unextracted
+    Skip,
+    /// Remove the other upload's files first
+    Clean,
+}
+
+/// What becomes of a downloaded file with `--unzip`, worked out before anything is extracted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExtractDecision {
+    /// Not an archive, so it stays as downloaded
+    Keep,
+    /// Named like an archive of this kind but not one inside, e.g. a self-extracting exe
+    Misnamed(ArchiveKind),
+    /// An archive the options have no extractor for
+    NoExtractor(ArchiveKind),
+    /// Another upload's files are in the directory and [`ExtractConflict::Skip`] leaves them be
+    Held { dir_name: String, previous: u64 },
+    /// Extract into `dir_name`, once whatever `conflict` says about its current files is done
+    Extract {
+        kind: ArchiveKind,
+        dir_name: String,
+        conflict: Option<Conflict>,
+    },
+}
+
+/// How an extraction gets past another upload's files in its directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Conflict {
+    /// `previous`'s files are removed first
+    Replace { previous: u64 },
+    /// `held` has `previous`'s files, so the archive goes into a directory suffixed with its id
+    Suffix { held: String, previous: u64 },
+}
+
+impl Conflict {
+    /// What was done about it, e.g. `replaced upload 12's files in Game`
+    pub fn describe(&self, dir_name: &str) -> String {
+        match self {
+            Conflict::Replace { previous } => {
+                format!("replaced upload {}'s files in {}", previous, dir_name)
+            }
+            Conflict::Suffix { held, previous } => format!(
+                "extracted to {} since {} holds upload {}",
+                dir_name, held, previous
+            ),
+        }
+    }
+}
+
+/// Decide what becomes of `filename`, downloaded into `dir` from `upload_id`, which would extract
+/// into `dir_name` within it
+///
+/// The archive kind comes from the file's contents, see [`ArchiveKind::detect`], and another
+/// upload's files in the directory are dealt with as `on_conflict` says.
+pub fn decide(
+    dir: &Path,
+    filename: &str,
+    dir_name: String,
+    upload_id: u64,
+    options: &ExtractOptions,
+    on_conflict: ExtractConflict,
+) -> ExtractDecision {
+    let Some(kind) = ArchiveKind::detect(&dir.join(filename), filename) else {
+        return match ArchiveKind::from_filename(filename) {
+            Some(named) => ExtractDecision::Misnamed(named),
+            None => ExtractDecision::Keep,
+        };
+    };
+    if !options.can_extract(kind) {
+        return ExtractDecision::NoExtractor(kind);
+    }
+    // Another upload's files already there, e.g. the Windows build when this is the Linux one
+    let previous = extracted_upload(&dir.join(&dir_name)).filter(|&previous| previous != upload_id);
+    let (dir_name, conflict) = match (previous, on_conflict) {
+        (None, _) => (dir_name, None),
+        (Some(previous), ExtractConflict::Skip) => {
+            return ExtractDecision::Held { dir_name, previous };
+        }
+        (Some(previous), ExtractConflict::Clean) => {
+            (dir_name, Some(Conflict::Replace { previous }))
+        }
+        (Some(previous), ExtractConflict::Suffix) => (
+            format!("{}-{}", dir_name, upload_id),
+            Some(Conflict::Suffix {
+                held: dir_name,
+                previous,
+            }),
+        ),
+    };
+    ExtractDecision::Extract {
+        kind,
+        dir_name,
+        conflict,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(contents: &[u8], filename: &str) -> Option<ArchiveKind> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(filename);
+        std::fs::write(&path, contents).unwrap();
+        ArchiveKind::detect(&path, filename)
+    }
+
+    #[test]
+    fn contents_win_over_the_extension() {
+        // A self-extracting exe uploaded as a zip
+        assert_eq!(detect(b"MZ\x90\x00\x03\x00\x00\x00", "game.zip"), None);
+        assert_eq!(detect(b"<!DOCTYPE html>", "game.zip"), None);
+        assert_eq!(
+            detect(b"PK\x03\x04rest", "game.bin"),
+            Some(ArchiveKind::Zip)
+        );
+        assert_eq!(detect(b"PK\x05\x06", "empty.zip"), Some(ArchiveKind::Zip));
+        assert_eq!(
+            detect(&[0x1F, 0x8B, 8, 0], "game"),
+            Some(ArchiveKind::TarGz)
+        );
+        assert_eq!(
+            detect(b"Rar!\x1A\x07\x01\x00", "game.zip"),
+            Some(ArchiveKind::Rar)
+        );
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(detect(&tar, "game.dat"), Some(ArchiveKind::Tar));
+    }
+
+    #[test]
+    fn only_tarballs_are_taken_without_magic() {
+        assert_eq!(detect(b"old style tar", "game.tar"), Some(ArchiveKind::Tar));
+        assert_eq!(detect(b"", "game.zip"), Some(ArchiveKind::Zip));
+        assert_eq!(detect(b"", "game.exe"), None);
+    }
+
+    #[test]
+    fn archive_extensions_are_stripped_whatever_their_case() {
+        assert_eq!(strip_archive_extension("assets.tar.gz"), "assets");
+        assert_eq!(strip_archive_extension("Game.ZIP"), "Game");
+        assert_eq!(strip_archive_extension("game.exe"), "game.exe");
+        // A name that's only an extension keeps it
+        assert_eq!(strip_archive_extension(".zip"), ".zip");
+    }
+
+    /// A directory holding `filename` with `contents`, and `Game` extracted from `previous`
+    fn download(filename: &str, contents: &[u8], previous: Option<u64>) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(filename), contents).unwrap();
+        if let Some(previous) = previous {
+            std::fs::create_dir(dir.path().join("Game")).unwrap();
+            mark_extracted(&dir.path().join("Game"), previous).unwrap();
+        }
+        dir
+    }
+
+    fn decide_for(dir: &Path, filename: &str, on_conflict: ExtractConflict) -> ExtractDecision {
+        let options = ExtractOptions::default();
+        decide(dir, filename, "Game".to_string(), 7, &options, on_conflict)
+    }
+
+    #[test]
+    fn archives_extract_into_their_directory() {
+        let dir = download("game.zip", b"PK\x03\x04rest", None);
+        let expected = ExtractDecision::Extract {
+            kind: ArchiveKind::Zip,
+            dir_name: "Game".to_string(),
+            conflict: None,
+        };
+        assert_eq!(
+            decide_for(dir.path(), "game.zip", ExtractConflict::Skip),
+            expected
+        );
+
+        // The same upload extracted again isn't a conflict
+        let dir = download("game.zip", b"PK\x03\x04rest", Some(7));
+        assert_eq!(
+            decide_for(dir.path(), "game.zip", ExtractConflict::Skip),
+            expected
+        );
+    }
+
+    #[test]
+    fn other_files_are_kept_or_called_misnamed() {
+        let dir = download("game.exe", b"MZ", None);
+        assert_eq!(
+            decide_for(dir.path(), "game.exe", ExtractConflict::Suffix),
+            ExtractDecision::Keep
+        );
+        let dir = download("installer.zip", b"MZ self-extracting", None);
+        assert_eq!(
+            decide_for(dir.path(), "installer.zip", ExtractConflict::Suffix),
+            ExtractDecision::Misnamed(ArchiveKind::Zip)
+        );
+    }
+
+    #[test]
+    fn rar_needs_an_extractor() {
+        let dir = download("game.rar", b"Rar!\x1A\x07\x00", None);
+        assert_eq!(
+            decide_for(dir.path(), "game.rar", ExtractConflict::Suffix),
+            ExtractDecision::NoExtractor(ArchiveKind::Rar)
+        );
+        let options = ExtractOptions {
+            unrar_path: Some(PathBuf::from("unrar")),
+            ..ExtractOptions::default()
+        };
+        assert!(matches!(
+            decide(
+                dir.path(),
+                "game.rar",
+                "Game".into(),
+                7,
+                &options,
+                ExtractConflict::Suffix
+            ),
+            ExtractDecision::Extract {
+                kind: ArchiveKind::Rar,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn another_uploads_files_are_dealt_with_as_asked() {
+        let dir = download("game.zip", b"PK\x03\x04rest", Some(3));
+        assert_eq!(
+            decide_for(dir.path(), "game.zip", ExtractConflict::Skip),
+            ExtractDecision::Held {
+                dir_name: "Game".to_string(),
+                previous: 3
+            }
+        );
+
+        let ExtractDecision::Extract {
+            dir_name, conflict, ..
+        } = decide_for(dir.path(), "game.zip", ExtractConflict::Clean)
+        else {
+            panic!("not extracted");
+        };
+        assert_eq!(dir_name, "Game");
+        let conflict = conflict.unwrap();
+        assert_eq!(conflict, Conflict::Replace { previous: 3 });
+        assert_eq!(
+            conflict.describe(&dir_name),
+            "replaced upload 3's files in Game"
+        );
+
+        let ExtractDecision::Extract {
+            dir_name, conflict, ..
+        } = decide_for(dir.path(), "game.zip", ExtractConflict::Suffix)
+        else {
+            panic!("not extracted");
+        };
+        assert_eq!(dir_name, "Game-7");
+        assert_eq!(
+            conflict.unwrap().describe(&dir_name),
+            "extracted to Game-7 since Game holds upload 3"
+        );
+    }
+}
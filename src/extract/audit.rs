@@ -0,0 +1,308 @@
+//! Checking an extracted directory against the listing of the archive it came from
+
+use super::unpack::{decode_entry_name, enclosed_entry_path};
+use super::{ArchiveKind, ZipEncoding, strip_archive_extension};
+use crate::error::{Context, ItchError, Result};
+use std::fs::File as StdFile;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// A file an archive lists
+struct ListedEntry {
+    /// The name as the archive has it
+    name: String,
+    /// Where extraction puts it relative to the temporary directory, `None` when it's skipped
+    path: Option<PathBuf>,
+    is_dir: bool,
+    size: u64,
+    crc32: Option<u32>,
+}
+
+/// What's wrong with one entry of an archive on disk
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryProblem {
+    /// Nothing at the entry's path
+    Missing,
+    /// Its name would escape the extraction directory, so extraction skipped it
+    NotExtractable,
+    /// The file on disk has another size than the archive lists
+    Size { expected: u64, found: u64 },
+    /// The file on disk has another CRC-32 than the archive lists
+    Crc { expected: u32, found: u32 },
+}
+
+impl std::fmt::Display for EntryProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntryProblem::Missing => write!(f, "missing"),
+            EntryProblem::NotExtractable => write!(f, "skipped, its path leaves the directory"),
+            EntryProblem::Size { expected, found } => {
+                write!(f, "{} bytes instead of {}", found, expected)
+            }
+            EntryProblem::Crc { expected, found } => {
+                write!(f, "CRC-32 {:08x} instead of {:08x}", found, expected)
+            }
+        }
+    }
+}
+
+/// An entry of the archive that didn't check out
+#[derive(Clone, Debug)]
+pub struct EntryFinding {
+    /// The entry's name in the archive
+    pub entry: String,
+    pub problem: EntryProblem,
+}
+
+/// How an extracted directory compares with the archive it came from
+#[derive(Clone, Debug, Default)]
+pub struct ExtractionAudit {
+    /// Files the archive lists
+    pub files: usize,
+    /// Files whose CRC-32 was compared, only with `deep`
+    pub crc_checked: usize,
+    pub findings: Vec<EntryFinding>,
+}
+
+impl ExtractionAudit {
+    /// Whether every file the archive lists is there as listed
+    pub fn is_complete(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// Lines describing what's wrong with the extraction of `filename`, a summary first and at
+    /// most 20 of the findings after it
+    pub fn describe(&self, filename: &str) -> Vec<String> {
+        let mut lines = vec![format!(
+            "Extraction of {} is incomplete: {} of {} files missing or different",
+            filename,
+            self.findings.len(),
+            self.files
+        )];
+        lines.extend(
+            self.findings
+                .iter()
+                .take(AUDIT_LINES)
+                .map(|finding| format!("  {}: {}", finding.entry, finding.problem)),
+        );
+        if self.findings.len() > AUDIT_LINES {
+            lines.push(format!(
+                "  ... and {} more",
+                self.findings.len() - AUDIT_LINES
+            ));
+        }
+        lines
+    }
+}
+
+/// At most this many of an audit's findings are described, the rest only counted
+const AUDIT_LINES: usize = 20;
+
+/// Check that `extract_dir` holds every file of the archive at `archive_path` with the size it's
+/// listed with, and with `deep` the CRC-32 too where the archive records one
+///
+/// Entry paths are mapped the way extraction places them, so a lone file or a single top-level
+/// folder that extraction unwrapped is looked for where it ended up. A nested archive that was
+/// extracted in turn counts as present when its directory is. Tarballs list no checksums and RAR
+/// archives can't be listed at all.
+pub fn audit_extraction(
+    archive_path: &Path,
+    kind: ArchiveKind,
+    extract_dir: &Path,
+    encoding: ZipEncoding,
+    deep: bool,
+) -> Result<ExtractionAudit> {
+    let entries = match kind {
+        ArchiveKind::Zip => list_zip(archive_path, encoding)?,
+        ArchiveKind::SevenZip => list_7z(archive_path)?,
+        ArchiveKind::Rar => return Err(ItchError::archive("RAR archives can't be audited")),
+        _ => list_tar(archive_path, kind)?,
+    };
+    let extract_dir = crate::staging::long_path(extract_dir);
+
+    let mut audit = ExtractionAudit::default();
+    let placed = placement(&entries);
+    for entry in entries.iter().filter(|entry| !entry.is_dir) {
+        audit.files += 1;
+        let problem = match placed(entry) {
+            None => Some(EntryProblem::NotExtractable),
+            Some(relative) => {
+                let path = extract_dir.join(&relative);
+                check_file(&path, entry, deep, &mut audit.crc_checked)?
+            }
+        };
+        if let Some(problem) = problem {
+            audit.findings.push(EntryFinding {
+                entry: entry.name.clone(),
+                problem,
+            });
+        }
+    }
+    Ok(audit)
+}
+
+/// How entry paths map to paths under the extraction directory, following `move_into_place`
+fn placement(entries: &[ListedEntry]) -> impl Fn(&ListedEntry) -> Option<PathBuf> {
+    let extracted: Vec<_> = entries
+        .iter()
+        .filter_map(|entry| Some((entry.path.as_ref()?, entry.is_dir)))
+        .collect();
+    let files: Vec<_> = extracted.iter().filter(|(_, is_dir)| !is_dir).collect();
+
+    // A lone file, at the root or wrapped in nothing but folders, is moved up to the root
+    let lone = match files.as_slice() {
+        [(file, _)] if extracted.iter().all(|(path, _)| file.starts_with(path)) => {
+            file.file_name().map(PathBuf::from)
+        }
+        _ => None,
+    };
+    // A single folder holding everything is unwrapped
+    let roots: std::collections::BTreeSet<_> = extracted
+        .iter()
+        .filter_map(|(path, _)| path.iter().next())
+        .collect();
+    let single_root = match roots.into_iter().collect::<Vec<_>>().as_slice() {
+        [root]
+            if extracted
+                .iter()
+                .any(|(path, is_dir)| *is_dir || path.components().count() > 1) =>
+        {
+            Some(PathBuf::from(root))
+        }
+        _ => None,
+    };
+
+    move |entry| {
+        let path = entry.path.as_ref()?;
+        if let Some(lone) = &lone {
+            return Some(lone.clone());
+        }
+        match &single_root {
+            Some(root) => path.strip_prefix(root).ok().map(Path::to_path_buf),
+            None => Some(path.clone()),
+        }
+    }
+}
+
+/// What's wrong with the file extraction put at `path`, if anything
+fn check_file(
+    path: &Path,
+    entry: &ListedEntry,
+    deep: bool,
+    crc_checked: &mut usize,
+) -> Result<Option<EntryProblem>> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => {
+            // A nested archive is replaced by the directory it was extracted into
+            let filename = path.file_name().unwrap_or_default().to_string_lossy();
+            let nested = ArchiveKind::from_filename(&filename).is_some_and(|_| {
+                path.with_file_name(strip_archive_extension(&filename))
+                    .is_dir()
+            });
+            return Ok((!nested).then_some(EntryProblem::Missing));
+        }
+    };
+    if metadata.len() != entry.size {
+        return Ok(Some(EntryProblem::Size {
+            expected: entry.size,
+            found: metadata.len(),
+        }));
+    }
+    let Some(expected) = entry.crc32.filter(|_| deep) else {
+        return Ok(None);
+    };
+    *crc_checked += 1;
+    let found = file_crc32(path)?;
+    Ok((found != expected).then_some(EntryProblem::Crc { expected, found }))
+}
+
+/// CRC-32 of a file's contents, as zip and 7z archives record it
+fn file_crc32(path: &Path) -> Result<u32> {
+    let mut file = StdFile::open(path).context("Failed to open extracted file")?;
+    let mut crc = flate2::Crc::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .context("Failed to read extracted file")?;
+        if read == 0 {
+            return Ok(crc.sum());
+        }
+        crc.update(&buffer[..read]);
+    }
+}
+
+/// The entries of a zip archive, from its central directory
+fn list_zip(archive_path: &Path, encoding: ZipEncoding) -> Result<Vec<ListedEntry>> {
+    let file = StdFile::open(archive_path).context("Failed to open zip file")?;
+    let mut archive = zip::ZipArchive::new(file)
+        .map_err(|e| ItchError::archive_caused("Failed to read zip archive", e))?;
+    let mut entries = Vec::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let file = archive
+            .by_index_raw(i)
+            .map_err(|e| ItchError::archive_caused("Failed to get file from archive", e))?;
+        let name = decode_entry_name(&file, encoding);
+        entries.push(ListedEntry {
+            path: enclosed_entry_path(&name),
+            is_dir: name.ends_with('/') || name.ends_with('\\'),
+            size: file.size(),
+            crc32: Some(file.crc32()),
+            name,
+        });
+    }
+    Ok(entries)
+}
+
+/// The entries of a 7z archive, from its header
+fn list_7z(archive_path: &Path) -> Result<Vec<ListedEntry>> {
+    let archive = sevenz_rust::SevenZReader::open(archive_path, sevenz_rust::Password::empty())
+        .map_err(|e| ItchError::archive(format!("Failed to read 7z archive: {}", e)))?;
+    Ok(archive
+        .archive()
+        .files
+        .iter()
+        .filter(|entry| !entry.is_anti_item)
+        .map(|entry| ListedEntry {
+            name: entry.name.clone(),
+            path: enclosed_entry_path(&entry.name),
+            is_dir: entry.is_directory,
+            size: entry.size,
+            crc32: entry.has_crc.then_some(entry.crc as u32),
+        })
+        .collect())
+}
+
+/// The files and directories of a tarball, read through since tarballs have no index
+fn list_tar(archive_path: &Path, kind: ArchiveKind) -> Result<Vec<ListedEntry>> {
+    let reader = BufReader::new(StdFile::open(archive_path).context("Failed to open tar file")?);
+    let reader: Box<dyn Read> = match kind {
+        ArchiveKind::TarGz => Box::new(flate2::read::GzDecoder::new(reader)),
+        ArchiveKind::TarXz => Box::new(liblzma::read::XzDecoder::new(reader)),
+        _ => Box::new(reader),
+    };
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries().context("Failed to read tar archive")? {
+        let entry = entry.context("Failed to get file from archive")?;
+        let entry_type = entry.header().entry_type();
+        // Links and special files have no contents to compare
+        if !entry_type.is_file() && !entry_type.is_dir() {
+            continue;
+        }
+        let name = entry
+            .path()
+            .context("Failed to read tar entry name")?
+            .to_string_lossy()
+            .into_owned();
+        entries.push(ListedEntry {
+            path: enclosed_entry_path(&name),
+            is_dir: entry_type.is_dir(),
+            size: entry.size(),
+            crc32: None,
+            name,
+        });
+    }
+    Ok(entries)
+}
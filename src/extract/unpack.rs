@@ -0,0 +1,1157 @@
+use super::{ArchiveKind, ExtractOptions, ZipEncoding, strip_archive_extension};
+use crate::error::{Context, ItchError, Result};
+use std::fs::File as StdFile;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio_util::sync::CancellationToken;
+use zip::ZipArchive;
+
+impl ZipEncoding {
+    fn encoding(self) -> Option<&'static encoding_rs::Encoding> {
+        match self {
+            // The zip crate already decodes non-UTF-8 names as CP437
+            ZipEncoding::Cp437 => None,
+            ZipEncoding::Sjis => Some(encoding_rs::SHIFT_JIS),
+            ZipEncoding::Gbk => Some(encoding_rs::GBK),
+            ZipEncoding::Big5 => Some(encoding_rs::BIG5),
+            ZipEncoding::EucKr => Some(encoding_rs::EUC_KR),
+        }
+    }
+}
+
+/// The most nested archives a single download may expand into
+const MAX_NESTED_ARCHIVES: usize = 256;
+
+/// Decode the name of a zip entry, honouring the encoding override for entries without the UTF-8 flag
+pub(super) fn decode_entry_name<R: Read>(
+    file: &zip::read::ZipFile<'_, R>,
+    encoding: ZipEncoding,
+) -> String {
+    let raw = file.name_raw();
+
+    // Entries flagged as UTF-8 are decoded verbatim by the zip crate, so the decoded name matches the raw bytes
+    if raw.is_ascii() || file.name().as_bytes() == raw {
+        return file.name().to_string();
+    }
+
+    // Plenty of tools write UTF-8 names without setting the flag
+    if let Ok(name) = std::str::from_utf8(raw) {
+        return name.to_string();
+    }
+
+    match encoding.encoding() {
+        Some(encoding) => encoding.decode_without_bom_handling(raw).0.into_owned(),
+        None => file.name().to_string(),
+    }
+}
+
+/// Turn a decoded entry name into a relative path that can't escape the extraction directory
+pub(super) fn enclosed_entry_path(name: &str) -> Option<PathBuf> {
+    if name.contains('\0') || name.starts_with('/') || name.starts_with('\\') {
+        return None;
+    }
+
+    let mut path = PathBuf::new();
+    // Archives made on Windows frequently use backslashes as separators
+    for component in name.split(['/', '\\']) {
+        match component {
+            "" | "." => continue,
+            ".." => {
+                if !path.pop() {
+                    return None;
+                }
+            }
+            // Drive letters or other prefixes would make the path absolute on Windows
+            component if component.contains(':') => return None,
+            component => path.push(crate::staging::shorten_component(component)),
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// The error extraction steps bail out with once cancelled, turned into [`ItchError::Cancelled`]
+/// by [`extract_archive_blocking`]
+fn cancelled() -> std::io::Error {
+    std::io::Error::other("cancelled")
+}
+
+/// Copy everything from `reader` into `writer`, calling `on_chunk` with the size of each chunk written
+fn copy_with_progress(
+    reader: &mut (impl Read + ?Sized),
+    writer: &mut impl std::io::Write,
+    cancel: &CancellationToken,
+    mut on_chunk: impl FnMut(u64),
+) -> std::io::Result<u64> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut copied = 0u64;
+
+    loop {
+        if cancel.is_cancelled() {
+            return Err(cancelled());
+        }
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+        on_chunk(read as u64);
+    }
+}
+
+/// A reader that reports how much of the underlying stream has been consumed
+struct ProgressReader<R, F> {
+    inner: R,
+    read: u64,
+    total: u64,
+    /// Reads fail once this fires
+    cancel: CancellationToken,
+    on_progress: F,
+}
+
+impl<R: Read, F: Fn(u64, u64)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cancel.is_cancelled() {
+            return Err(cancelled());
+        }
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        (self.on_progress)(self.read, self.total);
+        Ok(read)
+    }
+}
+
+/// Apply the unix permissions recorded in an archive to an extracted file
+#[cfg(unix)]
+fn set_unix_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))
+            .context("Failed to set file permissions")?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_unix_mode(_path: &Path, _mode: Option<u32>) -> Result<()> {
+    Ok(())
+}
+
+/// Zips with fewer entries than this are extracted on one thread, whatever `jobs` says
+const PARALLEL_MIN_ENTRIES: usize = 256;
+
+/// What creating a file costs in bytes written, when sharing out entries between workers
+const ENTRY_COST: u64 = 64 * 1024;
+
+/// An entry of a zip archive and where it goes
+struct ZipEntry {
+    index: usize,
+    name: String,
+    outpath: PathBuf,
+    size: u64,
+    is_dir: bool,
+}
+
+/// The entries of `archive` that can be extracted into `temp_extract`, in archive order
+fn zip_entries(
+    archive: &mut ZipArchive<StdFile>,
+    temp_extract: &Path,
+    encoding: ZipEncoding,
+) -> Result<Vec<ZipEntry>> {
+    let mut entries = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        let file = archive
+            .by_index_raw(index)
+            .map_err(|e| ItchError::archive_caused("Failed to get file from archive", e))?;
+        let name = decode_entry_name(&file, encoding);
+        let Some(path) = enclosed_entry_path(&name) else {
+            continue;
+        };
+        entries.push(ZipEntry {
+            index,
+            is_dir: name.ends_with('/') || name.ends_with('\\'),
+            outpath: temp_extract.join(path),
+            size: file.size(),
+            name,
+        });
+    }
+    Ok(entries)
+}
+
+/// Extract the file `entry` of `archive`, passing the size of each chunk written to `on_chunk`
+fn extract_zip_file(
+    archive: &mut ZipArchive<StdFile>,
+    entry: &ZipEntry,
+    cancel: &CancellationToken,
+    on_chunk: impl FnMut(u64),
+) -> Result<()> {
+    let mut file = archive
+        .by_index(entry.index)
+        .map_err(|e| ItchError::archive_caused("Failed to get file from archive", e))?;
+    let mut outfile = StdFile::create(&entry.outpath).context("Failed to create output file")?;
+    copy_with_progress(&mut file, &mut outfile, cancel, on_chunk)
+        .context("Failed to extract file")?;
+    set_unix_mode(&entry.outpath, file.unix_mode())
+}
+
+/// The error for `entry` failing to extract, naming it
+fn zip_entry_error(entry: &ZipEntry, error: ItchError) -> ItchError {
+    match error {
+        ItchError::Cancelled => ItchError::Cancelled,
+        error => ItchError::archive_caused(format!("Failed to extract {}", entry.name), error),
+    }
+}
+
+/// Extract every entry of a zip archive into `temp_extract`, on up to `jobs` threads
+fn extract_zip(
+    archive_path: &Path,
+    temp_extract: &Path,
+    encoding: ZipEncoding,
+    jobs: usize,
+    cancel: &CancellationToken,
+    on_progress: &impl Fn(u64, u64),
+) -> Result<()> {
+    let file = StdFile::open(archive_path).context("Failed to open zip file")?;
+    let mut archive = ZipArchive::new(file)
+        .map_err(|e| ItchError::archive_caused("Failed to read zip archive", e))?;
+    let entries = zip_entries(&mut archive, temp_extract, encoding)?;
+
+    if jobs > 1 && entries.len() >= PARALLEL_MIN_ENTRIES && !has_case_collisions(&entries) {
+        return extract_zip_parallel(archive_path, entries, jobs, cancel, on_progress);
+    }
+
+    let total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+    let mut written = 0u64;
+    on_progress(written, total_size);
+
+    for entry in &entries {
+        if cancel.is_cancelled() {
+            return Err(ItchError::Cancelled);
+        }
+        if entry.is_dir {
+            std::fs::create_dir_all(&entry.outpath).context("Failed to create directory")?;
+            continue;
+        }
+        if let Some(p) = entry.outpath.parent()
+            && !p.exists()
+        {
+            std::fs::create_dir_all(p).context("Failed to create parent directory")?;
+        }
+        extract_zip_file(&mut archive, entry, cancel, |chunk| {
+            written += chunk;
+            on_progress(written, total_size);
+        })
+        .map_err(|e| zip_entry_error(entry, e))?;
+    }
+
+    Ok(())
+}
+
+/// Whether two entries would land on the same file on a case-insensitive filesystem without
+/// having the same name, which only extracting them in order keeps deterministic
+fn has_case_collisions(entries: &[ZipEntry]) -> bool {
+    let mut seen = std::collections::HashMap::new();
+    entries.iter().any(|entry| {
+        let folded = entry.outpath.to_string_lossy().to_lowercase();
+        seen.insert(folded, &entry.outpath)
+            .is_some_and(|previous| *previous != entry.outpath)
+    })
+}
+
+/// Split `files` into at most `jobs` runs of consecutive entries with about as much to write each
+fn partition<'a>(files: &'a [&'a ZipEntry], jobs: usize) -> Vec<&'a [&'a ZipEntry]> {
+    let weight = |entry: &&ZipEntry| entry.size + ENTRY_COST;
+    let share = files.iter().map(weight).sum::<u64>().div_ceil(jobs as u64);
+    let mut slices = Vec::with_capacity(jobs);
+    let (mut start, mut weighed) = (0, 0);
+    for (i, entry) in files.iter().enumerate() {
+        weighed += weight(entry);
+        if weighed >= share * (slices.len() as u64 + 1) && slices.len() + 1 < jobs {
+            slices.push(&files[start..=i]);
+            start = i + 1;
+        }
+    }
+    if start < files.len() {
+        slices.push(&files[start..]);
+    }
+    slices
+}
+
+/// Extract `entries` of the zip at `archive_path`, each worker thread opening the archive for
+/// itself and extracting its own run of entries
+///
+/// Directories are all created first so workers never race to create them. Of entries with the
+/// same name only the last is extracted, which is the one left behind when extracting in order.
+/// When several entries fail, the one reported is the first in the archive, as it would be on one
+/// thread: workers only stop early for entries after a failure.
+fn extract_zip_parallel(
+    archive_path: &Path,
+    entries: Vec<ZipEntry>,
+    jobs: usize,
+    cancel: &CancellationToken,
+    on_progress: &impl Fn(u64, u64),
+) -> Result<()> {
+    let mut last_index = std::collections::HashMap::new();
+    for entry in &entries {
+        last_index.insert(&entry.outpath, entry.index);
+    }
+    let mut files = Vec::new();
+    for entry in &entries {
+        let dir = if entry.is_dir {
+            Some(entry.outpath.as_path())
+        } else {
+            entry.outpath.parent()
+        };
+        if let Some(dir) = dir {
+            std::fs::create_dir_all(dir)
+                .context("Failed to create directory")
+                .map_err(|e| zip_entry_error(entry, e))?;
+        }
+        if !entry.is_dir && last_index[&entry.outpath] == entry.index {
+            files.push(entry);
+        }
+    }
+    let total_size: u64 = files.iter().map(|entry| entry.size).sum();
+    on_progress(0, total_size);
+
+    // The first entry in archive order that failed so far
+    let first_failure = AtomicUsize::new(usize::MAX);
+    let (chunks, progress) = std::sync::mpsc::channel::<u64>();
+    let failures = std::thread::scope(|scope| {
+        let workers: Vec<_> = partition(&files, jobs)
+            .into_iter()
+            .map(|slice| {
+                let chunks = chunks.clone();
+                let first_failure = &first_failure;
+                scope.spawn(move || {
+                    let file = StdFile::open(archive_path).context("Failed to open zip file");
+                    let mut archive = match file.and_then(|file| {
+                        ZipArchive::new(file)
+                            .map_err(|e| ItchError::archive_caused("Failed to read zip archive", e))
+                    }) {
+                        Ok(archive) => archive,
+                        Err(e) => {
+                            first_failure.fetch_min(slice[0].index, Ordering::Relaxed);
+                            return Some((slice[0].index, e));
+                        }
+                    };
+                    for entry in slice {
+                        if cancel.is_cancelled() {
+                            return None;
+                        }
+                        if entry.index > first_failure.load(Ordering::Relaxed) {
+                            return None;
+                        }
+                        let extracted = extract_zip_file(&mut archive, entry, cancel, |chunk| {
+                            let _ = chunks.send(chunk);
+                        });
+                        if let Err(e) = extracted {
+                            first_failure.fetch_min(entry.index, Ordering::Relaxed);
+                            return Some((entry.index, zip_entry_error(entry, e)));
+                        }
+                    }
+                    None
+                })
+            })
+            .collect();
+        // Progress is reported from here, the callback doesn't have to be shareable between threads
+        drop(chunks);
+        let mut written = 0u64;
+        for chunk in progress {
+            written += chunk;
+            on_progress(written, total_size);
+        }
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker.join().unwrap_or_else(|_| {
+                    Some((
+                        usize::MAX,
+                        ItchError::archive("An extraction thread panicked"),
+                    ))
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+
+    if cancel.is_cancelled() {
+        return Err(ItchError::Cancelled);
+    }
+    match failures
+        .into_iter()
+        .flatten()
+        .min_by_key(|(index, _)| *index)
+    {
+        Some((_, error)) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// Extract every entry of a (possibly compressed) tar stream into `temp_extract`
+fn extract_tar(reader: impl Read, temp_extract: &Path, cancel: &CancellationToken) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_overwrite(true);
+
+    for entry in archive.entries().context("Failed to read tar archive")? {
+        if cancel.is_cancelled() {
+            return Err(ItchError::Cancelled);
+        }
+        let mut entry = entry.context("Failed to get file from archive")?;
+        // `unpack_in` refuses entries that would escape the extraction directory
+        entry
+            .unpack_in(temp_extract)
+            .context("Failed to extract file")?;
+    }
+
+    Ok(())
+}
+
+/// Extract every entry of a 7z archive into `temp_extract`
+fn extract_7z(
+    archive_path: &Path,
+    temp_extract: &Path,
+    cancel: &CancellationToken,
+    on_progress: &impl Fn(u64, u64),
+) -> Result<()> {
+    let filename = archive_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let password_error = || {
+        ItchError::archive(format!(
+            "{} is password protected and can't be extracted",
+            filename
+        ))
+    };
+
+    let mut archive =
+        match sevenz_rust::SevenZReader::open(archive_path, sevenz_rust::Password::empty()) {
+            Ok(archive) => archive,
+            Err(sevenz_rust::Error::PasswordRequired) => return Err(password_error()),
+            Err(e) => {
+                return Err(ItchError::archive(format!(
+                    "Failed to read 7z archive: {}",
+                    e
+                )));
+            }
+        };
+
+    let total_size: u64 = archive
+        .archive()
+        .files
+        .iter()
+        .filter(|entry| entry.has_stream())
+        .map(|entry| entry.size())
+        .sum();
+    let mut written = 0u64;
+    on_progress(written, total_size);
+
+    let result = archive.for_each_entries(|entry, reader| {
+        // Stopping the iteration early is reported as cancelled by the caller
+        if cancel.is_cancelled() {
+            return Ok(false);
+        }
+        let outpath = match enclosed_entry_path(entry.name()) {
+            Some(path) => temp_extract.join(path),
+            None => return Ok(true),
+        };
+
+        if entry.is_directory() {
+            std::fs::create_dir_all(&outpath)?;
+            return Ok(true);
+        }
+
+        if let Some(p) = outpath.parent() {
+            std::fs::create_dir_all(p)?;
+        }
+        let mut outfile = StdFile::create(&outpath)?;
+        copy_with_progress(reader, &mut outfile, cancel, |chunk| {
+            written += chunk;
+            on_progress(written, total_size);
+        })?;
+        Ok(true)
+    });
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(sevenz_rust::Error::PasswordRequired | sevenz_rust::Error::MaybeBadPassword(_)) => {
+            Err(password_error())
+        }
+        Err(e) => Err(ItchError::archive(format!("Failed to extract file: {}", e))),
+    }
+}
+
+/// Extract a RAR archive into `temp_extract` by running an external `unrar` or `7z` binary
+fn extract_rar(
+    archive_path: &Path,
+    temp_extract: &Path,
+    unrar_path: &Path,
+    on_progress: &impl Fn(u64, u64),
+) -> Result<()> {
+    let total = std::fs::metadata(archive_path)
+        .map(|m| m.len())
+        .unwrap_or(0);
+    on_progress(0, total);
+
+    let tool = unrar_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mut command = std::process::Command::new(unrar_path);
+    if tool.starts_with("7z") {
+        let mut output_flag = std::ffi::OsString::from("-o");
+        output_flag.push(temp_extract);
+        command
+            .arg("x")
+            .arg("-y")
+            .arg(output_flag)
+            .arg(archive_path);
+    } else {
+        // unrar wants a trailing separator to treat the destination as a directory
+        command
+            .arg("x")
+            .arg("-o+")
+            .arg("-y")
+            .arg(archive_path)
+            .arg(temp_extract.join(""));
+    }
+
+    let output = command
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|e| ItchError::io(format!("Failed to run {}", unrar_path.display()), e))?;
+    if !output.status.success() {
+        return Err(ItchError::archive(format!(
+            "{} exited with {}: {}",
+            unrar_path.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    on_progress(total, total);
+    Ok(())
+}
+
+/// Extract a tarball from disk, decompressing it according to `kind`
+fn extract_tar_file(
+    archive_path: &Path,
+    temp_extract: &Path,
+    kind: ArchiveKind,
+    cancel: &CancellationToken,
+    on_progress: &impl Fn(u64, u64),
+) -> Result<()> {
+    let file = StdFile::open(archive_path).context("Failed to open tar file")?;
+    let total = file.metadata().map(|m| m.len()).unwrap_or(0);
+    // Progress is measured against the compressed size since tarballs have no up front index
+    let reader = BufReader::new(ProgressReader {
+        inner: file,
+        read: 0,
+        total,
+        cancel: cancel.clone(),
+        on_progress,
+    });
+
+    match kind {
+        ArchiveKind::TarGz => {
+            extract_tar(flate2::read::GzDecoder::new(reader), temp_extract, cancel)
+        }
+        ArchiveKind::TarXz => {
+            extract_tar(liblzma::read::XzDecoder::new(reader), temp_extract, cancel)
+        }
+        _ => extract_tar(reader, temp_extract, cancel),
+    }
+}
+
+/// The entries at the root of an extracted directory
+fn list_entries(dir: &Path) -> Result<Vec<std::fs::DirEntry>> {
+    std::fs::read_dir(dir)
+        .context("Failed to read temporary extraction directory")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to list directory entries")
+}
+
+/// The only file in `dir`, when there's nothing else but the folders wrapping it
+fn lone_file(dir: &Path) -> Result<Option<PathBuf>> {
+    let mut dir = dir.to_path_buf();
+    loop {
+        let entries = list_entries(&dir)?;
+        let [entry] = entries.as_slice() else {
+            return Ok(None);
+        };
+        let file_type = entry.file_type().context("Failed to read file type")?;
+        if file_type.is_file() {
+            return Ok(Some(entry.path()));
+        } else if file_type.is_dir() {
+            dir = entry.path();
+        } else {
+            return Ok(None);
+        }
+    }
+}
+
+/// Move the extracted contents of `temp_extract` into `extract_to`, unwrapping a single top-level folder
+///
+/// An archive holding nothing but one file, at its root or inside folders, has the file placed
+/// directly in `extract_to`.
+fn move_into_place(temp_extract: &Path, extract_to: &Path) -> Result<()> {
+    std::fs::create_dir_all(extract_to).context("Failed to create final extraction directory")?;
+
+    if let Some(file) = lone_file(temp_extract)? {
+        let dest = extract_to.join(file.file_name().unwrap_or_default());
+        return crate::staging::move_path(&file, &dest)
+            .map_err(|e| ItchError::io("Failed to move extracted file", std::io::Error::other(e)));
+    }
+
+    // If there's exactly one directory and nothing else at the root, unwrap it
+    let mut entries = list_entries(temp_extract)?;
+    if let [entry] = entries.as_slice()
+        && entry.file_type().is_ok_and(|ft| ft.is_dir())
+    {
+        entries = list_entries(&entry.path())?;
+    }
+
+    for entry in entries {
+        let source = entry.path();
+        let dest = extract_to.join(entry.file_name());
+        crate::staging::move_path(&source, &dest).map_err(|e| {
+            ItchError::io("Failed to move extracted content", std::io::Error::other(e))
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Extract an archive into `extract_to` via a temporary directory, unwrapping a single top-level folder
+fn extract_archive_blocking(
+    archive_path: &Path,
+    extract_to: &Path,
+    kind: ArchiveKind,
+    options: &ExtractOptions,
+    cancel: &CancellationToken,
+    on_progress: &impl Fn(u64, u64),
+) -> Result<()> {
+    // Deep asset trees easily exceed Windows' 260 character limit, so use extended-length paths
+    let extract_to = &crate::staging::long_path(extract_to);
+
+    // First, extract to a temporary directory to check for single-folder structure
+    let parent = extract_to.parent().unwrap_or(Path::new("."));
+    let temp_extract = crate::staging::long_path(
+        &crate::staging::staging_dir(options.temp_dir.as_ref(), parent)
+            .join(crate::staging::extract_dir_name(extract_to)),
+    );
+    std::fs::create_dir_all(&temp_extract)
+        .context("Failed to create temporary extraction directory")?;
+
+    let extracted = match kind {
+        ArchiveKind::Zip => extract_zip(
+            archive_path,
+            &temp_extract,
+            options.zip_encoding,
+            options.jobs,
+            cancel,
+            on_progress,
+        ),
+        ArchiveKind::SevenZip => extract_7z(archive_path, &temp_extract, cancel, on_progress),
+        ArchiveKind::Rar => match &options.unrar_path {
+            Some(unrar_path) => extract_rar(archive_path, &temp_extract, unrar_path, on_progress),
+            None => Err(ItchError::archive(
+                "RAR archives need an external extractor, pass --unrar-path",
+            )),
+        },
+        _ => extract_tar_file(archive_path, &temp_extract, kind, cancel, on_progress),
+    };
+    // However the extractor noticed, a cancelled extraction never moves anything into place
+    let extracted = if cancel.is_cancelled() {
+        Err(ItchError::Cancelled)
+    } else {
+        extracted
+    };
+
+    let result = extracted.and_then(|()| move_into_place(&temp_extract, extract_to));
+
+    // Clean up temporary directory, even if extraction failed part way through
+    let cleanup =
+        std::fs::remove_dir_all(&temp_extract).context("Failed to remove temporary directory");
+
+    result.and(cleanup)
+}
+
+/// Recursively collect every regular file underneath `dir`
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir).context("Failed to read extracted directory")? {
+        let entry = entry.context("Failed to list directory entries")?;
+        let file_type = entry.file_type().context("Failed to read file type")?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Extract archives found inside an already extracted directory, up to `remaining_depth` levels deep
+///
+/// Each inner archive is extracted into a sibling directory named after it and then removed.
+/// `budget` caps how many nested archives we're willing to extract in total, which together with
+/// the depth limit keeps archives that contain themselves (or zip bombs) from running away.
+fn extract_nested(
+    dir: &Path,
+    remaining_depth: usize,
+    options: &ExtractOptions,
+    cancel: &CancellationToken,
+    budget: &mut usize,
+) -> Result<()> {
+    if remaining_depth == 0 {
+        return Ok(());
+    }
+
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+
+    for path in files {
+        let filename = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        let kind = match ArchiveKind::from_filename(&filename)
+            .and_then(|_| ArchiveKind::detect(&path, &filename))
+        {
+            Some(kind) if options.can_extract(kind) => kind,
+            _ => continue,
+        };
+
+        if *budget == 0 {
+            return Err(ItchError::archive(format!(
+                "Too many nested archives, stopped before extracting {}",
+                filename
+            )));
+        }
+        *budget -= 1;
+
+        let extract_to = path.with_file_name(strip_archive_extension(&filename));
+        if extract_to.exists() && !extract_to.is_dir() {
+            continue;
+        }
+
+        match extract_archive_blocking(&path, &extract_to, kind, options, cancel, &|_, _| {}) {
+            Ok(()) => {}
+            Err(ItchError::Cancelled) => return Err(ItchError::Cancelled),
+            Err(e) => {
+                return Err(ItchError::archive_caused(
+                    format!("Failed to extract nested archive {}", filename),
+                    e,
+                ));
+            }
+        }
+        std::fs::remove_file(&path).context("Failed to remove nested archive")?;
+
+        extract_nested(&extract_to, remaining_depth - 1, options, cancel, budget)?;
+    }
+
+    Ok(())
+}
+
+/// Extract an archive to the specified directory
+///
+/// `on_progress` is called with the bytes processed so far and the total to process. For zips and
+/// 7z archives that's the uncompressed size of the entries, for tarballs and RARs it's the size
+/// of the archive on disk.
+///
+/// Once `cancel` fires extraction stops between entries or chunks, the temporary directory is
+/// removed and [`ItchError::Cancelled`] is returned.
+pub async fn extract_archive<F>(
+    archive_path: &Path,
+    extract_to: &Path,
+    kind: ArchiveKind,
+    options: &ExtractOptions,
+    cancel: &CancellationToken,
+    on_progress: F,
+) -> Result<()>
+where
+    F: Fn(u64, u64) + Send + 'static,
+{
+    let archive_path = archive_path.to_path_buf();
+    let extract_to = extract_to.to_path_buf();
+    let options = options.clone();
+    let cancel = cancel.clone();
+    let span = tracing::info_span!("extract", archive = %archive_path.display(), ?kind);
+
+    // Run the extraction in a blocking task since the archive crates are synchronous
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.entered();
+        extract_archive_blocking(
+            &archive_path,
+            &extract_to,
+            kind,
+            &options,
+            &cancel,
+            &on_progress,
+        )?;
+
+        // The outer archive counts as the first level
+        let mut budget = MAX_NESTED_ARCHIVES;
+        extract_nested(
+            &extract_to,
+            options.max_depth.saturating_sub(1),
+            &options,
+            &cancel,
+            &mut budget,
+        )
+    })
+    .await
+    .map_err(|e| ItchError::io("Extraction task failed", std::io::Error::other(e)))??;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// A zip of `entries` whose names are stored as the given bytes, without the UTF-8 flag
+    ///
+    /// The zip crate only writes names it can take as a `&str`, and each only once, so the others
+    /// are written under an ASCII placeholder of the same length that's then swapped for the raw
+    /// name.
+    fn zip_with_raw_names(path: &Path, entries: &[(&[u8], &[u8])]) {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        let mut written = std::collections::HashSet::new();
+        let mut placeholders = Vec::new();
+        for (i, (name, contents)) in entries.iter().enumerate() {
+            let name = match std::str::from_utf8(name) {
+                Ok(name) if name.is_ascii() && written.insert(name) => name.to_string(),
+                _ => {
+                    let placeholder = format!("{:~<width$}", i, width = name.len());
+                    placeholders.push((*name, placeholder.clone()));
+                    placeholder
+                }
+            };
+            writer.start_file(name, options).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        let mut bytes = writer.finish().unwrap().into_inner();
+        for (name, placeholder) in placeholders {
+            let placeholder = placeholder.as_bytes();
+            let mut start = 0;
+            while let Some(at) = bytes[start..]
+                .windows(placeholder.len())
+                .position(|window| window == placeholder)
+            {
+                let at = start + at;
+                bytes[at..at + name.len()].copy_from_slice(name);
+                start = at + name.len();
+            }
+        }
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    fn entry_names(path: &Path, encoding: ZipEncoding) -> Vec<String> {
+        let mut archive = ZipArchive::new(StdFile::open(path).unwrap()).unwrap();
+        (0..archive.len())
+            .map(|i| decode_entry_name(&archive.by_index_raw(i).unwrap(), encoding))
+            .collect()
+    }
+
+    fn extract(archive: &Path, extract_to: &Path, options: &ExtractOptions) -> Result<()> {
+        extract_archive_blocking(
+            archive,
+            extract_to,
+            ArchiveKind::Zip,
+            options,
+            &CancellationToken::new(),
+            &|_, _| {},
+        )
+    }
+
+    /// Every file under `dir`, relative to it and with `/` separators, sorted
+    fn tree(dir: &Path) -> Vec<String> {
+        let mut files = Vec::new();
+        collect_files(dir, &mut files).unwrap();
+        let mut files: Vec<String> = files
+            .iter()
+            .map(|file| {
+                let relative = file.strip_prefix(dir).unwrap();
+                relative.to_string_lossy().replace('\\', "/")
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn unflagged_names_are_decoded_with_the_chosen_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("game.zip");
+        // `ソ` is 0x83 0x5C in Shift-JIS, the second byte being a backslash in ASCII
+        let sjis = encoding_rs::SHIFT_JIS.encode("ゲーム/ソフト.txt").0;
+        zip_with_raw_names(
+            &archive,
+            &[
+                (&sjis, b"sjis"),
+                (b"caf\x82.txt", b"cp437"),
+                ("naïve.txt".as_bytes(), b"utf-8"),
+            ],
+        );
+
+        let names = entry_names(&archive, ZipEncoding::Sjis);
+        assert_eq!(names[0], "ゲーム/ソフト.txt");
+        // Names that are valid UTF-8 are taken as such whatever the encoding
+        assert_eq!(names[2], "naïve.txt");
+
+        let names = entry_names(&archive, ZipEncoding::Cp437);
+        assert_eq!(names[1], "café.txt");
+        assert_eq!(names[2], "naïve.txt");
+    }
+
+    #[test]
+    fn decoded_names_are_extracted_as_readable_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("game.zip");
+        let sjis = encoding_rs::SHIFT_JIS.encode("ゲーム/ソフト.txt").0;
+        let escaping = encoding_rs::SHIFT_JIS.encode("../ソ.txt").0;
+        zip_with_raw_names(&archive, &[(&sjis, b"inside"), (&escaping, b"outside")]);
+
+        let extract_to = dir.path().join("game");
+        let options = ExtractOptions {
+            zip_encoding: ZipEncoding::Sjis,
+            ..ExtractOptions::default()
+        };
+        extract(&archive, &extract_to, &options).unwrap();
+
+        // The decoded backslash byte didn't split the name, the entry climbing out was skipped
+        // and the single top-level folder was unwrapped
+        assert_eq!(tree(&extract_to), ["ソフト.txt"]);
+        assert!(!dir.path().join("ソ.txt").exists());
+    }
+
+    #[test]
+    fn entry_paths_stay_inside_the_extraction_directory() {
+        assert_eq!(
+            enclosed_entry_path("a\\b/./c.txt"),
+            Some(PathBuf::from("a").join("b").join("c.txt"))
+        );
+        assert_eq!(
+            enclosed_entry_path("a/../b.txt"),
+            Some(PathBuf::from("b.txt"))
+        );
+        for name in [
+            "../a.txt",
+            "a/../../b.txt",
+            "/etc/passwd",
+            "\\a",
+            "C:/a",
+            "a\0b",
+            "./",
+        ] {
+            assert_eq!(enclosed_entry_path(name), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn a_lone_file_is_placed_directly_in_the_target() {
+        for name in [&b"readme.txt"[..], b"outer/inner/readme.txt"] {
+            let dir = tempfile::tempdir().unwrap();
+            let archive = dir.path().join("game.zip");
+            zip_with_raw_names(&archive, &[(name, b"hello")]);
+
+            let extract_to = dir.path().join("game");
+            extract(&archive, &extract_to, &ExtractOptions::default()).unwrap();
+
+            assert_eq!(tree(&extract_to), ["readme.txt"]);
+            assert_eq!(
+                std::fs::read(extract_to.join("readme.txt")).unwrap(),
+                b"hello"
+            );
+        }
+    }
+
+    #[test]
+    fn only_a_lone_top_level_folder_is_unwrapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let wrapped = dir.path().join("wrapped.zip");
+        zip_with_raw_names(
+            &wrapped,
+            &[(b"game/a.txt", b"a"), (b"game/data/b.txt", b"b")],
+        );
+        let extract_to = dir.path().join("wrapped");
+        extract(&wrapped, &extract_to, &ExtractOptions::default()).unwrap();
+        assert_eq!(tree(&extract_to), ["a.txt", "data/b.txt"]);
+
+        let flat = dir.path().join("flat.zip");
+        zip_with_raw_names(&flat, &[(b"game/a.txt", b"a"), (b"b.txt", b"b")]);
+        let extract_to = dir.path().join("flat");
+        extract(&flat, &extract_to, &ExtractOptions::default()).unwrap();
+        assert_eq!(tree(&extract_to), ["b.txt", "game/a.txt"]);
+    }
+
+    #[test]
+    fn a_file_that_isnt_a_zip_fails_without_leaving_anything() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("game.zip");
+        std::fs::write(&archive, b"MZ not really a zip").unwrap();
+        assert_eq!(ArchiveKind::detect(&archive, "game.zip"), None);
+
+        let extract_to = dir.path().join("game");
+        assert!(extract(&archive, &extract_to, &ExtractOptions::default()).is_err());
+        // Only the download itself is left, no half-made or temporary directories
+        let left: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(left, ["game.zip"]);
+    }
+
+    /// `count` entries spread over a few directories, each named after its index and holding it
+    /// a varying number of times
+    fn many_entries(count: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..count)
+            .map(|i| {
+                let name = format!("pack/dir{}/entry-{:04}.txt", i % 7, i);
+                let contents = format!("{:04};", i).repeat(1 + i % 50);
+                (name.into_bytes(), contents.into_bytes())
+            })
+            .collect()
+    }
+
+    fn borrowed(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<(&[u8], &[u8])> {
+        entries
+            .iter()
+            .map(|(name, contents)| (name.as_slice(), contents.as_slice()))
+            .collect()
+    }
+
+    fn jobs(jobs: usize) -> ExtractOptions {
+        ExtractOptions {
+            jobs,
+            ..ExtractOptions::default()
+        }
+    }
+
+    #[test]
+    fn many_entries_extract_the_same_on_several_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("pack.zip");
+        let entries = many_entries(PARALLEL_MIN_ENTRIES * 3);
+        zip_with_raw_names(&archive, &borrowed(&entries));
+
+        let (single, parallel) = (dir.path().join("single"), dir.path().join("parallel"));
+        extract(&archive, &single, &jobs(1)).unwrap();
+        let total: u64 = entries
+            .iter()
+            .map(|(_, contents)| contents.len() as u64)
+            .sum();
+        let progress = std::sync::Mutex::new((0, 0));
+        extract_archive_blocking(
+            &archive,
+            &parallel,
+            ArchiveKind::Zip,
+            &jobs(4),
+            &CancellationToken::new(),
+            &|done, total| *progress.lock().unwrap() = (done, total),
+        )
+        .unwrap();
+
+        assert_eq!(tree(&parallel).len(), entries.len());
+        assert_eq!(tree(&single), tree(&parallel));
+        for (name, contents) in &entries {
+            let name = std::str::from_utf8(name).unwrap();
+            let relative = name.strip_prefix("pack/").unwrap();
+            assert_eq!(&std::fs::read(parallel.join(relative)).unwrap(), contents);
+        }
+        assert_eq!(*progress.lock().unwrap(), (total, total));
+    }
+
+    #[test]
+    fn of_entries_with_the_same_name_the_last_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("pack.zip");
+        let mut entries = many_entries(PARALLEL_MIN_ENTRIES * 2);
+        let duplicate = entries[3].0.clone();
+        entries.push((duplicate.clone(), b"second copy".to_vec()));
+        zip_with_raw_names(&archive, &borrowed(&entries));
+
+        for threads in [1, 4] {
+            let extract_to = dir.path().join(format!("jobs-{}", threads));
+            extract(&archive, &extract_to, &jobs(threads)).unwrap();
+            let relative = std::str::from_utf8(&duplicate)
+                .unwrap()
+                .strip_prefix("pack/");
+            assert_eq!(
+                std::fs::read(extract_to.join(relative.unwrap())).unwrap(),
+                b"second copy"
+            );
+        }
+    }
+
+    #[test]
+    fn the_first_broken_entry_is_the_one_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("pack.zip");
+        let entries = many_entries(PARALLEL_MIN_ENTRIES * 3);
+        zip_with_raw_names(&archive, &borrowed(&entries));
+        // Corrupting stored data makes its checksum fail once the entry is read
+        let mut bytes = std::fs::read(&archive).unwrap();
+        for broken in [700, 300] {
+            let contents = &entries[broken].1;
+            let at = bytes
+                .windows(contents.len())
+                .position(|window| window == contents.as_slice())
+                .unwrap();
+            bytes[at] = b'X';
+        }
+        std::fs::write(&archive, bytes).unwrap();
+
+        for threads in [1, 2, 4, 8] {
+            let extract_to = dir.path().join(format!("jobs-{}", threads));
+            let error = extract(&archive, &extract_to, &jobs(threads)).unwrap_err();
+            assert!(
+                error.to_string().contains("entry-0300.txt"),
+                "{} threads: {}",
+                threads,
+                error
+            );
+            assert!(!extract_to.exists());
+        }
+    }
+
+    #[test]
+    fn work_is_shared_out_in_runs_of_similar_size() {
+        let entries: Vec<ZipEntry> = (0..100)
+            .map(|index| ZipEntry {
+                index,
+                name: index.to_string(),
+                outpath: PathBuf::from(index.to_string()),
+                size: if index < 10 { 1_000_000 } else { 1_000 },
+                is_dir: false,
+            })
+            .collect();
+        let files: Vec<&ZipEntry> = entries.iter().collect();
+        for jobs in [1, 2, 3, 8, 200] {
+            let slices = partition(&files, jobs);
+            assert!(slices.len() <= jobs);
+            // Every entry, in order, exactly once
+            let indexes: Vec<usize> = slices
+                .iter()
+                .flat_map(|slice| slice.iter().map(|entry| entry.index))
+                .collect();
+            assert_eq!(indexes, (0..100).collect::<Vec<_>>());
+        }
+        // The big entries at the start aren't all handed to the first thread
+        assert!(partition(&files, 4)[0].len() < 10);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::error::{Context, Result};
+use crate::history::{Outcome, Status};
+use crate::layout::Layout;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Name of the list of failed downloads inside the state directory
+const FAILED_FILE: &str = "failed.json";
+
+/// A game whose download failed and can be retried with `--retry-failed`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FailedItem {
+    pub game_id: u64,
+    pub upload_id: Option<u64>,
+    pub title: String,
+    pub error: Option<String>,
+}
+
+impl From<&Outcome> for FailedItem {
+    fn from(outcome: &Outcome) -> Self {
+        Self {
+            game_id: outcome.game_id,
+            upload_id: outcome.upload_id,
+            title: outcome.title.clone(),
+            error: outcome.error.clone(),
+        }
+    }
+}
+
+/// Load the failures recorded by the previous run, empty if there weren't any
+pub fn load(output_path: &Path, layout: &Layout) -> Result<Vec<FailedItem>> {
+    let path = layout.state_dir(output_path).join(FAILED_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Replace the recorded failures
+pub fn save(output_path: &Path, layout: &Layout, items: &[FailedItem]) -> Result<()> {
+    let state_dir = layout.state_dir(output_path);
+    std::fs::create_dir_all(&state_dir).context("Failed to create state directory")?;
+    let contents =
+        serde_json::to_string_pretty(items).context("Failed to serialize failed downloads")?;
+    std::fs::write(state_dir.join(FAILED_FILE), contents)
+        .context("Failed to write failed downloads")
+}
+
+/// Work out which failures remain after a run
+///
+/// A normal run replaces the list with its own failures. A `--retry-failed` run only ever shrinks
+/// it: anything that succeeded is dropped and anything it didn't get to is kept, so repeated
+/// retries converge to an empty list.
+pub fn remaining(previous: &[FailedItem], outcomes: &[Outcome], retrying: bool) -> Vec<FailedItem> {
+    let mut remaining: Vec<FailedItem> = outcomes
+        .iter()
+        .filter(|outcome| outcome.status == Status::Failed)
+        .map(FailedItem::from)
+        .collect();
+
+    if retrying {
+        let attempted: HashSet<u64> = outcomes.iter().map(|outcome| outcome.game_id).collect();
+        remaining.extend(
+            previous
+                .iter()
+                .filter(|item| !attempted.contains(&item.game_id))
+                .cloned(),
+        );
+    }
+
+    remaining
+}
@@ -0,0 +1,107 @@
+//! The allow and deny lists of `--filter-file`
+
+use crate::OwnedKey;
+use serde::Deserialize;
+
+/// Allow and deny lists read from `--filter-file`, on top of `--author` and `--title`
+///
+/// Authors and titles match like the flags do, case-insensitively and anywhere in the name. A
+/// key passes when it matches one of every non-empty `include_*` list and none of the
+/// `exclude_*` lists.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterFile {
+    #[serde(default)]
+    pub include_authors: Vec<String>,
+    #[serde(default)]
+    pub exclude_authors: Vec<String>,
+    #[serde(default)]
+    pub include_titles: Vec<String>,
+    #[serde(default)]
+    pub exclude_titles: Vec<String>,
+    #[serde(default)]
+    pub include_game_ids: Vec<u64>,
+    #[serde(default)]
+    pub exclude_game_ids: Vec<u64>,
+}
+
+impl FilterFile {
+    /// Check rules read from a filter file and lower-case their names to match against, failing
+    /// with what's wrong with them
+    pub fn checked(mut self) -> Result<Self, String> {
+        self.validate()?;
+        for list in [
+            &mut self.include_authors,
+            &mut self.exclude_authors,
+            &mut self.include_titles,
+            &mut self.exclude_titles,
+        ] {
+            for name in list.iter_mut() {
+                *name = name.trim().to_lowercase();
+            }
+        }
+        Ok(self)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let names = [
+            ("include_authors", &self.include_authors),
+            ("exclude_authors", &self.exclude_authors),
+            ("include_titles", &self.include_titles),
+            ("exclude_titles", &self.exclude_titles),
+        ];
+        for (key, list) in names {
+            if let Some(index) = list.iter().position(|name| name.trim().is_empty()) {
+                return Err(format!(
+                    "{}[{}] is empty, which would match everything",
+                    key, index
+                ));
+            }
+        }
+        if let Some(id) = self
+            .include_game_ids
+            .iter()
+            .find(|id| self.exclude_game_ids.contains(id))
+        {
+            return Err(format!(
+                "game {} is in both include_game_ids and exclude_game_ids",
+                id
+            ));
+        }
+        Ok(())
+    }
+
+    /// The rules with the ones `--author` and `--title` replace dropped, the command line winning
+    pub fn overridden_by(mut self, author: &Option<String>, title: &Option<String>) -> Self {
+        if author.is_some() {
+            self.include_authors.clear();
+            self.exclude_authors.clear();
+        }
+        if title.is_some() {
+            self.include_titles.clear();
+            self.exclude_titles.clear();
+        }
+        self
+    }
+
+    /// Whether a key passes the lists
+    pub fn matches(&self, key: &OwnedKey) -> bool {
+        let user = &key.game.user;
+        let author_names = [Some(user.username.as_str()), user.display_name.as_deref()];
+        let by_author = |name: &String| {
+            author_names
+                .iter()
+                .flatten()
+                .any(|author| author.to_lowercase().contains(name.as_str()))
+        };
+        let title = key.game.title.to_lowercase();
+        let by_title = |name: &String| title.contains(name.as_str());
+
+        (self.include_authors.is_empty() || self.include_authors.iter().any(by_author))
+            && !self.exclude_authors.iter().any(by_author)
+            && (self.include_titles.is_empty() || self.include_titles.iter().any(by_title))
+            && !self.exclude_titles.iter().any(by_title)
+            && (self.include_game_ids.is_empty() || self.include_game_ids.contains(&key.game_id))
+            && !self.exclude_game_ids.contains(&key.game_id)
+    }
+}
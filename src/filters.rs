@@ -0,0 +1,476 @@
+//! Which owned keys the `--author`, `--title`, `--bundle` and `--slug` options select
+
+use crate::OwnedKey;
+use crate::filter_file::FilterFile;
+use crate::timestamp::Timestamp;
+use std::collections::HashSet;
+use tracing::info;
+
+/// The `--author`, `--title` and `--bundle` filters, matched case-insensitively anywhere in the
+/// name, and `--slug`, which has to match the whole of [`Game::slug`](crate::Game::slug)
+///
+/// An author matches the username or the display name. A filter that wasn't given matches every
+/// key, so the default filter selects everything.
+#[derive(Clone, Debug, Default)]
+pub struct KeyFilter {
+    author: Option<String>,
+    title: Option<String>,
+    bundle: Option<String>,
+    slug: Option<String>,
+}
+
+impl KeyFilter {
+    /// A filter on the author and title, either of which may be left out
+    pub fn new(author: Option<String>, title: Option<String>) -> Self {
+        Self {
+            author: author.map(|author| author.to_lowercase()),
+            title: title.map(|title| title.to_lowercase()),
+            bundle: None,
+            slug: None,
+        }
+    }
+
+    /// Also require the key to come from a bundle, see [`OwnedKey::from_bundle`]
+    pub fn from_bundle(mut self, bundle: Option<String>) -> Self {
+        self.bundle = bundle;
+        self
+    }
+
+    /// Also require the game to be the one at `slug`, `<author>/<game>` or a link to its page
+    pub fn with_slug(mut self, slug: Option<String>) -> Self {
+        self.slug = slug.map(|slug| normalize_slug(&slug));
+        self
+    }
+
+    /// Whether no filter was given, so every key matches
+    pub fn is_empty(&self) -> bool {
+        self.author.is_none()
+            && self.title.is_none()
+            && self.bundle.is_none()
+            && self.slug.is_none()
+    }
+
+    /// Whether a key passes every filter given
+    pub fn matches(&self, key: &OwnedKey) -> bool {
+        self.matches_author(key)
+            && self.matches_title(key)
+            && self.matches_bundle(key)
+            && self.matches_slug(key)
+    }
+
+    fn matches_author(&self, key: &OwnedKey) -> bool {
+        let user = &key.game.user;
+        self.author.as_ref().is_none_or(|author| {
+            [Some(user.username.as_str()), user.display_name.as_deref()]
+                .into_iter()
+                .flatten()
+                .any(|name| name.to_lowercase().contains(author.as_str()))
+        })
+    }
+
+    fn matches_title(&self, key: &OwnedKey) -> bool {
+        self.title
+            .as_ref()
+            .is_none_or(|title| key.game.title.to_lowercase().contains(title.as_str()))
+    }
+
+    fn matches_bundle(&self, key: &OwnedKey) -> bool {
+        self.bundle
+            .as_ref()
+            .is_none_or(|bundle| key.from_bundle(bundle))
+    }
+
+    fn matches_slug(&self, key: &OwnedKey) -> bool {
+        self.slug
+            .as_ref()
+            .is_none_or(|slug| key.game.slug().as_ref() == Some(slug))
+    }
+}
+
+/// `<author>/<game>` from a slug or a link, e.g. `https://Someone.itch.io/some-game/purchase`
+/// becomes `someone/some-game`
+fn normalize_slug(slug: &str) -> String {
+    let slug = slug.trim().to_ascii_lowercase();
+    let slug = slug
+        .strip_prefix("https://")
+        .or_else(|| slug.strip_prefix("http://"))
+        .unwrap_or(&slug)
+        .replacen(".itch.io/", "/", 1);
+    let mut parts = slug.split(['/', '?', '#']).filter(|part| !part.is_empty());
+    match (parts.next(), parts.next()) {
+        (Some(author), Some(game)) => format!("{}/{}", author, game),
+        _ => slug.trim_end_matches('/').to_string(),
+    }
+}
+
+/// How many keys were looked at and how many each filter turned away, to say why nothing matched
+///
+/// A key several filters reject counts towards each of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Exclusions {
+    pub seen: usize,
+    pub author: usize,
+    pub title: usize,
+    pub bundle: usize,
+    pub slug: usize,
+}
+
+impl Exclusions {
+    /// Count every key of `keys` against `filter`
+    pub fn of<'a>(filter: &KeyFilter, keys: impl IntoIterator<Item = &'a OwnedKey>) -> Self {
+        let mut exclusions = Self::default();
+        for key in keys {
+            exclusions.count(filter, key);
+        }
+        exclusions
+    }
+
+    /// Count one more key against `filter`, returning whether it matches
+    pub fn count(&mut self, filter: &KeyFilter, key: &OwnedKey) -> bool {
+        self.seen += 1;
+        let checks = [
+            (filter.matches_author(key), &mut self.author),
+            (filter.matches_title(key), &mut self.title),
+            (filter.matches_bundle(key), &mut self.bundle),
+            (filter.matches_slug(key), &mut self.slug),
+        ];
+        let mut matches = true;
+        for (passed, excluded) in checks {
+            if !passed {
+                *excluded += 1;
+                matches = false;
+            }
+        }
+        matches
+    }
+
+    /// The options that turned keys away and how many each, e.g. `("--author", 12)`
+    pub fn by_option(&self) -> Vec<(&'static str, usize)> {
+        [
+            ("--author", self.author),
+            ("--title", self.title),
+            ("--bundle", self.bundle),
+            ("--slug", self.slug),
+        ]
+        .into_iter()
+        .filter(|(_, excluded)| *excluded > 0)
+        .collect()
+    }
+}
+
+/// Which library keys `dl` works through, counting how many get past each check so the run can say
+/// why nothing was left
+#[derive(Debug, Default)]
+pub struct KeySelection {
+    /// With `--retry-failed`, the games that failed last time, used instead of the filters
+    pub failed_games: Option<HashSet<u64>>,
+    /// `--author`, `--title`, `--bundle` and `--slug`
+    pub filter: KeyFilter,
+    /// With `--filter-file`, its allow and deny lists
+    pub filter_rules: Option<FilterFile>,
+    /// With `--collection`, the games in it
+    pub collection: Option<HashSet<u64>>,
+    /// With `bundle dl`, the bundle's claimed games
+    pub games: Option<HashSet<u64>>,
+    /// Purchases must be newer than this with `--since`
+    pub cutoff: Option<Timestamp>,
+    /// With `sync --new-only`, the games the manifest already knows about
+    pub known: Option<HashSet<u64>>,
+    /// Games skipped permanently during `--triage`
+    pub skipped: HashSet<u64>,
+    /// What `--author`, `--title`, `--bundle` and `--slug` turned away
+    pub exclusions: Exclusions,
+    /// Keys `--filter-file` turned away
+    pub ruled_out: usize,
+    /// Keys that matched the filters
+    pub matched: usize,
+    /// Keys that matched and were new enough for `--since`
+    pub recent: usize,
+}
+
+impl KeySelection {
+    /// A selection of the keys `filter` matches, narrowed by whichever other fields are set
+    pub fn new(filter: KeyFilter) -> Self {
+        Self {
+            filter,
+            ..Self::default()
+        }
+    }
+
+    /// Whether `key` is worked through, counting it towards every check it passes
+    pub fn admit(&mut self, key: &OwnedKey) -> bool {
+        let matched = match &self.failed_games {
+            Some(failed_games) => failed_games.contains(&key.game_id),
+            None => {
+                let matches = self.exclusions.count(&self.filter, key);
+                let ruled_in = self
+                    .filter_rules
+                    .as_ref()
+                    .is_none_or(|rules| rules.matches(key));
+                if !ruled_in {
+                    self.ruled_out += 1;
+                }
+                matches && ruled_in
+            }
+        } && [&self.collection, &self.games]
+            .into_iter()
+            .flatten()
+            .all(|games| games.contains(&key.game_id));
+        if !matched {
+            return false;
+        }
+        self.matched += 1;
+        if self
+            .cutoff
+            .is_some_and(|cutoff| !crate::since::is_newer(key.created_at, cutoff))
+        {
+            return false;
+        }
+        self.recent += 1;
+        !self
+            .known
+            .as_ref()
+            .is_some_and(|known| known.contains(&key.game_id))
+            && !self.skipped.contains(&key.game_id)
+    }
+
+    /// Log how many keys the filters and `--since` let through
+    pub fn log(&self) {
+        info!(
+            matched = self.matched,
+            retry_failed = self.failed_games.is_some(),
+            filter = ?self.filter,
+            "Applied filters"
+        );
+        if let Some(cutoff) = &self.cutoff {
+            info!(cutoff = %cutoff, matched = self.recent, "Applied --since");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(title: &str, username: &str, display_name: Option<&str>) -> OwnedKey {
+        serde_json::from_value(json!({
+            "id": 1,
+            "game_id": 10,
+            "game": {
+                "id": 10,
+                "title": title,
+                "url": format!("https://{}.itch.io/some-game", username),
+                "user": { "id": 2, "username": username, "display_name": display_name }
+            }
+        }))
+        .unwrap()
+    }
+
+    fn bundled(mut key: OwnedKey, id: u64, title: &str) -> OwnedKey {
+        key.bundle = Some(crate::BundleOrigin {
+            id,
+            title: title.to_string(),
+        });
+        key
+    }
+
+    #[test]
+    fn default_filter_matches_everything() {
+        let filter = KeyFilter::default();
+        assert!(filter.is_empty());
+        assert!(filter.matches(&key("Anything", "someone", None)));
+    }
+
+    #[test]
+    fn author_and_title_ignore_case_and_match_anywhere() {
+        let key = key("The Big Game", "SomeOne", None);
+        assert!(KeyFilter::new(Some("someone".into()), None).matches(&key));
+        assert!(KeyFilter::new(Some("EON".into()), None).matches(&key));
+        assert!(KeyFilter::new(None, Some("big".into())).matches(&key));
+        assert!(KeyFilter::new(None, Some("BIG GAME".into())).matches(&key));
+        assert!(!KeyFilter::new(None, Some("small".into())).matches(&key));
+    }
+
+    #[test]
+    fn author_falls_back_to_the_display_name() {
+        let named = key("Game", "someone", Some("Studio Name"));
+        assert!(KeyFilter::new(Some("studio".into()), None).matches(&named));
+        assert!(KeyFilter::new(Some("someone".into()), None).matches(&named));
+        let unnamed = key("Game", "someone", None);
+        assert!(!KeyFilter::new(Some("studio".into()), None).matches(&unnamed));
+    }
+
+    #[test]
+    fn every_given_filter_has_to_match() {
+        let key = key("The Big Game", "someone", None);
+        let filter = KeyFilter::new(Some("someone".into()), Some("small".into()));
+        assert!(!filter.is_empty());
+        assert!(!filter.matches(&key));
+    }
+
+    #[test]
+    fn bundle_matches_by_id_title_or_direct() {
+        let direct = key("Game", "someone", None);
+        let bundled = bundled(direct.clone(), 42, "Bundle for Racial Justice");
+        let filter = |bundle: &str| KeyFilter::default().from_bundle(Some(bundle.to_string()));
+        assert!(filter("42").matches(&bundled));
+        assert!(filter("racial").matches(&bundled));
+        assert!(!filter("43").matches(&bundled));
+        assert!(filter("direct").matches(&direct));
+        assert!(!filter("direct").matches(&bundled));
+        assert!(!filter("racial").matches(&direct));
+    }
+
+    #[test]
+    fn slugs_are_normalized_from_links() {
+        assert_eq!(normalize_slug("someone/some-game"), "someone/some-game");
+        assert_eq!(
+            normalize_slug(" https://SomeOne.itch.io/some-game/purchase?x=1 "),
+            "someone/some-game"
+        );
+        assert_eq!(
+            normalize_slug("someone.itch.io/some-game#top"),
+            "someone/some-game"
+        );
+        assert_eq!(normalize_slug("some-game/"), "some-game");
+    }
+
+    #[test]
+    fn slug_has_to_match_the_whole_slug() {
+        let key = key("Game", "someone", None);
+        let filter = |slug: &str| KeyFilter::default().with_slug(Some(slug.to_string()));
+        assert!(filter("someone/some-game").matches(&key));
+        assert!(filter("https://someone.itch.io/some-game").matches(&key));
+        assert!(!filter("someone/some").matches(&key));
+        assert!(!filter("other/some-game").matches(&key));
+    }
+
+    #[test]
+    fn exclusions_count_each_filter_that_turned_a_key_away() {
+        let keys = [
+            key("The Big Game", "someone", None),
+            key("Small Game", "someone", None),
+            key("Small Game", "other", None),
+        ];
+        let filter = KeyFilter::new(Some("someone".into()), Some("big".into()));
+        let exclusions = Exclusions::of(&filter, &keys);
+        assert_eq!(exclusions.seen, 3);
+        assert_eq!((exclusions.author, exclusions.title), (1, 2));
+        assert_eq!(exclusions.by_option(), [("--author", 1), ("--title", 2)]);
+    }
+
+    #[test]
+    fn an_empty_library_is_told_apart_from_filtered_out_keys() {
+        let filter = KeyFilter::new(Some("someone".into()), None);
+        let empty = Exclusions::of(&filter, &[]);
+        assert_eq!(empty.seen, 0);
+        assert!(empty.by_option().is_empty());
+
+        let filtered = Exclusions::of(&filter, &[key("Game", "other", None)]);
+        assert_eq!(filtered.seen, 1);
+        assert_eq!(filtered.by_option(), [("--author", 1)]);
+    }
+
+    fn game(game_id: u64, title: &str, created_at: &str) -> OwnedKey {
+        let mut key = key(title, "someone", None);
+        key.game_id = game_id;
+        key.game.id = game_id;
+        key.created_at = crate::timestamp::parse(created_at);
+        key
+    }
+
+    fn admitted(selection: &mut KeySelection, keys: &[OwnedKey]) -> Vec<u64> {
+        keys.iter()
+            .filter(|key| selection.admit(key))
+            .map(|key| key.game_id)
+            .collect()
+    }
+
+    fn library() -> Vec<OwnedKey> {
+        vec![
+            game(1, "Celeste", "2024-01-01"),
+            game(2, "Celeste Classic", "2024-06-01"),
+            game(3, "Hollow Knight", "2024-06-01"),
+        ]
+    }
+
+    #[test]
+    fn selection_counts_each_check() {
+        let mut selection = KeySelection {
+            cutoff: crate::timestamp::parse("2024-03-01"),
+            known: Some([2].into()),
+            ..KeySelection::new(KeyFilter::new(None, Some("celeste".into())))
+        };
+        assert_eq!(admitted(&mut selection, &library()), Vec::<u64>::new());
+        assert_eq!(selection.exclusions.seen, 3);
+        assert_eq!(selection.exclusions.title, 1);
+        assert_eq!(selection.matched, 2);
+        // Only Celeste Classic is new enough, and the manifest already knows it
+        assert_eq!(selection.recent, 1);
+    }
+
+    #[test]
+    fn selection_narrows_by_rules_collection_and_skips() {
+        let rules = FilterFile {
+            exclude_game_ids: vec![3],
+            ..FilterFile::default()
+        };
+        let mut selection = KeySelection {
+            filter_rules: Some(rules),
+            ..KeySelection::default()
+        };
+        assert_eq!(admitted(&mut selection, &library()), vec![1, 2]);
+        assert_eq!(selection.ruled_out, 1);
+
+        let mut selection = KeySelection {
+            collection: Some([2, 3].into()),
+            skipped: [3].into(),
+            ..KeySelection::default()
+        };
+        assert_eq!(admitted(&mut selection, &library()), vec![2]);
+        assert_eq!(selection.matched, 2);
+    }
+
+    #[test]
+    fn retrying_failures_replaces_the_filters() {
+        let rules = FilterFile {
+            exclude_game_ids: vec![3],
+            ..FilterFile::default()
+        };
+        let mut selection = KeySelection {
+            failed_games: Some([3].into()),
+            filter_rules: Some(rules),
+            ..KeySelection::new(KeyFilter::new(None, Some("celeste".into())))
+        };
+        assert_eq!(admitted(&mut selection, &library()), vec![3]);
+        assert_eq!(selection.exclusions.seen, 0);
+        assert_eq!(selection.ruled_out, 0);
+    }
+
+    #[test]
+    fn filter_file_rules_are_checked() {
+        let rules = FilterFile {
+            include_authors: vec!["  SomeOne ".into()],
+            ..FilterFile::default()
+        };
+        let rules = rules.checked().unwrap();
+        assert_eq!(rules.include_authors, vec!["someone"]);
+        assert!(rules.matches(&key("Game", "someone", None)));
+
+        let empty = FilterFile {
+            exclude_titles: vec!["ok".into(), " ".into()],
+            ..FilterFile::default()
+        };
+        assert_eq!(
+            empty.checked().unwrap_err(),
+            "exclude_titles[1] is empty, which would match everything"
+        );
+        let both = FilterFile {
+            include_game_ids: vec![4],
+            exclude_game_ids: vec![4],
+            ..FilterFile::default()
+        };
+        assert!(both.checked().unwrap_err().contains("game 4 is in both"));
+    }
+}
@@ -0,0 +1,290 @@
+use crate::error::{ItchError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Fields dropped from recorded responses, whatever they hold
+const SECRET_FIELDS: [&str; 8] = [
+    "api_key",
+    "key",
+    "secret",
+    "token",
+    "access_token",
+    "password",
+    "cookie",
+    "email",
+];
+
+/// Fields holding names of games, people or files, hashed unless names are kept
+const NAME_FIELDS: [&str; 6] = [
+    "title",
+    "username",
+    "display_name",
+    "name",
+    "short_text",
+    "filename",
+];
+
+/// One recorded API exchange, as written to a fixtures directory
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Fixture {
+    /// The request relative to the API, e.g. `profile/owned-keys?page=1`, see [`request_key`]
+    pub request: String,
+    pub status: u16,
+    /// The response, when it was JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+    /// The response when it wasn't JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl Fixture {
+    /// The response body as the server would have sent it
+    pub fn body_text(&self) -> String {
+        match (&self.body, &self.text) {
+            (Some(body), _) => body.to_string(),
+            (None, Some(text)) => text.clone(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+/// How a request is told apart in a fixtures directory: its path below `base_url`, or the whole
+/// URL for another host, with its query parameters sorted
+pub fn request_key(base_url: &reqwest::Url, url: &str, query: &[(&str, u64)]) -> String {
+    let Ok(mut parsed) = reqwest::Url::parse(url) else {
+        return url.to_string();
+    };
+    let mut pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .chain(
+            query
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )
+        .collect();
+    pairs.sort();
+    parsed.set_query(None);
+    let base = base_url.as_str().trim_end_matches('/');
+    let path = match parsed.as_str().strip_prefix(base) {
+        Some(path) => path.trim_start_matches('/').to_string(),
+        None => parsed.to_string(),
+    };
+    if pairs.is_empty() {
+        return path;
+    }
+    let query: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    format!("{}?{}", path, query.join("&"))
+}
+
+/// A short stand-in for `value` that's the same wherever the value appears
+fn pseudonym(value: &str) -> String {
+    let digest = Sha256::digest(value.as_bytes());
+    digest[..6]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether a string looks like an email address
+fn is_email(value: &str) -> bool {
+    let Some((user, domain)) = value.split_once('@') else {
+        return false;
+    };
+    !user.is_empty()
+        && domain.contains('.')
+        && !value.contains(char::is_whitespace)
+        && !domain.contains('@')
+}
+
+/// Strip secrets and email addresses from a response and, unless `keep_names`, replace names
+/// and page URLs with pseudonyms
+///
+/// The shape of the response is kept, so parse failures still reproduce. Filenames keep their
+/// extension, so archive handling behaves the same.
+pub fn sanitize(value: &mut Value, keep_names: bool) {
+    match value {
+        Value::Object(fields) => {
+            fields.retain(|name, _| !SECRET_FIELDS.contains(&name.as_str()));
+            for (name, value) in fields.iter_mut() {
+                match value {
+                    Value::String(text) if !keep_names && NAME_FIELDS.contains(&name.as_str()) => {
+                        *text = match text.split_once('.') {
+                            Some((stem, extension)) if name == "filename" => {
+                                format!("{}-{}.{}", name, pseudonym(stem), extension)
+                            }
+                            _ => format!("{}-{}", name, pseudonym(text)),
+                        };
+                    }
+                    Value::String(text) if !keep_names && name.ends_with("url") => {
+                        *text = format!("https://{}.itch.io/{}", pseudonym(text), pseudonym(text));
+                    }
+                    value => sanitize(value, keep_names),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| sanitize(item, keep_names)),
+        Value::String(text) if is_email(text) => *text = "<email>".to_string(),
+        _ => {}
+    }
+}
+
+/// Writes a sanitized copy of every API response to a directory, see
+/// [`crate::ItchClientBuilder::record_fixtures`]
+#[derive(Debug)]
+pub struct FixtureRecorder {
+    dir: PathBuf,
+    keep_names: bool,
+    next: AtomicUsize,
+}
+
+impl FixtureRecorder {
+    /// Start recording into `dir`, creating it if needed
+    ///
+    /// Numbering carries on after the fixtures already there, so recordings of several clients
+    /// or runs end up side by side in the order they were made.
+    pub fn new(dir: &Path, keep_names: bool) -> Result<Self> {
+        std::fs::create_dir_all(dir).map_err(|source| {
+            ItchError::io(format!("Failed to create {}", dir.display()), source)
+        })?;
+        let recorded = std::fs::read_dir(dir)
+            .map_err(|source| ItchError::io(format!("Failed to read {}", dir.display()), source))?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().to_string_lossy().into_owned();
+                name.split_once('-')?.0.parse::<usize>().ok()
+            })
+            .max()
+            .unwrap_or(0);
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            keep_names,
+            next: AtomicUsize::new(recorded + 1),
+        })
+    }
+
+    /// Write one exchange, returning the file it went to
+    pub fn record(
+        &self,
+        request: &str,
+        status: reqwest::StatusCode,
+        body: &str,
+    ) -> Result<PathBuf> {
+        let number = self.next.fetch_add(1, Ordering::Relaxed);
+        let fixture = match serde_json::from_str::<Value>(body) {
+            Ok(mut body) => {
+                sanitize(&mut body, self.keep_names);
+                Fixture {
+                    request: request.to_string(),
+                    status: status.as_u16(),
+                    body: Some(body),
+                    text: None,
+                }
+            }
+            Err(_) => Fixture {
+                request: request.to_string(),
+                status: status.as_u16(),
+                body: None,
+                text: Some(body.to_string()),
+            },
+        };
+        let endpoint: String = request
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let path = self.dir.join(format!("{:04}-{}.json", number, endpoint));
+        let contents = serde_json::to_string_pretty(&fixture)
+            .map_err(|e| ItchError::io("Failed to serialize fixture", std::io::Error::other(e)))?;
+        std::fs::write(&path, contents).map_err(|source| {
+            ItchError::io(format!("Failed to write {}", path.display()), source)
+        })?;
+        Ok(path)
+    }
+}
+
+/// The fixtures recorded for one request, with the files they came from, and how many of them
+/// were served so far
+type Recordings = (Vec<(PathBuf, Fixture)>, usize);
+
+/// Answers API requests from a directory of recorded fixtures instead of the network, see
+/// [`crate::ItchClientBuilder::replay_fixtures`]
+///
+/// A request recorded several times gets the recordings in order, the last one repeating.
+#[derive(Debug)]
+pub struct FixtureReplay {
+    dir: PathBuf,
+    fixtures: Mutex<HashMap<String, Recordings>>,
+}
+
+impl FixtureReplay {
+    /// Read every fixture in `dir`, in the order they were recorded
+    pub fn load(dir: &Path) -> Result<Self> {
+        let read_error = |source| {
+            ItchError::io(
+                format!("Failed to read fixtures in {}", dir.display()),
+                source,
+            )
+        };
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+            .map_err(read_error)?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "json")
+            })
+            .collect();
+        paths.sort();
+
+        let mut fixtures: HashMap<String, Recordings> = HashMap::new();
+        for path in paths {
+            let contents = std::fs::read_to_string(&path).map_err(|source| {
+                ItchError::io(format!("Failed to read {}", path.display()), source)
+            })?;
+            let fixture: Fixture = serde_json::from_str(&contents).map_err(|e| {
+                ItchError::io(
+                    format!("{} isn't a fixture", path.display()),
+                    std::io::Error::other(e),
+                )
+            })?;
+            fixtures
+                .entry(fixture.request.clone())
+                .or_default()
+                .0
+                .push((path, fixture));
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            fixtures: Mutex::new(fixtures),
+        })
+    }
+
+    /// The error for a request that has no recorded response
+    pub fn missing(&self, request: impl Into<String>) -> ItchError {
+        ItchError::MissingFixture {
+            request: request.into(),
+            dir: self.dir.clone(),
+        }
+    }
+
+    /// The next recorded response to `request` and the file it came from
+    pub fn respond(&self, request: &str) -> Result<(PathBuf, Fixture)> {
+        let mut fixtures = self.fixtures.lock().unwrap();
+        let Some((recorded, served)) = fixtures.get_mut(request) else {
+            return Err(self.missing(request));
+        };
+        let fixture = recorded[(*served).min(recorded.len() - 1)].clone();
+        *served += 1;
+        Ok(fixture)
+    }
+}
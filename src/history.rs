@@ -0,0 +1,193 @@
+use crate::error::{Context, Result};
+use crate::layout::Layout;
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+/// Name of the history log inside the state directory
+pub(crate) const HISTORY_FILE: &str = "history.jsonl";
+
+/// How processing a single game ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Completed,
+    Skipped,
+    Failed,
+    /// The game or its upload has been deleted from itch.io (404/410), retrying won't bring it back
+    Gone,
+    /// itch.io refused the download key as used up, revoked or expired, retrying won't help
+    #[serde(rename = "key_limit")]
+    KeyLimit,
+}
+
+impl Status {
+    /// Short name used in the results table
+    pub fn label(self) -> &'static str {
+        match self {
+            Status::Completed => "ok",
+            Status::Skipped => "skipped",
+            Status::Failed => "failed",
+            Status::Gone => "gone",
+            Status::KeyLimit => "limit",
+        }
+    }
+}
+
+/// The result of processing a single game
+#[derive(Clone, Debug, Serialize)]
+pub struct Outcome {
+    pub game_id: u64,
+    pub title: String,
+    pub upload_id: Option<u64>,
+    pub filename: Option<String>,
+    pub bytes: u64,
+    pub status: Status,
+    /// Why it failed, or what went wrong after a download that still completed
+    pub error: Option<String>,
+    /// Copied from another local mirror instead of downloaded
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub seeded: bool,
+}
+
+impl Outcome {
+    pub fn new(game_id: u64, title: &str) -> Self {
+        Self {
+            game_id,
+            title: title.to_string(),
+            upload_id: None,
+            filename: None,
+            bytes: 0,
+            status: Status::Completed,
+            error: None,
+            seeded: false,
+        }
+    }
+
+    /// Note which upload was picked for the game
+    pub fn upload(mut self, upload_id: u64, filename: &str) -> Self {
+        self.upload_id = Some(upload_id);
+        self.filename = Some(filename.to_string());
+        self
+    }
+
+    pub fn completed(mut self, bytes: u64, error: Option<String>) -> Self {
+        self.status = Status::Completed;
+        self.bytes = bytes;
+        self.error = error;
+        self
+    }
+
+    /// The file was copied from a seed directory rather than downloaded
+    pub fn seeded(mut self) -> Self {
+        self.seeded = true;
+        self
+    }
+
+    pub fn skipped(mut self) -> Self {
+        self.status = Status::Skipped;
+        self
+    }
+
+    /// Skipped for a reason worth showing in the report
+    pub fn skipped_because(mut self, reason: impl std::fmt::Display) -> Self {
+        self.status = Status::Skipped;
+        self.error = Some(reason.to_string());
+        self
+    }
+
+    pub fn failed(mut self, error: impl std::fmt::Display) -> Self {
+        self.status = Status::Failed;
+        self.error = Some(error.to_string());
+        self
+    }
+
+    /// The game was deleted, `what` saying what itch.io no longer has
+    pub fn gone(mut self, what: &str) -> Self {
+        self.status = Status::Gone;
+        self.error = Some(format!("{} no longer exists on itch.io", what));
+        self
+    }
+
+    /// itch.io won't hand out more downloads with the game's key, `error` being what it said
+    pub fn key_limit(mut self, error: impl std::fmt::Display) -> Self {
+        self.status = Status::KeyLimit;
+        self.error = Some(format!(
+            "download key limit reached for {} ({})",
+            self.title, error
+        ));
+        self
+    }
+}
+
+/// One line of the history log
+#[derive(Debug, Serialize)]
+struct HistoryRecord {
+    /// Seconds since the Unix epoch
+    timestamp: u64,
+    duration_ms: u64,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+/// A handle tasks use to append to the history log, cheap to clone
+#[derive(Clone)]
+pub struct History {
+    sender: mpsc::UnboundedSender<HistoryRecord>,
+}
+
+impl History {
+    /// Queue an outcome to be appended to the log
+    pub fn record(&self, outcome: Outcome, duration: Duration) {
+        let record = HistoryRecord {
+            timestamp: crate::manifest::now(),
+            duration_ms: duration.as_millis() as u64,
+            outcome,
+        };
+        // The writer only goes away once every handle has been dropped
+        let _ = self.sender.send(record);
+    }
+}
+
+/// The single task that owns the history file, so concurrent downloads never interleave lines
+pub struct HistoryWriter {
+    task: tokio::task::JoinHandle<Result<()>>,
+}
+
+impl HistoryWriter {
+    /// Wait for every queued record to be written, once all `History` handles are dropped
+    pub async fn finish(self) -> Result<()> {
+        self.task.await.context("History writer task failed")?
+    }
+}
+
+/// Open the history log in the output directory and start its writer task
+pub async fn start(output_path: &Path, layout: &Layout) -> Result<(History, HistoryWriter)> {
+    let state_dir = layout.state_dir(output_path);
+    tokio::fs::create_dir_all(&state_dir)
+        .await
+        .context("Failed to create state directory")?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state_dir.join(HISTORY_FILE))
+        .await
+        .context("Failed to open history log")?;
+
+    let (sender, mut receiver) = mpsc::unbounded_channel::<HistoryRecord>();
+    let task = tokio::spawn(async move {
+        while let Some(record) = receiver.recv().await {
+            let mut line =
+                serde_json::to_string(&record).context("Failed to serialize history record")?;
+            line.push('\n');
+            file.write_all(line.as_bytes())
+                .await
+                .context("Failed to write history log")?;
+        }
+        file.flush().await.context("Failed to flush history log")
+    });
+
+    Ok((History { sender }, HistoryWriter { task }))
+}
@@ -0,0 +1,91 @@
+use crate::error::{Context, Result};
+use reqwest::header::HeaderMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// Query parameters whose values are never written out
+const SECRET_PARAMS: [&str; 4] = ["api_key", "key", "token", "access_token"];
+
+/// Writes every API exchange to a numbered file for `--debug-http`
+pub struct HttpDump {
+    dir: PathBuf,
+    next: AtomicUsize,
+}
+
+static RECORDER: OnceLock<Arc<HttpDump>> = OnceLock::new();
+
+/// Start recording into `dir`, creating it if needed
+pub fn init(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let _ = RECORDER.set(Arc::new(HttpDump {
+        dir: dir.to_path_buf(),
+        next: AtomicUsize::new(1),
+    }));
+    Ok(())
+}
+
+/// The recorder, when `--debug-http` was given
+pub fn recorder() -> Option<Arc<HttpDump>> {
+    RECORDER.get().cloned()
+}
+
+/// The URL with any secret-looking query values replaced
+fn redact(url: &reqwest::Url) -> String {
+    let mut url = url.clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| {
+            let value = if SECRET_PARAMS.contains(&name.as_ref()) {
+                "<redacted>".to_string()
+            } else {
+                value.into_owned()
+            };
+            (name.into_owned(), value)
+        })
+        .collect();
+    if !pairs.is_empty() {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
+
+impl HttpDump {
+    /// Write one exchange, returning the file it went to
+    ///
+    /// The API key is sent as a header on the request and only response headers are written, so it
+    /// never ends up in the file.
+    pub fn record(
+        &self,
+        method: &str,
+        url: &reqwest::Url,
+        status: reqwest::StatusCode,
+        headers: &HeaderMap,
+        body: &str,
+    ) -> Result<PathBuf> {
+        let number = self.next.fetch_add(1, Ordering::Relaxed);
+        let endpoint = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|segment| !segment.is_empty())
+            .unwrap_or("root");
+        let path = self.dir.join(format!("{:04}-{}.txt", number, endpoint));
+
+        let mut contents = format!("{} {}\n{}\n", method, redact(url), status);
+        for (name, value) in headers {
+            let _ = writeln!(
+                contents,
+                "{}: {}",
+                name,
+                String::from_utf8_lossy(value.as_bytes())
+            );
+        }
+        contents.push('\n');
+        contents.push_str(body);
+
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+}
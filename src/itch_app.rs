@@ -0,0 +1,109 @@
+use crate::error::{Context, Result};
+use crate::timestamp::Timestamp;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Where the app keeps its receipt inside every install folder
+const RECEIPT_PATH: &str = ".itch/receipt.json.gz";
+
+/// How deep below the app directory we look for install folders
+const MAX_SEARCH_DEPTH: usize = 4;
+
+/// The game an install belongs to, as recorded by the itch app
+#[derive(Debug, Deserialize)]
+pub struct ReceiptGame {
+    pub id: u64,
+    pub title: Option<String>,
+}
+
+/// The upload that was installed, as recorded by the itch app
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptUpload {
+    pub id: u64,
+    pub filename: Option<String>,
+    pub size: Option<u64>,
+    pub md5_hash: Option<String>,
+    pub build_id: Option<u64>,
+    #[serde(default, deserialize_with = "crate::json::timestamp")]
+    pub updated_at: Option<Timestamp>,
+}
+
+/// The receipt the itch app (through butler) writes into each install folder
+#[derive(Debug, Deserialize)]
+pub struct Receipt {
+    pub game: Option<ReceiptGame>,
+    pub upload: Option<ReceiptUpload>,
+}
+
+/// A game installed by the itch app
+#[derive(Debug)]
+pub struct Install {
+    /// The install folder, the one containing `.itch`
+    pub dir: PathBuf,
+    pub game: ReceiptGame,
+    pub upload: ReceiptUpload,
+}
+
+/// What [`find_installs`] found below the app directory
+#[derive(Debug)]
+pub struct FoundInstalls {
+    /// Sorted by game id
+    pub installs: Vec<Install>,
+    /// Receipts that couldn't be used and why
+    pub unusable: Vec<(PathBuf, String)>,
+}
+
+/// The itch app's default data directory for this user, if the platform has one
+pub fn default_app_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("itch"))
+}
+
+fn read_receipt(path: &Path) -> Result<Receipt> {
+    let file = std::fs::File::open(path).context("Failed to open receipt")?;
+    serde_json::from_reader(GzDecoder::new(file)).context("Failed to parse receipt")
+}
+
+/// Find every install below `app_dir` that has a receipt, and the receipts that couldn't be used
+pub fn find_installs(app_dir: &Path) -> Result<FoundInstalls> {
+    let mut installs = Vec::new();
+    let mut unusable = Vec::new();
+    let mut pending = vec![(app_dir.to_path_buf(), 0)];
+
+    while let Some((dir, depth)) = pending.pop() {
+        let receipt_path = dir.join(RECEIPT_PATH);
+        if receipt_path.is_file() {
+            match read_receipt(&receipt_path) {
+                Ok(Receipt {
+                    game: Some(game),
+                    upload: Some(upload),
+                }) => installs.push(Install { dir, game, upload }),
+                Ok(_) => unusable.push((receipt_path, "no game or upload recorded".to_string())),
+                Err(e) => unusable.push((receipt_path, format!("{:#}", e))),
+            }
+            // Installs don't nest
+            continue;
+        }
+
+        if depth >= MAX_SEARCH_DEPTH {
+            continue;
+        }
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // The top level has to be readable, below it we skip what we can't read
+            Err(e) if depth == 0 => {
+                return Err(e).with_context(|| format!("Failed to read {}", app_dir.display()));
+            }
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                pending.push((entry.path(), depth + 1));
+            }
+        }
+    }
+
+    installs.sort_by_key(|install| install.game.id);
+    Ok(FoundInstalls { installs, unusable })
+}
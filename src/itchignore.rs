@@ -0,0 +1,239 @@
+use crate::error::{Context, ItchError, Result};
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Name of the files listing what the maintenance commands leave alone
+pub const IGNORE_FILE: &str = ".itchignore";
+
+/// How far below the output directory `.itchignore` files are read from, enough for game
+/// directories inside bundle directories
+const MAX_RULES_DEPTH: usize = 2;
+
+/// The `.itchignore` files of a mirror, gitignore-style patterns for paths `prune`, `verify`,
+/// `clean` and `dedupe-report` treat as if they weren't there
+///
+/// The one at the output root covers the whole mirror, one in a game directory covers that
+/// directory and takes precedence there, so it can `!` re-include what the root one ignores.
+#[derive(Debug, Default)]
+pub struct IgnoreRules {
+    /// Each directory with an `.itchignore` and its patterns, deepest first
+    matchers: Vec<(PathBuf, Gitignore)>,
+    /// The paths found ignored so far, not counting what's inside them
+    excluded: Mutex<HashSet<PathBuf>>,
+}
+
+impl IgnoreRules {
+    /// Read the `.itchignore` files of the mirror at `output_path`, empty if there are none
+    pub fn load(output_path: &Path) -> Result<Self> {
+        let mut dirs = Vec::new();
+        find_rule_dirs(output_path, 0, &mut dirs);
+        // Deeper directories first, so their rules are asked before those of their parents
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+
+        let mut matchers = Vec::with_capacity(dirs.len());
+        for dir in dirs {
+            let path = dir.join(IGNORE_FILE);
+            let mut builder = GitignoreBuilder::new(&dir);
+            if let Some(e) = builder.add(&path) {
+                return Err(std::io::Error::other(e))
+                    .with_context(|| format!("Failed to read {}", path.display()));
+            }
+            let matcher = builder.build().map_err(|e| ItchError::Invalid {
+                message: format!("Invalid pattern in {}: {}", path.display(), e),
+            })?;
+            matchers.push((dir, matcher));
+        }
+        Ok(Self {
+            matchers,
+            excluded: Default::default(),
+        })
+    }
+
+    /// Whether any `.itchignore` was found
+    pub fn is_empty(&self) -> bool {
+        self.matchers.is_empty()
+    }
+
+    /// Whether `path`, under the output directory it was loaded from, is to be left alone
+    ///
+    /// A path inside an ignored directory is ignored too. The `.itchignore` files themselves
+    /// always are, without counting towards [`IgnoreRules::excluded`].
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if path.file_name().is_some_and(|name| name == IGNORE_FILE) {
+            return true;
+        }
+        for (dir, matcher) in &self.matchers {
+            if !path.starts_with(dir) || path == dir {
+                continue;
+            }
+            match matcher.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => {
+                    self.excluded.lock().unwrap().insert(path.to_path_buf());
+                    return true;
+                }
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+
+    /// How many paths were found ignored so far, which staying at 0 suggests a pattern is off
+    pub fn excluded(&self) -> usize {
+        self.excluded.lock().unwrap().len()
+    }
+}
+
+/// Add the directories within [`MAX_RULES_DEPTH`] of `dir` that have an `.itchignore` to `dirs`
+fn find_rule_dirs(dir: &Path, depth: usize, dirs: &mut Vec<PathBuf>) {
+    if dir.join(IGNORE_FILE).is_file() {
+        dirs.push(dir.to_path_buf());
+    }
+    if depth >= MAX_RULES_DEPTH {
+        return;
+    }
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_name() == crate::layout::STATE_DIR {
+            continue;
+        }
+        if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            find_rule_dirs(&entry.path(), depth + 1, dirs);
+        }
+    }
+}
+
+/// Remove `path` like [`crate::staging::remove_path`], except for anything inside it `rules`
+/// ignore, which is kept along with the directories leading to it
+///
+/// Returns whether `path` is gone entirely.
+pub fn remove_unignored(path: &Path, rules: &IgnoreRules) -> std::io::Result<bool> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if rules.is_ignored(path, metadata.is_dir()) {
+        return Ok(false);
+    }
+    if rules.is_empty() || !metadata.is_dir() {
+        crate::staging::remove_path(path)?;
+        return Ok(true);
+    }
+    let mut emptied = true;
+    for entry in std::fs::read_dir(path)? {
+        emptied &= remove_unignored(&entry?.path(), rules)?;
+    }
+    if emptied {
+        std::fs::remove_dir(path)?;
+    }
+    Ok(emptied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn without_ignore_files_nothing_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let rules = IgnoreRules::load(dir.path()).unwrap();
+        assert!(rules.is_empty());
+        assert!(!rules.is_ignored(&dir.path().join("Game/notes.txt"), false));
+    }
+
+    #[test]
+    fn root_patterns_cover_the_whole_mirror() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write(
+            &root.join(IGNORE_FILE),
+            "# my own files\n*.txt\n/Patched/\nsaves/\n",
+        );
+        let rules = IgnoreRules::load(root).unwrap();
+
+        assert!(rules.is_ignored(&root.join("Game/notes.txt"), false));
+        assert!(rules.is_ignored(&root.join("Game/deep/down/notes.txt"), false));
+        assert!(!rules.is_ignored(&root.join("Game/game.zip"), false));
+        // Anchored to the root, and only directories
+        assert!(rules.is_ignored(&root.join("Patched"), true));
+        assert!(!rules.is_ignored(&root.join("Game/Patched"), true));
+        // Whatever is inside an ignored directory is ignored with it
+        assert!(rules.is_ignored(&root.join("Game/saves/slot1.dat"), false));
+        assert!(!rules.is_ignored(&root.join("Game/saves"), false));
+        // The ignore files themselves are always left alone
+        assert!(rules.is_ignored(&root.join(IGNORE_FILE), false));
+    }
+
+    #[test]
+    fn game_directories_can_re_include_what_the_root_ignores() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write(&root.join(IGNORE_FILE), "*.txt\n");
+        write(&root.join("Game").join(IGNORE_FILE), "!manual.txt\n*.bak\n");
+        write(
+            &root.join("Bundle/Nested").join(IGNORE_FILE),
+            "*.zip\n!keep.zip\n",
+        );
+        let rules = IgnoreRules::load(root).unwrap();
+
+        assert!(!rules.is_ignored(&root.join("Game/manual.txt"), false));
+        assert!(rules.is_ignored(&root.join("Game/notes.txt"), false));
+        assert!(rules.is_ignored(&root.join("Game/old.bak"), false));
+        // Another game's directory doesn't see the first one's rules
+        assert!(!rules.is_ignored(&root.join("Other/old.bak"), false));
+        assert!(rules.is_ignored(&root.join("Other/manual.txt"), false));
+        // Two levels down, for games inside bundle directories
+        assert!(rules.is_ignored(&root.join("Bundle/Nested/game.zip"), false));
+        assert!(!rules.is_ignored(&root.join("Bundle/Nested/keep.zip"), false));
+    }
+
+    #[test]
+    fn excluded_paths_are_counted_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write(&root.join(IGNORE_FILE), "*.txt\n");
+        let rules = IgnoreRules::load(root).unwrap();
+        for _ in 0..2 {
+            rules.is_ignored(&root.join("a.txt"), false);
+            rules.is_ignored(&root.join("b.txt"), false);
+            rules.is_ignored(&root.join("c.zip"), false);
+            rules.is_ignored(&root.join(IGNORE_FILE), false);
+        }
+        assert_eq!(rules.excluded(), 2);
+    }
+
+    #[test]
+    fn removing_keeps_ignored_files_and_their_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write(&root.join(IGNORE_FILE), "notes.txt\n");
+        write(&root.join("Game/data/notes.txt"), "mine");
+        write(&root.join("Game/data/game.pak"), "");
+        write(&root.join("Game/game.zip"), "");
+        write(&root.join("Other/game.zip"), "");
+        let rules = IgnoreRules::load(root).unwrap();
+
+        assert!(!remove_unignored(&root.join("Game"), &rules).unwrap());
+        assert!(root.join("Game/data/notes.txt").is_file());
+        assert!(!root.join("Game/data/game.pak").exists());
+        assert!(!root.join("Game/game.zip").exists());
+
+        assert!(remove_unignored(&root.join("Other"), &rules).unwrap());
+        assert!(!root.join("Other").exists());
+    }
+
+    #[test]
+    fn an_invalid_pattern_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        write(&dir.path().join(IGNORE_FILE), "[z-a]\n");
+        let error = IgnoreRules::load(dir.path()).unwrap_err();
+        assert!(format!("{:#}", error).contains(IGNORE_FILE), "{:#}", error);
+    }
+}
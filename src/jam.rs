@@ -0,0 +1,175 @@
+use crate::bundle::{decode_entities, meta};
+use crate::claim::is_paid;
+use crate::error::{ItchError, Result};
+use crate::json;
+use crate::models::{Game, User};
+use crate::timestamp::Timestamp;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// A game jam and its entries
+#[derive(Clone, Debug, Serialize)]
+pub struct Jam {
+    pub id: u64,
+    pub title: String,
+    pub entries: Vec<JamEntry>,
+}
+
+/// One submission to a jam
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JamEntry {
+    /// The submission's id, not the game's
+    pub id: u64,
+    pub game: JamGame,
+    /// The submission's page on the jam, relative to itch.io
+    #[serde(default)]
+    pub url: String,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub created_at: Option<Timestamp>,
+}
+
+/// The game an entry submitted, as the entries feed describes it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JamGame {
+    pub id: u64,
+    pub title: String,
+    #[serde(default)]
+    pub url: String,
+    pub short_text: Option<String>,
+    pub cover: Option<String>,
+    /// Only present for games that cost something, e.g. `$5.00`
+    pub price: Option<String>,
+    pub user: JamUser,
+}
+
+/// An entry's author
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JamUser {
+    pub id: u64,
+    pub name: String,
+    #[serde(default)]
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct EntriesResponse {
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    jam_games: Vec<JamEntry>,
+}
+
+impl JamGame {
+    /// Whether the game has to be bought, jam entries mostly being free
+    pub fn is_paid(&self) -> bool {
+        self.price.as_deref().is_some_and(is_paid)
+    }
+}
+
+impl From<JamGame> for Game {
+    fn from(game: JamGame) -> Self {
+        Game {
+            id: game.id,
+            title: game.title,
+            short_text: game.short_text,
+            url: game.url,
+            game_type: "default".to_string(),
+            classification: "game".to_string(),
+            created_at: None,
+            published_at: None,
+            cover_url: game.cover,
+            still_cover_url: None,
+            min_price: None,
+            published: Some(true),
+            downloads_count: None,
+            views_count: None,
+            purchases_count: None,
+            embed: None,
+            has_demo: false,
+            user: User {
+                id: game.user.id,
+                username: game.user.name,
+                display_name: None,
+                url: game.user.url,
+                cover_url: None,
+            },
+        }
+    }
+}
+
+/// Fetch a jam's entries, given the jam's page (`https://itch.io/jam/<name>`) or its numeric id
+pub async fn fetch(http: &Client, jam: &str) -> Result<Jam> {
+    let jam = jam.trim();
+    let (id, title) = match jam.parse::<u64>() {
+        Ok(id) => (id, id.to_string()),
+        Err(_) => find_jam(http, jam).await?,
+    };
+    let url = format!("https://itch.io/jam/{}/entries.json", id);
+    let body = get(http, &url, "Jam entries request").await?;
+    let response: EntriesResponse = json::parse(&url, &body)?;
+    info!(
+        jam_id = id,
+        entries = response.jam_games.len(),
+        "Fetched jam entries"
+    );
+    Ok(Jam {
+        id,
+        title,
+        entries: response.jam_games,
+    })
+}
+
+/// The id and title of the jam on a jam page, from where the page loads its entries
+async fn find_jam(http: &Client, url: &str) -> Result<(u64, String)> {
+    let invalid = || ItchError::Config {
+        message: format!("not an itch.io jam URL: {}", url),
+    };
+    let parsed: reqwest::Url = url.parse().map_err(|_| invalid())?;
+    let is_jam_page = parsed
+        .host_str()
+        .is_some_and(|host| host.eq_ignore_ascii_case("itch.io"))
+        && parsed.path().starts_with("/jam/");
+    if !is_jam_page {
+        return Err(invalid());
+    }
+    let html = get(http, parsed.as_str(), "Jam page request").await?;
+
+    // The page's script names the feed as `/jam/<id>/entries.json`, with its slashes escaped
+    let html_unescaped = html.replace("\\/", "/");
+    let id = html_unescaped
+        .match_indices("/jam/")
+        .find_map(|(at, pattern)| {
+            let rest = &html_unescaped[at + pattern.len()..];
+            let (id, rest) = rest.split_once('/')?;
+            rest.starts_with("entries.json")
+                .then(|| id.parse::<u64>().ok())
+                .flatten()
+        })
+        .ok_or_else(invalid)?;
+    let title = meta(&html, "property", "og:title")
+        .map(|title| decode_entities(title.trim_end_matches(" - itch.io")))
+        .unwrap_or_else(|| id.to_string());
+    Ok((id, title))
+}
+
+/// The body of a page or feed on itch.io
+async fn get(http: &Client, url: &str, context: &'static str) -> Result<String> {
+    let response = http
+        .get(url)
+        .send()
+        .await
+        .map_err(|source| ItchError::Request { context, source })?;
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|source| ItchError::Request { context, source })?;
+    if !status.is_success() {
+        return Err(ItchError::Http {
+            context,
+            status,
+            body: json::shorten(body),
+            saved_to: None,
+        });
+    }
+    Ok(body)
+}
@@ -0,0 +1,311 @@
+use crate::error::ItchError;
+use crate::timestamp::{self, Timestamp};
+use serde::de::{DeserializeOwned, Deserializer};
+use serde::{Deserialize, de};
+use serde_json::Value;
+
+/// How much of the JSON around a parse failure goes in the error
+const SNIPPET_LEN: usize = 200;
+
+/// Starts a `seq_or_map` error that names the failing item, so `parse` can extend the path
+const ITEM_PREFIX: &str = "item at ";
+
+/// Parse an API response, naming the endpoint, the JSON path that failed and what was there
+///
+/// The body is read into a `Value` first, so a mismatch deep inside a listing points at the
+/// offending field instead of a byte offset into the whole payload.
+pub fn parse<T: DeserializeOwned>(endpoint: &str, body: &str) -> Result<T, ItchError> {
+    let value: Value = serde_json::from_str(body).map_err(|e| ItchError::Decode {
+        endpoint: endpoint.to_string(),
+        path: ".".to_string(),
+        message: format!("invalid JSON: {}", e),
+        snippet: shorten(body.to_string()),
+        saved_to: None,
+    })?;
+    serde_path_to_error::deserialize(value.clone()).map_err(|e| {
+        let mut path = e.path().to_string();
+        let mut message = e.into_inner().to_string();
+        // Failures inside a `seq_or_map` collection carry the rest of the path in the message
+        if let Some((item_path, inner)) = message
+            .strip_prefix(ITEM_PREFIX)
+            .and_then(|rest| rest.split_once(": "))
+        {
+            path = format!("{}{}", path, item_path);
+            message = inner.to_string();
+        }
+        ItchError::Decode {
+            endpoint: endpoint.to_string(),
+            snippet: snippet(&value, &path),
+            path,
+            message,
+            saved_to: None,
+        }
+    })
+}
+
+/// The JSON at `path`, or the nearest parent that exists, cut down to a readable length
+fn snippet(value: &Value, path: &str) -> String {
+    let mut current = value;
+    for segment in path.split('.').filter(|segment| *segment != "?") {
+        let (key, indexes) = match segment.find('[') {
+            Some(bracket) => (&segment[..bracket], &segment[bracket..]),
+            None => (segment, ""),
+        };
+        let next = if key.is_empty() {
+            Some(current)
+        } else {
+            current.get(key)
+        };
+        let Some(mut next) = next else {
+            break;
+        };
+        for index in indexes
+            .split(['[', ']'])
+            .filter_map(|index| index.parse::<usize>().ok())
+        {
+            // Collections can come as either shape, see `seq_or_map`
+            let item = match next {
+                Value::Array(items) => items.get(index),
+                Value::Object(items) => items.values().nth(index),
+                _ => None,
+            };
+            match item {
+                Some(item) => next = item,
+                None => break,
+            }
+        }
+        current = next;
+    }
+
+    shorten(current.to_string())
+}
+
+/// `text` cut down to `SNIPPET_LEN` characters
+pub(crate) fn shorten(text: String) -> String {
+    match text.char_indices().nth(SNIPPET_LEN) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+/// Accept a collection as either a JSON array or an object whose values are the items
+///
+/// itch has served some listings both ways, e.g. `owned_keys` as `{"0": {...}, "1": {...}}`.
+pub fn seq_or_map<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let items: Vec<Value> = match Value::deserialize(deserializer)? {
+        Value::Array(items) => items,
+        Value::Object(items) => items.into_iter().map(|(_, item)| item).collect(),
+        Value::Null => Vec::new(),
+        other => {
+            return Err(de::Error::custom(format!(
+                "expected a list or an object of items, got {}",
+                other
+            )));
+        }
+    };
+    items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| {
+            serde_path_to_error::deserialize(item).map_err(|e| {
+                let path = e.path().to_string();
+                let path = if path == "." {
+                    String::new()
+                } else {
+                    format!(".{}", path)
+                };
+                de::Error::custom(format!(
+                    "{}[{}]{}: {}",
+                    ITEM_PREFIX,
+                    index,
+                    path,
+                    e.into_inner()
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Read a list of image URLs, given either as strings or as objects with a `url`
+///
+/// Anything else in the list is skipped rather than failing the game it came with.
+pub fn urls<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let items: Vec<Value> = match Value::deserialize(deserializer)? {
+        Value::Array(items) => items,
+        Value::Object(items) => items.into_iter().map(|(_, item)| item).collect(),
+        _ => Vec::new(),
+    };
+    Ok(items
+        .into_iter()
+        .filter_map(|item| match item {
+            Value::String(url) => Some(url),
+            Value::Object(mut fields) => match fields.remove("url") {
+                Some(Value::String(url)) => Some(url),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect())
+}
+
+/// Accept an object, treating an empty array or `null` as the type's default
+///
+/// itch's PHP backend serializes an empty object as `[]`, e.g. `"platforms": []`.
+pub fn object_or_empty<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + Default,
+{
+    match Value::deserialize(deserializer)? {
+        Value::Null => Ok(T::default()),
+        Value::Array(items) if items.is_empty() => Ok(T::default()),
+        value => T::deserialize(value).map_err(de::Error::custom),
+    }
+}
+
+/// Read a timestamp, treating `null`, an empty string or a format we don't know as missing
+///
+/// A date that can't be read shouldn't fail the whole listing it came in.
+pub fn timestamp<'de, D>(deserializer: D) -> Result<Option<Timestamp>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(text) => timestamp::parse(&text),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OwnedKeysResponse;
+
+    /// A page of the library as the API serves it now
+    const OWNED_KEYS: &str = r#"{
+        "page": 1,
+        "per_page": 50,
+        "owned_keys": [
+            {
+                "id": 11,
+                "game_id": 110,
+                "purchase_id": null,
+                "downloads": 3,
+                "created_at": "2023-04-01 10:20:30",
+                "a_field_from_the_future": { "nested": true },
+                "game": {
+                    "id": 110,
+                    "title": "First",
+                    "url": "https://someone.itch.io/first",
+                    "classification": "game",
+                    "user": { "id": 1, "username": "someone", "display_name": "Some One" }
+                }
+            }
+        ]
+    }"#;
+
+    /// The same page as older responses had it, the keys in an object and empty objects as `[]`
+    const OLD_OWNED_KEYS: &str = r#"{
+        "page": 1,
+        "owned_keys": {
+            "0": {
+                "id": 11,
+                "game_id": 110,
+                "game": {
+                    "id": 110,
+                    "title": "First",
+                    "user": { "id": 1, "username": "someone" },
+                    "embed": []
+                }
+            }
+        }
+    }"#;
+
+    fn decode_error(error: ItchError) -> (String, String, String, String) {
+        match error {
+            ItchError::Decode {
+                endpoint,
+                path,
+                message,
+                snippet,
+                ..
+            } => (endpoint, path, message, snippet),
+            error => panic!("expected a decode error, got {:?}", error),
+        }
+    }
+
+    #[test]
+    fn current_and_older_shapes_read_the_same() {
+        for body in [OWNED_KEYS, OLD_OWNED_KEYS] {
+            let page: OwnedKeysResponse = parse("/profile/owned-keys", body).unwrap();
+            assert_eq!(page.owned_keys.len(), 1);
+            let key = &page.owned_keys[0];
+            assert_eq!((key.id, key.game.id), (11, 110));
+            assert_eq!(key.game.title, "First");
+        }
+        // Fields an older response leaves out fall back to their defaults
+        let page: OwnedKeysResponse = parse("/profile/owned-keys", OLD_OWNED_KEYS).unwrap();
+        assert_eq!(page.per_page, 0);
+        assert_eq!(page.owned_keys[0].downloads, 0);
+    }
+
+    #[test]
+    fn errors_name_the_endpoint_and_the_failing_field() {
+        let body = OLD_OWNED_KEYS.replace(r#""title": "First""#, r#""title": ["First"]"#);
+        let error = parse::<OwnedKeysResponse>("/profile/owned-keys", &body).unwrap_err();
+        let (endpoint, path, message, snippet) = decode_error(error);
+        assert_eq!(endpoint, "/profile/owned-keys");
+        assert_eq!(path, "owned_keys[0].game.title");
+        assert!(message.starts_with("invalid type: sequence"), "{}", message);
+        assert_eq!(snippet, r#"["First"]"#);
+    }
+
+    #[test]
+    fn a_missing_field_points_at_the_object_missing_it() {
+        let body = OWNED_KEYS.replace(r#""game_id": 110,"#, "");
+        let error = parse::<OwnedKeysResponse>("/profile/owned-keys", &body).unwrap_err();
+        let (_, path, message, snippet) = decode_error(error);
+        assert_eq!(path, "owned_keys[0]");
+        assert!(message.contains("game_id"), "{}", message);
+        assert!(
+            snippet.starts_with(r#"{"a_field_from_the_future""#),
+            "{}",
+            snippet
+        );
+    }
+
+    #[test]
+    fn invalid_json_is_reported_with_the_start_of_the_body() {
+        let body = format!("<html>{}</html>", "x".repeat(500));
+        let error = parse::<OwnedKeysResponse>("/profile/owned-keys", &body).unwrap_err();
+        let (_, path, message, snippet) = decode_error(error);
+        assert_eq!(path, ".");
+        assert!(message.starts_with("invalid JSON"), "{}", message);
+        assert_eq!(snippet.chars().count(), SNIPPET_LEN + "...".len());
+        assert!(snippet.starts_with("<html>xxx"));
+    }
+
+    #[test]
+    fn collections_can_be_null_or_the_wrong_shape() {
+        let page: OwnedKeysResponse = parse("/", r#"{"owned_keys": null}"#).unwrap();
+        assert!(page.owned_keys.is_empty());
+        let error = parse::<OwnedKeysResponse>("/", r#"{"owned_keys": 5}"#).unwrap_err();
+        let (_, path, message, _) = decode_error(error);
+        assert_eq!(path, "owned_keys");
+        assert!(message.contains("expected a list"), "{}", message);
+    }
+
+    #[test]
+    fn unreadable_timestamps_are_left_out() {
+        let body = OWNED_KEYS.replace("2023-04-01 10:20:30", "sometime");
+        let page: OwnedKeysResponse = parse("/", &body).unwrap();
+        assert!(page.owned_keys[0].created_at.is_none());
+    }
+}
@@ -0,0 +1,374 @@
+use crate::error::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the link (or pointer file) that tracks the most recent download for a game
+const CURRENT_LINK: &str = "current";
+
+/// Name of the pointer file written when the filesystem doesn't support links
+const CURRENT_POINTER: &str = "current.txt";
+
+/// Name of the directory inside the output where we keep our own bookkeeping
+pub const STATE_DIR: &str = ".itch-downloader";
+
+/// Directory inside the state directory with one folder of state per profile
+pub(crate) const PROFILES_DIR: &str = "profiles";
+
+/// The placeholders a directory template can use
+const DIR_PLACEHOLDERS: [&str; 4] = ["{title}", "{slug}", "{author}", "{id}"];
+
+/// Which profile's state a mirror is kept with and how its game directories are named
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Layout {
+    /// The config profile keeping its state apart from others' sharing the output directory
+    pub profile: Option<String>,
+    /// Name game directories after this instead of the title, e.g. `{slug}`
+    ///
+    /// `{title}` is the title, `{slug}` the game's part of [`Game::slug`](crate::Game::slug) (the
+    /// id for pages on a custom domain), `{author}` the author's part of it (their username
+    /// otherwise) and `{id}` the game's id. Check it first with [`check_dir_template`].
+    pub dir_template: Option<String>,
+}
+
+impl Layout {
+    /// Directory holding the downloader's own state for the mirror at `output_path`
+    ///
+    /// Each profile gets its own, so two accounts' manifests and histories never mix.
+    pub fn state_dir(&self, output_path: &Path) -> PathBuf {
+        match &self.profile {
+            Some(name) => root_state_dir(output_path).join(PROFILES_DIR).join(name),
+            None => root_state_dir(output_path),
+        }
+    }
+
+    /// Directory name used for a game, from the directory template or else its title
+    pub fn game_dir(&self, game: &crate::Game) -> String {
+        let Some(template) = &self.dir_template else {
+            return game_dir_name(&game.title, game.id);
+        };
+        let slug = game.slug();
+        let (author, game_slug) = match slug.as_deref().and_then(|slug| slug.split_once('/')) {
+            Some((author, game_slug)) => (author.to_string(), game_slug.to_string()),
+            None => (
+                game.user
+                    .slug()
+                    .unwrap_or_else(|| game.user.username.clone()),
+                game.id.to_string(),
+            ),
+        };
+        let name = template
+            .replace("{title}", &game.title)
+            .replace("{slug}", &game_slug)
+            .replace("{author}", &author)
+            .replace("{id}", &game.id.to_string());
+        game_dir_name(&name, game.id)
+    }
+}
+
+/// The state directory shared by every profile, for things that guard the files themselves
+pub fn root_state_dir(output_path: &Path) -> PathBuf {
+    output_path.join(STATE_DIR)
+}
+
+/// Directory name used for a game, derived from its title
+pub fn game_dir_name(title: &str, game_id: u64) -> String {
+    crate::sanitize_filename(&title.replace(['/', '\\'], "_"))
+        .unwrap_or_else(|| game_id.to_string())
+}
+
+/// Check a game directory template only uses known placeholders and names a single directory
+pub fn check_dir_template(template: &str) -> Result<()> {
+    if template.contains(['/', '\\']) {
+        bail!("a directory template can't contain path separators");
+    }
+    let mut rest = template.to_string();
+    for placeholder in DIR_PLACEHOLDERS {
+        rest = rest.replace(placeholder, "");
+    }
+    if let Some(start) = rest.find('{') {
+        let unknown = rest[start..]
+            .split_inclusive('}')
+            .next()
+            .unwrap_or_default();
+        bail!(
+            "unknown placeholder {} in the directory template, use {}",
+            unknown,
+            DIR_PLACEHOLDERS.join(", ")
+        );
+    }
+    if !DIR_PLACEHOLDERS
+        .iter()
+        .any(|placeholder| *placeholder != "{author}" && template.contains(placeholder))
+    {
+        bail!("a directory template needs {{title}}, {{slug}} or {{id}} to tell games apart");
+    }
+    Ok(())
+}
+
+/// The game directories already in an output directory, by the game their sidecar names
+///
+/// A game renamed since it was downloaded would get a new directory from
+/// [`Layout::game_dir`], this finds the one its files are already in, by the id or slug recorded
+/// in its sidecar.
+#[derive(Clone, Debug, Default)]
+pub struct GameDirs {
+    by_id: HashMap<u64, PathBuf>,
+    by_slug: HashMap<String, PathBuf>,
+    /// How directories are named for games that don't have one yet
+    layout: Layout,
+}
+
+impl GameDirs {
+    /// Read the sidecars of the directories in `root`, and one level further down for
+    /// `--group-by-bundle`, new directories being named as `layout` says
+    pub fn scan(root: &Path, group_by_bundle: bool, layout: &Layout) -> Self {
+        let mut dirs = Self {
+            layout: layout.clone(),
+            ..Self::default()
+        };
+        let roots = if group_by_bundle {
+            subdirs(root).collect()
+        } else {
+            vec![root.to_path_buf()]
+        };
+        for dir in roots.iter().flat_map(|root| subdirs(root)) {
+            let metadata = std::fs::read_to_string(dir.join(crate::export::METADATA_FILE))
+                .ok()
+                .and_then(|contents| {
+                    serde_json::from_str::<crate::export::GameMetadata>(&contents).ok()
+                });
+            let Some(metadata) = metadata else {
+                continue;
+            };
+            if let Some(slug) = metadata.slug {
+                dirs.by_slug.entry(slug).or_insert_with(|| dir.clone());
+            }
+            dirs.by_id.entry(metadata.game_id).or_insert(dir);
+        }
+        dirs
+    }
+
+    /// The directory for `game` in `root`: the one named after it when that exists, else one
+    /// already holding its files, else the one named after it
+    pub fn dir_for(&self, root: &Path, game: &crate::Game) -> PathBuf {
+        let named = root.join(self.layout.game_dir(game));
+        if named.exists() {
+            return named;
+        }
+        self.find(game)
+            .filter(|dir| dir.parent() == Some(root))
+            .map_or(named, Path::to_path_buf)
+    }
+
+    /// How directories are named for games that don't have one yet
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// The directory whose sidecar names `game`, by id or else slug
+    pub fn find(&self, game: &crate::Game) -> Option<&Path> {
+        self.by_id
+            .get(&game.id)
+            .or_else(|| self.by_slug.get(&game.slug()?))
+            .map(PathBuf::as_path)
+    }
+}
+
+/// The directories directly in `dir`, skipping our own state
+fn subdirs(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+        .filter(|entry| entry.file_name() != STATE_DIR)
+        .map(|entry| entry.path())
+}
+
+/// Directory name for the bundle a key came from, used by `--group-by-bundle`
+pub fn origin_dir_name(key: &crate::OwnedKey) -> String {
+    crate::sanitize_filename(&key.origin().replace(['/', '\\'], "_")).unwrap_or_else(|| {
+        key.bundle
+            .as_ref()
+            .map_or(0, |bundle| bundle.id)
+            .to_string()
+    })
+}
+
+/// Extensions telling what a file is when its upload type doesn't, for [`Category::of`]
+const GAME_EXTENSIONS: &[&str] = &[
+    "exe", "msi", "apk", "dmg", "appimage", "x86_64", "deb", "rpm", "jar", "swf", "love",
+];
+const OST_EXTENSIONS: &[&str] = &["mp3", "ogg", "flac", "wav", "m4a", "opus", "aac"];
+
+/// The folder of a game's directory an upload is sorted into with `--organize`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Game,
+    Ost,
+    Extras,
+}
+
+impl Category {
+    pub const ALL: [Category; 3] = [Category::Game, Category::Ost, Category::Extras];
+
+    /// Name of the folder inside the game's directory
+    pub fn dir_name(self) -> &'static str {
+        match self {
+            Category::Game => "game",
+            Category::Ost => "ost",
+            Category::Extras => "extras",
+        }
+    }
+
+    /// Whether a directory name is one of the category folders
+    pub fn is_dir_name(name: &str) -> bool {
+        Category::ALL
+            .iter()
+            .any(|category| category.dir_name() == name)
+    }
+
+    /// The folder an upload goes in, by its type and, for uploads typed `other` or not at all, its
+    /// extension; anything unrecognised is an extra
+    pub fn of(upload_type: &str, filename: &str) -> Self {
+        match upload_type {
+            "default" | "flash" | "unity" | "java" | "html" => Category::Game,
+            "soundtrack" => Category::Ost,
+            "" | "other" => {
+                let extension = Path::new(filename)
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().to_lowercase())
+                    .unwrap_or_default();
+                if GAME_EXTENSIONS.contains(&extension.as_str()) {
+                    Category::Game
+                } else if OST_EXTENSIONS.contains(&extension.as_str()) {
+                    Category::Ost
+                } else {
+                    Category::Extras
+                }
+            }
+            _ => Category::Extras,
+        }
+    }
+}
+
+/// Name for an older version of `name` kept alongside the new one, e.g. `Game (build 12).zip`
+pub fn versioned_name(name: &str, version: &str) -> String {
+    let stem = crate::extract::strip_archive_extension(name);
+    let extension = &name[stem.len()..];
+    crate::sanitize_filename(&format!("{} ({}){}", stem, version, extension))
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// Remove whatever currently sits at `path`, be it a link, file or directory junction
+fn remove_link(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir(path),
+        Ok(_) => std::fs::remove_file(path).or_else(|_| std::fs::remove_dir(path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Create a link at `link` pointing to `target`, relative to the link's directory unless absolute
+#[cfg(unix)]
+fn create_link(_game_dir: &Path, target: &Path, link: &Path) -> std::io::Result<()> {
+    // A relative target keeps the link valid if the whole mirror is moved
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// Create a link at `link` pointing to `target`, relative to the link's directory unless absolute
+#[cfg(windows)]
+fn create_link(game_dir: &Path, target: &Path, link: &Path) -> std::io::Result<()> {
+    let target = game_dir.join(target);
+    if target.is_dir() {
+        // Junctions don't need the symlink privilege that regular accounts lack
+        junction::create(std::path::absolute(&target)?, link)
+    } else {
+        std::os::windows::fs::symlink_file(&target, link)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create_link(_game_dir: &Path, _target: &Path, _link: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Create a link at `link` pointing to the absolute path `target`, e.g. a folder outside the mirror
+pub fn link_to(target: &Path, link: &Path) -> Result<()> {
+    create_link(Path::new(""), target, link)
+        .with_context(|| format!("Failed to link {} to {}", link.display(), target.display()))
+}
+
+/// Point the game's `current` link at `target`, a file or directory name inside `game_dir`
+///
+/// The link is built under a temporary name and renamed over the old one so readers never see it
+/// missing. Filesystems without link support get a `current.txt` file naming the target instead.
+pub fn update_current_link(game_dir: &Path, target: &str) -> Result<()> {
+    let link = game_dir.join(CURRENT_LINK);
+    let temp_link = game_dir.join(format!(".{}-{}.tmp", CURRENT_LINK, std::process::id()));
+    let _ = remove_link(&temp_link);
+
+    let linked = create_link(game_dir, Path::new(target), &temp_link).and_then(|()| {
+        // Windows refuses to rename over an existing link, so it has to go first there
+        if cfg!(windows) {
+            remove_link(&link)?;
+        }
+        std::fs::rename(&temp_link, &link)
+    });
+
+    match linked {
+        Ok(()) => {
+            // A stale pointer file from an earlier fallback would only confuse things
+            let _ = std::fs::remove_file(game_dir.join(CURRENT_POINTER));
+            Ok(())
+        }
+        Err(_) => {
+            let _ = remove_link(&temp_link);
+            std::fs::write(game_dir.join(CURRENT_POINTER), format!("{}\n", target))
+                .context("Failed to write current pointer file")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn game() -> crate::Game {
+        serde_json::from_value(json!({
+            "id": 10,
+            "title": "Some Game",
+            "url": "https://someone.itch.io/some-game",
+            "user": { "id": 2, "username": "someone" }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn profiles_keep_their_state_apart() {
+        let output = Path::new("mirror");
+        let studio = Layout {
+            profile: Some("studio".to_string()),
+            ..Layout::default()
+        };
+        assert_eq!(Layout::default().state_dir(output), output.join(STATE_DIR));
+        assert_eq!(
+            studio.state_dir(output),
+            output.join(STATE_DIR).join(PROFILES_DIR).join("studio")
+        );
+    }
+
+    #[test]
+    fn game_dirs_follow_each_layouts_template() {
+        let by_slug = Layout {
+            dir_template: Some("{author} - {slug} ({id})".to_string()),
+            ..Layout::default()
+        };
+        assert_eq!(Layout::default().game_dir(&game()), "Some Game");
+        assert_eq!(by_slug.game_dir(&game()), "someone - some-game (10)");
+    }
+}
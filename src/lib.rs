@@ -0,0 +1,369 @@
+//! Talk to the itch.io API: list the games in an account's library, pick their uploads and
+//! download them, with the bookkeeping the `itch-downloader` CLI keeps alongside a mirror.
+//!
+//! [`ItchClient`] is the entry point. Nothing in the library prints, progress is reported through
+//! callbacks and the values functions return, and log events go through `tracing`.
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod budget;
+pub mod bundle;
+pub mod cache;
+pub mod check;
+pub mod checksums;
+pub mod claim;
+mod client;
+pub mod dedupe;
+pub mod diff;
+pub mod error;
+pub mod export;
+pub mod extract;
+pub mod failures;
+pub mod filter_file;
+pub mod filters;
+pub mod fixtures;
+pub mod history;
+pub mod http_dump;
+pub mod itch_app;
+pub mod itchignore;
+pub mod jam;
+pub mod json;
+pub mod layout;
+pub mod lock;
+pub mod manifest;
+// A run extracts what it downloads
+#[cfg(feature = "extract")]
+pub mod mirror;
+mod models;
+pub mod page;
+pub mod plan;
+pub mod preflight;
+pub mod proxy;
+pub mod prune;
+pub mod receipt;
+pub mod report;
+pub mod runs;
+pub mod schedule;
+pub mod seed;
+pub mod selection;
+pub mod since;
+pub mod skips;
+pub mod staging;
+// Exports are tarballs, which come with the extraction dependencies
+#[cfg(feature = "extract")]
+pub mod state;
+pub mod status;
+pub mod timestamp;
+
+pub use client::{
+    ApiUsage, ApiVersion, CLOCK_SKEW_THRESHOLD, CdnRewrite, DEFAULT_API_BASE_URL,
+    DEFAULT_LEGACY_API_BASE_URL, DEFAULT_PAGE_SIZE, DEFAULT_USER_AGENT, DownloadProgress,
+    DownloadedFile, HostPolicy, ItchClient, ItchClientBuilder, KeyLookup, PartialLibrary,
+    PurchaseFilter, REQUEST_WINDOW, RetryPolicy, SaveOptions, host_matches,
+};
+pub use error::ItchError;
+pub use filters::KeyFilter;
+pub use models::{
+    Build, BuildFile, BundleOrigin, Collection, CollectionGame, CollectionGamesResponse,
+    CredentialsInfo, DIRECT_ORIGIN, DownloadKey, Embed, Game, GameDetails, OwnedKey,
+    OwnedKeysResponse, Platforms, Purchase, PurchasesResponse, RatingSummary, Review, Reviews,
+    Sale, Upload, User,
+};
+pub use selection::{SelectionPolicy, UploadRule, UploadSet, choose_upload, choose_uploads};
+
+use tracing::info;
+
+/// Reduce a server-provided filename to a single safe path component
+///
+/// Returns `None` when nothing usable is left after sanitization.
+pub fn sanitize_filename(name: &str) -> Option<String> {
+    // Only the final component counts, anything before a separator is a path we don't want
+    let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    let sanitized: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    // Windows silently drops trailing dots and spaces, and leading spaces are never intended
+    let sanitized = sanitized.trim_start().trim_end_matches(['.', ' ']);
+
+    match sanitized {
+        "" | "." | ".." => None,
+        sanitized if is_windows_device(sanitized) => {
+            Some(staging::shorten_component(&format!("_{}", sanitized)))
+        }
+        sanitized => Some(staging::shorten_component(sanitized)),
+    }
+}
+
+/// Whether Windows takes `name` for a device, like `CON` or `com1.txt`, whatever the case and
+/// extension, so a file can't be created under it
+fn is_windows_device(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    let stem = stem.to_ascii_uppercase();
+    match stem.as_bytes() {
+        [b'C', b'O', b'M', b'1'..=b'9'] | [b'L', b'P', b'T', b'1'..=b'9'] => true,
+        _ => ["CON", "PRN", "AUX", "NUL"].contains(&stem.as_str()),
+    }
+}
+
+/// The name an upload is saved under, its listed filename made safe to use as a path
+///
+/// Names with nothing usable left, like `..` or an empty one, fall back to one made from the
+/// upload's id.
+pub fn upload_filename(filename: &str, upload_id: u64) -> String {
+    sanitize_filename(filename).unwrap_or_else(|| format!("upload-{}.bin", upload_id))
+}
+
+/// Give each of a game's uploads a filename of its own that's safe to use as a path
+///
+/// Unusable names fall back to the upload's display name, then to `upload-<id>.bin`. Names shared
+/// by several uploads, ignoring case for the file systems that do, get the upload's id added,
+/// e.g. `soundtrack (123).zip`, so one download doesn't overwrite another.
+pub fn name_uploads(uploads: &mut [Upload]) {
+    for upload in uploads.iter_mut() {
+        let filename = match sanitize_filename(&upload.filename) {
+            Some(filename) => filename,
+            None => {
+                let filename = upload
+                    .display_name
+                    .as_deref()
+                    .and_then(sanitize_filename)
+                    .unwrap_or_else(|| format!("upload-{}.bin", upload.id));
+                info!(upload_id = upload.id, listed = %upload.filename, %filename, "Named upload without a usable filename");
+                filename
+            }
+        };
+        upload.filename = filename;
+    }
+
+    let mut counts = std::collections::HashMap::new();
+    for upload in uploads.iter() {
+        *counts.entry(upload.filename.to_lowercase()).or_insert(0) += 1;
+    }
+    for upload in uploads.iter_mut() {
+        if counts[&upload.filename.to_lowercase()] > 1 {
+            let filename = layout::versioned_name(&upload.filename, &upload.id.to_string());
+            info!(upload_id = upload.id, listed = %upload.filename, %filename, "Renamed upload sharing its filename");
+            upload.filename = filename;
+        }
+    }
+}
+
+/// Extract the filename from a `Content-Disposition` header, preferring the RFC 5987 `filename*` form
+pub fn content_disposition_filename(header: &str) -> Option<String> {
+    let mut filename = None;
+    let mut extended_filename = None;
+
+    for parameter in header.split(';').skip(1) {
+        let Some((key, value)) = parameter.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                // charset'language'percent-encoded-value
+                let mut parts = value.splitn(3, '\'');
+                let (Some(charset), Some(_language), Some(encoded)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    continue;
+                };
+                let bytes: Vec<u8> = percent_encoding::percent_decode_str(encoded).collect();
+                extended_filename = if charset.eq_ignore_ascii_case("utf-8") {
+                    String::from_utf8(bytes).ok()
+                } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+                    Some(bytes.into_iter().map(char::from).collect())
+                } else {
+                    None
+                };
+            }
+            "filename" => {
+                let unquoted = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .map(|value| value.replace("\\\"", "\""))
+                    .unwrap_or_else(|| value.to_string());
+                filename = Some(unquoted);
+            }
+            _ => {}
+        }
+    }
+
+    extended_filename.or(filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostile_filenames_are_cut_down_to_one_component() {
+        let cases = [
+            ("../../etc/passwd", Some("passwd")),
+            ("/absolute/game.zip", Some("game.zip")),
+            ("C:\\Windows\\game.exe", Some("game.exe")),
+            ("dir/sub\\game.zip", Some("game.zip")),
+            ("C:game.zip", Some("C_game.zip")),
+            ("  game.zip. . ", Some("game.zip")),
+            ("what?<is>*this|\".zip", Some("what__is__this__.zip")),
+            ("game\u{0}\n.zip", Some("game.zip")),
+            ("..", None),
+            ("../", None),
+            ("...", None),
+            ("   ", None),
+            ("", None),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(sanitize_filename(name).as_deref(), expected, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn windows_device_names_are_prefixed() {
+        let cases = [
+            ("CON", "_CON"),
+            ("con.txt", "_con.txt"),
+            ("Aux.tar.gz", "_Aux.tar.gz"),
+            ("nul .zip", "_nul .zip"),
+            ("COM1", "_COM1"),
+            ("lpt9.exe", "_lpt9.exe"),
+            ("PRN.", "_PRN"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(
+                sanitize_filename(name).as_deref(),
+                Some(expected),
+                "{:?}",
+                name
+            );
+        }
+        // Only the whole stem is a device
+        for name in [
+            "CONSOLE.txt",
+            "COM0",
+            "COM10",
+            "LPT",
+            "icon.png",
+            "my-con.zip",
+        ] {
+            assert_eq!(sanitize_filename(name).as_deref(), Some(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn long_filenames_keep_their_extension() {
+        let name = format!("{}.zip", "a".repeat(400));
+        let sanitized = sanitize_filename(&name).unwrap();
+        assert!(sanitized.len() <= 255);
+        assert!(sanitized.ends_with(".zip"));
+        assert_eq!(sanitize_filename(&name), Some(sanitized));
+    }
+
+    #[test]
+    fn content_disposition_prefers_the_extended_filename() {
+        let header = r#"attachment; filename="fallback.zip"; filename*=UTF-8''%E3%82%B2%E3%83%BC%E3%83%A0.zip"#;
+        assert_eq!(
+            content_disposition_filename(header).as_deref(),
+            Some("ゲーム.zip")
+        );
+        assert_eq!(
+            content_disposition_filename("attachment; filename*=iso-8859-1'en'caf%E9.zip")
+                .as_deref(),
+            Some("café.zip")
+        );
+        assert_eq!(
+            content_disposition_filename(r#"attachment; filename="say \"hi\".zip""#).as_deref(),
+            Some(r#"say "hi".zip"#)
+        );
+        assert_eq!(
+            content_disposition_filename("attachment; filename=plain.zip").as_deref(),
+            Some("plain.zip")
+        );
+        assert_eq!(content_disposition_filename("inline"), None);
+    }
+
+    #[test]
+    fn content_disposition_names_are_sanitized_before_use() {
+        let header = r#"attachment; filename="../../.bashrc""#;
+        let name = content_disposition_filename(header).unwrap();
+        assert_eq!(sanitize_filename(&name).as_deref(), Some(".bashrc"));
+        let header = "attachment; filename*=UTF-8''..%2F..%2Fgame.zip";
+        let name = content_disposition_filename(header).unwrap();
+        assert_eq!(sanitize_filename(&name).as_deref(), Some("game.zip"));
+    }
+
+    fn upload(id: u64, filename: &str, display_name: Option<&str>) -> Upload {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "game_id": 1,
+            "filename": filename,
+            "display_name": display_name,
+            "size": 0
+        }))
+        .unwrap()
+    }
+
+    fn names(uploads: &[Upload]) -> Vec<&str> {
+        uploads
+            .iter()
+            .map(|upload| upload.filename.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn shared_filenames_get_the_upload_id() {
+        let mut uploads = [
+            upload(11, "soundtrack.zip", None),
+            upload(12, "game.tar.gz", None),
+            upload(13, "Soundtrack.ZIP", None),
+            upload(14, "game.tar.gz", None),
+        ];
+        name_uploads(&mut uploads);
+        assert_eq!(
+            names(&uploads),
+            [
+                "soundtrack (11).zip",
+                "game (12).tar.gz",
+                "Soundtrack (13).ZIP",
+                "game (14).tar.gz"
+            ]
+        );
+    }
+
+    #[test]
+    fn unusable_filenames_fall_back_to_the_display_name_or_id() {
+        let mut uploads = [
+            upload(11, "", Some("Windows Build")),
+            upload(12, "..", None),
+            upload(13, "", Some("..")),
+            upload(14, "game.zip", Some("ignored")),
+        ];
+        name_uploads(&mut uploads);
+        assert_eq!(
+            names(&uploads),
+            [
+                "Windows Build",
+                "upload-12.bin",
+                "upload-13.bin",
+                "game.zip"
+            ]
+        );
+        assert_eq!(upload_filename("", 12), "upload-12.bin");
+        assert_eq!(upload_filename("../game.zip", 12), "game.zip");
+    }
+
+    #[test]
+    fn fallback_names_that_collide_are_told_apart_too() {
+        let mut uploads = [
+            upload(11, "", Some("Build")),
+            upload(12, "/", Some("Build")),
+        ];
+        name_uploads(&mut uploads);
+        assert_eq!(names(&uploads), ["Build (11)", "Build (12)"]);
+    }
+}
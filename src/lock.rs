@@ -0,0 +1,335 @@
+use crate::error::{Context, ItchError, Result};
+use crate::timestamp::{self, Timestamp};
+use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Name of the lock file inside the state directory
+pub(crate) const LOCK_FILE: &str = "lock";
+
+/// File held locked by a run taking over a stale lock, next to the lock itself
+const TAKEOVER_FILE: &str = "lock.takeover";
+
+/// How long a lock file that can't be read is left alone before it's treated as stale
+///
+/// A run writes its lock straight after creating it, so one still unreadable after this long was
+/// left half written by a run that died, not one that's starting up.
+const UNREADABLE_GRACE: Duration = Duration::from_secs(60);
+
+/// Who holds the lock, written into the lock file
+#[derive(Debug, Serialize, Deserialize)]
+struct LockInfo {
+    pid: u32,
+    /// Seconds since the Unix epoch
+    started_at: u64,
+}
+
+/// Exclusive hold on an output directory, released when dropped
+#[derive(Debug)]
+pub struct OutputLock {
+    path: PathBuf,
+}
+
+/// Read who holds the lock at `path`, `None` when there's no lock or it can't be made sense of
+fn holder(path: &Path) -> Option<LockInfo> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+}
+
+/// How the lock on an output directory currently stands
+#[derive(Debug, PartialEq, Eq)]
+pub enum LockState {
+    Free,
+    /// Held by a process that's still running, or one we can't tell yet
+    Held {
+        pid: Option<u32>,
+        since: Option<Timestamp>,
+    },
+    /// Left behind by a process that's gone, or unreadable for longer than a run takes to write it
+    Stale(PathBuf),
+}
+
+/// Look at the lock file at `path`
+fn lock_state(path: &Path) -> LockState {
+    let modified = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.modified().ok(),
+        Err(e) if e.kind() == ErrorKind::NotFound => return LockState::Free,
+        // Something's there even if we can't look at it, so leave it be
+        Err(_) => None,
+    };
+    match holder(path) {
+        Some(info) if crate::staging::process_is_alive(info.pid) => LockState::Held {
+            pid: Some(info.pid),
+            since: Some(timestamp::from_unix(info.started_at as i64)),
+        },
+        Some(_) => LockState::Stale(path.to_path_buf()),
+        None => {
+            let age = modified.and_then(|modified| SystemTime::now().duration_since(modified).ok());
+            if age.is_some_and(|age| age >= UNREADABLE_GRACE) {
+                LockState::Stale(path.to_path_buf())
+            } else {
+                // Possibly a run that's still writing it
+                LockState::Held {
+                    pid: None,
+                    since: modified.map(Timestamp::from),
+                }
+            }
+        }
+    }
+}
+
+/// Replace the stale lock at `path` with one holding `contents`, `false` if it's no longer stale
+///
+/// Runs that found the same stale lock take turns holding [`TAKEOVER_FILE`], an OS lock that's
+/// released when its process dies, and each looks at the lock again once it's its turn, so only
+/// the first replaces it. The new lock is renamed over the old one, never leaving a moment with
+/// no lock for a run that didn't see the stale one to create its own in.
+fn take_over(path: &Path, contents: &str) -> Result<bool> {
+    let takeover = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.with_file_name(TAKEOVER_FILE))
+        .context("Failed to open lock takeover file")?;
+    takeover
+        .lock()
+        .context("Failed to wait for another run taking over the lock")?;
+    if !matches!(lock_state(path), LockState::Stale(_)) {
+        return Ok(false);
+    }
+    let temp = path.with_file_name(format!("{}.{}.tmp", LOCK_FILE, std::process::id()));
+    std::fs::write(&temp, contents).context("Failed to write lock file")?;
+    std::fs::rename(&temp, path).context("Failed to replace stale lock")?;
+    Ok(true)
+}
+
+/// Look at the lock on `output_path` without taking it
+pub fn inspect(output_path: &Path) -> LockState {
+    lock_state(&crate::layout::root_state_dir(output_path).join(LOCK_FILE))
+}
+
+impl OutputLock {
+    /// Take the lock on `output_path`, refusing if another run holds it
+    ///
+    /// Locks whose process is no longer running, or that have been unreadable for a while, are
+    /// treated as stale and taken over. Whether a process is running can only be told on Unix, see
+    /// [`crate::staging::process_is_alive`], so elsewhere a readable lock is never stale.
+    pub fn acquire(output_path: &Path) -> Result<Self> {
+        let state_dir = crate::layout::root_state_dir(output_path);
+        std::fs::create_dir_all(&state_dir).context("Failed to create state directory")?;
+        let path = state_dir.join(LOCK_FILE);
+        let info = LockInfo {
+            pid: std::process::id(),
+            started_at: crate::manifest::now(),
+        };
+        let contents = serde_json::to_string(&info).context("Failed to serialize lock")?;
+
+        // One retry, after clearing a stale lock
+        for _ in 0..2 {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    file.write_all(contents.as_bytes())
+                        .context("Failed to write lock file")?;
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => match lock_state(&path) {
+                    LockState::Held { pid, since } => {
+                        let holder = pid.map_or_else(
+                            || "a run that's still starting".to_string(),
+                            |pid| format!("pid {}", pid),
+                        );
+                        let since = since.map_or_else(String::new, |since| {
+                            format!(
+                                ", held since {} ({})",
+                                timestamp::local(since),
+                                timestamp::relative(since, timestamp::now())
+                            )
+                        });
+                        return Err(ItchError::Locked {
+                            message: format!(
+                                "{} is in use by another itch-downloader run ({}{}). \
+                                 Pass --no-lock if you're sure they won't conflict",
+                                output_path.display(),
+                                holder,
+                                since
+                            ),
+                        });
+                    }
+                    LockState::Stale(_) => {
+                        if take_over(&path, &contents)? {
+                            return Ok(Self { path });
+                        }
+                    }
+                    LockState::Free => {}
+                },
+                Err(e) => return Err(e).context("Failed to create lock file"),
+            }
+        }
+
+        Err(ItchError::Locked {
+            message: format!("Failed to acquire lock on {}", output_path.display()),
+        })
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_path(output: &Path) -> PathBuf {
+        crate::layout::root_state_dir(output).join(LOCK_FILE)
+    }
+
+    fn write_lock(output: &Path, contents: &str) -> PathBuf {
+        let path = lock_path(output);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// The id of a process that has already exited
+    #[cfg(unix)]
+    fn dead_pid() -> u32 {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        pid
+    }
+
+    fn backdate(path: &Path, by: Duration) {
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - by).unwrap();
+    }
+
+    #[test]
+    fn taken_and_released() {
+        let output = tempfile::tempdir().unwrap();
+        assert_eq!(inspect(output.path()), LockState::Free);
+        let lock = OutputLock::acquire(output.path()).unwrap();
+        assert!(matches!(
+            inspect(output.path()),
+            LockState::Held { pid: Some(pid), since: Some(_) } if pid == std::process::id()
+        ));
+        drop(lock);
+        assert_eq!(inspect(output.path()), LockState::Free);
+    }
+
+    #[test]
+    fn a_live_holder_is_refused_and_named() {
+        let output = tempfile::tempdir().unwrap();
+        let _lock = OutputLock::acquire(output.path()).unwrap();
+        let error = OutputLock::acquire(output.path()).unwrap_err().to_string();
+        assert!(
+            error.contains(&format!("pid {}", std::process::id())),
+            "{}",
+            error
+        );
+        // The start time is a date, not seconds since the epoch
+        let started = timestamp::local_date(timestamp::now());
+        assert!(error.contains(&started), "{}", error);
+        assert!(error.contains("--no-lock"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_dead_holder_is_taken_over() {
+        let output = tempfile::tempdir().unwrap();
+        let pid = dead_pid();
+        let path = write_lock(
+            output.path(),
+            &format!(r#"{{"pid":{},"started_at":1700000000}}"#, pid),
+        );
+        assert_eq!(inspect(output.path()), LockState::Stale(path.clone()));
+
+        let _lock = OutputLock::acquire(output.path()).unwrap();
+        let info = holder(&path).unwrap();
+        assert_eq!(info.pid, std::process::id());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_one_run_takes_over_a_stale_lock() {
+        let output = tempfile::tempdir().unwrap();
+        let pid = dead_pid();
+        write_lock(
+            output.path(),
+            &format!(r#"{{"pid":{},"started_at":1700000000}}"#, pid),
+        );
+
+        let runs = 8;
+        let start = std::sync::Barrier::new(runs);
+        let taken = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..runs)
+                .map(|_| {
+                    scope.spawn(|| {
+                        start.wait();
+                        // Held on to until every run has tried
+                        OutputLock::acquire(output.path()).ok()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(taken.iter().flatten().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_second_takeover_leaves_the_first_alone() {
+        let output = tempfile::tempdir().unwrap();
+        let pid = dead_pid();
+        let path = write_lock(
+            output.path(),
+            &format!(r#"{{"pid":{},"started_at":1700000000}}"#, pid),
+        );
+
+        // Two runs that both found it stale, the second getting its turn once the first is done
+        let first = format!(r#"{{"pid":{},"started_at":1}}"#, std::process::id());
+        assert!(take_over(&path, &first).unwrap());
+        assert!(!take_over(&path, r#"{"pid":1,"started_at":2}"#).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), first);
+    }
+
+    #[test]
+    fn a_fresh_unreadable_lock_is_left_alone() {
+        let output = tempfile::tempdir().unwrap();
+        // What a run that's just created its lock and not yet written it leaves
+        let path = write_lock(output.path(), "");
+        assert!(matches!(
+            inspect(output.path()),
+            LockState::Held {
+                pid: None,
+                since: Some(_)
+            }
+        ));
+        let error = OutputLock::acquire(output.path()).unwrap_err().to_string();
+        assert!(error.contains("in use by another"), "{}", error);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+    }
+
+    #[test]
+    fn an_old_unreadable_lock_is_stale() {
+        let output = tempfile::tempdir().unwrap();
+        let path = write_lock(output.path(), r#"{"pid":12"#);
+        backdate(&path, UNREADABLE_GRACE + Duration::from_secs(1));
+        assert_eq!(inspect(output.path()), LockState::Stale(path.clone()));
+
+        let _lock = OutputLock::acquire(output.path()).unwrap();
+        assert_eq!(holder(&path).unwrap().pid, std::process::id());
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::Path;
+use tracing::Level;
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard, TraceStyle};
+use tracing_subscriber::Layer;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Log target of this crate, everything else is kept to warnings
+const TARGET: &str = env!("CARGO_CRATE_NAME");
+
+/// Writes log lines to stderr with the progress bars cleared, so neither overdraws the other
+struct ProgressWriter;
+
+impl Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        crate::ui::suspend(|| std::io::stderr().write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// Set up logging to the terminal at the level `-v` asks for, and to `log_file` in full
+///
+/// Without `-v` nothing is logged to the terminal, the usual output covers it. With `trace_file`
+/// the crate's spans are also written there as a Chrome trace-event timeline, which is only
+/// complete once the returned guard is dropped.
+pub fn init(
+    verbose: u8,
+    log_file: Option<&Path>,
+    trace_file: Option<&Path>,
+) -> Result<Option<FlushGuard>> {
+    let level = match verbose {
+        0 => LevelFilter::OFF,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let terminal = tracing_subscriber::fmt::layer()
+        .with_writer(|| ProgressWriter)
+        .with_target(false)
+        .with_filter(
+            Targets::new()
+                .with_target(TARGET, level)
+                .with_default(level.min(LevelFilter::WARN)),
+        );
+
+    let file = match log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {}", path.display()))?;
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(std::sync::Mutex::new(file))
+                    .with_ansi(false)
+                    .with_filter(
+                        Targets::new()
+                            .with_target(TARGET, Level::TRACE)
+                            .with_default(Level::WARN),
+                    ),
+            )
+        }
+        None => None,
+    };
+
+    // Spans are only recorded when some layer wants them, so without a trace file they cost
+    // nothing beyond what -v and --log-file already ask for
+    let (trace, guard) = match trace_file {
+        Some(path) => {
+            // Checked here since the builder panics on a file it can't create
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("Failed to create trace file {}", path.display()))?;
+            let (layer, guard) = ChromeLayerBuilder::new()
+                .writer(file)
+                .trace_style(TraceStyle::Async)
+                .include_args(true)
+                .build();
+            let layer = layer.with_filter(Targets::new().with_target(TARGET, Level::TRACE));
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(terminal)
+        .with(file)
+        .with(trace)
+        .try_init()
+        .context("Failed to set up logging")?;
+    Ok(guard)
+}
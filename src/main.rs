@@ -1,19 +1,43 @@
-#![allow(dead_code)]
-
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, ValueHint};
 use futures::stream::StreamExt;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use reqwest::Client;
-use serde::Deserialize;
-use std::fs::File as StdFile;
-use std::path::PathBuf;
-use std::time::Duration;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use itch_downloader::dedupe::{self, DedupeMode, HashIndex};
+use itch_downloader::extract::{
+    ArchiveKind, ExtractConflict, ExtractOptions, ZipEncoding, extract_archive,
+};
+use itch_downloader::filter_file::FilterFile;
+use itch_downloader::filters::KeySelection;
+use itch_downloader::history::Outcome;
+use itch_downloader::manifest::{Manifest, UploadRecord};
+use itch_downloader::preflight::Access;
+use itch_downloader::seed::SeedDirs;
+use itch_downloader::{
+    ApiVersion, CdnRewrite, DEFAULT_API_BASE_URL, DownloadProgress, Game, ItchClient,
+    ItchClientBuilder, ItchError, KeyFilter, KeyLookup, OwnedKey, PurchaseFilter, RetryPolicy,
+    SaveOptions, SelectionPolicy, UploadSet, budget, bundle, cache, check, checksums,
+    choose_upload, choose_uploads, claim, diff, export, extract, failures, filters, history,
+    http_dump, itch_app, itchignore, jam, layout, lock, manifest, mirror, page, plan, preflight,
+    proxy, prune, report, runs, schedule, since, staging, state, status, timestamp,
+    upload_filename,
+};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
 use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
-use zip::ZipArchive;
+
+mod config;
+mod credentials;
+mod doctor;
+mod events;
+mod logging;
+mod notify;
+mod progress;
+mod tune;
+mod ui;
 
 /// Truncate a string to a specific visual width, accounting for Unicode characters
 fn truncate_to_width(s: &str, max_width: usize) -> String {
@@ -53,147 +77,202 @@ fn pad_to_width(s: &str, target_width: usize) -> String {
     format!("{}{}", s, " ".repeat(padding_needed))
 }
 
-/// Unzip a file to the specified directory
-async fn unzip_file(zip_path: &PathBuf, extract_to: &PathBuf) -> Result<()> {
-    let zip_path = zip_path.clone();
-    let extract_to = extract_to.clone();
-
-    // Run the unzip operation in a blocking task since zip crate is synchronous
-    tokio::task::spawn_blocking(move || {
-        let file = StdFile::open(&zip_path).context("Failed to open zip file")?;
-        let mut archive = ZipArchive::new(file).context("Failed to read zip archive")?;
-
-        // First, extract to a temporary directory to check for single-folder structure
-        let temp_extract = extract_to.with_extension("temp_extract");
-        std::fs::create_dir_all(&temp_extract)
-            .context("Failed to create temporary extraction directory")?;
-
-        for i in 0..archive.len() {
-            let mut file = archive
-                .by_index(i)
-                .context("Failed to get file from archive")?;
-            let outpath = match file.enclosed_name() {
-                Some(path) => temp_extract.join(path),
-                None => continue,
-            };
-
-            if file.is_dir() {
-                std::fs::create_dir_all(&outpath).context("Failed to create directory")?;
-            } else {
-                if let Some(p) = outpath.parent() {
-                    if !p.exists() {
-                        std::fs::create_dir_all(p).context("Failed to create parent directory")?;
-                    }
-                }
-                let mut outfile =
-                    StdFile::create(&outpath).context("Failed to create output file")?;
-                std::io::copy(&mut file, &mut outfile).context("Failed to extract file")?;
-            }
-        }
-
-        // Check if the temporary directory contains only one subdirectory
-        let entries: Vec<_> = std::fs::read_dir(&temp_extract)
-            .context("Failed to read temporary extraction directory")?
-            .collect::<Result<Vec<_>, _>>()
-            .context("Failed to list directory entries")?;
-
-        let directories: Vec<_> = entries
-            .iter()
-            .filter(|entry| entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false))
-            .collect();
-
-        let files: Vec<_> = entries
-            .iter()
-            .filter(|entry| entry.file_type().map(|ft| ft.is_file()).unwrap_or(false))
-            .collect();
-
-        // If there's exactly one directory and no files at the root, unwrap it
-        if directories.len() == 1 && files.is_empty() {
-            let single_dir = directories[0].path();
-            std::fs::create_dir_all(&extract_to)
-                .context("Failed to create final extraction directory")?;
-
-            // Move contents of the single directory to the target directory
-            let move_entries: Vec<_> = std::fs::read_dir(&single_dir)
-                .context("Failed to read single directory")?
-                .collect::<Result<Vec<_>, _>>()
-                .context("Failed to list single directory entries")?;
+/// Break a string into lines no wider than `max_width`, between words where possible
+///
+/// Words wider than a line, such as titles in scripts without spaces, are split between
+/// characters. Zero-width characters stay with the one before them, so emoji sequences aren't
+/// torn apart at a join.
+fn wrap_to_width(s: &str, max_width: usize) -> Vec<String> {
+    if s.width() <= max_width {
+        return vec![s.to_string()];
+    }
 
-            for entry in move_entries {
-                let source = entry.path();
-                let dest = extract_to.join(entry.file_name());
-                std::fs::rename(&source, &dest)
-                    .context("Failed to move file from single directory")?;
-            }
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in s.split_whitespace() {
+        let joined = if line.is_empty() {
+            word.to_string()
         } else {
-            // Multiple items at root, move everything as-is
-            std::fs::create_dir_all(&extract_to)
-                .context("Failed to create final extraction directory")?;
-
-            for entry in entries {
-                let source = entry.path();
-                let dest = extract_to.join(entry.file_name());
-                std::fs::rename(&source, &dest).context("Failed to move extracted content")?;
-            }
+            format!("{} {}", line, word)
+        };
+        if joined.width() <= max_width {
+            line = joined;
+            continue;
         }
-
-        // Clean up temporary directory
-        std::fs::remove_dir_all(&temp_extract).context("Failed to remove temporary directory")?;
-
-        Ok::<(), anyhow::Error>(())
-    })
-    .await
-    .context("Unzip task failed")??;
-
-    Ok(())
-}
-
-/// Make an HTTP request with retry logic for 429 errors
-async fn make_request_with_retry(
-    client: &Client,
-    url: &str,
-    query_params: &[(&str, u64)],
-    api_key: &str,
-    max_retries: u32,
-) -> Result<reqwest::Response> {
-    let mut attempt = 0;
-
-    loop {
-        let response = client
-            .get(url)
-            .bearer_auth(api_key)
-            .query(query_params)
-            .send()
-            .await
-            .context("Failed to send request to itch.io API")?;
-
-        match response.status() {
-            reqwest::StatusCode::TOO_MANY_REQUESTS => {
-                attempt += 1;
-                if attempt > max_retries {
-                    return Err(anyhow::anyhow!(
-                        "Too many requests (429) - exceeded max retries ({})",
-                        max_retries
-                    ));
-                }
-
-                let retry_delay = Duration::from_millis(1000 + (attempt as u64 * 500)); // 1s, 1.5s, 2s, etc.
-                println!(
-                    "Rate limited (429), retrying in {:?} (attempt {}/{})",
-                    retry_delay, attempt, max_retries
-                );
-                sleep(retry_delay).await;
-                continue;
+        if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+        for ch in word.chars() {
+            let starts_cell = ch.width().unwrap_or(0) > 0;
+            if starts_cell && !line.is_empty() && line.width() + ch.width().unwrap_or(0) > max_width
+            {
+                lines.push(std::mem::take(&mut line));
             }
-            _ => return Ok(response),
+            line.push(ch);
         }
     }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
 }
 
 #[derive(Parser)]
 #[command(name = "itch-downloader")]
 #[command(about = "A CLI tool for interacting with itch.io API")]
 struct Cli {
+    /// Read defaults from this file instead of ~/.config/itch-downloader/config.toml
+    #[arg(long, global = true, value_hint = ValueHint::FilePath)]
+    config: Option<PathBuf>,
+    /// Use the `[profiles.<name>]` section of the config file, `default_profile` when not given
+    ///
+    /// Each profile keeps its own manifest, history and listing cache, so two accounts can share
+    /// an output directory without their records mixing.
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// Only print errors and the final summary
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Print a plain line per event instead of drawing progress bars (automatic without a terminal)
+    #[arg(long, global = true)]
+    no_progress: bool,
+    /// How download bars look: `default`, `compact`, `detailed` or an indicatif template, e.g.
+    /// `{prefix} {percent}% {bytes_per_sec}` (`{prefix}` is the game's title)
+    #[arg(long, global = true, default_value = "default")]
+    progress_template: String,
+    /// Characters download bars are drawn with: filled, any in between, then empty
+    #[arg(long, global = true, default_value = ui::DEFAULT_PROGRESS_CHARS)]
+    progress_chars: String,
+    /// Draw a bar for every file, instead of one per game with bars under it only for the files
+    /// being downloaded
+    #[arg(long, global = true)]
+    flat_progress: bool,
+    /// How listings show dates: `absolute` (e.g. 2024-01-31, in the local time zone) or `relative` (e.g. 3 days ago)
+    #[arg(long, global = true, value_enum, default_value_t = ui::DateStyle::Absolute)]
+    dates: ui::DateStyle,
+    /// Write progress to stdout as newline-delimited JSON events, everything else to stderr
+    ///
+    /// Every line is an object whose `event` field is one of:
+    ///   run_started          games: [{game_id, title}]
+    ///   download_started     game_id, upload_id, filename, total
+    ///   download_progress    upload_id, bytes, total, rate (bytes/s), about once a second
+    ///   download_finished    game_id, title, upload_id, filename, bytes, status, error
+    ///   download_skipped     (same fields as download_finished)
+    ///   download_failed      (same fields as download_finished, status `gone` for deleted games)
+    ///   extraction_started   game_id, filename
+    ///   extraction_finished  game_id, filename, path
+    ///   extraction_failed    game_id, filename, error
+    ///   summary              completed, skipped, failed, gone, key_limit, bytes, requests, throttled
+    /// Fields are only ever added to these events, never renamed or removed.
+    #[arg(long, global = true, verbatim_doc_comment)]
+    porcelain: bool,
+    /// Log what's happening and why, -vv for requests, retries and timings
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Append a full debug log to this file, whatever the -v level
+    #[arg(long, global = true, value_hint = ValueHint::FilePath)]
+    log_file: Option<PathBuf>,
+    /// Write a timeline of where the run spent its time to this file, in the Chrome trace-event
+    /// format that chrome://tracing, Perfetto and speedscope open
+    ///
+    /// Listing, fetching uploads, waiting on the rate limits, downloading, moving into place and
+    /// extracting are each a span, with the game and upload ids, bytes and retries they involved.
+    #[arg(long, global = true, value_hint = ValueHint::FilePath)]
+    trace_file: Option<PathBuf>,
+    /// Write every API request and its full response to numbered files in this directory
+    #[arg(long, global = true, value_hint = ValueHint::DirPath)]
+    debug_http: Option<PathBuf>,
+    /// Save a sanitized copy of every API response to this directory, for attaching to bug reports
+    ///
+    /// Keys, tokens and email addresses are stripped, and the names of games, people and files
+    /// are replaced with short hashes unless --fixtures-keep-names is given.
+    #[arg(long, global = true, value_hint = ValueHint::DirPath)]
+    record_fixtures: Option<PathBuf>,
+    /// Leave names in the responses --record-fixtures saves
+    #[arg(long, global = true, requires = "record_fixtures")]
+    fixtures_keep_names: bool,
+    /// Answer API requests from fixtures saved by --record-fixtures instead of itch.io, for
+    /// reproducing bug reports
+    #[arg(
+        long,
+        global = true,
+        value_hint = ValueHint::DirPath,
+        conflicts_with_all = ["record_fixtures", "offline"]
+    )]
+    replay_fixtures: Option<PathBuf>,
+    /// Send API requests here instead of itch.io, e.g. a mock server or a debugging proxy
+    /// (also ITCH_API_BASE_URL)
+    #[arg(long, global = true, default_value = DEFAULT_API_BASE_URL, value_hint = ValueHint::Url)]
+    api_base_url: reqwest::Url,
+    /// Which itch.io API to use, `auto` trying the legacy server-side one when the current one
+    /// rejects the key
+    #[arg(long, global = true, value_enum, default_value_t = ApiVersion::Auto)]
+    api: ApiVersion,
+    /// User-Agent sent with every request, `itch-downloader/<version> (+<repository>)` by default
+    #[arg(long, global = true)]
+    user_agent: Option<String>,
+    /// Give up on an API request that takes longer than this, e.g. `30s` (downloads aren't limited)
+    #[arg(long, global = true, value_parser = parse_duration)]
+    timeout: Option<Duration>,
+    /// Give up on connecting to a server after this long, e.g. `10s`
+    #[arg(long, global = true, value_parser = parse_duration)]
+    connect_timeout: Option<Duration>,
+    /// Send every request through this proxy, instead of the one from HTTPS_PROXY/ALL_PROXY
+    #[arg(long, global = true, value_hint = ValueHint::Url)]
+    proxy: Option<reqwest::Url>,
+    /// Hosts to reach without the proxy, comma-separated domains (covering subdomains), addresses
+    /// and CIDR ranges, instead of NO_PROXY
+    #[arg(long, global = true, value_name = "LIST")]
+    no_proxy: Option<String>,
+    /// Let a proxy auto-config script pick each host's proxy, read from a file or fetched from a
+    /// URL; takes precedence over --proxy
+    #[arg(long, global = true, value_name = "URL|PATH")]
+    proxy_pac: Option<String>,
+    /// Fetch downloads itch.io redirects to FROM from the host TO instead, keeping the path and
+    /// signature, e.g. a LAN cache; repeatable
+    #[arg(long, global = true, value_name = "FROM=TO")]
+    cdn_rewrite: Vec<CdnRewrite>,
+    /// Don't check the TLS certificates of the hosts --cdn-rewrite sends downloads to, every
+    /// other host is still checked
+    #[arg(long, global = true, requires = "cdn_rewrite")]
+    cdn_insecure: bool,
+    /// How many times a rate limited (429) request is retried before giving up
+    #[arg(long, global = true, default_value = "3")]
+    max_retries: u32,
+    /// Send at most this many requests per second, e.g. `0.5` for one every two seconds
+    #[arg(long, global = true)]
+    rate_limit: Option<f64>,
+    /// Never send more than this many requests in a minute, queueing the rest instead of bursting
+    #[arg(long, global = true)]
+    max_requests_per_minute: Option<usize>,
+    /// List every game's uploads from itch.io instead of reusing the listings stored by earlier runs
+    #[arg(long, global = true)]
+    refresh_uploads: bool,
+    /// Send no requests at all, answering from cached listings however old they are
+    ///
+    /// `ls`, `stats`, `info`, `diff`, `check` and `extract` work from the cache and the output
+    /// directory, `dl` and `sync` refuse to start.
+    #[arg(long, global = true, conflicts_with = "refresh_uploads")]
+    offline: bool,
+    /// Exit with 2 when the library is empty or the filters match nothing, instead of 0
+    #[arg(long, global = true)]
+    strict_empty: bool,
+    /// Name game directories after this instead of the title, from `{title}`, `{slug}` (the
+    /// game's part of its URL), `{author}` and `{id}`, e.g. `{author} - {slug}`
+    ///
+    /// Games already downloaded keep the directory they're in, found by their `metadata.json`.
+    #[arg(long, global = true, value_name = "TEMPLATE", value_parser = parse_dir_template)]
+    dir_template: Option<String>,
+    /// How many library keys to ask for per page, fewer pages meaning fewer requests
+    #[arg(long, global = true, default_value = "100", value_parser = clap::value_parser!(u64).range(1..=1000))]
+    per_page: u64,
+    /// Read the API key from this file (also ITCH_API_KEY_FILE)
+    ///
+    /// The key is looked for in this order: --api-key, --api-key-file or ITCH_API_KEY_FILE,
+    /// the ITCH_API_KEY environment variable, `api_key` or `api_key_file` in the config file,
+    /// the system keyring (see `login`), then the credentials saved by `butler login`.
+    #[arg(long, global = true, value_hint = ValueHint::FilePath)]
+    api_key_file: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -202,7 +281,7 @@ struct Cli {
 enum Commands {
     /// List all your packages available on itch.io
     Ls {
-        /// Your itch.io API key (can also be set via ITCH_API_KEY environment variable)
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
         #[arg(short, long)]
         api_key: Option<String>,
         /// Filter by author username or display name
@@ -211,10 +290,131 @@ enum Commands {
         /// Filter by title (contains match)
         #[arg(long)]
         title: Option<String>,
+        /// Only list the game at this URL slug, `<author>/<game>` or a link to its page; unlike
+        /// --title it still matches after the game is renamed
+        #[arg(long, value_name = "AUTHOR/GAME")]
+        slug: Option<String>,
+        /// List the games you created instead of your purchases, with their stats
+        #[arg(long)]
+        mine: bool,
+        /// Add a column with how many times itch counted each key being downloaded
+        #[arg(long, conflicts_with = "mine")]
+        downloads: bool,
+        /// Only list keys from this bundle, by id or title (`direct` for keys no bundle granted)
+        #[arg(long, conflicts_with = "mine")]
+        bundle: Option<String>,
+        /// Add a column with the bundle each key came from
+        #[arg(long, conflicts_with = "mine")]
+        origin: bool,
+        /// Add a column with each game's URL slug, `<author>/<game>`
+        #[arg(long, conflicts_with = "mine")]
+        slug_column: bool,
+        /// List what a `dl --filter-file` would select, see `dl --help`
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        filter_file: Option<PathBuf>,
+        /// List the games in your library that have a demo
+        #[arg(long, conflicts_with = "mine")]
+        demos: bool,
+        /// List this game if it has a demo instead of your library's, by link to its page or id;
+        /// repeatable
+        #[arg(long, value_name = "URL", requires = "demos")]
+        demo_of: Vec<String>,
+        /// Show titles and other values in full instead of cutting them off: `wrap` continues
+        /// them on following lines within their column, `records` prints each game as
+        /// `Field: value` lines
+        #[arg(
+            long,
+            value_enum,
+            value_name = "LAYOUT",
+            num_args = 0..=1,
+            default_missing_value = "wrap"
+        )]
+        no_truncate: Option<Overflow>,
+    },
+    /// Add a free game to your library from a link to it, downloading it too with --and-download
+    Claim {
+        /// Link to the game's page, e.g. https://someone.itch.io/some-game
+        url: String,
+        /// The value of the `itchio` cookie from a browser logged in to itch.io (also
+        /// ITCH_SESSION), games can only be claimed on the website
+        #[arg(long)]
+        session: Option<String>,
+        /// Download the game once it's in your library
+        #[arg(long)]
+        and_download: bool,
+        #[command(flatten)]
+        download: DownloadArgs,
+    },
+    /// Download the entries of a game jam, each into `<jam>/<entry title>/` under the output
+    Jam {
+        /// The jam's page, e.g. https://itch.io/jam/some-jam, or its numeric id
+        jam: String,
+        #[command(flatten)]
+        download: DownloadArgs,
+    },
+    /// List the collections you've made on itch.io, for use with `dl --collection`
+    Collections {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
+        #[arg(short, long)]
+        api_key: Option<String>,
+        /// How to print the collections
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Work with a bundle's games, including the unclaimed ones that aren't in your library yet
+    Bundle {
+        #[command(subcommand)]
+        action: BundleAction,
     },
     /// Download all matched packages
     Dl {
-        /// Your itch.io API key (can also be set via ITCH_API_KEY environment variable)
+        #[command(flatten)]
+        download: DownloadArgs,
+        #[command(flatten)]
+        watch: WatchArgs,
+        /// List the games and uploads that would be downloaded, and where to, without downloading
+        #[arg(long, conflicts_with_all = ["watch", "once_then_watch"])]
+        dry_run: bool,
+        /// How to print the plan with --dry-run
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table, requires = "dry_run")]
+        format: OutputFormat,
+        /// Write the one upload the filters match to stdout instead of a file, e.g. to pipe into tar
+        #[arg(
+            long,
+            conflicts_with_all = ["dry_run", "watch", "once_then_watch", "unzip", "retry_failed", "build_id", "run_dir"]
+        )]
+        stdout: bool,
+    },
+    /// Keep the output directory in sync with your library, only fetching what's new or changed
+    Sync {
+        #[command(flatten)]
+        download: DownloadArgs,
+        /// Delete the files of games you no longer own
+        #[arg(long)]
+        prune: bool,
+        /// Print what would be fetched or removed without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Keep the previous version of updated uploads under a name with its version
+        #[arg(long)]
+        keep_old_versions: bool,
+        /// Replace local files with the upload itch serves even when it's an older version
+        #[arg(long)]
+        allow_downgrade: bool,
+        /// Keep the newer local copy under a name with its version when downgrading
+        #[arg(long, requires = "allow_downgrade")]
+        keep_downgraded: bool,
+        /// With --dry-run, list every game as new, updated, orphaned or unchanged with totals,
+        /// instead of what would be fetched or removed
+        #[arg(long, requires = "dry_run")]
+        diff: bool,
+        /// How to print the listing with --diff
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table, requires = "diff")]
+        format: OutputFormat,
+    },
+    /// Compare the output directory against the uploads itch currently serves, without downloading
+    Check {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
         #[arg(short, long)]
         api_key: Option<String>,
         /// Filter by author username or display name
@@ -223,511 +423,5409 @@ enum Commands {
         /// Filter by title (contains match)
         #[arg(long)]
         title: Option<String>,
-        /// Output directory for downloads
-        #[arg(short, long, default_value = ".")]
+        /// Output directory to check
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
         output: PathBuf,
-        /// Maximum number of concurrent downloads
+        /// The mirror was downloaded with --per-game-dirs
+        #[arg(long)]
+        per_game_dirs: bool,
+        /// Maximum number of concurrent upload listings
         #[arg(long, default_value = "3")]
         max_concurrent: usize,
-        /// Automatically unzip downloaded files
+        /// How many files are hashed at once [default: one per CPU]
+        #[arg(long)]
+        hash_jobs: Option<usize>,
+        /// How to print the results
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Compare two output directories offline, e.g. before migrating a mirror or to see what a copy lacks
+    Diff {
+        /// The first directory
+        #[arg(value_hint = ValueHint::DirPath)]
+        a: PathBuf,
+        /// The second directory
+        #[arg(value_hint = ValueHint::DirPath)]
+        b: PathBuf,
+        /// Compare whole games instead of individual files
+        #[arg(long)]
+        games_only: bool,
+        /// How to print the results
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Find files stored more than once across the mirror and the space linking them would save
+    DedupeReport {
+        /// Output directory to look through
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+        /// Leave out files smaller than this, e.g. `64K` or `10M`
+        #[arg(long, default_value = "1M", value_parser = budget::parse_size)]
+        min_size: u64,
+        /// Replace the duplicates instead of only reporting them
+        #[arg(long, value_enum)]
+        apply: Option<DedupeMode>,
+        /// How to print the results
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Seed the manifest from games already installed by the itch desktop app
+    ImportApp {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
+        #[arg(short, long)]
+        api_key: Option<String>,
+        /// The itch app's data directory or an install location, searched for install receipts
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        app_dir: Option<PathBuf>,
+        /// Output directory whose manifest is seeded
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+        /// Use the per-game directory layout when copying or linking installs into the output
+        #[arg(long)]
+        per_game_dirs: bool,
+        /// What to do with the installed files
+        #[arg(long, value_enum, default_value_t = ImportMode::Record)]
+        files: ImportMode,
+    },
+    /// Write launcher entries for the games in the output directory
+    Export {
+        /// Which launcher to write entries for
+        #[arg(value_enum)]
+        format: ExportFormat,
+        /// Output directory the games were downloaded to
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+        /// Directory the launcher entries are written to
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        dir: PathBuf,
+    },
+    /// Show everything itch.io says about a game
+    Info {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
+        #[arg(short, long)]
+        api_key: Option<String>,
+        /// The game's id, as shown by `ls`
+        game_id: u64,
+        /// How to print the game
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// List the builds pushed with butler to each of a game's uploads, for use with `dl --build-id`
+    Builds {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
+        #[arg(short, long)]
+        api_key: Option<String>,
+        /// The game's id, as shown by `ls`, one in your library or that you created
+        game_id: u64,
+        /// How to print the builds
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Look up a download key issued for one of your own games
+    Keys {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
+        #[arg(short, long)]
+        api_key: Option<String>,
+        /// The game the key is for, one you created (see `ls --mine`)
+        game_id: u64,
+        /// Find the key sent to this email address
+        #[arg(long, required_unless_present = "key", conflicts_with = "key")]
+        email: Option<String>,
+        /// Find the key with this key string, as found in its download URL
+        #[arg(long)]
+        key: Option<String>,
+        /// How to print the key
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// List the sales and donations of one of your own games
+    Purchases {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
+        #[arg(short, long)]
+        api_key: Option<String>,
+        /// The game to list purchases of, one you created (see `ls --mine`)
+        #[arg(long)]
+        game_id: u64,
+        /// Only purchases made with this email address
+        #[arg(long)]
+        email: Option<String>,
+        /// Only purchases made by this buyer or donor, by itch.io user id
+        #[arg(long)]
+        user_id: Option<u64>,
+        /// How to print the purchases
+        #[arg(long, value_enum, default_value_t = PurchasesFormat::Table)]
+        format: PurchasesFormat,
+    },
+    /// Archive the ratings and reviews of one of your own games into its directory in the mirror
+    Reviews {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
+        #[arg(short, long)]
+        api_key: Option<String>,
+        /// The game to archive reviews of, one you created (see `ls --mine`)
+        #[arg(long)]
+        game_id: u64,
+        /// The mirror, reviews being written to `reviews.json` and `reviews.csv` in the game's
+        /// directory
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+    },
+    /// Show which itch.io account the API key belongs to
+    Whoami {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
+        #[arg(short, long)]
+        api_key: Option<String>,
+        /// How to print the profile
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Check an API key and store it in the system keyring for later runs
+    Login,
+    /// Remove the API key stored by `login`
+    Logout,
+    /// Print a shell completion script to stdout
+    Completions {
+        /// Shell to write the script for
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print a man page to stdout, for packagers
+    #[command(hide = true)]
+    GenerateMan,
+    /// Inspect the configuration file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Diagnose setup problems: network, API key, and the output, state and cache directories
+    Doctor {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
+        #[arg(short, long)]
+        api_key: Option<String>,
+        /// Output directory downloads would go to
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+        /// How to print the results
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Measure download throughput and API latency and recommend concurrency and rate limits
+    Tune {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
+        #[arg(short, long)]
+        api_key: Option<String>,
+        /// Download at most this much in total, e.g. `50M`
+        #[arg(long, default_value = "200M", value_parser = budget::parse_size)]
+        budget: u64,
+        /// Write the recommended values into the configuration file
+        #[arg(long)]
+        save: bool,
+        /// How to print the results
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Remove temporary files, partial downloads and stale locks left behind by interrupted runs
+    ///
+    /// Only on Unix can it tell that the run which left something has exited, so elsewhere it only
+    /// finds half-written state files and locks that can't be read.
+    Clean {
+        /// Output directory to clean
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+        /// The --temp-dir used for downloads, if any
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        temp_dir: Option<PathBuf>,
+        /// Actually remove the leftovers instead of only listing them
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Remove files in the output directory for games that are no longer selected or owned
+    Prune {
+        /// Your itch.io API key, see --api-key-file for the other ways to provide one
+        #[arg(short, long)]
+        api_key: Option<String>,
+        /// Filter by author username or display name
+        #[arg(long)]
+        author: Option<String>,
+        /// Filter by title (contains match)
+        #[arg(long)]
+        title: Option<String>,
+        /// Only keep the game at this URL slug, `<author>/<game>` or a link to its page
+        #[arg(long, value_name = "AUTHOR/GAME")]
+        slug: Option<String>,
+        /// Only keep games in this collection, given by id or title (see `collections`)
+        #[arg(long)]
+        collection: Option<String>,
+        /// Only keep keys from this bundle, by id or title (`direct` for keys no bundle granted)
+        #[arg(long = "bundle", id = "from_bundle")]
+        from_bundle: Option<String>,
+        /// TOML file of author, title and game id allow/deny lists, as `dl --filter-file` takes
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        filter_file: Option<PathBuf>,
+        /// Keep the games you created, as backed up with `dl --mine`, instead of your purchases
+        #[arg(long, conflicts_with = "collection")]
+        mine: bool,
+        /// Output directory to prune
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+        /// Actually delete the orphaned files instead of only listing them
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Merge a `dl --run-dir` run into the main layout of the output directory
+    Promote {
+        /// The run's directory, or its name under `runs/`; the most recent run when left out
+        run: Option<String>,
+        /// Output directory the run was downloaded into
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+    },
+    /// Extract archives already in the output directory again, without downloading anything
+    Extract {
+        /// Output directory holding the archives
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+        /// Filter by title (contains match)
+        #[arg(long)]
+        title: Option<String>,
+        /// Filter by author username or display name, known from the metadata sidecars
+        #[arg(long)]
+        author: Option<String>,
+        /// Only extract the archives of this game
         #[arg(long)]
-        unzip: bool,
+        game_id: Option<u64>,
+        /// Encoding for zip entry names that aren't marked as UTF-8
+        #[arg(long, value_enum, default_value_t = ZipEncoding::Cp437)]
+        zip_encoding: ZipEncoding,
+        /// Path to an `unrar` or `7z` binary used to extract RAR archives
+        #[arg(long, value_hint = ValueHint::ExecutablePath)]
+        unrar_path: Option<PathBuf>,
+        /// How many levels of archives inside archives to extract (1 only extracts the archive)
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..=8))]
+        unzip_depth: u32,
+        /// Directory to stage extractions in before moving them into place
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        temp_dir: Option<PathBuf>,
+        /// Create missing parents of the temporary directory too
+        #[arg(long, requires = "temp_dir")]
+        create_parents: bool,
+        /// How many threads extract a zip with many entries
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..=64))]
+        extract_jobs: u32,
+        /// Extract again over directories that were already extracted
+        #[arg(long)]
+        force: bool,
+        /// Only list what would be extracted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check extracted directories against the archives kept next to them, listing the files
+    /// that are missing or differ
+    Verify {
+        /// Output directory holding the archives
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+        /// Filter by title (contains match)
+        #[arg(long)]
+        title: Option<String>,
+        /// Filter by author username or display name, known from the metadata sidecars
+        #[arg(long)]
+        author: Option<String>,
+        /// Only verify the archives of this game
+        #[arg(long)]
+        game_id: Option<u64>,
+        /// Encoding for zip entry names that aren't marked as UTF-8, as they were extracted with
+        #[arg(long, value_enum, default_value_t = ZipEncoding::Cp437)]
+        zip_encoding: ZipEncoding,
+        /// Also compare the CRC-32 of every file a zip or 7z archive records one for
+        #[arg(long)]
+        deep: bool,
+    },
+    /// Move what the tool knows about a mirror to another machine
+    State {
+        #[command(subcommand)]
+        action: StateAction,
     },
 }
 
-#[derive(Debug, Deserialize)]
-struct User {
-    id: u64,
-    username: String,
-    display_name: Option<String>,
-    url: String,
-    cover_url: Option<String>,
+#[derive(Subcommand)]
+enum StateAction {
+    /// Write the manifest, history, cached listings and receipts of a mirror to a `.tar.gz`
+    Export {
+        /// File to write the export to
+        #[arg(value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+        /// Output directory whose state is exported
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+        /// The mirror was downloaded with --per-game-dirs
+        #[arg(long)]
+        per_game_dirs: bool,
+        /// The mirror was downloaded with --link-latest
+        #[arg(long)]
+        link_latest: bool,
+        /// The mirror was downloaded with --organize
+        #[arg(long)]
+        organize: bool,
+        /// The mirror was downloaded with --group-by-bundle
+        #[arg(long)]
+        group_by_bundle: bool,
+    },
+    /// Restore an export into an output directory, moving its paths to the new location
+    Import {
+        /// Export written by `state export`
+        #[arg(value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+        /// Output directory to restore the state into
+        #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+        output: PathBuf,
+        /// Combine with state already in the output directory instead of refusing to import
+        #[arg(long)]
+        merge: bool,
+    },
 }
 
-#[derive(Debug, Deserialize)]
-struct Game {
-    id: u64,
-    title: String,
-    short_text: Option<String>,
-    url: String,
-    #[serde(rename = "type")]
-    game_type: String,
-    classification: String,
-    created_at: String,
-    published_at: Option<String>,
-    cover_url: Option<String>,
-    still_cover_url: Option<String>,
-    min_price: Option<u64>,
-    user: User,
-}
-
-#[derive(Debug, Deserialize)]
-struct OwnedKey {
-    id: u64,
-    game_id: u64,
-    purchase_id: Option<u64>,
-    downloads: u64,
-    created_at: String,
-    updated_at: String,
-    game: Game,
+#[derive(Subcommand)]
+enum BundleAction {
+    /// List a bundle's games and whether each has been claimed
+    Ls {
+        #[command(flatten)]
+        bundle: BundleArgs,
+        /// How to print the games
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Download a bundle's claimed games, claiming the rest first with --claim
+    Dl {
+        #[command(flatten)]
+        bundle: BundleArgs,
+        /// Claim every unclaimed game so it's added to your library and downloaded
+        #[arg(long)]
+        claim: bool,
+        #[command(flatten)]
+        download: Box<DownloadArgs>,
+    },
 }
 
-#[derive(Debug, Deserialize)]
-struct Upload {
-    id: u64,
-    filename: String,
-    size: u64,
-    #[serde(rename = "type")]
-    upload_type: String,
-    game_id: u64,
+/// Which bundle to read and how to log in to its page
+#[derive(Args)]
+struct BundleArgs {
+    /// The bundle's download URL (https://itch.io/bundle/download/...) or the secret at its end
+    bundle: String,
+    /// The value of the `itchio` cookie from a browser logged in to itch.io (also ITCH_SESSION),
+    /// bundle pages can't be read with an API key
+    #[arg(long)]
+    session: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct UploadsResponse {
-    uploads: Vec<Upload>,
+/// The browser session from `--session` or ITCH_SESSION, if there is one
+fn session_cookie(session: Option<String>) -> Option<String> {
+    session
+        .or_else(|| std::env::var("ITCH_SESSION").ok())
+        .filter(|session| !session.trim().is_empty())
 }
 
-#[derive(Debug, Deserialize)]
-struct OwnedKeysResponse {
-    owned_keys: Vec<OwnedKey>,
-    page: u64,
-    per_page: u64,
+/// The error for a missing session, `what` being what it's needed for
+fn session_required(what: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "A session cookie is required to {}. Log in to itch.io in a browser and pass the value of \
+         its `itchio` cookie via --session or ITCH_SESSION",
+        what
+    )
 }
 
-#[derive(Clone)]
-struct ItchClient {
-    client: Client,
-    api_key: String,
-}
+impl BundleArgs {
+    fn session(&self) -> Result<String> {
+        session_cookie(self.session.clone()).ok_or_else(|| session_required("read bundle pages"))
+    }
 
-impl ItchClient {
-    fn new(api_key: String) -> Self {
-        Self {
-            client: Client::new(),
-            api_key,
-        }
+    /// The bundle's entries, with a status line per page
+    async fn entries(&self, ctx: &CommandContext) -> Result<Vec<bundle::BundleEntry>> {
+        let url = bundle::bundle_url(&self.bundle)?;
+        // Bundle pages authenticate with the session, the client only provides the HTTP settings
+        let client = ctx.new_client(String::new())?;
+        let entries = bundle::list_entries(client.http(), &url, &self.session()?, |page| {
+            ui::status!("Fetching bundle page {}...", page)
+        })
+        .await?;
+        ui::status!(
+            "Found {} games, {} unclaimed.",
+            entries.len(),
+            entries.iter().filter(|entry| !entry.claimed).count()
+        );
+        Ok(entries)
     }
+}
 
-    async fn list_owned_keys(&self) -> Result<Vec<OwnedKey>> {
-        let url = "https://api.itch.io/profile/owned-keys";
-        let mut all_owned_keys = Vec::new();
-        let mut page = 1;
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the configuration in effect, with built-in defaults filled in and the API key hidden
+    Show,
+}
 
-        loop {
-            println!("Fetching page {}...", page);
+/// How commands that report results print them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human readable table
+    Table,
+    /// JSON on stdout
+    Json,
+}
 
-            let response = make_request_with_retry(
-                &self.client,
-                url,
-                &[("page", page)],
-                &self.api_key,
-                3, // max retries
-            )
-            .await?;
+/// How `purchases` prints them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum PurchasesFormat {
+    /// Human readable table
+    Table,
+    /// JSON on stdout
+    Json,
+    /// CSV on stdout with a header row, for spreadsheets and accounting
+    Csv,
+}
 
-            if !response.status().is_success() {
-                let status = response.status();
-                let text = response.text().await.unwrap_or_default();
-                return Err(anyhow::anyhow!(
-                    "API request failed with status {}: {}",
-                    status,
-                    text
-                ));
-            }
+/// What `import-app` does with the installed files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ImportMode {
+    /// Leave them where they are and record their location
+    Record,
+    /// Copy them into the output directory
+    Copy,
+    /// Link them into the output directory
+    Link,
+}
 
-            let owned_keys_response: OwnedKeysResponse = response
-                .json()
-                .await
-                .context("Failed to parse JSON response")?;
+/// Launchers `export` can write entries for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ExportFormat {
+    /// Lutris installer YAML, one per game
+    Lutris,
+    /// A Pegasus frontend `metadata.pegasus.txt` collection
+    Pegasus,
+    /// A JSON list of games for importing into Playnite
+    Playnite,
+}
 
-            let keys_count = owned_keys_response.owned_keys.len();
-            all_owned_keys.extend(owned_keys_response.owned_keys);
+/// Options for running `dl` as a long-lived watcher
+#[derive(Args)]
+struct WatchArgs {
+    /// Keep running, downloading newly acquired games every --interval
+    #[arg(long)]
+    watch: bool,
+    /// How long to wait between checks in watch mode, e.g. `30m`, `2h` or `1h30m`
+    #[arg(long, value_parser = parse_duration, default_value = "30m")]
+    interval: Duration,
+    /// Start watching with a full sync that also fetches updates to games already downloaded
+    #[arg(long)]
+    once_then_watch: bool,
+}
 
-            // If we got fewer keys than the per_page limit, we've reached the end
-            if keys_count < owned_keys_response.per_page as usize {
-                break;
-            }
+/// Check a `--dir-template` before anything is named after it
+fn parse_dir_template(text: &str) -> Result<String, String> {
+    layout::check_dir_template(text).map_err(|e| e.to_string())?;
+    Ok(text.to_string())
+}
 
-            page += 1;
+/// Parse a duration such as `90s`, `30m`, `2h` or `1h30m`, a bare number being seconds
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in text.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
         }
-
-        println!(
-            "Fetched {} total packages across {} pages.",
-            all_owned_keys.len(),
-            page
-        );
-        Ok(all_owned_keys)
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(format!("unknown unit '{}' in '{}'", c, text)),
+        };
+        let value: u64 = number
+            .parse()
+            .map_err(|_| format!("missing number before '{}' in '{}'", c, text))?;
+        total += value * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        total += number.parse::<u64>().map_err(|e| e.to_string())?;
+    }
+    if total == 0 {
+        return Err("duration must be greater than zero".to_string());
     }
+    Ok(Duration::from_secs(total))
+}
 
-    async fn get_game_uploads(&self, game_id: u64, download_key_id: u64) -> Result<Vec<Upload>> {
-        let url = format!("https://api.itch.io/games/{}/uploads", game_id);
+/// Read and check a `--filter-file`, its errors naming the line or key at fault
+fn load_filter_file(path: &Path) -> Result<FilterFile> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let rules: FilterFile =
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))?;
+    rules
+        .checked()
+        .map_err(anyhow::Error::msg)
+        .with_context(|| format!("Invalid filters in {}", path.display()))
+}
 
-        // Add delay before making request to avoid rate limiting
-        sleep(Duration::from_millis(1000)).await;
+/// The `--filter-file` rules, if one was given, with `--author`/`--title` replacing its
+/// author/title rules
+fn load_filter_rules(
+    path: Option<&Path>,
+    author: &Option<String>,
+    title: &Option<String>,
+) -> Result<Option<FilterFile>> {
+    Ok(path
+        .map(load_filter_file)
+        .transpose()?
+        .map(|rules| rules.overridden_by(author, title)))
+}
 
-        let response = make_request_with_retry(
-            &self.client,
-            &url,
-            &[("download_key_id", download_key_id)],
-            &self.api_key,
-            3, // max retries
-        )
-        .await?;
+/// Options shared by every command that downloads packages
+#[derive(Args)]
+struct DownloadArgs {
+    /// Your itch.io API key, see --api-key-file for the other ways to provide one
+    #[arg(short, long)]
+    api_key: Option<String>,
+    /// Filter by author username or display name
+    #[arg(long)]
+    author: Option<String>,
+    /// Filter by title (contains match)
+    #[arg(long)]
+    title: Option<String>,
+    /// Only download the game at this URL slug, `<author>/<game>` or a link to its page; unlike
+    /// --title it still matches after the game is renamed
+    #[arg(long, value_name = "AUTHOR/GAME")]
+    slug: Option<String>,
+    /// Only download games in this collection, given by id or title (see `collections`)
+    #[arg(long)]
+    collection: Option<String>,
+    /// Only download keys from this bundle, by id or title (`direct` for keys no bundle granted)
+    #[arg(long = "bundle", id = "from_bundle")]
+    from_bundle: Option<String>,
+    /// TOML file of author, title and game id allow/deny lists, `--author`/`--title` replacing
+    /// its author/title rules
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    filter_file: Option<PathBuf>,
+    /// Back up every upload of the games you created, drafts included, instead of your purchases
+    #[arg(long, conflicts_with_all = ["collection", "retry_failed"])]
+    mine: bool,
+    /// Download this build's archive instead of the latest file, the build being one of the
+    /// selected games' (see `builds`)
+    #[arg(long, conflicts_with_all = ["mine", "retry_failed"])]
+    build_id: Option<u64>,
+    /// Also save each game's description, cover and screenshots into `page/` in its directory
+    #[arg(long)]
+    archive_page: bool,
+    /// Download only the games' demo uploads, which need no download key
+    #[arg(long, conflicts_with_all = ["mine", "build_id"])]
+    demos_only: bool,
+    /// Download the demo of this game instead of your library's, by link to its page or id; repeatable
+    #[arg(
+        long,
+        value_name = "URL",
+        requires = "demos_only",
+        conflicts_with = "collection"
+    )]
+    demo_of: Vec<String>,
+    /// Download a set of each game's uploads, e.g. `game+soundtrack`, or a set named in the config file
+    ///
+    /// Sets are `+`-separated parts, `game` being the upload that's picked otherwise and anything
+    /// else an upload type (soundtrack, book, video, documentation, mod, ...).
+    #[arg(long, value_name = "SET", conflicts_with_all = ["mine", "build_id"])]
+    bundle_set: Option<String>,
+    /// With --mine, also archive each game's ratings and reviews into its directory
+    #[arg(long, requires = "mine")]
+    with_reviews: bool,
+    /// Output directory for downloads
+    #[arg(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
+    output: PathBuf,
+    /// Create missing parents of the output and temporary directories too, rather than only the
+    /// directories themselves
+    #[arg(long)]
+    create_parents: bool,
+    /// Maximum number of concurrent downloads
+    #[arg(long, default_value = "3")]
+    max_concurrent: usize,
+    /// Maximum number of games whose uploads are looked up at once, apart from the download slots
+    #[arg(long, default_value = "4", value_parser = clap::value_parser!(u32).range(1..))]
+    meta_concurrent: u32,
+    /// Maximum number of concurrent downloads of games by the same author, unlimited by default
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrent_per_author: Option<u32>,
+    /// Automatically extract downloaded archives (zip, 7z, tar.gz, tar.xz, tar)
+    #[arg(long)]
+    unzip: bool,
+    /// Encoding for zip entry names that aren't marked as UTF-8
+    #[arg(long, value_enum, default_value_t = ZipEncoding::Cp437)]
+    zip_encoding: ZipEncoding,
+    /// Path to an `unrar` or `7z` binary used to extract RAR archives
+    #[arg(long, value_hint = ValueHint::ExecutablePath)]
+    unrar_path: Option<PathBuf>,
+    /// What to do when a different upload was already extracted into the same directory
+    #[arg(long, value_enum, default_value_t = ExtractConflict::Suffix)]
+    extract_conflict: ExtractConflict,
+    /// Check each extraction holds every file of its archive at its listed size, keeping the
+    /// archive and counting it as a failed extraction when it doesn't
+    #[arg(long, requires = "unzip")]
+    audit_extractions: bool,
+    /// Also compare the CRC-32 of every extracted file against the archive's
+    #[arg(long, requires = "audit_extractions")]
+    audit_deep: bool,
+    /// Save files under the filename from the uploads listing, ignoring the name sent with the download
+    #[arg(long)]
+    trust_listed_names: bool,
+    /// Directory to stage partial downloads and extractions in before moving them to the output
+    ///
+    /// What an interrupted run leaves in it is removed once its process has exited, which can only
+    /// be told on Unix. Elsewhere the leftovers stay until deleted by hand.
+    #[arg(long, value_hint = ValueHint::DirPath)]
+    temp_dir: Option<PathBuf>,
+    /// Put each game's files in its own directory under the output directory
+    #[arg(long)]
+    per_game_dirs: bool,
+    /// Keep a `current` link in each game's directory pointing at the latest download (implies --per-game-dirs)
+    #[arg(long)]
+    link_latest: bool,
+    /// Sort each game's files into `game/`, `ost/` and `extras/` folders by upload type (implies --per-game-dirs)
+    #[arg(long)]
+    organize: bool,
+    /// Put games under a directory named after the bundle their key came from
+    #[arg(long)]
+    group_by_bundle: bool,
+    /// Put what this run downloads in `runs/<timestamp>/` under the output, with a `run.json`
+    #[arg(long)]
+    run_dir: bool,
+    /// How many levels of archives inside archives to extract (1 only extracts the download)
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..=8))]
+    unzip_depth: u32,
+    /// How many threads extract a zip with many entries
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..=64))]
+    extract_jobs: u32,
+    /// Deduplicate identical files across versions and games
+    #[arg(long, value_enum)]
+    dedupe: Option<DedupeMode>,
+    /// Another local mirror to copy or hard link matching files from before downloading them,
+    /// can be repeated
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    seed_from: Vec<PathBuf>,
+    /// Write a `<file>.sha256` next to each downloaded file, in the format `sha256sum -c` checks
+    #[arg(long)]
+    sidecar_checksums: bool,
+    /// Don't append to the download history log in the output directory
+    #[arg(long)]
+    no_history: bool,
+    /// Don't write a RECEIPT.txt recording the account and download key into each game's directory
+    #[arg(long)]
+    no_receipts: bool,
+    /// Only retry the downloads that failed last run, ignoring the filters
+    #[arg(long)]
+    retry_failed: bool,
+    /// After the run, go through its failures one by one at the terminal to retry, skip
+    /// permanently or look at each
+    #[arg(long, conflicts_with = "fail_fast")]
+    triage: bool,
+    /// Attempt the downloads skipped permanently during --triage again
+    #[arg(long)]
+    reset_skips: bool,
+    /// Where to write the machine-readable report, `.itch-downloader/report.json` by default
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    report: Option<PathBuf>,
+    /// Don't lock the output directory against other runs
+    ///
+    /// A lock left by a crashed run is taken over on Unix. Elsewhere whether its process is still
+    /// running can't be told, so it has to be deleted by hand.
+    #[arg(long)]
+    no_lock: bool,
+    /// Only download purchases made since `last-run` (the last successful run) or a date like 2024-01-01
+    #[arg(long)]
+    since: Option<since::Since>,
+    /// Pace download starts evenly across this window instead of starting them all at once, e.g. `6h`
+    #[arg(long, value_parser = parse_duration)]
+    spread_over: Option<Duration>,
+    /// Stop starting downloads once they'd take the run past this many bytes, e.g. `500M` or `50G`
+    #[arg(long, value_parser = budget::parse_size)]
+    max_total_size: Option<u64>,
+    /// Start downloads in this order instead of the library's; the budget is spent in it too
+    #[arg(long, value_enum)]
+    order: Option<schedule::DownloadOrder>,
+    /// Show a notification when the run finishes
+    #[arg(long, value_enum)]
+    notify: Option<notify::NotifyMode>,
+    /// POST the run's report as JSON to this URL when it finishes, e.g. an ntfy or Discord webhook
+    #[arg(long, value_hint = ValueHint::Url)]
+    notify_webhook: Option<String>,
+    /// Don't ask for confirmation before downloading more than --confirm-above games
+    #[arg(short, long)]
+    yes: bool,
+    /// Ask before downloading more than this many games, required to be --yes without a terminal
+    #[arg(long, default_value = "10")]
+    confirm_above: usize,
+    /// Stop starting new downloads after the first failure, letting those under way finish
+    #[arg(long)]
+    fail_fast: bool,
+    /// Count games deleted from itch.io as failures, for the exit code and --fail-fast
+    #[arg(long)]
+    strict: bool,
+    /// Carry on with the part of the library that arrived when a page of it can't be fetched
+    #[arg(long)]
+    allow_partial: bool,
+}
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "API request failed with status {}: {}",
-                status,
-                text
-            ));
+impl DownloadArgs {
+    /// Check the directories these arguments name can be used, before anything is fetched
+    fn check_dirs(&self, ctx: &CommandContext) -> Result<()> {
+        preflight::check_dir(
+            "output directory",
+            &self.output,
+            Access::Write,
+            self.create_parents,
+        )?;
+        // Created inside the output directory as needed, so only something in its way is a problem
+        let state_dir = ctx.layout.state_dir(&self.output);
+        preflight::check_dir("state directory", &state_dir, Access::Write, true)?;
+        if let Some(temp_dir) = &self.temp_dir {
+            preflight::check_dir(
+                "temporary directory",
+                temp_dir,
+                Access::Write,
+                self.create_parents,
+            )?;
         }
-
-        let uploads_response: UploadsResponse = response
-            .json()
-            .await
-            .context("Failed to parse JSON response")?;
-
-        Ok(uploads_response.uploads)
+        for dir in &self.seed_from {
+            preflight::check_dir("seed directory", dir, Access::Read, false)?;
+        }
+        Ok(())
     }
 
-    async fn download_file(
-        &self,
-        upload_id: u64,
-        download_key_id: u64,
-        filename: &str,
-        output_path: &PathBuf,
-        progress_bar: ProgressBar,
-    ) -> Result<()> {
-        let url = format!(
-            "https://api.itch.io/uploads/{}/download?download_key_id={}",
-            upload_id, download_key_id
-        );
-
-        // Add delay before making request to avoid rate limiting
-        sleep(Duration::from_millis(1000)).await;
-
-        let mut attempt = 0;
-        let max_retries = 3;
+    /// Build the download options these arguments describe, loading any state they need
+    fn download_options(&self, ctx: &CommandContext) -> Result<mirror::DownloadOptions> {
+        self.check_dirs(ctx)?;
+        let dedupe = match self.dedupe {
+            Some(DedupeMode::Hardlink) => Some(std::sync::Arc::new(std::sync::Mutex::new(
+                HashIndex::load(&self.output, &ctx.layout)?,
+            ))),
+            None => None,
+        };
+        let seeds = if self.seed_from.is_empty() {
+            None
+        } else {
+            Some(std::sync::Arc::new(SeedDirs::open(
+                &self.seed_from,
+                &ctx.layout,
+            )?))
+        };
+        Ok(mirror::DownloadOptions {
+            output_path: self.output.clone(),
+            layout: ctx.layout.clone(),
+            author: self.author.clone(),
+            title: self.title.clone(),
+            per_game_dirs: self.per_game_dirs,
+            link_latest: self.link_latest,
+            organize: self.organize,
+            group_by_bundle: self.group_by_bundle,
+            run_dir: self.run_dir,
+            save: SaveOptions {
+                temp_dir: self.temp_dir.clone(),
+                trust_listed_names: self.trust_listed_names,
+                dedupe,
+                expected_size: None,
+            },
+            max_concurrent: self.max_concurrent,
+            meta_concurrent: self.meta_concurrent as usize,
+            max_concurrent_per_author: self.max_concurrent_per_author.map(|n| n as usize),
+            max_total_size: self.max_total_size,
+            spread_over: self.spread_over,
+            order: self.order,
+            since: self.since.clone(),
+            unzip: self.unzip,
+            extract: ExtractOptions {
+                zip_encoding: self.zip_encoding,
+                unrar_path: self.unrar_path.clone(),
+                max_depth: self.unzip_depth as usize,
+                temp_dir: self.temp_dir.clone(),
+                jobs: self.extract_jobs as usize,
+            },
+            extract_conflict: self.extract_conflict,
+            audit_extractions: self.audit_extractions.then_some(self.audit_deep),
+            seeds,
+            sidecar_checksums: self.sidecar_checksums,
+            history: !self.no_history,
+            receipts: !self.no_receipts,
+            retry_failed: self.retry_failed,
+            // Nobody is there to answer, and the summary already says what failed
+            triage: self.triage
+                && if std::io::IsTerminal::is_terminal(&std::io::stdin()) && !ui::is_porcelain() {
+                    true
+                } else {
+                    ui::status!("Not triaging failures without a terminal to ask at");
+                    false
+                },
+            reset_skips: self.reset_skips,
+            report: self.report.clone(),
+            lock: !self.no_lock,
+            webhook: self.notify_webhook.clone(),
+            run_args: Vec::new(),
+            sync: None,
+            confirm_above: Some(self.confirm_above).filter(|_| !self.yes),
+            // `run_started` lists every game before the first download
+            select_up_front: ui::is_porcelain(),
+            fail_fast: self.fail_fast,
+            strict: self.strict,
+            allow_partial: self.allow_partial,
+            collection: self.collection.clone(),
+            from_bundle: self.from_bundle.clone(),
+            slug: self.slug.clone(),
+            filter_rules: load_filter_rules(
+                self.filter_file.as_deref(),
+                &self.author,
+                &self.title,
+            )?,
+            archive_page: self.archive_page,
+            demos_only: self.demos_only,
+            demo_of: self.demo_of.clone(),
+            bundle_set: self
+                .bundle_set
+                .as_deref()
+                .map(|name| ctx.resolve_bundle_set(name))
+                .transpose()?,
+            games: None,
+            mine: self.mine,
+            keys: None,
+            build_id: self.build_id,
+            with_reviews: self.with_reviews,
+            shutdown: CancellationToken::new(),
+            abort: CancellationToken::new(),
+        })
+    }
 
-        loop {
-            let response = self
-                .client
-                .get(&url)
-                .bearer_auth(&self.api_key)
-                .send()
-                .await
-                .context("Failed to send download request")?;
-
-            match response.status() {
-                reqwest::StatusCode::TOO_MANY_REQUESTS => {
-                    attempt += 1;
-                    if attempt > max_retries {
-                        return Err(anyhow::anyhow!(
-                            "Download failed: Too many requests (429) - exceeded max retries ({})",
-                            max_retries
-                        ));
-                    }
+    /// Whether a desktop notification announces the end of the run
+    fn notify_desktop(&self) -> bool {
+        self.notify == Some(notify::NotifyMode::Desktop)
+    }
+}
 
-                    let retry_delay = Duration::from_millis(1000 + (attempt as u64 * 500));
-                    progress_bar.set_message(format!(
-                        "Rate limited, retrying {} in {:?}...",
-                        filename, retry_delay
-                    ));
-                    sleep(retry_delay).await;
-                    progress_bar.set_message(format!("Downloading {}", filename));
-                    continue;
-                }
-                status if !status.is_success() => {
-                    let text = response.text().await.unwrap_or_default();
-                    return Err(anyhow::anyhow!(
-                        "Download request failed with status {}: {}",
-                        status,
-                        text
-                    ));
-                }
-                _ => {
-                    // Success, proceed with download
-                    let total_size = response.content_length().unwrap_or(0);
-                    progress_bar.set_length(total_size);
-
-                    let file_path = output_path.join(filename);
-                    let mut file = File::create(&file_path)
-                        .await
-                        .context("Failed to create output file")?;
-
-                    let mut stream = response.bytes_stream();
-                    let mut downloaded = 0u64;
-
-                    while let Some(chunk) = stream.next().await {
-                        let chunk = chunk.context("Failed to read chunk from response")?;
-                        file.write_all(&chunk)
-                            .await
-                            .context("Failed to write chunk to file")?;
-                        downloaded += chunk.len() as u64;
-                        progress_bar.set_position(downloaded);
-                    }
+/// How a command finished, mapped to the process exit code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RunStatus {
+    Success,
+    /// The library is empty or the filters didn't match any owned package, only a failure with
+    /// `--strict-empty`
+    NothingMatched,
+    /// At least one download failed
+    PartialFailure,
+    /// `check` found missing, changed or extra files
+    Incomplete,
+    /// `doctor` found a problem with the setup
+    Unhealthy,
+    /// Interrupted a second time, abandoning transfers and extractions part way
+    Interrupted,
+}
 
-                    progress_bar.finish_with_message(format!("Downloaded {}", filename));
-                    return Ok(());
-                }
-            }
+impl RunStatus {
+    fn exit_code(self, strict_empty: bool) -> ExitCode {
+        match self {
+            RunStatus::Success => ExitCode::SUCCESS,
+            RunStatus::NothingMatched if !strict_empty => ExitCode::SUCCESS,
+            RunStatus::NothingMatched => ExitCode::from(EXIT_NOTHING_MATCHED),
+            RunStatus::PartialFailure => ExitCode::from(EXIT_PARTIAL_FAILURE),
+            RunStatus::Incomplete => ExitCode::from(EXIT_INCOMPLETE),
+            RunStatus::Unhealthy => ExitCode::from(EXIT_UNHEALTHY),
+            RunStatus::Interrupted => ExitCode::from(EXIT_INTERRUPTED),
         }
     }
 }
 
-async fn list_packages(
-    api_key: Option<String>,
-    author_filter: Option<String>,
-    title_filter: Option<String>,
-) -> Result<()> {
-    let api_key = api_key
-        .or_else(|| std::env::var("ITCH_API_KEY").ok())
-        .context("API key is required. Provide it via --api-key flag or ITCH_API_KEY environment variable")?;
+/// Exit code when the filters matched nothing
+const EXIT_NOTHING_MATCHED: u8 = 2;
 
-    let client = ItchClient::new(api_key);
-    let owned_keys = client.list_owned_keys().await?;
-
-    let mut filtered_keys = owned_keys;
-
-    // Apply author filter
-    if let Some(author) = &author_filter {
-        filtered_keys.retain(|key| {
-            key.game
-                .user
-                .username
-                .to_lowercase()
-                .contains(&author.to_lowercase())
-                || key
-                    .game
-                    .user
-                    .display_name
-                    .as_ref()
-                    .map(|name| name.to_lowercase().contains(&author.to_lowercase()))
-                    .is_some_and(|b| b)
-        });
-    }
+/// Exit code when some downloads failed
+const EXIT_PARTIAL_FAILURE: u8 = 3;
 
-    // Apply title filter
-    if let Some(title) = &title_filter {
-        filtered_keys.retain(|key| {
-            key.game
-                .title
-                .to_lowercase()
-                .contains(&title.to_lowercase())
-        });
-    }
+/// Exit code when itch.io rejected the API key
+const EXIT_AUTH_FAILURE: u8 = 4;
 
-    if filtered_keys.is_empty() {
-        println!("No packages found.");
-        return Ok(());
-    }
+/// Exit code when `check` found the mirror incomplete or out of date, or `diff` found differences
+const EXIT_INCOMPLETE: u8 = 5;
 
-    println!("Your itch.io packages:");
-    println!("{:<8} {:<20} {:<40}", "ID", "Author", "Title");
-    println!("{:-<8} {:-<20} {:-<40}", "", "", "");
+/// Exit code when `doctor` found a failing check
+const EXIT_UNHEALTHY: u8 = 6;
 
-    for key in filtered_keys {
-        let title = truncate_to_width(&key.game.title, 37);
-        let title_padded = pad_to_width(&title, 40);
+/// Exit code when itch.io kept rate limiting after every retry
+const EXIT_RATE_LIMITED: u8 = 7;
 
-        let author_name = key.game.user.display_name.unwrap_or(key.game.user.username);
-        let author = truncate_to_width(&author_name, 17);
-        let author_padded = pad_to_width(&author, 20);
+/// Exit code when downloads were stopped part way, the conventional one for SIGINT
+const EXIT_INTERRUPTED: u8 = 130;
 
-        println!("{:<8} {} {}", key.game.id, author_padded, title_padded);
+/// Say why no package was selected: there were none to begin with, or which options turned
+/// them all away, `extra` adding options outside the [`KeyFilter`] such as `--filter-file`
+fn report_nothing_matched(mine: bool, exclusions: &filters::Exclusions, extra: &[(&str, usize)]) {
+    let (empty, source) = match mine {
+        true => ("You haven't created any games on itch.io.", "your games"),
+        false => ("Your itch.io library is empty.", "your library"),
+    };
+    if exclusions.seen == 0 {
+        ui::status!("{}", empty);
+        return;
+    }
+    let reasons: Vec<String> = exclusions
+        .by_option()
+        .into_iter()
+        .chain(extra.iter().copied().filter(|(_, excluded)| *excluded > 0))
+        .map(|(option, excluded)| format!("{} by {}", excluded, option))
+        .collect();
+    if reasons.is_empty() {
+        ui::status!(
+            "None of the {} packages in {} were selected.",
+            exclusions.seen,
+            source
+        );
+    } else {
+        ui::status!(
+            "Your filters matched none of the {} packages in {} (excluded: {}).",
+            exclusions.seen,
+            source,
+            reasons.join(", ")
+        );
     }
+}
 
-    Ok(())
+/// A library key for `game_id`, or a keyless stand in for one of your own games
+async fn key_for_game(client: &ItchClient, game_id: u64) -> Result<OwnedKey> {
+    let library = mirror::fetch_library(client, progress::show).await?;
+    if let Some(key) = library.into_iter().find(|key| key.game_id == game_id) {
+        return Ok(key);
+    }
+    match client
+        .list_my_games()
+        .await?
+        .into_iter()
+        .find(|game| game.id == game_id)
+    {
+        Some(game) => Ok(OwnedKey::keyless(game)),
+        None => anyhow::bail!(
+            "Game {} isn't in your library or one of your own games",
+            game_id
+        ),
+    }
 }
 
-async fn download_packages(
-    api_key: Option<String>,
+async fn list_builds(
+    ctx: &CommandContext,
+    api_key: String,
+    game_id: u64,
+    format: OutputFormat,
+) -> Result<RunStatus> {
+    let client = ctx.new_client(api_key)?;
+    let key = key_for_game(&client, game_id).await?;
+    let uploads = client
+        .get_game_uploads(game_id, key.download_key_id(), &CancellationToken::new())
+        .await?;
+    let mut listings = Vec::new();
+    for upload in uploads
+        .into_iter()
+        .filter(|upload| upload.build_id.is_some())
+    {
+        let builds = client.list_builds(upload.id, key.download_key_id()).await?;
+        listings.push((upload, builds));
+    }
+    if listings.is_empty() {
+        ui::status!("{} has no uploads pushed with butler.", key.game.title);
+        return Ok(RunStatus::NothingMatched);
+    }
+
+    match format {
+        OutputFormat::Json => {
+            let value: Vec<_> = listings
+                .iter()
+                .map(|(upload, builds)| serde_json::json!({ "upload": upload, "builds": builds }))
+                .collect();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&value).context("Failed to serialize builds")?
+            )
+        }
+        OutputFormat::Table => {
+            for (index, (upload, builds)) in listings.iter().enumerate() {
+                if index > 0 {
+                    println!();
+                }
+                println!("Upload {}: {}", upload.id, upload.filename);
+                println!("Build      Version  User version     State       Created");
+                println!("{:-<10} {:-<8} {:-<16} {:-<11} {:-<20}", "", "", "", "", "");
+                for build in builds {
+                    println!(
+                        "{:<10} {:<8} {:<16} {:<11} {}",
+                        build.id,
+                        build.version,
+                        truncate_to_width(build.user_version.as_deref().unwrap_or("-"), 16),
+                        build.state,
+                        ui::date(build.created_at)
+                    );
+                }
+            }
+        }
+    }
+    Ok(RunStatus::Success)
+}
+
+async fn game_info(
+    ctx: &CommandContext,
+    api_key: String,
+    game_id: u64,
+    format: OutputFormat,
+) -> Result<RunStatus> {
+    let Some(details) = ctx.new_client(api_key)?.get_game(game_id).await? else {
+        ui::status!(
+            "Game {} isn't available, it may have been delisted or made private.",
+            game_id
+        );
+        return Ok(RunStatus::NothingMatched);
+    };
+
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&details).context("Failed to serialize game")?
+        ),
+        OutputFormat::Table => {
+            let game = &details.game;
+            let author = game
+                .user
+                .display_name
+                .as_ref()
+                .unwrap_or(&game.user.username);
+            let platforms = details.platforms();
+            println!("Title:        {}", game.title);
+            println!("Id:           {}", game.id);
+            println!("Author:       {}", author);
+            println!("URL:          {}", game.url);
+            println!("Kind:         {} ({})", game.classification, game.game_type);
+            println!(
+                "Platforms:    {}",
+                if platforms.is_empty() {
+                    "-".to_string()
+                } else {
+                    platforms.join(", ")
+                }
+            );
+            println!(
+                "Price:        {}",
+                match game.min_price {
+                    Some(0) | None => "free or pay what you want".to_string(),
+                    Some(cents) => format!("{}.{:02}", cents / 100, cents % 100),
+                }
+            );
+            if let Some(sale) = &details.sale {
+                println!(
+                    "Sale:         {}% off until {}",
+                    sale.rate.unwrap_or_default(),
+                    sale.end_date.as_deref().unwrap_or("-")
+                );
+            }
+            println!("Published:    {}", ui::date(game.published_at));
+            println!("Has demo:     {}", if game.has_demo { "yes" } else { "no" });
+            println!(
+                "Press system: {}",
+                if details.in_press_system { "yes" } else { "no" }
+            );
+            if let Some(short_text) = &game.short_text {
+                println!("Summary:      {}", short_text);
+            }
+        }
+    }
+    Ok(RunStatus::Success)
+}
+
+/// The game `game_id` if the account created it, `what` saying what's limited to own games
+///
+/// itch.io's own answer for someone else's game doesn't say why, so ownership is checked first.
+async fn ensure_own_game(client: &ItchClient, game_id: u64, what: &str) -> Result<Game> {
+    let my_games = client.list_my_games().await?;
+    match my_games.into_iter().find(|game| game.id == game_id) {
+        Some(game) => Ok(game),
+        None => anyhow::bail!(
+            "You don't own game {}, {} for games you created (see `itch-downloader ls --mine`)",
+            game_id,
+            what
+        ),
+    }
+}
+
+async fn archive_reviews(
+    ctx: &CommandContext,
+    api_key: String,
+    game_id: u64,
+    output_path: PathBuf,
+) -> Result<RunStatus> {
+    let client = ctx.new_client(api_key)?;
+    let game = ensure_own_game(&client, game_id, "reviews can only be archived").await?;
+    if !mirror::save_reviews(&client, &game, &output_path, &ctx.layout).await? {
+        ui::status!("itch.io doesn't make reviews of {} available.", game.title);
+        return Ok(RunStatus::NothingMatched);
+    }
+    ui::status!(
+        "Saved the reviews of {} to {}",
+        game.title,
+        output_path.join(ctx.layout.game_dir(&game)).display()
+    );
+    Ok(RunStatus::Success)
+}
+
+async fn list_purchases(
+    ctx: &CommandContext,
+    api_key: String,
+    game_id: u64,
+    filter: PurchaseFilter,
+    format: PurchasesFormat,
+) -> Result<RunStatus> {
+    let client = ctx.new_client(api_key)?;
+    ensure_own_game(&client, game_id, "purchases can only be listed").await?;
+    let purchases = client.list_purchases(game_id, &filter).await?;
+
+    match format {
+        PurchasesFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&purchases).context("Failed to serialize purchases")?
+        ),
+        PurchasesFormat::Csv => {
+            println!("id,created_at,price,currency,sale_rate,donation,source,email");
+            for purchase in &purchases {
+                let fields = [
+                    purchase.id.to_string(),
+                    purchase
+                        .created_at
+                        .map(timestamp::to_rfc3339)
+                        .unwrap_or_default(),
+                    purchase.price.clone().unwrap_or_default(),
+                    purchase.currency.clone().unwrap_or_default(),
+                    purchase.sale_rate.to_string(),
+                    purchase.donation.to_string(),
+                    purchase.source.clone().unwrap_or_default(),
+                    purchase.email.clone().unwrap_or_default(),
+                ];
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|field| export::csv_field(field))
+                    .collect();
+                println!("{}", fields.join(","));
+            }
+        }
+        PurchasesFormat::Table => {
+            if purchases.is_empty() {
+                ui::status!("No purchases found.");
+                return Ok(RunStatus::NothingMatched);
+            }
+            println!("ID         Date                      Price Source     Email");
+            println!(
+                "{:-<10} {:-<20} {:->10} {:-<10} {:-<30}",
+                "", "", "", "", ""
+            );
+            for purchase in &purchases {
+                let price = match (&purchase.price, purchase.donation) {
+                    (Some(price), true) => format!("{} (donation)", price),
+                    (Some(price), false) => price.clone(),
+                    (None, _) => "-".to_string(),
+                };
+                println!(
+                    "{:<10} {:<20} {:>10} {:<10} {}",
+                    purchase.id,
+                    truncate_to_width(&ui::date(purchase.created_at), 20),
+                    price,
+                    truncate_to_width(purchase.source.as_deref().unwrap_or("-"), 10),
+                    purchase.email.as_deref().unwrap_or("-")
+                );
+            }
+        }
+    }
+    if purchases.is_empty() {
+        return Ok(RunStatus::NothingMatched);
+    }
+    Ok(RunStatus::Success)
+}
+
+async fn lookup_key(
+    ctx: &CommandContext,
+    api_key: String,
+    game_id: u64,
+    lookup: KeyLookup,
+    format: OutputFormat,
+) -> Result<RunStatus> {
+    let client = ctx.new_client(api_key)?;
+    ensure_own_game(&client, game_id, "download keys can only be looked up").await?;
+    let key = client.lookup_download_key(game_id, &lookup).await?;
+
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&key).context("Failed to serialize download key")?
+        ),
+        OutputFormat::Table => {
+            let status = if key.owner.is_some() {
+                "claimed"
+            } else {
+                "unclaimed"
+            };
+            let owner =
+                key.owner
+                    .as_ref()
+                    .map_or("-".to_string(), |owner| match &owner.display_name {
+                        Some(name) => format!("{} ({})", owner.username, name),
+                        None => owner.username.clone(),
+                    });
+            println!("Key id:    {}", key.id);
+            println!("Key:       {}", key.key.as_deref().unwrap_or("-"));
+            println!("Status:    {}", status);
+            println!("Owner:     {}", owner);
+            println!("Downloads: {}", key.downloads);
+            println!("Created:   {}", ui::date(key.created_at));
+        }
+    }
+    Ok(RunStatus::Success)
+}
+
+async fn whoami(ctx: &CommandContext, api_key: String, format: OutputFormat) -> Result<RunStatus> {
+    let client = ctx.new_client(api_key)?;
+    let user = match client.get_profile().await {
+        Err(ItchError::LegacyKey) => return describe_legacy_key(&client, format).await,
+        user => user?,
+    };
+
+    let profile = ctx.layout.profile.as_deref();
+    match format {
+        OutputFormat::Json => {
+            let mut value = serde_json::to_value(&user).context("Failed to serialize profile")?;
+            value["config_profile"] = serde_json::json!(profile);
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&value).context("Failed to serialize profile")?
+            )
+        }
+        OutputFormat::Table => {
+            println!("Profile:      {}", profile.unwrap_or("(default)"));
+            println!("Username:     {}", user.username);
+            println!(
+                "Display name: {}",
+                user.display_name.as_deref().unwrap_or("-")
+            );
+            println!("User id:      {}", user.id);
+            println!("URL:          {}", user.url);
+        }
+    }
+    Ok(RunStatus::Success)
+}
+
+/// What `whoami` shows for a legacy key, which has no profile to show
+async fn describe_legacy_key(client: &ItchClient, format: OutputFormat) -> Result<RunStatus> {
+    let info = client.credentials_info().await?;
+    match format {
+        OutputFormat::Json => {
+            let mut value = serde_json::to_value(&info).context("Failed to serialize key info")?;
+            value["api"] = serde_json::json!("legacy");
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&value).context("Failed to serialize key info")?
+            )
+        }
+        OutputFormat::Table => {
+            println!("API:     legacy server-side (itch.io/api/1)");
+            println!("Type:    {}", info.key_type);
+            println!(
+                "Scopes:  {}",
+                if info.scopes.is_empty() {
+                    "-".to_string()
+                } else {
+                    info.scopes.join(", ")
+                }
+            );
+            println!("Expires: {}", info.expires_at.as_deref().unwrap_or("never"));
+            println!();
+            println!("{}", ItchError::LegacyKey);
+        }
+    }
+    Ok(RunStatus::Success)
+}
+
+async fn list_bundle(
+    ctx: &CommandContext,
+    args: BundleArgs,
+    format: OutputFormat,
+) -> Result<RunStatus> {
+    let entries = args.entries(ctx).await?;
+
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&entries).context("Failed to serialize bundle")?
+        ),
+        OutputFormat::Table => {
+            if entries.is_empty() {
+                ui::status!("No games found, check the bundle URL.");
+                return Ok(RunStatus::NothingMatched);
+            }
+            println!("{:<10} {:<9} {:<40}", "ID", "Claimed", "Title");
+            println!("{:-<10} {:-<9} {:-<40}", "", "", "");
+            for entry in entries {
+                let claimed = if entry.claimed { "yes" } else { "no" };
+                let title = truncate_to_width(&entry.title, 40);
+                println!("{:<10} {:<9} {}", entry.game_id, claimed, title);
+            }
+        }
+    }
+    Ok(RunStatus::Success)
+}
+
+/// Claim a bundle's games with `--claim` and download the claimed ones like `dl`
+async fn download_bundle(
+    ctx: &CommandContext,
+    api_key: String,
+    args: BundleArgs,
+    claim: bool,
+    download: DownloadArgs,
+) -> Result<RunStatus> {
+    let entries = args.entries(ctx).await?;
+    let unclaimed: Vec<_> = entries.iter().filter(|entry| !entry.claimed).collect();
+
+    let mut games: std::collections::HashSet<u64> = entries
+        .iter()
+        .filter(|entry| entry.claimed)
+        .map(|entry| entry.game_id)
+        .collect();
+    if claim && !unclaimed.is_empty() {
+        let url = bundle::bundle_url(&args.bundle)?;
+        let session = args.session()?;
+        let client = ctx.new_client(String::new())?;
+        for (index, entry) in unclaimed.iter().enumerate() {
+            ui::status!(
+                "Claiming {} of {}: {}",
+                index + 1,
+                unclaimed.len(),
+                entry.title
+            );
+            match bundle::claim(client.http(), &url, &session, entry).await {
+                Ok(()) => {
+                    games.insert(entry.game_id);
+                }
+                // Every other claim would be refused the same way
+                Err(e @ ItchError::SessionRejected) => return Err(e.into()),
+                Err(e) => ui::report!("Failed to claim {}: {}", entry.title, e),
+            }
+            // Claims are page loads on itch.io, so go easy on it
+            sleep(Duration::from_millis(1000)).await;
+        }
+    } else if !unclaimed.is_empty() {
+        ui::report!(
+            "Skipping {} unclaimed games, pass --claim to add them to your library first",
+            unclaimed.len()
+        );
+    }
+    if games.is_empty() {
+        ui::status!("No claimed games to download.");
+        return Ok(RunStatus::NothingMatched);
+    }
+
+    let mut options = download.download_options(ctx)?;
+    options.games = Some(games);
+    cancel_on_signal(options.shutdown.clone(), options.abort.clone());
+    download_packages(ctx, api_key, options, download.notify_desktop()).await
+}
+
+async fn claim_game(
+    ctx: &CommandContext,
+    api_key: String,
+    url: String,
+    session: Option<String>,
+    and_download: bool,
+    download: DownloadArgs,
+) -> Result<RunStatus> {
+    let page_url = claim::game_url(&url)?;
+    let session = session_cookie(session);
+    let client = ctx.new_client(api_key.clone())?;
+    // A bad key fails here, before anything is claimed
+    client.get_profile().await?;
+
+    ui::status!("Looking up {}...", page_url);
+    // Fetched as the logged in user, since the claim has to carry the page's form token
+    let page = claim::fetch_page(client.http(), &page_url, session.as_deref()).await?;
+    let library = mirror::fetch_library(&client, progress::show).await?;
+    if library.iter().any(|key| key.game.id == page.game_id) {
+        ui::status!("{} is already in your library.", page.title);
+    } else if page.requires_purchase() {
+        anyhow::bail!(
+            "{} costs {}, it has to be bought on itch.io before it can be downloaded",
+            page.title,
+            page.price.as_deref().unwrap_or_default()
+        );
+    } else {
+        let session = session.ok_or_else(|| session_required("claim games"))?;
+        claim::claim(client.http(), &page, &session).await?;
+        ui::report!("Claimed {}, it's now in your library.", page.title);
+    }
+    if !and_download {
+        return Ok(RunStatus::Success);
+    }
+
+    let mut options = download.download_options(ctx)?;
+    options.games = Some(std::collections::HashSet::from([page.game_id]));
+    cancel_on_signal(options.shutdown.clone(), options.abort.clone());
+    download_packages(ctx, api_key, options, download.notify_desktop()).await
+}
+
+async fn download_jam(
+    ctx: &CommandContext,
+    api_key: String,
+    jam_ref: String,
+    download: DownloadArgs,
+) -> Result<RunStatus> {
+    let client = ctx.new_client(api_key.clone())?;
+    client.get_profile().await?;
+    ui::status!("Fetching jam entries...");
+    let jam = jam::fetch(client.http(), &jam_ref).await?;
+    ui::status!("Found {} entries in {}.", jam.entries.len(), jam.title);
+
+    // Entries already in the library are downloaded with their keys, paid or not
+    let library: std::collections::HashMap<u64, OwnedKey> =
+        mirror::fetch_library(&client, progress::show)
+            .await?
+            .into_iter()
+            .map(|key| (key.game_id, key))
+            .collect();
+    let mut seen = std::collections::HashSet::new();
+    let mut keys = Vec::new();
+    let mut paid = 0;
+    for entry in jam.entries {
+        if !seen.insert(entry.game.id) {
+            continue;
+        }
+        match library.get(&entry.game.id) {
+            Some(key) => keys.push(key.clone()),
+            None if entry.game.is_paid() => {
+                paid += 1;
+                ui::report!(
+                    "Skipping {}, it costs {} and isn't in your library",
+                    entry.game.title,
+                    entry.game.price.as_deref().unwrap_or_default()
+                );
+            }
+            None => keys.push(OwnedKey::keyless(entry.game.into())),
+        }
+    }
+    if paid > 0 {
+        ui::status!("Skipped {} paid entries you don't own.", paid);
+    }
+    if keys.is_empty() {
+        ui::status!("No entries to download.");
+        return Ok(RunStatus::NothingMatched);
+    }
+
+    let mut options = download.download_options(ctx)?;
+    options.output_path = options
+        .output_path
+        .join(layout::game_dir_name(&jam.title, jam.id));
+    options.per_game_dirs = true;
+    options.keys = Some(keys);
+    cancel_on_signal(options.shutdown.clone(), options.abort.clone());
+    download_packages(ctx, api_key, options, download.notify_desktop()).await
+}
+
+async fn list_collections(
+    ctx: &CommandContext,
+    api_key: String,
+    format: OutputFormat,
+) -> Result<RunStatus> {
+    let collections = ctx.new_client(api_key)?.list_collections().await?;
+
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&collections)
+                .context("Failed to serialize collections")?
+        ),
+        OutputFormat::Table => {
+            if collections.is_empty() {
+                ui::status!("No collections found.");
+                return Ok(RunStatus::NothingMatched);
+            }
+            println!("{:<10} {:<6} {:<40}", "ID", "Games", "Title");
+            println!("{:-<10} {:-<6} {:-<40}", "", "", "");
+            for collection in collections {
+                let title = truncate_to_width(&collection.title, 40);
+                println!(
+                    "{:<10} {:<6} {}",
+                    collection.id, collection.games_count, title
+                );
+            }
+        }
+    }
+    Ok(RunStatus::Success)
+}
+
+/// Which games `ls` lists
+enum ListSource {
+    Library,
+    /// `--mine`, the games the account created
+    Mine,
+    /// `--demos`, the library's games with a demo or the games behind `--demo-of`
+    Demos(Vec<String>),
+}
+
+/// The optional columns of `ls`, and how values too long for theirs are shown
+struct ListColumns {
+    downloads: bool,
+    origin: bool,
+    slug: bool,
+    /// `None` truncates them
+    overflow: Option<Overflow>,
+}
+
+/// How `ls --no-truncate` shows values longer than their column
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Overflow {
+    /// Continue them on the following lines, within their column
+    Wrap,
+    /// Print each game as a block of `Field: value` lines instead of a table
+    Records,
+}
+
+/// A value in an `ls` row
+struct ListCell {
+    header: &'static str,
+    text: String,
+    /// How wide the text may get before it's wrapped
+    width: usize,
+    /// How wide the column is, at least `width` so there's a gap before the next one
+    column: usize,
+}
+
+impl ListCell {
+    fn new(header: &'static str, text: impl Into<String>, width: usize, column: usize) -> Self {
+        Self {
+            header,
+            text: text.into(),
+            width,
+            column,
+        }
+    }
+}
+
+/// Print a row of `ls` in full, wrapped within its columns or as a record
+fn print_full_row(cells: &[ListCell], overflow: Overflow) {
+    for line in full_row_lines(cells, overflow, ui::terminal_width()) {
+        println!("{}", line);
+    }
+}
+
+/// The lines of a row of `ls` in full, see [`print_full_row`]
+///
+/// The last column takes the rest of the `terminal`'s width when stdout is one. Records are only
+/// wrapped on a terminal, so piped output keeps each value on one line.
+fn full_row_lines(cells: &[ListCell], overflow: Overflow, terminal: Option<usize>) -> Vec<String> {
+    let mut output = Vec::new();
+    match overflow {
+        Overflow::Wrap => {
+            let leading: usize = cells.iter().rev().skip(1).map(|cell| cell.column + 1).sum();
+            let wrapped: Vec<Vec<String>> = cells
+                .iter()
+                .enumerate()
+                .map(|(i, cell)| {
+                    let width = match terminal {
+                        Some(terminal) if i + 1 == cells.len() => {
+                            terminal.saturating_sub(leading).max(MIN_WRAP_WIDTH)
+                        }
+                        _ => cell.width,
+                    };
+                    wrap_to_width(&cell.text, width)
+                })
+                .collect();
+            let height = wrapped.iter().map(Vec::len).max().unwrap_or(1);
+            for line in 0..height {
+                let row: Vec<String> = cells
+                    .iter()
+                    .zip(&wrapped)
+                    .map(|(cell, lines)| {
+                        pad_to_width(lines.get(line).map_or("", String::as_str), cell.column)
+                    })
+                    .collect();
+                output.push(row.join(" ").trim_end().to_string());
+            }
+        }
+        Overflow::Records => {
+            let label_width = cells
+                .iter()
+                .map(|cell| cell.header.width())
+                .max()
+                .unwrap_or(0)
+                + 2;
+            let width = terminal.map_or(usize::MAX, |terminal| {
+                terminal.saturating_sub(label_width).max(MIN_WRAP_WIDTH)
+            });
+            for cell in cells {
+                let label = pad_to_width(&format!("{}:", cell.header), label_width);
+                for (i, line) in wrap_to_width(cell.text.trim(), width).iter().enumerate() {
+                    let label = if i == 0 { label.as_str() } else { "" };
+                    output.push(format!("{}{}", pad_to_width(label, label_width), line));
+                }
+            }
+            output.push(String::new());
+        }
+    }
+    output
+}
+
+/// Narrowest a wrapped value gets on a terminal too narrow for the columns before it
+const MIN_WRAP_WIDTH: usize = 10;
+
+async fn list_packages(
+    ctx: &CommandContext,
+    api_key: String,
+    filter: KeyFilter,
+    filter_rules: Option<FilterFile>,
+    source: ListSource,
+    columns: ListColumns,
+) -> Result<RunStatus> {
+    let mine = matches!(source, ListSource::Mine);
+    let client = ctx.new_client(api_key)?;
+    // A bad key fails here, before anything else is printed. Own games are the one listing a
+    // legacy key can fetch, and that fails just as early
+    if !mine {
+        client.get_profile().await?;
+    }
+    let owned_keys = match &source {
+        ListSource::Mine => mirror::fetch_own_games(&client, progress::show).await?,
+        ListSource::Library => mirror::fetch_library(&client, progress::show).await?,
+        ListSource::Demos(games) if games.is_empty() => {
+            let mut keys = mirror::fetch_library(&client, progress::show).await?;
+            keys.retain(|key| key.game.has_demo);
+            keys
+        }
+        ListSource::Demos(games) => mirror::demo_keys(&client, games, progress::show).await?,
+    };
+
+    let mut filtered_keys = owned_keys;
+
+    let mut exclusions = filters::Exclusions::default();
+    filtered_keys.retain(|key| exclusions.count(&filter, key));
+    let mut ruled_out = 0;
+    if let Some(rules) = &filter_rules {
+        let before = filtered_keys.len();
+        filtered_keys.retain(|key| rules.matches(key));
+        ruled_out = before - filtered_keys.len();
+        ui::status!(
+            "The filter file kept {} of {} games.",
+            filtered_keys.len(),
+            before
+        );
+    }
+
+    if filtered_keys.is_empty() {
+        match source {
+            ListSource::Demos(_) if exclusions.seen == 0 => ui::status!("No demos found."),
+            _ => report_nothing_matched(mine, &exclusions, &[("--filter-file", ruled_out)]),
+        }
+        return Ok(RunStatus::NothingMatched);
+    }
+
+    let records = columns.overflow == Some(Overflow::Records);
+    if mine {
+        ui::status!("Your itch.io games:");
+        if !records {
+            println!(
+                "{:<8} {:<10} {:>10} {:>10} {:<40}",
+                "ID", "Status", "Downloads", "Views", "Title"
+            );
+            println!("{:-<8} {:-<10} {:->10} {:->10} {:-<40}", "", "", "", "", "");
+        }
+        let count = |count: Option<u64>| count.map_or("-".to_string(), |count| count.to_string());
+        for key in filtered_keys {
+            let status = match key.game.published {
+                Some(false) => "draft",
+                _ => "published",
+            };
+            if let Some(overflow) = columns.overflow {
+                let cells = [
+                    ListCell::new("ID", key.game.id.to_string(), 8, 8),
+                    ListCell::new("Status", status, 10, 10),
+                    ListCell::new(
+                        "Downloads",
+                        format!("{:>10}", count(key.game.downloads_count)),
+                        10,
+                        10,
+                    ),
+                    ListCell::new(
+                        "Views",
+                        format!("{:>10}", count(key.game.views_count)),
+                        10,
+                        10,
+                    ),
+                    ListCell::new("Title", key.game.title, 40, 40),
+                ];
+                print_full_row(&cells, overflow);
+                continue;
+            }
+            println!(
+                "{:<8} {:<10} {:>10} {:>10} {}",
+                key.game.id,
+                status,
+                count(key.game.downloads_count),
+                count(key.game.views_count),
+                truncate_to_width(&key.game.title, 40)
+            );
+        }
+        return Ok(RunStatus::Success);
+    }
+
+    if matches!(source, ListSource::Demos(_)) {
+        ui::status!("Games with a demo, see `dl --demos-only`:");
+    } else {
+        ui::status!("Your itch.io packages:");
+    }
+    let mut header = format!("{:<8} ", "ID");
+    let mut rule = format!("{:-<8} ", "");
+    if columns.downloads {
+        header += &format!("{:>10} ", "Downloads");
+        rule += &format!("{:->10} ", "");
+    }
+    if columns.origin {
+        header += &format!("{} ", pad_to_width("Bundle", 24));
+        rule += &format!("{:-<24} ", "");
+    }
+    if columns.slug {
+        header += &format!("{} ", pad_to_width("Slug", 30));
+        rule += &format!("{:-<30} ", "");
+    }
+    if !records {
+        println!("{}{:<20} {:<40}", header, "Author", "Title");
+        println!("{}{:-<20} {:-<40}", rule, "", "");
+    }
+
+    for key in filtered_keys {
+        if let Some(overflow) = columns.overflow {
+            let mut cells = vec![ListCell::new("ID", key.game.id.to_string(), 8, 8)];
+            if columns.downloads {
+                let downloads = format!("{:>10}", key.downloads);
+                cells.push(ListCell::new("Downloads", downloads, 10, 10));
+            }
+            if columns.origin {
+                cells.push(ListCell::new("Bundle", key.origin(), 21, 24));
+            }
+            if columns.slug {
+                let slug = key.game.slug().unwrap_or_else(|| "-".to_string());
+                cells.push(ListCell::new("Slug", slug, 27, 30));
+            }
+            let author = (key.game.user.display_name.clone())
+                .unwrap_or_else(|| key.game.user.username.clone());
+            cells.push(ListCell::new("Author", author, 17, 20));
+            cells.push(ListCell::new("Title", key.game.title, 37, 40));
+            print_full_row(&cells, overflow);
+            continue;
+        }
+        let title = truncate_to_width(&key.game.title, 37);
+        let title_padded = pad_to_width(&title, 40);
+
+        let mut row = format!("{:<8} ", key.game.id);
+        if columns.downloads {
+            row += &format!("{:>10} ", key.downloads);
+        }
+        if columns.origin {
+            row += &format!(
+                "{} ",
+                pad_to_width(&truncate_to_width(key.origin(), 21), 24)
+            );
+        }
+        if columns.slug {
+            let slug = key.game.slug().unwrap_or_else(|| "-".to_string());
+            row += &format!("{} ", pad_to_width(&truncate_to_width(&slug, 27), 30));
+        }
+
+        let author_name = key.game.user.display_name.unwrap_or(key.game.user.username);
+        let author = truncate_to_width(&author_name, 17);
+        let author_padded = pad_to_width(&author, 20);
+
+        println!("{}{} {}", row, author_padded, title_padded);
+    }
+
+    Ok(RunStatus::Success)
+}
+
+/// Say why the filters selected nothing, whether they turned every key away or `--retry-failed`
+/// found none of the games that failed
+fn report_unselected(selection: &KeySelection, mine: bool) -> RunStatus {
+    if selection.failed_games.is_some() {
+        ui::status!("None of the games that failed last time were selected.");
+    } else {
+        report_nothing_matched(
+            mine,
+            &selection.exclusions,
+            &[("--filter-file", selection.ruled_out)],
+        );
+    }
+    RunStatus::NothingMatched
+}
+
+/// Say which check left nothing to download and how the run ends
+fn nothing_selected(selection: &KeySelection, mine: bool) -> RunStatus {
+    if selection.matched == 0 {
+        return report_unselected(selection, mine);
+    }
+    match selection.cutoff.as_ref().filter(|_| selection.recent == 0) {
+        Some(cutoff) => ui::status!("No purchases since {}.", cutoff),
+        None => ui::status!("No new games since the last check."),
+    }
+    RunStatus::Success
+}
+
+/// Run a download with bars for its progress, then print what it did
+///
+/// With `desktop` a notification sums the run up once it's done.
+async fn download_packages(
+    ctx: &CommandContext,
+    api_key: String,
+    mut options: mirror::DownloadOptions,
+    desktop: bool,
+) -> Result<RunStatus> {
+    let mine = options.mine;
+    options.run_args = redacted_args(&api_key);
+    let client = ctx.new_client(api_key)?;
+    let view = std::sync::Arc::new(progress::RunView::new(&client, &options));
+    let finished = mirror::download(&client, options, view.clone()).await;
+    view.clear();
+    let summary = match finished? {
+        mirror::Finished::NothingToRetry => {
+            ui::status!("No failed downloads to retry.");
+            return Ok(RunStatus::Success);
+        }
+        mirror::Finished::NothingSelected(selection) => {
+            return Ok(nothing_selected(&selection, mine));
+        }
+        mirror::Finished::Ran(summary) => summary,
+    };
+
+    let api_usage = client.api_usage();
+    ui::status!(
+        "API: {} requests, {} throttled (429)",
+        api_usage.requests,
+        api_usage.throttled
+    );
+    ui::status!("All downloads completed!");
+    if let Some(title) = &summary.first_failure {
+        ui::report!(
+            "Stopped early because {} failed (--fail-fast), games that hadn't started are listed as failed",
+            title
+        );
+    }
+    if let Some(remaining) = summary
+        .remaining_failures
+        .filter(|&remaining| remaining > 0)
+    {
+        ui::report!(
+            "{} downloads failed, run again with --retry-failed to retry just those",
+            remaining
+        );
+    }
+
+    if let Some(sync) = &summary.sync {
+        if sync.up_to_date > 0 {
+            ui::report!("{} games were already up to date", sync.up_to_date);
+        }
+        if !sync.updated.is_empty() {
+            ui::report!("Updated {} games:", sync.updated.len());
+            for update in &sync.updated {
+                ui::report!("  {}", update);
+            }
+        }
+        if !sync.downgrades.is_empty() {
+            ui::report!(
+                "Warning: itch serves an older version than the local copy of {} uploads:",
+                sync.downgrades.len()
+            );
+            for downgrade in &sync.downgrades {
+                ui::report!("  {}", downgrade);
+            }
+        }
+        if !sync.key_use.is_empty() {
+            ui::report!(
+                "Note: {} keys were downloaded with more often than this tool did since the last run, check nobody else is using them:",
+                sync.key_use.len()
+            );
+            for game in &sync.key_use {
+                ui::report!("  {}", game);
+            }
+        }
+    }
+
+    if !summary.seeded.is_empty() {
+        ui::report!(
+            "Seeded {} files locally instead of downloading {}:",
+            summary.seeded.len(),
+            indicatif::HumanBytes(summary.seeded.iter().map(|(_, bytes)| bytes).sum())
+        );
+        for (file, _) in &summary.seeded {
+            ui::report!("  {}", file);
+        }
+    }
+
+    if let Some((linked_files, saved_bytes)) =
+        summary.deduplicated.filter(|&(linked, _)| linked > 0)
+    {
+        ui::report!(
+            "Deduplicated {} files, saving {}",
+            linked_files,
+            indicatif::HumanBytes(saved_bytes)
+        );
+    }
+
+    if let Some((used, limit)) = summary.budget {
+        ui::report!(
+            "Used {} of the {} budget",
+            indicatif::HumanBytes(used),
+            indicatif::HumanBytes(limit)
+        );
+        if !summary.deferred.is_empty() {
+            ui::report!(
+                "{} downloads were deferred to a later run by --max-total-size:",
+                summary.deferred.len()
+            );
+            for download in &summary.deferred {
+                let size = match download.size {
+                    Some(size) => indicatif::HumanBytes(size).to_string(),
+                    None => "unknown size".to_string(),
+                };
+                ui::report!("  {} ({}, {})", download.filename, download.title, size);
+            }
+        }
+    }
+
+    if !summary.not_extracted.is_empty() {
+        ui::report!(
+            "{} archives were downloaded but not extracted:",
+            summary.not_extracted.len()
+        );
+        for archive in &summary.not_extracted {
+            ui::report!("  {}", archive);
+        }
+    }
+
+    if !summary.extract_conflicts.is_empty() {
+        ui::report!(
+            "{} archives would have been extracted over another upload's files:",
+            summary.extract_conflicts.len()
+        );
+        for conflict in &summary.extract_conflicts {
+            ui::report!("  {}", conflict);
+        }
+    }
+
+    let outcomes = &summary.outcomes;
+    if let Some(report_path) = &summary.report_path {
+        print_results_table(outcomes);
+        ui::status!("Wrote report to {}", report_path.display());
+        if let Some(path) = &summary.run_manifest {
+            ui::status!(
+                "Wrote {}, `promote` merges the run into the output directory",
+                path.display()
+            );
+        }
+        if desktop {
+            notify::show(&report::Report::new(summary.started_at, outcomes)).await;
+        }
+    }
+
+    let count = |status| {
+        outcomes
+            .iter()
+            .filter(|outcome| outcome.status == status)
+            .count()
+    };
+    events::emit(&events::Event::Summary {
+        completed: count(history::Status::Completed),
+        skipped: count(history::Status::Skipped),
+        failed: count(history::Status::Failed),
+        gone: count(history::Status::Gone),
+        key_limit: count(history::Status::KeyLimit),
+        bytes: outcomes.iter().map(|outcome| outcome.bytes).sum(),
+        requests: api_usage.requests,
+        throttled: api_usage.throttled,
+    });
+
+    let gone: Vec<&Outcome> = outcomes
+        .iter()
+        .filter(|outcome| outcome.status == history::Status::Gone)
+        .collect();
+    if !gone.is_empty() {
+        ui::report!(
+            "{} games have been deleted from itch.io (listed under `gone` in the report):",
+            gone.len()
+        );
+        for outcome in &gone {
+            ui::report!("  {} ({})", outcome.title, outcome.game_id);
+        }
+    }
+
+    let key_limited: Vec<&Outcome> = outcomes
+        .iter()
+        .filter(|outcome| outcome.status == history::Status::KeyLimit)
+        .collect();
+    if !key_limited.is_empty() {
+        ui::report!(
+            "itch.io refused the download keys of {} games as used up, revoked or expired (listed under `key_limit` in the report, and not retried by --retry-failed):",
+            key_limited.len()
+        );
+        for outcome in &key_limited {
+            ui::report!("  {} ({})", outcome.title, outcome.game_id);
+        }
+    }
+
+    Ok(if summary.interrupted {
+        RunStatus::Interrupted
+    } else if summary.failed || !summary.library_complete {
+        RunStatus::PartialFailure
+    } else {
+        RunStatus::Success
+    })
+}
+
+/// The command line with the API key and session cookie replaced, for `run.json`
+fn redacted_args(secret: &str) -> Vec<String> {
+    let mut redact_next = false;
+    std::env::args()
+        .map(|arg| {
+            let redact = std::mem::replace(&mut redact_next, false)
+                || (!secret.is_empty() && arg.contains(secret))
+                || arg.starts_with("--session=");
+            redact_next = matches!(arg.as_str(), "--api-key" | "-a" | "--session");
+            if redact {
+                "<redacted>".to_string()
+            } else {
+                arg
+            }
+        })
+        .collect()
+}
+
+/// Open `url` with the system's default handler
+fn open_in_browser(url: &str) -> std::io::Result<()> {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = std::process::Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    command
+        .arg(url)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .map(|_| ())
+}
+
+/// Print one row per game with how it went, failures last so they're what's left on screen
+fn print_results_table(outcomes: &[Outcome]) {
+    if outcomes.is_empty() {
+        return;
+    }
+
+    let mut rows: Vec<&Outcome> = outcomes.iter().collect();
+    rows.sort_by_key(|outcome| {
+        (
+            outcome.status == history::Status::Failed,
+            outcome.status == history::Status::KeyLimit,
+            outcome.status == history::Status::Gone,
+            outcome.title.to_lowercase(),
+        )
+    });
+
+    ui::report!();
+    ui::report!(
+        "{} {} {:<8} {:>10} Error",
+        pad_to_width("Game", 30),
+        pad_to_width("Upload", 30),
+        "Status",
+        "Size"
+    );
+    ui::report!("{:-<30} {:-<30} {:-<8} {:->10} {:-<30}", "", "", "", "", "");
+    for outcome in rows {
+        let game = pad_to_width(&truncate_to_width(&outcome.title, 30), 30);
+        let upload = pad_to_width(
+            &truncate_to_width(outcome.filename.as_deref().unwrap_or("-"), 30),
+            30,
+        );
+        let size = if outcome.bytes > 0 {
+            indicatif::HumanBytes(outcome.bytes).to_string()
+        } else {
+            "-".to_string()
+        };
+        // Only the first line, full errors are in the report
+        let error = outcome
+            .error
+            .as_deref()
+            .and_then(|error| error.lines().next())
+            .map(|error| truncate_to_width(error, 60))
+            .unwrap_or_default();
+        ui::report!(
+            "{} {} {:<8} {:>10} {}",
+            game,
+            upload,
+            if outcome.seeded {
+                "seeded"
+            } else {
+                outcome.status.label()
+            },
+            size,
+            error
+        );
+    }
+}
+
+/// Cancel `shutdown` on Ctrl-C or SIGTERM, then `abort` if it comes a second time and exit
+/// straight away on the third
+fn cancel_on_signal(shutdown: CancellationToken, abort: CancellationToken) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).ok();
+        for _ in 0..3 {
+            #[cfg(unix)]
+            let terminated = async {
+                match terminate.as_mut() {
+                    Some(terminate) => terminate.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let terminated = std::future::pending::<Option<()>>();
+
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminated => {}
+            }
+            if abort.is_cancelled() {
+                // Skips destructors, so the lock is left behind for the next run to take over
+                std::process::exit(EXIT_INTERRUPTED.into());
+            }
+            if shutdown.is_cancelled() {
+                ui::println(
+                    &ui::multi_progress(),
+                    "Stopping now, interrupt again to exit without cleaning up",
+                );
+                abort.cancel();
+                continue;
+            }
+            ui::println(
+                &ui::multi_progress(),
+                "Stopping after the current downloads finish, interrupt again to stop now",
+            );
+            shutdown.cancel();
+        }
+    });
+}
+
+/// Download newly acquired games every `interval` until interrupted
+async fn watch_packages(
+    ctx: &CommandContext,
+    api_key: String,
+    args: DownloadArgs,
+    watch: WatchArgs,
+) -> Result<RunStatus> {
+    args.check_dirs(ctx)?;
+    tokio::fs::create_dir_all(&args.output)
+        .await
+        .context("Failed to create output directory")?;
+    // Held across cycles rather than per run, so nothing sneaks in while we sleep
+    let _lock = if args.no_lock {
+        None
+    } else {
+        Some(lock::OutputLock::acquire(&args.output)?)
+    };
+    let shutdown = CancellationToken::new();
+    let abort = CancellationToken::new();
+    cancel_on_signal(shutdown.clone(), abort.clone());
+
+    let mut full_sync = watch.once_then_watch;
+    let mut cycle = 1;
+    while !shutdown.is_cancelled() {
+        ui::status!("Watch cycle {} starting", cycle);
+        let result = async {
+            let mut options = args.download_options(ctx)?;
+            options.lock = false;
+            // Nobody is there to answer between cycles
+            options.confirm_above = None;
+            options.shutdown = shutdown.clone();
+            options.abort = abort.clone();
+            options.sync = Some(mirror::SyncOptions {
+                manifest: std::sync::Mutex::new(Manifest::load(&args.output, &ctx.layout)?),
+                prune: false,
+                dry_run: false,
+                keep_old_versions: false,
+                allow_downgrade: false,
+                keep_downgraded: false,
+                new_only: !full_sync,
+            });
+            download_packages(ctx, api_key.clone(), options, args.notify_desktop()).await
+        }
+        .await;
+
+        match result {
+            Ok(status) => {
+                ui::status!("Watch cycle {} finished: {:?}", cycle, status);
+                full_sync = false;
+            }
+            // A revoked key won't fix itself by waiting
+            Err(e)
+                if e.downcast_ref::<ItchError>()
+                    .is_some_and(ItchError::is_auth) =>
+            {
+                return Err(e);
+            }
+            Err(e) => eprintln!("Watch cycle {} failed, will retry: {:?}", cycle, e),
+        }
+        cycle += 1;
+
+        tokio::select! {
+            _ = tokio::time::sleep(watch.interval) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+
+    ui::status!("Stopped watching");
+    Ok(if abort.is_cancelled() {
+        RunStatus::Interrupted
+    } else {
+        RunStatus::Success
+    })
+}
+
+/// Work out how one game's local copy compares to its current upload
+async fn check_game(
+    ctx: &CommandContext,
+    client: &ItchClient,
+    key: &OwnedKey,
+    output_path: &Path,
+    per_game_dirs: bool,
+    manifest: &Manifest,
+) -> (check::CheckEntry, Option<PendingCompare>) {
+    let mut entry = check::CheckEntry {
+        game_id: key.game_id,
+        title: key.game.title.clone(),
+        upload_id: None,
+        filename: None,
+        path: None,
+        status: check::CheckStatus::Error,
+        detail: None,
+    };
+
+    let uploads = match client.get_key_uploads(key, &CancellationToken::new()).await {
+        Ok(uploads) => uploads,
+        Err(e) => {
+            entry.detail = Some(format!("Failed to get uploads: {}", e));
+            return (entry, None);
+        }
+    };
+
+    // Whatever we recorded downloading wins, otherwise the upload `dl` would pick
+    let recorded = uploads.iter().find_map(|upload| {
+        manifest
+            .previous(key.game_id, upload.id)
+            .map(|record| (upload, record))
+    });
+    let Some(upload) = recorded
+        .map(|(upload, _)| upload)
+        .or_else(|| choose_upload(&uploads, &SelectionPolicy::default()))
+    else {
+        entry.detail = Some("No uploads found".to_string());
+        return (entry, None);
+    };
+    entry.upload_id = Some(upload.id);
+    entry.filename = Some(upload.filename.clone());
+
+    let game_dir_name = ctx.layout.game_dir(&key.game);
+    let dest_dir = if per_game_dirs {
+        output_path.join(&game_dir_name)
+    } else {
+        output_path.to_path_buf()
+    };
+    let md5 = upload.md5_hash.clone();
+
+    let (path, compare_local) =
+        match recorded {
+            Some((_, record)) => {
+                let path = manifest.resolve(&record.path);
+                if path.is_file() {
+                    (path, true)
+                } else if path.exists() {
+                    // Extracted, so compare what we recorded at download time against the server
+                    entry.path = Some(path);
+                    (entry.status, entry.detail) =
+                        if record.size != upload.size {
+                            (
+                                check::CheckStatus::SizeMismatch,
+                                Some(format!(
+                                    "downloaded {} bytes, remote now {} bytes",
+                                    record.size, upload.size
+                                )),
+                            )
+                        } else if record.md5.as_deref().zip(md5.as_deref()).is_some_and(
+                            |(recorded, remote)| !recorded.eq_ignore_ascii_case(remote),
+                        ) {
+                            (
+                                check::CheckStatus::Md5Mismatch,
+                                Some("replaced on itch since it was downloaded".to_string()),
+                            )
+                        } else {
+                            (check::CheckStatus::Ok, None)
+                        };
+                    return (entry, None);
+                } else {
+                    (path, false)
+                }
+            }
+            None => {
+                let filename = upload_filename(&upload.filename, upload.id);
+                // With --organize the sidecar says which folder the file was sorted into
+                let organized = export::GameMetadata::find(&dest_dir)
+                    .filter(|_| per_game_dirs)
+                    .and_then(|metadata| metadata.organized.get(&filename).copied());
+                let dest_dir = match organized {
+                    Some(category) => dest_dir.join(category.dir_name()),
+                    None => dest_dir,
+                };
+                let path = dest_dir.join(&filename);
+                if path.is_file() {
+                    (path, true)
+                } else {
+                    let extract_dir = if per_game_dirs {
+                        dest_dir.join(extract::strip_archive_extension(&filename))
+                    } else {
+                        output_path.join(&game_dir_name)
+                    };
+                    if extract_dir.is_dir() {
+                        entry.path = Some(extract_dir);
+                        entry.status = check::CheckStatus::Unverified;
+                        entry.detail = Some("extracted without a record to compare".to_string());
+                        return (entry, None);
+                    }
+                    (path, false)
+                }
+            }
+        };
+
+    entry.path = Some(path.clone());
+    if !compare_local {
+        entry.status = check::CheckStatus::Missing;
+        return (entry, None);
+    }
+
+    // Hashed once every game is listed, see `check_mirror`
+    let pending = PendingCompare {
+        path,
+        size: upload.size,
+        md5,
+    };
+    (entry, Some(pending))
+}
+
+/// A local file `check` still has to compare against its upload
+struct PendingCompare {
+    path: PathBuf,
+    size: u64,
+    md5: Option<String>,
+}
+
+/// Compare a file against its upload on the hash pool, advancing `bar` by the bytes hashed
+async fn compare_pending(
+    pool: &check::HashPool,
+    mut entry: check::CheckEntry,
+    pending: PendingCompare,
+    bar: ProgressBar,
+) -> check::CheckEntry {
+    let result = pool
+        .run(move || {
+            let mut hashed = 0;
+            let result = check::compare_file(
+                &pending.path,
+                pending.size,
+                pending.md5.as_deref(),
+                |read| {
+                    hashed += read;
+                    bar.inc(read);
+                },
+            );
+            // A file that wasn't hashed to the end still counts as done
+            if pending.md5.is_some() {
+                bar.inc(pending.size.saturating_sub(hashed));
+            }
+            result
+        })
+        .await;
+    match result {
+        Ok((status, detail)) => {
+            entry.status = status;
+            entry.detail = detail;
+        }
+        Err(e) => entry.detail = Some(format!("{:#}", e)),
+    }
+    entry
+}
+
+/// Print what `dl` would do without downloading anything, or with `diff_against` how the
+/// mirror it describes compares to the plan, as `sync --dry-run --diff` does
+async fn plan_downloads(
+    ctx: &CommandContext,
+    api_key: String,
+    options: mirror::DownloadOptions,
+    format: OutputFormat,
+    diff_against: Option<Manifest>,
+) -> Result<RunStatus> {
+    let client = ctx.new_client(api_key)?;
+    client.get_profile().await?;
+    let collection_games = match &options.collection {
+        Some(collection) => {
+            Some(mirror::fetch_collection(&client, collection, progress::show).await?)
+        }
+        None => None,
+    };
+    let mut filtered_keys = if !options.demo_of.is_empty() {
+        mirror::demo_keys(&client, &options.demo_of, progress::show).await?
+    } else if options.mine {
+        mirror::fetch_own_games(&client, progress::show).await?
+    } else {
+        mirror::fetch_library(&client, progress::show).await?
+    };
+    // Every game the output could hold something for, so what isn't selected can be told apart
+    // from what nothing accounts for
+    let library: Vec<(String, u64, String)> = filtered_keys
+        .iter()
+        .map(|key| {
+            let name = ctx.layout.game_dir(&key.game);
+            (name, key.game_id, key.game.title.clone())
+        })
+        .collect();
+    if let Some(games) = &collection_games {
+        let owned_game_ids = filtered_keys.iter().map(|key| key.game_id).collect();
+        mirror::report_unowned_collection_games(games, &owned_game_ids, progress::show);
+    }
+
+    // The same checks `dl` makes, but with --since applied afterwards, as the games it leaves out
+    // are still selected and their files aren't orphans
+    let mut selection = KeySelection {
+        failed_games: options
+            .retry_failed
+            .then(|| failures::load(&options.output_path, &ctx.layout))
+            .transpose()?
+            .map(|failed| failed.iter().map(|item| item.game_id).collect()),
+        ..mirror::filter_selection(
+            options.author.clone(),
+            options.title.clone(),
+            options.from_bundle.clone(),
+            options.slug.clone(),
+            options.filter_rules.clone(),
+            collection_games.as_deref(),
+        )
+    };
+    filtered_keys.retain(|key| selection.admit(key));
+    selection.log();
+    if filtered_keys.is_empty() && !options.retry_failed {
+        return Ok(report_unselected(&selection, options.mine));
+    }
+    let selected: std::collections::HashSet<u64> = if options.retry_failed {
+        library.iter().map(|(_, game_id, _)| *game_id).collect()
+    } else {
+        filtered_keys.iter().map(|key| key.game_id).collect()
+    };
+    let cutoff = match &options.since {
+        Some(since) => since.cutoff(&options.output_path, &ctx.layout)?,
+        None => None,
+    };
+    if let Some(cutoff) = &cutoff {
+        filtered_keys.retain(|key| since::is_newer(key.created_at, *cutoff));
+    }
+    if filtered_keys.is_empty() && diff_against.is_none() {
+        ui::status!("No packages found to download.");
+        return Ok(RunStatus::NothingMatched);
+    }
+
+    let plan = client
+        .plan_downloads(
+            &filtered_keys,
+            &options.plan_options(),
+            &CancellationToken::new(),
+        )
+        .await;
+    if let Some(manifest) = diff_against {
+        let report = mirror_status(&plan, &manifest, &library, &selected, &options.output_path)?;
+        match format {
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&report).context("Failed to serialize status")?
+            ),
+            OutputFormat::Table => print_status_table(&report),
+        }
+        return Ok(RunStatus::Success);
+    }
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&plan).context("Failed to serialize plan")?
+        ),
+        OutputFormat::Table => print_plan_table(&plan),
+    }
+    Ok(RunStatus::Success)
+}
+
+/// Sort the planned uploads by how they compare to `manifest`, and the top level entries of the
+/// output the way `prune` would, `library` being every owned game's directory name, id and title
+fn mirror_status(
+    plan: &plan::DownloadPlan,
+    manifest: &Manifest,
+    library: &[(String, u64, String)],
+    selected: &std::collections::HashSet<u64>,
+    output_path: &Path,
+) -> Result<status::StatusReport> {
+    let mut entries: Vec<status::StatusEntry> = plan
+        .games
+        .iter()
+        .map(|entry| status::StatusEntry::planned(entry, manifest))
+        .collect();
+
+    let mut expected = std::collections::HashSet::new();
+    let mut owners = std::collections::HashMap::new();
+    for (name, game_id, title) in library {
+        if selected.contains(game_id) {
+            expected.insert(name.clone());
+        }
+        owners.insert(name.clone(), (*game_id, title.as_str()));
+    }
+    for (game_id, game) in manifest.games() {
+        for upload in game.uploads.values() {
+            let Some(name) = manifest::top_level_name(&upload.path) else {
+                continue;
+            };
+            if selected.contains(&game_id) {
+                expected.insert(name.clone());
+            }
+            owners.entry(name).or_insert((game_id, game.title.as_str()));
+        }
+    }
+    if output_path.is_dir() {
+        let attributable = owners.keys().cloned().collect();
+        let ignore = itchignore::IgnoreRules::load(output_path)?;
+        let pruned = prune::plan(output_path, &expected, &attributable, &ignore)?;
+        report_ignored(&ignore);
+        for path in &pruned.orphans {
+            let owner = path
+                .file_name()
+                .and_then(|name| owners.get(name.to_string_lossy().as_ref()))
+                .copied();
+            entries.push(status::StatusEntry::local(
+                status::StatusCategory::Orphaned,
+                path,
+                owner,
+            ));
+        }
+        for path in &pruned.unattributed {
+            entries.push(status::StatusEntry::local(
+                status::StatusCategory::Untracked,
+                path,
+                None,
+            ));
+        }
+    }
+    Ok(status::StatusReport::new(entries))
+}
+
+/// Print `sync --dry-run --diff` results grouped by category, git status style
+fn print_status_table(report: &status::StatusReport) {
+    use status::StatusCategory;
+    use ui::Color;
+
+    let mut category = None;
+    for entry in &report.entries {
+        let (marker, color, heading) = match entry.category {
+            StatusCategory::New => ("+", Color::Green, "will download"),
+            StatusCategory::Updated => ("~", Color::Yellow, "will replace"),
+            StatusCategory::Orphaned => ("-", Color::Red, "candidates for prune"),
+            StatusCategory::Untracked => ("?", Color::Dim, "left alone"),
+            StatusCategory::Error => ("!", Color::Red, "can't be downloaded"),
+            StatusCategory::Unchanged => (" ", Color::Dim, "up to date"),
+        };
+        if category != Some(entry.category) {
+            if category.is_some() {
+                println!();
+            }
+            category = Some(entry.category);
+            let total = report.total(entry.category);
+            println!(
+                "{} ({}, {}), {}:",
+                entry.category.label(),
+                total.count,
+                indicatif::HumanBytes(total.bytes),
+                heading
+            );
+        }
+        let name = match (&entry.title, &entry.filename) {
+            (Some(title), Some(filename)) => format!("{}: {}", title, filename),
+            (Some(title), None) => title.clone(),
+            (None, _) => String::new(),
+        };
+        let size = entry
+            .size
+            .map(|size| indicatif::HumanBytes(size).to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let mut detail = match (&entry.local_version, &entry.remote_version) {
+            (Some(local), Some(remote)) if entry.category == StatusCategory::Updated => {
+                format!("{} -> {}", local, remote)
+            }
+            _ => entry
+                .path
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+        };
+        if let Some(extra) = &entry.detail {
+            detail = format!("{} ({})", detail, extra).trim_start().to_string();
+        }
+        let line = format!(
+            "  {} {} {:>10} {}",
+            marker,
+            pad_to_width(&truncate_to_width(&name, 50), 50),
+            size,
+            detail
+        );
+        println!("{}", ui::paint(line.trim_end(), color));
+    }
+
+    let summary: Vec<String> = report
+        .totals
+        .iter()
+        .map(|(category, total)| {
+            format!(
+                "{} {} ({})",
+                total.count,
+                category.label(),
+                indicatif::HumanBytes(total.bytes)
+            )
+        })
+        .collect();
+    if !report.entries.is_empty() {
+        println!();
+    }
+    if summary.is_empty() {
+        println!("Nothing to compare.");
+    } else {
+        println!("{}.", summary.join(", "));
+    }
+}
+
+/// `dl --stdout`: write the one upload the filters match to stdout, everything else going to stderr
+///
+/// Fails before a byte is written unless the filters match exactly one game with exactly one upload.
+async fn download_to_stdout(
+    ctx: &CommandContext,
+    api_key: String,
+    options: mirror::DownloadOptions,
+) -> Result<RunStatus> {
+    if ui::is_porcelain() {
+        anyhow::bail!("--stdout can't be combined with --porcelain, which also writes to stdout");
+    }
+    let client = ctx.new_client(api_key)?;
+    let mut keys = if !options.demo_of.is_empty() {
+        mirror::demo_keys(&client, &options.demo_of, progress::show).await?
+    } else if options.mine {
+        mirror::fetch_own_games(&client, progress::show).await?
+    } else {
+        mirror::fetch_library(&client, progress::show).await?
+    };
+    let collection = match &options.collection {
+        Some(collection) => {
+            Some(mirror::fetch_collection(&client, collection, progress::show).await?)
+        }
+        None => None,
+    };
+    let mut selection = mirror::filter_selection(
+        options.author.clone(),
+        options.title.clone(),
+        options.from_bundle.clone(),
+        options.slug.clone(),
+        options.filter_rules.clone(),
+        collection.as_deref(),
+    );
+    keys.retain(|key| selection.admit(key));
+    let key = match keys.as_slice() {
+        [] => return Ok(report_unselected(&selection, options.mine)),
+        [key] => key,
+        keys => anyhow::bail!(
+            "--stdout needs the filters to match one game, they match {}: {}",
+            keys.len(),
+            keys.iter()
+                .map(|key| key.game.title.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    let key = &if options.demos_only {
+        OwnedKey::keyless(key.game.clone())
+    } else {
+        key.clone()
+    };
+    let uploads = client
+        .get_key_uploads(key, &options.abort)
+        .await
+        .with_context(|| format!("Failed to get uploads for {}", key.game.title))?;
+    let policy = SelectionPolicy {
+        demos_only: options.demos_only,
+        ..SelectionPolicy::default()
+    };
+    let uploads = choose_uploads(&uploads, &policy);
+    let upload = match uploads.as_slice() {
+        [] => anyhow::bail!("{} has no uploads", key.game.title),
+        [upload] => *upload,
+        uploads => anyhow::bail!(
+            "{} has {} uploads and --stdout can only write one: {}",
+            key.game.title,
+            uploads.len(),
+            uploads
+                .iter()
+                .map(|upload| upload.filename.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    let progress_bar = ui::multi_progress().add(ProgressBar::new(upload.size));
+    progress_bar.set_style(ui::bar_style());
+    progress_bar.set_prefix(key.game.title.clone());
+    let mut stdout = tokio::io::stdout();
+    let downloaded = client
+        .download_file_to(
+            upload.id,
+            key.download_key_id(),
+            &upload.filename,
+            (upload.size > 0).then_some(upload.size),
+            &mut stdout,
+            events::progress_sink(upload.id, &upload.filename, progress_bar.clone()),
+            &options.abort,
+        )
+        .await;
+    let downloaded = match downloaded {
+        Ok(downloaded) => downloaded,
+        Err(e) => {
+            ui::fail(&progress_bar, format!("Failed {}: {}", upload.filename, e));
+            return Err(e.into());
+        }
+    };
+    ui::finish(
+        &progress_bar,
+        format!(
+            "Wrote {} ({}) to stdout",
+            downloaded.filename,
+            indicatif::HumanBytes(downloaded.size)
+        ),
+    );
+    Ok(RunStatus::Success)
+}
+
+/// Print a `dl --dry-run` plan as a table with totals
+fn print_plan_table(plan: &plan::DownloadPlan) {
+    println!(
+        "{} {} {:<8} {:>10} Path",
+        pad_to_width("Game", 30),
+        pad_to_width("Upload", 30),
+        "Action",
+        "Size"
+    );
+    println!("{:-<30} {:-<30} {:-<8} {:->10} {:-<30}", "", "", "", "", "");
+    for entry in &plan.games {
+        let size = entry
+            .size
+            .map(|size| indicatif::HumanBytes(size).to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let mut path = match (&entry.path, &entry.detail) {
+            (Some(path), Some(detail)) => format!("{} ({})", path.display(), detail),
+            (Some(path), None) => path.display().to_string(),
+            (None, detail) => detail.clone().unwrap_or_default(),
+        };
+        if let Some(captured_by) = &entry.captured_by {
+            path.push_str(&format!(" [{}]", captured_by));
+        }
+        println!(
+            "{} {} {:<8} {:>10} {}",
+            pad_to_width(&truncate_to_width(&entry.title, 30), 30),
+            pad_to_width(
+                &truncate_to_width(entry.filename.as_deref().unwrap_or("-"), 30),
+                30
+            ),
+            entry.action.label(),
+            size,
+            path
+        );
+    }
+
+    println!();
+    println!(
+        "{} to download, {} replacing an existing copy, {} with errors, {} in total.",
+        plan.count(plan::PlanAction::Download),
+        plan.count(plan::PlanAction::Replace),
+        plan.count(plan::PlanAction::Error),
+        indicatif::HumanBytes(plan.total_bytes)
+    );
+
+    let heavy: Vec<&plan::PlannedDownload> = plan.heavily_used_keys().collect();
+    if !heavy.is_empty() {
+        println!();
+        println!(
+            "{} keys have been downloaded with {} times or more already, itch.io may refuse another download:",
+            heavy.len(),
+            plan::HEAVY_KEY_DOWNLOADS
+        );
+        for entry in heavy {
+            println!("  {} ({} downloads)", entry.title, entry.key_downloads);
+        }
+    }
+}
+
+/// Options for `check`
+struct CheckOptions {
+    output_path: PathBuf,
+    per_game_dirs: bool,
+    max_concurrent: usize,
+    /// How many files are hashed at once, one per CPU when unset
+    hash_jobs: Option<usize>,
+    format: OutputFormat,
+}
+
+async fn check_mirror(
+    ctx: &CommandContext,
+    api_key: String,
+    author_filter: Option<String>,
+    title_filter: Option<String>,
+    options: CheckOptions,
+) -> Result<RunStatus> {
+    let CheckOptions {
+        output_path,
+        per_game_dirs,
+        max_concurrent,
+        hash_jobs,
+        format,
+    } = options;
+
+    let client = ctx.new_client(api_key)?;
+    let mut filtered_keys = mirror::fetch_library(&client, progress::show).await?;
+    let filter = KeyFilter::new(author_filter, title_filter);
+    let mut exclusions = filters::Exclusions::default();
+    filtered_keys.retain(|key| exclusions.count(&filter, key));
+    if filtered_keys.is_empty() {
+        report_nothing_matched(false, &exclusions, &[]);
+        return Ok(RunStatus::NothingMatched);
+    }
+
+    let manifest = Manifest::load(&output_path, &ctx.layout)?;
+    let mut listed: Vec<(check::CheckEntry, Option<PendingCompare>)> =
+        futures::stream::iter(&filtered_keys)
+            .map(|key| check_game(ctx, &client, key, &output_path, per_game_dirs, &manifest))
+            .buffer_unordered(max_concurrent.max(1))
+            .collect()
+            .await;
+    listed.sort_by_key(|(entry, _)| entry.title.to_lowercase());
+
+    // The listings are done, so the hashing gets every core rather than waiting on the network.
+    // Results come back in the order the files went in
+    let pool = check::HashPool::new(hash_jobs.unwrap_or_else(check::HashPool::default_jobs));
+    let to_hash: u64 = listed
+        .iter()
+        .filter_map(|(_, pending)| pending.as_ref())
+        .filter(|pending| pending.md5.is_some())
+        .map(|pending| pending.size)
+        .sum();
+    let hash_bar = ui::multi_progress().add(ProgressBar::new(to_hash));
+    hash_bar.set_style(ui::bar_style());
+    hash_bar.set_prefix(format!("Hashing ({} jobs)", pool.jobs()));
+    let games: Vec<check::CheckEntry> = futures::stream::iter(listed)
+        .map(|(entry, pending)| {
+            let pool = &pool;
+            let hash_bar = hash_bar.clone();
+            async move {
+                match pending {
+                    Some(pending) => compare_pending(pool, entry, pending, hash_bar).await,
+                    None => entry,
+                }
+            }
+        })
+        .buffered(pool.jobs())
+        .collect()
+        .await;
+    hash_bar.finish_and_clear();
+
+    // Anything at the top level that no selected game accounts for is extra
+    let game_dirs = layout::GameDirs::scan(&output_path, false, &ctx.layout);
+    let mut expected: std::collections::HashSet<String> = filtered_keys
+        .iter()
+        .flat_map(|key| game_dir_names(ctx, &game_dirs, &key.game))
+        .collect();
+    expected.extend(
+        games
+            .iter()
+            .filter_map(|entry| entry.path.as_ref()?.strip_prefix(&output_path).ok())
+            .filter_map(manifest::top_level_name),
+    );
+    let extra = if output_path.is_dir() {
+        let ignore = itchignore::IgnoreRules::load(&output_path)?;
+        let plan = prune::plan(
+            &output_path,
+            &expected,
+            &std::collections::HashSet::new(),
+            &ignore,
+        )?;
+        report_ignored(&ignore);
+        plan.unattributed
+    } else {
+        Vec::new()
+    };
+
+    let report = check::CheckReport { games, extra };
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).context("Failed to serialize check results")?
+        ),
+        OutputFormat::Table => print_check_table(&report),
+    }
+
+    Ok(if report.is_complete() {
+        RunStatus::Success
+    } else {
+        RunStatus::Incomplete
+    })
+}
+
+/// Print `check` results as a table followed by any extra local files
+fn print_check_table(report: &check::CheckReport) {
+    println!(
+        "{} {} {:<10} Detail",
+        pad_to_width("Game", 30),
+        pad_to_width("Upload", 30),
+        "Status"
+    );
+    println!("{:-<30} {:-<30} {:-<10} {:-<30}", "", "", "", "");
+    for entry in &report.games {
+        println!(
+            "{} {} {:<10} {}",
+            pad_to_width(&truncate_to_width(&entry.title, 30), 30),
+            pad_to_width(
+                &truncate_to_width(entry.filename.as_deref().unwrap_or("-"), 30),
+                30
+            ),
+            entry.status.label(),
+            entry.detail.as_deref().unwrap_or_default()
+        );
+    }
+
+    if !report.extra.is_empty() {
+        println!();
+        println!("{} extra local entries:", report.extra.len());
+        for path in &report.extra {
+            println!("  {}", path.display());
+        }
+    }
+
+    let problems = report
+        .games
+        .iter()
+        .filter(|entry| entry.status.is_problem())
+        .count();
+    println!();
+    if report.is_complete() {
+        println!("Mirror is complete ({} games checked).", report.games.len());
+    } else {
+        println!(
+            "Mirror is incomplete: {} of {} games have problems, {} extra entries.",
+            problems,
+            report.games.len(),
+            report.extra.len()
+        );
+    }
+}
+
+/// How long `doctor` waits for a connection to the API
+const DOCTOR_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run `doctor`'s checks in order, skipping the ones that need something that already failed
+async fn doctor(
+    ctx: &CommandContext,
+    keys: &credentials::KeySources<'_>,
+    api_key: Option<String>,
+    output_path: &Path,
+    format: OutputFormat,
+) -> Result<RunStatus> {
+    let mut report = doctor::DoctorReport::default();
+    let builder = &ctx.client_builder;
+    let base_url = builder.api_base_url();
+    let api_host = base_url.host_str().unwrap_or_default();
+
+    // Through a proxy only the proxy itself is looked up and connected to
+    let route = builder.proxy_route(&base_url).await?;
+    report.pass("proxy", format!("{} for {}", route, api_host));
+    let (host, port, target) = match &route {
+        proxy::ProxyRoute::Proxy(proxy) => {
+            let host = proxy.host_str().unwrap_or_default();
+            (
+                host,
+                proxy.port_or_known_default().unwrap_or(80),
+                format!("proxy {}", host),
+            )
+        }
+        proxy::ProxyRoute::Direct => (
+            api_host,
+            base_url.port_or_known_default().unwrap_or(443),
+            api_host.to_string(),
+        ),
+    };
+
+    const DNS_HINT: &str = "Check the network connection and DNS settings, and --api-base-url, \
+                            --proxy or --proxy-pac if you set them";
+    let addresses = match tokio::net::lookup_host((host, port)).await {
+        Ok(addresses) => addresses.collect::<Vec<_>>(),
+        Err(e) => {
+            report.fail("dns", format!("Can't resolve {}: {}", target, e), DNS_HINT);
+            Vec::new()
+        }
+    };
+    let mut reachable = false;
+    match addresses.first() {
+        Some(first) => {
+            report.pass("dns", format!("{} resolves to {}", target, first.ip()));
+            let connect = tokio::net::TcpStream::connect(&addresses[..]);
+            match tokio::time::timeout(DOCTOR_CONNECT_TIMEOUT, connect).await {
+                Ok(Ok(_)) => {
+                    reachable = true;
+                    report.pass("connect", format!("Connected to {}:{}", target, port));
+                }
+                Ok(Err(e)) => report.fail(
+                    "connect",
+                    format!("Can't connect to {}:{}: {}", target, port, e),
+                    "A firewall or proxy may be blocking the connection",
+                ),
+                Err(_) => report.fail(
+                    "connect",
+                    format!("Timed out connecting to {}:{}", target, port),
+                    "A firewall or proxy may be dropping the connection",
+                ),
+            }
+        }
+        None => {
+            if !report.checks.iter().any(|check| check.name == "dns") {
+                report.fail("dns", format!("{} has no addresses", target), DNS_HINT);
+            }
+            report.skip("connect", "Needs the host to resolve");
+        }
+    }
+
+    let client = match keys.resolve(api_key) {
+        Ok(api_key) => Some(ctx.new_client(api_key)?),
+        Err(e) => {
+            report.fail(
+                "api key",
+                format!("{:#}", e),
+                "Store a key with `itch-downloader login` or pass --api-key",
+            );
+            None
+        }
+    };
+    let mut authenticated = false;
+    match &client {
+        Some(client) if reachable => match client.get_profile().await {
+            Ok(user) => {
+                authenticated = true;
+                report.pass("api key", format!("Valid, belongs to {}", user.username));
+            }
+            Err(e) if e.is_auth() => {
+                report.fail("api key", format!("{:#}", e), keys.rejection_hint());
+            }
+            Err(e) => report.fail(
+                "api key",
+                format!("{:#}", e),
+                "TLS and certificate errors are often caused by a wrong system clock, check it's \
+                 set correctly",
+            ),
+        },
+        Some(_) => report.skip("api key", "Needs a connection to the API"),
+        None => {}
+    }
+    // Measured from the responses above, so only known once the API answered
+    match client.as_ref().and_then(ItchClient::clock_skew) {
+        Some(skew) if skew.unsigned_abs() > itch_downloader::CLOCK_SKEW_THRESHOLD.as_secs() => {
+            report.fail(
+                "clock",
+                format!(
+                    "{}s {} itch.io's",
+                    skew.unsigned_abs(),
+                    if skew > 0 { "ahead of" } else { "behind" }
+                ),
+                "Correct the system clock, e.g. by enabling NTP; signed download links are \
+                 rejected when it's far off",
+            )
+        }
+        Some(skew) => report.pass(
+            "clock",
+            format!("Within {}s of itch.io's", skew.unsigned_abs()),
+        ),
+        None => report.skip("clock", "Needs a response from the API"),
+    }
+    match &client {
+        Some(client) if authenticated => match client.owned_keys_page(1).await {
+            Ok(page) => report.pass(
+                "library",
+                format!("Fetched the first page, {} games", page.owned_keys.len()),
+            ),
+            Err(e) => report.fail(
+                "library",
+                format!("{:#}", e),
+                "itch.io may be having problems, try again later",
+            ),
+        },
+        _ => report.skip("library", "Needs a valid API key"),
+    }
+
+    report.check_writable(
+        "output directory",
+        output_path,
+        "Fix the directory's permissions or pick another with --output",
+    );
+    report.check_free_space(output_path);
+    report.check_writable(
+        "state directory",
+        &ctx.layout.state_dir(output_path),
+        "Fix the permissions of the .itch-downloader directory inside the output directory",
+    );
+    match cache::HttpCache::default_dir(ctx.layout.profile.as_deref()) {
+        Some(dir) => report.check_writable(
+            "cache directory",
+            &dir,
+            "Metadata won't be cached between runs until the directory's permissions are fixed",
+        ),
+        None => report.skip("cache directory", "No cache directory on this platform"),
+    }
+
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).context("Failed to serialize report")?
+        ),
+        OutputFormat::Table => print_doctor_table(&report),
+    }
+    Ok(if report.is_healthy() {
+        RunStatus::Success
+    } else {
+        RunStatus::Unhealthy
+    })
+}
+
+/// Print a line per `doctor` check, with the hint under any that failed
+fn print_doctor_table(report: &doctor::DoctorReport) {
+    for check in &report.checks {
+        println!(
+            "[{}] {:<18} {}",
+            check.status.label(),
+            check.name,
+            check.detail
+        );
+        if let Some(hint) = &check.hint {
+            println!("       {:<18} {}", "", hint);
+        }
+    }
+    let failed = report
+        .checks
+        .iter()
+        .filter(|check| check.status == doctor::DoctorStatus::Fail)
+        .count();
+    println!();
+    if failed == 0 {
+        println!("No problems found.");
+    } else {
+        println!("{} of {} checks failed.", failed, report.checks.len());
+    }
+}
+
+/// Measure the connection through the real client and recommend settings for it
+///
+/// Downloads are streamed into nothing, so there's nothing to clean up afterwards.
+async fn tune(
+    ctx: &CommandContext,
+    api_key: String,
+    config: &config::Config,
+    budget: u64,
+    save: bool,
+    format: OutputFormat,
+) -> Result<RunStatus> {
+    let client = ctx.new_client(api_key)?;
+    let cancel = CancellationToken::new();
+    let started = Instant::now();
+    let owned_keys = mirror::fetch_library(&client, progress::show).await?;
+    let mut report = tune::TuneReport::default();
+
+    // Metadata first: look up a few games, timing each request, and list their uploads
+    let mut uploads = Vec::new();
+    for key in owned_keys.iter().take(tune::SAMPLE_GAMES) {
+        let request = Instant::now();
+        client.get_game(key.game_id).await?;
+        report
+            .latencies_ms
+            .push(request.elapsed().as_millis() as u64);
+        ui::status!("Looking up uploads of {}...", key.game.title);
+        let listed = client
+            .get_game_uploads(key.game_id, key.download_key_id(), &cancel)
+            .await?;
+        uploads.extend(listed.into_iter().map(|upload| (key, upload)));
+    }
+    let samples = tune::pick_samples(uploads, budget);
+    if samples.is_empty() {
+        ui::status!(
+            "None of the first {} games has an upload between {} and {} to time downloads with.",
+            tune::SAMPLE_GAMES,
+            indicatif::HumanBytes(tune::MIN_SAMPLE_SIZE),
+            indicatif::HumanBytes(budget)
+        );
+    }
+
+    // Then rounds of more and more streams at once, as long as the budget allows
+    for streams in tune::STREAM_LEVELS {
+        if samples.is_empty() {
+            break;
+        }
+        let round = tune::round_samples(&samples, streams);
+        let cost: u64 = round.iter().map(|sample| sample.upload.size).sum();
+        if report.downloaded + cost > budget {
+            break;
+        }
+        ui::status!(
+            "Downloading {} with {} stream{}...",
+            indicatif::HumanBytes(cost),
+            streams,
+            if streams == 1 { "" } else { "s" }
+        );
+        // Timed from the first response, leaving out the delay before each download starts
+        let first_response = std::sync::Mutex::new(None::<Instant>);
+        let round_cancel = cancel.child_token();
+        let received = std::sync::atomic::AtomicU64::new(0);
+        let results = futures::future::join_all(round.iter().map(|sample| {
+            let (first_response, round_cancel, received) =
+                (&first_response, &round_cancel, &received);
+            let client = &client;
+            let budget_left = budget - report.downloaded;
+            async move {
+                let mut last = 0;
+                client
+                    .download_file_to(
+                        sample.upload.id,
+                        sample.download_key_id,
+                        &sample.upload.filename,
+                        Some(sample.upload.size),
+                        &mut tokio::io::sink(),
+                        |progress| match progress {
+                            DownloadProgress::Started { .. } => {
+                                first_response
+                                    .lock()
+                                    .unwrap()
+                                    .get_or_insert_with(Instant::now);
+                            }
+                            DownloadProgress::Bytes(bytes) => {
+                                let new = bytes.saturating_sub(last);
+                                last = bytes;
+                                // Listed sizes can be off, the budget holds regardless
+                                let total = received
+                                    .fetch_add(new, std::sync::atomic::Ordering::Relaxed)
+                                    + new;
+                                if total > budget_left {
+                                    round_cancel.cancel();
+                                }
+                            }
+                            _ => {}
+                        },
+                        round_cancel,
+                    )
+                    .await
+            }
+        }))
+        .await;
+        let finished = Instant::now();
+        report.downloaded += received.into_inner();
+        if round_cancel.is_cancelled() {
+            ui::status!(
+                "Stopped at the download budget of {}.",
+                indicatif::HumanBytes(budget)
+            );
+            break;
+        }
+        let mut bytes = 0;
+        for (sample, result) in round.iter().zip(results) {
+            match result {
+                Ok(file) => bytes += file.size,
+                Err(e) => {
+                    anyhow::bail!(
+                        "Failed to download {} of {}: {:#}",
+                        sample.upload.filename,
+                        sample.game_title,
+                        e
+                    )
+                }
+            }
+        }
+        let seconds = first_response
+            .into_inner()
+            .unwrap()
+            .map_or(0.0, |first| (finished - first).as_secs_f64())
+            .max(f64::EPSILON);
+        report.rounds.push(tune::RoundResult {
+            streams,
+            bytes,
+            seconds,
+            bytes_per_second: bytes as f64 / seconds,
+        });
+    }
+
+    let usage = client.api_usage();
+    report.requests = usage.requests;
+    report.throttled = usage.throttled;
+    report.recommend(started.elapsed().as_secs_f64());
+
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).context("Failed to serialize report")?
+        ),
+        OutputFormat::Table => print_tune_table(&report),
+    }
+
+    if save {
+        let mut values = Vec::new();
+        if let Some(streams) = report.recommended.max_concurrent {
+            values.push(("max_concurrent", toml_edit::Value::from(streams as i64)));
+        }
+        if let Some(rate) = report.recommended.rate_limit {
+            values.push(("rate_limit", toml_edit::Value::from(rate)));
+        }
+        let path = config
+            .path
+            .clone()
+            .or_else(config::default_path)
+            .context("No configuration file to save to on this platform, see --config")?;
+        if values.is_empty() {
+            ui::status!("Nothing to save to {}.", path.display());
+        } else {
+            config::save_values(&path, config.profile.as_deref(), &values)?;
+            ui::status!("Saved the recommendation to {}.", path.display());
+        }
+    }
+    Ok(RunStatus::Success)
+}
+
+/// Print what `tune` measured and what it recommends
+fn print_tune_table(report: &tune::TuneReport) {
+    if let Some(median) = report.median_latency_ms() {
+        println!(
+            "{:<18} {} ms median over {} requests",
+            "API latency",
+            median,
+            report.latencies_ms.len()
+        );
+    }
+    for round in &report.rounds {
+        println!(
+            "{:<18} {}/s ({} in {:.1}s)",
+            format!(
+                "{} stream{}",
+                round.streams,
+                if round.streams == 1 { "" } else { "s" }
+            ),
+            indicatif::HumanBytes(round.bytes_per_second as u64),
+            indicatif::HumanBytes(round.bytes),
+            round.seconds
+        );
+    }
+    println!(
+        "{:<18} {}, none of it kept",
+        "Downloaded",
+        indicatif::HumanBytes(report.downloaded)
+    );
+    println!(
+        "{:<18} {}, {} rate limited",
+        "Requests", report.requests, report.throttled
+    );
+    println!();
+    println!("Recommended:");
+    match report.recommended.max_concurrent {
+        Some(streams) => println!("  --max-concurrent {}", streams),
+        None => println!("  --max-concurrent: no downloads were timed, keep the default"),
+    }
+    match report.recommended.rate_limit {
+        Some(rate) => println!("  --rate-limit {}", rate),
+        None => println!("  --rate-limit: not needed, itch.io didn't throttle any request"),
+    }
+    println!("  Segments: each file is fetched over one connection, there's nothing to set");
+}
+
+/// Print `diff` results as a table followed by how much would need copying
+fn print_diff_table(report: &diff::DiffReport, games_only: bool) {
+    let heading = if games_only { "Game" } else { "Path" };
+    println!(
+        "{} {:<10} {:>10} {:>10}",
+        pad_to_width(heading, 50),
+        "Status",
+        "Size (a)",
+        "Size (b)"
+    );
+    println!("{:-<50} {:-<10} {:->10} {:->10}", "", "", "", "");
+    let size = |size: Option<u64>| {
+        size.map(|size| indicatif::HumanBytes(size).to_string())
+            .unwrap_or_else(|| "-".to_string())
+    };
+    for entry in &report.entries {
+        println!(
+            "{} {:<10} {:>10} {:>10}",
+            pad_to_width(&truncate_to_width(&entry.name, 50), 50),
+            entry.status.label(),
+            size(entry.size_a),
+            size(entry.size_b)
+        );
+    }
+
+    println!();
+    if report.is_identical() {
+        println!("No differences found.");
+    } else {
+        println!(
+            "{} differences. To copy a -> b: {}, b -> a: {}",
+            report.entries.len(),
+            indicatif::HumanBytes(report.bytes_a_to_b),
+            indicatif::HumanBytes(report.bytes_b_to_a)
+        );
+    }
+}
+
+fn diff_trees(
+    ctx: &CommandContext,
+    a: &Path,
+    b: &Path,
+    games_only: bool,
+    format: OutputFormat,
+) -> Result<RunStatus> {
+    let report = diff::compare(a, b, &ctx.layout, games_only)?;
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).context("Failed to serialize diff")?
+        ),
+        OutputFormat::Table => print_diff_table(&report, games_only),
+    }
+
+    Ok(if report.is_identical() {
+        RunStatus::Success
+    } else {
+        RunStatus::Incomplete
+    })
+}
+
+fn print_duplicates_table(report: &dedupe::DuplicateReport) {
+    for group in &report.groups {
+        println!(
+            "{} x{}, {} reclaimable ({})",
+            indicatif::HumanBytes(group.size),
+            group.files.len(),
+            indicatif::HumanBytes(group.reclaimable()),
+            &group.sha256[..12.min(group.sha256.len())]
+        );
+        for file in &group.files {
+            let mut line = format!("  {}", file.path.display());
+            if let Some(game) = &file.game {
+                line.push_str(&format!(" ({})", game));
+            }
+            if file.linked {
+                line.push_str(" [already linked]");
+            }
+            println!("{}", line);
+        }
+    }
+
+    if !report.groups.is_empty() {
+        println!();
+    }
+    if report.reclaimable_bytes == 0 {
+        println!("No duplicate files to reclaim space from.");
+    } else {
+        println!(
+            "{} groups of identical files, {} reclaimable with --apply hardlink",
+            report.groups.len(),
+            indicatif::HumanBytes(report.reclaimable_bytes)
+        );
+    }
+}
+
+fn dedupe_report(
+    ctx: &CommandContext,
+    output_path: &Path,
+    min_size: u64,
+    apply: Option<DedupeMode>,
+    format: OutputFormat,
+) -> Result<RunStatus> {
+    // Linking replaces files, so no download may be writing them meanwhile
+    let _lock = match apply {
+        Some(_) => Some(lock::OutputLock::acquire(output_path)?),
+        None => None,
+    };
+    let hash_bar = ui::multi_progress().add(ProgressBar::new_spinner());
+    hash_bar.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner} Hashing files of the same size, {bytes} ({bytes_per_sec})")
+            .unwrap(),
+    );
+    let ignore = itchignore::IgnoreRules::load(output_path)?;
+    let report = dedupe::find_duplicates(output_path, &ctx.layout, min_size, &ignore, |read| {
+        hash_bar.inc(read)
+    })?;
+    hash_bar.finish_and_clear();
+    report_ignored(&ignore);
+
+    let linked = apply.map(|mode| match mode {
+        DedupeMode::Hardlink => dedupe::link_duplicates(output_path, &report),
+    });
+    match format {
+        OutputFormat::Json => {
+            let json = serde_json::json!({ "report": report, "linked": linked });
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&json).context("Failed to serialize report")?
+            )
+        }
+        OutputFormat::Table => {
+            print_duplicates_table(&report);
+            if let Some(linked) = &linked {
+                println!(
+                    "Linked {} files, saving {}",
+                    linked.linked_files,
+                    indicatif::HumanBytes(linked.saved_bytes)
+                );
+                for (path, reason) in &linked.failed {
+                    println!("  Couldn't link {}: {}", path.display(), reason);
+                }
+            }
+        }
+    }
+
+    Ok(match linked {
+        Some(linked) if !linked.failed.is_empty() => RunStatus::Incomplete,
+        _ => RunStatus::Success,
+    })
+}
+
+/// Options for `import-app`
+struct ImportOptions {
+    app_dir: Option<PathBuf>,
+    output_path: PathBuf,
+    per_game_dirs: bool,
+    files: ImportMode,
+}
+
+async fn import_app(
+    ctx: &CommandContext,
+    api_key: String,
+    options: ImportOptions,
+) -> Result<RunStatus> {
+    let ImportOptions {
+        app_dir,
+        output_path,
+        per_game_dirs,
+        files,
+    } = options;
+
+    let app_dir = app_dir
+        .or_else(itch_app::default_app_dir)
+        .context("Couldn't find the itch app's data directory, pass it with --app-dir")?;
+    let itch_app::FoundInstalls { installs, unusable } = itch_app::find_installs(&app_dir)?;
+    if installs.is_empty() && unusable.is_empty() {
+        println!("No itch app installs found in {}", app_dir.display());
+        return Ok(RunStatus::NothingMatched);
+    }
+
+    let client = ctx.new_client(api_key)?;
+    let owned_keys: std::collections::HashMap<u64, OwnedKey> =
+        mirror::fetch_library(&client, progress::show)
+            .await?
+            .into_iter()
+            .map(|key| (key.game_id, key))
+            .collect();
+
+    tokio::fs::create_dir_all(&output_path)
+        .await
+        .context("Failed to create output directory")?;
+    let _lock = lock::OutputLock::acquire(&output_path)?;
+    let mut manifest = Manifest::load(&output_path, &ctx.layout)?;
+
+    let mut imported = 0;
+    let mut unmatched = Vec::new();
+    for install in installs {
+        let Some(key) = owned_keys.get(&install.game.id) else {
+            let title = install.game.title.as_deref().unwrap_or("unknown game");
+            unmatched.push(format!("{} ({})", title, install.dir.display()));
+            continue;
+        };
+
+        let filename = install
+            .upload
+            .filename
+            .clone()
+            .unwrap_or_else(|| install.upload.id.to_string());
+        let game_dir_name = ctx.layout.game_dir(&key.game);
+        // Mirror the layout `dl --unzip` would have produced
+        let target = if per_game_dirs {
+            output_path
+                .join(&game_dir_name)
+                .join(extract::strip_archive_extension(&filename))
+        } else {
+            output_path.join(&game_dir_name)
+        };
+
+        let path = match files {
+            ImportMode::Record => std::path::absolute(&install.dir).unwrap_or(install.dir.clone()),
+            ImportMode::Copy | ImportMode::Link if target.exists() => {
+                eprintln!(
+                    "Skipping {}: {} already exists",
+                    key.game.title,
+                    target.display()
+                );
+                continue;
+            }
+            ImportMode::Copy | ImportMode::Link => {
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent).context("Failed to create game directory")?;
+                }
+                if files == ImportMode::Copy {
+                    staging::copy_recursive(&install.dir, &target)
+                        .with_context(|| format!("Failed to copy {}", install.dir.display()))?;
+                    // The app's bookkeeping doesn't belong in the mirror
+                    let _ = std::fs::remove_dir_all(target.join(".itch"));
+                } else {
+                    let source = std::path::absolute(&install.dir).unwrap_or(install.dir.clone());
+                    layout::link_to(&source, &target)?;
+                }
+                target
+            }
+        };
+
+        let record = UploadRecord {
+            filename,
+            path: manifest.relative(&path),
+            size: install.upload.size.unwrap_or(0),
+            md5: install.upload.md5_hash.clone(),
+            sha256: None,
+            build_id: install.upload.build_id,
+            updated_at: install.upload.updated_at,
+            downloaded_at: manifest::now(),
+        };
+        manifest.record(
+            key.game_id,
+            &key.game.title,
+            key.id,
+            install.upload.id,
+            record,
+        );
+        println!("Imported {} from {}", key.game.title, install.dir.display());
+        imported += 1;
+    }
+
+    manifest.save()?;
+    println!("Imported {} games into {}", imported, output_path.display());
+
+    if !unmatched.is_empty() {
+        println!("{} installs don't match any game you own:", unmatched.len());
+        for install in &unmatched {
+            println!("  {}", install);
+        }
+    }
+    if !unusable.is_empty() {
+        println!("{} receipts couldn't be read:", unusable.len());
+        for (path, reason) in &unusable {
+            println!("  {}: {}", path.display(), reason);
+        }
+    }
+
+    Ok(RunStatus::Success)
+}
+
+fn export_games(
+    ctx: &CommandContext,
+    format: ExportFormat,
+    output_path: PathBuf,
+    dir: PathBuf,
+) -> Result<RunStatus> {
+    let manifest = Manifest::load(&output_path, &ctx.layout)?;
+    let games = export::collect_games(&manifest)?;
+    if games.is_empty() {
+        println!("No games found in {}", output_path.display());
+        return Ok(RunStatus::NothingMatched);
+    }
+
+    let unidentified = match format {
+        ExportFormat::Lutris => export::write_lutris(&games, &dir)?,
+        ExportFormat::Pegasus => export::write_pegasus(&games, &dir)?,
+        ExportFormat::Playnite => export::write_playnite(&games, &dir)?,
+    };
+    // Lutris entries can't do without an executable, the other formats list the game regardless
+    let exported = if format == ExportFormat::Lutris {
+        games.len() - unidentified.len()
+    } else {
+        games.len()
+    };
+    println!("Exported {} games to {}", exported, dir.display());
+
+    if !unidentified.is_empty() {
+        println!(
+            "{} games have no recognizable executable and need their launch file set by hand:",
+            unidentified.len()
+        );
+        for game in &unidentified {
+            println!("  {}", game);
+        }
+    }
+
+    Ok(RunStatus::Success)
+}
+
+fn clean_output(
+    ctx: &CommandContext,
+    output_path: PathBuf,
+    temp_dir: Option<PathBuf>,
+    yes: bool,
+) -> Result<RunStatus> {
+    let lock_state = lock::inspect(&output_path);
+    let mut leftovers = Vec::new();
+    for dir in staging::orphan_dirs(&output_path)?
+        .into_iter()
+        .chain(temp_dir)
+    {
+        leftovers.extend(staging::find_orphans(&dir)?);
+    }
+
+    // Files in the state directory are only written under the lock, so while a run holds it
+    // they may well be in use
+    let state_dir = ctx.layout.state_dir(&output_path);
+    match &lock_state {
+        lock::LockState::Held { pid, .. } => {
+            let holder =
+                pid.map_or_else(|| "another run".to_string(), |pid| format!("pid {}", pid));
+            println!(
+                "{} is locked by {}, leaving its state directory alone",
+                output_path.display(),
+                holder
+            );
+        }
+        lock::LockState::Stale(lock_path) => {
+            leftovers.extend(tmp_files(&state_dir)?);
+            leftovers.push(lock_path.clone());
+        }
+        lock::LockState::Free => leftovers.extend(tmp_files(&state_dir)?),
+    }
+    if let Some(cache_dir) = cache::HttpCache::default_dir(ctx.layout.profile.as_deref()) {
+        leftovers.extend(staging::find_orphaned_tmp_files(&cache_dir)?);
+    }
+    let ignore = itchignore::IgnoreRules::load(&output_path)?;
+    leftovers.retain(|path| !ignore.is_ignored(path, path.is_dir()));
+    report_ignored(&ignore);
+
+    if leftovers.is_empty() {
+        println!("Nothing to clean.");
+    } else if yes {
+        for path in &leftovers {
+            staging::remove_path(path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+            println!("Removed {}", path.display());
+        }
+    } else {
+        println!(
+            "{} leftovers would be removed (pass --yes to delete them):",
+            leftovers.len()
+        );
+        for path in &leftovers {
+            println!("  {}", path.display());
+        }
+    }
+
+    Ok(RunStatus::Success)
+}
+
+/// Half written `*.tmp` files in `dir`
+fn tmp_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry.context("Failed to list directory entries")?;
+        let is_file = entry.file_type().is_ok_and(|file_type| file_type.is_file());
+        if is_file && entry.path().extension().is_some_and(|ext| ext == "tmp") {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// Names a game's files can have at the top of the output directory: the directory named after
+/// it, and the one its sidecar is in if it was renamed since
+fn game_dir_names(ctx: &CommandContext, game_dirs: &layout::GameDirs, game: &Game) -> Vec<String> {
+    let mut names = vec![ctx.layout.game_dir(game)];
+    if let Some(name) = game_dirs.find(game).and_then(Path::file_name) {
+        names.push(name.to_string_lossy().into_owned());
+    }
+    names
+}
+
+/// Options for the `prune` command
+struct PruneOptions {
+    author_filter: Option<String>,
+    title_filter: Option<String>,
+    slug: Option<String>,
+    collection: Option<String>,
+    from_bundle: Option<String>,
+    filter_rules: Option<FilterFile>,
+    mine: bool,
+    output_path: PathBuf,
+    yes: bool,
+}
+
+/// Remove what's in the output directory for games `dl` with the same filters wouldn't download
+async fn prune_output(
+    ctx: &CommandContext,
+    api_key: String,
+    options: PruneOptions,
+) -> Result<RunStatus> {
+    let PruneOptions {
+        author_filter,
+        title_filter,
+        slug,
+        collection,
+        from_bundle,
+        filter_rules,
+        mine,
+        output_path,
+        yes,
+    } = options;
+    let client = ctx.new_client(api_key)?;
+    let owned_keys = if mine {
+        mirror::fetch_own_games(&client, progress::show).await?
+    } else {
+        mirror::fetch_library(&client, progress::show).await?
+    };
+    let collection_games = match &collection {
+        Some(collection) => {
+            Some(mirror::fetch_collection(&client, collection, progress::show).await?)
+        }
+        None => None,
+    };
+    let mut manifest = Manifest::load(&output_path, &ctx.layout)?;
+
+    // The games `dl` would work through with these filters; --since isn't taken, as the games it
+    // leaves out are still selected and their files aren't orphans
+    let mut selection = mirror::filter_selection(
+        author_filter,
+        title_filter,
+        from_bundle,
+        slug,
+        filter_rules,
+        collection_games.as_deref(),
+    );
+    // Names are the same whether or not --per-game-dirs was used: the game directory or the
+    // extraction directory named after the game, plus whatever the manifest recorded
+    let mut expected = std::collections::HashSet::new();
+    let mut attributable = std::collections::HashSet::new();
+    let mut selected_games = std::collections::HashSet::new();
+    let game_dirs = layout::GameDirs::scan(&output_path, false, &ctx.layout);
+    for key in &owned_keys {
+        let selected = selection.admit(key);
+        if selected {
+            selected_games.insert(key.game_id);
+        }
+        for name in game_dir_names(ctx, &game_dirs, &key.game) {
+            if selected {
+                expected.insert(name.clone());
+            }
+            attributable.insert(name);
+        }
+    }
+    selection.log();
+    // Filters that match nothing would make every game's files look orphaned
+    if selection.matched == 0 {
+        return Ok(report_unselected(&selection, mine));
+    }
+    for (game_id, game) in manifest.games() {
+        for upload in game.uploads.values() {
+            let Some(name) = manifest::top_level_name(&upload.path) else {
+                continue;
+            };
+            if selected_games.contains(&game_id) {
+                expected.insert(name.clone());
+            }
+            attributable.insert(name);
+        }
+    }
+
+    let ignore = itchignore::IgnoreRules::load(&output_path)?;
+    let plan = prune::plan(&output_path, &expected, &attributable, &ignore)?;
+
+    if plan.orphans.is_empty() {
+        ui::report!("Nothing to prune.");
+    } else if yes {
+        let mut removed = std::collections::HashSet::new();
+        for path in &plan.orphans {
+            let gone = itchignore::remove_unignored(path, &ignore)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+            if !gone {
+                ui::report!(
+                    "Kept {}, .itchignore rules match some of it",
+                    path.display()
+                );
+                continue;
+            }
+            info!(path = %path.display(), "Pruned");
+            ui::report!("Removed {}", path.display());
+            if let Some(name) = path.file_name() {
+                removed.insert(name.to_string_lossy().into_owned());
+            }
+        }
+        manifest.forget_top_level(&removed);
+        manifest.save()?;
+    } else {
+        ui::report!(
+            "{} entries would be removed (pass --yes to delete them):",
+            plan.orphans.len()
+        );
+        for path in &plan.orphans {
+            ui::report!("  {}", path.display());
+        }
+    }
+
+    if !plan.unattributed.is_empty() {
+        ui::report!(
+            "{} entries couldn't be attributed to any game and were left alone:",
+            plan.unattributed.len()
+        );
+        for path in &plan.unattributed {
+            ui::report!("  {}", path.display());
+        }
+    }
+    report_ignored(&ignore);
+
+    Ok(RunStatus::Success)
+}
+
+/// Say how many paths `.itchignore` rules kept out of a command, when there are any rules
+fn report_ignored(ignore: &itchignore::IgnoreRules) {
+    if !ignore.is_empty() {
+        ui::status!("{} paths excluded by .itchignore rules", ignore.excluded());
+    }
+}
+
+async fn run(cli: Cli, config: config::Config, ctx: &CommandContext) -> Result<RunStatus> {
+    let keys = credentials::KeySources {
+        file: cli
+            .api_key_file
+            .or_else(|| std::env::var_os("ITCH_API_KEY_FILE").map(PathBuf::from)),
+        config: &config,
+        source: Default::default(),
+    };
+    if cli.offline && matches!(cli.command, Commands::Dl { .. } | Commands::Sync { .. }) {
+        anyhow::bail!("Downloading needs the network, run without --offline");
+    }
+    // A speed test is meaningless without the network, and unwanted in CI
+    if matches!(cli.command, Commands::Tune { .. }) && (cli.offline || tune::in_ci()) {
+        ui::status!("Skipping the speed test, it needs the network and doesn't run in CI.");
+        return Ok(RunStatus::Success);
+    }
+    let status = dispatch(cli.command, &keys, &config, ctx)
+        .await
+        .map_err(|e| keys.explain(e));
+    ctx.report_offline_age();
+    status
+}
+
+async fn dispatch(
+    command: Commands,
+    keys: &credentials::KeySources<'_>,
+    config: &config::Config,
+    ctx: &CommandContext,
+) -> Result<RunStatus> {
+    let status = match command {
+        Commands::Info {
+            api_key,
+            game_id,
+            format,
+        } => game_info(ctx, keys.resolve(api_key)?, game_id, format).await?,
+        Commands::Builds {
+            api_key,
+            game_id,
+            format,
+        } => list_builds(ctx, keys.resolve(api_key)?, game_id, format).await?,
+        Commands::Purchases {
+            api_key,
+            game_id,
+            email,
+            user_id,
+            format,
+        } => {
+            let filter = PurchaseFilter { email, user_id };
+            list_purchases(ctx, keys.resolve(api_key)?, game_id, filter, format).await?
+        }
+        Commands::Keys {
+            api_key,
+            game_id,
+            email,
+            key,
+            format,
+        } => {
+            let lookup = match (key, email) {
+                (Some(key), _) => KeyLookup::Key(key),
+                (None, Some(email)) => KeyLookup::Email(email),
+                (None, None) => unreachable!("clap requires --email or --key"),
+            };
+            lookup_key(ctx, keys.resolve(api_key)?, game_id, lookup, format).await?
+        }
+        Commands::Reviews {
+            api_key,
+            game_id,
+            output,
+        } => archive_reviews(ctx, keys.resolve(api_key)?, game_id, output).await?,
+        Commands::Whoami { api_key, format } => whoami(ctx, keys.resolve(api_key)?, format).await?,
+        Commands::Login => {
+            let api_key = rpassword::prompt_password("itch.io API key: ")
+                .context("Failed to read API key")?;
+            let api_key = api_key.trim().to_string();
+            if api_key.is_empty() {
+                anyhow::bail!("No API key entered");
+            }
+            let user = ctx.new_client(api_key.clone())?.get_profile().await?;
+            credentials::store_in_keyring(ctx.layout.profile.as_deref(), &api_key)?;
+            println!(
+                "Logged in as {}, the key is stored in the system keyring",
+                user.display_name.as_deref().unwrap_or(&user.username)
+            );
+            RunStatus::Success
+        }
+        Commands::Completions { shell } => {
+            clap_complete::generate(
+                shell,
+                &mut Cli::command(),
+                "itch-downloader",
+                &mut std::io::stdout(),
+            );
+            RunStatus::Success
+        }
+        Commands::GenerateMan => {
+            clap_mangen::Man::new(Cli::command())
+                .render(&mut std::io::stdout())
+                .context("Failed to write man page")?;
+            RunStatus::Success
+        }
+        Commands::Logout => {
+            if credentials::remove_from_keyring(ctx.layout.profile.as_deref())? {
+                println!("Removed the API key from the system keyring");
+            } else {
+                println!("No API key was stored");
+            }
+            RunStatus::Success
+        }
+        Commands::State {
+            action:
+                StateAction::Export {
+                    file,
+                    output,
+                    per_game_dirs,
+                    link_latest,
+                    organize,
+                    group_by_bundle,
+                },
+        } => {
+            let layout_settings = state::LayoutSettings {
+                per_game_dirs,
+                link_latest,
+                organize,
+                group_by_bundle,
+            };
+            let cache_dir = cache::HttpCache::default_dir(ctx.layout.profile.as_deref());
+            let counts = state::export_state(
+                &output,
+                &ctx.layout,
+                layout_settings,
+                cache_dir.as_deref(),
+                &file,
+            )?;
+            ui::status!(
+                "Exported {} state files, {} cached responses and {} receipts to {}",
+                counts.state_files,
+                counts.cache_files,
+                counts.receipts,
+                file.display()
+            );
+            RunStatus::Success
+        }
+        Commands::State {
+            action:
+                StateAction::Import {
+                    file,
+                    output,
+                    merge,
+                },
+        } => import_state(ctx, &file, &output, merge)?,
+        Commands::Config {
+            action: ConfigAction::Show,
+        } => {
+            match &config.path {
+                Some(path) => println!("# Read from {}", path.display()),
+                None => println!("# No configuration file, showing built-in defaults"),
+            }
+            if let Some(profile) = &config.profile {
+                println!("# Profile: {}", profile);
+            }
+            let command = config.apply_defaults(Cli::command());
+            print!(
+                "{}",
+                toml::to_string(&config.effective(&command))
+                    .context("Failed to serialize configuration")?
+            );
+            RunStatus::Success
+        }
+        Commands::Ls {
+            api_key,
+            author,
+            title,
+            slug,
+            mine,
+            downloads,
+            bundle,
+            origin,
+            slug_column,
+            filter_file,
+            demos,
+            demo_of,
+            no_truncate,
+        } => {
+            let columns = ListColumns {
+                downloads,
+                origin,
+                slug: slug_column,
+                overflow: no_truncate,
+            };
+            let source = if mine {
+                ListSource::Mine
+            } else if demos {
+                ListSource::Demos(demo_of)
+            } else {
+                ListSource::Library
+            };
+            let rules = filter_file
+                .as_deref()
+                .map(load_filter_file)
+                .transpose()?
+                .map(|rules| rules.overridden_by(&author, &title));
+            let filter = KeyFilter::new(author, title)
+                .from_bundle(bundle)
+                .with_slug(slug);
+            list_packages(ctx, keys.resolve(api_key)?, filter, rules, source, columns).await?
+        }
+        Commands::Bundle {
+            action: BundleAction::Ls { bundle, format },
+        } => list_bundle(ctx, bundle, format).await?,
+        Commands::Bundle {
+            action:
+                BundleAction::Dl {
+                    bundle,
+                    claim,
+                    download,
+                },
+        } => {
+            download_bundle(
+                ctx,
+                keys.resolve(download.api_key.clone())?,
+                bundle,
+                claim,
+                *download,
+            )
+            .await?
+        }
+        Commands::Claim {
+            url,
+            session,
+            and_download,
+            download,
+        } => {
+            claim_game(
+                ctx,
+                keys.resolve(download.api_key.clone())?,
+                url,
+                session,
+                and_download,
+                download,
+            )
+            .await?
+        }
+        Commands::Jam { jam, download } => {
+            download_jam(ctx, keys.resolve(download.api_key.clone())?, jam, download).await?
+        }
+        Commands::Collections { api_key, format } => {
+            list_collections(ctx, keys.resolve(api_key)?, format).await?
+        }
+        Commands::Dl {
+            download: args,
+            dry_run: true,
+            format,
+            ..
+        } => {
+            let options = args.download_options(ctx)?;
+            plan_downloads(ctx, keys.resolve(args.api_key)?, options, format, None).await?
+        }
+        Commands::Dl {
+            download: args,
+            stdout: true,
+            ..
+        } => {
+            let options = args.download_options(ctx)?;
+            cancel_on_signal(options.shutdown.clone(), options.abort.clone());
+            download_to_stdout(ctx, keys.resolve(args.api_key)?, options).await?
+        }
+        Commands::Dl {
+            download: args,
+            watch,
+            ..
+        } if watch.watch || watch.once_then_watch => {
+            watch_packages(ctx, keys.resolve(args.api_key.clone())?, args, watch).await?
+        }
+        Commands::Dl { download: args, .. } => {
+            let options = args.download_options(ctx)?;
+            cancel_on_signal(options.shutdown.clone(), options.abort.clone());
+            let desktop = args.notify_desktop();
+            download_packages(ctx, keys.resolve(args.api_key)?, options, desktop).await?
+        }
+        Commands::Sync {
+            download: args,
+            diff: true,
+            format,
+            ..
+        } => {
+            let options = args.download_options(ctx)?;
+            let manifest = Manifest::load(&args.output, &ctx.layout)?;
+            plan_downloads(
+                ctx,
+                keys.resolve(args.api_key)?,
+                options,
+                format,
+                Some(manifest),
+            )
+            .await?
+        }
+        Commands::Sync {
+            download: args,
+            prune,
+            dry_run,
+            keep_old_versions,
+            allow_downgrade,
+            keep_downgraded,
+            ..
+        } => {
+            let mut options = args.download_options(ctx)?;
+            // A dry run doesn't download anything worth recording
+            options.history &= !dry_run;
+            options.sync = Some(mirror::SyncOptions {
+                manifest: std::sync::Mutex::new(Manifest::load(&args.output, &ctx.layout)?),
+                prune,
+                dry_run,
+                keep_old_versions,
+                allow_downgrade,
+                keep_downgraded,
+                new_only: false,
+            });
+            cancel_on_signal(options.shutdown.clone(), options.abort.clone());
+            let desktop = args.notify_desktop();
+            download_packages(ctx, keys.resolve(args.api_key)?, options, desktop).await?
+        }
+        Commands::Doctor {
+            api_key,
+            output,
+            format,
+        } => doctor(ctx, keys, api_key, &output, format).await?,
+        Commands::Tune {
+            api_key,
+            budget,
+            save,
+            format,
+        } => tune(ctx, keys.resolve(api_key)?, config, budget, save, format).await?,
+        Commands::Check {
+            api_key,
+            author,
+            title,
+            output,
+            per_game_dirs,
+            max_concurrent,
+            hash_jobs,
+            format,
+        } => {
+            let options = CheckOptions {
+                output_path: output,
+                per_game_dirs,
+                max_concurrent,
+                hash_jobs,
+                format,
+            };
+            check_mirror(ctx, keys.resolve(api_key)?, author, title, options).await?
+        }
+        Commands::Diff {
+            a,
+            b,
+            games_only,
+            format,
+        } => diff_trees(ctx, &a, &b, games_only, format)?,
+        Commands::DedupeReport {
+            output,
+            min_size,
+            apply,
+            format,
+        } => dedupe_report(ctx, &output, min_size, apply, format)?,
+        Commands::ImportApp {
+            api_key,
+            app_dir,
+            output,
+            per_game_dirs,
+            files,
+        } => {
+            let options = ImportOptions {
+                app_dir,
+                output_path: output,
+                per_game_dirs,
+                files,
+            };
+            import_app(ctx, keys.resolve(api_key)?, options).await?
+        }
+        Commands::Export {
+            format,
+            output,
+            dir,
+        } => export_games(ctx, format, output, dir)?,
+        Commands::Clean {
+            output,
+            temp_dir,
+            yes,
+        } => clean_output(ctx, output, temp_dir, yes)?,
+        Commands::Prune {
+            api_key,
+            author,
+            title,
+            slug,
+            collection,
+            from_bundle,
+            filter_file,
+            mine,
+            output,
+            yes,
+        } => {
+            let options = PruneOptions {
+                filter_rules: load_filter_rules(filter_file.as_deref(), &author, &title)?,
+                author_filter: author,
+                title_filter: title,
+                slug,
+                collection,
+                from_bundle,
+                mine,
+                output_path: output,
+                yes,
+            };
+            prune_output(ctx, keys.resolve(api_key)?, options).await?
+        }
+        Commands::Promote { run, output } => promote_run(ctx, output, run)?,
+        Commands::Extract {
+            output,
+            title,
+            author,
+            game_id,
+            zip_encoding,
+            unrar_path,
+            unzip_depth,
+            temp_dir,
+            create_parents,
+            extract_jobs,
+            force,
+            dry_run,
+        } => {
+            preflight::check_dir("output directory", &output, Access::Update, false)?;
+            if let Some(temp_dir) = &temp_dir {
+                preflight::check_dir(
+                    "temporary directory",
+                    temp_dir,
+                    Access::Write,
+                    create_parents,
+                )?;
+            }
+            let options = ReextractOptions {
+                output_path: output,
+                title_filter: title,
+                author_filter: author,
+                game_id,
+                extract: ExtractOptions {
+                    zip_encoding,
+                    unrar_path,
+                    max_depth: unzip_depth as usize,
+                    temp_dir,
+                    jobs: extract_jobs as usize,
+                },
+                force,
+                dry_run,
+            };
+            reextract_archives(ctx, options).await?
+        }
+        Commands::Verify {
+            output,
+            title,
+            author,
+            game_id,
+            zip_encoding,
+            deep,
+        } => {
+            preflight::check_dir("output directory", &output, Access::Read, false)?;
+            verify_extractions(ctx, output, title, author, game_id, zip_encoding, deep).await?
+        }
+    };
+
+    Ok(status)
+}
+
+/// Options for the `extract` command
+struct ReextractOptions {
+    output_path: PathBuf,
+    title_filter: Option<String>,
     author_filter: Option<String>,
+    game_id: Option<u64>,
+    extract: ExtractOptions,
+    force: bool,
+    dry_run: bool,
+}
+
+/// Restore a state export and say what it assumes of the files that have to be moved separately
+fn import_state(
+    ctx: &CommandContext,
+    file: &Path,
+    output_path: &Path,
+    merge: bool,
+) -> Result<RunStatus> {
+    let cache_dir = cache::HttpCache::default_dir(ctx.layout.profile.as_deref());
+    let summary = state::import_state(file, output_path, &ctx.layout, cache_dir.as_deref(), merge)?;
+    let description = &summary.description;
+    ui::status!(
+        "Imported {} state files, {} cached responses and {} receipts exported from {}",
+        summary.written.state_files,
+        summary.written.cache_files,
+        summary.written.receipts,
+        description.output_root.display()
+    );
+    if !summary.merged.is_empty() {
+        ui::status!("Merged with the existing {}", summary.merged.join(", "));
+    }
+    if !summary.kept.is_empty() {
+        ui::status!(
+            "Kept the existing {}, the export's differ",
+            summary.kept.join(", ")
+        );
+    }
+    if summary.rebased_paths > 0 {
+        ui::status!(
+            "Moved {} recorded paths to {}",
+            summary.rebased_paths,
+            output_path.display()
+        );
+    }
+    if let Some(profile) = &description.profile
+        && ctx.layout.profile.as_deref() != Some(profile.as_str())
+    {
+        ui::status!(
+            "Note: the state belonged to profile {} and was imported as {}",
+            profile,
+            ctx.layout.profile.as_deref().map_or(
+                "the default profile".to_string(),
+                |name| format!("profile {}", name)
+            )
+        );
+    }
+    let layout_settings = description.layout;
+    let flags: Vec<&str> = [
+        (layout_settings.per_game_dirs, "--per-game-dirs"),
+        (layout_settings.link_latest, "--link-latest"),
+        (layout_settings.organize, "--organize"),
+        (layout_settings.group_by_bundle, "--group-by-bundle"),
+    ]
+    .into_iter()
+    .filter_map(|(set, flag)| set.then_some(flag))
+    .collect();
+    if !flags.is_empty() {
+        ui::status!(
+            "The mirror was laid out with {}, keep passing them so its files are found",
+            flags.join(" ")
+        );
+    }
+    Ok(RunStatus::Success)
+}
+
+/// The local archives `extract` and `verify` work on, narrowed down by their filters
+fn matching_local_archives(
+    ctx: &CommandContext,
+    output_path: &Path,
     title_filter: Option<String>,
+    author_filter: Option<String>,
+    game_id: Option<u64>,
+) -> Result<Vec<LocalArchive>> {
+    let manifest = Manifest::load(output_path, &ctx.layout)?;
+    let title_filter = title_filter.map(|title| title.to_lowercase());
+    let author_filter = author_filter.map(|author| author.to_lowercase());
+    Ok(find_local_archives(output_path, &manifest)?
+        .into_iter()
+        .filter(|archive| {
+            title_filter
+                .as_ref()
+                .is_none_or(|title| archive.title.to_lowercase().contains(title))
+                && author_filter.as_ref().is_none_or(|author| {
+                    archive
+                        .author
+                        .as_ref()
+                        .is_some_and(|name| name.to_lowercase().contains(author))
+                })
+                && game_id.is_none_or(|id| archive.game_id == Some(id))
+        })
+        .collect())
+}
+
+/// Check the extracted directories of the archives in the output directory against them
+async fn verify_extractions(
+    ctx: &CommandContext,
     output_path: PathBuf,
-    max_concurrent: usize,
-    unzip: bool,
-) -> Result<()> {
-    let api_key = api_key
-        .or_else(|| std::env::var("ITCH_API_KEY").ok())
-        .context("API key is required. Provide it via --api-key flag or ITCH_API_KEY environment variable")?;
+    title_filter: Option<String>,
+    author_filter: Option<String>,
+    game_id: Option<u64>,
+    zip_encoding: ZipEncoding,
+    deep: bool,
+) -> Result<RunStatus> {
+    let ignore = itchignore::IgnoreRules::load(&output_path)?;
+    let mut archives =
+        matching_local_archives(ctx, &output_path, title_filter, author_filter, game_id)?;
+    archives.retain(|archive| !ignore.is_ignored(&archive.path, false));
+    if archives.is_empty() {
+        report_ignored(&ignore);
+        ui::status!("No archives found to verify in {}.", output_path.display());
+        return Ok(RunStatus::NothingMatched);
+    }
+    let manifest = Manifest::load(&output_path, &ctx.layout)?;
+
+    let (mut complete, mut incomplete, mut skipped) = (0, 0, 0);
+    for archive in archives {
+        let filename = archive
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        // The archive itself first, against its sidecar or else what the manifest recorded
+        let recorded = archive
+            .game_id
+            .zip(archive.upload_id)
+            .and_then(|(game_id, upload_id)| manifest.previous(game_id, upload_id))
+            .filter(|record| manifest.resolve(&record.path) == archive.path)
+            .and_then(|record| record.sha256.clone());
+        let expected = match checksums::read_sidecar(&archive.path)? {
+            Some(sha256) => Some((sha256, "its checksum sidecar")),
+            None => recorded.map(|sha256| (sha256, "the manifest")),
+        };
+        if let Some((expected, source)) = expected {
+            let path = archive.path.clone();
+            let actual = tokio::task::spawn_blocking(move || dedupe::file_sha256(&path, |_| {}))
+                .await
+                .context("Hashing task failed")??;
+            if !actual.eq_ignore_ascii_case(&expected) {
+                ui::report!(
+                    "{}: {} doesn't match the SHA-256 in {}",
+                    archive.title,
+                    filename,
+                    source
+                );
+                incomplete += 1;
+                continue;
+            }
+        }
+        if !archive.extract_dir.is_dir() {
+            ui::status!("Skipping {}, it hasn't been extracted", filename);
+            skipped += 1;
+            continue;
+        }
+        let (path, dir, kind) = (
+            archive.path.clone(),
+            archive.extract_dir.clone(),
+            archive.kind,
+        );
+        let audit = tokio::task::spawn_blocking(move || {
+            extract::audit_extraction(&path, kind, &dir, zip_encoding, deep)
+        })
+        .await
+        .context("Audit task failed")?
+        .map(|mut audit| {
+            // Files deliberately changed or removed in the extraction don't count against it
+            audit.findings.retain(|finding| {
+                !ignore.is_ignored(&archive.extract_dir.join(&finding.entry), false)
+            });
+            audit
+        });
+        match audit {
+            Ok(audit) if audit.is_complete() => {
+                ui::report!(
+                    "{}: {} matches {} ({} files{})",
+                    archive.title,
+                    archive.extract_dir.display(),
+                    filename,
+                    audit.files,
+                    if audit.crc_checked > 0 {
+                        format!(", {} CRCs checked", audit.crc_checked)
+                    } else {
+                        String::new()
+                    }
+                );
+                complete += 1;
+            }
+            Ok(audit) => {
+                ui::report!("{}: {}", archive.title, archive.extract_dir.display());
+                for line in audit.describe(&filename) {
+                    ui::report!("  {}", line);
+                }
+                incomplete += 1;
+            }
+            Err(e) => {
+                ui::status!("Skipping {}: {}", filename, e);
+                skipped += 1;
+            }
+        }
+    }
 
-    let client = ItchClient::new(api_key);
-    let owned_keys = client.list_owned_keys().await?;
+    report_ignored(&ignore);
+    ui::status!(
+        "{} complete, {} incomplete, {} skipped",
+        complete,
+        incomplete,
+        skipped
+    );
+    Ok(if incomplete > 0 {
+        RunStatus::Incomplete
+    } else {
+        RunStatus::Success
+    })
+}
 
-    let mut filtered_keys = owned_keys;
+/// An archive found in the output directory and where `extract` would put its contents
+struct LocalArchive {
+    path: PathBuf,
+    kind: ArchiveKind,
+    game_id: Option<u64>,
+    upload_id: Option<u64>,
+    title: String,
+    author: Option<String>,
+    extract_dir: PathBuf,
+}
 
-    // Apply author filter
-    if let Some(author) = &author_filter {
-        filtered_keys.retain(|key| {
-            key.game
-                .user
-                .username
-                .to_lowercase()
-                .contains(&author.to_lowercase())
-                || key
-                    .game
-                    .user
-                    .display_name
-                    .as_ref()
-                    .map(|name| name.to_lowercase().contains(&author.to_lowercase()))
-                    .is_some_and(|b| b)
-        });
+/// Find the archives at the top of the output directory and one level into game directories
+///
+/// The manifest tells which game and upload an archive is, the metadata sidecars come next and
+/// the names on disk last. Files are only taken for archives when both their extension and
+/// their contents say so, so the likes of `.apk` and `.jar` aren't unpacked.
+fn find_local_archives(output_path: &Path, manifest: &Manifest) -> Result<Vec<LocalArchive>> {
+    let skipped = |name: &str| {
+        name.starts_with('.')
+            || staging::is_temp_name(name)
+            || name == runs::RUNS_DIR
+            || name == page::PAGE_DIR
+    };
+    let mut candidates = Vec::new();
+    let entries = std::fs::read_dir(output_path)
+        .with_context(|| format!("Failed to read {}", output_path.display()))?;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if skipped(&name) {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_file() {
+            candidates.push((path, false));
+        } else if path.is_dir() {
+            // A sidecar recording organized files means its category folders hold archives too
+            let organized = export::GameMetadata::find(&path)
+                .is_some_and(|metadata| !metadata.organized.is_empty());
+            for inner in std::fs::read_dir(&path).into_iter().flatten().flatten() {
+                let inner_name = inner.file_name().to_string_lossy().into_owned();
+                if skipped(&inner_name) {
+                    continue;
+                }
+                if inner.path().is_file() {
+                    candidates.push((inner.path(), true));
+                } else if organized && layout::Category::is_dir_name(&inner_name) {
+                    for file in std::fs::read_dir(inner.path())
+                        .into_iter()
+                        .flatten()
+                        .flatten()
+                    {
+                        let file_name = file.file_name().to_string_lossy().into_owned();
+                        if !skipped(&file_name) && file.path().is_file() {
+                            candidates.push((file.path(), true));
+                        }
+                    }
+                }
+            }
+        }
     }
+    candidates.sort();
+
+    let mut archives = Vec::new();
+    for (path, in_game_dir) in candidates {
+        let Some(filename) = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+        else {
+            continue;
+        };
+        if ArchiveKind::from_filename(&filename).is_none() {
+            continue;
+        }
+        let Some(kind) = ArchiveKind::detect(&path, &filename) else {
+            continue;
+        };
 
-    // Apply title filter
-    if let Some(title) = &title_filter {
-        filtered_keys.retain(|key| {
-            key.game
-                .title
-                .to_lowercase()
-                .contains(&title.to_lowercase())
+        let recorded = manifest.games().find_map(|(game_id, game)| {
+            game.uploads.iter().find_map(|(&upload_id, upload)| {
+                let stored = manifest.resolve(&upload.path);
+                let same = stored == path
+                    || (upload.filename == filename && stored.parent() == path.parent());
+                same.then(|| (game_id, upload_id, game.title.clone()))
+            })
+        });
+        let metadata = export::GameMetadata::find(&path);
+        let stem = extract::strip_archive_extension(&filename).to_string();
+        let (game_id, upload_id, title) = match (recorded, &metadata) {
+            (Some((game_id, upload_id, title)), _) => (Some(game_id), Some(upload_id), Some(title)),
+            (None, Some(metadata)) => (Some(metadata.game_id), None, Some(metadata.title.clone())),
+            (None, None) => (None, None, None),
+        };
+        let extract_dir = match (&title, path.parent()) {
+            (_, Some(parent)) if in_game_dir => parent.join(&stem),
+            (Some(title), _) => {
+                output_path.join(layout::game_dir_name(title, game_id.unwrap_or(0)))
+            }
+            (None, _) => output_path.join(&stem),
+        };
+        // The game's directory, above the category folder for an organized file
+        let game_dir = path.parent().map(|dir| match dir.parent() {
+            Some(above)
+                if above != output_path
+                    && dir.file_name().is_some_and(|name| {
+                        layout::Category::is_dir_name(&name.to_string_lossy())
+                    }) =>
+            {
+                above
+            }
+            _ => dir,
+        });
+        let title = title.unwrap_or_else(|| match game_dir.and_then(Path::file_name) {
+            Some(dir) if in_game_dir => dir.to_string_lossy().into_owned(),
+            _ => stem.clone(),
+        });
+        archives.push(LocalArchive {
+            path,
+            kind,
+            game_id,
+            upload_id,
+            title,
+            author: metadata.and_then(|metadata| metadata.developer),
+            extract_dir,
         });
     }
+    Ok(archives)
+}
 
-    if filtered_keys.is_empty() {
-        println!("No packages found to download.");
-        return Ok(());
+/// Run the extraction pipeline over archives already downloaded, with the current options
+///
+/// An archive whose extraction directory is already there is skipped unless `--force` is given,
+/// since how a directory was extracted isn't recorded. Archives are kept either way.
+async fn reextract_archives(ctx: &CommandContext, options: ReextractOptions) -> Result<RunStatus> {
+    let ReextractOptions {
+        output_path,
+        title_filter,
+        author_filter,
+        game_id,
+        extract: extract_options,
+        force,
+        dry_run,
+    } = options;
+
+    let archives =
+        matching_local_archives(ctx, &output_path, title_filter, author_filter, game_id)?;
+    if archives.is_empty() {
+        ui::status!("No archives found to extract in {}.", output_path.display());
+        return Ok(RunStatus::NothingMatched);
     }
 
-    // Create output directory if it doesn't exist
-    tokio::fs::create_dir_all(&output_path)
-        .await
-        .context("Failed to create output directory")?;
+    let _lock = (!dry_run)
+        .then(|| lock::OutputLock::acquire(&output_path))
+        .transpose()?;
+    let cancel = CancellationToken::new();
+    let interrupt = cancel.clone();
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        interrupt.cancel();
+    });
 
-    println!("Found {} packages to download", filtered_keys.len());
+    let multi_progress = ui::multi_progress();
+    let (mut extracted, mut skipped, mut failed) = (0, 0, 0);
+    for archive in &archives {
+        let filename = archive
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        if !extract_options.can_extract(archive.kind) {
+            ui::println(
+                &multi_progress,
+                format!("Skipping {}, RAR archives need --unrar-path", filename),
+            );
+            skipped += 1;
+            continue;
+        }
+        if archive.extract_dir.exists() && !force {
+            let held = extract::extracted_upload(&archive.extract_dir);
+            let note = match (held, archive.upload_id) {
+                (Some(held), Some(upload)) if held != upload => {
+                    format!(", it holds upload {} (use --force to replace it)", held)
+                }
+                _ => String::new(),
+            };
+            ui::println(
+                &multi_progress,
+                format!(
+                    "Skipping {}, already extracted to {}{}",
+                    filename,
+                    archive.extract_dir.display(),
+                    note
+                ),
+            );
+            skipped += 1;
+            continue;
+        }
+        if dry_run {
+            ui::report!(
+                "{} -> {}",
+                archive.path.display(),
+                archive.extract_dir.display()
+            );
+            extracted += 1;
+            continue;
+        }
 
-    let multi_progress = MultiProgress::new();
-    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(max_concurrent));
+        let progress_bar = multi_progress.add(ProgressBar::new(0));
+        progress_bar.set_style(ui::bar_style());
+        progress_bar.set_prefix(truncate_to_width(&archive.title, 30));
+        progress_bar.set_message(format!("Extracting {}", filename));
+        let extract_bar = progress_bar.clone();
+        let result = extract_archive(
+            &archive.path,
+            &archive.extract_dir,
+            archive.kind,
+            &extract_options,
+            &cancel,
+            move |written, total| {
+                extract_bar.set_length(total);
+                extract_bar.set_position(written);
+            },
+        )
+        .await;
+        match result {
+            Ok(()) => {
+                if let Some(upload_id) = archive.upload_id {
+                    let _ = extract::mark_extracted(&archive.extract_dir, upload_id);
+                }
+                ui::finish(
+                    &progress_bar,
+                    format!(
+                        "Extracted {} to {}",
+                        filename,
+                        archive.extract_dir.display()
+                    ),
+                );
+                extracted += 1;
+            }
+            Err(ItchError::Cancelled) => {
+                ui::fail(&progress_bar, format!("Stopped extracting {}", filename));
+                return Ok(RunStatus::Interrupted);
+            }
+            Err(e) => {
+                ui::fail(
+                    &progress_bar,
+                    format!("Failed to extract {}: {}", filename, e),
+                );
+                failed += 1;
+            }
+        }
+    }
 
-    // Create download tasks
-    let download_tasks: Vec<_> = filtered_keys
-        .into_iter()
-        .map(|key| {
-            let client = client.clone();
-            let output_path = output_path.clone();
-            let multi_progress = multi_progress.clone();
-            let semaphore = semaphore.clone();
-
-            tokio::spawn(async move {
-                let _permit = semaphore.acquire().await.unwrap();
-
-                // Get uploads for this game
-                let uploads = match client.get_game_uploads(key.game_id, key.id).await {
-                    Ok(uploads) => uploads,
-                    Err(e) => {
-                        eprintln!("Failed to get uploads for {}: {}", key.game.title, e);
-                        return;
-                    }
-                };
+    ui::status!(
+        "{} {}, {} skipped, {} failed",
+        if dry_run {
+            "Would extract"
+        } else {
+            "Extracted"
+        },
+        extracted,
+        skipped,
+        failed
+    );
+    Ok(if failed > 0 {
+        RunStatus::PartialFailure
+    } else {
+        RunStatus::Success
+    })
+}
 
-                // Find zip file (prefer zip over other formats)
-                let zip_upload = uploads
-                    .iter()
-                    .find(|upload| upload.filename.to_lowercase().ends_with(".zip"));
+/// Move a run directory's files into the main layout and point the manifest at them
+fn promote_run(
+    ctx: &CommandContext,
+    output_path: PathBuf,
+    run: Option<String>,
+) -> Result<RunStatus> {
+    let runs_dir = output_path.join(runs::RUNS_DIR);
+    let run_dir = match run {
+        Some(run) if Path::new(&run).is_dir() => PathBuf::from(run),
+        Some(run) => runs_dir.join(run),
+        // Run directories are named after when they started, so the last name is the newest
+        None => std::fs::read_dir(&runs_dir)
+            .ok()
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .max()
+            .with_context(|| format!("No runs in {}", runs_dir.display()))?,
+    };
+    if !run_dir.is_dir() {
+        anyhow::bail!("No run directory at {}", run_dir.display());
+    }
 
-                let upload = match zip_upload.or_else(|| uploads.first()) {
-                    Some(upload) => upload,
-                    None => {
-                        eprintln!("No uploads found for {}", key.game.title);
-                        return;
-                    }
-                };
+    let _lock = lock::OutputLock::acquire(&output_path)?;
+    let promoted = runs::promote(&run_dir, &output_path)?;
+    // `sync` recorded the run's downloads under the run directory
+    if let Some(name) = run_dir.file_name() {
+        let mut manifest = Manifest::load(&output_path, &ctx.layout)?;
+        manifest.strip_prefix(&Path::new(runs::RUNS_DIR).join(name));
+        manifest.save()?;
+    }
 
-                // Create progress bar
-                let progress_bar = multi_progress.add(ProgressBar::new(upload.size));
-                progress_bar.set_style(
-                    ProgressStyle::default_bar()
-                        .template("{msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                        .unwrap()
-                        .progress_chars("#>-"),
-                );
-                progress_bar.set_message(format!("Downloading {}", upload.filename));
-
-                // Download the file
-                let download_result = client
-                    .download_file(
-                        upload.id,
-                        key.id,
-                        &upload.filename,
-                        &output_path,
-                        progress_bar.clone(),
-                    )
-                    .await;
-
-                match download_result {
-                    Ok(()) => {
-                        // If unzip is enabled and the file is a zip, extract it
-                        if unzip && upload.filename.to_lowercase().ends_with(".zip") {
-                            progress_bar.set_message(format!("Extracting {}", upload.filename));
-                            let zip_path = output_path.join(&upload.filename);
-
-                            // Create a directory named after the game for extraction
-                            let extract_dir = output_path
-                                .join(&key.game.title.replace("/", "_").replace("\\", "_"));
-
-                            match unzip_file(&zip_path, &extract_dir).await {
-                                Ok(()) => {
-                                    progress_bar.finish_with_message(format!(
-                                        "Downloaded and extracted {}",
-                                        upload.filename
-                                    ));
-                                    // Optionally remove the zip file after extraction
-                                    let _ = tokio::fs::remove_file(&zip_path).await;
-                                }
-                                Err(e) => {
-                                    progress_bar.finish_with_message(format!(
-                                        "Downloaded {} but failed to extract: {}",
-                                        upload.filename, e
-                                    ));
-                                    eprintln!("Failed to extract {}: {}", upload.filename, e);
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        progress_bar.finish_with_message(format!("Failed: {}", e));
-                        eprintln!("Failed to download {}: {}", upload.filename, e);
-                    }
-                }
-            })
-        })
-        .collect();
+    for path in &promoted.added {
+        ui::report!("  added {}", path.display());
+    }
+    for path in &promoted.replaced {
+        ui::report!("  replaced {}", path.display());
+    }
+    ui::status!(
+        "Promoted {}: {} added, {} replaced",
+        run_dir.display(),
+        promoted.added.len(),
+        promoted.replaced.len()
+    );
+    Ok(RunStatus::Success)
+}
+
+/// What every command runs with, from the global flags and the config file
+#[derive(Default)]
+struct CommandContext {
+    /// Which profile's state the run uses and how it names game directories, from `--profile` and
+    /// `--dir-template`
+    layout: layout::Layout,
+    /// How every client the command creates is set up
+    client_builder: ItchClientBuilder,
+    /// The `[bundle_sets.<name>]` sections of the config file
+    bundle_sets: std::collections::BTreeMap<String, UploadSet>,
+    /// Offline clients the run created, so how old their cached data was can be reported at the end
+    offline_clients: std::sync::Mutex<Vec<ItchClient>>,
+}
+
+impl CommandContext {
+    /// A client for `api_key` set up from the global flags
+    fn new_client(&self, api_key: String) -> Result<ItchClient> {
+        let client = self.client_builder.clone().api_key(api_key).build()?;
+        if client.is_offline() {
+            self.offline_clients.lock().unwrap().push(client.clone());
+        }
+        Ok(client)
+    }
 
-    // Wait for all downloads to complete
-    for task in download_tasks {
-        let _ = task.await;
+    /// The set `--bundle-set` names, one from the config file or else one made of `+`-separated
+    /// parts
+    fn resolve_bundle_set(&self, name: &str) -> Result<(String, UploadSet)> {
+        if let Some(set) = self.bundle_sets.get(name) {
+            return Ok((name.to_string(), set.clone()));
+        }
+        let set = name
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid --bundle-set {}: {}", name, e))?;
+        Ok((name.to_string(), set))
+    }
+
+    /// Say how old the cached data an offline run showed was
+    fn report_offline_age(&self) {
+        let clients = self.offline_clients.lock().unwrap();
+        if clients.is_empty() {
+            return;
+        }
+        let oldest = clients.iter().filter_map(ItchClient::offline_since).min();
+        match oldest {
+            Some(since) => ui::status!(
+                "Offline: the oldest cached data shown was fetched {}",
+                ui::date(Some(since))
+            ),
+            None => ui::status!("Offline: the cached data shown is of unknown age"),
+        }
+    }
+}
+
+/// How long fetching the `--proxy-pac` script may take
+const PAC_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The text of the PAC script `--proxy-pac` names, fetched directly when it's a URL
+async fn load_proxy_pac(source: &str) -> Result<String> {
+    if !(source.starts_with("http://") || source.starts_with("https://")) {
+        return std::fs::read_to_string(source)
+            .with_context(|| format!("Failed to read the PAC script {}", source));
     }
+    // The script is what says which proxy to use, so it can't be fetched through one
+    let fetch = async {
+        reqwest::Client::builder()
+            .no_proxy()
+            .timeout(PAC_FETCH_TIMEOUT)
+            .build()?
+            .get(source)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+    };
+    fetch
+        .await
+        .with_context(|| format!("Failed to fetch the PAC script {}", source))
+}
 
-    println!("All downloads completed!");
-    Ok(())
+/// Turn the global flags into the settings every client is built with
+fn client_builder(
+    cli: &Cli,
+    config: &config::Config,
+    proxy_pac: Option<String>,
+) -> Result<ItchClientBuilder> {
+    let mut builder = ItchClient::builder()
+        .base_url(cli.api_base_url.clone())
+        .api_version(cli.api)
+        .page_size(cli.per_page)
+        .refresh_uploads(cli.refresh_uploads)
+        .offline(cli.offline)
+        .retry_policy(RetryPolicy {
+            max_retries: cli.max_retries,
+            ..RetryPolicy::default()
+        });
+    if let Some(user_agent) = &cli.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+    if let Some(profile) = &config.profile {
+        builder = builder.profile(profile);
+    }
+    if let Some(timeout) = cli.timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = cli.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(proxy) = &cli.proxy {
+        builder = builder.proxy(proxy.clone());
+    }
+    if let Some(list) = &cli.no_proxy {
+        builder = builder.no_proxy(proxy::NoProxy::parse(list));
+    }
+    if let Some(script) = proxy_pac {
+        builder = builder.proxy_pac(script);
+    }
+    if let Some(rate) = cli.rate_limit {
+        builder = builder.rate_limit(rate);
+    }
+    if let Some(requests) = cli.max_requests_per_minute {
+        builder = builder.max_requests_per_minute(requests);
+    }
+    for rewrite in &cli.cdn_rewrite {
+        builder = builder.cdn_rewrite(rewrite.clone());
+    }
+    for (host, settings) in &config.hosts {
+        builder = builder.host_policy(host, settings.policy()?);
+    }
+    if let Some(dir) = &cli.record_fixtures {
+        builder = builder.record_fixtures(dir, cli.fixtures_keep_names);
+    }
+    if let Some(dir) = &cli.replay_fixtures {
+        builder = builder.replay_fixtures(dir);
+    }
+    Ok(builder.cdn_insecure(cli.cdn_insecure))
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
+async fn main() -> Result<ExitCode> {
+    // The file's values become clap's defaults, so it has to be read before parsing the rest
+    let config = config::Config::load(
+        config::path_from_args(std::env::args_os()).as_deref(),
+        config::profile_from_args(std::env::args_os()).as_deref(),
+    )?;
+    let matches = config.apply_defaults(Cli::command()).get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let raw_stdout = matches!(cli.command, Commands::Dl { stdout: true, .. });
+    ui::init(
+        cli.quiet,
+        cli.no_progress,
+        cli.porcelain,
+        raw_stdout,
+        cli.dates,
+        cli.flat_progress,
+    );
+    ui::set_bar_style(&cli.progress_template, &cli.progress_chars)?;
+    // Held until main returns, when the trace is written out in full
+    let _trace = logging::init(
+        cli.verbose,
+        cli.log_file.as_deref(),
+        cli.trace_file.as_deref(),
+    )?;
+    if let Some(dir) = &cli.debug_http {
+        http_dump::init(dir)?;
+    }
+    // Every client the run creates, e.g. one per `dl --watch` cycle, reuses the same connections
+    let proxy_pac = match &cli.proxy_pac {
+        Some(source) => Some(load_proxy_pac(source).await?),
+        None => None,
+    };
+    let ctx = CommandContext {
+        layout: layout::Layout {
+            profile: config.profile.clone(),
+            dir_template: cli.dir_template.clone(),
+        },
+        client_builder: client_builder(&cli, &config, proxy_pac)?.share_connections()?,
+        bundle_sets: config.bundle_sets.clone(),
+        offline_clients: Default::default(),
+    };
 
-    match cli.command {
-        Commands::Ls {
-            api_key,
-            author,
-            title,
-        } => {
-            list_packages(api_key, author, title).await?;
+    let strict_empty = cli.strict_empty;
+    match run(cli, config, &ctx).await {
+        Ok(status) => Ok(status.exit_code(strict_empty)),
+        Err(e) => match e.downcast_ref::<ItchError>() {
+            Some(ItchError::Auth { .. } | ItchError::LegacyKey) => {
+                eprintln!("Error: {:?}", e);
+                Ok(ExitCode::from(EXIT_AUTH_FAILURE))
+            }
+            Some(ItchError::RateLimited { .. }) => {
+                eprintln!("Error: {:?}", e);
+                eprintln!("itch.io is rate limiting this key, wait a few minutes and run again");
+                Ok(ExitCode::from(EXIT_RATE_LIMITED))
+            }
+            Some(ItchError::Decode { .. }) => {
+                eprintln!("Error: {:?}", e);
+                eprintln!(
+                    "itch.io may have changed its API, please report this with the fixtures saved by \
+                     --record-fixtures (or the output of --debug-http)"
+                );
+                Ok(ExitCode::FAILURE)
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_fits(lines: &[String], max_width: usize) {
+        for line in lines {
+            assert!(
+                line.width() <= max_width,
+                "{:?} is wider than {}",
+                line,
+                max_width
+            );
         }
-        Commands::Dl {
-            api_key,
-            author,
-            title,
-            output,
-            max_concurrent,
-            unzip,
-        } => {
-            download_packages(api_key, author, title, output, max_concurrent, unzip).await?;
+    }
+
+    #[test]
+    fn text_that_fits_is_one_line() {
+        assert_eq!(wrap_to_width("Celeste", 10), vec!["Celeste"]);
+        assert_eq!(wrap_to_width("", 10), vec![""]);
+    }
+
+    #[test]
+    fn wraps_between_words() {
+        let lines = wrap_to_width("A Short Hike with friends", 12);
+        assert_eq!(lines, vec!["A Short Hike", "with friends"]);
+    }
+
+    #[test]
+    fn wide_characters_count_twice() {
+        // Each character takes two cells, so five of them fill a line of ten
+        let lines = wrap_to_width("ゆめにっきゆめにっき", 10);
+        assert_eq!(lines, vec!["ゆめにっき", "ゆめにっき"]);
+
+        let lines = wrap_to_width("東方 紅魔郷 the Embodiment of Scarlet Devil", 9);
+        assert_fits(&lines, 9);
+        assert_eq!(lines[0], "東方");
+        assert_eq!(
+            lines.concat().replace(' ', ""),
+            "東方紅魔郷theEmbodimentofScarletDevil"
+        );
+    }
+
+    #[test]
+    fn wide_characters_are_not_split_across_an_odd_width() {
+        // A line of seven holds three wide characters, never three and a half
+        let lines = wrap_to_width("あいうえおかきくけこ", 7);
+        assert_eq!(lines, vec!["あいう", "えおか", "きくけ", "こ"]);
+        assert_fits(&lines, 7);
+    }
+
+    #[test]
+    fn long_words_are_split_between_characters() {
+        let lines = wrap_to_width("Supercalifragilistic game", 8);
+        assert_eq!(lines, vec!["Supercal", "ifragili", "stic", "game"]);
+    }
+
+    #[test]
+    fn emoji_sequences_stay_together() {
+        // The family is four emoji joined by zero-width joiners, which mustn't start a line
+        let family = "👨\u{200d}👩\u{200d}👧\u{200d}👦";
+        let lines = wrap_to_width(&format!("🎮🎮🎮{}🎮", family), 6);
+        for line in &lines {
+            assert!(!line.starts_with('\u{200d}'), "{:?} starts at a join", line);
+            assert!(!line.ends_with('\u{200d}'), "{:?} ends at a join", line);
         }
+        assert_eq!(lines.concat(), format!("🎮🎮🎮{}🎮", family));
+
+        let lines = wrap_to_width("Neon 🌃 Drive 🚗 Deluxe", 8);
+        assert_fits(&lines, 8);
+        assert_eq!(lines, vec!["Neon 🌃", "Drive 🚗", "Deluxe"]);
+    }
+
+    #[test]
+    fn truncates_to_width_with_wide_characters() {
+        assert_eq!(truncate_to_width("Celeste", 10), "Celeste");
+        assert_eq!(truncate_to_width("A Short Hike", 8), "A Sho...");
+        // Two wide characters and the ellipsis fill seven cells, a third wouldn't fit
+        let truncated = truncate_to_width("ゆめにっき", 8);
+        assert_eq!(truncated, "ゆめ...");
+        assert!(truncated.width() <= 8);
+        assert_eq!(truncate_to_width("🎮🎮🎮🎮", 7), "🎮🎮...");
+        assert_eq!(truncate_to_width("Celeste", 2), "..");
+    }
+
+    #[test]
+    fn pads_to_width_with_wide_characters() {
+        assert_eq!(pad_to_width("ゆめ", 6), "ゆめ  ");
+        assert_eq!(pad_to_width("🎮", 3), "🎮 ");
+        assert_eq!(pad_to_width("Celeste", 3), "Celeste");
+    }
+
+    fn row() -> Vec<ListCell> {
+        vec![
+            ListCell::new("ID", "42", 4, 5),
+            ListCell::new("Title", "ゆめにっき 夢日記 Yume Nikki", 10, 11),
+            ListCell::new("Author", "Kikiyama", 8, 8),
+        ]
+    }
+
+    #[test]
+    fn wrapped_rows_keep_their_columns() {
+        let lines = full_row_lines(&row(), Overflow::Wrap, Some(40));
+        assert_eq!(
+            lines,
+            vec![
+                "42    ゆめにっき  Kikiyama",
+                "      夢日記",
+                "      Yume Nikki",
+            ]
+        );
+        assert_fits(&lines, 40);
     }
 
-    Ok(())
+    #[test]
+    fn the_last_column_wraps_at_the_terminal() {
+        let cells = vec![
+            ListCell::new("ID", "42", 4, 5),
+            ListCell::new(
+                "Title",
+                "東方紅魔郷 the Embodiment of Scarlet Devil",
+                80,
+                80,
+            ),
+        ];
+        let lines = full_row_lines(&cells, Overflow::Wrap, Some(20));
+        assert!(lines.len() > 1);
+        assert_fits(&lines, 20);
+        // Piped output keeps the column as wide as it was laid out
+        let lines = full_row_lines(&cells, Overflow::Wrap, None);
+        assert_eq!(lines.len(), 1);
+    }
+
+    #[test]
+    fn records_wrap_after_their_labels() {
+        let lines = full_row_lines(&row(), Overflow::Records, Some(20));
+        assert_fits(&lines, 20);
+        assert_eq!(lines[0], "ID:     42");
+        assert_eq!(lines[1], "Title:  ゆめにっき");
+        assert!(lines[2].starts_with("        "));
+        assert_eq!(lines.last().unwrap(), "");
+
+        // Without a terminal each value stays on one line
+        let lines = full_row_lines(&row(), Overflow::Records, None);
+        assert_eq!(
+            lines,
+            vec![
+                "ID:     42",
+                "Title:  ゆめにっき 夢日記 Yume Nikki",
+                "Author: Kikiyama",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn default_download_options_stream() {
+        let output = tempfile::tempdir().unwrap();
+        let cli = Cli::try_parse_from([
+            "itch-downloader",
+            "dl",
+            "--output",
+            output.path().to_str().unwrap(),
+        ])
+        .unwrap();
+        let Commands::Dl { download, .. } = cli.command else {
+            panic!("expected dl");
+        };
+        let options = download
+            .download_options(&CommandContext::default())
+            .unwrap();
+        // Asking above a count only holds the downloads back, it doesn't stop them streaming
+        assert_eq!(options.confirm_above, Some(10));
+        assert!(options.streams());
+
+        let options = mirror::DownloadOptions {
+            spread_over: Some(Duration::from_secs(3600)),
+            ..options
+        };
+        assert!(!options.streams());
+    }
 }
@@ -0,0 +1,283 @@
+use crate::error::{Context, Result};
+use crate::layout::Layout;
+use crate::timestamp::{self, Timestamp};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Name of the manifest file inside the state directory
+pub(crate) const MANIFEST_FILE: &str = "manifest.json";
+
+/// What we know about one downloaded upload
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadRecord {
+    pub filename: String,
+    /// Where the download ended up relative to the output directory, the extraction directory
+    /// when the archive was extracted
+    pub path: PathBuf,
+    pub size: u64,
+    /// MD5 reported by itch for the upload, when it reports one
+    pub md5: Option<String>,
+    /// SHA-256 of the bytes we actually downloaded, unknown for imported installs
+    #[serde(default)]
+    pub sha256: Option<String>,
+    pub build_id: Option<u64>,
+    /// When itch last saw the upload change
+    #[serde(default, deserialize_with = "crate::json::timestamp")]
+    pub updated_at: Option<Timestamp>,
+    /// Seconds since the Unix epoch
+    pub downloaded_at: u64,
+}
+
+impl UploadRecord {
+    /// Whether this is a newer version than the one given, by build id when both have one and
+    /// otherwise by when itch last saw the upload change
+    pub fn is_newer_than(&self, build_id: Option<u64>, updated_at: Option<Timestamp>) -> bool {
+        match ((self.build_id, build_id), (self.updated_at, updated_at)) {
+            ((Some(recorded), Some(build_id)), _) => recorded > build_id,
+            (_, (Some(recorded), Some(updated_at))) => recorded > updated_at,
+            _ => false,
+        }
+    }
+}
+
+/// Everything downloaded for a single game
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GameRecord {
+    pub title: String,
+    pub download_key_id: u64,
+    pub uploads: BTreeMap<u64, UploadRecord>,
+    /// itch's download count for the key when last listed, plus the downloads we made since
+    #[serde(default)]
+    pub key_downloads: Option<u64>,
+}
+
+/// The durable record of what a `sync` has put in the output directory, keyed by game id
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    games: BTreeMap<u64, GameRecord>,
+    #[serde(skip)]
+    root: PathBuf,
+    #[serde(skip)]
+    state_dir: PathBuf,
+}
+
+impl Manifest {
+    /// Load the manifest kept in the output directory's state for `layout`, starting empty if
+    /// there isn't one yet
+    pub fn load(output_path: &Path, layout: &Layout) -> Result<Self> {
+        let state_dir = layout.state_dir(output_path);
+        let path = state_dir.join(MANIFEST_FILE);
+        let mut manifest: Manifest = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        manifest.root = output_path.to_path_buf();
+        manifest.state_dir = state_dir;
+        Ok(manifest)
+    }
+
+    /// Write the manifest back to the output directory
+    ///
+    /// It's written to a temporary file and renamed into place so an interrupted run can't leave
+    /// a truncated manifest behind.
+    pub fn save(&self) -> Result<()> {
+        let state_dir = &self.state_dir;
+        std::fs::create_dir_all(state_dir).context("Failed to create state directory")?;
+        let contents =
+            serde_json::to_string_pretty(self).context("Failed to serialize manifest")?;
+        let temp_path = state_dir.join(format!("{}.tmp", MANIFEST_FILE));
+        std::fs::write(&temp_path, contents).context("Failed to write manifest")?;
+        std::fs::rename(&temp_path, state_dir.join(MANIFEST_FILE))
+            .context("Failed to move manifest into place")
+    }
+
+    /// Whether the given upload was already downloaded in the same version and is still on disk
+    pub fn is_current(
+        &self,
+        game_id: u64,
+        upload_id: u64,
+        size: u64,
+        md5: Option<&str>,
+        build_id: Option<u64>,
+        updated_at: Option<Timestamp>,
+    ) -> bool {
+        let Some(record) = self
+            .games
+            .get(&game_id)
+            .and_then(|game| game.uploads.get(&upload_id))
+        else {
+            return false;
+        };
+
+        // Not every upload has a checksum, so only compare when both sides know one
+        let md5_matches = match (record.md5.as_deref(), md5) {
+            (Some(recorded), Some(md5)) => recorded.eq_ignore_ascii_case(md5),
+            _ => true,
+        };
+        let updated_at_matches = match (record.updated_at, updated_at) {
+            (Some(recorded), Some(updated_at)) => recorded == updated_at,
+            _ => true,
+        };
+        record.size == size
+            && md5_matches
+            && updated_at_matches
+            && record.build_id == build_id
+            && self.root.join(&record.path).exists()
+    }
+
+    /// What was last downloaded for the given upload, if anything
+    pub fn previous(&self, game_id: u64, upload_id: u64) -> Option<&UploadRecord> {
+        self.games
+            .get(&game_id)
+            .and_then(|game| game.uploads.get(&upload_id))
+    }
+
+    /// Record a finished download, replacing whatever was known about that upload before
+    pub fn record(
+        &mut self,
+        game_id: u64,
+        title: &str,
+        download_key_id: u64,
+        upload_id: u64,
+        record: UploadRecord,
+    ) {
+        let game = self.games.entry(game_id).or_insert_with(|| GameRecord {
+            title: title.to_string(),
+            download_key_id,
+            uploads: BTreeMap::new(),
+            key_downloads: None,
+        });
+        game.title = title.to_string();
+        game.download_key_id = download_key_id;
+        game.uploads.insert(upload_id, record);
+    }
+
+    /// How many more times itch says the game's key was used than we account for, when it was
+    ///
+    /// Someone else downloading with the key shows up here, though so do downloads made through
+    /// the website or another tool.
+    pub fn unexplained_downloads(&self, game_id: u64, downloads: u64) -> Option<u64> {
+        let expected = self.games.get(&game_id)?.key_downloads?;
+        downloads.checked_sub(expected).filter(|&extra| extra > 0)
+    }
+
+    /// Remember itch's download count for a game's key, if the game is in the manifest
+    pub fn observe_key_downloads(&mut self, game_id: u64, downloads: u64) {
+        if let Some(game) = self.games.get_mut(&game_id) {
+            game.key_downloads = Some(downloads);
+        }
+    }
+
+    /// Path relative to the output directory when possible, so the mirror can be moved around
+    pub fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root)
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|_| path.to_path_buf())
+    }
+
+    /// Add what `other` knows that this manifest doesn't, keeping this one's records where both
+    /// have the same upload
+    pub fn merge(&mut self, other: Manifest) {
+        for (game_id, game) in other.games {
+            match self.games.get_mut(&game_id) {
+                Some(existing) => {
+                    for (upload_id, upload) in game.uploads {
+                        existing.uploads.entry(upload_id).or_insert(upload);
+                    }
+                }
+                None => {
+                    self.games.insert(game_id, game);
+                }
+            }
+        }
+    }
+
+    /// Make absolute paths under `old_root` relative, as they were recorded for a mirror that has
+    /// since moved, returning how many changed
+    pub fn rebase(&mut self, old_root: &Path) -> usize {
+        let mut rebased = 0;
+        for upload in self
+            .games
+            .values_mut()
+            .flat_map(|game| game.uploads.values_mut())
+        {
+            if let Ok(relative) = upload.path.strip_prefix(old_root) {
+                upload.path = relative.to_path_buf();
+                rebased += 1;
+            }
+        }
+        rebased
+    }
+
+    /// Drop every game that isn't in `owned_game_ids`, returning what was dropped
+    pub fn retain_owned(&mut self, owned_game_ids: &HashSet<u64>) -> Vec<GameRecord> {
+        let (owned, removed) = std::mem::take(&mut self.games)
+            .into_iter()
+            .partition(|(game_id, _)| owned_game_ids.contains(game_id));
+        self.games = owned;
+        removed.into_values().collect()
+    }
+
+    /// Every game in the manifest with its id
+    pub fn games(&self) -> impl Iterator<Item = (u64, &GameRecord)> {
+        self.games.iter().map(|(&game_id, game)| (game_id, game))
+    }
+
+    /// Forget every upload stored under one of the given top level names in the output directory
+    pub fn forget_top_level(&mut self, names: &HashSet<String>) {
+        for game in self.games.values_mut() {
+            game.uploads.retain(|_, upload| {
+                !top_level_name(&upload.path).is_some_and(|name| names.contains(&name))
+            });
+        }
+        self.games.retain(|_, game| !game.uploads.is_empty());
+    }
+
+    /// Record uploads stored under `prefix` at the same path without it, e.g. once a run directory
+    /// was promoted into the main layout
+    pub fn strip_prefix(&mut self, prefix: &Path) {
+        for upload in self
+            .games
+            .values_mut()
+            .flat_map(|game| game.uploads.values_mut())
+        {
+            if let Ok(rest) = upload.path.strip_prefix(prefix) {
+                upload.path = rest.to_path_buf();
+            }
+        }
+    }
+
+    /// Absolute path of something recorded relative to the output directory
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        self.root.join(path)
+    }
+}
+
+/// The first component of a path recorded relative to the output directory
+pub fn top_level_name(path: &Path) -> Option<String> {
+    match path.components().next()? {
+        std::path::Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+        _ => None,
+    }
+}
+
+/// Human readable description of an upload's version, for summaries and kept file names
+pub fn version_label(build_id: Option<u64>, updated_at: Option<Timestamp>) -> String {
+    match (build_id, updated_at) {
+        (Some(build_id), _) => format!("build {}", build_id),
+        // Only the date, the full timestamp has characters Windows doesn't allow in names
+        (None, Some(updated_at)) => format!("updated {}", timestamp::date(updated_at)),
+        (None, None) => "unknown version".to_string(),
+    }
+}
+
+/// Seconds since the Unix epoch, for timestamps in the manifest
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,2259 @@
+//! A whole `dl` or `sync` run: pick keys from the library, resolve their uploads, download and
+//! extract them, and keep the mirror's bookkeeping up to date, telling an [`Observer`] what happens
+
+use crate::budget::{self, Budget};
+use crate::checksums;
+use crate::dedupe::HashIndex;
+use crate::error::{Context, Result, bail};
+use crate::export;
+use crate::extract::{
+    self, ArchiveKind, ExtractConflict, ExtractDecision, ExtractOptions, ZipEncoding,
+    extract_archive,
+};
+use crate::failures;
+use crate::filter_file::FilterFile;
+use crate::filters::KeySelection;
+use crate::history::{self, Outcome};
+use crate::itchignore;
+use crate::layout::{self, Layout};
+use crate::lock;
+use crate::manifest::{self, Manifest, UploadRecord};
+use crate::page;
+use crate::plan;
+use crate::receipt;
+use crate::report;
+use crate::runs;
+use crate::schedule;
+use crate::seed::{self, SeedDirs};
+use crate::since;
+use crate::skips::Skips;
+use crate::staging;
+use crate::timestamp;
+use crate::{
+    CLOCK_SKEW_THRESHOLD, CollectionGame, DownloadProgress, Game, ItchClient, KeyFilter, OwnedKey,
+    SaveOptions, SelectionPolicy, Upload, UploadSet, choose_upload, choose_uploads,
+    upload_filename,
+};
+use futures::stream::{StreamExt, TryStreamExt};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, info};
+
+/// Why a game without any uploads failed, which `fail_fast` doesn't stop for
+const NO_UPLOADS: &str = "No uploads found";
+
+/// Everything that controls how a run downloads and extracts packages
+pub struct DownloadOptions {
+    pub output_path: PathBuf,
+    /// Which profile's state the mirror is kept with and how its game directories are named
+    pub layout: Layout,
+    /// Only download games whose author matches this
+    pub author: Option<String>,
+    /// Only download games whose title matches this
+    pub title: Option<String>,
+    /// Put each game's files in its own directory under the output
+    pub per_game_dirs: bool,
+    /// Maintain a `current` link in each game's directory pointing at the latest download
+    pub link_latest: bool,
+    /// Sort each game's files into folders by what they are
+    pub organize: bool,
+    /// Group games under the bundle their key came from
+    pub group_by_bundle: bool,
+    /// Download into a directory of this run's own, leaving the main layout untouched
+    pub run_dir: bool,
+    pub save: SaveOptions,
+    pub max_concurrent: usize,
+    /// Upload lookups running at once, separate from `max_concurrent`
+    pub meta_concurrent: usize,
+    /// Downloads of one author's games running at once, within `max_concurrent`
+    pub max_concurrent_per_author: Option<usize>,
+    /// Byte budget for the whole run
+    pub max_total_size: Option<u64>,
+    /// Window the download starts are spread evenly across
+    pub spread_over: Option<Duration>,
+    /// The order downloads start in, the library's when unset
+    pub order: Option<schedule::DownloadOrder>,
+    /// Only consider keys created after this
+    pub since: Option<since::Since>,
+    pub unzip: bool,
+    pub extract: ExtractOptions,
+    /// What to do when another upload was extracted into the same directory
+    pub extract_conflict: ExtractConflict,
+    /// Check extractions against their archives, `Some(true)` comparing CRCs too
+    pub audit_extractions: Option<bool>,
+    /// Other local mirrors matching files are copied from instead of downloaded
+    pub seeds: Option<Arc<SeedDirs>>,
+    /// Write a `sha256sum` sidecar next to each downloaded file
+    pub sidecar_checksums: bool,
+    /// Append every game's outcome to the history log
+    pub history: bool,
+    /// Write a receipt next to each game's files
+    pub receipts: bool,
+    /// Restrict the selection to the failures recorded by the previous run
+    pub retry_failed: bool,
+    /// Hand the run's failures to [`Observer::triage`] once it's done
+    pub triage: bool,
+    /// Clear the list of downloads skipped permanently before starting
+    pub reset_skips: bool,
+    /// Where to write the report, the state directory when unset
+    pub report: Option<PathBuf>,
+    /// Hold a lock on the output directory for the whole run
+    pub lock: bool,
+    /// URL the report is POSTed to as JSON once the run is done
+    pub webhook: Option<String>,
+    /// The command line the run was started with, for a run directory's `run.json`
+    pub run_args: Vec<String>,
+    /// Set when running as `sync`, skipping anything the manifest says is already up to date
+    pub sync: Option<SyncOptions>,
+    /// Ask [`Observer::confirm`] before downloading more games than this
+    pub confirm_above: Option<usize>,
+    /// Fetch the whole library before any download starts, so [`Event::Selected`] can list it
+    pub select_up_front: bool,
+    /// Stop starting downloads once one has failed
+    pub fail_fast: bool,
+    /// Treat games deleted from itch.io as failures
+    pub strict: bool,
+    /// Download what arrived of the library when a page of it can't be fetched
+    pub allow_partial: bool,
+    /// Only download games in this collection, by id or title
+    pub collection: Option<String>,
+    /// Only download keys from this bundle, by id or title
+    pub from_bundle: Option<String>,
+    /// Only download the game at this URL slug
+    pub slug: Option<String>,
+    /// The lists from `--filter-file`
+    pub filter_rules: Option<FilterFile>,
+    /// Save each game's store page into its directory
+    pub archive_page: bool,
+    /// Download only demo uploads, without the download key
+    pub demos_only: bool,
+    /// Links to games, owned or not, whose demos are downloaded instead of the library's
+    pub demo_of: Vec<String>,
+    /// With `--bundle-set`, its name and the uploads it takes from each game
+    pub bundle_set: Option<(String, UploadSet)>,
+    /// Only download these games, the claimed games of a bundle for `bundle dl`
+    pub games: Option<HashSet<u64>>,
+    /// Download every upload of the account's own games instead of its purchases
+    pub mine: bool,
+    /// Download these games instead of the library's, the entries of a jam for `jam`
+    pub keys: Option<Vec<OwnedKey>>,
+    /// Download this build of one of the selected games instead of the latest files
+    pub build_id: Option<u64>,
+    /// Archive the ratings and reviews of each of the account's own games too
+    pub with_reviews: bool,
+    /// Once cancelled, downloads that haven't started yet are abandoned
+    pub shutdown: CancellationToken,
+    /// Once cancelled, transfers and extractions under way stop and clean up after themselves
+    pub abort: CancellationToken,
+}
+
+impl DownloadOptions {
+    /// Whether downloads start while later pages of the library are still being fetched
+    ///
+    /// Not when something needs the whole selection up front: the `spread_over` schedule,
+    /// `order` or `select_up_front`. `confirm_above` only holds the downloads back until the
+    /// library has arrived, asking when there turn out to be too many.
+    pub fn streams(&self) -> bool {
+        !self.mine
+            && self.bundle_set.is_none()
+            && self.keys.is_none()
+            && self.build_id.is_none()
+            && self.spread_over.is_none()
+            && self.order.is_none()
+            && !self.select_up_front
+    }
+
+    /// What `dl --dry-run` plans with these options, which is also what a confirmation shows
+    pub fn plan_options(&self) -> plan::PlanOptions {
+        plan::PlanOptions {
+            output_path: self.output_path.clone(),
+            per_game_dirs: self.per_game_dirs || self.link_latest,
+            group_by_bundle: self.group_by_bundle,
+            extract: Some(self.extract.clone()).filter(|_| self.unzip),
+            concurrency: self.max_concurrent,
+            demos_only: self.demos_only,
+            bundle_set: self.bundle_set.as_ref().map(|(_, set)| set.clone()),
+            game_dirs: layout::GameDirs::scan(
+                &self.output_path,
+                self.group_by_bundle,
+                &self.layout,
+            ),
+        }
+    }
+}
+
+/// State and flags specific to `sync`
+pub struct SyncOptions {
+    pub manifest: Mutex<Manifest>,
+    /// Delete the files of games that are no longer owned
+    pub prune: bool,
+    /// Only report what would be fetched or removed
+    pub dry_run: bool,
+    /// Rename replaced versions instead of deleting them
+    pub keep_old_versions: bool,
+    /// Replace local files with older versions itch serves instead of skipping them
+    pub allow_downgrade: bool,
+    /// Rename the newer local copy a downgrade replaces instead of deleting it
+    pub keep_downgraded: bool,
+    /// Only consider games the manifest has never seen, without checking the rest for updates
+    pub new_only: bool,
+}
+
+/// Something that happened during a run, for the caller to show
+///
+/// Events about one download carry the `job` it was given in [`Event::Queued`], a failed
+/// download that's retried being a new job.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// Progress worth a line of its own, e.g. which page of the library is being fetched
+    Info(String),
+    /// Something went wrong without stopping the run
+    Warning(String),
+    /// Part of the run's results, e.g. a game that's no longer owned
+    Report(String),
+    /// A page of the library is being fetched while downloads already start
+    LibraryPage(u64),
+    /// The whole library arrived while downloads were starting, `selected` of its `total`
+    /// packages being downloaded
+    LibraryStreamed { total: usize, selected: usize },
+    /// The keys the run is about to download, when they're all known before any download starts
+    Selected(&'a [OwnedKey]),
+    /// The uploads of `total` games are being resolved to put the downloads in order
+    Ordering(usize),
+    /// The upload of a game to put in order was resolved
+    Ordered(&'a OwnedKey),
+    /// Download starts are spread `step` apart from now, `total` of them
+    Spread { step: Duration, total: usize },
+    /// The downloads about to start, with their upload when it was listed up front
+    Jobs(&'a [(OwnedKey, Option<Upload>)]),
+    /// A download was queued, its uploads still to be listed unless `upload` is known
+    Queued {
+        job: usize,
+        key: &'a OwnedKey,
+        upload: Option<&'a Upload>,
+    },
+    /// The game's uploads are being listed
+    ListingUploads { job: usize, key: &'a OwnedKey },
+    /// The game's uploads were listed, or failed to be
+    UploadsListed { job: usize },
+    /// How many downloads have their upload and wait for a download slot
+    WaitingForSlot(usize),
+    /// `sync --dry-run` would fetch `upload`, replacing `previous` when it's an update
+    WouldFetch {
+        key: &'a OwnedKey,
+        upload: &'a Upload,
+        previous: Option<&'a UploadRecord>,
+    },
+    /// The upload is being downloaded, or copied from `seed` in another mirror
+    DownloadStarted {
+        job: usize,
+        key: &'a OwnedKey,
+        upload: &'a Upload,
+        seed: Option<&'a Path>,
+    },
+    Progress {
+        job: usize,
+        progress: DownloadProgress,
+    },
+    /// What the download is doing now
+    Note { job: usize, message: String },
+    /// The download went well, so far as `message` says
+    Succeeded { job: usize, message: String },
+    /// The download failed, or its extraction did
+    Failed { job: usize, message: String },
+    /// The download is waiting for an extraction slot
+    ExtractQueued { job: usize, filename: &'a str },
+    ExtractStarted {
+        job: usize,
+        key: &'a OwnedKey,
+        filename: &'a str,
+    },
+    /// `written` of the archive's `total` bytes are extracted
+    ExtractProgress {
+        job: usize,
+        written: u64,
+        total: u64,
+    },
+    Extracted {
+        job: usize,
+        key: &'a OwnedKey,
+        filename: &'a str,
+        path: &'a Path,
+    },
+    ExtractFailed {
+        job: usize,
+        key: &'a OwnedKey,
+        filename: &'a str,
+        error: String,
+    },
+    /// The job is over, the outcome saying how it went
+    Done { job: usize, outcome: &'a Outcome },
+}
+
+/// A failed download and what it was started with, so [`Observer::triage`] can start it again
+pub type FailedJob = (Outcome, (OwnedKey, Option<Upload>));
+
+/// Shows a run's [`Event`]s and answers what the run asks along the way
+///
+/// Downloads run on tasks of their own, so events arrive from several at once.
+pub trait Observer: Send + Sync + 'static {
+    fn event(&self, event: Event<'_>);
+
+    /// Whether to go ahead with `keys`, more games than `confirm_above`, `known_size` being the
+    /// bytes `sync` downloaded of them before
+    ///
+    /// An error stops the run before anything is downloaded. Goes ahead unless implemented.
+    fn confirm(
+        &self,
+        keys: &[OwnedKey],
+        known_size: u64,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let _ = (keys, known_size);
+        async { Ok(()) }
+    }
+
+    /// Which of the run's failed downloads to start again with `triage`, adding those to skip
+    /// for good to `skips`
+    ///
+    /// Retries nothing unless implemented.
+    fn triage(&self, failed: Vec<FailedJob>, skips: &mut Skips) -> Result<Vec<FailedJob>> {
+        let _ = (failed, skips);
+        Ok(Vec::new())
+    }
+}
+
+/// How a run ended
+pub enum Finished {
+    /// `retry_failed` found no failures recorded by the previous run
+    NothingToRetry,
+    /// Nothing was left to download, the selection saying which check turned everything away
+    NothingSelected(Box<KeySelection>),
+    Ran(Box<Summary>),
+}
+
+/// What a run did, once everything it keeps alongside the mirror has been saved
+pub struct Summary {
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+    /// How each download went, in the order they finished
+    pub outcomes: Vec<Outcome>,
+    /// Whether any download failed, counting deleted games with `strict`
+    pub failed: bool,
+    /// Whether every page of the library arrived, only ever not with `allow_partial`
+    pub library_complete: bool,
+    /// Whether `abort` stopped the run
+    pub interrupted: bool,
+    /// The game whose failure stopped the run with `fail_fast`
+    pub first_failure: Option<String>,
+    /// The failures recorded for `retry_failed`, a dry run recording none
+    pub remaining_failures: Option<usize>,
+    /// What `sync` found, for `sync` runs
+    pub sync: Option<SyncSummary>,
+    /// Files copied from another mirror, each described with the bytes it saved downloading
+    pub seeded: Vec<(String, u64)>,
+    /// Files linked to identical ones and the bytes that saved, with `dedupe`
+    pub deduplicated: Option<(usize, u64)>,
+    /// Bytes used of the `max_total_size` budget, and the budget
+    pub budget: Option<(u64, u64)>,
+    /// Downloads the budget left for a later run
+    pub deferred: Vec<Deferred>,
+    /// Archives downloaded that there was no way of extracting, and why
+    pub not_extracted: Vec<String>,
+    /// What was done instead of mixing two uploads in one extraction directory
+    pub extract_conflicts: Vec<String>,
+    /// Where the report was written, a dry run writing none
+    pub report_path: Option<PathBuf>,
+    /// Where the run directory's `run.json` was written
+    pub run_manifest: Option<PathBuf>,
+}
+
+/// What `sync` found besides the downloads
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    /// Games skipped because the manifest says they're current
+    pub up_to_date: usize,
+    /// Games whose upload changed since the last sync, with the versions involved
+    pub updated: Vec<String>,
+    /// Uploads itch serves in an older version than the one on disk, and what was done about them
+    pub downgrades: Vec<String>,
+    /// Games whose key itch says was used more often than we downloaded with it
+    pub key_use: Vec<String>,
+}
+
+/// A download the budget left for a later run
+#[derive(Clone, Debug)]
+pub struct Deferred {
+    pub title: String,
+    pub filename: String,
+    /// `None` when itch doesn't report one, which leaves it out of a budgeted run
+    pub size: Option<u64>,
+}
+
+/// Every key in the library
+pub async fn fetch_library(
+    client: &ItchClient,
+    on_event: impl Fn(Event<'_>),
+) -> Result<Vec<OwnedKey>> {
+    let owned_keys = client
+        .list_owned_keys(|page| on_event(Event::Info(format!("Fetching page {}...", page))))
+        .await?;
+    on_event(Event::Info(format!(
+        "Fetched {} total packages.",
+        owned_keys.len()
+    )));
+    Ok(owned_keys)
+}
+
+/// Every key in the library that could be fetched, and whether that's all of it
+///
+/// Unless `allow_partial`, a page that can't be fetched fails the whole listing.
+async fn fetch_partial_library(
+    client: &ItchClient,
+    allow_partial: bool,
+    on_event: impl Fn(Event<'_>),
+) -> Result<(Vec<OwnedKey>, bool)> {
+    if !allow_partial {
+        return Ok((fetch_library(client, on_event).await?, true));
+    }
+    let library = client
+        .list_owned_keys_partial(|page| on_event(Event::Info(format!("Fetching page {}...", page))))
+        .await?;
+    let complete = library.is_complete();
+    if let Some(e) = &library.error {
+        on_event(Event::Warning(format!(
+            "Warning: only fetched {} packages before the library stopped loading, carrying on with those: {}",
+            library.keys.len(),
+            e
+        )));
+    } else {
+        on_event(Event::Info(format!(
+            "Fetched {} total packages.",
+            library.keys.len()
+        )));
+    }
+    Ok((library.keys, complete))
+}
+
+/// The games the account created, as keys so they can go through the same pipeline as purchases
+pub async fn fetch_own_games(
+    client: &ItchClient,
+    on_event: impl Fn(Event<'_>),
+) -> Result<Vec<OwnedKey>> {
+    let games = client.list_my_games().await?;
+    on_event(Event::Info(format!(
+        "Fetched {} games you created.",
+        games.len()
+    )));
+    Ok(games.into_iter().map(OwnedKey::keyless).collect())
+}
+
+/// Keyless keys for the games behind `--demo-of`, given by link to their page or by id
+///
+/// Owning the games doesn't matter, demos are free to anyone.
+pub async fn demo_keys(
+    client: &ItchClient,
+    games: &[String],
+    on_event: impl Fn(Event<'_>),
+) -> Result<Vec<OwnedKey>> {
+    let mut keys = Vec::new();
+    for game in games {
+        let game_id = match game.trim().parse() {
+            Ok(game_id) => game_id,
+            Err(_) => {
+                let page_url = crate::claim::game_url(game)?;
+                on_event(Event::Info(format!("Looking up {}...", page_url)));
+                crate::claim::fetch_page(client.http(), &page_url, None)
+                    .await?
+                    .game_id
+            }
+        };
+        let Some(details) = client.get_game(game_id).await? else {
+            bail!(
+                "Game {} isn't available, it may have been delisted or made private",
+                game
+            );
+        };
+        if !details.game.has_demo {
+            on_event(Event::Info(format!(
+                "{} doesn't list a demo, looking anyway.",
+                details.game.title
+            )));
+        }
+        keys.push(OwnedKey::keyless(details.game));
+    }
+    Ok(keys)
+}
+
+/// The games in one of the account's collections, picked by id or title
+pub async fn fetch_collection(
+    client: &ItchClient,
+    wanted: &str,
+    on_event: impl Fn(Event<'_>),
+) -> Result<Vec<CollectionGame>> {
+    let collections = client.list_collections().await?;
+    let by_id = wanted
+        .parse::<u64>()
+        .ok()
+        .and_then(|id| collections.iter().find(|collection| collection.id == id));
+    let by_title: Vec<_> = collections
+        .iter()
+        .filter(|collection| collection.title.eq_ignore_ascii_case(wanted))
+        .collect();
+    let collection = match (by_id, by_title.as_slice()) {
+        (Some(collection), _) => collection,
+        (None, [collection]) => *collection,
+        (None, []) => bail!(
+            "No collection with the id or title {:?}, run `itch-downloader collections` to list yours",
+            wanted
+        ),
+        (None, _) => bail!(
+            "{} collections are titled {:?}, pass the id of the one you mean instead",
+            by_title.len(),
+            wanted
+        ),
+    };
+
+    on_event(Event::Info(format!(
+        "Fetching the games in {}...",
+        collection.title
+    )));
+    let games = client.list_collection_games(collection.id).await?;
+    info!(
+        collection_id = collection.id,
+        games = games.len(),
+        "Resolved --collection"
+    );
+    Ok(games)
+}
+
+/// Say which games in a collection can't be downloaded because they aren't in the library
+pub fn report_unowned_collection_games(
+    games: &[CollectionGame],
+    owned_game_ids: &HashSet<u64>,
+    on_event: impl Fn(Event<'_>),
+) {
+    let unowned: Vec<&str> = games
+        .iter()
+        .filter(|entry| !owned_game_ids.contains(&entry.game.id))
+        .map(|entry| entry.game.title.as_str())
+        .collect();
+    if !unowned.is_empty() {
+        on_event(Event::Report(format!(
+            "{} games in the collection aren't in your library and are skipped: {}",
+            unowned.len(),
+            unowned.join(", ")
+        )));
+    }
+}
+
+/// The keys `dl`'s filters select: `--author`, `--title`, `--bundle`, `--slug`, `--filter-file`
+/// and `--collection`, before what only a download run narrows them by
+pub fn filter_selection(
+    author_filter: Option<String>,
+    title_filter: Option<String>,
+    from_bundle: Option<String>,
+    slug: Option<String>,
+    filter_rules: Option<FilterFile>,
+    collection_games: Option<&[CollectionGame]>,
+) -> KeySelection {
+    KeySelection {
+        filter_rules,
+        collection: collection_games.map(|games| games.iter().map(|entry| entry.game.id).collect()),
+        ..KeySelection::new(
+            KeyFilter::new(author_filter, title_filter)
+                .from_bundle(from_bundle)
+                .with_slug(slug),
+        )
+    }
+}
+
+/// Save the ratings and reviews of one of the account's own games into its directory, `false`
+/// when itch.io has none to give
+pub async fn save_reviews(
+    client: &ItchClient,
+    game: &Game,
+    output_path: &Path,
+    layout: &Layout,
+) -> Result<bool> {
+    let Some(reviews) = client.get_reviews(game.id).await? else {
+        return Ok(false);
+    };
+    let dir = output_path.join(layout.game_dir(game));
+    std::fs::create_dir_all(&dir).context("Failed to create game directory")?;
+    export::write_reviews(&reviews, &dir)?;
+    Ok(true)
+}
+
+/// The selected game and upload `build_id` was pushed to, the upload standing in for the build
+///
+/// Only butler uploads have builds, so only those are asked for theirs.
+async fn find_build(
+    client: &ItchClient,
+    keys: Vec<OwnedKey>,
+    build_id: u64,
+    cancel: &CancellationToken,
+    on_event: impl Fn(Event<'_>),
+) -> Result<(OwnedKey, Option<Upload>)> {
+    for key in keys {
+        let uploads = client.get_key_uploads(&key, cancel).await?;
+        for upload in uploads
+            .into_iter()
+            .filter(|upload| upload.build_id.is_some())
+        {
+            let builds = client.list_builds(upload.id, key.download_key_id()).await?;
+            let Some(build) = builds.into_iter().find(|build| build.id == build_id) else {
+                continue;
+            };
+            if build.state != "completed" {
+                bail!(
+                    "Build {} of {} is {}, only completed builds can be downloaded",
+                    build_id,
+                    key.game.title,
+                    build.state
+                );
+            }
+            on_event(Event::Info(format!(
+                "Found build {} (version {}) of {}",
+                build_id, build.version, key.game.title
+            )));
+            let version = format!("build {}", build.version);
+            let upload = Upload {
+                filename: layout::versioned_name(&upload.filename, &version),
+                size: build.archive_size(),
+                build_id: Some(build.id),
+                ..upload
+            };
+            return Ok((key, Some(upload)));
+        }
+    }
+    bail!(
+        "Build {} isn't one of the selected games' builds, see `itch-downloader builds <game-id>`",
+        build_id
+    )
+}
+
+/// Every upload of each of your own games, listed up front so each can be downloaded on its own
+///
+/// Games whose uploads can't be listed are left to the download task, which reports the failure.
+async fn list_own_uploads(
+    client: &ItchClient,
+    keys: Vec<OwnedKey>,
+    cancel: &CancellationToken,
+    on_event: impl Fn(Event<'_>),
+) -> Vec<(OwnedKey, Option<Upload>)> {
+    let total = keys.len();
+    let mut jobs = Vec::new();
+    for (index, key) in keys.into_iter().enumerate() {
+        on_event(Event::Info(format!(
+            "Listing uploads of {} ({} of {})...",
+            key.game.title,
+            index + 1,
+            total
+        )));
+        match client.get_game_uploads(key.game_id, None, cancel).await {
+            Ok(uploads) if !uploads.is_empty() => jobs.extend(
+                uploads
+                    .into_iter()
+                    .map(|upload| (key.clone(), Some(upload))),
+            ),
+            _ => jobs.push((key, None)),
+        }
+    }
+    jobs
+}
+
+/// The uploads of each game in a `--bundle-set`, listed up front so each can be downloaded on its own
+///
+/// Games whose uploads can't be listed are left to the download task, which reports the failure.
+async fn list_set_uploads(
+    client: &ItchClient,
+    keys: Vec<OwnedKey>,
+    (name, set): &(String, UploadSet),
+    policy: &SelectionPolicy,
+    meta_concurrent: usize,
+    cancel: &CancellationToken,
+    on_event: impl Fn(Event<'_>),
+) -> Vec<(OwnedKey, Option<Upload>)> {
+    on_event(Event::Info(format!(
+        "Listing the uploads of {} games for the {} set...",
+        keys.len(),
+        name
+    )));
+    let listed: Vec<(OwnedKey, Result<Vec<Upload>>)> = futures::stream::iter(keys)
+        .map(|key| async move {
+            let uploads = client.get_key_uploads(&key, cancel).await;
+            (key, uploads)
+        })
+        .buffered(meta_concurrent.max(1))
+        .collect()
+        .await;
+
+    let mut jobs = Vec::new();
+    for (key, uploads) in listed {
+        let Ok(uploads) = uploads else {
+            jobs.push((key, None));
+            continue;
+        };
+        let selected = set.select(&uploads, policy);
+        if selected.is_empty() {
+            on_event(Event::Info(format!(
+                "None of the uploads of {} are in the {} set",
+                key.game.title, name
+            )));
+        }
+        for (upload, captured_by) in selected {
+            debug!(game = %key.game.title, upload = %upload.filename, %captured_by, "Upload is in the set");
+            jobs.push((key.clone(), Some(upload.clone())));
+        }
+    }
+    jobs
+}
+
+/// Drop manifest entries for games that are no longer owned, deleting their files with `prune`
+fn forget_unowned_games(
+    sync: &SyncOptions,
+    owned_game_ids: &HashSet<u64>,
+    on_event: impl Fn(Event<'_>),
+) -> Result<()> {
+    let mut manifest = sync.manifest.lock().unwrap();
+    let ignore = match sync.prune {
+        true => itchignore::IgnoreRules::load(&manifest.resolve(Path::new("")))?,
+        false => Default::default(),
+    };
+
+    for game in manifest.retain_owned(owned_game_ids) {
+        for upload in game.uploads.values() {
+            let path = manifest.resolve(&upload.path);
+            let report = |message| on_event(Event::Report(message));
+            if !sync.prune {
+                report(format!(
+                    "No longer owned: {} ({})",
+                    game.title,
+                    path.display()
+                ));
+            } else if sync.dry_run {
+                report(format!("Would remove {} ({})", path.display(), game.title));
+            } else {
+                match itchignore::remove_unignored(&path, &ignore) {
+                    Ok(true) => report(format!("Removed {} ({})", path.display(), game.title)),
+                    Ok(false) => report(format!(
+                        "Kept {} ({}), .itchignore rules match it",
+                        path.display(),
+                        game.title
+                    )),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("Failed to remove {}", path.display()));
+                    }
+                }
+            }
+        }
+    }
+    if !ignore.is_empty() {
+        on_event(Event::Info(format!(
+            "{} paths excluded by .itchignore rules",
+            ignore.excluded()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Move the previously downloaded version of an upload out of the way before it's replaced
+///
+/// With `keep` it's renamed for good and nothing is returned, otherwise it's moved to a
+/// temporary name and the original and temporary paths are returned so the caller can delete
+/// it once the update succeeds or put it back if it fails.
+fn set_aside_previous(
+    sync: &SyncOptions,
+    previous: &UploadRecord,
+    keep: bool,
+) -> Result<Option<(PathBuf, PathBuf)>> {
+    let original = sync.manifest.lock().unwrap().resolve(&previous.path);
+    if !original.exists() {
+        return Ok(None);
+    }
+
+    if keep {
+        let name = original
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let version = manifest::version_label(previous.build_id, previous.updated_at);
+        let kept = original.with_file_name(layout::versioned_name(&name, &version));
+        staging::move_path(&original, &kept).context("Failed to keep old version")?;
+        if let Err(e) = checksums::rename_sidecar(&original, &kept) {
+            info!(error = %e, "Failed to move the checksum sidecar of the old version");
+        }
+        return Ok(None);
+    }
+
+    let set_aside = staging::set_aside_path(&original);
+    std::fs::rename(&original, &set_aside).context("Failed to move old version aside")?;
+    Ok(Some((original, set_aside)))
+}
+
+/// Audit a fresh extraction against its archive for `audit_extractions`, returning what to
+/// report when it's incomplete
+///
+/// An audit that can't be done, e.g. of a RAR archive, is only logged since the extraction
+/// itself succeeded.
+async fn audit_extracted(
+    archive_path: &Path,
+    kind: ArchiveKind,
+    extract_dir: &Path,
+    zip_encoding: ZipEncoding,
+    deep: bool,
+) -> Option<Vec<String>> {
+    let (archive, dir) = (archive_path.to_path_buf(), extract_dir.to_path_buf());
+    let audit = tokio::task::spawn_blocking(move || {
+        extract::audit_extraction(&archive, kind, &dir, zip_encoding, deep)
+    })
+    .await;
+    let filename = archive_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    match audit {
+        Ok(Ok(audit)) if audit.is_complete() => {
+            debug!(
+                files = audit.files,
+                crc_checked = audit.crc_checked,
+                "Extraction audited"
+            );
+            None
+        }
+        Ok(Ok(audit)) => Some(audit.describe(&filename)),
+        Ok(Err(e)) => {
+            info!(error = %e, "Couldn't audit the extraction of {}", filename);
+            None
+        }
+        Err(e) => {
+            info!(error = %e, "Audit task failed");
+            None
+        }
+    }
+}
+
+/// Ask `observer` before a download of more than `threshold` games
+async fn confirm<O: Observer>(
+    observer: &O,
+    keys: &[OwnedKey],
+    sync: Option<&SyncOptions>,
+    threshold: usize,
+) -> Result<()> {
+    if keys.len() <= threshold {
+        return Ok(());
+    }
+    // Only games `sync` downloaded before have a size without listing their uploads
+    let known_size: u64 = sync
+        .map(|sync| {
+            let manifest = sync.manifest.lock().unwrap();
+            keys.iter()
+                .filter_map(|key| {
+                    manifest
+                        .games()
+                        .find(|&(game_id, _)| game_id == key.game_id)
+                })
+                .flat_map(|(_, game)| game.uploads.values().map(|upload| upload.size))
+                .sum()
+        })
+        .unwrap_or(0);
+    observer.confirm(keys, known_size).await
+}
+
+/// Warn when the local clock is far off itch.io's, which signed download links are checked against
+fn warn_on_clock_skew(client: &ItchClient, on_event: impl Fn(Event<'_>)) {
+    let Some(skew) = client
+        .clock_skew()
+        .filter(|skew| skew.unsigned_abs() > CLOCK_SKEW_THRESHOLD.as_secs())
+    else {
+        return;
+    };
+    on_event(Event::Warning(format!(
+        "Warning: this computer's clock is {}s {} itch.io's, downloads may fail until it's corrected (e.g. by enabling NTP)",
+        skew.unsigned_abs(),
+        if skew > 0 { "ahead of" } else { "behind" }
+    )));
+}
+
+/// Say which check left nothing to download, recording the run when `since` found nothing new
+fn nothing_selected(
+    selection: KeySelection,
+    output_path: &Path,
+    layout: &Layout,
+    started_at: u64,
+) -> Result<Finished> {
+    if selection.matched > 0 && selection.cutoff.is_some() && selection.recent == 0 {
+        since::record_run(output_path, layout, started_at)?;
+    }
+    Ok(Finished::NothingSelected(Box::new(selection)))
+}
+
+/// Download everything `options` select with `client`, telling `observer` how it goes
+///
+/// Fails before anything is downloaded when the key is rejected, the selection can't be made or
+/// `observer` doesn't confirm it. Once downloads start, their failures end up in the summary
+/// rather than failing the run.
+pub async fn download<O: Observer>(
+    client: &ItchClient,
+    options: DownloadOptions,
+    observer: Arc<O>,
+) -> Result<Finished> {
+    let streaming = options.streams();
+    let DownloadOptions {
+        output_path,
+        layout,
+        author,
+        title,
+        per_game_dirs,
+        link_latest,
+        organize,
+        group_by_bundle,
+        run_dir,
+        save,
+        max_concurrent,
+        meta_concurrent,
+        max_concurrent_per_author,
+        max_total_size,
+        spread_over,
+        order,
+        since,
+        unzip,
+        extract: extract_options,
+        extract_conflict,
+        audit_extractions,
+        seeds,
+        sidecar_checksums,
+        history,
+        receipts,
+        retry_failed,
+        triage,
+        reset_skips,
+        report,
+        lock,
+        webhook,
+        run_args,
+        sync,
+        confirm_above,
+        select_up_front: _,
+        fail_fast,
+        strict,
+        allow_partial,
+        collection,
+        from_bundle,
+        slug,
+        filter_rules,
+        archive_page,
+        demos_only,
+        demo_of,
+        bundle_set,
+        games,
+        mine,
+        keys,
+        build_id,
+        with_reviews,
+        shutdown,
+        abort,
+    } = options;
+    let on_event = |event: Event<'_>| observer.event(event);
+    // --fail-fast stops this run only, a watcher carries on with the next cycle
+    let shutdown = shutdown.child_token();
+    let started_at = manifest::now();
+    // Files go here, while the manifest and other state stay with the main layout so games already
+    // downloaded there aren't fetched again
+    let files_root = if run_dir {
+        runs::run_dir(&output_path, timestamp::from_unix(started_at as i64))
+    } else {
+        output_path.clone()
+    };
+
+    // Taken before anything is read or written so two runs never fight over the same files
+    let _lock = if lock {
+        tokio::fs::create_dir_all(&output_path)
+            .await
+            .context("Failed to create output directory")?;
+        Some(lock::OutputLock::acquire(&output_path)?)
+    } else {
+        None
+    };
+    // The `current` link lives in the game's directory, so linking implies the per-game layout, as
+    // does --mine since several uploads of a game would otherwise extract into the same directory,
+    // and --organize which sorts within it
+    let per_game_dirs = per_game_dirs || link_latest || mine || organize;
+
+    // A bad key fails here, before any progress output starts
+    let account = Arc::new(client.get_profile().await?);
+    warn_on_clock_skew(client, on_event);
+    let keys = if demo_of.is_empty() {
+        keys
+    } else {
+        Some(demo_keys(client, &demo_of, on_event).await?)
+    };
+    let mut skips = Skips::load(&output_path, &layout)?;
+    if reset_skips && !skips.is_empty() {
+        on_event(Event::Info(format!(
+            "Attempting the {} downloads skipped permanently again",
+            skips.items().len()
+        )));
+        skips.clear();
+        skips.save(&output_path, &layout)?;
+    }
+    let previous_failures = failures::load(&output_path, &layout)?;
+    if retry_failed && previous_failures.is_empty() {
+        return Ok(Finished::NothingToRetry);
+    }
+    // Retries go back to the upload that failed rather than picking one afresh
+    let retry_uploads: Arc<HashMap<u64, u64>> = Arc::new(
+        previous_failures
+            .iter()
+            .filter(|_| retry_failed)
+            .filter_map(|item| Some((item.game_id, item.upload_id?)))
+            .collect(),
+    );
+
+    // Cut down before resolving uploads, which is the expensive part
+    let cutoff = match &since {
+        Some(since) => since.cutoff(&output_path, &layout)?,
+        None => None,
+    };
+    if cutoff.is_none() && since == Some(since::Since::LastRun) {
+        on_event(Event::Info(
+            "No previous successful run recorded, considering every purchase.".to_string(),
+        ));
+    }
+    let collection_games = match &collection {
+        Some(collection) => Some(fetch_collection(client, collection, on_event).await?),
+        None => None,
+    };
+    let mut selection = KeySelection {
+        failed_games: retry_failed
+            .then(|| previous_failures.iter().map(|item| item.game_id).collect()),
+        games,
+        cutoff,
+        known: sync.as_ref().filter(|sync| sync.new_only).map(|sync| {
+            sync.manifest
+                .lock()
+                .unwrap()
+                .games()
+                .map(|(game_id, _)| game_id)
+                .collect()
+        }),
+        skipped: skips
+            .items()
+            .iter()
+            .filter(|item| item.upload_id.is_none())
+            .map(|item| item.game_id)
+            .collect(),
+        ..filter_selection(
+            author,
+            title,
+            from_bundle,
+            slug,
+            filter_rules,
+            collection_games.as_deref(),
+        )
+    };
+
+    // A dry run only prints, so there's nothing to confirm
+    let dry_run = sync.as_ref().is_some_and(|sync| sync.dry_run);
+    let confirm_above = confirm_above.filter(|_| !dry_run);
+    let fixed_keys = keys.is_some();
+    let mut selected_keys = Vec::new();
+    // Cleared when --allow-partial carries on without some pages of the library
+    let mut library_complete = true;
+    if !streaming {
+        let owned_keys = if let Some(keys) = keys {
+            keys
+        } else if mine {
+            fetch_own_games(client, on_event).await?
+        } else {
+            let (owned_keys, complete) =
+                fetch_partial_library(client, allow_partial, on_event).await?;
+            library_complete = complete;
+            owned_keys
+        };
+        let owned_game_ids = owned_keys.iter().map(|key| key.game_id).collect();
+        // Your own games aren't purchases, so their absence from the list says nothing about those,
+        // nor does a game's absence from a library that didn't fully arrive
+        if let Some(sync) = sync
+            .as_ref()
+            .filter(|_| !mine && !fixed_keys && library_complete)
+        {
+            forget_unowned_games(sync, &owned_game_ids, on_event)?;
+        }
+        if let Some(games) = &collection_games {
+            report_unowned_collection_games(games, &owned_game_ids, on_event);
+        }
+        selected_keys = owned_keys
+            .into_iter()
+            .filter(|key| selection.admit(key))
+            .collect();
+        selection.log();
+        if selected_keys.is_empty() {
+            return nothing_selected(selection, &output_path, &layout, started_at);
+        }
+        if let Some(threshold) = confirm_above {
+            confirm(&*observer, &selected_keys, sync.as_ref(), threshold).await?;
+        }
+    }
+
+    // Create output directory if it doesn't exist
+    tokio::fs::create_dir_all(&output_path)
+        .await
+        .context("Failed to create output directory")?;
+
+    // Clear out partial downloads and extractions left behind by runs that crashed
+    let mut orphan_dirs = if per_game_dirs {
+        staging::orphan_dirs(&output_path)?
+    } else {
+        vec![output_path.clone()]
+    };
+    orphan_dirs.extend(save.temp_dir.clone());
+    for dir in orphan_dirs {
+        let removed = staging::remove_orphans(&dir)?;
+        if removed > 0 {
+            on_event(Event::Info(format!(
+                "Removed {} leftover temporary files from {}",
+                removed,
+                dir.display()
+            )));
+        }
+    }
+    if let Some(temp_dir) = &save.temp_dir {
+        tokio::fs::create_dir_all(temp_dir)
+            .await
+            .context("Failed to create temporary directory")?;
+    }
+
+    if !streaming {
+        on_event(Event::Selected(&selected_keys));
+    }
+
+    let (history, history_writer) = if history {
+        let (history, writer) = history::start(&output_path, &layout).await?;
+        (Some(history), Some(writer))
+    } else {
+        (None, None)
+    };
+
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(max_concurrent));
+    // Looking up uploads is a short API call, so it has its own slots rather than holding a download
+    // slot idle while it waits, and a game's download only queues for a slot once its upload is known
+    let meta_semaphore = Arc::new(tokio::sync::Semaphore::new(meta_concurrent));
+    // A game queues for its author's slot before a download slot, so while one author's games
+    // wait their turn the download slots go to other authors' games
+    let author_slots = Arc::new(schedule::AuthorSlots::new(max_concurrent_per_author));
+    // Extraction is CPU/disk bound so it gets its own pool, separate from the download slots
+    let extract_jobs = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let extract_semaphore = Arc::new(tokio::sync::Semaphore::new(extract_jobs));
+    let extract_options = Arc::new(extract_options);
+    let selection_policy = Arc::new(SelectionPolicy {
+        extract: (*extract_options).clone(),
+        demos_only,
+    });
+    let save = Arc::new(save);
+    // Archives we downloaded but had no way of extracting, reported once everything is done
+    let not_extracted = Arc::new(Mutex::new(Vec::new()));
+    // What was done instead of mixing two uploads in one extraction directory
+    let extract_conflicts = Arc::new(Mutex::new(Vec::new()));
+    // A dry run's "failures" are only things it would have fetched
+    let record_failures = !dry_run;
+    let sync = sync.map(Arc::new);
+    let outcomes = Arc::new(Mutex::new(Vec::new()));
+    // What `sync` found besides the downloads
+    let sync_summary = Arc::new(Mutex::new(SyncSummary::default()));
+    // Files copied from a seed directory, with the bytes they saved downloading
+    let seeded = Arc::new(Mutex::new(Vec::new()));
+    // Games renamed since they were downloaded keep going into the directory they're in
+    let game_dirs = Arc::new(layout::GameDirs::scan(
+        &files_root,
+        group_by_bundle,
+        &layout,
+    ));
+    let budget = max_total_size.map(|limit| Arc::new(Mutex::new(Budget::new(limit))));
+    // Downloads left for a later run because of the budget
+    let deferred = Arc::new(Mutex::new(Vec::new()));
+    // The game whose failure stopped the run under --fail-fast
+    let first_failure = Arc::new(Mutex::new(None));
+    let skips = Arc::new(skips);
+    // With --triage, what each failed download was started with, to start it again
+    let failed_jobs = Arc::new(Mutex::new(Vec::new()));
+    // Tells the events of one download apart from another's
+    let next_job = AtomicUsize::new(0);
+    let waiting_for_slot = Arc::new(AtomicUsize::new(0));
+
+    if with_reviews {
+        for key in &selected_keys {
+            match save_reviews(client, &key.game, &output_path, &layout).await {
+                Ok(true) => debug!(game = %key.game.title, "Saved reviews"),
+                Ok(false) => on_event(Event::Info(format!(
+                    "No reviews available for {}, skipping them",
+                    key.game.title
+                ))),
+                Err(e) => on_event(Event::Report(format!(
+                    "Failed to save the reviews of {}: {}",
+                    key.game.title, e
+                ))),
+            }
+        }
+    }
+
+    // Your own games have every upload backed up, not only the one `dl` would pick
+    let jobs = if let Some(build_id) = build_id {
+        vec![find_build(client, selected_keys, build_id, &abort, on_event).await?]
+    } else if mine {
+        list_own_uploads(client, selected_keys, &abort, on_event).await
+    } else if let Some(bundle_set) = &bundle_set {
+        list_set_uploads(
+            client,
+            selected_keys,
+            bundle_set,
+            &selection_policy,
+            meta_concurrent,
+            &abort,
+            on_event,
+        )
+        .await
+    } else {
+        selected_keys.into_iter().map(|key| (key, None)).collect()
+    };
+
+    let jobs = match order {
+        Some(order) => {
+            let pick = |key: &OwnedKey, uploads: &[Upload]| {
+                let retry_upload = retry_uploads.get(&key.game_id).and_then(|&upload_id| {
+                    choose_uploads(uploads, &selection_policy)
+                        .into_iter()
+                        .find(|upload| upload.id == upload_id)
+                });
+                retry_upload
+                    .or_else(|| choose_upload(uploads, &selection_policy))
+                    .cloned()
+            };
+            on_event(Event::Ordering(jobs.len()));
+            schedule::order_jobs(client, jobs, order, pick, meta_concurrent, &abort, |key| {
+                on_event(Event::Ordered(key))
+            })
+            .await
+        }
+        None => jobs,
+    };
+
+    // Create download tasks
+    // With --spread-over the nth game waits until n steps into the window before queueing
+    let schedule_start = tokio::time::Instant::now();
+    let schedule_step = spread_over.and_then(|window| schedule::spread_step(window, jobs.len()));
+    if let Some(step) = schedule_step {
+        on_event(Event::Spread {
+            step,
+            total: jobs.len(),
+        });
+    }
+    if !streaming {
+        on_event(Event::Jobs(&jobs));
+    }
+
+    let spawn_download = |index: usize, key: OwnedKey, listed: Option<Upload>| {
+        // Demos are free to anyone, so they're listed and fetched without the download key
+        let key = if demos_only {
+            OwnedKey::keyless(key.game)
+        } else {
+            key
+        };
+        let job = next_job.fetch_add(1, Ordering::Relaxed);
+        on_event(Event::Queued {
+            job,
+            key: &key,
+            upload: listed.as_ref(),
+        });
+        let failed_job = triage.then(|| (key.clone(), listed.clone()));
+        let game_dir_name = layout.game_dir(&key.game);
+        let scheduled_at = schedule_step.map(|step| schedule_start + step * index as u32);
+        let observer = observer.clone();
+        let client = client.clone();
+        let files_root = files_root.clone();
+        let game_dirs = game_dirs.clone();
+        let semaphore = semaphore.clone();
+        let meta_semaphore = meta_semaphore.clone();
+        let author_slots = author_slots.clone();
+        let waiting_for_slot = waiting_for_slot.clone();
+        let extract_semaphore = extract_semaphore.clone();
+        let extract_options = extract_options.clone();
+        let selection_policy = selection_policy.clone();
+        let save = save.clone();
+        let not_extracted = not_extracted.clone();
+        let extract_conflicts = extract_conflicts.clone();
+        let account = account.clone();
+        let sync = sync.clone();
+        let sync_summary = sync_summary.clone();
+        let seeded = seeded.clone();
+        let seeds = seeds.clone();
+        let budget = budget.clone();
+        let deferred = deferred.clone();
+        let history = history.clone();
+        let outcomes = outcomes.clone();
+        let retry_uploads = retry_uploads.clone();
+        let shutdown = shutdown.clone();
+        let abort = abort.clone();
+        let first_failure = first_failure.clone();
+        let skips = skips.clone();
+        let failed_jobs = failed_jobs.clone();
+
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let on_event = |event: Event<'_>| observer.event(event);
+            let warn = |message: String| on_event(Event::Warning(message));
+            let outcome = async {
+                    let outcome = Outcome::new(key.game_id, &key.game.title);
+                    if let Some(scheduled_at) = scheduled_at {
+                        tokio::select! {
+                            _ = tokio::time::sleep_until(scheduled_at) => {}
+                            _ = shutdown.cancelled() => return outcome.failed("Stopped before starting"),
+                        }
+                    }
+
+                    // Get uploads for this game, unless the upload was already picked
+                    let uploads = match listed {
+                        Some(upload) => vec![upload],
+                        None => {
+                            let meta_permit = tokio::select! {
+                                permit = meta_semaphore.acquire() => permit.unwrap(),
+                                _ = shutdown.cancelled() => return outcome.failed("Stopped before starting"),
+                            };
+                            on_event(Event::ListingUploads { job, key: &key });
+                            let uploads = client
+                                .get_key_uploads(&key, &abort)
+                                .await;
+                            drop(meta_permit);
+                            on_event(Event::UploadsListed { job });
+                            match uploads {
+                                Ok(uploads) => uploads,
+                                // Deleted games come up every run, the summary lists them once
+                                Err(e) if e.is_gone() => {
+                                    debug!(error = %e, "Game is gone");
+                                    return outcome.gone("The game");
+                                }
+                                Err(e) if e.is_key_limit() => {
+                                    let outcome = outcome.key_limit(&e);
+                                    if let Some(error) = &outcome.error {
+                                        warn(error.clone());
+                                    }
+                                    return outcome;
+                                }
+                                Err(e) => {
+                                    warn(format!(
+                                        "Failed to get uploads for {}: {}",
+                                        key.game.title, e
+                                    ));
+                                    return outcome.failed(format!("Failed to get uploads: {}", e));
+                                }
+                            }
+                        }
+                    };
+
+                    let retry_upload = retry_uploads.get(&key.game_id).and_then(|&upload_id| {
+                        choose_uploads(&uploads, &selection_policy)
+                            .into_iter()
+                            .find(|upload| upload.id == upload_id)
+                    });
+                    let upload =
+                        match retry_upload.or_else(|| choose_upload(&uploads, &selection_policy)) {
+                            Some(upload) => upload,
+                            None => {
+                                let missing = if demos_only { "No demo" } else { "No uploads" };
+                                warn(format!("{} found for {}", missing, key.game.title));
+                                return outcome.failed(NO_UPLOADS);
+                            }
+                        };
+                    let outcome = outcome.upload(upload.id, &upload.filename);
+                    if skips.skips_upload(key.game_id, upload.id) {
+                        debug!(upload = %upload.filename, "Skipped permanently");
+                        return outcome
+                            .skipped_because("skipped permanently, --reset-skips attempts it again");
+                    }
+
+                    // Done for games that are up to date too, so a mirror made before can catch up
+                    if archive_page && !dry_run {
+                        let root = if group_by_bundle {
+                            files_root.join(layout::origin_dir_name(&key))
+                        } else {
+                            files_root.clone()
+                        };
+                        let page_dir = game_dirs.dir_for(&root, &key.game).join(page::PAGE_DIR);
+                        match client.archive_page(&key.game, &page_dir, &abort).await {
+                            Ok(index) => {
+                                let missing = index
+                                    .cover
+                                    .iter()
+                                    .chain(&index.screenshots)
+                                    .filter(|image| image.file.is_none())
+                                    .count();
+                                if missing > 0 {
+                                    warn(format!(
+                                        "Couldn't fetch {} images from the page of {}, see {}",
+                                        missing,
+                                        key.game.title,
+                                        page_dir.join(page::PAGE_INDEX).display()
+                                    ));
+                                }
+                            }
+                            Err(e) => warn(format!(
+                                "Failed to archive the page of {}: {}",
+                                key.game.title, e
+                            )),
+                        }
+                    }
+
+                    // What we downloaded for this upload last time, when this is an update
+                    let mut previous = None;
+                    let mut downgrading = false;
+                    if let Some(sync) = &sync {
+                        let mut manifest = sync.manifest.lock().unwrap();
+                        if key.download_key_id().is_some() {
+                            if let Some(extra) =
+                                manifest.unexplained_downloads(key.game_id, key.downloads)
+                            {
+                                sync_summary.lock().unwrap().key_use.push(format!(
+                                    "{} ({} more downloads than this tool made)",
+                                    key.game.title, extra
+                                ));
+                            }
+                            if !sync.dry_run {
+                                manifest.observe_key_downloads(key.game_id, key.downloads);
+                            }
+                        }
+                        if manifest.is_current(
+                            key.game_id,
+                            upload.id,
+                            upload.size,
+                            upload.md5_hash.as_deref(),
+                            upload.build_id,
+                            upload.updated_at,
+                        ) {
+                            sync_summary.lock().unwrap().up_to_date += 1;
+                            debug!(game = %key.game.title, "Up to date according to the manifest");
+                            return outcome.skipped();
+                        }
+                        previous = manifest.previous(key.game_id, upload.id).cloned();
+                        // A creator rolling an upload back shouldn't replace the newer copy we have
+                        downgrading = previous.as_ref().is_some_and(|previous| {
+                            previous.is_newer_than(upload.build_id, upload.updated_at)
+                                && manifest.resolve(&previous.path).exists()
+                        });
+                        drop(manifest);
+
+                        if let (true, Some(previous)) = (downgrading, &previous) {
+                            let versions = format!(
+                                "local {}, remote {}",
+                                manifest::version_label(previous.build_id, previous.updated_at),
+                                manifest::version_label(upload.build_id, upload.updated_at)
+                            );
+                            if !sync.allow_downgrade {
+                                warn(format!(
+                                    "Warning: itch serves an older {} for {} than the one downloaded ({}), skipping it, pass --allow-downgrade to replace it",
+                                    upload.filename, key.game.title, versions
+                                ));
+                                sync_summary.lock().unwrap().downgrades.push(format!(
+                                    "{}: {} skipped ({})",
+                                    key.game.title, upload.filename, versions
+                                ));
+                                return outcome.skipped_because(format!(
+                                    "remote is older than the local copy ({})",
+                                    versions
+                                ));
+                            }
+                            if !sync.dry_run {
+                                sync_summary.lock().unwrap().downgrades.push(format!(
+                                    "{}: {} replaced{} ({})",
+                                    key.game.title,
+                                    upload.filename,
+                                    if sync.keep_downgraded {
+                                        ", keeping the newer copy"
+                                    } else {
+                                        ""
+                                    },
+                                    versions
+                                ));
+                            }
+                        }
+
+                        if sync.dry_run {
+                            on_event(Event::WouldFetch {
+                                key: &key,
+                                upload,
+                                previous: previous.as_ref(),
+                            });
+                            return outcome.skipped();
+                        }
+                    }
+
+                    if let Some(budget) = &budget {
+                        let reservation = budget.lock().unwrap().reserve(upload.size);
+                        match reservation {
+                            budget::Reservation::Granted => {}
+                            budget::Reservation::UnknownSize => {
+                                warn(format!(
+                                    "Warning: itch doesn't report a size for {} ({}), leaving it out of the budgeted run",
+                                    upload.filename, key.game.title
+                                ));
+                                deferred.lock().unwrap().push(Deferred {
+                                    title: key.game.title.clone(),
+                                    filename: upload.filename.clone(),
+                                    size: None,
+                                });
+                                return outcome.skipped();
+                            }
+                            budget::Reservation::OverBudget => {
+                                deferred.lock().unwrap().push(Deferred {
+                                    title: key.game.title.clone(),
+                                    filename: upload.filename.clone(),
+                                    size: Some(upload.size),
+                                });
+                                return outcome.skipped();
+                            }
+                        }
+                    }
+
+                    // Transfers already under way finish, anything still queued is dropped
+                    let waiting = waiting_for_slot.fetch_add(1, Ordering::Relaxed);
+                    on_event(Event::WaitingForSlot(waiting + 1));
+                    let permit = tokio::select! {
+                        permit = async {
+                            let author_permit = author_slots.acquire(key.game.user.id).await;
+                            (semaphore.acquire().await.unwrap(), author_permit)
+                        } => Some(permit),
+                        _ = shutdown.cancelled() => None,
+                    };
+                    let waiting = waiting_for_slot.fetch_sub(1, Ordering::Relaxed) - 1;
+                    on_event(Event::WaitingForSlot(waiting));
+                    let Some(permit) = permit else {
+                        return outcome.failed("Stopped before starting");
+                    };
+
+                    // A copy in another local mirror saves downloading it again, builds aside
+                    let filename = upload_filename(&upload.filename, upload.id);
+                    let seed = match (&seeds, build_id) {
+                        (Some(seeds), None) => seeds.find(
+                            key.game_id,
+                            upload.id,
+                            &filename,
+                            upload.size,
+                            upload.md5_hash.as_deref(),
+                        ),
+                        _ => None,
+                    };
+                    on_event(Event::DownloadStarted {
+                        job,
+                        key: &key,
+                        upload,
+                        seed: seed.as_ref().map(|seed| seed.path.as_path()),
+                    });
+                    let fail = |message: String| on_event(Event::Failed { job, message });
+
+                    // Work out where this game's files go
+                    let root = if group_by_bundle {
+                        files_root.join(layout::origin_dir_name(&key))
+                    } else {
+                        files_root.clone()
+                    };
+                    let game_dir = if per_game_dirs {
+                        game_dirs.dir_for(&root, &key.game)
+                    } else {
+                        root
+                    };
+                    // --organize puts the file one folder further down, by what it is
+                    let category = organize
+                        .then(|| layout::Category::of(&upload.upload_type, &upload.filename));
+                    let dest_dir = match category {
+                        Some(category) => game_dir.join(category.dir_name()),
+                        None => game_dir.clone(),
+                    };
+                    if let Err(e) = tokio::fs::create_dir_all(&dest_dir).await {
+                        fail(format!("Failed {}: {}", upload.filename, e));
+                        return outcome.failed(format!("Failed to create directory: {}", e));
+                    }
+
+                    // Move the version being replaced out of the way, it's restored if the update fails.
+                    // A run directory leaves it where it is
+                    let set_aside = match (&sync, &previous) {
+                        (Some(sync), Some(previous)) if !run_dir => match set_aside_previous(
+                            sync,
+                            previous,
+                            sync.keep_old_versions || (downgrading && sync.keep_downgraded),
+                        ) {
+                            Ok(set_aside) => set_aside,
+                            Err(e) => {
+                                fail(format!("Failed {}: {}", upload.filename, e));
+                                return outcome.failed(e);
+                            }
+                        },
+                        _ => None,
+                    };
+
+                    // Download the file, or the build asked for
+                    let upload_save = SaveOptions {
+                        expected_size: (upload.size > 0).then_some(upload.size),
+                        ..SaveOptions::clone(&save)
+                    };
+                    let on_progress = |progress: DownloadProgress| {
+                        on_event(Event::Progress { job, progress })
+                    };
+                    let (mut seeded_from, mut seeded_file) = (None, None);
+                    if let Some(seed) = seed {
+                        let (dest_dir, save) = (dest_dir.clone(), upload_save.clone());
+                        let (upload_id, size, md5) =
+                            (upload.id, upload.size, upload.md5_hash.clone());
+                        let source = seed.path.clone();
+                        let placed = tokio::task::spawn_blocking(move || {
+                            seed::place(&seed, upload_id, &filename, size, md5.as_deref(), &dest_dir, &save)
+                        })
+                        .await
+                        .context("Seeding task panicked")
+                        .and_then(|placed| placed);
+                        let note = |message: String| on_event(Event::Note { job, message });
+                        match placed {
+                            Ok(Some(placed)) => {
+                                seeded_from = Some(source);
+                                seeded_file = Some(placed);
+                            }
+                            Ok(None) => note(format!(
+                                "{} doesn't match the upload, downloading {}",
+                                source.display(),
+                                upload.filename
+                            )),
+                            Err(e) => note(format!(
+                                "Failed to seed {} ({}), downloading it",
+                                upload.filename, e
+                            )),
+                        }
+                    }
+                    let download_result = match (seeded_file, build_id) {
+                        (Some(placed), _) => Ok(placed),
+                        (None, Some(build_id)) => {
+                            client
+                                .download_build(
+                                    build_id,
+                                    key.download_key_id(),
+                                    &upload.filename,
+                                    &dest_dir,
+                                    &upload_save,
+                                    on_progress,
+                                    &abort,
+                                )
+                                .await
+                        }
+                        (None, None) => {
+                            client
+                                .download_file(
+                                    upload.id,
+                                    key.download_key_id(),
+                                    &upload.filename,
+                                    &dest_dir,
+                                    &upload_save,
+                                    on_progress,
+                                    &abort,
+                                )
+                                .await
+                        }
+                    };
+
+                    // The bytes are on disk, so free the download slot before any extraction
+                    drop(permit);
+
+                    let succeed = |message: String| on_event(Event::Succeeded { job, message });
+                    match download_result {
+                        Ok(downloaded) => {
+                            let filename = downloaded.filename.clone();
+                            succeed(if seeded_from.is_some() {
+                                format!("Seeded {} locally", filename)
+                            } else if downloaded.linked {
+                                format!("Downloaded {} (linked to an identical file)", filename)
+                            } else {
+                                format!("Downloaded {}", filename)
+                            });
+                            // What the game's `current` link should point at once we're done
+                            let mut latest = Some(filename.clone());
+
+                            // If unzip is enabled and the file is an archive, extract it
+                            let archive_path = dest_dir.join(&filename);
+                            // Where the download lives once we're done, for the sync manifest
+                            let mut stored_path = archive_path.clone();
+                            let mut extract_error = None;
+                            // With per-game directories each archive extracts next to itself so
+                            // versions don't mix, otherwise into a directory named after the game
+                            let extract_dir_name = if per_game_dirs {
+                                extract::strip_archive_extension(&filename).to_string()
+                            } else {
+                                game_dir_name.clone()
+                            };
+                            let decision = if unzip {
+                                extract::decide(
+                                    &dest_dir,
+                                    &filename,
+                                    extract_dir_name,
+                                    upload.id,
+                                    &extract_options,
+                                    extract_conflict,
+                                )
+                            } else {
+                                ExtractDecision::Keep
+                            };
+                            match decision {
+                                ExtractDecision::Misnamed(named) => {
+                                    succeed(format!(
+                                        "Downloaded {} (not extracted, it isn't really a {:?} archive)",
+                                        filename, named
+                                    ));
+                                    not_extracted.lock().unwrap().push(format!(
+                                        "{} ({}, not really a {:?} archive)",
+                                        filename, key.game.title, named
+                                    ));
+                                }
+                                ExtractDecision::NoExtractor(kind) => {
+                                    succeed(format!(
+                                        "Downloaded {} (not extracted, no extractor for {:?})",
+                                        filename, kind
+                                    ));
+                                    not_extracted.lock().unwrap().push(format!(
+                                        "{} ({}, pass --unrar-path to extract RAR files)",
+                                        filename, key.game.title
+                                    ));
+                                }
+                                ExtractDecision::Held { dir_name, previous } => {
+                                    succeed(format!(
+                                        "Downloaded {} (not extracted, {} holds upload {})",
+                                        filename, dir_name, previous
+                                    ));
+                                    extract_conflicts.lock().unwrap().push(format!(
+                                        "{} ({}): skipped, {} holds upload {}",
+                                        filename, key.game.title, dir_name, previous
+                                    ));
+                                }
+                                ExtractDecision::Extract {
+                                    kind,
+                                    dir_name: extract_dir_name,
+                                    conflict,
+                                } => {
+                                    on_event(Event::ExtractQueued { job, filename: &filename });
+                                    let _extract_permit = extract_semaphore.acquire().await.unwrap();
+                                    on_event(Event::ExtractStarted {
+                                        job,
+                                        key: &key,
+                                        filename: &filename,
+                                    });
+
+                                    let extract_dir = dest_dir.join(&extract_dir_name);
+                                    if let Some(conflict) = conflict {
+                                        if matches!(conflict, extract::Conflict::Replace { .. }) {
+                                            let _ = tokio::fs::remove_dir_all(&extract_dir).await;
+                                        }
+                                        let decision = conflict.describe(&extract_dir_name);
+                                        on_event(Event::Note {
+                                            job,
+                                            message: format!("Extracting {}, {}", filename, decision),
+                                        });
+                                        extract_conflicts.lock().unwrap().push(format!(
+                                            "{} ({}): {}",
+                                            filename, key.game.title, decision
+                                        ));
+                                    }
+
+                                    let extract_observer = observer.clone();
+                                    let unzip_result = extract_archive(
+                                        &archive_path,
+                                        &extract_dir,
+                                        kind,
+                                        &extract_options,
+                                        &abort,
+                                        move |written, total| {
+                                            extract_observer.event(Event::ExtractProgress {
+                                                job,
+                                                written,
+                                                total,
+                                            })
+                                        },
+                                    )
+                                    .await;
+
+                                    match unzip_result {
+                                        Ok(()) => {
+                                            // Directories extracted before this marker existed are
+                                            // never treated as conflicting
+                                            let _ = extract::mark_extracted(&extract_dir, upload.id);
+                                            let incomplete = match audit_extractions {
+                                                Some(deep) => {
+                                                    audit_extracted(
+                                                        &archive_path,
+                                                        kind,
+                                                        &extract_dir,
+                                                        extract_options.zip_encoding,
+                                                        deep,
+                                                    )
+                                                    .await
+                                                }
+                                                None => None,
+                                            };
+                                            on_event(Event::Extracted {
+                                                job,
+                                                key: &key,
+                                                filename: &filename,
+                                                path: &extract_dir,
+                                            });
+                                            if let Some(lines) = incomplete {
+                                                fail(format!(
+                                                    "Downloaded {} but the extraction is incomplete, keeping the archive",
+                                                    filename
+                                                ));
+                                                for line in &lines {
+                                                    warn(line.clone());
+                                                }
+                                                extract_error = lines.into_iter().next();
+                                            } else {
+                                                succeed(format!(
+                                                    "Downloaded and extracted {}",
+                                                    filename
+                                                ));
+                                                // Remove the archive once it has been extracted
+                                                let _ = tokio::fs::remove_file(&archive_path).await;
+                                            }
+                                            latest = Some(extract_dir_name);
+                                            stored_path = extract_dir;
+                                        }
+                                        Err(e) => {
+                                            on_event(Event::ExtractFailed {
+                                                job,
+                                                key: &key,
+                                                filename: &filename,
+                                                error: e.to_string(),
+                                            });
+                                            fail(format!(
+                                                "Downloaded {} but failed to extract: {}",
+                                                filename, e
+                                            ));
+                                            extract_error = Some(format!("Failed to extract: {}", e));
+                                            latest = None;
+                                        }
+                                    }
+                                }
+                                ExtractDecision::Keep => {}
+                            }
+
+                            // Only a file that stays gets one, an extracted archive is removed.
+                            // Sidecars left by files since renamed or removed go at the same time.
+                            if sidecar_checksums {
+                                let written = if archive_path.is_file() {
+                                    checksums::write_sidecar(&archive_path, &downloaded.sha256)
+                                } else {
+                                    Ok(())
+                                };
+                                if let Err(e) = written
+                                    .and_then(|()| checksums::remove_stale(&dest_dir).map(|_| ()))
+                                {
+                                    warn(e.to_string());
+                                }
+                            }
+
+                            // Launchers read these when the mirror is exported
+                            let metadata_dir = if per_game_dirs {
+                                Some(game_dir.clone())
+                            } else {
+                                Some(stored_path.clone()).filter(|path| path.is_dir())
+                            };
+                            if let Some(metadata_dir) = metadata_dir {
+                                // The key's copy of the game lacks the description and platforms,
+                                // but does when the game endpoint can't be reached or has nothing
+                                let mut metadata = match client.get_game(key.game_id).await {
+                                    Ok(Some(details)) => export::GameMetadata::from(&details),
+                                    Ok(None) => export::GameMetadata::from(&key.game),
+                                    Err(e) => {
+                                        debug!(error = %e, "Game endpoint unavailable for metadata");
+                                        export::GameMetadata::from(&key.game)
+                                    }
+                                };
+                                metadata.key_downloads =
+                                    key.download_key_id().map(|_| key.downloads);
+                                metadata.origin = Some(key.origin().to_string());
+                                if let Some(category) = category {
+                                    // Each download only knows its own file, so keep the others'
+                                    metadata.organized = export::GameMetadata::find(&game_dir)
+                                        .map(|earlier| earlier.organized)
+                                        .unwrap_or_default();
+                                    metadata.organized.insert(filename.clone(), category);
+                                }
+                                if receipts {
+                                    let receipt = receipt::Receipt::new(
+                                        &account,
+                                        &key,
+                                        upload.id,
+                                        &filename,
+                                        &downloaded.sha256,
+                                    );
+                                    if let Err(e) = receipt.write(&metadata_dir, &key.game.title) {
+                                        warn(e.to_string());
+                                    }
+                                    metadata.receipt = Some(receipt);
+                                }
+                                if let Err(e) = metadata.write(&metadata_dir) {
+                                    warn(e.to_string());
+                                }
+                            }
+
+                            if let (true, Some(latest)) = (link_latest, latest) {
+                                let latest = match category {
+                                    Some(category) => format!("{}/{}", category.dir_name(), latest),
+                                    None => latest,
+                                };
+                                if let Err(e) = layout::update_current_link(&game_dir, &latest) {
+                                    warn(format!(
+                                        "Failed to update current link for {}: {}",
+                                        key.game.title, e
+                                    ));
+                                }
+                            }
+
+                            if let Some((_, set_aside)) = &set_aside
+                                && let Err(e) = staging::remove_path(set_aside)
+                            {
+                                warn(format!(
+                                    "Failed to remove the old version of {}: {}",
+                                    key.game.title, e
+                                ));
+                            }
+                            if let Some(previous) = &previous {
+                                sync_summary.lock().unwrap().updated.push(format!(
+                                    "{}: {} -> {}",
+                                    key.game.title,
+                                    manifest::version_label(
+                                        previous.build_id,
+                                        previous.updated_at
+                                    ),
+                                    manifest::version_label(
+                                        upload.build_id,
+                                        upload.updated_at
+                                    ),
+                                ));
+                            }
+
+                            let mut outcome = outcome.completed(downloaded.size, extract_error);
+                            if let Some(source) = &seeded_from {
+                                outcome = outcome.seeded();
+                                let root = seeds
+                                    .as_ref()
+                                    .and_then(|seeds| seeds.root_of(source))
+                                    .unwrap_or(source);
+                                seeded.lock().unwrap().push((
+                                    format!(
+                                        "{} ({}) from {}",
+                                        downloaded.filename,
+                                        key.game.title,
+                                        root.display()
+                                    ),
+                                    downloaded.size,
+                                ));
+                            }
+                            if let Some(sync) = &sync {
+                                let mut manifest = sync.manifest.lock().unwrap();
+                                let record = UploadRecord {
+                                    filename: downloaded.filename,
+                                    path: manifest.relative(&stored_path),
+                                    size: downloaded.size,
+                                    md5: upload.md5_hash.clone(),
+                                    sha256: Some(downloaded.sha256),
+                                    build_id: upload.build_id,
+                                    updated_at: upload.updated_at,
+                                    downloaded_at: manifest::now(),
+                                };
+                                manifest.record(
+                                    key.game_id,
+                                    &key.game.title,
+                                    key.id,
+                                    upload.id,
+                                    record,
+                                );
+                                // itch counts the download we just made against the key
+                                if key.download_key_id().is_some() {
+                                    manifest
+                                        .observe_key_downloads(key.game_id, key.downloads + 1);
+                                }
+                                // Saved after every download so an interrupted run keeps its progress
+                                if let Err(e) = manifest.save() {
+                                    warn(format!("Failed to save manifest: {}", e));
+                                }
+                            }
+                            outcome
+                        }
+                        Err(e) => {
+                            if let Some((original, set_aside)) = &set_aside {
+                                let _ = std::fs::rename(set_aside, original);
+                            }
+                            if e.is_gone() {
+                                debug!(error = %e, "Upload is gone");
+                                fail(format!("Gone {}", upload.filename));
+                                return outcome.gone("The upload");
+                            }
+                            if e.is_key_limit() {
+                                fail(format!(
+                                    "Download key limit reached for {}",
+                                    key.game.title
+                                ));
+                                return outcome.key_limit(&e);
+                            }
+                            fail(format!("Failed {}: {}", upload.filename, e));
+                            outcome.failed(e)
+                        }
+                    }
+                }
+                // Tags everything logged for this game, such as why its upload was chosen
+                .instrument(tracing::info_span!("game", id = key.game_id, title = %key.game.title))
+                .await;
+            on_event(Event::Done {
+                job,
+                outcome: &outcome,
+            });
+
+            // A game without uploads is nothing to fix, so it doesn't count as a failure here, nor
+            // does a deleted one unless --strict says so
+            let hard_failure = (outcome.status == history::Status::Failed
+                && outcome.error.as_deref() != Some(NO_UPLOADS))
+                || outcome.status == history::Status::KeyLimit
+                || (strict && outcome.status == history::Status::Gone);
+            if fail_fast && hard_failure && !shutdown.is_cancelled() {
+                first_failure
+                    .lock()
+                    .unwrap()
+                    .get_or_insert_with(|| outcome.title.clone());
+                shutdown.cancel();
+            }
+            if let Some(history) = &history {
+                history.record(outcome.clone(), started.elapsed());
+            }
+            if let Some(failed_job) =
+                failed_job.filter(|_| outcome.status == history::Status::Failed)
+            {
+                failed_jobs
+                    .lock()
+                    .unwrap()
+                    .push((outcome.clone(), failed_job));
+            }
+            outcomes.lock().unwrap().push(outcome);
+        })
+    };
+
+    let mut download_tasks = Vec::new();
+    if streaming {
+        let mut owned_game_ids = HashSet::new();
+        // With --confirm-above, games wait until the whole library has arrived and they're either
+        // few enough not to ask about or the answer was yes
+        let mut held = Vec::new();
+        let mut library =
+            std::pin::pin!(client.owned_keys_stream(|page| on_event(Event::LibraryPage(page))));
+        loop {
+            let key = match library.try_next().await {
+                Ok(Some(key)) => key,
+                Ok(None) => break,
+                Err(e) if allow_partial && !owned_game_ids.is_empty() && !e.is_auth() => {
+                    on_event(Event::Warning(format!(
+                        "Warning: only fetched {} packages before the library stopped loading, carrying on with those: {}",
+                        owned_game_ids.len(),
+                        e
+                    )));
+                    library_complete = false;
+                    break;
+                }
+                Err(e) => {
+                    // Downloads already under way finish, the rest of the library is never seen
+                    shutdown.cancel();
+                    for task in download_tasks {
+                        let _ = task.await;
+                    }
+                    return Err(e);
+                }
+            };
+            owned_game_ids.insert(key.game_id);
+            if !selection.admit(&key) {
+                continue;
+            }
+            if confirm_above.is_some() {
+                held.push(key);
+            } else {
+                download_tasks.push(spawn_download(download_tasks.len(), key, None));
+            }
+        }
+        on_event(Event::LibraryStreamed {
+            total: owned_game_ids.len(),
+            selected: download_tasks.len() + held.len(),
+        });
+
+        if let Some(sync) = sync.as_ref().filter(|_| library_complete) {
+            forget_unowned_games(sync, &owned_game_ids, on_event)?;
+        }
+        if let Some(games) = &collection_games {
+            report_unowned_collection_games(games, &owned_game_ids, on_event);
+        }
+        selection.log();
+        if download_tasks.is_empty() && held.is_empty() {
+            drop(history);
+            if let Some(writer) = history_writer {
+                writer.finish().await?;
+            }
+            return nothing_selected(selection, &output_path, &layout, started_at);
+        }
+        if let Some(threshold) = confirm_above {
+            let confirmed = confirm(&*observer, &held, sync.as_deref(), threshold).await;
+            if let Err(e) = confirmed {
+                drop(history);
+                if let Some(writer) = history_writer {
+                    writer.finish().await?;
+                }
+                return Err(e);
+            }
+            download_tasks.extend(
+                held.into_iter()
+                    .enumerate()
+                    .map(|(index, key)| spawn_download(index, key, None)),
+            );
+        }
+    } else {
+        download_tasks.extend(
+            jobs.into_iter()
+                .enumerate()
+                .map(|(index, (key, listed))| spawn_download(index, key, listed)),
+        );
+    }
+
+    // Wait for all downloads and their extractions to complete
+    for task in download_tasks {
+        let _ = task.await;
+    }
+    // Retries started from the triage go through the same slots and history as the run
+    let mut skips = Skips::clone(&skips);
+    if triage && !shutdown.is_cancelled() && !dry_run {
+        loop {
+            let failed = std::mem::take(&mut *failed_jobs.lock().unwrap());
+            if failed.is_empty() {
+                break;
+            }
+            let retry = observer.triage(failed, &mut skips)?;
+            skips.save(&output_path, &layout)?;
+            if retry.is_empty() {
+                break;
+            }
+            on_event(Event::Info(format!("Retrying {} downloads", retry.len())));
+            // The retry's outcome replaces the failure in the summary
+            outcomes.lock().unwrap().retain(|outcome| {
+                !retry.iter().any(|(failed, _)| {
+                    (failed.game_id, failed.upload_id) == (outcome.game_id, outcome.upload_id)
+                        && outcome.status == history::Status::Failed
+                })
+            });
+            let tasks: Vec<_> = retry
+                .into_iter()
+                .enumerate()
+                .map(|(index, (_, (key, listed)))| spawn_download(index, key, listed))
+                .collect();
+            for task in tasks {
+                let _ = task.await;
+            }
+        }
+    }
+
+    // Every task's handle is gone by now, dropping ours lets the writer drain and stop
+    drop(history);
+    if let Some(writer) = history_writer {
+        writer.finish().await?;
+    }
+
+    let remaining_failures = if record_failures {
+        let outcomes = outcomes.lock().unwrap();
+        let mut remaining = failures::remaining(&previous_failures, &outcomes, retry_failed);
+        remaining.retain(|item| match item.upload_id {
+            Some(upload_id) => !skips.skips_upload(item.game_id, upload_id),
+            None => !skips.skips_game(item.game_id),
+        });
+        failures::save(&output_path, &layout, &remaining)?;
+        Some(remaining.len())
+    } else {
+        None
+    };
+
+    let sync_summary = match &sync {
+        Some(sync) => {
+            if !sync.dry_run {
+                sync.manifest.lock().unwrap().save()?;
+            }
+            Some(std::mem::take(&mut *sync_summary.lock().unwrap()))
+        }
+        None => None,
+    };
+
+    let deduplicated = match &save.dedupe {
+        Some(index) => {
+            let index: std::sync::MutexGuard<'_, HashIndex> = index.lock().unwrap();
+            index.save()?;
+            Some((index.linked_files, index.saved_bytes))
+        }
+        None => None,
+    };
+
+    let outcomes = std::mem::take(&mut *outcomes.lock().unwrap());
+    let (mut report_path, mut run_manifest) = (None, None);
+    if record_failures {
+        let run_report = report::Report::new(started_at, &outcomes);
+        report_path = Some(run_report.write(report.as_ref(), &output_path, &layout)?);
+        if run_dir && files_root.exists() {
+            let manifest = runs::RunManifest::new(run_args, started_at, &outcomes);
+            run_manifest = Some(manifest.write(&files_root)?);
+        }
+        // Delivery problems never fail the run, the downloads themselves went fine
+        if let Some(url) = &webhook
+            && let Err(e) = run_report.post(client, url).await
+        {
+            on_event(Event::Warning(format!(
+                "Warning: failed to send webhook notification: {}",
+                e
+            )));
+        }
+    }
+
+    let failed = outcomes.iter().any(|outcome| {
+        outcome.status == history::Status::Failed
+            || outcome.status == history::Status::KeyLimit
+            || (strict && outcome.status == history::Status::Gone)
+    });
+    // Games on the pages that never arrived weren't looked at, so the run doesn't count as complete
+    if record_failures && !failed && library_complete {
+        since::record_run(&output_path, &layout, started_at)?;
+    }
+    let first_failure = first_failure.lock().unwrap().take();
+    let budget = budget.map(|budget| {
+        let budget = budget.lock().unwrap();
+        (budget.used(), budget.limit())
+    });
+    Ok(Finished::Ran(Box::new(Summary {
+        started_at,
+        outcomes,
+        failed,
+        library_complete,
+        interrupted: abort.is_cancelled(),
+        first_failure,
+        remaining_failures,
+        sync: sync_summary,
+        seeded: std::mem::take(&mut *seeded.lock().unwrap()),
+        deduplicated,
+        budget,
+        deferred: std::mem::take(&mut *deferred.lock().unwrap()),
+        not_extracted: std::mem::take(&mut *not_extracted.lock().unwrap()),
+        extract_conflicts: std::mem::take(&mut *extract_conflicts.lock().unwrap()),
+        report_path,
+        run_manifest,
+    })))
+}
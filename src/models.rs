@@ -0,0 +1,685 @@
+use crate::timestamp::Timestamp;
+use crate::{export, json};
+use serde::{Deserialize, Serialize};
+
+/// An itch.io account, as returned by the profile endpoint and embedded in games
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct User {
+    pub id: u64,
+    pub username: String,
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub url: String,
+    pub cover_url: Option<String>,
+}
+
+/// A game or asset page
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Game {
+    pub id: u64,
+    pub title: String,
+    pub short_text: Option<String>,
+    #[serde(default)]
+    pub url: String,
+    #[serde(rename = "type", default)]
+    pub game_type: String,
+    #[serde(default)]
+    pub classification: String,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub created_at: Option<Timestamp>,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub published_at: Option<Timestamp>,
+    pub cover_url: Option<String>,
+    pub still_cover_url: Option<String>,
+    pub min_price: Option<u64>,
+    /// Whether the page is public, absent on older responses
+    #[serde(default)]
+    pub published: Option<bool>,
+    /// Only reported for games the key's owner can see stats for
+    pub downloads_count: Option<u64>,
+    /// Only reported for the account's own games
+    pub views_count: Option<u64>,
+    /// Only reported for the account's own games
+    pub purchases_count: Option<u64>,
+    /// How HTML games are embedded on the page
+    #[serde(default, deserialize_with = "json::object_or_empty")]
+    pub embed: Option<Embed>,
+    #[serde(default)]
+    pub has_demo: bool,
+    /// The game's author
+    pub user: User,
+}
+
+/// A game as the game endpoint describes it, with more than the copy embedded in keys
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GameDetails {
+    #[serde(flatten)]
+    pub game: Game,
+    /// The page's full description, as HTML
+    pub description: Option<String>,
+    #[serde(default)]
+    pub can_be_bought: bool,
+    #[serde(default)]
+    pub in_press_system: bool,
+    #[serde(default)]
+    pub p_windows: bool,
+    #[serde(default)]
+    pub p_linux: bool,
+    #[serde(default)]
+    pub p_osx: bool,
+    #[serde(default)]
+    pub p_android: bool,
+    /// The sale running right now, if any
+    pub sale: Option<Sale>,
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    pub traits: Vec<String>,
+    /// The page's screenshots, when the endpoint lists them
+    #[serde(default, deserialize_with = "json::urls")]
+    pub screenshots: Vec<String>,
+}
+
+impl User {
+    /// The account's subdomain on itch.io, e.g. `someone` for `https://someone.itch.io`
+    pub fn slug(&self) -> Option<String> {
+        url_slug(&self.url).map(|(user, _)| user)
+    }
+}
+
+impl Game {
+    /// `<author>/<game>` from the page's URL, e.g. `someone/some-game` for
+    /// `https://someone.itch.io/some-game`
+    ///
+    /// Unlike the title it stays the same when the game is renamed. `None` for pages on a custom
+    /// domain.
+    pub fn slug(&self) -> Option<String> {
+        let (user, game) = url_slug(&self.url)?;
+        Some(format!("{}/{}", user, game?))
+    }
+}
+
+/// The subdomain and first path segment of an itch.io page URL, lowercased
+fn url_slug(url: &str) -> Option<(String, Option<String>)> {
+    let parsed: reqwest::Url = url.trim().parse().ok()?;
+    let host = parsed.host_str()?.to_ascii_lowercase();
+    let user = host.strip_suffix(".itch.io")?;
+    if user.is_empty() || user == "www" || user.contains('.') {
+        return None;
+    }
+    let game = parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next())
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_ascii_lowercase);
+    Some((user.to_string(), game))
+}
+
+impl GameDetails {
+    /// The systems the game is marked as running on
+    pub fn platforms(&self) -> Vec<&'static str> {
+        [
+            (self.p_windows, "windows"),
+            (self.p_linux, "linux"),
+            (self.p_osx, "osx"),
+            (self.p_android, "android"),
+        ]
+        .into_iter()
+        .filter_map(|(supported, name)| supported.then_some(name))
+        .collect()
+    }
+}
+
+/// A discount on a game
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Sale {
+    pub id: u64,
+    /// Percentage off
+    pub rate: Option<f64>,
+    pub start_date: Option<String>,
+    pub end_date: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct GameResponse {
+    /// Missing when the game is gone, itch answering with `errors` instead
+    pub game: Option<GameDetails>,
+}
+
+/// The frame an HTML game is played in on its page
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Embed {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub fullscreen: bool,
+}
+
+impl From<&Game> for export::GameMetadata {
+    fn from(game: &Game) -> Self {
+        Self {
+            game_id: game.id,
+            title: game.title.clone(),
+            developer: Some(
+                game.user
+                    .display_name
+                    .clone()
+                    .unwrap_or_else(|| game.user.username.clone()),
+            ),
+            short_text: game.short_text.clone(),
+            published_at: game.published_at,
+            url: Some(game.url.clone()),
+            slug: game.slug(),
+            cover_url: game.cover_url.clone(),
+            description: None,
+            platforms: Vec::new(),
+            receipt: None,
+            key_downloads: None,
+            origin: None,
+            organized: Default::default(),
+        }
+    }
+}
+
+impl From<&GameDetails> for export::GameMetadata {
+    fn from(details: &GameDetails) -> Self {
+        Self {
+            description: details.description.clone(),
+            platforms: details
+                .platforms()
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            ..Self::from(&details.game)
+        }
+    }
+}
+
+/// The bundle a key was granted through
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BundleOrigin {
+    pub id: u64,
+    pub title: String,
+}
+
+/// How keys with no bundle to them are grouped, bought on their own or claimed for free
+pub const DIRECT_ORIGIN: &str = "direct purchase/claim";
+
+/// A download key for a game in the account's library
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OwnedKey {
+    pub id: u64,
+    pub game_id: u64,
+    pub purchase_id: Option<u64>,
+    /// The bundle that granted the key, when itch.io says
+    #[serde(default)]
+    pub bundle: Option<BundleOrigin>,
+    #[serde(default)]
+    pub downloads: u64,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub created_at: Option<Timestamp>,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub updated_at: Option<Timestamp>,
+    pub game: Game,
+}
+
+impl OwnedKey {
+    /// Stand in for a key to a game downloaded without one, one the account created or a free one
+    ///
+    /// The key's id is 0, [`OwnedKey::download_key_id`] turning that back into no key.
+    pub fn keyless(game: Game) -> Self {
+        Self {
+            id: 0,
+            game_id: game.id,
+            purchase_id: None,
+            bundle: None,
+            downloads: 0,
+            created_at: game.created_at,
+            updated_at: game.created_at,
+            game,
+        }
+    }
+
+    /// Where the key came from, the bundle's title or [`DIRECT_ORIGIN`]
+    pub fn origin(&self) -> &str {
+        self.bundle
+            .as_ref()
+            .map_or(DIRECT_ORIGIN, |bundle| bundle.title.as_str())
+    }
+
+    /// Whether the key came from the bundle with this id or a title containing it, ignoring case
+    pub fn from_bundle(&self, bundle: &str) -> bool {
+        match &self.bundle {
+            Some(origin) => {
+                bundle.parse() == Ok(origin.id)
+                    || origin.title.to_lowercase().contains(&bundle.to_lowercase())
+            }
+            // `--bundle direct` selects everything no bundle granted
+            None => DIRECT_ORIGIN.starts_with(&bundle.to_lowercase()),
+        }
+    }
+
+    /// The key to send with upload listings and downloads, `None` for keyless ones
+    pub fn download_key_id(&self) -> Option<u64> {
+        Some(self.id).filter(|&id| id != 0)
+    }
+}
+
+/// A downloadable file attached to a game
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Upload {
+    pub id: u64,
+    pub filename: String,
+    /// The name shown on the game's page, when the author gave it one
+    pub display_name: Option<String>,
+    pub size: u64,
+    #[serde(rename = "type", default)]
+    pub upload_type: String,
+    /// `hosted`, `build` or `external`
+    #[serde(default)]
+    pub storage: String,
+    /// Where an external upload lives
+    pub host: Option<String>,
+    pub game_id: u64,
+    pub md5_hash: Option<String>,
+    pub build_id: Option<u64>,
+    /// The butler channel a build was pushed to
+    pub channel_name: Option<String>,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub created_at: Option<Timestamp>,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub updated_at: Option<Timestamp>,
+    /// Which systems the upload runs on
+    #[serde(default, deserialize_with = "json::object_or_empty")]
+    pub platforms: Platforms,
+    /// Flags such as `demo` or `preorder`, plus `p_windows` style platforms on some responses
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    pub traits: Vec<String>,
+    /// Set on demos by the server-side API, the current one marks them with the `demo` trait
+    #[serde(default)]
+    pub demo: bool,
+}
+
+impl Upload {
+    /// Whether the upload is a demo, which anyone can download without a key
+    pub fn is_demo(&self) -> bool {
+        self.demo || self.traits.iter().any(|name| name == "demo")
+    }
+}
+
+/// The systems an upload is marked as running on, each with the architectures it supports
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Platforms {
+    pub windows: Option<String>,
+    pub linux: Option<String>,
+    pub osx: Option<String>,
+}
+
+/// A version of a butler upload, as pushed with `butler push`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Build {
+    pub id: u64,
+    pub upload_id: u64,
+    pub parent_build_id: Option<u64>,
+    /// Counts up from 1 with every push to the upload
+    #[serde(default)]
+    pub version: u64,
+    /// The version given with `butler push --userversion`, if any
+    pub user_version: Option<String>,
+    /// `started`, `processing`, `completed` or `failed`, only completed builds can be downloaded
+    #[serde(default)]
+    pub state: String,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub created_at: Option<Timestamp>,
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    pub files: Vec<BuildFile>,
+}
+
+/// One of the files stored for a build: the archive itself, patches and signatures
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuildFile {
+    #[serde(rename = "type", default)]
+    pub file_type: String,
+    #[serde(default)]
+    pub size: u64,
+}
+
+impl Build {
+    /// The size of the build's archive, 0 when itch.io doesn't say
+    pub fn archive_size(&self) -> u64 {
+        self.files
+            .iter()
+            .find(|file| file.file_type == "archive")
+            .map_or(0, |file| file.size)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BuildsResponse {
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    pub builds: Vec<Build>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UploadsResponse {
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    pub uploads: Vec<Upload>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct MyGamesResponse {
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    pub games: Vec<Game>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ProfileResponse {
+    pub user: User,
+}
+
+/// One page of the library
+///
+/// An account without any keys may get a page with none of the fields, which reads as empty.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OwnedKeysResponse {
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    pub owned_keys: Vec<OwnedKey>,
+    #[serde(default)]
+    pub page: u64,
+    /// The page size the API used, 0 when it didn't say
+    #[serde(default)]
+    pub per_page: u64,
+}
+
+/// A list of games the account has put together on itch.io
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Collection {
+    pub id: u64,
+    pub title: String,
+    #[serde(default)]
+    pub games_count: u64,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub created_at: Option<Timestamp>,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub updated_at: Option<Timestamp>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CollectionsResponse {
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    pub collections: Vec<Collection>,
+}
+
+/// A game's place in a collection
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CollectionGame {
+    pub game: Game,
+    pub position: Option<u64>,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub created_at: Option<Timestamp>,
+}
+
+/// One page of a collection's games
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CollectionGamesResponse {
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    pub collection_games: Vec<CollectionGame>,
+    #[serde(default)]
+    pub page: u64,
+    #[serde(default)]
+    pub per_page: u64,
+}
+
+/// A download key the account issued for one of its own games
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DownloadKey {
+    pub id: u64,
+    pub key: Option<String>,
+    pub game_id: u64,
+    #[serde(default)]
+    pub downloads: u64,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub created_at: Option<Timestamp>,
+    /// Who claimed the key, `None` while it's unclaimed
+    pub owner: Option<User>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DownloadKeyResponse {
+    pub download_key: DownloadKey,
+}
+
+/// A sale or donation for one of the account's own games
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Purchase {
+    pub id: u64,
+    pub game_id: u64,
+    pub email: Option<String>,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub created_at: Option<Timestamp>,
+    /// Where the sale came from, e.g. `web`, `bundle` or `amazon`
+    pub source: Option<String>,
+    /// The amount paid, formatted with the currency symbol, e.g. `$5.00`
+    pub price: Option<String>,
+    pub currency: Option<String>,
+    /// Percent off the game was on sale for, 0 when it wasn't
+    #[serde(default)]
+    pub sale_rate: f64,
+    /// Paid for a free game, as a donation
+    #[serde(default)]
+    pub donation: bool,
+}
+
+/// One page of a game's purchases
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PurchasesResponse {
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    pub purchases: Vec<Purchase>,
+    /// Absent when itch.io sends every purchase at once
+    #[serde(default)]
+    pub page: u64,
+    #[serde(default)]
+    pub per_page: u64,
+}
+
+/// The ratings and reviews players left on one of the account's own games
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Reviews {
+    pub game_id: u64,
+    pub rating: RatingSummary,
+    pub reviews: Vec<Review>,
+}
+
+/// How a game has been rated overall
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RatingSummary {
+    /// Out of 5, `None` until someone rates the game
+    pub average: Option<f64>,
+    #[serde(default)]
+    pub count: u64,
+}
+
+/// One player's rating, with what they wrote if anything
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Review {
+    pub id: u64,
+    /// Who left it, `None` when they've since deleted their account
+    pub user: Option<User>,
+    /// From 1 to 5
+    pub rating: Option<u8>,
+    pub body: Option<String>,
+    #[serde(default, deserialize_with = "json::timestamp")]
+    pub created_at: Option<Timestamp>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ReviewsResponse {
+    pub rating: Option<RatingSummary>,
+    #[serde(default, deserialize_with = "json::seq_or_map")]
+    pub reviews: Vec<Review>,
+    #[serde(default)]
+    pub per_page: u64,
+}
+
+/// What the legacy server-side API says about the key it was called with
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CredentialsInfo {
+    /// `key` for API keys, `jwt` for the short lived tokens games are launched with
+    #[serde(rename = "type", default)]
+    pub key_type: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub expires_at: Option<String>,
+}
+
+/// How the legacy API reports a failure, with a success status
+#[derive(Debug, Deserialize)]
+pub(crate) struct LegacyErrors {
+    pub errors: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An upload listing as `/games/{id}/uploads` serves it
+    const UPLOADS: &str = r#"{
+        "uploads": [
+            {
+                "id": 7,
+                "game_id": 70,
+                "filename": "game-linux.zip",
+                "display_name": "Linux build",
+                "size": 1048576,
+                "type": "default",
+                "storage": "build",
+                "md5_hash": "9e107d9d372bb6826bd81d3542a419d6",
+                "build_id": 1234,
+                "channel_name": "linux-stable",
+                "created_at": "2022-01-02 03:04:05",
+                "updated_at": "2024-06-07T08:09:10.123Z",
+                "platforms": { "linux": "all" },
+                "traits": ["p_linux"]
+            },
+            {
+                "id": 8,
+                "game_id": 70,
+                "filename": "demo.exe",
+                "size": 0,
+                "host": "example.com",
+                "platforms": [],
+                "traits": { "0": "demo" }
+            }
+        ]
+    }"#;
+
+    /// A game as `/games/{id}` serves it
+    const GAME: &str = r#"{
+        "game": {
+            "id": 70,
+            "title": "Some Game",
+            "url": "https://someone.itch.io/some-game",
+            "type": "default",
+            "classification": "game",
+            "published": true,
+            "published_at": "2021-05-06 07:08:09",
+            "downloads_count": 42,
+            "min_price": 500,
+            "embed": { "width": 800, "height": 600, "fullscreen": true },
+            "has_demo": true,
+            "p_linux": true,
+            "p_windows": true,
+            "sale": { "id": 3, "rate": 50 },
+            "traits": ["p_linux", "p_windows"],
+            "screenshots": ["https://img.itch.zone/1.png", { "url": "https://img.itch.zone/2.png" }],
+            "user": { "id": 1, "username": "someone", "display_name": "Some One" }
+        }
+    }"#;
+
+    #[test]
+    fn uploads_read_every_field() {
+        let uploads: UploadsResponse = json::parse("/uploads", UPLOADS).unwrap();
+        let [build, demo] = uploads.uploads.as_slice() else {
+            panic!("expected two uploads");
+        };
+        assert_eq!(build.display_name.as_deref(), Some("Linux build"));
+        assert_eq!(build.storage, "build");
+        assert_eq!(
+            build.md5_hash.as_deref(),
+            Some("9e107d9d372bb6826bd81d3542a419d6")
+        );
+        assert_eq!(build.build_id, Some(1234));
+        assert_eq!(build.channel_name.as_deref(), Some("linux-stable"));
+        assert_eq!(build.platforms.linux.as_deref(), Some("all"));
+        assert_eq!(build.traits, ["p_linux"]);
+        assert_eq!(
+            build
+                .updated_at
+                .map(crate::timestamp::to_rfc3339)
+                .as_deref(),
+            Some("2024-06-07T08:09:10Z")
+        );
+        assert!(!build.is_demo());
+
+        // Fields left out or served as empty lists fall back to their defaults
+        assert_eq!(demo.upload_type, "");
+        assert_eq!(demo.host.as_deref(), Some("example.com"));
+        assert!(demo.platforms.windows.is_none());
+        assert!(demo.created_at.is_none());
+        assert!(demo.is_demo());
+    }
+
+    #[test]
+    fn games_read_every_field() {
+        let response: GameResponse = json::parse("/games/70", GAME).unwrap();
+        let details = response.game.unwrap();
+        let game = &details.game;
+        assert_eq!(game.published, Some(true));
+        assert_eq!(game.downloads_count, Some(42));
+        assert_eq!(game.min_price, Some(500));
+        assert!(game.has_demo);
+        let embed = game.embed.as_ref().unwrap();
+        assert_eq!(
+            (embed.width, embed.height, embed.fullscreen),
+            (Some(800), Some(600), true)
+        );
+        assert_eq!(details.platforms(), ["windows", "linux"]);
+        assert_eq!(details.sale.as_ref().and_then(|sale| sale.rate), Some(50.0));
+        assert_eq!(details.screenshots.len(), 2);
+    }
+
+    #[test]
+    fn a_missing_game_reads_as_none() {
+        let response: GameResponse =
+            json::parse("/games/70", r#"{"errors": ["invalid game"]}"#).unwrap();
+        assert!(response.game.is_none());
+    }
+
+    #[test]
+    fn models_survive_a_round_trip() {
+        let uploads: UploadsResponse = json::parse("/uploads", UPLOADS).unwrap();
+        let written = serde_json::to_string(&uploads.uploads).unwrap();
+        let read: Vec<Upload> = serde_json::from_str(&written).unwrap();
+        assert_eq!(serde_json::to_string(&read).unwrap(), written);
+        assert_eq!(read[0].updated_at, uploads.uploads[0].updated_at);
+
+        let response: GameResponse = json::parse("/games/70", GAME).unwrap();
+        let written = serde_json::to_string(&response.game).unwrap();
+        let read: GameDetails = serde_json::from_str(&written).unwrap();
+        assert_eq!(serde_json::to_string(&read).unwrap(), written);
+    }
+
+    #[test]
+    fn an_empty_account_reads_as_an_empty_page() {
+        // What a new account gets, with and without the fields a page normally has
+        for body in [
+            "{}",
+            r#"{"owned_keys": [], "page": 1, "per_page": 50}"#,
+            r#"{"owned_keys": {}, "page": 1}"#,
+            r#"{"owned_keys": null}"#,
+        ] {
+            let page: OwnedKeysResponse = json::parse("/profile/owned-keys", body).unwrap();
+            assert!(page.owned_keys.is_empty(), "{}", body);
+        }
+        let collections: CollectionsResponse = json::parse("/profile/collections", "{}").unwrap();
+        assert!(collections.collections.is_empty());
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use itch_downloader::report::Report;
+
+/// Ways of announcing that a run has finished
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum NotifyMode {
+    /// A desktop notification
+    Desktop,
+}
+
+/// Show a desktop notification summing up the run, warning when it can't be shown
+///
+/// Delivery problems never fail the run, the downloads themselves went fine.
+pub async fn show(report: &Report<'_>) {
+    if let Err(e) = show_desktop(report).await {
+        eprintln!("Warning: failed to show desktop notification: {:#}", e);
+    }
+}
+
+/// One line summary of a run, e.g. `12 downloaded, 1 failed (1.2 GiB)`
+fn summary(report: &Report<'_>) -> String {
+    let bytes: u64 = report.completed.iter().map(|outcome| outcome.bytes).sum();
+    format!(
+        "{} downloaded, {} skipped, {} failed ({})",
+        report.completed.len(),
+        report.skipped.len(),
+        report.failed.len(),
+        indicatif::HumanBytes(bytes)
+    )
+}
+
+async fn show_desktop(report: &Report<'_>) -> Result<()> {
+    let body = summary(report);
+    // Showing a notification can block on the session bus
+    tokio::task::spawn_blocking(move || {
+        notify_rust::Notification::new()
+            .appname("itch-downloader")
+            .summary("itch-downloader finished")
+            .body(&body)
+            .show()
+            .map(|_| ())
+    })
+    .await
+    .context("Notification task failed")?
+    .context("Failed to show notification")
+}
@@ -0,0 +1,164 @@
+//! A copy of a game's store page, kept in case the game is delisted and the page goes with it
+
+use crate::error::{Context, ItchError, Result};
+use crate::timestamp::{self, Timestamp};
+use crate::{Game, ItchClient};
+use serde::Serialize;
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+/// Directory inside a game's directory its page is archived in
+pub const PAGE_DIR: &str = "page";
+
+/// Manifest of an archived page, inside [`PAGE_DIR`]
+pub const PAGE_INDEX: &str = "index.json";
+
+/// The page's description, as the game endpoint's HTML
+const DESCRIPTION_FILE: &str = "description.html";
+
+/// What `index.json` records about an archived page
+#[derive(Debug, Serialize)]
+pub struct PageIndex {
+    pub game_id: u64,
+    pub title: String,
+    pub url: String,
+    pub author: String,
+    pub short_text: Option<String>,
+    /// The file holding the description, when the game endpoint had one
+    pub description: Option<String>,
+    /// Whether the game endpoint answered, otherwise only what the library listing has is archived
+    pub from_game_endpoint: bool,
+    pub cover: Option<PageImage>,
+    pub screenshots: Vec<PageImage>,
+    pub archived_at: Timestamp,
+}
+
+/// An image on the page and where its copy is
+#[derive(Debug, Serialize)]
+pub struct PageImage {
+    pub url: String,
+    /// Name of the copy in the page directory, `None` when it couldn't be fetched
+    pub file: Option<String>,
+    pub error: Option<String>,
+}
+
+impl ItchClient {
+    /// Save a game's description, cover and screenshots into `dir`, along with an `index.json`
+    ///
+    /// Images already in `dir` aren't fetched again. When the game endpoint has nothing for the
+    /// game, `game` (the copy embedded in its key) is archived instead. An image that can't be
+    /// fetched is noted in the index rather than failing the archive.
+    pub async fn archive_page(
+        &self,
+        game: &Game,
+        dir: &Path,
+        cancel: &CancellationToken,
+    ) -> Result<PageIndex> {
+        let details = self.get_game(game.id).await?;
+        let game = details.as_ref().map_or(game, |details| &details.game);
+        tokio::fs::create_dir_all(dir)
+            .await
+            .context("Failed to create page directory")?;
+
+        let description = match details
+            .as_ref()
+            .and_then(|details| details.description.as_ref())
+        {
+            Some(description) => {
+                tokio::fs::write(dir.join(DESCRIPTION_FILE), description)
+                    .await
+                    .context("Failed to write description")?;
+                Some(DESCRIPTION_FILE.to_string())
+            }
+            None => None,
+        };
+        let cover = match game.cover_url.as_ref().or(game.still_cover_url.as_ref()) {
+            Some(url) => Some(self.save_image(url, dir, "cover", cancel).await?),
+            None => None,
+        };
+        let mut screenshots = Vec::new();
+        for (index, url) in details
+            .iter()
+            .flat_map(|details| &details.screenshots)
+            .enumerate()
+        {
+            let stem = format!("screenshot-{:02}", index + 1);
+            screenshots.push(self.save_image(url, dir, &stem, cancel).await?);
+        }
+
+        let index = PageIndex {
+            game_id: game.id,
+            title: game.title.clone(),
+            url: game.url.clone(),
+            author: game.user.username.clone(),
+            short_text: game.short_text.clone(),
+            description,
+            from_game_endpoint: details.is_some(),
+            cover,
+            screenshots,
+            archived_at: timestamp::now(),
+        };
+        let contents = serde_json::to_string_pretty(&index).map_err(|e| {
+            ItchError::io("Failed to serialize page index", std::io::Error::other(e))
+        })?;
+        tokio::fs::write(dir.join(PAGE_INDEX), contents)
+            .await
+            .context("Failed to write page index")?;
+        Ok(index)
+    }
+
+    /// Save the image at `url` as `<stem>.<extension>`, unless it's already there
+    async fn save_image(
+        &self,
+        url: &str,
+        dir: &Path,
+        stem: &str,
+        cancel: &CancellationToken,
+    ) -> Result<PageImage> {
+        let name = format!("{}.{}", stem, image_extension(url));
+        let path = dir.join(&name);
+        let mut image = PageImage {
+            url: url.to_string(),
+            file: None,
+            error: None,
+        };
+        if path.exists() {
+            image.file = Some(name);
+            return Ok(image);
+        }
+        let bytes = match self.fetch_asset(url, cancel).await {
+            Ok(bytes) => bytes,
+            Err(ItchError::Cancelled) => return Err(ItchError::Cancelled),
+            Err(e) => {
+                debug!(url, error = %e, "Couldn't fetch page image");
+                image.error = Some(e.to_string());
+                return Ok(image);
+            }
+        };
+        // Renamed into place so an interrupted run never leaves half an image to be skipped later
+        let temp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+        tokio::fs::write(&temp_path, bytes)
+            .await
+            .context("Failed to write image")?;
+        tokio::fs::rename(&temp_path, &path)
+            .await
+            .context("Failed to move image into place")?;
+        image.file = Some(name);
+        Ok(image)
+    }
+}
+
+/// The extension of the file at `url`, `img` when it doesn't have a sensible one
+fn image_extension(url: &str) -> &str {
+    url.split(['?', '#'])
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, extension)| extension)
+        .filter(|extension| {
+            (1..=5).contains(&extension.len())
+                && extension.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+        .unwrap_or("img")
+}
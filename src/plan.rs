@@ -0,0 +1,270 @@
+//! What `dl` would download, worked out from the uploads listings without downloading anything
+
+use crate::extract::{self, ArchiveKind, ExtractOptions};
+use crate::selection::{SelectionPolicy, UploadSet, choose_upload};
+use crate::{ItchClient, OwnedKey, Upload, layout, upload_filename};
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio_util::sync::CancellationToken;
+
+/// How many times a key can have been downloaded with before the plan flags it
+///
+/// itch.io limits how often some keys can be used, so a key near that shouldn't be spent on a
+/// download that isn't needed.
+pub const HEAVY_KEY_DOWNLOADS: u64 = 20;
+
+/// What `dl` would do with a game
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanAction {
+    Download,
+    /// Something is already at the target path, `dl` downloads over it (`sync` is what skips)
+    Replace,
+    /// The game has no uploads, or they couldn't be listed
+    Error,
+}
+
+impl PlanAction {
+    /// Short name used in the table
+    pub fn label(self) -> &'static str {
+        match self {
+            PlanAction::Download => "download",
+            PlanAction::Replace => "replace",
+            PlanAction::Error => "error",
+        }
+    }
+}
+
+/// The plan for a single game
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlannedDownload {
+    pub game_id: u64,
+    pub title: String,
+    pub upload_id: Option<u64>,
+    pub filename: Option<String>,
+    pub size: Option<u64>,
+    /// Where the download, or its extraction with `--unzip`, would end up
+    pub path: Option<PathBuf>,
+    pub action: PlanAction,
+    /// Why a game can't be downloaded, or what's in the way
+    pub detail: Option<String>,
+    /// How often itch.io says the key has been downloaded with
+    #[serde(default)]
+    pub key_downloads: u64,
+    /// Whether that's [`HEAVY_KEY_DOWNLOADS`] or more, so the download may hit the key's limit
+    #[serde(default)]
+    pub heavy_key_use: bool,
+    /// With `--bundle-set`, what in the set captured the upload: `game` or the rule
+    #[serde(default)]
+    pub captured_by: Option<String>,
+    /// The upload itself, for comparing against what was downloaded before
+    #[serde(skip)]
+    pub upload: Option<Upload>,
+}
+
+/// Everything `dl --dry-run` found, in the shape written by `--format json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DownloadPlan {
+    pub games: Vec<PlannedDownload>,
+    /// Bytes every game that would be fetched adds up to
+    pub total_bytes: u64,
+}
+
+impl DownloadPlan {
+    pub fn new(mut games: Vec<PlannedDownload>) -> Self {
+        games.sort_by_key(|entry| entry.title.to_lowercase());
+        let total_bytes = games
+            .iter()
+            .filter(|entry| entry.action != PlanAction::Error)
+            .filter_map(|entry| entry.size)
+            .sum();
+        Self { games, total_bytes }
+    }
+
+    /// The games whose key has been downloaded with suspiciously often
+    pub fn heavily_used_keys(&self) -> impl Iterator<Item = &PlannedDownload> {
+        self.games.iter().filter(|entry| entry.heavy_key_use)
+    }
+
+    /// How many games have the given action
+    pub fn count(&self, action: PlanAction) -> usize {
+        self.games
+            .iter()
+            .filter(|entry| entry.action == action)
+            .count()
+    }
+
+    /// The `(game id, upload id)` pairs a download following the plan fetches
+    pub fn uploads(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.games
+            .iter()
+            .filter(|entry| entry.action != PlanAction::Error)
+            .filter_map(|entry| Some((entry.game_id, entry.upload_id?)))
+    }
+}
+
+/// The settings a plan is made with, the same ones `dl` takes
+#[derive(Clone, Debug, Default)]
+pub struct PlanOptions {
+    pub output_path: PathBuf,
+    /// Whether each game gets its own directory under the output directory
+    pub per_game_dirs: bool,
+    /// Whether games are grouped under the bundle their key came from
+    pub group_by_bundle: bool,
+    /// How archives are extracted, `None` when they're kept as downloaded
+    pub extract: Option<ExtractOptions>,
+    /// How many games' uploads are listed at once, at least 1
+    pub concurrency: usize,
+    /// Whether only demo uploads are considered, listed without the download key
+    pub demos_only: bool,
+    /// With `--bundle-set`, the uploads of each game fetched instead of the one `dl` picks
+    pub bundle_set: Option<UploadSet>,
+    /// The game directories already in the output directory, for games renamed since
+    pub game_dirs: layout::GameDirs,
+}
+
+impl ItchClient {
+    /// Work out which uploads `dl` would fetch for each game and where they would go
+    ///
+    /// Only the uploads listings are fetched, nothing is downloaded or written. Games whose
+    /// uploads can't be listed end up in the plan with [`PlanAction::Error`] rather than failing
+    /// it.
+    pub async fn plan_downloads(
+        &self,
+        keys: &[OwnedKey],
+        options: &PlanOptions,
+        cancel: &CancellationToken,
+    ) -> DownloadPlan {
+        // Made up front rather than by a closure on the stream, which would keep the plan from
+        // being awaited where it has to be `Send`, like in a run's confirmation
+        let planned: Vec<_> = keys
+            .iter()
+            .map(|key| self.plan_game(key, options, cancel))
+            .collect();
+        let games: Vec<Vec<PlannedDownload>> = futures::stream::iter(planned)
+            .buffer_unordered(options.concurrency.max(1))
+            .collect()
+            .await;
+        DownloadPlan::new(games.into_iter().flatten().collect())
+    }
+
+    /// The plan for each upload of a game that would be fetched, a single error entry when none
+    /// would be
+    async fn plan_game(
+        &self,
+        key: &OwnedKey,
+        options: &PlanOptions,
+        cancel: &CancellationToken,
+    ) -> Vec<PlannedDownload> {
+        let mut entry = PlannedDownload {
+            game_id: key.game_id,
+            title: key.game.title.clone(),
+            upload_id: None,
+            filename: None,
+            size: None,
+            path: None,
+            action: PlanAction::Error,
+            detail: None,
+            key_downloads: key.downloads,
+            heavy_key_use: key.download_key_id().is_some() && key.downloads >= HEAVY_KEY_DOWNLOADS,
+            captured_by: None,
+            upload: None,
+        };
+
+        // Demos are free to anyone, so they're listed as they would be without buying the game
+        let listed_key = if options.demos_only {
+            OwnedKey::keyless(key.game.clone())
+        } else {
+            key.clone()
+        };
+        let uploads = match self.get_key_uploads(&listed_key, cancel).await {
+            Ok(uploads) => uploads,
+            Err(e) => {
+                entry.detail = Some(format!("Failed to get uploads: {}", e));
+                return vec![entry];
+            }
+        };
+        let policy = SelectionPolicy {
+            extract: options.extract.clone().unwrap_or_default(),
+            demos_only: options.demos_only,
+        };
+        let chosen: Vec<(&Upload, Option<String>)> = match &options.bundle_set {
+            Some(set) => set
+                .select(&uploads, &policy)
+                .into_iter()
+                .map(|(upload, captured_by)| (upload, Some(captured_by)))
+                .collect(),
+            None => choose_upload(&uploads, &policy)
+                .map(|upload| (upload, None))
+                .into_iter()
+                .collect(),
+        };
+        if chosen.is_empty() {
+            entry.detail = Some(
+                if options.bundle_set.is_some() {
+                    "No uploads in the set"
+                } else if options.demos_only {
+                    "No demo found"
+                } else {
+                    "No uploads found"
+                }
+                .to_string(),
+            );
+            return vec![entry];
+        }
+        chosen
+            .into_iter()
+            .map(|(upload, captured_by)| {
+                let mut entry = entry.clone();
+                entry.captured_by = captured_by;
+                plan_upload(key, upload, options, entry)
+            })
+            .collect()
+    }
+}
+
+/// Fill in where `upload` would go and whether something is already there
+fn plan_upload(
+    key: &OwnedKey,
+    upload: &Upload,
+    options: &PlanOptions,
+    mut entry: PlannedDownload,
+) -> PlannedDownload {
+    entry.upload_id = Some(upload.id);
+    entry.filename = Some(upload.filename.clone());
+    entry.size = Some(upload.size);
+    entry.upload = Some(upload.clone());
+
+    let root = if options.group_by_bundle {
+        options.output_path.join(layout::origin_dir_name(key))
+    } else {
+        options.output_path.clone()
+    };
+    let dest_dir = if options.per_game_dirs {
+        options.game_dirs.dir_for(&root, &key.game)
+    } else {
+        root.clone()
+    };
+    // Only the listed name is known without opening the download, which may still rename it
+    let filename = upload_filename(&upload.filename, upload.id);
+    let extracts = options.extract.as_ref().is_some_and(|extract| {
+        ArchiveKind::from_filename(&filename).is_some_and(|kind| extract.can_extract(kind))
+    });
+    let path = if extracts && options.per_game_dirs {
+        dest_dir.join(extract::strip_archive_extension(&filename))
+    } else if extracts {
+        root.join(options.game_dirs.layout().game_dir(&key.game))
+    } else {
+        dest_dir.join(&filename)
+    };
+
+    if path.exists() {
+        entry.action = PlanAction::Replace;
+        entry.detail = Some("already present".to_string());
+    } else {
+        entry.action = PlanAction::Download;
+    }
+    entry.path = Some(path);
+    entry
+}
@@ -0,0 +1,220 @@
+//! Checks on the directories a command works in, made before anything is fetched or read
+//!
+//! Without them a file in the way of the output directory only shows up as an OS error from
+//! creating it, after the whole library has been listed.
+
+use crate::error::{Result, bail};
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// What a command needs from a directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Files are read from it, so it has to exist
+    Read,
+    /// Files are read from it and written into it, so it has to exist and be writable
+    Update,
+    /// Files are written into it, and it's created if it doesn't exist yet
+    Write,
+}
+
+/// Check `path` can be used as the `what`, e.g. "output directory", failing with a message
+/// saying what's wrong
+///
+/// A directory written to that doesn't exist yet needs its parent to exist and be writable, or
+/// with `create_parents` its closest existing ancestor. Nothing is created, although checking a
+/// directory is writable briefly creates and removes a file in it.
+pub fn check_dir(what: &str, path: &Path, access: Access, create_parents: bool) -> Result<()> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            return match access {
+                Access::Read => Ok(()),
+                Access::Update | Access::Write => check_writable(what, path, path),
+            };
+        }
+        Ok(_) => bail!(
+            "The {} {} exists but isn't a directory",
+            what,
+            path.display()
+        ),
+        // A file further up is reported below, as the ancestor in the way
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {}
+        Err(e) => bail!("Can't use {} as the {}: {}", path.display(), what, e),
+    }
+    if access != Access::Write {
+        bail!("The {} {} doesn't exist", what, path.display());
+    }
+
+    // Ancestors that don't exist either, closest first
+    let mut missing: Vec<&Path> = Vec::new();
+    for ancestor in path.ancestors().skip(1) {
+        let ancestor = if ancestor.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            ancestor
+        };
+        match std::fs::metadata(ancestor) {
+            Ok(metadata) if metadata.is_dir() => {
+                if let Some(outermost) = missing.last().filter(|_| !create_parents) {
+                    bail!(
+                        "The {} {} can't be created because {} doesn't exist, pass \
+                         --create-parents to create it too",
+                        what,
+                        path.display(),
+                        outermost.display()
+                    );
+                }
+                return check_writable(what, path, ancestor);
+            }
+            Ok(_) => bail!(
+                "The {} {} can't be created because {} isn't a directory",
+                what,
+                path.display(),
+                ancestor.display()
+            ),
+            Err(_) => missing.push(ancestor),
+        }
+    }
+    Ok(())
+}
+
+/// Check files can be created in `dir`, the `what` at `path` or the directory it would be
+/// created in
+fn check_writable(what: &str, path: &Path, dir: &Path) -> Result<()> {
+    let probe = dir.join(format!(".preflight-{}.tmp", std::process::id()));
+    let error = match std::fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = std::fs::remove_file(&probe);
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+    let problem = if error.kind() == ErrorKind::ReadOnlyFilesystem {
+        "is on a read-only filesystem".to_string()
+    } else {
+        format!("isn't writable ({})", error)
+    };
+    if dir == path {
+        bail!("The {} {} {}", what, path.display(), problem);
+    }
+    bail!(
+        "The {} {} can't be created because {} {}",
+        what,
+        path.display(),
+        dir.display(),
+        problem
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(result: Result<()>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn an_existing_directory_passes_for_every_access() {
+        let dir = tempfile::tempdir().unwrap();
+        for access in [Access::Read, Access::Update, Access::Write] {
+            check_dir("output directory", dir.path(), access, false).unwrap();
+        }
+        // The probe doesn't stay behind
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn a_missing_directory_is_only_fine_to_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("games");
+        check_dir("output directory", &missing, Access::Write, false).unwrap();
+        assert!(!missing.exists(), "checking created the directory");
+
+        for access in [Access::Read, Access::Update] {
+            let error = message(check_dir("output directory", &missing, access, false));
+            assert!(error.contains("doesn't exist"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn missing_parents_need_create_parents() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("a").join("b").join("games");
+        let error = message(check_dir("output directory", &nested, Access::Write, false));
+        assert!(error.contains("--create-parents"), "{}", error);
+        // The outermost missing ancestor is the one named
+        let outermost = dir.path().join("a");
+        assert!(
+            error.contains(&format!("because {} doesn't exist", outermost.display())),
+            "{}",
+            error
+        );
+
+        check_dir("output directory", &nested, Access::Write, true).unwrap();
+        assert!(!dir.path().join("a").exists());
+    }
+
+    #[test]
+    fn a_file_in_place_of_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("games");
+        std::fs::write(&file, b"not a directory").unwrap();
+        for access in [Access::Read, Access::Update, Access::Write] {
+            let error = message(check_dir("output directory", &file, access, true));
+            assert!(error.contains("exists but isn't a directory"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn a_file_in_the_way_of_a_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("library");
+        std::fs::write(&file, b"not a directory").unwrap();
+        let nested = file.join("itch").join("games");
+        for create_parents in [false, true] {
+            let error = message(check_dir(
+                "output directory",
+                &nested,
+                Access::Write,
+                create_parents,
+            ));
+            assert!(
+                error.contains(&format!("because {} isn't a directory", file.display())),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_read_only_directory_is_not_writable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let locked = dir.path().join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o555)).unwrap();
+        // Root writes regardless of permissions, so there's nothing to check
+        let writable = std::fs::write(locked.join("probe"), b"").is_ok();
+        if !writable {
+            check_dir("output directory", &locked, Access::Read, false).unwrap();
+            let error = message(check_dir(
+                "output directory",
+                &locked,
+                Access::Update,
+                false,
+            ));
+            assert!(error.contains("isn't writable"), "{}", error);
+            let error = message(check_dir(
+                "output directory",
+                &locked.join("games"),
+                Access::Write,
+                false,
+            ));
+            assert!(error.contains("can't be created because"), "{}", error);
+        }
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
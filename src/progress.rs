@@ -0,0 +1,553 @@
+use crate::{events, ui};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use itch_downloader::error::Result;
+use itch_downloader::mirror::{DownloadOptions, Event, FailedJob, Observer};
+use itch_downloader::skips::{self, Skips};
+use itch_downloader::{DownloadProgress, ItchClient, ItchError, OwnedKey, history, plan};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+/// Print the events that are a line of their own, for commands that only fetch the library
+pub fn show(event: Event<'_>) {
+    match event {
+        Event::Info(message) => ui::status!("{}", message),
+        Event::Warning(message) => ui::println(&ui::multi_progress(), message),
+        Event::Report(message) => ui::report!("{}", message),
+        _ => {}
+    }
+}
+
+/// Draws a download run's bars from its events and asks at the terminal what it wants to know
+pub struct RunView {
+    multi_progress: MultiProgress,
+    client: ItchClient,
+    /// What a confirmation shows, only worked out when the run may ask for one
+    plan_options: Option<plan::PlanOptions>,
+    cancel: CancellationToken,
+    state: Mutex<Bars>,
+}
+
+/// The bars on screen and what updates them
+struct Bars {
+    /// Pages of a library fetched while downloads start
+    library: Option<ProgressBar>,
+    /// Uploads resolved to put the downloads in order
+    order: Option<ProgressBar>,
+    /// Counts the games whose uploads are being looked up, the slow part before any download
+    /// starts, and those resolved but still waiting for a download slot. Only shown once there's
+    /// one to look up, since picked uploads skip the lookup.
+    resolve: ProgressBar,
+    schedule_ticker: Option<JoinHandle<()>>,
+    /// Requests made so far, to see a run heading for 429s before it gets there
+    api: Option<(ProgressBar, JoinHandle<()>)>,
+    /// Games downloading several files get one bar, their files' bars only showing while they're
+    /// worked on, so a game with ten files doesn't push everything else off the screen
+    games: HashMap<u64, ui::GameBars>,
+    jobs: HashMap<usize, JobBar>,
+}
+
+/// The bar of one download and what feeds it
+#[derive(Default)]
+struct JobBar {
+    /// The game's bars and the upload's id, when it's one of several files of a game
+    group: Option<(ui::GameBars, u64)>,
+    bar: Option<ProgressBar>,
+    sink: Option<Box<dyn FnMut(DownloadProgress) + Send>>,
+}
+
+impl RunView {
+    pub fn new(client: &ItchClient, options: &DownloadOptions) -> Self {
+        let resolve = ProgressBar::new(0);
+        resolve.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner} Resolving uploads {pos}/{len}{prefix} {wide_msg}")
+                .unwrap(),
+        );
+        Self {
+            multi_progress: ui::multi_progress(),
+            client: client.clone(),
+            // The confirmation comes from the same plan as `--dry-run`
+            plan_options: options.confirm_above.map(|_| options.plan_options()),
+            cancel: options.abort.clone(),
+            state: Mutex::new(Bars {
+                library: None,
+                order: None,
+                resolve,
+                schedule_ticker: None,
+                api: None,
+                games: HashMap::new(),
+                jobs: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Take every bar of the run off the screen, leaving the line saying how the library went
+    pub fn clear(&self) {
+        let mut bars = self.state.lock().unwrap();
+        bars.resolve.finish_and_clear();
+        for bar in bars.order.iter().chain(&bars.library) {
+            if !bar.is_finished() {
+                bar.finish_and_clear();
+            }
+        }
+        if let Some(ticker) = bars.schedule_ticker.take() {
+            ticker.abort();
+        }
+        if let Some((api_bar, ticker)) = bars.api.take() {
+            ticker.abort();
+            api_bar.finish_and_clear();
+        }
+    }
+
+    /// Start the line counting API requests, below any bars already up
+    fn show_api_usage(&self, bars: &mut Bars) {
+        if bars.api.is_some() {
+            return;
+        }
+        let api_bar = self.multi_progress.add(ProgressBar::new_spinner());
+        api_bar.set_style(ProgressStyle::default_spinner().template("{msg}").unwrap());
+        let ticker = {
+            let api_bar = api_bar.clone();
+            let client = self.client.clone();
+            tokio::spawn(async move {
+                loop {
+                    let usage = client.api_usage();
+                    api_bar.set_message(format!(
+                        "API: {} req ({} in the last minute), {} throttles",
+                        usage.requests, usage.recent_requests, usage.throttled
+                    ));
+                    sleep(Duration::from_secs(1)).await;
+                }
+            })
+        };
+        bars.api = Some((api_bar, ticker));
+    }
+}
+
+impl Observer for RunView {
+    fn event(&self, event: Event<'_>) {
+        let mut bars = self.state.lock().unwrap();
+        let bars = &mut *bars;
+        match event {
+            Event::Info(_) | Event::Warning(_) | Event::Report(_) => show(event),
+            Event::LibraryPage(page) => {
+                self.show_api_usage(bars);
+                let library_bar = bars
+                    .library
+                    .get_or_insert_with(|| self.multi_progress.add(ProgressBar::new_spinner()));
+                ui::event(library_bar, format!("Fetching page {}...", page));
+                library_bar.tick();
+            }
+            Event::LibraryStreamed { total, selected } => {
+                let library_bar = bars
+                    .library
+                    .get_or_insert_with(|| self.multi_progress.add(ProgressBar::new_spinner()));
+                ui::finish(
+                    library_bar,
+                    format!("Fetched {} total packages, {} to download", total, selected),
+                );
+            }
+            Event::Selected(keys) => {
+                ui::status!("Found {} packages to download", keys.len());
+                events::emit(&events::Event::RunStarted {
+                    games: keys
+                        .iter()
+                        .map(|key| events::PlannedGame {
+                            game_id: key.game_id,
+                            title: &key.game.title,
+                        })
+                        .collect(),
+                });
+            }
+            Event::Ordering(total) => {
+                let order_bar = self.multi_progress.add(ProgressBar::new(total as u64));
+                order_bar.set_style(
+                    ProgressStyle::default_spinner()
+                        .template(
+                            "{spinner} Resolving uploads to order by size {pos}/{len} {wide_msg}",
+                        )
+                        .unwrap(),
+                );
+                bars.order = Some(order_bar);
+            }
+            Event::Ordered(key) => {
+                if let Some(order_bar) = &bars.order {
+                    order_bar.set_message(key.game.title.clone());
+                    order_bar.inc(1);
+                }
+            }
+            Event::Spread { step, total } => {
+                if let Some(order_bar) = bars.order.take() {
+                    order_bar.finish_and_clear();
+                }
+                let schedule_bar = self.multi_progress.add(ProgressBar::new_spinner());
+                let total = total as u32;
+                let schedule_start = tokio::time::Instant::now();
+                bars.schedule_ticker = Some(tokio::spawn(async move {
+                    loop {
+                        let elapsed = schedule_start.elapsed();
+                        let started =
+                            (elapsed.as_secs_f64() / step.as_secs_f64()).floor() as u32 + 1;
+                        if started >= total {
+                            schedule_bar.finish_and_clear();
+                            break;
+                        }
+                        let next_start = (step * started).saturating_sub(elapsed);
+                        schedule_bar.set_message(format!(
+                            "{} of {} downloads scheduled, next in {}",
+                            started,
+                            total,
+                            indicatif::HumanDuration(next_start)
+                        ));
+                        schedule_bar.tick();
+                        sleep(Duration::from_secs(1)).await;
+                    }
+                }));
+            }
+            Event::Jobs(jobs) => {
+                if let Some(order_bar) = bars.order.take() {
+                    order_bar.finish_and_clear();
+                }
+                self.show_api_usage(bars);
+                if !ui::group_progress() {
+                    return;
+                }
+                let mut files: HashMap<u64, (&str, usize, u64)> = HashMap::new();
+                for (key, listed) in jobs {
+                    let entry = files.entry(key.game_id).or_insert((&key.game.title, 0, 0));
+                    entry.1 += 1;
+                    entry.2 += listed.as_ref().map_or(0, |upload| upload.size);
+                }
+                for (game_id, (title, count, total)) in files {
+                    if count > 1 {
+                        bars.games.insert(
+                            game_id,
+                            ui::GameBars::new(&self.multi_progress, title, count, total),
+                        );
+                    }
+                }
+            }
+            Event::Queued { job, key, upload } => {
+                self.show_api_usage(bars);
+                if upload.is_none() {
+                    if bars.resolve.length() == Some(0) {
+                        self.multi_progress.insert(0, bars.resolve.clone());
+                    }
+                    bars.resolve.inc_length(1);
+                }
+                // Only games whose files were listed up front are grouped, so the upload is known
+                // here. A retry gets a bar of its own, its game's is done with.
+                let group = bars
+                    .games
+                    .get(&key.game_id)
+                    .filter(|game_bars| !game_bars.is_finished())
+                    .cloned()
+                    .zip(upload.map(|upload| upload.id));
+                bars.jobs.insert(
+                    job,
+                    JobBar {
+                        group,
+                        ..Default::default()
+                    },
+                );
+            }
+            Event::ListingUploads { key, .. } => bars.resolve.set_message(key.game.title.clone()),
+            Event::UploadsListed { .. } => {
+                bars.resolve.inc(1);
+                if Some(bars.resolve.position()) == bars.resolve.length() {
+                    bars.resolve.set_message("");
+                }
+            }
+            Event::WaitingForSlot(waiting) => bars.resolve.set_prefix(match waiting {
+                0 => String::new(),
+                waiting => format!(", {} waiting for a download slot", waiting),
+            }),
+            Event::WouldFetch {
+                key,
+                upload,
+                previous,
+            } => match previous {
+                Some(previous) => ui::report!(
+                    "Would update {} for {} ({} -> {})",
+                    upload.filename,
+                    key.game.title,
+                    itch_downloader::manifest::version_label(
+                        previous.build_id,
+                        previous.updated_at
+                    ),
+                    itch_downloader::manifest::version_label(upload.build_id, upload.updated_at),
+                ),
+                None => ui::report!(
+                    "Would fetch {} ({}) for {}",
+                    upload.filename,
+                    indicatif::HumanBytes(upload.size),
+                    key.game.title
+                ),
+            },
+            Event::DownloadStarted {
+                job,
+                key,
+                upload,
+                seed,
+            } => {
+                let Some(job) = bars.jobs.get_mut(&job) else {
+                    return;
+                };
+                let bar = match &job.group {
+                    Some((game_bars, _)) => game_bars.child(upload.id, upload.size),
+                    None => {
+                        let bar = self.multi_progress.add(ProgressBar::new(upload.size));
+                        bar.set_style(ui::bar_style());
+                        bar.set_prefix(key.game.title.clone());
+                        bar
+                    }
+                };
+                ui::event(
+                    &bar,
+                    match seed {
+                        Some(seed) => {
+                            format!("Seeding {} from {}", upload.filename, seed.display())
+                        }
+                        None => format!("Downloading {}", upload.filename),
+                    },
+                );
+                events::emit(&events::Event::DownloadStarted {
+                    game_id: key.game_id,
+                    upload_id: upload.id,
+                    filename: &upload.filename,
+                    total: upload.size,
+                });
+                let mut sink = events::progress_sink(upload.id, &upload.filename, bar.clone());
+                let game_bars = job.group.as_ref().map(|(game_bars, _)| game_bars.clone());
+                let upload_id = upload.id;
+                job.sink = Some(Box::new(move |progress: DownloadProgress| {
+                    if let (Some(game_bars), DownloadProgress::Bytes(bytes)) =
+                        (&game_bars, &progress)
+                    {
+                        game_bars.record(upload_id, *bytes);
+                    }
+                    sink(progress)
+                }));
+                job.bar = Some(bar);
+            }
+            Event::Progress { job, progress } => {
+                if let Some(sink) = bars.jobs.get_mut(&job).and_then(|job| job.sink.as_mut()) {
+                    sink(progress);
+                }
+            }
+            Event::Note { job, message } => {
+                if let Some(bar) = job_bar(bars, job) {
+                    ui::event(bar, message);
+                }
+            }
+            Event::Succeeded { job, message } => {
+                if let Some(bar) = job_bar(bars, job) {
+                    ui::finish(bar, message);
+                }
+            }
+            Event::Failed { job, message } => {
+                if let Some(bar) = job_bar(bars, job) {
+                    ui::fail(bar, message);
+                }
+            }
+            Event::ExtractQueued { job, filename } => {
+                if let Some(bar) = job_bar(bars, job) {
+                    bar.set_message(format!("Waiting to extract {}", filename));
+                }
+            }
+            Event::ExtractStarted { job, key, filename } => {
+                if let Some(bar) = job_bar(bars, job) {
+                    bar.set_style(ui::bar_style());
+                    bar.reset();
+                    ui::event(bar, format!("Extracting {}", filename));
+                }
+                events::emit(&events::Event::ExtractionStarted {
+                    game_id: key.game_id,
+                    filename,
+                });
+            }
+            Event::ExtractProgress {
+                job,
+                written,
+                total,
+            } => {
+                if let Some(bar) = job_bar(bars, job) {
+                    bar.set_length(total);
+                    bar.set_position(written);
+                }
+            }
+            Event::Extracted {
+                key,
+                filename,
+                path,
+                ..
+            } => events::emit(&events::Event::ExtractionFinished {
+                game_id: key.game_id,
+                filename,
+                path,
+            }),
+            Event::ExtractFailed {
+                key,
+                filename,
+                error,
+                ..
+            } => events::emit(&events::Event::ExtractionFailed {
+                game_id: key.game_id,
+                filename,
+                error,
+            }),
+            Event::Done { job, outcome } => {
+                if let Some((game_bars, upload_id)) =
+                    bars.jobs.remove(&job).and_then(|job| job.group)
+                {
+                    let failed = !matches!(
+                        outcome.status,
+                        history::Status::Completed | history::Status::Skipped
+                    );
+                    game_bars.file_done(upload_id, failed);
+                }
+                events::emit(&events::outcome(outcome));
+            }
+        }
+    }
+
+    /// Ask before a download of many games, refusing outright without a terminal
+    ///
+    /// At a terminal the plan `--dry-run` prints is shown first. Without one, refusing doesn't
+    /// wait for the uploads to be listed, so the estimate only covers games `sync` has
+    /// downloaded before.
+    async fn confirm(&self, keys: &[OwnedKey], known_size: u64) -> Result<()> {
+        use std::io::{BufRead, IsTerminal, Write};
+
+        let summary = if known_size > 0 {
+            format!(
+                "{} games (at least {} going by previous downloads)",
+                keys.len(),
+                indicatif::HumanBytes(known_size)
+            )
+        } else {
+            format!("{} games", keys.len())
+        };
+
+        let Some(plan_options) = self
+            .plan_options
+            .as_ref()
+            .filter(|_| std::io::stdin().is_terminal())
+        else {
+            return Err(ItchError::Invalid {
+                message: format!(
+                    "Refusing to download {} without confirmation, pass --yes to go ahead",
+                    summary
+                ),
+            });
+        };
+        // Show exactly what would be fetched, the way `--dry-run` does
+        ui::status!("Resolving the uploads of {} games...", keys.len());
+        let plan = self
+            .client
+            .plan_downloads(keys, plan_options, &self.cancel)
+            .await;
+        if self.cancel.is_cancelled() {
+            return Err(ItchError::Cancelled);
+        }
+        // A streamed run already has bars up, which would draw over the question
+        let answer = ui::suspend(|| {
+            crate::print_plan_table(&plan);
+            eprint!(
+                "About to download {} games ({}). Continue? [y/N] ",
+                keys.len() - plan.count(plan::PlanAction::Error),
+                indicatif::HumanBytes(plan.total_bytes)
+            );
+            std::io::stderr().flush().ok();
+            let mut answer = String::new();
+            std::io::stdin()
+                .lock()
+                .read_line(&mut answer)
+                .map(|_| answer)
+        })
+        .map_err(|source| ItchError::Io {
+            context: "Failed to read answer".to_string(),
+            source,
+        })?;
+        if !matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes") {
+            return Err(ItchError::Invalid {
+                message: "Download cancelled".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Ask what to do about each failed download, returning the ones to retry
+    ///
+    /// Skipping one adds it to `skips`, opening its page leaves it up for another answer.
+    fn triage(&self, failed: Vec<FailedJob>, skips: &mut Skips) -> Result<Vec<FailedJob>> {
+        use std::io::{BufRead, Write};
+
+        ui::report!("{} downloads failed:", failed.len());
+        let total = failed.len();
+        let mut retry = Vec::new();
+        for (index, job) in failed.into_iter().enumerate() {
+            let (outcome, (key, _)) = &job;
+            ui::report!(
+                "\n[{}/{}] {}{}",
+                index + 1,
+                total,
+                outcome.title,
+                outcome
+                    .filename
+                    .as_deref()
+                    .map(|filename| format!(" ({})", filename))
+                    .unwrap_or_default()
+            );
+            ui::report!("  {}", outcome.error.as_deref().unwrap_or("Unknown error"));
+            loop {
+                eprint!("[r]etry now, [s]kip permanently, [o]pen page, [n]ext, [q]uit? ");
+                std::io::stderr().flush().ok();
+                let mut answer = String::new();
+                let read = std::io::stdin()
+                    .lock()
+                    .read_line(&mut answer)
+                    .map_err(|source| ItchError::Io {
+                        context: "Failed to read answer".to_string(),
+                        source,
+                    })?;
+                // The end of input leaves the rest as they are
+                let answer = if read == 0 {
+                    "q".to_string()
+                } else {
+                    answer.trim().to_ascii_lowercase()
+                };
+                match answer.as_str() {
+                    "r" | "retry" => {
+                        retry.push(job);
+                        break;
+                    }
+                    "s" | "skip" => {
+                        skips.add(skips::SkippedItem::from(outcome));
+                        ui::report!("  Skipped, --reset-skips attempts it again");
+                        break;
+                    }
+                    "o" | "open" => {
+                        ui::report!("  {}", key.game.url);
+                        if let Err(e) = crate::open_in_browser(&key.game.url) {
+                            ui::report!("  Couldn't open a browser: {}", e);
+                        }
+                    }
+                    "n" | "next" | "" => break,
+                    "q" | "quit" => return Ok(retry),
+                    _ => ui::report!("  Answer r, s, o, n or q"),
+                }
+            }
+        }
+        Ok(retry)
+    }
+}
+
+/// The bar of a download that has started
+fn job_bar(bars: &Bars, job: usize) -> Option<&ProgressBar> {
+    bars.jobs.get(&job).and_then(|job| job.bar.as_ref())
+}
@@ -0,0 +1,969 @@
+//! A small evaluator for proxy auto-config scripts
+//!
+//! PAC scripts are JavaScript, but nearly all of them stick to `if`/`else`, `return`, `var`,
+//! string comparisons and the PAC helper functions, which is what's understood here. Loops,
+//! objects and regular expressions aren't, and a script using them fails to load.
+
+use super::ProxyRoute;
+use std::collections::HashMap;
+use std::net::{IpAddr, ToSocketAddrs};
+
+/// How deep functions in a script may call each other before it's considered stuck
+const MAX_CALL_DEPTH: usize = 64;
+
+/// Statements this evaluator doesn't run, refused when the script is loaded
+const UNSUPPORTED_KEYWORDS: [&str; 6] = ["while", "for", "do", "switch", "try", "throw"];
+
+/// Why a PAC script couldn't be loaded or run
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct PacError {
+    message: String,
+}
+
+fn error(message: impl Into<String>) -> PacError {
+    PacError {
+        message: message.into(),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Punct(&'static str),
+}
+
+/// Punctuation the tokenizer knows, longest first so `===` isn't read as `==` and `=`
+const PUNCTUATION: [&str; 23] = [
+    "===", "!==", "==", "!=", "<=", ">=", "&&", "||", "(", ")", "{", "}", ",", ";", "=", "!", "+",
+    "-", "<", ">", "?", ":", ".",
+];
+
+/// Split a script into tokens, each with the line it's on
+fn tokenize(script: &str) -> Result<Vec<(Token, usize)>, PacError> {
+    let chars: Vec<char> = script.chars().collect();
+    let mut tokens = Vec::new();
+    let (mut i, mut line) = (0, 1);
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..chars.len().min(i + 3)].iter().collect();
+        if c == '\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if rest.starts_with("//") {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if rest.starts_with("/*") {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                line += usize::from(chars[i] == '\n');
+                i += 1;
+            }
+            i += 2;
+        } else if c == '"' || c == '\'' {
+            let mut text = String::new();
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' && i + 1 < chars.len() {
+                    i += 1;
+                    text.push(match chars[i] {
+                        'n' => '\n',
+                        't' => '\t',
+                        other => other,
+                    });
+                } else {
+                    text.push(chars[i]);
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err(error(format!("unterminated string on line {}", line)));
+            }
+            i += 1;
+            tokens.push((Token::Str(text), line));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            let number = number
+                .parse()
+                .map_err(|_| error(format!("invalid number {} on line {}", number, line)))?;
+            tokens.push((Token::Num(number), line));
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$')
+            {
+                i += 1;
+            }
+            tokens.push((Token::Ident(chars[start..i].iter().collect()), line));
+        } else if let Some(punct) = PUNCTUATION.iter().find(|punct| rest.starts_with(**punct)) {
+            i += punct.len();
+            tokens.push((Token::Punct(punct), line));
+        } else {
+            return Err(error(format!("unexpected `{}` on line {}", c, line)));
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Literal(Value),
+    Var(String),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Method(Box<Expr>, String, Vec<Expr>),
+    Property(Box<Expr>, String),
+}
+
+#[derive(Clone, Debug)]
+enum Stmt {
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    Return(Option<Expr>),
+    Assign(String, Expr),
+    Expr(Expr),
+    Block(Vec<Stmt>),
+}
+
+#[derive(Clone, Debug)]
+struct Function {
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+/// Reads the tokens of a script into statements and functions
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .or(self.tokens.last())
+            .map_or(1, |(_, line)| *line)
+    }
+
+    fn unexpected(&self) -> PacError {
+        match self.peek() {
+            Some(token) => error(format!("unexpected {:?} on line {}", token, self.line())),
+            None => error("unexpected end of script"),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .map(|(token, _)| token.clone());
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, punct: &'static str) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: &'static str) -> Result<(), PacError> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, PacError> {
+        match self.peek() {
+            Some(Token::Ident(name)) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(name)) if name == keyword)
+    }
+
+    /// The whole script: functions, and statements run once when it's loaded
+    fn script(&mut self) -> Result<(HashMap<String, Function>, Vec<Stmt>), PacError> {
+        let mut functions = HashMap::new();
+        let mut statements = Vec::new();
+        while self.peek().is_some() {
+            if self.is_keyword("function") {
+                self.position += 1;
+                let name = self.ident()?;
+                functions.insert(name, self.function()?);
+            } else {
+                statements.push(self.statement()?);
+            }
+        }
+        Ok((functions, statements))
+    }
+
+    fn function(&mut self) -> Result<Function, PacError> {
+        self.expect("(")?;
+        let mut params = Vec::new();
+        while !self.eat(")") {
+            params.push(self.ident()?);
+            if !self.eat(",") {
+                self.expect(")")?;
+                break;
+            }
+        }
+        let body = self.block()?;
+        Ok(Function { params, body })
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, PacError> {
+        self.expect("{")?;
+        let mut statements = Vec::new();
+        while !self.eat("}") {
+            if self.peek().is_none() {
+                return Err(self.unexpected());
+            }
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, PacError> {
+        if self.eat(";") {
+            return Ok(Stmt::Block(Vec::new()));
+        }
+        if self.peek() == Some(&Token::Punct("{")) {
+            return Ok(Stmt::Block(self.block()?));
+        }
+        if let Some(keyword) = UNSUPPORTED_KEYWORDS
+            .iter()
+            .find(|keyword| self.is_keyword(keyword))
+        {
+            return Err(error(format!(
+                "`{}` on line {} isn't supported",
+                keyword,
+                self.line()
+            )));
+        }
+        if self.is_keyword("if") {
+            self.position += 1;
+            self.expect("(")?;
+            let condition = self.expression()?;
+            self.expect(")")?;
+            let then = Box::new(self.statement()?);
+            let otherwise = if self.is_keyword("else") {
+                self.position += 1;
+                Some(Box::new(self.statement()?))
+            } else {
+                None
+            };
+            return Ok(Stmt::If(condition, then, otherwise));
+        }
+        if self.is_keyword("return") {
+            self.position += 1;
+            let value = match self.peek() {
+                Some(Token::Punct(";" | "}")) | None => None,
+                _ => Some(self.expression()?),
+            };
+            self.eat(";");
+            return Ok(Stmt::Return(value));
+        }
+        if self.is_keyword("var") || self.is_keyword("let") || self.is_keyword("const") {
+            self.position += 1;
+            let mut assignments = Vec::new();
+            loop {
+                let name = self.ident()?;
+                let value = if self.eat("=") {
+                    self.expression()?
+                } else {
+                    Expr::Literal(Value::Undefined)
+                };
+                assignments.push(Stmt::Assign(name, value));
+                if !self.eat(",") {
+                    break;
+                }
+            }
+            self.eat(";");
+            return Ok(Stmt::Block(assignments));
+        }
+        if let (Some(Token::Ident(name)), Some((Token::Punct("="), _))) =
+            (self.peek(), self.tokens.get(self.position + 1))
+        {
+            let name = name.clone();
+            self.position += 2;
+            let value = self.expression()?;
+            self.eat(";");
+            return Ok(Stmt::Assign(name, value));
+        }
+        let expression = self.expression()?;
+        self.eat(";");
+        Ok(Stmt::Expr(expression))
+    }
+
+    fn expression(&mut self) -> Result<Expr, PacError> {
+        let condition = self.or()?;
+        if self.eat("?") {
+            let then = self.expression()?;
+            self.expect(":")?;
+            let otherwise = self.expression()?;
+            return Ok(Expr::Conditional(
+                Box::new(condition),
+                Box::new(then),
+                Box::new(otherwise),
+            ));
+        }
+        Ok(condition)
+    }
+
+    fn or(&mut self) -> Result<Expr, PacError> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, PacError> {
+        let mut left = self.equality()?;
+        while self.eat("&&") {
+            left = Expr::And(Box::new(left), Box::new(self.equality()?));
+        }
+        Ok(left)
+    }
+
+    fn equality(&mut self) -> Result<Expr, PacError> {
+        let mut left = self.comparison()?;
+        while let Some(op) = ["===", "!==", "==", "!="]
+            .into_iter()
+            .find(|op| self.eat(op))
+        {
+            left = Expr::Binary(op, Box::new(left), Box::new(self.comparison()?));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, PacError> {
+        let mut left = self.additive()?;
+        while let Some(op) = ["<=", ">=", "<", ">"].into_iter().find(|op| self.eat(op)) {
+            left = Expr::Binary(op, Box::new(left), Box::new(self.additive()?));
+        }
+        Ok(left)
+    }
+
+    fn additive(&mut self) -> Result<Expr, PacError> {
+        let mut left = self.unary()?;
+        while let Some(op) = ["+", "-"].into_iter().find(|op| self.eat(op)) {
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, PacError> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> Result<Expr, PacError> {
+        let mut expression = self.primary()?;
+        while self.eat(".") {
+            let name = self.ident()?;
+            if self.peek() == Some(&Token::Punct("(")) {
+                let args = self.arguments()?;
+                expression = Expr::Method(Box::new(expression), name, args);
+            } else {
+                expression = Expr::Property(Box::new(expression), name);
+            }
+        }
+        Ok(expression)
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Expr>, PacError> {
+        self.expect("(")?;
+        let mut args = Vec::new();
+        while !self.eat(")") {
+            args.push(self.expression()?);
+            if !self.eat(",") {
+                self.expect(")")?;
+                break;
+            }
+        }
+        Ok(args)
+    }
+
+    fn primary(&mut self) -> Result<Expr, PacError> {
+        match self.next() {
+            Some(Token::Str(text)) => Ok(Expr::Literal(Value::Str(text))),
+            Some(Token::Num(number)) => Ok(Expr::Literal(Value::Num(number))),
+            Some(Token::Punct("(")) => {
+                let expression = self.expression()?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            Some(Token::Ident(name)) => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" | "undefined" => Ok(Expr::Literal(Value::Undefined)),
+                _ if self.peek() == Some(&Token::Punct("(")) => {
+                    Ok(Expr::Call(name, self.arguments()?))
+                }
+                _ => Ok(Expr::Var(name)),
+            },
+            _ => {
+                self.position -= 1;
+                Err(self.unexpected())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+    Undefined,
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Str(text) => !text.is_empty(),
+            Value::Num(number) => *number != 0.0 && !number.is_nan(),
+            Value::Bool(value) => *value,
+            Value::Undefined => false,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Value::Str(text) => text.clone(),
+            Value::Num(number) if number.fract() == 0.0 => format!("{}", *number as i64),
+            Value::Num(number) => number.to_string(),
+            Value::Bool(value) => value.to_string(),
+            Value::Undefined => "undefined".to_string(),
+        }
+    }
+
+    fn number(&self) -> f64 {
+        match self {
+            Value::Num(number) => *number,
+            Value::Bool(value) => f64::from(u8::from(*value)),
+            Value::Str(text) => text.trim().parse().unwrap_or(f64::NAN),
+            Value::Undefined => f64::NAN,
+        }
+    }
+
+    /// `==`, which converts between strings and numbers
+    fn loose_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Undefined, Value::Undefined) => true,
+            (Value::Undefined, _) | (_, Value::Undefined) => false,
+            _ => self.number() == other.number(),
+        }
+    }
+}
+
+/// What running a statement led to
+enum Flow {
+    Next,
+    Return(Value),
+}
+
+/// A loaded PAC script, ready to answer `FindProxyForURL`
+#[derive(Debug)]
+pub struct Pac {
+    functions: HashMap<String, Function>,
+    globals: HashMap<String, Value>,
+}
+
+impl Pac {
+    /// Read a script, which has to define `FindProxyForURL(url, host)`
+    pub fn parse(script: &str) -> Result<Self, PacError> {
+        let mut parser = Parser {
+            tokens: tokenize(script)?,
+            position: 0,
+        };
+        let (functions, statements) = parser.script()?;
+        if !functions.contains_key("FindProxyForURL") {
+            return Err(error(
+                "the script doesn't define FindProxyForURL(url, host)",
+            ));
+        }
+        let mut pac = Self {
+            functions,
+            globals: HashMap::new(),
+        };
+        let mut globals = HashMap::new();
+        for statement in &statements {
+            pac.run(statement, &mut globals, 0)?;
+        }
+        pac.globals = globals;
+        Ok(pac)
+    }
+
+    /// What the script returns for `url` on `host`, e.g. `PROXY proxy:8080; DIRECT`
+    pub fn find_proxy(&self, url: &str, host: &str) -> Result<String, PacError> {
+        let args = vec![Value::Str(url.to_string()), Value::Str(host.to_string())];
+        Ok(self.call("FindProxyForURL", args, 0)?.text())
+    }
+
+    /// The route the script picks for `url`, the first of its choices that can be used
+    ///
+    /// As browsers do, only the scheme and host of HTTPS URLs are shown to the script, and SOCKS
+    /// proxies are passed over since requests can't go through them.
+    pub fn route(&self, url: &reqwest::Url) -> Result<ProxyRoute, PacError> {
+        let host = url.host_str().unwrap_or_default();
+        let shown = match url.scheme() {
+            "https" => format!("https://{}/", url.authority()),
+            _ => url.to_string(),
+        };
+        let answer = self.find_proxy(&shown, host.trim_start_matches('[').trim_end_matches(']'))?;
+        for choice in answer
+            .split(';')
+            .map(str::trim)
+            .filter(|choice| !choice.is_empty())
+        {
+            let (kind, target) = choice
+                .split_once(char::is_whitespace)
+                .unwrap_or((choice, ""));
+            let scheme = match kind.to_ascii_uppercase().as_str() {
+                "DIRECT" => return Ok(ProxyRoute::Direct),
+                "PROXY" | "HTTP" => "http",
+                "HTTPS" => "https",
+                _ => continue,
+            };
+            match format!("{}://{}", scheme, target.trim()).parse() {
+                Ok(proxy) => return Ok(ProxyRoute::Proxy(proxy)),
+                Err(_) => continue,
+            }
+        }
+        Err(error(format!(
+            "FindProxyForURL returned nothing usable: \"{}\"",
+            answer
+        )))
+    }
+
+    fn call(&self, name: &str, args: Vec<Value>, depth: usize) -> Result<Value, PacError> {
+        if depth > MAX_CALL_DEPTH {
+            return Err(error("functions call each other too deeply"));
+        }
+        let Some(function) = self.functions.get(name) else {
+            return builtin(name, &args);
+        };
+        let mut locals = self.globals.clone();
+        for (i, param) in function.params.iter().enumerate() {
+            locals.insert(
+                param.clone(),
+                args.get(i).cloned().unwrap_or(Value::Undefined),
+            );
+        }
+        for statement in &function.body {
+            if let Flow::Return(value) = self.run(statement, &mut locals, depth)? {
+                return Ok(value);
+            }
+        }
+        Ok(Value::Undefined)
+    }
+
+    fn run(
+        &self,
+        statement: &Stmt,
+        vars: &mut HashMap<String, Value>,
+        depth: usize,
+    ) -> Result<Flow, PacError> {
+        match statement {
+            Stmt::If(condition, then, otherwise) => {
+                if self.eval(condition, vars, depth)?.truthy() {
+                    self.run(then, vars, depth)
+                } else if let Some(otherwise) = otherwise {
+                    self.run(otherwise, vars, depth)
+                } else {
+                    Ok(Flow::Next)
+                }
+            }
+            Stmt::Return(value) => Ok(Flow::Return(match value {
+                Some(value) => self.eval(value, vars, depth)?,
+                None => Value::Undefined,
+            })),
+            Stmt::Assign(name, value) => {
+                let value = self.eval(value, vars, depth)?;
+                vars.insert(name.clone(), value);
+                Ok(Flow::Next)
+            }
+            Stmt::Expr(expression) => {
+                self.eval(expression, vars, depth)?;
+                Ok(Flow::Next)
+            }
+            Stmt::Block(statements) => {
+                for statement in statements {
+                    if let Flow::Return(value) = self.run(statement, vars, depth)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+                Ok(Flow::Next)
+            }
+        }
+    }
+
+    fn eval(
+        &self,
+        expression: &Expr,
+        vars: &HashMap<String, Value>,
+        depth: usize,
+    ) -> Result<Value, PacError> {
+        Ok(match expression {
+            Expr::Literal(value) => value.clone(),
+            Expr::Var(name) => match vars.get(name) {
+                Some(value) => value.clone(),
+                None => return Err(error(format!("{} is not defined", name))),
+            },
+            Expr::Not(inner) => Value::Bool(!self.eval(inner, vars, depth)?.truthy()),
+            Expr::Negate(inner) => Value::Num(-self.eval(inner, vars, depth)?.number()),
+            Expr::And(left, right) => {
+                let left = self.eval(left, vars, depth)?;
+                if left.truthy() {
+                    self.eval(right, vars, depth)?
+                } else {
+                    left
+                }
+            }
+            Expr::Or(left, right) => {
+                let left = self.eval(left, vars, depth)?;
+                if left.truthy() {
+                    left
+                } else {
+                    self.eval(right, vars, depth)?
+                }
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                if self.eval(condition, vars, depth)?.truthy() {
+                    self.eval(then, vars, depth)?
+                } else {
+                    self.eval(otherwise, vars, depth)?
+                }
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left, vars, depth)?;
+                let right = self.eval(right, vars, depth)?;
+                binary(op, &left, &right)
+            }
+            Expr::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg, vars, depth))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.call(name, args, depth + 1)?
+            }
+            Expr::Method(target, name, args) => {
+                let target = self.eval(target, vars, depth)?.text();
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg, vars, depth))
+                    .collect::<Result<Vec<_>, _>>()?;
+                string_method(&target, name, &args)?
+            }
+            Expr::Property(target, name) => match name.as_str() {
+                "length" => {
+                    Value::Num(self.eval(target, vars, depth)?.text().chars().count() as f64)
+                }
+                _ => return Err(error(format!("unsupported property .{}", name))),
+            },
+        })
+    }
+}
+
+fn binary(op: &str, left: &Value, right: &Value) -> Value {
+    match op {
+        "===" => Value::Bool(left == right),
+        "!==" => Value::Bool(left != right),
+        "==" => Value::Bool(left.loose_eq(right)),
+        "!=" => Value::Bool(!left.loose_eq(right)),
+        "+" if matches!(left, Value::Str(_)) || matches!(right, Value::Str(_)) => {
+            Value::Str(left.text() + &right.text())
+        }
+        "+" => Value::Num(left.number() + right.number()),
+        "-" => Value::Num(left.number() - right.number()),
+        _ => {
+            let ordering = match (left, right) {
+                (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+                _ => left.number().partial_cmp(&right.number()),
+            };
+            Value::Bool(ordering.is_some_and(|ordering| match op {
+                "<" => ordering.is_lt(),
+                ">" => ordering.is_gt(),
+                "<=" => ordering.is_le(),
+                _ => ordering.is_ge(),
+            }))
+        }
+    }
+}
+
+fn string_method(target: &str, name: &str, args: &[Value]) -> Result<Value, PacError> {
+    let arg = |i: usize| args.get(i).map(Value::text).unwrap_or_default();
+    let chars: Vec<char> = target.chars().collect();
+    let index = |i: usize, default: usize| {
+        args.get(i)
+            .map_or(default, |value| value.number().max(0.0) as usize)
+            .min(chars.len())
+    };
+    Ok(match name {
+        "toLowerCase" => Value::Str(target.to_lowercase()),
+        "toUpperCase" => Value::Str(target.to_uppercase()),
+        "trim" => Value::Str(target.trim().to_string()),
+        "indexOf" => Value::Num(
+            target
+                .find(&arg(0))
+                .map_or(-1.0, |at| target[..at].chars().count() as f64),
+        ),
+        "startsWith" => Value::Bool(target.starts_with(&arg(0))),
+        "endsWith" => Value::Bool(target.ends_with(&arg(0))),
+        "includes" => Value::Bool(target.contains(&arg(0))),
+        "substring" => {
+            let (start, end) = (index(0, 0), index(1, chars.len()));
+            Value::Str(chars[start.min(end)..start.max(end)].iter().collect())
+        }
+        _ => return Err(error(format!("unsupported method .{}()", name))),
+    })
+}
+
+/// The first address `host` resolves to, IPv4 preferred as PAC scripts expect
+fn resolve(host: &str) -> Option<IpAddr> {
+    if let Ok(address) = host.parse::<IpAddr>() {
+        return Some(address);
+    }
+    let addresses: Vec<IpAddr> = (host, 0)
+        .to_socket_addrs()
+        .ok()?
+        .map(|address| address.ip())
+        .collect();
+    addresses
+        .iter()
+        .find(|address| address.is_ipv4())
+        .or(addresses.first())
+        .copied()
+}
+
+/// This machine's address on the network its default route is on
+fn my_ip_address() -> IpAddr {
+    // Connecting a UDP socket sends nothing, it only picks the interface
+    std::net::UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect("192.0.2.1:80")?;
+            socket.local_addr()
+        })
+        .map_or(IpAddr::from([127, 0, 0, 1]), |address| address.ip())
+}
+
+/// Whether `text` matches the shell pattern `pattern`, with `*` and `?` wildcards
+fn sh_exp_match(text: &[char], pattern: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| sh_exp_match(&text[skip..], rest)),
+        Some(('?', rest)) => !text.is_empty() && sh_exp_match(&text[1..], rest),
+        Some((c, rest)) => text.first() == Some(c) && sh_exp_match(&text[1..], rest),
+    }
+}
+
+/// The PAC helper functions scripts can call
+fn builtin(name: &str, args: &[Value]) -> Result<Value, PacError> {
+    let arg = |i: usize| args.get(i).map(Value::text).unwrap_or_default();
+    Ok(match name {
+        "isPlainHostName" => Value::Bool(!arg(0).contains('.')),
+        "dnsDomainIs" => Value::Bool(arg(0).to_lowercase().ends_with(&arg(1).to_lowercase())),
+        "localHostOrDomainIs" => {
+            let (host, full) = (arg(0).to_lowercase(), arg(1).to_lowercase());
+            Value::Bool(host == full || (!host.contains('.') && full.starts_with(&(host + "."))))
+        }
+        "dnsDomainLevels" => Value::Num(arg(0).matches('.').count() as f64),
+        "shExpMatch" => {
+            let text: Vec<char> = arg(0).chars().collect();
+            let pattern: Vec<char> = arg(1).chars().collect();
+            Value::Bool(sh_exp_match(&text, &pattern))
+        }
+        "isResolvable" => Value::Bool(resolve(&arg(0)).is_some()),
+        "dnsResolve" => {
+            resolve(&arg(0)).map_or(Value::Undefined, |address| Value::Str(address.to_string()))
+        }
+        "myIpAddress" => Value::Str(my_ip_address().to_string()),
+        "isInNet" => {
+            let parse = |text: String| text.parse::<std::net::Ipv4Addr>().ok().map(u32::from);
+            let address = match resolve(&arg(0)) {
+                Some(IpAddr::V4(address)) => Some(u32::from(address)),
+                _ => None,
+            };
+            Value::Bool(match (address, parse(arg(1)), parse(arg(2))) {
+                (Some(address), Some(pattern), Some(mask)) => address & mask == pattern & mask,
+                _ => false,
+            })
+        }
+        "alert" => {
+            tracing::debug!("PAC script: {}", arg(0));
+            Value::Undefined
+        }
+        _ => return Err(error(format!("{}() isn't supported", name))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(script: &str, url: &str, host: &str) -> String {
+        Pac::parse(script).unwrap().find_proxy(url, host).unwrap()
+    }
+
+    fn route(script: &str, url: &str) -> ProxyRoute {
+        Pac::parse(script)
+            .unwrap()
+            .route(&url.parse().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn dns_domain_is_matches_the_end_of_the_host() {
+        let script = r#"function FindProxyForURL(url, host) {
+            if (dnsDomainIs(host, ".itch.io")) { return "PROXY a:1"; }
+            else { return "DIRECT"; }
+        }"#;
+        assert_eq!(
+            find(script, "https://api.itch.io/", "api.itch.io"),
+            "PROXY a:1"
+        );
+        assert_eq!(
+            find(script, "https://API.ITCH.IO/", "API.ITCH.IO"),
+            "PROXY a:1"
+        );
+        assert_eq!(find(script, "https://itch.zone/", "itch.zone"), "DIRECT");
+    }
+
+    #[test]
+    fn sh_exp_match_supports_both_wildcards() {
+        let script = r#"function FindProxyForURL(url, host) {
+            return shExpMatch(url, "http://*.example.com/?ame*") ? "PROXY a:1" : "DIRECT";
+        }"#;
+        assert_eq!(
+            find(script, "http://www.example.com/game.zip", ""),
+            "PROXY a:1"
+        );
+        assert_eq!(find(script, "http://www.example.com/name", ""), "PROXY a:1");
+        assert_eq!(find(script, "http://example.com/game", ""), "DIRECT");
+        assert_eq!(find(script, "http://www.example.com/gamer", "").len(), 9);
+    }
+
+    #[test]
+    fn is_in_net_masks_the_address() {
+        let script = r#"function FindProxyForURL(url, host) {
+            if (isInNet(host, "10.0.0.0", "255.0.0.0")) return "DIRECT";
+            if (isInNet(dnsResolve(host), "192.168.1.0", "255.255.255.0")) return "PROXY lan:1";
+            return "PROXY wan:1";
+        }"#;
+        assert_eq!(find(script, "", "10.1.2.3"), "DIRECT");
+        assert_eq!(find(script, "", "192.168.1.77"), "PROXY lan:1");
+        assert_eq!(find(script, "", "192.168.2.77"), "PROXY wan:1");
+    }
+
+    #[test]
+    fn the_first_usable_choice_is_taken() {
+        let script = |answer: &str| {
+            format!(
+                "function FindProxyForURL(url, host) {{ return \"{}\"; }}",
+                answer
+            )
+        };
+        let url = "https://api.itch.io/profile";
+        assert_eq!(
+            route(&script("PROXY a:1; PROXY b:2; DIRECT"), url),
+            ProxyRoute::Proxy("http://a:1".parse().unwrap())
+        );
+        assert_eq!(
+            route(&script("SOCKS s:1; HTTPS b:2; DIRECT"), url),
+            ProxyRoute::Proxy("https://b:2".parse().unwrap())
+        );
+        assert_eq!(route(&script("SOCKS5 s:1;DIRECT"), url), ProxyRoute::Direct);
+        assert!(
+            Pac::parse(&script("SOCKS s:1"))
+                .unwrap()
+                .route(&url.parse().unwrap())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn https_urls_are_cut_to_the_host() {
+        let script = r#"function FindProxyForURL(url, host) {
+            return url == "https://api.itch.io/" ? "DIRECT" : "PROXY a:1";
+        }"#;
+        assert_eq!(
+            route(script, "https://api.itch.io/profile?secret=1"),
+            ProxyRoute::Direct
+        );
+    }
+
+    #[test]
+    fn variables_functions_and_string_methods() {
+        let script = r#"
+            var proxy = "PROXY " + "corp:80";
+            function internal(host) { return host.toLowerCase().indexOf("corp") >= 0; }
+            function FindProxyForURL(url, host) {
+                if (isPlainHostName(host) || internal(host)) return "DIRECT";
+                if (dnsDomainLevels(host) > 2) return proxy;
+                return host.substring(0, 3) == "api" ? proxy + "; DIRECT" : "DIRECT";
+            }"#;
+        assert_eq!(find(script, "", "intranet"), "DIRECT");
+        assert_eq!(find(script, "", "wiki.CORP.example"), "DIRECT");
+        assert_eq!(find(script, "", "a.b.c.d"), "PROXY corp:80");
+        assert_eq!(find(script, "", "api.itch.io"), "PROXY corp:80; DIRECT");
+        assert_eq!(find(script, "", "itch.io"), "DIRECT");
+    }
+
+    #[test]
+    fn local_host_or_domain_is() {
+        let script = r#"function FindProxyForURL(url, host) {
+            return localHostOrDomainIs(host, "www.example.com") ? "DIRECT" : "PROXY a:1";
+        }"#;
+        assert_eq!(find(script, "", "www"), "DIRECT");
+        assert_eq!(find(script, "", "www.example.com"), "DIRECT");
+        assert_eq!(find(script, "", "www.other.com"), "PROXY a:1");
+    }
+
+    #[test]
+    fn scripts_that_cant_run_are_refused() {
+        assert!(Pac::parse("function Other() { return 1; }").is_err());
+        assert!(Pac::parse("function FindProxyForURL(url, host) { while (1) {} }").is_err());
+        let recursive = Pac::parse(
+            "function f(x) { return f(x); } function FindProxyForURL(url, host) { return f(1); }",
+        )
+        .unwrap();
+        assert!(recursive.find_proxy("", "").is_err());
+    }
+}
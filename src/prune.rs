@@ -0,0 +1,59 @@
+use crate::error::{Context, Result};
+use crate::itchignore::IgnoreRules;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// What `prune` found at the top level of the output directory
+#[derive(Debug, Default)]
+pub struct PrunePlan {
+    /// Entries we created for games that are no longer selected or owned
+    pub orphans: Vec<PathBuf>,
+    /// Entries we can't tie to any game, which are never deleted automatically
+    pub unattributed: Vec<PathBuf>,
+}
+
+/// Sort the top level entries of `output_path` into ones no current run would produce
+///
+/// `expected` holds the names a run with the current filters would create and `attributable`
+/// every name we know belongs to some game, selected or not. Entries `ignore` matches are left
+/// out entirely.
+pub fn plan(
+    output_path: &Path,
+    expected: &HashSet<String>,
+    attributable: &HashSet<String>,
+    ignore: &IgnoreRules,
+) -> Result<PrunePlan> {
+    let mut plan = PrunePlan::default();
+    let entries = std::fs::read_dir(output_path).context("Failed to read output directory")?;
+
+    for entry in entries {
+        let entry = entry.context("Failed to list output directory")?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        // Our own bookkeeping and in-flight files are never candidates
+        if name == crate::layout::STATE_DIR || crate::staging::is_temp_name(&name) {
+            continue;
+        }
+        let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+        if ignore.is_ignored(&entry.path(), is_dir) {
+            continue;
+        }
+        // A checksum sidecar goes with the file it's for
+        let owner = crate::checksums::sidecar_target(&name)
+            .filter(|target| expected.contains(*target) || attributable.contains(*target))
+            .unwrap_or(&name);
+        if expected.contains(owner) {
+            continue;
+        }
+
+        if attributable.contains(owner) {
+            plan.orphans.push(entry.path());
+        } else {
+            plan.unattributed.push(entry.path());
+        }
+    }
+
+    plan.orphans.sort();
+    plan.unattributed.sort();
+    Ok(plan)
+}
@@ -0,0 +1,99 @@
+//! `RECEIPT.txt`, a record kept next to a game's files of who downloaded them, with which key and
+//! when, for provenance
+
+use crate::error::{Context, Result};
+use crate::models::{OwnedKey, User};
+use crate::timestamp::{self, Timestamp};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// Name of the receipt written into each game's directory
+pub const RECEIPT_FILE: &str = "RECEIPT.txt";
+
+/// Where a game's files came from
+///
+/// Only the download key's id is recorded, never the API key the files were fetched with.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Receipt {
+    /// Username of the account the files were downloaded with
+    pub account: String,
+    pub account_id: u64,
+    /// The download key that granted access, `None` for free and own games
+    pub download_key_id: Option<u64>,
+    pub purchase_id: Option<u64>,
+    /// When the key was created, i.e. when the game was bought or claimed
+    #[serde(default, deserialize_with = "crate::json::timestamp")]
+    pub purchased_at: Option<Timestamp>,
+    pub upload_id: u64,
+    pub filename: String,
+    /// Hex encoded SHA-256 of the file as downloaded
+    pub sha256: String,
+    /// The tool and version that made the backup, e.g. `itch-downloader 0.1.4`
+    pub tool: String,
+    pub downloaded_at: Timestamp,
+}
+
+impl Receipt {
+    /// A receipt for `filename`, downloaded just now by `account` with `key`
+    pub fn new(
+        account: &User,
+        key: &OwnedKey,
+        upload_id: u64,
+        filename: &str,
+        sha256: &str,
+    ) -> Self {
+        Self {
+            account: account.username.clone(),
+            account_id: account.id,
+            download_key_id: key.download_key_id(),
+            purchase_id: key.purchase_id,
+            purchased_at: key.created_at,
+            upload_id,
+            filename: filename.to_string(),
+            sha256: sha256.to_string(),
+            tool: concat!("itch-downloader ", env!("CARGO_PKG_VERSION")).to_string(),
+            downloaded_at: timestamp::now(),
+        }
+    }
+
+    /// The receipt as people read it, `title` naming the game
+    pub fn to_text(&self, title: &str) -> String {
+        let optional = |value: Option<u64>| value.map_or("-".to_string(), |id| id.to_string());
+        let mut text = String::new();
+        let _ = writeln!(text, "Receipt for {}", title);
+        let _ = writeln!(text);
+        let _ = writeln!(
+            text,
+            "Account:          {} ({})",
+            self.account, self.account_id
+        );
+        let _ = writeln!(text, "Download key id:  {}", optional(self.download_key_id));
+        let _ = writeln!(text, "Purchase id:      {}", optional(self.purchase_id));
+        let _ = writeln!(
+            text,
+            "Purchased:        {}",
+            self.purchased_at
+                .map_or("-".to_string(), timestamp::to_rfc3339)
+        );
+        let _ = writeln!(
+            text,
+            "Upload:           {} ({})",
+            self.filename, self.upload_id
+        );
+        let _ = writeln!(text, "SHA-256:          {}", self.sha256);
+        let _ = writeln!(
+            text,
+            "Downloaded:       {}",
+            timestamp::to_rfc3339(self.downloaded_at)
+        );
+        let _ = writeln!(text, "Downloaded with:  {}", self.tool);
+        text
+    }
+
+    /// Write `RECEIPT.txt` into `dir`, replacing the one from an earlier download
+    pub fn write(&self, dir: &Path, title: &str) -> Result<()> {
+        std::fs::write(dir.join(RECEIPT_FILE), self.to_text(title))
+            .with_context(|| format!("Failed to write the receipt for {}", title))
+    }
+}
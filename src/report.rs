@@ -0,0 +1,86 @@
+use crate::ItchClient;
+use crate::error::{Context, ItchError, Result};
+use crate::history::{Outcome, Status};
+use crate::layout::Layout;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Name of the report inside the state directory when no path is given
+const REPORT_FILE: &str = "report.json";
+
+/// Machine-readable summary of a download run
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub completed: Vec<&'a Outcome>,
+    pub skipped: Vec<&'a Outcome>,
+    pub failed: Vec<&'a Outcome>,
+    /// Games deleted from itch.io, listed apart from failures so what's been lost can be tracked
+    pub gone: Vec<&'a Outcome>,
+    /// Games whose download key itch.io refused as used up, revoked or expired
+    pub key_limit: Vec<&'a Outcome>,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(started_at: u64, outcomes: &'a [Outcome]) -> Self {
+        let with_status = |status| {
+            outcomes
+                .iter()
+                .filter(move |outcome| outcome.status == status)
+        };
+        Self {
+            started_at,
+            finished_at: crate::manifest::now(),
+            completed: with_status(Status::Completed).collect(),
+            skipped: with_status(Status::Skipped).collect(),
+            failed: with_status(Status::Failed).collect(),
+            gone: with_status(Status::Gone).collect(),
+            key_limit: with_status(Status::KeyLimit).collect(),
+        }
+    }
+
+    /// Write the report to `path`, or to the state directory of `output_path` when unset
+    pub fn write(
+        &self,
+        path: Option<&PathBuf>,
+        output_path: &Path,
+        layout: &Layout,
+    ) -> Result<PathBuf> {
+        let path = match path {
+            Some(path) => path.clone(),
+            None => {
+                let state_dir = layout.state_dir(output_path);
+                std::fs::create_dir_all(&state_dir).context("Failed to create state directory")?;
+                state_dir.join(REPORT_FILE)
+            }
+        };
+        let contents = serde_json::to_string_pretty(self).context("Failed to serialize report")?;
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Failed to write report to {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// POST the report as JSON to `url`, the API key scrubbed from any error message it copies
+    pub async fn post(&self, client: &ItchClient, url: &str) -> Result<()> {
+        let payload = serde_json::to_string(self).context("Failed to serialize report")?;
+        client
+            .http()
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(client.redact(&payload))
+            .send()
+            .await
+            .map_err(|source| ItchError::Request {
+                context: "Failed to reach webhook",
+                source,
+            })?
+            .error_for_status()
+            .map_err(|source| ItchError::Request {
+                context: "Webhook rejected the notification",
+                source,
+            })?;
+        Ok(())
+    }
+}
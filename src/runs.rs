@@ -0,0 +1,109 @@
+use crate::error::{Context, Result};
+use crate::history::Outcome;
+use crate::timestamp::{self, Timestamp};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Directory under the output directory that `dl --run-dir` runs go in
+pub const RUNS_DIR: &str = "runs";
+
+/// Name of the manifest describing a run, inside its directory
+pub const RUN_MANIFEST: &str = "run.json";
+
+/// The directory a run started at `started_at` downloads into, `<output>/runs/<timestamp>`
+///
+/// The timestamp has no colons so the name works on Windows too.
+pub fn run_dir(output_path: &Path, started_at: Timestamp) -> PathBuf {
+    output_path
+        .join(RUNS_DIR)
+        .join(timestamp::to_rfc3339(started_at).replace(':', "-"))
+}
+
+/// What `run.json` records about a `dl --run-dir` invocation
+#[derive(Debug, Serialize)]
+pub struct RunManifest<'a> {
+    /// `itch-downloader` and its version
+    pub tool: String,
+    /// The command line, with secrets like the API key replaced
+    pub args: Vec<String>,
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+    pub finished_at: u64,
+    /// What happened to every game the run looked at
+    pub outcomes: &'a [Outcome],
+}
+
+impl<'a> RunManifest<'a> {
+    pub fn new(args: Vec<String>, started_at: u64, outcomes: &'a [Outcome]) -> Self {
+        Self {
+            tool: concat!("itch-downloader ", env!("CARGO_PKG_VERSION")).to_string(),
+            args,
+            started_at,
+            finished_at: crate::manifest::now(),
+            outcomes,
+        }
+    }
+
+    /// Write the manifest into the run's directory
+    pub fn write(&self, run_dir: &Path) -> Result<PathBuf> {
+        std::fs::create_dir_all(run_dir).context("Failed to create run directory")?;
+        let path = run_dir.join(RUN_MANIFEST);
+        let contents =
+            serde_json::to_string_pretty(self).context("Failed to serialize run manifest")?;
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+/// What promoting a run moved into the main layout
+#[derive(Debug, Default)]
+pub struct Promoted {
+    /// Files and directories that weren't in the main layout yet
+    pub added: Vec<PathBuf>,
+    /// Files that replaced one already there
+    pub replaced: Vec<PathBuf>,
+}
+
+/// Merge a run directory into `output_path` and remove it
+///
+/// Directories are merged entry by entry, a file in the run replaces the main layout's file at
+/// the same path since it's the newer download. Moves fall back to copying across filesystems.
+/// `run.json` isn't promoted, it's removed with the run directory.
+pub fn promote(run_dir: &Path, output_path: &Path) -> Result<Promoted> {
+    let mut promoted = Promoted::default();
+    merge_into(run_dir, output_path, Path::new(""), &mut promoted)?;
+    std::fs::remove_dir_all(run_dir)
+        .with_context(|| format!("Failed to remove {}", run_dir.display()))?;
+    Ok(promoted)
+}
+
+/// Move everything in `source` into `dest`, `relative` being where both are under their roots
+fn merge_into(source: &Path, dest: &Path, relative: &Path, promoted: &mut Promoted) -> Result<()> {
+    std::fs::create_dir_all(dest)
+        .with_context(|| format!("Failed to create {}", dest.display()))?;
+    let entries = std::fs::read_dir(source)
+        .with_context(|| format!("Failed to read {}", source.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to read {}", source.display()))?;
+        let name = entry.file_name();
+        if relative.as_os_str().is_empty() && name == RUN_MANIFEST {
+            continue;
+        }
+        let from = entry.path();
+        let to = dest.join(&name);
+        let relative = relative.join(&name);
+        if from.is_dir() && to.is_dir() {
+            merge_into(&from, &to, &relative, promoted)?;
+        } else if to.exists() || to.is_symlink() {
+            crate::staging::remove_path(&to)
+                .with_context(|| format!("Failed to replace {}", to.display()))?;
+            crate::staging::move_path(&from, &to)?;
+            promoted.replaced.push(relative);
+        } else {
+            crate::staging::move_path(&from, &to)?;
+            promoted.added.push(relative);
+        }
+    }
+    Ok(())
+}
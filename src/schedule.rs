@@ -0,0 +1,231 @@
+//! When and in what order `dl` starts its downloads: `--order`, `--spread-over` and
+//! `--max-concurrent-per-author`
+
+use crate::{ItchClient, OwnedKey, Upload};
+use futures::stream::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+/// The order `dl --order` starts downloads in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DownloadOrder {
+    /// Smallest upload first
+    SizeAsc,
+    /// Largest upload first
+    SizeDesc,
+    /// Alphabetically by title
+    Title,
+    /// Oldest purchase first
+    Purchased,
+    /// Shuffled, differently every run
+    Random,
+}
+
+/// Put the jobs in the order `order` asks for
+///
+/// Sizes are only known once a game's uploads are listed, so ordering by size looks up every game's
+/// uploads, `meta_concurrent` at a time, before any download starts, `pick` choosing the one that
+/// counts and `on_resolved` called as each game's is known. Games whose lookup fails or finds
+/// nothing go last, for their own download to look again and report why.
+pub async fn order_jobs(
+    client: &ItchClient,
+    mut jobs: Vec<(OwnedKey, Option<Upload>)>,
+    order: DownloadOrder,
+    pick: impl Fn(&OwnedKey, &[Upload]) -> Option<Upload>,
+    meta_concurrent: usize,
+    cancel: &CancellationToken,
+    on_resolved: impl Fn(&OwnedKey),
+) -> Vec<(OwnedKey, Option<Upload>)> {
+    match order {
+        DownloadOrder::Title => {
+            jobs.sort_by_cached_key(|(key, _)| key.game.title.to_lowercase());
+        }
+        DownloadOrder::Purchased => {
+            jobs.sort_by_key(|(key, _)| (key.created_at.is_none(), key.created_at));
+        }
+        DownloadOrder::Random => shuffle(&mut jobs),
+        DownloadOrder::SizeAsc | DownloadOrder::SizeDesc => {
+            let (pick, on_resolved) = (&pick, &on_resolved);
+            jobs = futures::stream::iter(jobs)
+                .map(|(key, listed)| async move {
+                    let upload = match listed {
+                        Some(listed) => Some(listed),
+                        None => client
+                            .get_key_uploads(&key, cancel)
+                            .await
+                            .ok()
+                            .and_then(|uploads| pick(&key, &uploads)),
+                    };
+                    on_resolved(&key);
+                    (key, upload)
+                })
+                .buffered(meta_concurrent.max(1))
+                .collect()
+                .await;
+
+            sort_by_size(&mut jobs, order == DownloadOrder::SizeDesc);
+        }
+    }
+    jobs
+}
+
+/// Sort jobs by their upload's size, those without one last
+fn sort_by_size(jobs: &mut [(OwnedKey, Option<Upload>)], descending: bool) {
+    jobs.sort_by_key(|(_, upload)| match upload {
+        Some(upload) if descending => (false, u64::MAX - upload.size),
+        Some(upload) => (false, upload.size),
+        None => (true, 0),
+    });
+}
+
+/// Shuffle `items` in place, seeded from the clock so each run gets a different order
+pub fn shuffle<T>(items: &mut [T]) {
+    let mut state = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+        | 1;
+    // Fisher-Yates, with xorshift standing in for a random number generator
+    for i in (1..items.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        items.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+/// How far apart `--spread-over` starts `jobs` downloads within `window`, the nth waiting until n
+/// steps in
+///
+/// `None` when there's nothing to spread because every game was filtered out.
+pub fn spread_step(window: Duration, jobs: usize) -> Option<Duration> {
+    window.checked_div(u32::try_from(jobs).ok()?)
+}
+
+/// Download slots of each author, for `--max-concurrent-per-author`
+pub struct AuthorSlots {
+    /// Downloads allowed per author at once, unlimited when `None`
+    per_author: Option<usize>,
+    slots: Mutex<HashMap<u64, Arc<Semaphore>>>,
+}
+
+impl AuthorSlots {
+    pub fn new(per_author: Option<usize>) -> Self {
+        Self {
+            per_author,
+            slots: Default::default(),
+        }
+    }
+
+    /// Wait for one of the author's slots, held until the permit is dropped
+    pub async fn acquire(&self, author_id: u64) -> Option<OwnedSemaphorePermit> {
+        let per_author = self.per_author?;
+        let slots = self
+            .slots
+            .lock()
+            .unwrap()
+            .entry(author_id)
+            .or_insert_with(|| Arc::new(Semaphore::new(per_author)))
+            .clone();
+        Some(slots.acquire_owned().await.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn job(game_id: u64, title: &str, size: Option<u64>) -> (OwnedKey, Option<Upload>) {
+        let key = serde_json::from_value(json!({
+            "id": game_id,
+            "game_id": game_id,
+            "game": { "id": game_id, "title": title, "user": { "id": 2, "username": "someone" } }
+        }))
+        .unwrap();
+        let upload = size.map(|size| {
+            serde_json::from_value(
+                json!({ "id": game_id, "game_id": game_id, "filename": title, "size": size }),
+            )
+            .unwrap()
+        });
+        (key, upload)
+    }
+
+    fn ids(jobs: &[(OwnedKey, Option<Upload>)]) -> Vec<u64> {
+        jobs.iter().map(|(key, _)| key.game_id).collect()
+    }
+
+    #[test]
+    fn sizes_sort_either_way_with_unknown_ones_last() {
+        let mut jobs = vec![
+            job(1, "a", Some(300)),
+            job(2, "b", None),
+            job(3, "c", Some(100)),
+            job(4, "d", Some(200)),
+        ];
+        sort_by_size(&mut jobs, false);
+        assert_eq!(ids(&jobs), vec![3, 4, 1, 2]);
+        sort_by_size(&mut jobs, true);
+        assert_eq!(ids(&jobs), vec![1, 4, 3, 2]);
+    }
+
+    #[tokio::test]
+    async fn orders_by_title_without_looking_anything_up() {
+        let client = ItchClient::new("unused".to_string());
+        let jobs = vec![
+            job(1, "zeta", None),
+            job(2, "Alpha", None),
+            job(3, "beta", None),
+        ];
+        let resolved = Mutex::new(0);
+        let jobs = order_jobs(
+            &client,
+            jobs,
+            DownloadOrder::Title,
+            |_, _| None,
+            4,
+            &CancellationToken::new(),
+            |_| *resolved.lock().unwrap() += 1,
+        )
+        .await;
+        assert_eq!(ids(&jobs), vec![2, 3, 1]);
+        assert_eq!(*resolved.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn shuffling_keeps_every_item() {
+        let mut items: Vec<u32> = (0..100).collect();
+        shuffle(&mut items);
+        let mut sorted = items.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..100).collect::<Vec<_>>());
+        shuffle(&mut Vec::<u32>::new());
+    }
+
+    #[test]
+    fn spreads_the_window_over_the_jobs() {
+        let hour = Duration::from_secs(3_600);
+        assert_eq!(spread_step(hour, 4), Some(Duration::from_secs(900)));
+        assert_eq!(spread_step(hour, 1), Some(hour));
+        assert_eq!(spread_step(hour, 0), None);
+    }
+
+    #[tokio::test]
+    async fn author_slots_limit_each_author_separately() {
+        let slots = AuthorSlots::new(Some(1));
+        let first = slots.acquire(1).await;
+        assert!(first.is_some());
+        // Another author isn't held up
+        assert!(slots.acquire(2).await.is_some());
+        let waiting = tokio::time::timeout(Duration::from_millis(50), slots.acquire(1)).await;
+        assert!(waiting.is_err(), "a second download for the author started");
+        drop(first);
+        assert!(slots.acquire(1).await.is_some());
+
+        assert!(AuthorSlots::new(None).acquire(1).await.is_none());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::check::{self, CheckStatus};
+use crate::error::{Context, Result};
+use crate::export::GameMetadata;
+use crate::layout::{Layout, STATE_DIR};
+use crate::manifest::Manifest;
+use crate::{DownloadedFile, SaveOptions, staging};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// How deep into a seed directory files are looked for, enough for bundle and game directories
+const MAX_SEED_DEPTH: usize = 3;
+
+/// A file in a seed directory that should hold an upload's bytes
+#[derive(Clone, Debug)]
+pub struct SeedMatch {
+    pub path: PathBuf,
+    /// SHA-256 the seed's manifest recorded for it, saving hashing it again
+    pub sha256: Option<String>,
+}
+
+/// One other local mirror files can be copied from
+struct Seed {
+    root: PathBuf,
+    manifest: Manifest,
+    /// Files by name, anywhere within [`MAX_SEED_DEPTH`] of the root
+    files: HashMap<String, Vec<PathBuf>>,
+}
+
+/// Other local mirrors looked in before downloading an upload, see `--seed-from`
+pub struct SeedDirs {
+    seeds: Vec<Seed>,
+}
+
+impl SeedDirs {
+    /// Read the manifests `layout` keeps in the given directories and list the files they hold
+    pub fn open(dirs: &[PathBuf], layout: &Layout) -> Result<Self> {
+        let mut seeds = Vec::with_capacity(dirs.len());
+        for root in dirs {
+            if !root.is_dir() {
+                crate::error::bail!("Seed directory {} doesn't exist", root.display());
+            }
+            let manifest = Manifest::load(root, layout)?;
+            let mut files = HashMap::new();
+            list_files(root, 0, &mut files);
+            seeds.push(Seed {
+                root: root.clone(),
+                manifest,
+                files,
+            });
+        }
+        Ok(Self { seeds })
+    }
+
+    /// Find a file with the upload's name and size in the seed directories
+    ///
+    /// What a seed's manifest recorded for the upload is tried first, then any file of that
+    /// name, skipping those whose metadata sidecar says they belong to another game and those
+    /// with a recorded md5 other than `md5`. Matches still need their contents checking.
+    pub fn find(
+        &self,
+        game_id: u64,
+        upload_id: u64,
+        filename: &str,
+        size: u64,
+        md5: Option<&str>,
+    ) -> Option<SeedMatch> {
+        let has_size = |path: &Path| {
+            std::fs::metadata(path)
+                .is_ok_and(|metadata| metadata.is_file() && metadata.len() == size)
+        };
+        let md5_matches = |recorded: Option<&str>| match (recorded, md5) {
+            (Some(recorded), Some(md5)) => recorded.eq_ignore_ascii_case(md5),
+            _ => true,
+        };
+
+        for seed in &self.seeds {
+            // The manifest's path is the extracted directory when the archive was unpacked
+            if let Some(record) = seed.manifest.previous(game_id, upload_id) {
+                let path = seed.manifest.resolve(&record.path);
+                if record.size == size && md5_matches(record.md5.as_deref()) && has_size(&path) {
+                    return Some(SeedMatch {
+                        path,
+                        sha256: record.sha256.clone(),
+                    });
+                }
+            }
+        }
+
+        for seed in &self.seeds {
+            let Some(paths) = seed.files.get(filename) else {
+                continue;
+            };
+            for path in paths {
+                if !has_size(path) {
+                    continue;
+                }
+                let other_game = path
+                    .parent()
+                    .and_then(GameMetadata::find)
+                    .is_some_and(|metadata| metadata.game_id != game_id);
+                if other_game {
+                    continue;
+                }
+                // The same file may be known to the manifest under another upload
+                let recorded = seed.manifest.games().find_map(|(_, game)| {
+                    game.uploads
+                        .values()
+                        .find(|record| seed.manifest.resolve(&record.path) == *path)
+                });
+                if recorded.is_some_and(|record| !md5_matches(record.md5.as_deref())) {
+                    continue;
+                }
+                return Some(SeedMatch {
+                    path: path.clone(),
+                    sha256: recorded.and_then(|record| record.sha256.clone()),
+                });
+            }
+        }
+        None
+    }
+
+    /// The seed directory a matched file is in, for messages
+    pub fn root_of(&self, path: &Path) -> Option<&Path> {
+        self.seeds
+            .iter()
+            .map(|seed| seed.root.as_path())
+            .find(|root| path.starts_with(root))
+    }
+}
+
+/// Add the files under `dir` to `files`, leaving out our state and temporary files
+fn list_files(dir: &Path, depth: usize, files: &mut HashMap<String, Vec<PathBuf>>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == STATE_DIR || staging::is_temp_name(&name) {
+            continue;
+        }
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_file() {
+            files.entry(name).or_default().push(entry.path());
+        } else if file_type.is_dir() && depth + 1 < MAX_SEED_DEPTH {
+            list_files(&entry.path(), depth + 1, files);
+        }
+    }
+}
+
+/// Put a copy of `seed` in `dest_dir` as `filename`, the way a download would be saved
+///
+/// The file is hard linked when the seed is on the same filesystem and copied otherwise, staged
+/// under a temporary name first so a partial copy never sits at the final path. The copy is
+/// checked against the upload's size and md5 like a download, returning `None` without placing
+/// anything when it doesn't match.
+pub fn place(
+    seed: &SeedMatch,
+    upload_id: u64,
+    filename: &str,
+    size: u64,
+    md5: Option<&str>,
+    dest_dir: &Path,
+    save: &SaveOptions,
+) -> Result<Option<DownloadedFile>> {
+    let part_path = staging::long_path(
+        &staging::staging_dir(save.temp_dir.as_ref(), dest_dir)
+            .join(staging::part_file_name(upload_id)),
+    );
+    let _ = std::fs::remove_file(&part_path);
+    let linked = std::fs::hard_link(&seed.path, &part_path).is_ok();
+    if !linked {
+        std::fs::copy(&seed.path, &part_path)
+            .with_context(|| format!("Failed to copy {}", seed.path.display()))?;
+    }
+
+    let staged = (|| -> Result<Option<DownloadedFile>> {
+        let (status, _) = check::compare_file(&part_path, size, md5, |_| {})?;
+        if status != CheckStatus::Ok {
+            return Ok(None);
+        }
+        let sha256 = match &seed.sha256 {
+            Some(sha256) => sha256.clone(),
+            None => crate::dedupe::file_sha256(&part_path, |_| {})?,
+        };
+        let file_path = staging::long_path(&dest_dir.join(filename));
+        let deduplicated = match &save.dedupe {
+            Some(index) => index
+                .lock()
+                .unwrap()
+                .finalize(&part_path, &file_path, &sha256, size)?,
+            None => {
+                staging::move_path(&part_path, &file_path)?;
+                false
+            }
+        };
+        Ok(Some(DownloadedFile {
+            filename: filename.to_string(),
+            size,
+            sha256,
+            linked: linked || deduplicated,
+        }))
+    })();
+    if !matches!(staged, Ok(Some(_))) {
+        let _ = std::fs::remove_file(&part_path);
+    }
+    staged
+}
@@ -0,0 +1,439 @@
+//! Which of a game's uploads gets downloaded
+
+use crate::Upload;
+use crate::extract::{ArchiveKind, ExtractOptions};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// What [`choose_uploads`] prefers
+#[derive(Clone, Debug, Default)]
+pub struct SelectionPolicy {
+    /// Decides which archives count as extractable, RAR ones needing an external tool
+    pub extract: ExtractOptions,
+    /// Only consider demo uploads, see [`Upload::is_demo`]
+    pub demos_only: bool,
+}
+
+impl SelectionPolicy {
+    /// Where an upload ranks, lower being preferred
+    fn rank(&self, upload: &Upload) -> u8 {
+        match ArchiveKind::from_filename(&upload.filename) {
+            Some(ArchiveKind::Zip) => 0,
+            Some(kind) if self.extract.can_extract(kind) => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// A game's uploads from most to least preferred
+///
+/// Zip files come first, then any other archive that can be extracted, then everything else.
+/// Uploads that rank the same keep their listed order, so the first listed zip wins. Uploads the
+/// policy rules out aren't returned at all.
+pub fn choose_uploads<'a>(uploads: &'a [Upload], policy: &SelectionPolicy) -> Vec<&'a Upload> {
+    let mut candidates: Vec<&Upload> = uploads
+        .iter()
+        .filter(|upload| !policy.demos_only || upload.is_demo())
+        .collect();
+    // A stable sort, which is what keeps the listed order within a rank
+    candidates.sort_by_key(|upload| policy.rank(upload));
+    candidates
+}
+
+/// Pick the upload to download for a game, the first of [`choose_uploads`]
+pub fn choose_upload<'a>(uploads: &'a [Upload], policy: &SelectionPolicy) -> Option<&'a Upload> {
+    let candidates = choose_uploads(uploads, policy);
+    let upload = *candidates.first()?;
+    let reason = match policy.rank(upload) {
+        0 => "first zip",
+        1 => "first archive we can extract",
+        _ => "first listed, no extractable archive",
+    };
+    info!(
+        upload_id = upload.id,
+        filename = %upload.filename,
+        candidates = candidates.len(),
+        reason,
+        "Chose upload"
+    );
+    Some(upload)
+}
+
+/// The upload types itch.io knows, which [`UploadRule::upload_type`] has to be one of
+pub const UPLOAD_TYPES: &[&str] = &[
+    "default",
+    "flash",
+    "unity",
+    "java",
+    "html",
+    "soundtrack",
+    "book",
+    "video",
+    "documentation",
+    "mod",
+    "audio_assets",
+    "graphical_assets",
+    "sourcecode",
+    "other",
+];
+
+/// The platforms [`UploadRule::platform`] can name
+const PLATFORMS: &[&str] = &["windows", "linux", "osx"];
+
+/// Picks uploads by type, platform and filename, every part that's given having to match
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UploadRule {
+    /// One of [`UPLOAD_TYPES`], e.g. `soundtrack`
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub upload_type: Option<String>,
+    /// `windows`, `linux` or `osx`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    /// Filename pattern matched ignoring case, `*` standing for any run of characters and `?`
+    /// for any one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+impl UploadRule {
+    /// Check the rule names a known type and platform and isn't empty
+    pub fn validate(&self) -> Result<(), String> {
+        if self.upload_type.is_none() && self.platform.is_none() && self.pattern.is_none() {
+            return Err(
+                "a rule needs a type, platform or pattern, an empty one would match everything"
+                    .to_string(),
+            );
+        }
+        if let Some(upload_type) = &self.upload_type
+            && !UPLOAD_TYPES.contains(&upload_type.as_str())
+        {
+            return Err(format!(
+                "unknown upload type \"{}\" (known: {})",
+                upload_type,
+                UPLOAD_TYPES.join(", ")
+            ));
+        }
+        if let Some(platform) = &self.platform
+            && !PLATFORMS.contains(&platform.as_str())
+        {
+            return Err(format!(
+                "unknown platform \"{}\" (known: {})",
+                platform,
+                PLATFORMS.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether an upload fits every part of the rule
+    pub fn matches(&self, upload: &Upload) -> bool {
+        let on_platform = |platform: &str| {
+            let marked = match platform {
+                "windows" => upload.platforms.windows.is_some(),
+                "linux" => upload.platforms.linux.is_some(),
+                "osx" => upload.platforms.osx.is_some(),
+                _ => false,
+            };
+            // Some responses only have the `p_windows` style traits
+            marked
+                || upload
+                    .traits
+                    .iter()
+                    .any(|name| name.strip_prefix("p_") == Some(platform))
+        };
+        self.upload_type
+            .as_ref()
+            .is_none_or(|upload_type| upload.upload_type == *upload_type)
+            && self.platform.as_deref().is_none_or(on_platform)
+            && self.pattern.as_ref().is_none_or(|pattern| {
+                wildcard_match(&pattern.to_lowercase(), &upload.filename.to_lowercase())
+            })
+    }
+}
+
+impl std::fmt::Display for UploadRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts = [
+            ("type", &self.upload_type),
+            ("platform", &self.platform),
+            ("pattern", &self.pattern),
+        ];
+        let parts: Vec<String> = parts
+            .into_iter()
+            .filter_map(|(name, value)| Some(format!("{}={}", name, value.as_ref()?)))
+            .collect();
+        f.write_str(&parts.join(","))
+    }
+}
+
+/// Whether `text` fits `pattern`, `*` standing for any run of characters and `?` for any one
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was and how much of the text it had taken, to backtrack to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// A named combination of a game's uploads to download together, chosen with `--bundle-set`
+///
+/// Named sets come from the config file, other names are read as `+`-separated parts, each
+/// either `game` for the upload [`choose_upload`] picks or an upload type, so `game+soundtrack`
+/// is the game's archive plus every soundtrack upload.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UploadSet {
+    /// Whether the upload [`choose_upload`] picks is part of the set
+    #[serde(default)]
+    pub game: bool,
+    /// Uploads any of these match are part of the set too
+    #[serde(default)]
+    pub rules: Vec<UploadRule>,
+}
+
+impl std::str::FromStr for UploadSet {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let mut set = UploadSet::default();
+        for part in name.split('+').map(str::trim) {
+            match part {
+                "game" => set.game = true,
+                "" => return Err(format!("\"{}\" has an empty part", name)),
+                upload_type => set.rules.push(UploadRule {
+                    upload_type: Some(upload_type.to_string()),
+                    ..UploadRule::default()
+                }),
+            }
+        }
+        set.validate()?;
+        Ok(set)
+    }
+}
+
+impl UploadSet {
+    /// Check the set takes something and that each of its rules is valid
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.game && self.rules.is_empty() {
+            return Err("a set needs `game = true` or at least one rule".to_string());
+        }
+        for (index, rule) in self.rules.iter().enumerate() {
+            rule.validate()
+                .map_err(|e| format!("rules[{}]: {}", index, e))?;
+        }
+        Ok(())
+    }
+
+    /// The uploads of a game in the set, each with what captured it, `game` or the rule
+    ///
+    /// The upload `game` picks comes first, the rest keep their listed order. An upload captured
+    /// twice is only returned once.
+    pub fn select<'a>(
+        &self,
+        uploads: &'a [Upload],
+        policy: &SelectionPolicy,
+    ) -> Vec<(&'a Upload, String)> {
+        let mut selected: Vec<(&Upload, String)> = Vec::new();
+        if self.game
+            && let Some(upload) = choose_upload(uploads, policy)
+        {
+            selected.push((upload, "game".to_string()));
+        }
+        for upload in uploads {
+            if (policy.demos_only && !upload.is_demo())
+                || selected.iter().any(|(taken, _)| taken.id == upload.id)
+            {
+                continue;
+            }
+            if let Some(rule) = self.rules.iter().find(|rule| rule.matches(upload)) {
+                selected.push((upload, rule.to_string()));
+            }
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn upload(id: u64, filename: &str) -> Upload {
+        serde_json::from_value(json!({
+            "id": id,
+            "filename": filename,
+            "size": 100,
+            "type": "default",
+            "game_id": 10
+        }))
+        .unwrap()
+    }
+
+    fn ids(uploads: &[&Upload]) -> Vec<u64> {
+        uploads.iter().map(|upload| upload.id).collect()
+    }
+
+    #[test]
+    fn zips_come_first_then_extractable_archives() {
+        let uploads = [
+            upload(1, "game.exe"),
+            upload(2, "game.tar.gz"),
+            upload(3, "game.ZIP"),
+            upload(4, "game.rar"),
+        ];
+        let policy = SelectionPolicy::default();
+        // RAR needs an external tool, so it ranks with everything else
+        assert_eq!(ids(&choose_uploads(&uploads, &policy)), [3, 2, 1, 4]);
+
+        let policy = SelectionPolicy {
+            extract: ExtractOptions {
+                unrar_path: Some(PathBuf::from("unrar")),
+                ..ExtractOptions::default()
+            },
+            ..SelectionPolicy::default()
+        };
+        assert_eq!(ids(&choose_uploads(&uploads, &policy)), [3, 2, 4, 1]);
+    }
+
+    #[test]
+    fn ties_keep_the_listed_order() {
+        let uploads = [
+            upload(1, "manual.pdf"),
+            upload(2, "game-windows.zip"),
+            upload(3, "game-linux.zip"),
+        ];
+        let policy = SelectionPolicy::default();
+        assert_eq!(ids(&choose_uploads(&uploads, &policy)), [2, 3, 1]);
+        assert_eq!(choose_upload(&uploads, &policy).unwrap().id, 2);
+    }
+
+    #[test]
+    fn demos_only_leaves_out_full_games() {
+        let mut demo = upload(2, "demo.zip");
+        demo.traits.push("demo".to_string());
+        let mut old_demo = upload(3, "old-demo.exe");
+        old_demo.demo = true;
+        let uploads = [upload(1, "game.zip"), demo, old_demo];
+        let policy = SelectionPolicy {
+            demos_only: true,
+            ..SelectionPolicy::default()
+        };
+        assert_eq!(ids(&choose_uploads(&uploads, &policy)), [2, 3]);
+        assert!(choose_upload(&[upload(1, "game.zip")], &policy).is_none());
+    }
+
+    #[test]
+    fn platform_rules_read_the_platforms_or_the_traits() {
+        let rule = UploadRule {
+            platform: Some("linux".to_string()),
+            ..UploadRule::default()
+        };
+        let mut marked = upload(1, "game.tar.gz");
+        marked.platforms.linux = Some("all".to_string());
+        let mut traited = upload(2, "game.AppImage");
+        traited.traits.push("p_linux".to_string());
+        let mut windows = upload(3, "game.exe");
+        windows.traits.push("p_windows".to_string());
+        assert!(rule.matches(&marked));
+        assert!(rule.matches(&traited));
+        assert!(!rule.matches(&windows));
+        assert!(!rule.matches(&upload(4, "game.zip")));
+    }
+
+    #[test]
+    fn every_part_of_a_rule_has_to_match() {
+        let rule = UploadRule {
+            upload_type: Some("soundtrack".to_string()),
+            pattern: Some("*.FLAC".to_string()),
+            ..UploadRule::default()
+        };
+        let mut flac = upload(1, "OST.flac");
+        flac.upload_type = "soundtrack".to_string();
+        let mut mp3 = upload(2, "ost.mp3");
+        mp3.upload_type = "soundtrack".to_string();
+        assert!(rule.matches(&flac));
+        assert!(!rule.matches(&mp3));
+        assert!(!rule.matches(&upload(3, "game.flac")));
+        assert_eq!(rule.to_string(), "type=soundtrack,pattern=*.FLAC");
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("game-*-win??.zip", "game-1.2-win64.zip"));
+        assert!(wildcard_match("*linux*", "game_linux_x64.tar.gz"));
+        assert!(!wildcard_match("game-?.zip", "game-10.zip"));
+        assert!(!wildcard_match("*.zip", "game.zip.part"));
+    }
+
+    #[test]
+    fn rules_are_validated() {
+        assert!(UploadRule::default().validate().is_err());
+        let rule = |upload_type: &str, platform: &str| UploadRule {
+            upload_type: Some(upload_type.to_string()),
+            platform: Some(platform.to_string()),
+            pattern: None,
+        };
+        assert!(rule("soundtrack", "osx").validate().is_ok());
+        assert!(rule("music", "osx").validate().is_err());
+        assert!(rule("soundtrack", "macos").validate().is_err());
+    }
+
+    #[test]
+    fn sets_are_parsed_from_names() {
+        let set: UploadSet = "game + soundtrack".parse().unwrap();
+        assert!(set.game);
+        assert_eq!(set.rules.len(), 1);
+        assert_eq!(set.rules[0].upload_type.as_deref(), Some("soundtrack"));
+        assert!("game+".parse::<UploadSet>().is_err());
+        assert!("game+music".parse::<UploadSet>().is_err());
+    }
+
+    #[test]
+    fn sets_put_the_game_first_and_take_each_upload_once() {
+        let mut soundtrack = upload(1, "ost.zip");
+        soundtrack.upload_type = "soundtrack".to_string();
+        let uploads = [soundtrack, upload(2, "manual.pdf"), upload(3, "game.zip")];
+        let set = UploadSet {
+            game: true,
+            rules: vec![
+                UploadRule {
+                    pattern: Some("*.zip".to_string()),
+                    ..UploadRule::default()
+                },
+                UploadRule {
+                    upload_type: Some("soundtrack".to_string()),
+                    ..UploadRule::default()
+                },
+            ],
+        };
+        let selected: Vec<(u64, String)> = set
+            .select(&uploads, &SelectionPolicy::default())
+            .into_iter()
+            .map(|(upload, reason)| (upload.id, reason))
+            .collect();
+        // The soundtrack zip is listed first, so it's the one `game` picks
+        assert_eq!(
+            selected,
+            [(1, "game".to_string()), (3, "pattern=*.zip".to_string())]
+        );
+    }
+}
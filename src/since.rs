@@ -0,0 +1,67 @@
+use crate::error::{Context, Result};
+use crate::layout::Layout;
+use crate::timestamp::{self, Timestamp};
+use std::path::Path;
+
+/// Name of the file in the state directory holding when the last successful run started
+const LAST_RUN_FILE: &str = "last-run";
+
+/// Which purchases `--since` keeps
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Since {
+    /// Newer than the start of the last successful run
+    LastRun,
+    /// Newer than a date, `YYYY-MM-DD` with an optional ` HH:MM:SS` in UTC
+    Date(Timestamp),
+}
+
+impl std::str::FromStr for Since {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text == "last-run" {
+            return Ok(Since::LastRun);
+        }
+        timestamp::parse(text).map(Since::Date).ok_or_else(|| {
+            format!(
+                "expected `last-run` or a date like 2024-01-01, got '{}'",
+                text
+            )
+        })
+    }
+}
+
+impl Since {
+    /// The moment purchases must be newer than, `None` for `last-run` before any run succeeded
+    pub fn cutoff(&self, output_path: &Path, layout: &Layout) -> Result<Option<Timestamp>> {
+        match self {
+            Since::Date(date) => Ok(Some(*date)),
+            Since::LastRun => {
+                let path = layout.state_dir(output_path).join(LAST_RUN_FILE);
+                match std::fs::read_to_string(&path) {
+                    Ok(contents) => timestamp::parse(&contents)
+                        .map(Some)
+                        .with_context(|| format!("{} doesn't hold a date", path.display())),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+                }
+            }
+        }
+    }
+}
+
+/// Whether an owned key's `created_at` is at or after the cutoff, keys without one never are
+pub fn is_newer(created_at: Option<Timestamp>, cutoff: Timestamp) -> bool {
+    created_at.is_some_and(|created_at| created_at >= cutoff)
+}
+
+/// Remember that a run which started at `started_at` succeeded, for the next `--since last-run`
+pub fn record_run(output_path: &Path, layout: &Layout, started_at: u64) -> Result<()> {
+    let state_dir = layout.state_dir(output_path);
+    std::fs::create_dir_all(&state_dir).context("Failed to create state directory")?;
+    std::fs::write(
+        state_dir.join(LAST_RUN_FILE),
+        timestamp::to_itch(timestamp::from_unix(started_at as i64)),
+    )
+    .context("Failed to record last run")
+}
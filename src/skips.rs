@@ -0,0 +1,100 @@
+use crate::error::{Context, Result};
+use crate::history::Outcome;
+use crate::layout::Layout;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Name of the list of permanently skipped downloads inside the state directory
+const SKIPS_FILE: &str = "skips.json";
+
+/// A game or upload that `dl` and `sync` no longer attempt, until `--reset-skips`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SkippedItem {
+    pub game_id: u64,
+    /// Only this upload of the game is skipped, the whole game when missing
+    pub upload_id: Option<u64>,
+    pub title: String,
+    /// The error the download failed with when it was skipped
+    pub error: Option<String>,
+    /// Unix timestamp of when it was skipped
+    pub skipped_at: u64,
+}
+
+impl From<&Outcome> for SkippedItem {
+    fn from(outcome: &Outcome) -> Self {
+        Self {
+            game_id: outcome.game_id,
+            upload_id: outcome.upload_id,
+            title: outcome.title.clone(),
+            error: outcome.error.clone(),
+            skipped_at: crate::manifest::now(),
+        }
+    }
+}
+
+/// The downloads skipped permanently in an output directory
+#[derive(Clone, Debug, Default)]
+pub struct Skips {
+    items: Vec<SkippedItem>,
+}
+
+impl Skips {
+    /// Load the skip list, empty if nothing was ever skipped
+    pub fn load(output_path: &Path, layout: &Layout) -> Result<Self> {
+        let path = layout.state_dir(output_path).join(SKIPS_FILE);
+        let items = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        Ok(Self { items })
+    }
+
+    /// Replace the skip list on disk
+    pub fn save(&self, output_path: &Path, layout: &Layout) -> Result<()> {
+        let state_dir = layout.state_dir(output_path);
+        std::fs::create_dir_all(&state_dir).context("Failed to create state directory")?;
+        let contents = serde_json::to_string_pretty(&self.items)
+            .context("Failed to serialize skipped downloads")?;
+        std::fs::write(state_dir.join(SKIPS_FILE), contents)
+            .context("Failed to write skipped downloads")
+    }
+
+    pub fn items(&self) -> &[SkippedItem] {
+        &self.items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Forget everything skipped so far
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    /// Add an item, replacing any earlier entry for the same game and upload
+    pub fn add(&mut self, item: SkippedItem) {
+        self.items.retain(|skipped| {
+            (skipped.game_id, skipped.upload_id) != (item.game_id, item.upload_id)
+        });
+        self.items.push(item);
+    }
+
+    /// Whether the whole game is skipped
+    pub fn skips_game(&self, game_id: u64) -> bool {
+        self.items
+            .iter()
+            .any(|item| item.game_id == game_id && item.upload_id.is_none())
+    }
+
+    /// Whether the upload is skipped, on its own or as part of its game
+    pub fn skips_upload(&self, game_id: u64, upload_id: u64) -> bool {
+        self.items.iter().any(|item| {
+            item.game_id == game_id && item.upload_id.is_none_or(|skipped| skipped == upload_id)
+        })
+    }
+}
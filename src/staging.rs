@@ -0,0 +1,313 @@
+use crate::error::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Prefix for every temporary file or directory we create, so leftovers can be attributed to us
+const TEMP_PREFIX: &str = ".itch-downloader-";
+
+/// Name of the temporary file a download is streamed into before being moved into place
+pub fn part_file_name(upload_id: u64) -> String {
+    format!("{}{}-{}.part", TEMP_PREFIX, std::process::id(), upload_id)
+}
+
+/// Extractions this process has started, numbering their temporary directories
+static EXTRACTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Name of the temporary directory an archive is extracted into before being moved into place
+///
+/// Each call gives a new name, as extractions into targets with the same name, such as two games'
+/// `windows` uploads, can share a `--temp-dir`.
+pub fn extract_dir_name(target: &Path) -> String {
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!(
+        "{}{}-{}-{}.temp_extract",
+        TEMP_PREFIX,
+        std::process::id(),
+        EXTRACTIONS.fetch_add(1, Ordering::Relaxed),
+        name
+    )
+}
+
+/// Temporary name a file or directory is moved to while a replacement is put in place
+pub fn set_aside_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(
+        "{}{}-{}.old",
+        TEMP_PREFIX,
+        std::process::id(),
+        name
+    ))
+}
+
+/// Pull the owning process id out of one of our temporary names
+fn owner_pid(name: &str) -> Option<u32> {
+    let rest = name.strip_prefix(TEMP_PREFIX)?;
+    let (pid, _) = rest.split_once('-')?;
+    pid.parse().ok()
+}
+
+/// Whether a file name is one of our temporary files or directories
+pub fn is_temp_name(name: &str) -> bool {
+    owner_pid(name).is_some()
+}
+
+/// Check whether a process with the given id is still running
+#[cfg(unix)]
+pub fn process_is_alive(pid: u32) -> bool {
+    // Signal 0 performs the permission and existence checks without delivering anything
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// Check whether a process with the given id is still running
+///
+/// Only Unix is asked, everywhere else any process counts as running. So there, leftovers of
+/// interrupted runs are never cleaned up and their locks never taken over without a hand.
+#[cfg(not(unix))]
+pub fn process_is_alive(pid: u32) -> bool {
+    // Without a cheap way to ask the OS we err on the side of leaving things alone
+    pid != 0
+}
+
+/// Entries in `dir` whose name says which process wrote them, and that process is no longer running
+fn find_dead_owned(dir: &Path, owner: impl Fn(&str) -> Option<u32>) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read directory for leftover temporary files"),
+    };
+
+    let mut orphans = Vec::new();
+    for entry in entries {
+        let entry = entry.context("Failed to list directory entries")?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(pid) = owner(&name) else {
+            continue;
+        };
+        if pid == std::process::id() || process_is_alive(pid) {
+            continue;
+        }
+        orphans.push(entry.path());
+    }
+
+    orphans.sort();
+    Ok(orphans)
+}
+
+/// Temporary files and directories in `dir` left behind by runs that are no longer alive
+pub fn find_orphans(dir: &Path) -> Result<Vec<PathBuf>> {
+    find_dead_owned(dir, owner_pid)
+}
+
+/// Files written as `<name>.<pid>.tmp` and renamed into place, left behind by runs that died mid-write
+pub fn find_orphaned_tmp_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    find_dead_owned(dir, |name| {
+        let stem = name.strip_suffix(".tmp")?;
+        let (_, pid) = stem.rsplit_once('.')?;
+        pid.parse().ok()
+    })
+}
+
+/// Remove temporary files and directories left behind in `dir` by runs that are no longer alive
+///
+/// Returns how many leftovers were removed.
+pub fn remove_orphans(dir: &Path) -> Result<usize> {
+    let orphans = find_orphans(dir)?;
+    for path in &orphans {
+        remove_path(path)
+            .with_context(|| format!("Failed to remove leftover {}", path.display()))?;
+    }
+    Ok(orphans.len())
+}
+
+/// Directories of an output directory our temporary files can end up in: the output itself and,
+/// with per-game directories, each game's directory and the category folders `--organize` adds
+pub fn orphan_dirs(output_path: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![output_path.to_path_buf()];
+    let entries = match std::fs::read_dir(output_path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(dirs),
+        Err(e) => return Err(e).context("Failed to read output directory"),
+    };
+    for entry in entries {
+        let entry = entry.context("Failed to list output directory")?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+        if is_dir && name != crate::layout::STATE_DIR && !is_temp_name(&name) {
+            for category in crate::layout::Category::ALL {
+                let category_dir = entry.path().join(category.dir_name());
+                if category_dir.is_dir() {
+                    dirs.push(category_dir);
+                }
+            }
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+/// Remove a file or a whole directory tree
+pub fn remove_path(path: &Path) -> std::io::Result<()> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+/// Recursively copy a file or directory
+pub fn copy_recursive(source: &Path, dest: &Path) -> std::io::Result<()> {
+    if source.is_dir() {
+        std::fs::create_dir_all(dest)?;
+        for entry in std::fs::read_dir(source)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &dest.join(entry.file_name()))?;
+        }
+    } else {
+        std::fs::copy(source, dest)?;
+    }
+    Ok(())
+}
+
+/// Move a file or directory, falling back to copy and delete when it has to cross filesystems
+pub fn move_path(source: &Path, dest: &Path) -> Result<()> {
+    match std::fs::rename(source, dest) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {}
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("Failed to move {} to {}", source.display(), dest.display())
+            });
+        }
+    }
+
+    copy_recursive(source, dest)
+        .with_context(|| format!("Failed to copy {} to {}", source.display(), dest.display()))?;
+    if source.is_dir() {
+        std::fs::remove_dir_all(source)
+    } else {
+        std::fs::remove_file(source)
+    }
+    .with_context(|| format!("Failed to remove {} after copying it", source.display()))
+}
+
+/// The directory temporary files should be staged in, `fallback` unless `--temp-dir` was given
+pub fn staging_dir(temp_dir: Option<&PathBuf>, fallback: &Path) -> PathBuf {
+    temp_dir.cloned().unwrap_or_else(|| fallback.to_path_buf())
+}
+
+/// The longest single path component most filesystems accept, in bytes
+const MAX_COMPONENT_BYTES: usize = 255;
+
+/// Shorten a single path component that exceeds filesystem limits, keeping it unique with a hash suffix
+///
+/// The extension is preserved when it's reasonably short so file types still resolve.
+pub fn shorten_component(name: &str) -> String {
+    if name.len() <= MAX_COMPONENT_BYTES {
+        return name.to_string();
+    }
+
+    // FNV-1a, stable across runs and Rust versions unlike the std hasher
+    let hash = name.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    let suffix = format!("~{:08x}", hash as u32);
+
+    let (stem, extension) = match name.rfind('.') {
+        Some(index) if index > 0 && name.len() - index <= 16 => name.split_at(index),
+        _ => (name, ""),
+    };
+    let mut keep = MAX_COMPONENT_BYTES - suffix.len() - extension.len();
+    while !stem.is_char_boundary(keep) {
+        keep -= 1;
+    }
+
+    format!("{}{}{}", &stem[..keep], suffix, extension)
+}
+
+/// Convert a path to Windows' extended-length form so deep trees aren't limited to 260 characters
+///
+/// The path doesn't need to exist yet, it's made absolute lexically rather than canonicalized.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> PathBuf {
+    let absolute = match std::path::absolute(path) {
+        Ok(absolute) => absolute,
+        Err(_) => return path.to_path_buf(),
+    };
+    let raw = absolute.as_os_str().to_string_lossy();
+    if raw.starts_with(r"\\?\") {
+        absolute
+    } else if let Some(unc) = raw.strip_prefix(r"\\") {
+        PathBuf::from(format!(r"\\?\UNC\{}", unc))
+    } else {
+        PathBuf::from(format!(r"\\?\{}", raw))
+    }
+}
+
+/// Convert a path to Windows' extended-length form, a no-op on other platforms
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extractions_to_the_same_name_get_their_own_directories() {
+        let first = extract_dir_name(Path::new("/games/celeste/windows"));
+        let second = extract_dir_name(Path::new("/games/hollow-knight/windows"));
+        assert_ne!(first, second);
+        for name in [&first, &second] {
+            assert!(name.ends_with("-windows.temp_extract"), "{}", name);
+            assert_eq!(owner_pid(name), Some(std::process::id()));
+            assert!(is_temp_name(name));
+        }
+    }
+
+    #[test]
+    fn names_say_which_process_wrote_them() {
+        assert_eq!(owner_pid(&part_file_name(42)), Some(std::process::id()));
+        let aside = set_aside_path(Path::new("/games/celeste"));
+        let aside = aside.file_name().unwrap().to_str().unwrap();
+        assert_eq!(owner_pid(aside), Some(std::process::id()));
+        assert!(!is_temp_name("celeste"));
+        assert!(!is_temp_name(".itch-downloader-notapid-celeste"));
+    }
+
+    #[test]
+    fn extractions_with_a_shared_temp_dir_do_not_collide() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let staging = staging_dir(Some(&temp_dir.path().to_path_buf()), Path::new("unused"));
+        let names: Vec<String> = (0..8)
+            .map(|_| extract_dir_name(Path::new("windows")))
+            .collect();
+        for name in &names {
+            std::fs::create_dir(staging.join(name)).unwrap();
+        }
+        assert_eq!(std::fs::read_dir(&staging).unwrap().count(), names.len());
+    }
+
+    #[test]
+    fn a_move_that_fails_leaves_both_sides_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let dest = dir.path().join("dest");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("game.exe"), "new").unwrap();
+        std::fs::create_dir(&dest).unwrap();
+        std::fs::write(dest.join("save.dat"), "old").unwrap();
+
+        // Copying over is only for crossing filesystems, here it would merge the two directories
+        assert!(move_path(&source, &dest).is_err());
+        assert!(source.join("game.exe").exists());
+        assert!(!dest.join("game.exe").exists());
+    }
+}
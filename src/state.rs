@@ -0,0 +1,648 @@
+//! Carrying what the tool knows about a mirror to another machine, as a single `.tar.gz`
+
+use crate::cache::KEY_FILE;
+use crate::dedupe::{HashIndex, INDEX_FILE};
+use crate::error::{Context, Result, bail};
+use crate::history::HISTORY_FILE;
+use crate::layout::{Layout, PROFILES_DIR};
+use crate::lock::LOCK_FILE;
+use crate::manifest::{MANIFEST_FILE, Manifest};
+use crate::receipt::RECEIPT_FILE;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Format of state exports, bumped whenever their layout changes
+pub const STATE_FORMAT_VERSION: u32 = 1;
+
+/// Name of the entry describing an export, always the first in the archive
+const DESCRIPTION_FILE: &str = "state.json";
+
+/// Directories inside the archive for the state directory, the HTTP cache and the receipts
+const STATE_ENTRIES: &str = "state";
+const CACHE_ENTRIES: &str = "cache";
+const RECEIPT_ENTRIES: &str = "receipts";
+
+/// How deep into the output directory receipts are looked for
+const MAX_RECEIPT_DEPTH: usize = 3;
+
+/// How the mirror's files are laid out, which the paths in its state assume
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct LayoutSettings {
+    pub per_game_dirs: bool,
+    pub link_latest: bool,
+    #[serde(default)]
+    pub organize: bool,
+    pub group_by_bundle: bool,
+}
+
+/// What a state export holds, written into it as `state.json`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateDescription {
+    /// [`STATE_FORMAT_VERSION`] when the export was written
+    pub version: u32,
+    /// Version of itch-downloader that wrote it
+    pub tool_version: String,
+    /// Seconds since the Unix epoch
+    pub exported_at: u64,
+    /// The output directory the state was exported from
+    pub output_root: PathBuf,
+    pub profile: Option<String>,
+    pub layout: LayoutSettings,
+}
+
+/// How many files of each kind went into or came out of an export
+#[derive(Clone, Debug, Default)]
+pub struct StateCounts {
+    pub state_files: usize,
+    pub cache_files: usize,
+    pub receipts: usize,
+}
+
+/// What [`import_state`] did
+#[derive(Clone, Debug)]
+pub struct ImportSummary {
+    pub description: StateDescription,
+    pub written: StateCounts,
+    /// State files combined with the ones already there, with `merge`
+    pub merged: Vec<String>,
+    /// State files left as they were since there's no way to combine them
+    pub kept: Vec<String>,
+    /// Absolute paths under the old output directory made relative
+    pub rebased_paths: usize,
+}
+
+/// Write the state of the mirror at `output_path` to a `.tar.gz` at `archive_path`
+///
+/// That's everything in its state directory but the lock, the cached API responses in
+/// `cache_dir`, usually [`HttpCache::default_dir`](crate::cache::HttpCache::default_dir), and
+/// the receipts kept with each game's files.
+pub fn export_state(
+    output_path: &Path,
+    layout: &Layout,
+    layout_settings: LayoutSettings,
+    cache_dir: Option<&Path>,
+    archive_path: &Path,
+) -> Result<StateCounts> {
+    let state_dir = layout.state_dir(output_path);
+    if !state_dir.is_dir() {
+        bail!("{} has no state to export", output_path.display());
+    }
+    let description = StateDescription {
+        version: STATE_FORMAT_VERSION,
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        exported_at: crate::manifest::now(),
+        output_root: std::path::absolute(output_path)
+            .context("Failed to resolve the output directory")?,
+        profile: layout.profile.clone(),
+        layout: layout_settings,
+    };
+
+    let file = std::fs::File::create(archive_path)
+        .with_context(|| format!("Failed to create {}", archive_path.display()))?;
+    let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+    let mut archive = tar::Builder::new(encoder);
+    let contents =
+        serde_json::to_vec_pretty(&description).context("Failed to serialize description")?;
+    append(&mut archive, Path::new(DESCRIPTION_FILE), &contents)?;
+
+    let mut counts = StateCounts::default();
+    let mut files = Vec::new();
+    // Other profiles' state lives inside the default one's directory
+    let skip = |name: &str| {
+        name == LOCK_FILE
+            || crate::staging::is_temp_name(name)
+            || (layout.profile.is_none() && name == PROFILES_DIR)
+    };
+    list_files(&state_dir, Path::new(""), usize::MAX, &skip, &mut files)?;
+    for relative in files {
+        let contents = std::fs::read(state_dir.join(&relative))
+            .with_context(|| format!("Failed to read {}", relative.display()))?;
+        append(
+            &mut archive,
+            &Path::new(STATE_ENTRIES).join(&relative),
+            &contents,
+        )?;
+        counts.state_files += 1;
+    }
+
+    if let Some(cache_dir) = cache_dir.filter(|dir| dir.is_dir()) {
+        let mut files = Vec::new();
+        list_files(
+            cache_dir,
+            Path::new(""),
+            0,
+            &|name| crate::staging::is_temp_name(name),
+            &mut files,
+        )?;
+        for relative in files {
+            let contents = std::fs::read(cache_dir.join(&relative))
+                .with_context(|| format!("Failed to read cached {}", relative.display()))?;
+            append(
+                &mut archive,
+                &Path::new(CACHE_ENTRIES).join(&relative),
+                &contents,
+            )?;
+            counts.cache_files += 1;
+        }
+    }
+
+    let mut files = Vec::new();
+    let skip = |name: &str| name == crate::layout::STATE_DIR || crate::staging::is_temp_name(name);
+    list_files(
+        output_path,
+        Path::new(""),
+        MAX_RECEIPT_DEPTH,
+        &skip,
+        &mut files,
+    )?;
+    for relative in files
+        .into_iter()
+        .filter(|path| path.ends_with(RECEIPT_FILE))
+    {
+        let contents = std::fs::read(output_path.join(&relative))
+            .with_context(|| format!("Failed to read {}", relative.display()))?;
+        append(
+            &mut archive,
+            &Path::new(RECEIPT_ENTRIES).join(&relative),
+            &contents,
+        )?;
+        counts.receipts += 1;
+    }
+
+    archive
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .and_then(|mut file| file.flush())
+        .context("Failed to write state export")?;
+    Ok(counts)
+}
+
+/// Add a file to the archive under `path`
+fn append<W: Write>(archive: &mut tar::Builder<W>, path: &Path, contents: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(crate::manifest::now());
+    header.set_entry_type(tar::EntryType::Regular);
+    archive
+        .append_data(&mut header, path, contents)
+        .with_context(|| format!("Failed to add {} to the export", path.display()))
+}
+
+/// Collect the files under `dir`, relative to where the walk started, `depth` levels down at most
+fn list_files(
+    dir: &Path,
+    relative: &Path,
+    depth: usize,
+    skip: &dyn Fn(&str) -> bool,
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let entries = std::fs::read_dir(dir.join(relative))
+        .with_context(|| format!("Failed to read {}", dir.join(relative).display()))?;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if skip(&name) {
+            continue;
+        }
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = relative.join(&name);
+        if file_type.is_file() {
+            files.push(path);
+        } else if file_type.is_dir() && depth > 0 {
+            list_files(dir, &path, depth - 1, skip, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// The entries of an export, read whole since state is small next to the mirror itself
+struct StateArchive {
+    description: StateDescription,
+    state: Vec<(PathBuf, Vec<u8>)>,
+    cache: Vec<(PathBuf, Vec<u8>)>,
+    receipts: Vec<(PathBuf, Vec<u8>)>,
+}
+
+/// Read an export, checking it's one in a format this version understands
+fn read_archive(archive_path: &Path) -> Result<StateArchive> {
+    let file = std::fs::File::open(archive_path)
+        .with_context(|| format!("Failed to open {}", archive_path.display()))?;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+    let mut entries = archive.entries().context("Failed to read state export")?;
+
+    let mut first = entries
+        .next()
+        .transpose()
+        .context("Failed to read state export")?
+        .with_context(|| format!("{} is empty", archive_path.display()))?;
+    if first.path().ok().as_deref() != Some(Path::new(DESCRIPTION_FILE)) {
+        bail!("{} isn't a state export", archive_path.display());
+    }
+    let mut contents = String::new();
+    first
+        .read_to_string(&mut contents)
+        .context("Failed to read the export's description")?;
+    let description: StateDescription =
+        serde_json::from_str(&contents).context("Failed to parse the export's description")?;
+    if description.version != STATE_FORMAT_VERSION {
+        bail!(
+            "{} is in state format {} (written by itch-downloader {}), this version reads format {}",
+            archive_path.display(),
+            description.version,
+            description.tool_version,
+            STATE_FORMAT_VERSION
+        );
+    }
+
+    let mut state_archive = StateArchive {
+        description,
+        state: Vec::new(),
+        cache: Vec::new(),
+        receipts: Vec::new(),
+    };
+    for entry in entries {
+        let mut entry = entry.context("Failed to read state export")?;
+        let path = entry
+            .path()
+            .context("Failed to read entry name")?
+            .into_owned();
+        // Nothing in an export may point outside the directory it's restored into
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("State export entry {} has an unsafe path", path.display());
+        }
+        let mut contents = Vec::new();
+        entry
+            .read_to_end(&mut contents)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut components = path.components();
+        let kind = components.next().map(|kind| kind.as_os_str().to_owned());
+        let relative = components.as_path().to_path_buf();
+        match kind.as_deref().and_then(|kind| kind.to_str()) {
+            Some(STATE_ENTRIES) => state_archive.state.push((relative, contents)),
+            Some(CACHE_ENTRIES) => state_archive.cache.push((relative, contents)),
+            Some(RECEIPT_ENTRIES) => state_archive.receipts.push((relative, contents)),
+            _ => bail!("Unexpected entry {} in state export", path.display()),
+        }
+    }
+    Ok(state_archive)
+}
+
+/// Restore an export written by [`export_state`] into the mirror at `output_path`
+///
+/// State files already there with other contents are a conflict, which stops the import unless
+/// `merge` is set. Merging adds the manifest and hash index records and the history lines that
+/// aren't there yet and keeps the existing copy of anything else. Absolute paths recorded under
+/// the old output directory are made relative so they follow the mirror to its new place. Cached
+/// responses are added to `cache_dir` and receipts to the mirror only where missing, receipts
+/// only into directories that exist.
+pub fn import_state(
+    archive_path: &Path,
+    output_path: &Path,
+    layout: &Layout,
+    cache_dir: Option<&Path>,
+    merge: bool,
+) -> Result<ImportSummary> {
+    let archive = read_archive(archive_path)?;
+    let state_dir = layout.state_dir(output_path);
+
+    let conflicts: Vec<String> = archive
+        .state
+        .iter()
+        .filter(|(relative, contents)| {
+            std::fs::read(state_dir.join(relative)).is_ok_and(|existing| existing != *contents)
+        })
+        .map(|(relative, _)| relative.display().to_string())
+        .collect();
+    if !conflicts.is_empty() && !merge {
+        bail!(
+            "{} already has state that differs from the export ({}), pass --merge to combine them",
+            output_path.display(),
+            conflicts.join(", ")
+        );
+    }
+
+    let mut summary = ImportSummary {
+        description: archive.description,
+        written: StateCounts::default(),
+        merged: Vec::new(),
+        kept: Vec::new(),
+        rebased_paths: 0,
+    };
+    for (relative, contents) in &archive.state {
+        let path = state_dir.join(relative);
+        let name = relative.display().to_string();
+        if !conflicts.contains(&name) {
+            if !path.exists() {
+                write_file(&path, contents)?;
+                summary.written.state_files += 1;
+            }
+            continue;
+        }
+        let top_level = relative.parent() == Some(Path::new(""));
+        match relative.to_str() {
+            Some(MANIFEST_FILE) if top_level => {
+                let imported: Manifest = serde_json::from_slice(contents)
+                    .context("Failed to parse the exported manifest")?;
+                let mut manifest = Manifest::load(output_path, layout)?;
+                manifest.merge(imported);
+                manifest.save()?;
+                summary.merged.push(name);
+            }
+            Some(INDEX_FILE) if top_level => {
+                let imported: HashIndex = serde_json::from_slice(contents)
+                    .context("Failed to parse the exported hash index")?;
+                let mut index = HashIndex::load(output_path, layout)?;
+                index.merge(imported);
+                index.save()?;
+                summary.merged.push(name);
+            }
+            Some(HISTORY_FILE) if top_level => {
+                let existing = std::fs::read_to_string(&path).unwrap_or_default();
+                let known: std::collections::HashSet<&str> = existing.lines().collect();
+                let mut file = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&path)
+                    .context("Failed to open history log")?;
+                for line in String::from_utf8_lossy(contents).lines() {
+                    if !line.is_empty() && !known.contains(line) {
+                        writeln!(file, "{}", line).context("Failed to append to history log")?;
+                    }
+                }
+                summary.merged.push(name);
+            }
+            _ => summary.kept.push(name),
+        }
+    }
+
+    // Records of files outside the old output directory, e.g. imported installs, stay absolute
+    let old_root = &summary.description.output_root;
+    let mut manifest = Manifest::load(output_path, layout)?;
+    let rebased = manifest.rebase(old_root);
+    if rebased > 0 {
+        manifest.save()?;
+    }
+    let mut index = HashIndex::load(output_path, layout)?;
+    let rebased_index = index.rebase(old_root);
+    if rebased_index > 0 {
+        index.save()?;
+    }
+    summary.rebased_paths = rebased + rebased_index;
+
+    // The cache is tied to an API key, entries for another key would be thrown away anyway
+    if let Some(cache_dir) = cache_dir {
+        let key = |dir: &Path| std::fs::read(dir.join(KEY_FILE)).ok();
+        let exported_key = archive
+            .cache
+            .iter()
+            .find(|(relative, _)| relative == Path::new(KEY_FILE))
+            .map(|(_, contents)| contents.clone());
+        if key(cache_dir).is_none_or(|existing| Some(existing) == exported_key) {
+            for (relative, contents) in &archive.cache {
+                let path = cache_dir.join(relative);
+                if !path.exists() {
+                    write_file(&path, contents)?;
+                    summary.written.cache_files += 1;
+                }
+            }
+        }
+    }
+
+    for (relative, contents) in &archive.receipts {
+        let path = output_path.join(relative);
+        if path.parent().is_some_and(Path::is_dir) && !path.exists() {
+            write_file(&path, contents)?;
+            summary.written.receipts += 1;
+        }
+    }
+    Ok(summary)
+}
+
+/// Write a restored file, creating the directories it goes in
+fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    std::fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::UploadRecord;
+
+    const LAYOUT: LayoutSettings = LayoutSettings {
+        per_game_dirs: true,
+        link_latest: false,
+        organize: false,
+        group_by_bundle: false,
+    };
+
+    fn record(path: PathBuf) -> UploadRecord {
+        UploadRecord {
+            filename: "game.zip".to_string(),
+            path,
+            size: 4,
+            md5: None,
+            sha256: None,
+            build_id: Some(3),
+            updated_at: None,
+            downloaded_at: 1_700_000_000,
+        }
+    }
+
+    /// A mirror at `root` with two downloads, one recorded by an absolute path, a history line,
+    /// a receipt and a cached response
+    fn mirror(root: &Path, cache_dir: &Path) {
+        for game in ["First", "Second"] {
+            std::fs::create_dir_all(root.join(game)).unwrap();
+            std::fs::write(root.join(game).join("game.zip"), b"data").unwrap();
+        }
+        std::fs::write(root.join("First").join(RECEIPT_FILE), b"receipt").unwrap();
+        let mut manifest = Manifest::load(root, &Layout::default()).unwrap();
+        manifest.record(1, "First", 10, 100, record(PathBuf::from("First/game.zip")));
+        manifest.record(2, "Second", 20, 200, record(root.join("Second/game.zip")));
+        manifest.save().unwrap();
+        std::fs::write(
+            Layout::default().state_dir(root).join(HISTORY_FILE),
+            "{\"run\":1}\n",
+        )
+        .unwrap();
+        std::fs::write(Layout::default().state_dir(root).join(LOCK_FILE), "123").unwrap();
+
+        std::fs::create_dir_all(cache_dir).unwrap();
+        std::fs::write(cache_dir.join(KEY_FILE), "fingerprint").unwrap();
+        std::fs::write(cache_dir.join("profile.json"), "{}").unwrap();
+    }
+
+    /// Copy the downloads of the mirror at `from` to `to`, as moving it to another machine would,
+    /// leaving its state behind
+    fn copy_downloads(from: &Path, to: &Path) {
+        for game in ["First", "Second"] {
+            std::fs::create_dir_all(to.join(game)).unwrap();
+            std::fs::copy(
+                from.join(game).join("game.zip"),
+                to.join(game).join("game.zip"),
+            )
+            .unwrap();
+        }
+    }
+
+    fn is_current(manifest: &Manifest, game_id: u64, upload_id: u64) -> bool {
+        manifest.is_current(game_id, upload_id, 4, None, Some(3), None)
+    }
+
+    #[test]
+    fn a_mirror_moved_with_its_state_is_up_to_date() {
+        let old = tempfile::tempdir().unwrap();
+        let new = tempfile::tempdir().unwrap();
+        let (old_root, new_root) = (old.path().join("mirror"), new.path().join("mirror"));
+        mirror(&old_root, &old.path().join("cache"));
+        let archive = new.path().join("state.tar.gz");
+
+        let exported = export_state(
+            &old_root,
+            &Layout::default(),
+            LAYOUT,
+            Some(&old.path().join("cache")),
+            &archive,
+        )
+        .unwrap();
+        assert_eq!(
+            (
+                exported.state_files,
+                exported.cache_files,
+                exported.receipts
+            ),
+            (2, 2, 1)
+        );
+
+        copy_downloads(&old_root, &new_root);
+        // The old mirror is gone by the time its state is imported
+        drop(old);
+        let new_cache = new.path().join("cache");
+        let summary = import_state(
+            &archive,
+            &new_root,
+            &Layout::default(),
+            Some(&new_cache),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(summary.description.version, STATE_FORMAT_VERSION);
+        assert!(summary.description.layout.per_game_dirs);
+        assert_eq!(summary.rebased_paths, 1);
+        assert_eq!(
+            (
+                summary.written.state_files,
+                summary.written.cache_files,
+                summary.written.receipts
+            ),
+            (2, 2, 1)
+        );
+        // Every download is current where it now is, so a sync has nothing to do
+        let manifest = Manifest::load(&new_root, &Layout::default()).unwrap();
+        assert!(is_current(&manifest, 1, 100));
+        assert!(is_current(&manifest, 2, 200));
+        assert_eq!(
+            manifest.previous(2, 200).unwrap().path,
+            Path::new("Second/game.zip")
+        );
+        assert_eq!(
+            std::fs::read(new_root.join("First").join(RECEIPT_FILE)).unwrap(),
+            b"receipt"
+        );
+        assert!(new_cache.join("profile.json").is_file());
+        // The old machine's lock isn't carried over
+        assert!(
+            !Layout::default()
+                .state_dir(&new_root)
+                .join(LOCK_FILE)
+                .exists()
+        );
+    }
+
+    #[test]
+    fn differing_state_is_only_combined_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old_root, new_root) = (dir.path().join("old"), dir.path().join("new"));
+        mirror(&old_root, &dir.path().join("cache"));
+        let archive = dir.path().join("state.tar.gz");
+        export_state(&old_root, &Layout::default(), LAYOUT, None, &archive).unwrap();
+
+        copy_downloads(&old_root, &new_root);
+        std::fs::create_dir_all(new_root.join("Third")).unwrap();
+        std::fs::write(new_root.join("Third/game.zip"), b"data").unwrap();
+        let mut manifest = Manifest::load(&new_root, &Layout::default()).unwrap();
+        manifest.record(3, "Third", 30, 300, record(PathBuf::from("Third/game.zip")));
+        manifest.save().unwrap();
+
+        let error = import_state(&archive, &new_root, &Layout::default(), None, false).unwrap_err();
+        assert!(error.to_string().contains("--merge"), "{}", error);
+        // Nothing was touched by the refused import
+        let manifest = Manifest::load(&new_root, &Layout::default()).unwrap();
+        assert!(manifest.previous(1, 100).is_none());
+
+        let summary = import_state(&archive, &new_root, &Layout::default(), None, true).unwrap();
+        assert_eq!(summary.merged, [MANIFEST_FILE]);
+        let manifest = Manifest::load(&new_root, &Layout::default()).unwrap();
+        for (game_id, upload_id) in [(1, 100), (2, 200), (3, 300)] {
+            assert!(is_current(&manifest, game_id, upload_id), "{}", game_id);
+        }
+    }
+
+    #[test]
+    fn exports_in_another_format_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("state.tar.gz");
+        let description = StateDescription {
+            version: STATE_FORMAT_VERSION + 1,
+            tool_version: "9.9.9".to_string(),
+            exported_at: 0,
+            output_root: dir.path().to_path_buf(),
+            profile: None,
+            layout: LAYOUT,
+        };
+        let file = std::fs::File::create(&archive_path).unwrap();
+        let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+            file,
+            flate2::Compression::default(),
+        ));
+        let contents = serde_json::to_vec(&description).unwrap();
+        append(&mut archive, Path::new(DESCRIPTION_FILE), &contents).unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+
+        let error =
+            import_state(&archive_path, dir.path(), &Layout::default(), None, false).unwrap_err();
+        assert!(error.to_string().contains("9.9.9"), "{}", error);
+        assert!(!Layout::default().state_dir(dir.path()).exists());
+    }
+
+    #[test]
+    fn other_archives_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("other.tar.gz");
+        let file = std::fs::File::create(&archive_path).unwrap();
+        let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+            file,
+            flate2::Compression::default(),
+        ));
+        append(&mut archive, Path::new("readme.txt"), b"hello").unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+
+        let error =
+            import_state(&archive_path, dir.path(), &Layout::default(), None, false).unwrap_err();
+        assert!(
+            error.to_string().contains("isn't a state export"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! How the library compares to a mirror, what `sync --dry-run --diff` shows
+
+use crate::manifest::{Manifest, version_label};
+use crate::plan::{PlanAction, PlannedDownload};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// What `sync` would do with a game's upload or a local entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatusCategory {
+    /// Never downloaded, `sync` fetches it
+    New,
+    /// Downloaded before in another version, or since deleted, `sync` replaces it
+    Updated,
+    /// Created for a game that's no longer owned or selected, which `prune` would remove
+    Orphaned,
+    /// Can't be tied to any game, left alone by everything
+    Untracked,
+    /// The uploads couldn't be listed, or there are none to download
+    Error,
+    Unchanged,
+}
+
+impl StatusCategory {
+    /// Short name used in the listing
+    pub fn label(self) -> &'static str {
+        match self {
+            StatusCategory::New => "new",
+            StatusCategory::Updated => "updated",
+            StatusCategory::Orphaned => "orphaned",
+            StatusCategory::Untracked => "untracked",
+            StatusCategory::Error => "error",
+            StatusCategory::Unchanged => "unchanged",
+        }
+    }
+}
+
+/// An upload or a local entry and what `sync` would do with it
+#[derive(Clone, Debug, Serialize)]
+pub struct StatusEntry {
+    pub category: StatusCategory,
+    pub game_id: Option<u64>,
+    pub title: Option<String>,
+    pub filename: Option<String>,
+    /// Where the upload goes or went, or the local entry
+    pub path: Option<PathBuf>,
+    /// The upload's size, or what the local entry takes up on disk
+    pub size: Option<u64>,
+    /// The version on disk, when one was recorded
+    pub local_version: Option<String>,
+    /// The version itch currently serves
+    pub remote_version: Option<String>,
+    pub detail: Option<String>,
+}
+
+impl StatusEntry {
+    /// Compare a planned download against what the manifest recorded for its upload
+    pub fn planned(entry: &PlannedDownload, manifest: &Manifest) -> Self {
+        let mut status = StatusEntry {
+            category: StatusCategory::Error,
+            game_id: Some(entry.game_id),
+            title: Some(entry.title.clone()),
+            filename: entry.filename.clone(),
+            path: entry.path.clone(),
+            size: entry.size,
+            local_version: None,
+            remote_version: None,
+            detail: entry.detail.clone(),
+        };
+        let Some(upload) = entry
+            .upload
+            .as_ref()
+            .filter(|_| entry.action != PlanAction::Error)
+        else {
+            return status;
+        };
+        status.remote_version = Some(version_label(upload.build_id, upload.updated_at));
+        let Some(previous) = manifest.previous(entry.game_id, upload.id) else {
+            status.category = StatusCategory::New;
+            return status;
+        };
+        let local_path = manifest.resolve(&previous.path);
+        status.local_version = Some(version_label(previous.build_id, previous.updated_at));
+        status.path = Some(local_path.clone());
+        status.detail = None;
+        status.category = if manifest.is_current(
+            entry.game_id,
+            upload.id,
+            upload.size,
+            upload.md5_hash.as_deref(),
+            upload.build_id,
+            upload.updated_at,
+        ) {
+            StatusCategory::Unchanged
+        } else {
+            if !local_path.exists() {
+                status.detail = Some("local copy missing".to_string());
+            } else if previous.is_newer_than(upload.build_id, upload.updated_at) {
+                status.detail =
+                    Some("older than the local copy, kept without --allow-downgrade".to_string());
+            }
+            StatusCategory::Updated
+        };
+        status
+    }
+
+    /// A local entry `sync` doesn't download, with what it takes up on disk
+    pub fn local(category: StatusCategory, path: &Path, game: Option<(u64, &str)>) -> Self {
+        StatusEntry {
+            category,
+            game_id: game.map(|(game_id, _)| game_id),
+            title: game.map(|(_, title)| title.to_string()),
+            filename: None,
+            path: Some(path.to_path_buf()),
+            size: Some(disk_usage(path)),
+            local_version: None,
+            remote_version: None,
+            detail: None,
+        }
+    }
+}
+
+/// How many entries a category has and the bytes they add up to
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct CategoryTotal {
+    pub count: usize,
+    pub bytes: u64,
+}
+
+/// Everything `sync --dry-run --diff` found, in the shape written by `--format json`
+#[derive(Debug, Default, Serialize)]
+pub struct StatusReport {
+    /// Sorted by category, then title
+    pub entries: Vec<StatusEntry>,
+    pub totals: BTreeMap<StatusCategory, CategoryTotal>,
+}
+
+impl StatusReport {
+    pub fn new(mut entries: Vec<StatusEntry>) -> Self {
+        entries.sort_by_cached_key(|entry| {
+            (
+                entry.category,
+                entry.title.as_deref().unwrap_or_default().to_lowercase(),
+                entry.path.clone(),
+            )
+        });
+        let mut totals: BTreeMap<StatusCategory, CategoryTotal> = BTreeMap::new();
+        for entry in &entries {
+            let total = totals.entry(entry.category).or_default();
+            total.count += 1;
+            total.bytes += entry.size.unwrap_or(0);
+        }
+        Self { entries, totals }
+    }
+
+    pub fn total(&self, category: StatusCategory) -> CategoryTotal {
+        self.totals.get(&category).copied().unwrap_or_default()
+    }
+}
+
+/// Bytes taken up by the files at or under `path`, not following links
+fn disk_usage(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| disk_usage(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
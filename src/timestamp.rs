@@ -0,0 +1,309 @@
+use chrono::{
+    DateTime, Local, NaiveDate, NaiveDateTime, SecondsFormat, SubsecRound, TimeZone, Utc,
+};
+
+/// A moment itch.io reported, in UTC
+///
+/// Most endpoints send `2024-01-31 12:00:00`, some RFC 3339 with fractions and an offset; [`parse`]
+/// reads both into the same value, so timestamps compare and sort properly whichever endpoint sent
+/// them. Serialized as RFC 3339 in UTC, and shown to people at their local offset.
+pub type Timestamp = DateTime<Utc>;
+
+/// The moment `secs` seconds after the Unix epoch
+pub fn from_unix(secs: i64) -> Timestamp {
+    DateTime::from_timestamp(secs, 0).unwrap_or_default()
+}
+
+/// The current time, to the second like the API's timestamps
+pub fn now() -> Timestamp {
+    Utc::now().trunc_subsecs(0)
+}
+
+/// Read `YYYY-MM-DD`, optionally followed by ` HH:MM:SS` or `THH:MM:SS`, fractions of a second
+/// and a `Z` or `+HH:MM` offset
+///
+/// Fractions are dropped, itch.io's timestamps only being to the second.
+pub fn parse(text: &str) -> Option<Timestamp> {
+    let text = text.trim();
+    // chrono takes fewer digits than these, and other scripts' digits when they're there
+    let shape = |text: &str, pattern: &str| {
+        text.len() == pattern.len()
+            && text.bytes().zip(pattern.bytes()).all(|(c, p)| match p {
+                b'9' => c.is_ascii_digit(),
+                _ => c == p,
+            })
+    };
+    let date = text.get(..10).filter(|date| shape(date, "9999-99-99"))?;
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    let Some(rest) = text.get(10..).filter(|rest| !rest.is_empty()) else {
+        return Some(date.and_time(Default::default()).and_utc());
+    };
+
+    let rest = rest.strip_prefix([' ', 'T', 't'])?;
+    let time = rest.get(..8).filter(|time| shape(time, "99:99:99"))?;
+    let local = NaiveDateTime::new(date, time.parse().ok()?);
+    let mut zone = &rest[8..];
+    if let Some(fraction) = zone.strip_prefix('.') {
+        zone = fraction.trim_start_matches(|c: char| c.is_ascii_digit());
+    }
+    match zone.trim() {
+        "" | "Z" | "z" | "UTC" => Some(local.and_utc()),
+        zone => {
+            let offset = chrono::FixedOffset::east_opt(parse_offset(zone)?)?;
+            Some(offset.from_local_datetime(&local).single()?.to_utc())
+        }
+    }
+}
+
+/// Seconds east of UTC of an offset like `+05:30` or `-0800`
+fn parse_offset(zone: &str) -> Option<i32> {
+    let sign = match zone.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let zone = zone[1..].replace(':', "");
+    if zone.is_empty() || !zone.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = zone.get(..2)?.parse().ok()?;
+    let minutes: i32 = zone
+        .get(2..)
+        .filter(|minutes| !minutes.is_empty())
+        .map_or(Some(0), |minutes| minutes.parse().ok())?;
+    Some(sign * (hours * 3_600 + minutes * 60))
+}
+
+/// Read an HTTP date like `Sun, 06 Nov 1994 08:49:37 GMT`, as sent in `Date` and `Retry-After`
+pub fn parse_http_date(text: &str) -> Option<Timestamp> {
+    let text = text.trim();
+    let text = text
+        .strip_suffix("GMT")
+        .or_else(|| text.strip_suffix("UTC"))
+        .unwrap_or(text);
+    NaiveDateTime::parse_from_str(text.trim(), "%a, %d %b %Y %H:%M:%S")
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// The day as `YYYY-MM-DD`, in UTC
+pub fn date(timestamp: Timestamp) -> String {
+    timestamp.format("%Y-%m-%d").to_string()
+}
+
+/// The day as `YYYY-MM-DD` where the user is
+pub fn local_date(timestamp: Timestamp) -> String {
+    timestamp
+        .with_timezone(&Local)
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// As RFC 3339, e.g. `2024-01-31T12:00:00Z`
+pub fn to_rfc3339(timestamp: Timestamp) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// `YYYY-MM-DD HH:MM:SS` in UTC, the form itch.io sends
+pub fn to_itch(timestamp: Timestamp) -> String {
+    timestamp.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// The date and time where the user is, with their offset, e.g. `2024-01-31 13:00:00 +01:00`
+pub fn local(timestamp: Timestamp) -> String {
+    at_offset(timestamp, &Local)
+}
+
+/// The date and time in `zone`, with its offset
+fn at_offset<Tz: TimeZone>(timestamp: Timestamp, zone: &Tz) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    timestamp
+        .with_timezone(zone)
+        .format("%Y-%m-%d %H:%M:%S %:z")
+        .to_string()
+}
+
+/// How long before `now` `timestamp` was, e.g. `3 days ago`
+pub fn relative(timestamp: Timestamp, now: Timestamp) -> String {
+    let elapsed = (now - timestamp).num_seconds();
+    let (count, unit) = match elapsed {
+        i64::MIN..60 => return "just now".to_string(),
+        60..3_600 => (elapsed / 60, "minute"),
+        3_600..86_400 => (elapsed / 3_600, "hour"),
+        86_400..2_592_000 => (elapsed / 86_400, "day"),
+        2_592_000..31_536_000 => (elapsed / 2_592_000, "month"),
+        _ => (elapsed / 31_536_000, "year"),
+    };
+    format!(
+        "{} {}{} ago",
+        count,
+        unit,
+        if count == 1 { "" } else { "s" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    fn at(text: &str) -> i64 {
+        parse(text).unwrap().timestamp()
+    }
+
+    #[test]
+    fn reads_both_forms_itch_sends() {
+        assert_eq!(at("2024-01-31 12:00:00"), 1_706_702_400);
+        assert_eq!(at("2024-01-31T12:00:00Z"), 1_706_702_400);
+        assert_eq!(at("2024-01-31T12:00:00.123456Z"), 1_706_702_400);
+        assert_eq!(at("2024-01-31"), 1_706_659_200);
+        assert_eq!(at("1970-01-01 00:00:00"), 0);
+    }
+
+    #[test]
+    fn offsets_are_taken_off() {
+        let utc = at("2024-01-31T12:00:00Z");
+        assert_eq!(at("2024-01-31T13:00:00+01:00"), utc);
+        assert_eq!(at("2024-01-31T17:30:00+05:30"), utc);
+        assert_eq!(at("2024-01-31T17:30:00+0530"), utc);
+        assert_eq!(at("2024-01-31T04:00:00-08:00"), utc);
+        assert_eq!(at("2024-01-31T12:00:00.5-00:00"), utc);
+        // An offset can move the moment onto another day
+        let late = parse("2024-01-31T23:30:00-02:00").unwrap();
+        assert_eq!(to_rfc3339(late), "2024-02-01T01:30:00Z");
+        let early = parse("2024-03-01T00:30:00+09:00").unwrap();
+        assert_eq!(to_rfc3339(early), "2024-02-29T15:30:00Z");
+    }
+
+    #[test]
+    fn leap_years() {
+        assert_eq!(at("2024-02-29") + 86_400, at("2024-03-01"));
+        assert_eq!(at("2000-02-29") + 86_400, at("2000-03-01"));
+        assert_eq!(at("2023-02-28") + 86_400, at("2023-03-01"));
+        // Every fourth year, except centuries that aren't a multiple of 400
+        for day in ["2023-02-29", "1900-02-29", "2100-02-29"] {
+            assert_eq!(parse(day), None, "{}", day);
+        }
+        assert_eq!(at("2024-12-31") - at("2024-01-01"), 365 * 86_400);
+        assert_eq!(at("2023-12-31") - at("2023-01-01"), 364 * 86_400);
+    }
+
+    #[test]
+    fn days_past_the_end_of_the_month_are_refused() {
+        for text in [
+            "2024-04-31",
+            "2024-06-31",
+            "2024-02-30",
+            "2024-13-01",
+            "2024-00-10",
+        ] {
+            assert_eq!(parse(text), None, "{}", text);
+        }
+        assert!(parse("2024-01-31").is_some());
+    }
+
+    #[test]
+    fn refuses_what_isnt_a_timestamp() {
+        for text in [
+            "",
+            "yesterday",
+            "2024/01/31",
+            "2024-1-31",
+            "2024-01-31 25:00:00",
+            "2024-01-31 12:00",
+            "2024-01-31T12:00:00 Europe/Paris",
+            "２０２４-01-31",
+        ] {
+            assert_eq!(parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        for text in [
+            "1970-01-01T00:00:00Z",
+            "1969-12-31T23:59:59Z",
+            "2000-02-29T06:07:08Z",
+            "2024-01-31T12:00:00Z",
+            "2100-12-31T23:59:59Z",
+        ] {
+            let timestamp = parse(text).unwrap();
+            assert_eq!(to_rfc3339(timestamp), text);
+            assert_eq!(parse(&to_itch(timestamp)), Some(timestamp));
+            // What serde writes reads back the same
+            let json = serde_json::to_string(&timestamp).unwrap();
+            assert_eq!(json, format!("\"{}\"", text));
+            assert_eq!(
+                parse(&serde_json::from_str::<String>(&json).unwrap()),
+                Some(timestamp)
+            );
+        }
+        let timestamp = parse("1969-12-31T23:59:59Z").unwrap();
+        assert_eq!(timestamp.timestamp(), -1);
+        assert_eq!(date(timestamp), "1969-12-31");
+        assert_eq!(to_itch(timestamp), "1969-12-31 23:59:59");
+    }
+
+    #[test]
+    fn shown_at_an_offset() {
+        let timestamp = parse("2024-01-31T23:30:00Z").unwrap();
+        let east = |secs| FixedOffset::east_opt(secs).unwrap();
+        assert_eq!(at_offset(timestamp, &Utc), "2024-01-31 23:30:00 +00:00");
+        assert_eq!(
+            at_offset(timestamp, &east(3_600)),
+            "2024-02-01 00:30:00 +01:00"
+        );
+        assert_eq!(
+            at_offset(timestamp, &east(19_800)),
+            "2024-02-01 05:00:00 +05:30"
+        );
+        assert_eq!(
+            at_offset(timestamp, &east(-28_800)),
+            "2024-01-31 15:30:00 -08:00"
+        );
+        assert_eq!(
+            at_offset(timestamp, &east(-34_200)),
+            "2024-01-31 14:00:00 -09:30"
+        );
+    }
+
+    #[test]
+    fn local_time_agrees_with_the_local_offset() {
+        let timestamp = parse("2024-07-01T12:00:00Z").unwrap();
+        let offset = timestamp.with_timezone(&Local).offset().local_minus_utc();
+        assert!(offset.abs() <= 14 * 3_600, "{}", offset);
+        let east = FixedOffset::east_opt(offset).unwrap();
+        assert_eq!(local(timestamp), at_offset(timestamp, &east));
+        assert_eq!(
+            local_date(timestamp),
+            date(timestamp + chrono::Duration::seconds(offset.into()))
+        );
+    }
+
+    #[test]
+    fn reads_http_dates() {
+        let timestamp = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(to_rfc3339(timestamp), "1994-11-06T08:49:37Z");
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT"),
+            parse("2024-02-29")
+        );
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 PST"), None);
+        assert_eq!(parse_http_date("120"), None);
+    }
+
+    #[test]
+    fn relative_times() {
+        let now = parse("2024-03-01 00:00:00").unwrap();
+        let ago = |secs: i64| relative(from_unix(now.timestamp() - secs), now);
+        assert_eq!(ago(-5), "just now");
+        assert_eq!(ago(59), "just now");
+        assert_eq!(ago(60), "1 minute ago");
+        assert_eq!(ago(7_200), "2 hours ago");
+        // 2024-02-01 is 29 days before, February being a leap month
+        assert_eq!(relative(parse("2024-02-01").unwrap(), now), "29 days ago");
+        assert_eq!(ago(3 * 31_536_000), "3 years ago");
+    }
+}
@@ -0,0 +1,112 @@
+use itch_downloader::{OwnedKey, Upload};
+use serde::Serialize;
+
+/// Uploads smaller than this are never timed, the connection setup would dominate them
+pub const MIN_SAMPLE_SIZE: u64 = 1024 * 1024;
+
+/// How many games are looked up to time metadata requests and find uploads to download
+pub const SAMPLE_GAMES: usize = 10;
+
+/// Streams downloaded at once in each round, one sample file per stream
+pub const STREAM_LEVELS: [usize; 5] = [1, 2, 4, 8, 16];
+
+/// How much faster a round has to be than the one before for its extra streams to be worth it
+const MIN_GAIN: f64 = 1.1;
+
+/// Share of the request rate reached when throttling started that's recommended as the limit
+const RATE_MARGIN: f64 = 0.8;
+
+/// Whether this looks like a CI job, where `tune` skips itself instead of using the network
+pub fn in_ci() -> bool {
+    std::env::var("CI").is_ok_and(|value| !value.is_empty() && value != "false" && value != "0")
+}
+
+/// An upload `tune` can download to measure throughput
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub game_title: String,
+    pub download_key_id: Option<u64>,
+    pub upload: Upload,
+}
+
+/// The smallest uploads of `uploads` big enough to time and no bigger than `budget` bytes,
+/// smallest first and at most one per stream of the largest round
+pub fn pick_samples(uploads: Vec<(&OwnedKey, Upload)>, budget: u64) -> Vec<Sample> {
+    let mut samples: Vec<Sample> = uploads
+        .into_iter()
+        .filter(|(_, upload)| upload.storage != "external")
+        .filter(|(_, upload)| (MIN_SAMPLE_SIZE..=budget).contains(&upload.size))
+        .map(|(key, upload)| Sample {
+            game_title: key.game.title.clone(),
+            download_key_id: key.download_key_id(),
+            upload,
+        })
+        .collect();
+    samples.sort_by_key(|sample| (sample.upload.size, sample.upload.id));
+    samples.dedup_by_key(|sample| sample.upload.id);
+    samples.truncate(STREAM_LEVELS[STREAM_LEVELS.len() - 1]);
+    samples
+}
+
+/// The samples a round with `streams` streams downloads, reusing them when there are fewer
+pub fn round_samples(samples: &[Sample], streams: usize) -> Vec<&Sample> {
+    samples.iter().cycle().take(streams).collect()
+}
+
+/// How fast one round of downloads went, from the first response to the last byte
+#[derive(Clone, Debug, Serialize)]
+pub struct RoundResult {
+    pub streams: usize,
+    pub bytes: u64,
+    pub seconds: f64,
+    pub bytes_per_second: f64,
+}
+
+/// The settings `tune` suggests, `None` where it has nothing to suggest
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Recommendation {
+    pub max_concurrent: Option<usize>,
+    pub rate_limit: Option<f64>,
+}
+
+/// Everything `tune` measured, in the shape written by `--format json`
+#[derive(Debug, Default, Serialize)]
+pub struct TuneReport {
+    /// How long each metadata request took, in milliseconds
+    pub latencies_ms: Vec<u64>,
+    pub rounds: Vec<RoundResult>,
+    /// Bytes downloaded in total, none of which were kept
+    pub downloaded: u64,
+    pub requests: u64,
+    /// Requests itch.io answered with `429 Too Many Requests`
+    pub throttled: u64,
+    pub recommended: Recommendation,
+}
+
+impl TuneReport {
+    pub fn median_latency_ms(&self) -> Option<u64> {
+        let mut latencies = self.latencies_ms.clone();
+        latencies.sort_unstable();
+        latencies.get(latencies.len() / 2).copied()
+    }
+
+    /// Work out the recommendation from the measurements, `seconds` being how long the whole
+    /// test took
+    ///
+    /// Streams are added while doubling them still speeds things up noticeably. A rate limit is
+    /// only suggested when itch.io throttled the test, a bit under the rate it ran at.
+    pub fn recommend(&mut self, seconds: f64) {
+        let mut best: Option<&RoundResult> = None;
+        for round in &self.rounds {
+            match best {
+                Some(best) if round.bytes_per_second < best.bytes_per_second * MIN_GAIN => break,
+                _ => best = Some(round),
+            }
+        }
+        self.recommended.max_concurrent = best.map(|round| round.streams);
+        self.recommended.rate_limit = (self.throttled > 0 && seconds > 0.0).then(|| {
+            let rate = self.requests as f64 / seconds * RATE_MARGIN;
+            ((rate * 10.0).floor() / 10.0).max(0.1)
+        });
+    }
+}
@@ -0,0 +1,430 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use itch_downloader::timestamp::{self, Timestamp};
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex, OnceLock};
+use unicode_width::UnicodeWidthChar;
+
+/// How chatty the terminal output is, decided once from the flags at startup
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    /// Only errors and the final summary
+    quiet: bool,
+    /// Draw progress bars, otherwise print a plain line per event
+    progress: bool,
+    /// Progress goes to stdout as JSON events, everything for people to stderr
+    porcelain: bool,
+    /// A download's bytes go to stdout, so everything for people goes to stderr
+    raw_stdout: bool,
+    dates: DateStyle,
+    /// A bar per file even for games downloading several, instead of one per game
+    flat_progress: bool,
+}
+
+/// How dates are shown in listings, JSON output always having RFC 3339
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DateStyle {
+    /// The day, where the user is
+    #[default]
+    Absolute,
+    /// How long ago, e.g. `3 days ago`
+    Relative,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Shared by every run so log lines know which bars to clear around themselves
+static MULTI_PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// How download bars are drawn, set once from `--progress-template` and `--progress-chars`
+static BAR_STYLE: OnceLock<ProgressStyle> = OnceLock::new();
+
+/// The download bar layout unless `--progress-template` picks another
+const DEFAULT_TEMPLATE: &str = "{msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})";
+
+/// What download bars are drawn with unless `--progress-chars` says otherwise
+pub const DEFAULT_PROGRESS_CHARS: &str = "#>-";
+
+/// Layouts `--progress-template` accepts by name, `{prefix}` being the game's title
+const TEMPLATE_PRESETS: &[(&str, &str)] = &[
+    ("default", DEFAULT_TEMPLATE),
+    (
+        "compact",
+        "{bar:20.cyan/blue} {percent:>3}% {binary_bytes_per_sec:>12} {msg}",
+    ),
+    (
+        "detailed",
+        "{prefix:.bold} {msg} [{wide_bar:.cyan/blue}] {percent:>3}% {bytes}/{total_bytes} at {bytes_per_sec} ({eta})",
+    ),
+];
+
+/// Check and set how download bars are drawn, `template` being a preset's name or an indicatif
+/// template
+pub fn set_bar_style(template: &str, chars: &str) -> anyhow::Result<()> {
+    let template = TEMPLATE_PRESETS
+        .iter()
+        .find(|(name, _)| *name == template)
+        .map_or(template, |(_, preset)| preset);
+    // indicatif panics on fewer than two characters or ones of different widths
+    let widths: Vec<_> = chars.chars().map(|c| c.width()).collect();
+    if widths.len() < 2
+        || widths
+            .iter()
+            .any(|&width| width != widths[0] || width == Some(0))
+    {
+        anyhow::bail!(
+            "Invalid progress characters \"{}\": need at least two (filled, then empty) of the same width",
+            chars
+        );
+    }
+    let style = ProgressStyle::default_bar()
+        .template(template)
+        .map_err(|e| anyhow::anyhow!("Invalid progress template \"{}\": {}", template, e))?
+        .progress_chars(chars);
+    let _ = BAR_STYLE.set(style);
+    Ok(())
+}
+
+/// The style download and extraction bars are drawn with
+pub fn bar_style() -> ProgressStyle {
+    BAR_STYLE
+        .get_or_init(|| {
+            ProgressStyle::default_bar()
+                .template(DEFAULT_TEMPLATE)
+                .unwrap()
+                .progress_chars(DEFAULT_PROGRESS_CHARS)
+        })
+        .clone()
+}
+
+/// Set the output mode, bars being left out whenever stderr isn't a terminal
+pub fn init(
+    quiet: bool,
+    no_progress: bool,
+    porcelain: bool,
+    raw_stdout: bool,
+    dates: DateStyle,
+    flat_progress: bool,
+) {
+    let _ = SETTINGS.set(Settings {
+        quiet,
+        progress: !quiet && !no_progress && !porcelain && std::io::stderr().is_terminal(),
+        porcelain,
+        raw_stdout,
+        dates,
+        flat_progress,
+    });
+}
+
+fn settings() -> Settings {
+    *SETTINGS.get_or_init(|| Settings {
+        quiet: false,
+        progress: std::io::stderr().is_terminal(),
+        porcelain: false,
+        raw_stdout: false,
+        dates: DateStyle::Absolute,
+        flat_progress: false,
+    })
+}
+
+/// Whether `--porcelain` events own stdout
+pub fn is_porcelain() -> bool {
+    settings().porcelain
+}
+
+/// Whether stdout carries data, `--porcelain` events or `dl --stdout` bytes, rather than text
+pub fn stdout_is_data() -> bool {
+    let settings = settings();
+    settings.porcelain || settings.raw_stdout
+}
+
+/// Whether informational messages are suppressed
+pub fn is_quiet() -> bool {
+    settings().quiet
+}
+
+/// A date as `--dates` asks for it, `-` when there isn't one
+pub fn date(at: Option<Timestamp>) -> String {
+    match (at, settings().dates) {
+        (Some(at), DateStyle::Absolute) => timestamp::local_date(at),
+        (Some(at), DateStyle::Relative) => timestamp::relative(at, timestamp::now()),
+        (None, _) => "-".to_string(),
+    }
+}
+
+/// How many columns wide the terminal stdout goes to is, `None` when it isn't one
+pub fn terminal_width() -> Option<usize> {
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    window_width().or_else(|| {
+        std::env::var("COLUMNS")
+            .ok()
+            .and_then(|columns| columns.parse().ok())
+            .filter(|&columns| columns > 0)
+    })
+}
+
+#[cfg(unix)]
+fn window_width() -> Option<usize> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != 0 {
+        return None;
+    }
+    Some(usize::from(size.ws_col)).filter(|&columns| columns > 0)
+}
+
+#[cfg(not(unix))]
+fn window_width() -> Option<usize> {
+    None
+}
+
+/// Colors listings are drawn in on a terminal
+#[derive(Clone, Copy, Debug)]
+pub enum Color {
+    Red,
+    Green,
+    Yellow,
+    Dim,
+}
+
+/// `text` in `color` when stdout is a terminal showing text, unless `NO_COLOR` is set
+pub fn paint(text: &str, color: Color) -> String {
+    let colored = std::io::stdout().is_terminal()
+        && !stdout_is_data()
+        && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+    if !colored {
+        return text.to_string();
+    }
+    let code = match color {
+        Color::Red => "31",
+        Color::Green => "32",
+        Color::Yellow => "33",
+        Color::Dim => "2",
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+/// Whether progress bars are drawn
+pub fn progress_enabled() -> bool {
+    settings().progress
+}
+
+/// Whether a game downloading several files gets one bar with the files under it, rather than
+/// a bar per file as `--flat-progress` asks
+pub fn group_progress() -> bool {
+    let settings = settings();
+    settings.progress && !settings.flat_progress
+}
+
+/// The set of progress bars, which stays hidden when bars are off
+pub fn multi_progress() -> MultiProgress {
+    MULTI_PROGRESS
+        .get_or_init(|| {
+            if progress_enabled() {
+                MultiProgress::new()
+            } else {
+                MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+            }
+        })
+        .clone()
+}
+
+/// Run `f` with the progress bars cleared from the screen, redrawing them afterwards
+///
+/// Before any bars exist this just runs `f`, so it's safe to call before [`init`].
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    match MULTI_PROGRESS.get() {
+        Some(multi_progress) => multi_progress.suspend(f),
+        None => f(),
+    }
+}
+
+/// Print a warning or error above the bars, or as a plain line when they're hidden
+///
+/// Anything printed while downloads run goes through here or [`suspend`], writing to the
+/// terminal directly draws over the bars.
+pub fn println(multi_progress: &MultiProgress, line: impl AsRef<str>) {
+    if progress_enabled() {
+        let _ = multi_progress.println(line.as_ref());
+    } else {
+        eprintln!("{}", line.as_ref());
+    }
+}
+
+/// Set a bar's message, also printed as a line of its own when bars are hidden
+pub fn event(bar: &ProgressBar, message: String) {
+    if !progress_enabled() && !is_quiet() {
+        eprintln!("{}", message);
+    }
+    bar.set_message(message);
+}
+
+/// Finish a bar that succeeded
+pub fn finish(bar: &ProgressBar, message: String) {
+    if !progress_enabled() && !is_quiet() {
+        eprintln!("{}", message);
+    }
+    bar.finish_with_message(message);
+}
+
+/// Finish a bar that failed, which is printed even when quiet
+pub fn fail(bar: &ProgressBar, message: String) {
+    if !progress_enabled() {
+        eprintln!("{}", message);
+    }
+    bar.finish_with_message(message);
+}
+
+/// Print an informational message to stderr unless `--quiet` was given
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::ui::is_quiet() {
+            $crate::ui::suspend(|| eprintln!($($arg)*));
+        }
+    };
+}
+pub(crate) use status;
+
+/// Print results and summaries to stdout, or to stderr when stdout carries data
+macro_rules! report {
+    ($($arg:tt)*) => {
+        if $crate::ui::stdout_is_data() {
+            $crate::ui::suspend(|| eprintln!($($arg)*));
+        } else {
+            $crate::ui::suspend(|| println!($($arg)*));
+        }
+    };
+}
+pub(crate) use report;
+
+/// The bars of a game downloading several files: one for the game, counting the bytes of all of
+/// them, with a bar per file under it only while that file is being worked on
+///
+/// The game's bar is added with its first file's, and reads e.g. `3/5 files done` in between.
+/// Clones share the same bars.
+#[derive(Clone)]
+pub struct GameBars {
+    multi_progress: MultiProgress,
+    parent: ProgressBar,
+    state: Arc<Mutex<GameBarsState>>,
+}
+
+#[derive(Default)]
+struct GameBarsState {
+    files: usize,
+    done: usize,
+    failed: usize,
+    shown: bool,
+    /// The bars of the files being worked on, by upload id
+    children: HashMap<u64, ProgressBar>,
+    /// Bytes received for each file so far
+    bytes: HashMap<u64, u64>,
+}
+
+impl GameBars {
+    /// Bars for `title`'s `files` files, `total` bytes between them
+    pub fn new(multi_progress: &MultiProgress, title: &str, files: usize, total: u64) -> Self {
+        let parent = ProgressBar::new(total);
+        parent.set_style(bar_style());
+        parent.set_prefix(title.to_string());
+        let bars = Self {
+            multi_progress: multi_progress.clone(),
+            parent,
+            state: Arc::new(Mutex::new(GameBarsState {
+                files,
+                ..Default::default()
+            })),
+        };
+        bars.update(&bars.state.lock().unwrap());
+        bars
+    }
+
+    /// The bar for one of the files, shown under the game's until [`GameBars::file_done`]
+    pub fn child(&self, upload_id: u64, size: u64) -> ProgressBar {
+        let mut state = self.state.lock().unwrap();
+        if !state.shown {
+            state.shown = true;
+            self.multi_progress.add(self.parent.clone());
+        }
+        let bar = self
+            .multi_progress
+            .insert_after(&self.parent, ProgressBar::new(size));
+        bar.set_style(bar_style());
+        bar.set_prefix(format!("  {}", self.parent.prefix()));
+        state.children.insert(upload_id, bar.clone());
+        self.update(&state);
+        bar
+    }
+
+    /// Note how many bytes of a file have arrived
+    pub fn record(&self, upload_id: u64, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.bytes.insert(upload_id, bytes);
+        self.parent.set_position(state.bytes.values().sum());
+    }
+
+    /// A file is dealt with, taking its bar off the screen
+    ///
+    /// The message of a file that failed is kept as a line above the bars.
+    pub fn file_done(&self, upload_id: u64, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.done += 1;
+        if failed {
+            state.failed += 1;
+        }
+        if let Some(bar) = state.children.remove(&upload_id) {
+            if failed {
+                let _ = self.multi_progress.println(bar.message());
+            }
+            bar.finish_and_clear();
+            self.multi_progress.remove(&bar);
+        }
+        self.update(&state);
+    }
+
+    /// Whether every file of the game is dealt with
+    pub fn is_finished(&self) -> bool {
+        self.parent.is_finished()
+    }
+
+    /// Redraw the game's line from `state`
+    fn update(&self, state: &GameBarsState) {
+        let mut message = format!("{}/{} files done", state.done, state.files);
+        if state.failed > 0 {
+            message.push_str(&format!(", {} failed", state.failed));
+        }
+        if !state.children.is_empty() {
+            message.push_str(&format!(", {} in progress", state.children.len()));
+        }
+        if state.done == state.files {
+            self.parent.finish_with_message(message);
+        } else {
+            self.parent.set_message(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indicatif::InMemoryTerm;
+
+    #[test]
+    fn suspend_hides_the_bars_while_it_runs() {
+        let term = InMemoryTerm::new(10, 80);
+        let multi_progress =
+            MultiProgress::with_draw_target(ProgressDrawTarget::term_like(Box::new(term.clone())));
+        MULTI_PROGRESS
+            .set(multi_progress.clone())
+            .expect("only this test sets up the bars");
+        let bar = multi_progress.add(ProgressBar::new_spinner());
+        bar.set_message("Downloading Celeste");
+        bar.tick();
+        assert!(term.contents().contains("Downloading Celeste"));
+
+        let during = suspend(|| term.contents());
+        assert!(!during.contains("Downloading Celeste"), "{}", during);
+        assert!(term.contents().contains("Downloading Celeste"));
+    }
+}
@@ -0,0 +1,693 @@
+//! The API client against a local mock of itch.io
+
+use itch_downloader::{
+    ApiVersion, DownloadProgress, DownloadedFile, ItchClient, ItchClientBuilder, ItchError,
+    RetryPolicy, SaveOptions,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use wiremock::matchers::{header, method, path, query_param, query_param_is_missing};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// A client for `server` that doesn't cache, pause between requests or wait long to retry
+fn client(server: &MockServer) -> ItchClient {
+    builder(server).build().unwrap()
+}
+
+fn builder(server: &MockServer) -> ItchClientBuilder {
+    ItchClient::builder()
+        .api_key("test-key")
+        .base_url(server.uri().parse().unwrap())
+        .api_version(ApiVersion::Modern)
+        .request_delay(Duration::ZERO)
+        .cache(false)
+        .retry_policy(RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(10),
+            delay_step: Duration::ZERO,
+        })
+}
+
+fn key(id: u64) -> serde_json::Value {
+    json!({
+        "id": id,
+        "game_id": id * 10,
+        "game": {
+            "id": id * 10,
+            "title": format!("Game {}", id),
+            "url": format!("https://someone.itch.io/game-{}", id),
+            "user": { "id": 1, "username": "someone" }
+        }
+    })
+}
+
+fn profile() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({ "user": { "id": 1, "username": "someone" } }))
+}
+
+#[tokio::test]
+async fn library_is_fetched_page_by_page_until_a_short_one() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/profile/owned-keys"))
+        .and(query_param("page", "1"))
+        .and(header("authorization", "Bearer test-key"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "owned_keys": [key(1), key(2)],
+            "page": 1,
+            "per_page": 2
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/profile/owned-keys"))
+        .and(query_param("page", "2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            // A key repeated from the first page is only returned once
+            "owned_keys": [key(2), key(3)],
+            "page": 2,
+            "per_page": 3
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut pages = Vec::new();
+    let keys = client(&server)
+        .list_owned_keys(|page| pages.push(page))
+        .await
+        .unwrap();
+
+    assert_eq!(pages, [1, 2]);
+    let ids: Vec<u64> = keys.iter().map(|key| key.id).collect();
+    assert_eq!(ids, [1, 2, 3]);
+}
+
+/// Answer library page `page` with `keys`, echoing `per_page` when it's given
+async fn library_page(server: &MockServer, page: u64, keys: &[u64], per_page: Option<u64>) {
+    let mut body = json!({
+        "owned_keys": keys.iter().map(|id| key(*id)).collect::<Vec<_>>(),
+        "page": page
+    });
+    if let Some(per_page) = per_page {
+        body["per_page"] = json!(per_page);
+    }
+    Mock::given(path("/profile/owned-keys"))
+        .and(query_param("page", page.to_string()))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .expect(1)
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn a_page_without_per_page_is_measured_against_the_size_asked_for() {
+    let server = MockServer::start().await;
+    library_page(&server, 1, &[1, 2], None).await;
+    library_page(&server, 2, &[3], None).await;
+
+    let mut pages = Vec::new();
+    let keys = builder(&server)
+        .page_size(2)
+        .build()
+        .unwrap()
+        .list_owned_keys(|page| pages.push(page))
+        .await
+        .unwrap();
+
+    assert_eq!(pages, [1, 2]);
+    assert_eq!(keys.len(), 3);
+    let requests = server.received_requests().await.unwrap();
+    assert!(requests.iter().all(|request| {
+        request
+            .url
+            .query_pairs()
+            .any(|(name, value)| name == "per_page" && value == "2")
+    }));
+}
+
+#[tokio::test]
+async fn without_a_page_size_only_an_empty_page_ends_the_library() {
+    let server = MockServer::start().await;
+    // itch.io refusing the size asked for leaves the client not knowing the page size at all
+    Mock::given(path("/profile/owned-keys"))
+        .and(query_param("per_page", "2"))
+        .respond_with(ResponseTemplate::new(400))
+        .expect(1)
+        .mount(&server)
+        .await;
+    for (page, keys) in [(1, &[1, 2][..]), (2, &[3]), (3, &[])] {
+        Mock::given(path("/profile/owned-keys"))
+            .and(query_param("page", page.to_string()))
+            .and(query_param_is_missing("per_page"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "owned_keys": keys.iter().map(|id| key(*id)).collect::<Vec<_>>()
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    let mut pages = Vec::new();
+    let keys = builder(&server)
+        .page_size(2)
+        .build()
+        .unwrap()
+        .list_owned_keys(|page| pages.push(page))
+        .await
+        .unwrap();
+
+    // The short second page doesn't end it, the size it's short of being unknown
+    assert_eq!(pages, [1, 2, 3]);
+    assert_eq!(keys.len(), 3);
+}
+
+#[tokio::test]
+async fn a_repeated_page_ends_the_library() {
+    let server = MockServer::start().await;
+    library_page(&server, 1, &[1, 2], Some(2)).await;
+    library_page(&server, 2, &[1, 2], Some(2)).await;
+
+    let mut pages = Vec::new();
+    let keys = client(&server)
+        .list_owned_keys(|page| pages.push(page))
+        .await
+        .unwrap();
+
+    assert_eq!(pages, [1, 2]);
+    let ids: Vec<u64> = keys.iter().map(|key| key.id).collect();
+    assert_eq!(ids, [1, 2]);
+}
+
+#[tokio::test]
+async fn a_repeated_page_ends_a_collection() {
+    let server = MockServer::start().await;
+    let games: Vec<_> = [1, 2]
+        .map(|id| json!({ "game": key(id)["game"], "position": id }))
+        .into();
+    // Every page answers with the same games, so only seeing them again stops it
+    Mock::given(path("/collections/5/collection-games"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "collection_games": games, "per_page": 2 })),
+        )
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let games = client(&server).list_collection_games(5).await.unwrap();
+
+    let ids: Vec<u64> = games.iter().map(|game| game.game.id).collect();
+    assert_eq!(ids, [10, 20]);
+}
+
+#[tokio::test]
+async fn a_failing_page_keeps_the_keys_before_it() {
+    let server = MockServer::start().await;
+    library_page(&server, 1, &[1, 2], Some(2)).await;
+    Mock::given(path("/profile/owned-keys"))
+        .and(query_param("page", "2"))
+        .respond_with(ResponseTemplate::new(503))
+        // The first attempt and both retries
+        .expect(3)
+        .mount(&server)
+        .await;
+
+    let library = client(&server)
+        .list_owned_keys_partial(|_| {})
+        .await
+        .unwrap();
+    assert_eq!(library.keys.len(), 2);
+    assert!(library.error.is_some_and(|e| e.is_transient()));
+}
+
+#[tokio::test]
+async fn an_empty_library_takes_one_request() {
+    let server = MockServer::start().await;
+    Mock::given(path("/profile/owned-keys"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let library = client(&server)
+        .list_owned_keys_partial(|_| {})
+        .await
+        .unwrap();
+
+    assert!(library.keys.is_empty());
+    assert!(library.error.is_none());
+}
+
+#[tokio::test]
+async fn rate_limited_requests_wait_for_retry_after() {
+    let server = MockServer::start().await;
+    Mock::given(path("/profile"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(path("/profile"))
+        .respond_with(profile())
+        .mount(&server)
+        .await;
+
+    let client = client(&server);
+    let started = Instant::now();
+    let user = client.get_profile().await.unwrap();
+
+    assert_eq!(user.username, "someone");
+    // The policy alone would have waited 10ms
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    let usage = client.api_usage();
+    assert_eq!((usage.requests, usage.throttled), (2, 1));
+}
+
+#[tokio::test]
+async fn rate_limiting_gives_up_after_the_retries() {
+    let server = MockServer::start().await;
+    Mock::given(path("/profile"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+        .expect(3)
+        .mount(&server)
+        .await;
+
+    let error = client(&server).get_profile().await.unwrap_err();
+
+    assert!(
+        matches!(error, ItchError::RateLimited { retries: 2 }),
+        "{:?}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn rejected_key_is_an_auth_error() {
+    let server = MockServer::start().await;
+    Mock::given(path("/profile"))
+        .respond_with(ResponseTemplate::new(401).set_body_string(r#"{"errors":["invalid key"]}"#))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let error = client(&server).get_profile().await.unwrap_err();
+
+    assert!(error.is_auth(), "{:?}", error);
+    assert!(!error.is_transient());
+}
+
+#[test]
+fn debug_output_leaves_out_the_api_key() {
+    let builder = ItchClient::builder().api_key("sekrit-api-key");
+
+    let debug = format!("{:?}", builder);
+
+    assert!(!debug.contains("sekrit-api-key"), "{}", debug);
+    assert!(debug.contains("<redacted>"), "{}", debug);
+}
+
+#[tokio::test]
+async fn malformed_json_is_a_decode_error() {
+    let server = MockServer::start().await;
+    Mock::given(path("/profile"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"user": {"id": "one""#))
+        .mount(&server)
+        .await;
+    Mock::given(path("/profile/collections"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(
+            json!({ "collections": [{ "id": 5, "title": "Saved", "games_count": "many" }] }),
+        ))
+        .mount(&server)
+        .await;
+
+    let client = client(&server);
+    let error = client.get_profile().await.unwrap_err();
+    assert!(matches!(error, ItchError::Decode { .. }), "{:?}", error);
+
+    // A value of the wrong type is reported by where it is
+    match client.list_collections().await.unwrap_err() {
+        ItchError::Decode { path, .. } => assert!(path.contains("games_count"), "{}", path),
+        error => panic!("expected a decode error, got {:?}", error),
+    }
+}
+
+#[tokio::test]
+async fn download_is_streamed_from_the_storage_host() {
+    let server = MockServer::start().await;
+    let body: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    Mock::given(method("GET"))
+        .and(path("/uploads/7/download"))
+        .and(query_param("download_key_id", "3"))
+        .and(header("authorization", "Bearer test-key"))
+        .respond_with(
+            ResponseTemplate::new(302)
+                .insert_header("Location", format!("{}/storage/7", server.uri()).as_str()),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/storage/7"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("Content-Disposition", r#"attachment; filename="game.zip""#)
+                .set_body_bytes(body.clone()),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut events = Vec::new();
+    let downloaded = client(&server)
+        .download_file(
+            7,
+            Some(3),
+            "listed.zip",
+            dir.path(),
+            &SaveOptions::default(),
+            |progress| events.push(progress),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    // The storage host isn't sent the API key
+    let requests = server.received_requests().await.unwrap();
+    let storage = requests
+        .iter()
+        .find(|request| request.url.path() == "/storage/7")
+        .unwrap();
+    assert!(!storage.headers.contains_key("authorization"));
+
+    assert_eq!(downloaded.filename, "game.zip");
+    assert_eq!(downloaded.size, body.len() as u64);
+    assert_eq!(downloaded.sha256, format!("{:x}", Sha256::digest(&body)));
+    assert_eq!(std::fs::read(dir.path().join("game.zip")).unwrap(), body);
+    // Only the finished file is left in the directory
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    assert!(matches!(
+        events.first(),
+        Some(DownloadProgress::Started { total: 200_000, .. })
+    ));
+    assert!(
+        events
+            .iter()
+            .any(|event| matches!(event, DownloadProgress::Bytes(200_000)))
+    );
+    assert!(matches!(events.last(), Some(DownloadProgress::Done)));
+}
+
+/// A storage host answering each connection with the next of `responses`, written as given and
+/// followed by hanging up, for what wiremock won't send: bodies without a length or cut short
+///
+/// Returns the host's URL and the head of every request it received.
+async fn raw_host(responses: Vec<Vec<u8>>) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/storage/7", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = requests.clone();
+    tokio::spawn(async move {
+        for response in responses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0u8; 1];
+            while !head.ends_with(b"\r\n\r\n") && socket.read(&mut byte).await.unwrap() == 1 {
+                head.push(byte[0]);
+            }
+            received
+                .lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(&head).to_lowercase());
+            socket.write_all(&response).await.unwrap();
+            socket.shutdown().await.unwrap();
+        }
+    });
+    (url, requests)
+}
+
+/// A response with `head` as its status line and headers and `body` after them
+fn raw_response(head: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!("{}\r\nConnection: close\r\n\r\n", head).into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// `body` sent in chunks of 1000 bytes, with no length up front
+fn chunked(body: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for chunk in body.chunks(1000) {
+        encoded.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        encoded.extend_from_slice(chunk);
+        encoded.extend_from_slice(b"\r\n");
+    }
+    encoded.extend_from_slice(b"0\r\n\r\n");
+    raw_response("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked", &encoded)
+}
+
+/// Redirect upload 7's download to `location`
+async fn redirect_download(server: &MockServer, location: &str) {
+    Mock::given(path("/uploads/7/download"))
+        .respond_with(ResponseTemplate::new(302).insert_header("Location", location))
+        .mount(server)
+        .await;
+}
+
+async fn download(
+    client: &ItchClient,
+    dir: &Path,
+    save: &SaveOptions,
+) -> (Result<DownloadedFile, ItchError>, Vec<DownloadProgress>) {
+    let mut events = Vec::new();
+    let downloaded = client
+        .download_file(
+            7,
+            None,
+            "game.zip",
+            dir,
+            save,
+            |progress| events.push(progress),
+            &CancellationToken::new(),
+        )
+        .await;
+    (downloaded, events)
+}
+
+fn body(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn a_body_of_unknown_length_is_downloaded_whole() {
+    let server = MockServer::start().await;
+    let body = body(2_500);
+    let (url, _) = raw_host(vec![chunked(&body)]).await;
+    redirect_download(&server, &url).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (downloaded, events) =
+        download(&client(&server), dir.path(), &SaveOptions::default()).await;
+
+    assert_eq!(downloaded.unwrap().size, 2_500);
+    assert_eq!(std::fs::read(dir.path().join("game.zip")).unwrap(), body);
+    assert!(matches!(
+        events.first(),
+        Some(DownloadProgress::Started { total: 0, .. })
+    ));
+}
+
+#[tokio::test]
+async fn a_body_short_of_the_listed_size_is_a_truncated_download() {
+    let server = MockServer::start().await;
+    // Every attempt gets the same short body, the first and two resumes
+    let (url, requests) = raw_host(vec![chunked(&body(300)); 3]).await;
+    redirect_download(&server, &url).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let save = SaveOptions {
+        expected_size: Some(1_000),
+        ..SaveOptions::default()
+    };
+    let (downloaded, events) = download(&client(&server), dir.path(), &save).await;
+
+    match downloaded {
+        Err(ItchError::Truncated { received, expected }) => {
+            assert_eq!((received, expected), (300, 1_000));
+        }
+        Err(error) => panic!("expected a truncated download, got {:?}", error),
+        Ok(_) => panic!("a truncated download was reported as done"),
+    }
+    assert_eq!(requests.lock().unwrap().len(), 3);
+    assert!(
+        events
+            .iter()
+            .any(|event| matches!(event, DownloadProgress::Resuming(_)))
+    );
+    // Nothing is left behind, not even the partial file
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn a_body_cut_short_of_its_content_length_fails() {
+    let server = MockServer::start().await;
+    let cut = raw_response("HTTP/1.1 200 OK\r\nContent-Length: 1000", &body(400));
+    let (url, _) = raw_host(vec![cut; 3]).await;
+    redirect_download(&server, &url).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (downloaded, _) = download(&client(&server), dir.path(), &SaveOptions::default()).await;
+
+    let error = downloaded
+        .err()
+        .expect("a cut off download was reported as done");
+    assert!(error.is_transient(), "{:?}", error);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn a_rate_limited_download_is_retried() {
+    let server = MockServer::start().await;
+    let body = body(1_000);
+    let (url, requests) = raw_host(vec![
+        raw_response(
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\nContent-Length: 0",
+            b"",
+        ),
+        raw_response("HTTP/1.1 200 OK\r\nContent-Length: 1000", &body),
+    ])
+    .await;
+    redirect_download(&server, &url).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (downloaded, events) =
+        download(&client(&server), dir.path(), &SaveOptions::default()).await;
+
+    assert_eq!(downloaded.unwrap().size, 1_000);
+    assert_eq!(requests.lock().unwrap().len(), 2);
+    assert!(
+        events
+            .iter()
+            .any(|event| matches!(event, DownloadProgress::RetryScheduled(_)))
+    );
+    assert_eq!(std::fs::read(dir.path().join("game.zip")).unwrap(), body);
+}
+
+#[tokio::test]
+async fn a_transfer_dropped_halfway_resumes_where_it_stopped() {
+    let server = MockServer::start().await;
+    let body = body(100_000);
+    let (url, requests) = raw_host(vec![
+        raw_response(
+            "HTTP/1.1 200 OK\r\nContent-Length: 100000",
+            &body[..50_000],
+        ),
+        raw_response(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 50000-99999/100000\r\nContent-Length: 50000",
+            &body[50_000..],
+        ),
+    ])
+    .await;
+    redirect_download(&server, &url).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (downloaded, events) =
+        download(&client(&server), dir.path(), &SaveOptions::default()).await;
+
+    let downloaded = downloaded.unwrap();
+    assert_eq!(downloaded.size, 100_000);
+    assert_eq!(downloaded.sha256, format!("{:x}", Sha256::digest(&body)));
+    assert_eq!(std::fs::read(dir.path().join("game.zip")).unwrap(), body);
+
+    // The resume goes straight back to the storage host, asking for the rest only
+    let requests = requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    assert!(!requests[0].contains("range:"));
+    assert!(
+        requests[1].contains("range: bytes=50000-"),
+        "{}",
+        requests[1]
+    );
+    assert_eq!(
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| request.url.path() == "/uploads/7/download")
+            .count(),
+        1
+    );
+    assert!(
+        events
+            .iter()
+            .any(|event| matches!(event, DownloadProgress::Resuming(_)))
+    );
+}
+
+#[tokio::test]
+async fn a_restarted_body_is_not_written_twice() {
+    let server = MockServer::start().await;
+    let body = body(10_000);
+    // The host ignores the range and sends everything again
+    let (url, _) = raw_host(vec![
+        raw_response("HTTP/1.1 200 OK\r\nContent-Length: 10000", &body[..4_000]),
+        raw_response("HTTP/1.1 200 OK\r\nContent-Length: 10000", &body),
+    ])
+    .await;
+    redirect_download(&server, &url).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (downloaded, _) = download(&client(&server), dir.path(), &SaveOptions::default()).await;
+
+    assert_eq!(downloaded.unwrap().size, 10_000);
+    assert_eq!(std::fs::read(dir.path().join("game.zip")).unwrap(), body);
+}
+
+#[tokio::test]
+async fn the_api_key_stays_off_the_storage_host_and_its_signature_is_kept() {
+    let server = MockServer::start().await;
+    let body = body(10_000);
+    let (url, requests) = raw_host(vec![
+        raw_response("HTTP/1.1 200 OK\r\nContent-Length: 10000", &body[..5_000]),
+        raw_response(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5000-9999/10000\r\nContent-Length: 5000",
+            &body[5_000..],
+        ),
+    ])
+    .await;
+    Mock::given(path("/uploads/7/download"))
+        .and(header("authorization", "Bearer test-key"))
+        .respond_with(
+            ResponseTemplate::new(302)
+                .insert_header("Location", format!("{}?sig=a%2Bb&expires=99", url).as_str()),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (downloaded, _) = download(&client(&server), dir.path(), &SaveOptions::default()).await;
+    assert_eq!(downloaded.unwrap().size, 10_000);
+
+    // Both the first request and the resume go to the signed URL as given, without the key
+    let requests = requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    for request in &requests {
+        assert!(
+            request.starts_with("get /storage/7?sig=a%2bb&expires=99 "),
+            "{}",
+            request
+        );
+        assert!(!request.contains("authorization"), "{}", request);
+        assert!(!request.contains("test-key"), "{}", request);
+    }
+}